@@ -0,0 +1,16 @@
+#!/bin/sh
+# Build the crate with no features, with each feature on its own, and with all of
+# them, so a feature that leans on another without declaring it fails here.
+set -e
+cd "$(dirname "$0")"
+
+features=$(sed -n '/^\[features\]/,/^\[/p' Cargo.toml | sed -n 's/^\([a-z0-9-]*\) = .*/\1/p' | grep -v -e '^default$' -e '^full$')
+
+echo "== no features"
+cargo check --quiet --all-targets --no-default-features
+for feature in $features; do
+    echo "== $feature"
+    cargo check --quiet --all-targets --no-default-features --features "$feature"
+done
+echo "== full"
+cargo check --quiet --all-targets --features full
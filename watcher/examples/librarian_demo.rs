@@ -0,0 +1,110 @@
+//! A small librarian: watch a local library, scan and index its tracks, and sync
+//! them into an in-memory mirror, then audit the mirror against the source.
+//!
+//!     cargo run --example librarian_demo [LIBRARY_DIR]
+//!
+//! Without a directory, a throwaway library is created under the temp directory.
+
+use futures::StreamExt;
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use watcher::{
+    AuditOptions, EntryKind, EntryMetadata, LocalStorage, MemoryStorage, PollingWatcher,
+    Provenance, RootConfig, Storage, StorageExt, StorageTransaction, UniversalPath, Watcher, audit,
+    record_provenance,
+};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let arg = std::env::args().nth(1);
+    let dir = match &arg {
+        Some(dir) => std::path::PathBuf::from(dir),
+        None => demo_library()?,
+    };
+    let root = RootConfig::new(UniversalPath::local(dir.to_string_lossy()).as_dir());
+    let source: Arc<dyn Storage> = Arc::new(LocalStorage::new());
+
+    println!("watching {} for a moment", root.path);
+    let watcher = PollingWatcher::new(source.clone()).with_interval(Duration::from_millis(200));
+    let mut events = watcher.watch(&root.path).await?;
+    let _ = tokio::time::timeout(Duration::from_secs(1), async {
+        while let Some(event) = events.next().await {
+            println!("  {:?}", event?);
+        }
+        Ok::<_, watcher::StorageError>(())
+    })
+    .await;
+    drop(events);
+
+    let mut entries = Vec::new();
+    let mut walk = source.walk(&root.path);
+    while let Some(entry) = walk.next().await {
+        entries.push(entry?);
+    }
+    drop(walk);
+
+    // The index: every track by its path under the root
+    let index: BTreeMap<String, &(UniversalPath, EntryMetadata)> = entries
+        .iter()
+        .filter(|(path, meta)| {
+            meta.kind == EntryKind::File && root.accepts_extension(path.extension())
+        })
+        .filter_map(|entry| Some((entry.0.relative_to(&root.path)?.join("/"), entry)))
+        .collect();
+    println!(
+        "scanned {} entries, indexed {} tracks",
+        entries.len(),
+        index.len()
+    );
+    for (rel, (_, meta)) in &index {
+        println!("  {rel} ({} bytes)", meta.size_bytes.unwrap_or(0));
+    }
+
+    let mirror = MemoryStorage::named("library");
+    let mirror_root = UniversalPath::from_uri_str("mem://library/")?;
+    let mut tx = StorageTransaction::new(&mirror);
+    for (path, meta) in &entries {
+        let Some(rel) = path.relative_to(&root.path) else {
+            continue;
+        };
+        let target = mirror_root.join_all(&rel);
+        match meta.kind {
+            EntryKind::Directory => tx.create_dir(&target),
+            _ => tx.write(&target, source.read(path).await?),
+        };
+    }
+    let report = tx.commit().await?;
+    println!("synced into {mirror_root}: {report:?}");
+    for (path, meta) in index.values() {
+        let data = source.read(path).await?;
+        let target = mirror_root.join_all(&path.relative_to(&root.path).unwrap_or_default());
+        record_provenance(&mirror, &target, &Provenance::new(path, Some(meta), &data)).await?;
+    }
+
+    let options = AuditOptions {
+        // The mirror's times are when it was written
+        mtime_tolerance: Duration::MAX,
+        ..AuditOptions::default()
+    };
+    let audit = audit(source.as_ref(), &root.path, &mirror, &mirror_root, &options).await?;
+    println!("{}", audit.to_json());
+    if arg.is_none() {
+        std::fs::remove_dir_all(&dir)?;
+    }
+    Ok(())
+}
+
+/// A few albums under the temp directory
+fn demo_library() -> std::io::Result<std::path::PathBuf> {
+    let dir = std::env::temp_dir().join(format!("otolith-librarian-{}", std::process::id()));
+    for (path, data) in [
+        ("Artist/First Album/01 Intro.flac", &b"intro"[..]),
+        ("Artist/First Album/02 Song.flac", b"song"),
+        ("Artist/First Album/cover.jpg", b"jpeg"),
+        ("Other Artist/Single/01 Single.mp3", b"single"),
+    ] {
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap_or(&dir))?;
+        std::fs::write(path, data)?;
+    }
+    Ok(dir)
+}
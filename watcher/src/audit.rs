@@ -0,0 +1,409 @@
+use crate::collation::Collation;
+use crate::storage::{EntryKind, EntryMetadata, Storage, StorageError};
+use crate::universal_path::UniversalPath;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::Duration,
+};
+
+/// How much a difference matters for a backup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DifferenceKind {
+    /// In the source but not the mirror
+    Missing,
+    /// In the mirror but not the source
+    Extra,
+    /// File on one side, directory on the other
+    KindMismatch,
+    SizeMismatch,
+    ContentMismatch,
+    /// Modification times differ by more than the tolerance
+    TimestampMismatch,
+    /// Could not be listed, stat'ed or read on one side
+    Unreadable,
+}
+
+impl DifferenceKind {
+    pub fn severity(self) -> Severity {
+        match self {
+            DifferenceKind::Missing
+            | DifferenceKind::KindMismatch
+            | DifferenceKind::SizeMismatch
+            | DifferenceKind::ContentMismatch
+            | DifferenceKind::Unreadable => Severity::Error,
+            DifferenceKind::Extra => Severity::Warning,
+            DifferenceKind::TimestampMismatch => Severity::Info,
+        }
+    }
+}
+
+/// One finding, keyed by the path relative to both roots.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Difference {
+    /// `/`-separated path below the roots; empty for the roots themselves
+    pub path: String,
+    pub kind: DifferenceKind,
+    pub severity: Severity,
+    pub detail: String,
+}
+
+impl Difference {
+    pub(crate) fn new(path: &str, kind: DifferenceKind, detail: String) -> Self {
+        Difference {
+            path: path.to_string(),
+            kind,
+            severity: kind.severity(),
+            detail,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditOptions {
+    /// Hash file contents when sizes match. Off compares only metadata.
+    pub compare_content: bool,
+    /// Allowed drift in modification times; FAT and some remotes keep only 2s precision.
+    pub mtime_tolerance: Duration,
+}
+
+impl Default for AuditOptions {
+    fn default() -> Self {
+        AuditOptions {
+            compare_content: true,
+            mtime_tolerance: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Result of `audit`; serializes to JSON for scripts and dashboards.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditReport {
+    pub source: UniversalPath,
+    pub mirror: UniversalPath,
+    pub files_compared: usize,
+    pub dirs_compared: usize,
+    pub bytes_hashed: u64,
+    pub differences: Vec<Difference>,
+}
+
+impl AuditReport {
+    pub fn is_clean(&self) -> bool {
+        self.differences.is_empty()
+    }
+
+    /// Worst severity found, or None for a clean audit
+    pub fn max_severity(&self) -> Option<Severity> {
+        self.differences.iter().map(|d| d.severity).max()
+    }
+
+    pub fn at_least(&self, severity: Severity) -> impl Iterator<Item = &Difference> {
+        self.differences
+            .iter()
+            .filter(move |d| d.severity >= severity)
+    }
+
+    /// Put the differences in path order under `collation`, each directory's findings
+    /// together and right before those of its contents
+    pub fn sort(&mut self, collation: &Collation) {
+        self.differences.sort_by(|a, b| {
+            let a: Vec<_> = a.path.split('/').collect();
+            let b: Vec<_> = b.path.split('/').collect();
+            collation.compare_segments(&a, &b)
+        });
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("audit report is always serializable")
+    }
+}
+
+/// Compare a source tree with its mirror without modifying either.
+///
+/// Both trees are walked together; entries are matched by name. Only errors on the
+/// roots themselves fail the audit, everything below is reported as a difference.
+pub async fn audit(
+    source: &dyn Storage,
+    source_root: &UniversalPath,
+    mirror: &dyn Storage,
+    mirror_root: &UniversalPath,
+    options: &AuditOptions,
+) -> Result<AuditReport, StorageError> {
+    let mut report = AuditReport {
+        source: source_root.clone(),
+        mirror: mirror_root.clone(),
+        files_compared: 0,
+        dirs_compared: 0,
+        bytes_hashed: 0,
+        differences: Vec::new(),
+    };
+    let source_meta = source.stat(source_root).await?;
+    let mirror_meta = mirror.stat(mirror_root).await?;
+
+    let mut pending = VecDeque::from([(
+        String::new(),
+        source_root.clone(),
+        source_meta,
+        mirror_root.clone(),
+        mirror_meta,
+    )]);
+    while let Some((rel, src, src_meta, dst, dst_meta)) = pending.pop_front() {
+        let differences = &mut report.differences;
+        let mut differ = |kind, detail| differences.push(Difference::new(&rel, kind, detail));
+
+        if src_meta.kind != dst_meta.kind {
+            differ(
+                DifferenceKind::KindMismatch,
+                format!(
+                    "{:?} in source, {:?} in mirror",
+                    src_meta.kind, dst_meta.kind
+                ),
+            );
+            continue;
+        }
+        match src_meta.kind {
+            EntryKind::File => {
+                report.files_compared += 1;
+                let source_file = (source, &src, &src_meta);
+                let mirror_file = (mirror, &dst, &dst_meta);
+                let (found, hashed) = compare_files(&rel, source_file, mirror_file, options).await;
+                report.bytes_hashed += hashed;
+                differences.extend(found);
+            }
+            EntryKind::Directory => {
+                report.dirs_compared += 1;
+                let (src_children, dst_children) =
+                    match (children(source, &src).await, children(mirror, &dst).await) {
+                        (Ok(a), Ok(b)) => (a, b),
+                        (Err(e), _) => {
+                            differ(DifferenceKind::Unreadable, format!("source: {e}"));
+                            continue;
+                        }
+                        (_, Err(e)) => {
+                            differ(DifferenceKind::Unreadable, format!("mirror: {e}"));
+                            continue;
+                        }
+                    };
+
+                let mut dst_children = dst_children;
+                for (name, (src_child, src_child_meta)) in src_children {
+                    let child_rel = if rel.is_empty() {
+                        name.clone()
+                    } else {
+                        format!("{rel}/{name}")
+                    };
+                    match dst_children.remove(&name) {
+                        Some((dst_child, dst_child_meta)) => pending.push_back((
+                            child_rel,
+                            src_child,
+                            src_child_meta,
+                            dst_child,
+                            dst_child_meta,
+                        )),
+                        None => differences.push(Difference::new(
+                            &child_rel,
+                            DifferenceKind::Missing,
+                            format!("{:?} missing from mirror", src_child_meta.kind),
+                        )),
+                    }
+                }
+                for (name, (_, dst_child_meta)) in dst_children {
+                    let child_rel = if rel.is_empty() {
+                        name
+                    } else {
+                        format!("{rel}/{name}")
+                    };
+                    differences.push(Difference::new(
+                        &child_rel,
+                        DifferenceKind::Extra,
+                        format!("{:?} only in mirror", dst_child_meta.kind),
+                    ));
+                }
+            }
+            EntryKind::Symlink | EntryKind::Other => {}
+        }
+    }
+
+    report
+        .differences
+        .sort_by(|a, b| a.path.cmp(&b.path).then(b.severity.cmp(&a.severity)));
+    Ok(report)
+}
+
+/// A file on one side, with where it is and what it is
+pub(crate) type Side<'a> = (&'a dyn Storage, &'a UniversalPath, &'a EntryMetadata);
+
+/// How the file at `rel` differs between source and mirror, and how many bytes were
+/// hashed to tell
+pub(crate) async fn compare_files(
+    rel: &str,
+    (source, src, src_meta): Side<'_>,
+    (mirror, dst, dst_meta): Side<'_>,
+    options: &AuditOptions,
+) -> (Vec<Difference>, u64) {
+    let mut differences = Vec::new();
+    let mut differ = |kind, detail| differences.push(Difference::new(rel, kind, detail));
+    if let Some(detail) = timestamp_drift(src_meta, dst_meta, options.mtime_tolerance) {
+        differ(DifferenceKind::TimestampMismatch, detail);
+    }
+    if src_meta.size_bytes != dst_meta.size_bytes {
+        differ(
+            DifferenceKind::SizeMismatch,
+            format!(
+                "{} bytes in source, {} in mirror",
+                fmt_size(src_meta.size_bytes),
+                fmt_size(dst_meta.size_bytes)
+            ),
+        );
+        return (differences, 0);
+    }
+    if !options.compare_content {
+        return (differences, 0);
+    }
+    let mut hashed = 0;
+    match (hash(source, src).await, hash(mirror, dst).await) {
+        (Ok((a, len)), Ok((b, _))) => {
+            hashed = len;
+            if a != b {
+                differ(
+                    DifferenceKind::ContentMismatch,
+                    format!("sha256 {a} in source, {b} in mirror"),
+                );
+            }
+        }
+        (Err(e), _) => differ(DifferenceKind::Unreadable, format!("source: {e}")),
+        (_, Err(e)) => differ(DifferenceKind::Unreadable, format!("mirror: {e}")),
+    }
+    (differences, hashed)
+}
+
+type Children = BTreeMap<String, (UniversalPath, EntryMetadata)>;
+
+/// Children of `dir` keyed by name. Entries that cannot be stat'ed are an error for
+/// the whole directory, since a partial listing would report false differences.
+async fn children(storage: &dyn Storage, dir: &UniversalPath) -> Result<Children, StorageError> {
+    let mut out = BTreeMap::new();
+    for child in storage.list(dir).await? {
+        let Some(name) = child.last_segment().map(str::to_string) else {
+            continue;
+        };
+        let meta = storage.stat(&child).await?;
+        out.insert(name, (child, meta));
+    }
+    Ok(out)
+}
+
+async fn hash(storage: &dyn Storage, path: &UniversalPath) -> Result<(String, u64), StorageError> {
+    let bytes = storage.read(path).await?;
+    Ok((hex::encode(Sha256::digest(&bytes)), bytes.len() as u64))
+}
+
+fn timestamp_drift(a: &EntryMetadata, b: &EntryMetadata, tolerance: Duration) -> Option<String> {
+    let (Some(a), Some(b)) = (a.modified_at, b.modified_at) else {
+        return None;
+    };
+    let drift = a.duration_since(b).or_else(|_| b.duration_since(a)).ok()?;
+    (drift > tolerance).then(|| {
+        let newer = if b > a { "mirror" } else { "source" };
+        format!(
+            "modified times differ by {}s, {newer} is newer",
+            drift.as_secs()
+        )
+    })
+}
+
+fn fmt_size(size: Option<u64>) -> String {
+    size.map_or_else(|| "unknown".to_string(), |s| s.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LocalStorage;
+    use std::{fs, time::SystemTime};
+
+    #[tokio::test]
+    async fn test_audit_reports_differences() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let (src, dst) = (dir.join("src"), dir.join("dst"));
+        for root in [&src, &dst] {
+            fs::create_dir_all(root.join("album")).unwrap();
+            fs::write(root.join("album/same.flac"), b"identical").unwrap();
+            fs::write(root.join("album/flipped.flac"), b"original").unwrap();
+        }
+        fs::write(dst.join("album/flipped.flac"), b"bitrot!!").unwrap();
+        fs::write(src.join("album/short.flac"), b"longer").unwrap();
+        fs::write(dst.join("album/short.flac"), b"short").unwrap();
+        fs::write(src.join("only-source.mp3"), b"x").unwrap();
+        fs::write(dst.join("only-mirror.mp3"), b"x").unwrap();
+        fs::create_dir_all(src.join("kind")).unwrap();
+        fs::write(dst.join("kind"), b"file").unwrap();
+        let old = SystemTime::now() - Duration::from_secs(3600);
+        fs::File::options()
+            .write(true)
+            .open(dst.join("album/same.flac"))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        let report = audit(
+            &LocalStorage::new(),
+            &UniversalPath::local(src.to_str().unwrap()),
+            &LocalStorage::new(),
+            &UniversalPath::local(dst.to_str().unwrap()),
+            &AuditOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let found: Vec<_> = report
+            .differences
+            .iter()
+            .map(|d| (d.path.as_str(), d.kind))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("album/flipped.flac", DifferenceKind::ContentMismatch),
+                ("album/same.flac", DifferenceKind::TimestampMismatch),
+                ("album/short.flac", DifferenceKind::SizeMismatch),
+                ("kind", DifferenceKind::KindMismatch),
+                ("only-mirror.mp3", DifferenceKind::Extra),
+                ("only-source.mp3", DifferenceKind::Missing),
+            ]
+        );
+        assert_eq!(report.max_severity(), Some(Severity::Error));
+        assert_eq!(report.at_least(Severity::Warning).count(), 5);
+        assert_eq!(report.files_compared, 3);
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(json["differences"][0]["kind"], "content_mismatch");
+        assert_eq!(json["differences"][0]["severity"], "error");
+
+        let metadata_only = audit(
+            &LocalStorage::new(),
+            &UniversalPath::local(src.join("album").to_str().unwrap()),
+            &LocalStorage::new(),
+            &UniversalPath::local(dst.join("album").to_str().unwrap()),
+            &AuditOptions {
+                compare_content: false,
+                mtime_tolerance: Duration::from_secs(7200),
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(metadata_only.differences.len(), 1);
+        assert_eq!(metadata_only.bytes_hashed, 0);
+    }
+}
@@ -0,0 +1,1001 @@
+use std::env;
+
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use futures::StreamExt;
+use watcher::{
+    AuditOptions, BackendInfo, ChecksumAlgorithm, ChecksumDb, Daemon, DaemonStatus, EntryKind,
+    EnvironmentReport, FileIndexStore, IncrementalChecksums, Index, Manifest, OtolithConfig,
+    ProbeReport, RootConfig, Schedule, Scheduler, SharedIndex, Storage, capability_matrix, control_call, diff_roots,
+    open_storage_for, parse_root_input, probe_root, redactor, serve_status,
+};
+
+const USAGE: &str = "Usage: otolith <command>\n\nCommands:\n  add-root [--config <file>] [--socket <path>] [--label <name>] <uri>\n                     Probe a root and, if it is reachable, add it to the running\n                     daemon, or to the configuration file when none is running\n  backends [--json]  What each storage backend supports\n  config [--file <file>] [schema]\n                     Print the configuration in effect, after environment\n                     overrides and validation, or the schema of the file\n  daemon [--config <file>] [--socket <path>] [--status <addr>] [--rescan <secs>]\n         [--service] [<root>...]\n                     Watch the configured roots and those given, keeping the\n                     index in the configured file and serving the JSON-RPC\n                     control API on a local socket (a named pipe on Windows)\n                     and /status on <addr> if given; --rescan scans each\n                     root that often, counting from its last scan as kept\n                     beside the index; on Unix, SIGHUP reloads\n                     the configuration, and sockets passed by systemd or\n                     launchd are used when there are any; on Windows,\n                     --service runs it under the service control\n                     manager, following roots whose drive letter changes\n  diff [--content] [--json] <uri-a> <uri-b>\n                     Compare two roots as they are now, printing differences as\n                     they are found; --content also compares file contents\n  env [--json] [--cache <dir>] [<root>...]\n                     Check limits, free space and that each root is reachable\n  jobs [--json] [--socket <path>] [pause [<id>] | resume [<id>] | cancel <id>]\n                     List the running daemon's jobs; pause, resume or cancel\n                     one, or without an id, pause or resume the whole queue\n  ops [--json] [--socket <path>]\n                     List what the running daemon is doing on its roots' storage\n  verify [--force] [--db <file>] [--algorithm <name>] <checksum-file> <root>\n                     Check files against a sha256sum-style checksum file, only\n                     hashing files changed since the last run with --db\n";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("add-root") => add_root(&args[1..]),
+        Some("backends") => {
+            let matrix = capability_matrix();
+            if args.iter().any(|a| a == "--json") {
+                println!("{}", serde_json::to_string_pretty(&matrix).expect("matrix serializes"));
+            } else {
+                for info in &matrix {
+                    print_backend(info);
+                }
+            }
+        }
+        Some("config") => config(&args[1..]),
+        Some("daemon") => daemon(&args[1..]),
+        Some("diff") => diff(&args[1..]),
+        Some("env") => env_report(&args[1..]),
+        Some("jobs") => jobs(&args[1..]),
+        Some("ops") => ops(&args[1..]),
+        Some("verify") => verify(&args[1..]),
+        _ => usage_error(),
+    }
+}
+
+/// How `otolith add-root` was asked to run
+#[derive(Debug, PartialEq)]
+struct AddRootArgs {
+    file: Option<std::path::PathBuf>,
+    socket: Option<String>,
+    label: Option<String>,
+    uri: String,
+}
+
+/// The arguments to `otolith add-root`, or None if they are not usable
+fn parse_add_root(args: &[String]) -> Option<AddRootArgs> {
+    let (mut file, mut socket, mut label, mut uri) = (None, None, None, None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => file = Some(std::path::PathBuf::from(args.next()?)),
+            "--socket" => socket = Some(args.next()?.clone()),
+            "--label" => label = Some(args.next()?.clone()),
+            flag if flag.starts_with("--") => return None,
+            given if uri.is_none() => uri = Some(given.to_string()),
+            _ => return None,
+        }
+    }
+    Some(AddRootArgs {
+        file,
+        socket,
+        label,
+        uri: uri?,
+    })
+}
+
+fn add_root(args: &[String]) {
+    let Some(args) = parse_add_root(args) else { usage_error() };
+    let runtime = tokio::runtime::Runtime::new().expect("tokio runtime starts");
+    let report = runtime.block_on(probe_root(&args.uri)).unwrap_or_else(|e| fail(&args.uri, e));
+    print_probe(&report);
+    if report.root_kind != EntryKind::Directory {
+        fail(&args.uri, "is not a directory");
+    }
+    let mut root = report.suggested_config;
+    if let Some(label) = args.label {
+        root.label = label;
+    }
+    match runtime.block_on(connect_control(args.socket.as_deref())) {
+        Ok(stream) => {
+            let uri = root.path.to_uri().unwrap_or_else(|e| fail(&args.uri, e));
+            let params = serde_json::json!({"uri": uri, "label": root.label, "extensions": root.extensions});
+            let added = runtime
+                .block_on(control_call(stream, "add_root", params))
+                .unwrap_or_else(|e| fail("daemon", e));
+            println!("added {} to the running daemon", added["label"].as_str().unwrap_or(&root.label));
+        }
+        // No daemon is listening, so the root waits in the configuration for one
+        Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused) => {
+            let path = args
+                .file
+                .or_else(OtolithConfig::default_path)
+                .unwrap_or_else(|| fail("config", "no configuration file to add the root to"));
+            let what = path.display().to_string();
+            let mut config = if path.exists() {
+                OtolithConfig::from_file(&path).unwrap_or_else(|e| fail(&what, e))
+            } else {
+                OtolithConfig::default()
+            };
+            let label = root.label.clone();
+            config.roots.push(root);
+            config.validate().unwrap_or_else(|e| fail(&what, e));
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                std::fs::create_dir_all(dir).unwrap_or_else(|e| fail(&what, e));
+            }
+            config.save(&path).unwrap_or_else(|e| fail(&what, e));
+            println!("added {} to {}", label, what);
+        }
+        Err(e) => fail("control socket", e),
+    }
+}
+
+fn print_probe(report: &ProbeReport) {
+    println!("{} ({:?})", redactor().path(&report.root), report.root_kind);
+    println!(
+        "  sampled:     {} files in {} directories, {} MiB",
+        report.sampled_files,
+        report.sampled_dirs,
+        report.sampled_bytes >> 20
+    );
+    if !report.complete {
+        println!("  estimated:   {} MiB in all", report.estimated_total_bytes >> 20);
+    }
+    for (ext, stats) in &report.formats {
+        let ext = if ext.is_empty() { "(none)" } else { ext.as_str() };
+        println!("  format:      {} {} files, {} MiB", ext, stats.files, stats.bytes >> 20);
+    }
+    println!("  audio files: {}", report.audio_files());
+    for (path, error) in &report.errors {
+        println!("  error:       {}: {}", redactor().path(path), error);
+    }
+    let suggested = &report.suggested_config;
+    println!("  label:       {}", suggested.label);
+    if !suggested.extensions.is_empty() {
+        println!("  extensions:  {}", suggested.extensions.join(", "));
+    }
+}
+
+/// A connection to the running daemon's control API, at `socket` or the default
+/// place; NotFound or ConnectionRefused when no daemon is running there
+#[cfg(unix)]
+async fn connect_control(socket: Option<&str>) -> std::io::Result<tokio::net::UnixStream> {
+    let path = match socket {
+        Some(path) => path.into(),
+        None => watcher::default_control_socket()?,
+    };
+    tokio::net::UnixStream::connect(path).await
+}
+
+#[cfg(windows)]
+async fn connect_control(
+    socket: Option<&str>,
+) -> std::io::Result<tokio::net::windows::named_pipe::NamedPipeClient> {
+    let name = socket.unwrap_or(watcher::DEFAULT_CONTROL_PIPE);
+    tokio::net::windows::named_pipe::ClientOptions::new().open(name)
+}
+
+fn jobs(args: &[String]) {
+    let mut json = false;
+    let mut socket = None;
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--socket" => match args.next() {
+                Some(path) => socket = Some(path.clone()),
+                None => usage_error(),
+            },
+            other => positional.push(other),
+        }
+    }
+    let id = |given: &str| given.parse::<u64>().unwrap_or_else(|e| fail(given, e));
+    let (method, params) = match positional[..] {
+        [] => ("jobs", serde_json::Value::Null),
+        [action @ ("pause" | "resume")] => (action, serde_json::Value::Null),
+        [action @ ("pause" | "resume" | "cancel"), job] => (action, serde_json::json!({"job": id(job)})),
+        _ => usage_error(),
+    };
+    let answer = daemon_call(socket.as_deref(), method, params);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&answer).expect("answer serializes"));
+        return;
+    }
+    if method != "jobs" {
+        println!("{} {}", method, positional.get(1).copied().unwrap_or("all jobs"));
+        return;
+    }
+    if answer["paused"] == true {
+        println!("the queue is paused");
+    }
+    let jobs = answer["jobs"].as_array().map(Vec::as_slice).unwrap_or_default();
+    if jobs.is_empty() {
+        println!("no jobs");
+    }
+    for job in jobs {
+        println!(
+            "{:>6}  {:<12} {:<10} {} attempts{}",
+            job["id"],
+            job["kind"].as_str().unwrap_or("?"),
+            job["state"].as_str().unwrap_or("?"),
+            job["attempts"],
+            job["last_error"].as_str().map(|e| format!(": {e}")).unwrap_or_default()
+        );
+    }
+}
+
+fn ops(args: &[String]) {
+    let mut json = false;
+    let mut socket = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--socket" => match args.next() {
+                Some(path) => socket = Some(path.clone()),
+                None => usage_error(),
+            },
+            _ => usage_error(),
+        }
+    }
+    let ops = daemon_call(socket.as_deref(), "ops", serde_json::Value::Null);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&ops).expect("operations serialize"));
+        return;
+    }
+    let ops = ops.as_array().map(Vec::as_slice).unwrap_or_default();
+    if ops.is_empty() {
+        println!("nothing running");
+    }
+    for op in ops {
+        println!(
+            "{:>6}  {:<12} {:>8.1}s {:>12} bytes  {}{}",
+            op["id"],
+            op["kind"].as_str().unwrap_or("?"),
+            op["elapsed_ms"].as_u64().unwrap_or(0) as f64 / 1000.0,
+            op["bytes"],
+            op["path"].as_str().unwrap_or("-"),
+            if op["cancelling"] == true { " (cancelling)" } else { "" }
+        );
+    }
+}
+
+/// Make one control request of the running daemon, failing if none is running
+fn daemon_call(socket: Option<&str>, method: &str, params: serde_json::Value) -> serde_json::Value {
+    let runtime = tokio::runtime::Runtime::new().expect("tokio runtime starts");
+    runtime.block_on(async {
+        let stream = connect_control(socket).await.unwrap_or_else(|e| fail("control socket", e));
+        control_call(stream, method, params).await.unwrap_or_else(|e| fail("daemon", e))
+    })
+}
+
+fn print_backend(info: &BackendInfo) {
+    println!("{} ({:?})", info.schemes.join(", "), info.backend);
+    println!("  operations:  {}", info.capabilities.operations().join(", "));
+    if !info.native.is_empty() {
+        println!("  native:      {}", info.native.join(", "));
+    }
+    let auth: Vec<String> = info
+        .auth
+        .iter()
+        .map(|a| serde_json::to_value(a).ok().and_then(|v| v.as_str().map(str::to_string)).unwrap_or_default())
+        .collect();
+    println!("  auth:        {}", auth.join(", "));
+    for limitation in info.limitations {
+        println!("  limitation:  {}", limitation);
+    }
+    println!();
+}
+
+fn env_report(args: &[String]) {
+    let mut json = false;
+    let mut cache_dir = env::temp_dir();
+    let mut roots = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--cache" => match args.next() {
+                Some(dir) => cache_dir = dir.into(),
+                None => usage_error(),
+            },
+            root => match parse_root_input(root) {
+                Ok(path) => roots.push(RootConfig::new(path)),
+                Err(e) => {
+                    eprintln!("otolith: {}: {}", root, e);
+                    std::process::exit(1);
+                }
+            },
+        }
+    }
+    let runtime = tokio::runtime::Runtime::new().expect("tokio runtime starts");
+    let report = runtime.block_on(EnvironmentReport::gather(&roots, Some(&cache_dir)));
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report).expect("report serializes"));
+        return;
+    }
+    println!("otolith {} on {}/{}", report.version, report.os, report.arch);
+    println!("  features:    {}", if report.features.is_empty() { "none".to_string() } else { report.features.join(", ") });
+    let limit = |v: Option<u64>| v.map_or("unlimited".to_string(), |v| v.to_string());
+    match report.open_files {
+        Some(l) => println!("  open files:  {} (hard {})", limit(l.soft), limit(l.hard)),
+        None => println!("  open files:  unknown"),
+    }
+    if let Some(inotify) = report.inotify {
+        println!(
+            "  inotify:     {} watches, {} instances, {} queued events",
+            inotify.max_user_watches, inotify.max_user_instances, inotify.max_queued_events
+        );
+    }
+    if let Some(space) = &report.cache_space {
+        println!(
+            "  cache space: {} MiB free of {} MiB in {}",
+            space.available_bytes >> 20,
+            space.total_bytes >> 20,
+            space.path.display()
+        );
+    }
+    for root in &report.roots {
+        match (&root.error, root.latency_ms) {
+            (None, Some(ms)) => println!("  root:        {} ({}) reachable in {} ms", root.label, root.root, ms),
+            (error, _) if root.possibly_asleep => println!("  root:        {} ({}) may be asleep: {}", root.label, root.root, error.as_deref().unwrap_or("?")),
+            (error, _) => println!("  root:        {} ({}) unreachable: {}", root.label, root.root, error.as_deref().unwrap_or("?")),
+        }
+    }
+    for warning in &report.warnings {
+        println!("warning: {}", warning);
+    }
+}
+
+fn config(args: &[String]) {
+    let mut file = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--file" => match args.next() {
+                Some(path) => file = Some(std::path::PathBuf::from(path)),
+                None => usage_error(),
+            },
+            "schema" => {
+                println!("{}", serde_json::to_string_pretty(&OtolithConfig::schema()).expect("schema serializes"));
+                return;
+            }
+            _ => usage_error(),
+        }
+    }
+    let config = OtolithConfig::load(file.as_deref()).unwrap_or_else(|e| fail("config", e));
+    println!("{}", serde_json::to_string_pretty(&config).expect("config serializes"));
+}
+
+/// How `otolith daemon` was asked to run
+struct DaemonArgs {
+    file: Option<std::path::PathBuf>,
+    socket: Option<String>,
+    status_addr: Option<String>,
+    rescan: Option<Duration>,
+    roots: Vec<RootConfig>,
+}
+
+fn daemon(args: &[String]) {
+    let mut parsed = DaemonArgs {
+        file: None,
+        socket: None,
+        status_addr: None,
+        rescan: None,
+        roots: Vec::new(),
+    };
+    #[cfg(windows)]
+    let mut service = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => match args.next() {
+                Some(path) => parsed.file = Some(std::path::PathBuf::from(path)),
+                None => usage_error(),
+            },
+            "--socket" => match args.next() {
+                Some(path) => parsed.socket = Some(path.clone()),
+                None => usage_error(),
+            },
+            "--status" => match args.next() {
+                Some(addr) => parsed.status_addr = Some(addr.clone()),
+                None => usage_error(),
+            },
+            "--rescan" => match args.next().map(|secs| secs.parse::<u64>()) {
+                Some(Ok(secs)) if secs > 0 => parsed.rescan = Some(Duration::from_secs(secs)),
+                Some(Ok(_)) => fail("--rescan", "must be at least a second"),
+                Some(Err(e)) => fail("--rescan", e),
+                None => usage_error(),
+            },
+            #[cfg(windows)]
+            "--service" => service = true,
+            root => match parse_root_input(root) {
+                Ok(path) => parsed.roots.push(RootConfig::new(path.as_dir())),
+                Err(e) => fail(root, e),
+            },
+        }
+    }
+    #[cfg(windows)]
+    if service {
+        windows_service::run(parsed);
+        return;
+    }
+    #[cfg(unix)]
+    run_daemon(parsed);
+    #[cfg(windows)]
+    {
+        // Only Ctrl-C stops a daemon run from a console
+        let (_requests, receiver) = tokio::sync::mpsc::unbounded_channel();
+        run_daemon(parsed, receiver);
+    }
+}
+
+/// Load the configuration, start watching its roots and those given, and serve the
+/// control API until told to stop
+fn run_daemon(
+    args: DaemonArgs,
+    #[cfg(windows)] requests: tokio::sync::mpsc::UnboundedReceiver<ServiceRequest>,
+) {
+    let DaemonArgs {
+        file,
+        socket,
+        status_addr,
+        rescan,
+        roots,
+    } = args;
+    let config = OtolithConfig::load(file.as_deref()).unwrap_or_else(|e| fail("config", e));
+    let index_path = config.index_path(file.as_deref());
+    let scheduler = match &index_path {
+        Some(path) => {
+            let state = path.with_extension("schedule.json");
+            Scheduler::with_state_file(&state)
+                .unwrap_or_else(|e| fail(&state.display().to_string(), e))
+        }
+        None => Scheduler::new(),
+    };
+    let store = match index_path {
+        Some(path) => {
+            let what = path.display().to_string();
+            let store = FileIndexStore::open(&path).unwrap_or_else(|e| fail(&what, e));
+            let recovery = store.recovery();
+            if recovery.torn_bytes > 0 || recovery.from_previous {
+                eprintln!("otolith: {}: recovered from an unclean shutdown", what);
+            }
+            store.with_fsync(config.index.fsync_policy())
+        }
+        None => FileIndexStore::in_memory(),
+    };
+    let index = Index::from_store(&store).unwrap_or_else(|e| fail("index", e));
+    let daemon = Daemon::new(SharedIndex::new(index), DaemonStatus::new())
+        .with_store(store)
+        .unwrap_or_else(|e| fail("jobs", e));
+    for root in config.roots.into_iter().chain(roots.iter().cloned()) {
+        let label = root.label.clone();
+        daemon.add_root(root).unwrap_or_else(|e| fail(&label, e));
+    }
+    let rescans = Rescans::new(daemon.clone(), scheduler, rescan);
+
+    let runtime = tokio::runtime::Runtime::new().expect("tokio runtime starts");
+    runtime.block_on(async {
+        #[cfg(unix)]
+        let mut activated = watcher::ActivatedSockets::from_env();
+        #[cfg(unix)]
+        let activated_status = activated.take_tcp("status");
+        #[cfg(not(unix))]
+        let activated_status: Option<std::net::TcpListener> = None;
+        let status_listener = match (&status_addr, activated_status) {
+            (Some(addr), _) => Some(
+                tokio::net::TcpListener::bind(addr)
+                    .await
+                    .unwrap_or_else(|e| fail(addr, e)),
+            ),
+            (None, Some(listener)) => {
+                listener
+                    .set_nonblocking(true)
+                    .unwrap_or_else(|e| fail("status socket", e));
+                Some(
+                    tokio::net::TcpListener::from_std(listener)
+                        .unwrap_or_else(|e| fail("status socket", e)),
+                )
+            }
+            (None, None) => None,
+        };
+        if let Some(listener) = status_listener {
+            tokio::spawn(serve_status(listener, daemon.status().clone()));
+        }
+        match rescan {
+            Some(_) => {
+                tokio::spawn(rescans.clone().run());
+            }
+            // Without --rescan each root is scanned once, on the way up
+            None => rescans.start(daemon.roots().into_iter().map(|root| root.label).collect()),
+        }
+
+        #[cfg(unix)]
+        {
+            let notifier = watcher::ServiceNotifier::from_env();
+            let (listener, bound) = bind_control(socket, &mut activated);
+            let _ = notifier.status(&format!("watching {} roots", daemon.roots().len()));
+            let _ = notifier.ready();
+            tokio::select! {
+                served = watcher::serve_control_socket(listener, daemon.clone()) => {
+                    served.unwrap_or_else(|e| fail("control socket", e));
+                }
+                () = supervise(&rescans, &notifier, file.as_deref(), &roots) => {}
+            }
+            let _ = notifier.stopping();
+            if let Some(path) = bound {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        #[cfg(windows)]
+        {
+            let name = socket.unwrap_or_else(|| watcher::DEFAULT_CONTROL_PIPE.to_string());
+            tokio::select! {
+                served = watcher::serve_control_pipe(&name, daemon.clone()) => {
+                    served.unwrap_or_else(|e| fail(&name, e));
+                }
+                () = supervise(&rescans, file.as_deref(), &roots, requests) => {}
+            }
+        }
+    });
+}
+
+fn report_rescans(results: Vec<(String, Result<usize, watcher::DaemonError>)>) {
+    for (label, result) in results {
+        if let Err(e) = result {
+            eprintln!("otolith: {}: {}", label, e);
+        }
+    }
+}
+
+/// Each root's rescan as a job of a `Scheduler`, named by the root's label, so a scan
+/// asked for while the root's last one is still going is skipped. With `--rescan` the
+/// jobs also run that often; without, only when started.
+#[derive(Clone)]
+struct Rescans {
+    daemon: Daemon,
+    scheduler: Arc<Mutex<Scheduler>>,
+    every: Option<Duration>,
+}
+
+impl Rescans {
+    fn new(daemon: Daemon, scheduler: Scheduler, every: Option<Duration>) -> Self {
+        Rescans {
+            daemon,
+            scheduler: Arc::new(Mutex::new(scheduler)),
+            every,
+        }
+    }
+
+    /// The scheduler, with a job for each root the daemon has now and none for the
+    /// roots it has dropped
+    fn scheduler(&self) -> MutexGuard<'_, Scheduler> {
+        let mut scheduler = self.scheduler.lock().unwrap_or_else(PoisonError::into_inner);
+        let labels: BTreeSet<String> =
+            self.daemon.roots().into_iter().map(|root| root.label).collect();
+        let gone: Vec<String> = scheduler
+            .names()
+            .filter(|name| !labels.contains(*name))
+            .map(str::to_string)
+            .collect();
+        for name in gone {
+            scheduler.remove(&name);
+        }
+        for label in labels {
+            if scheduler.names().any(|name| name == label) {
+                continue;
+            }
+            let daemon = self.daemon.clone();
+            let name = label.clone();
+            let job = move || {
+                let daemon = daemon.clone();
+                let label = name.clone();
+                async move {
+                    let result = daemon.rescan(&label).await;
+                    report_rescans(vec![(label, result)]);
+                }
+            };
+            let added = match self.every {
+                Some(every) => scheduler.add(&label, Schedule::Interval(every), job),
+                None => scheduler.add_unscheduled(&label, job),
+            };
+            // Labels are unique, and only a second job of the same name is refused
+            added.expect("one job per root");
+        }
+        scheduler
+    }
+
+    /// Scan `labels` in the background, but not those already being scanned
+    fn start(&self, labels: Vec<String>) {
+        let mut scheduler = self.scheduler();
+        for label in labels {
+            if let Err(e) = scheduler.run_now(&label) {
+                eprintln!("otolith: schedule: {}", e);
+            }
+        }
+    }
+
+    /// Scan each root whenever it comes due
+    async fn run(self) {
+        loop {
+            let wait = {
+                let mut scheduler = self.scheduler();
+                if let Err(e) = scheduler.run_pending() {
+                    eprintln!("otolith: schedule: {}", e);
+                }
+                scheduler.next_wait()
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Read the configuration again and watch the roots it has, with those given on the
+/// command line, scanning any that are new. A configuration that does not load is
+/// left for the caller to report, and the roots are left as they were.
+fn reload_roots(
+    rescans: &Rescans,
+    file: Option<&std::path::Path>,
+    roots: &[RootConfig],
+) -> Result<(), watcher::ConfigError> {
+    let config = OtolithConfig::load(file)?;
+    let added = rescans.daemon.reload(
+        config
+            .roots
+            .into_iter()
+            .chain(roots.iter().cloned())
+            .collect(),
+    );
+    rescans.start(added);
+    Ok(())
+}
+
+/// The control socket systemd or launchd opened for the daemon, or else one bound at
+/// `path` or the default path, with the path to remove on the way out if it was
+/// bound here
+#[cfg(unix)]
+fn bind_control(
+    path: Option<String>,
+    activated: &mut watcher::ActivatedSockets,
+) -> (tokio::net::UnixListener, Option<std::path::PathBuf>) {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    if let Some(listener) = activated.take_unix("control") {
+        listener
+            .set_nonblocking(true)
+            .unwrap_or_else(|e| fail("control socket", e));
+        let listener = tokio::net::UnixListener::from_std(listener)
+            .unwrap_or_else(|e| fail("control socket", e));
+        return (listener, None);
+    }
+    let path = match path {
+        Some(path) => path.into(),
+        None => watcher::default_control_socket().unwrap_or_else(|e| fail("control socket", e)),
+    };
+    let what = path.display().to_string();
+    // A socket left behind by a daemon that did not exit cleanly would fail the bind;
+    // anything else at the path is not ours to remove
+    match std::fs::symlink_metadata(&path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(&path).is_err() {
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+        Ok(_) => fail(&what, "exists and is not a socket"),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => fail(&what, e),
+    }
+    let listener = tokio::net::UnixListener::bind(&path).unwrap_or_else(|e| fail(&what, e));
+    // Whoever can connect can add roots and read the library
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))
+        .unwrap_or_else(|e| fail(&what, e));
+    (listener, Some(path))
+}
+
+/// Answer the service manager until told to stop: reload the configuration on SIGHUP,
+/// and ping the watchdog if it wants pinging
+#[cfg(unix)]
+async fn supervise(
+    rescans: &Rescans,
+    notifier: &watcher::ServiceNotifier,
+    file: Option<&std::path::Path>,
+    roots: &[RootConfig],
+) {
+    let daemon = &rescans.daemon;
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut hangup = signal(SignalKind::hangup()).expect("SIGHUP handler installs");
+    let mut terminate = signal(SignalKind::terminate()).expect("SIGTERM handler installs");
+    let mut watchdog = notifier.watchdog_interval().map(tokio::time::interval);
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                let _ = notifier.reloading();
+                match reload_roots(rescans, file, roots) {
+                    Ok(()) => {
+                        let _ = notifier.status(&format!("watching {} roots", daemon.roots().len()));
+                    }
+                    Err(e) => {
+                        eprintln!("otolith: config: {}", e);
+                        let _ = notifier.status(&format!("reload failed: {}", e));
+                    }
+                }
+                let _ = notifier.ready();
+            }
+            _ = terminate.recv() => return,
+            _ = tokio::signal::ctrl_c() => return,
+            () = tick(&mut watchdog) => {
+                let _ = notifier.watchdog();
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn tick(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// What the service control manager asks of a daemon run as a Windows service
+#[cfg(windows)]
+enum ServiceRequest {
+    Reload,
+    Stop,
+}
+
+/// How often the drive letters volumes are mounted at are looked at again
+#[cfg(windows)]
+const VOLUME_POLL: Duration = Duration::from_secs(5);
+
+/// Answer the service control manager until told to stop, reloading the
+/// configuration when its parameters change, and follow roots on volumes that are
+/// removed, come back or move to another drive letter
+#[cfg(windows)]
+async fn supervise(
+    rescans: &Rescans,
+    file: Option<&std::path::Path>,
+    roots: &[RootConfig],
+    mut requests: tokio::sync::mpsc::UnboundedReceiver<ServiceRequest>,
+) {
+    // Kept beside the configuration, so roots are found again after a restart
+    let registry_path = file
+        .map(std::path::Path::to_path_buf)
+        .or_else(OtolithConfig::default_path)
+        .map(|config| config.with_file_name("volumes.json"));
+    let mut volumes = match registry_path {
+        Some(path) => watcher::VolumeRegistry::open(&path).unwrap_or_else(|e| {
+            eprintln!("otolith: {}: {}", path.display(), e);
+            watcher::VolumeRegistry::new()
+        }),
+        None => watcher::VolumeRegistry::new(),
+    };
+    let daemon = &rescans.daemon;
+    let mut poll = tokio::time::interval(VOLUME_POLL);
+    loop {
+        tokio::select! {
+            request = requests.recv() => match request {
+                Some(ServiceRequest::Reload) => {
+                    if let Err(e) = reload_roots(rescans, file, roots) {
+                        eprintln!("otolith: config: {}", e);
+                    }
+                    poll.reset_immediately();
+                }
+                Some(ServiceRequest::Stop) => return,
+                // The console has no one to send requests
+                None => std::future::pending().await,
+            },
+            _ = tokio::signal::ctrl_c() => return,
+            _ = poll.tick() => {
+                match volumes.update(&daemon.roots(), &watcher::mounted_volumes()) {
+                    Ok(changes) => rescans.start(daemon.apply_volume_changes(changes)),
+                    Err(e) => eprintln!("otolith: volumes: {}", e),
+                }
+            }
+        }
+    }
+}
+
+/// Running `otolith daemon --service` under the service control manager, as set up
+/// with `sc.exe create otolith binPath= "<path>\otolith.exe daemon --service ..."`
+#[cfg(windows)]
+mod windows_service {
+    use super::{DaemonArgs, ServiceRequest, fail, run_daemon};
+    use std::{ffi::OsString, sync::Mutex, time::Duration};
+    use windows_service::{
+        define_windows_service,
+        service::{
+            ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+            ServiceType,
+        },
+        service_control_handler::{self, ServiceControlHandlerResult},
+        service_dispatcher,
+    };
+
+    const SERVICE_NAME: &str = "otolith";
+
+    /// The arguments from the service's command line, for the service's main
+    /// function, which the dispatcher calls with only those given at start
+    static ARGS: Mutex<Option<DaemonArgs>> = Mutex::new(None);
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    /// Hand the process over to the service control manager until the service stops
+    pub fn run(args: DaemonArgs) {
+        *ARGS.lock().unwrap_or_else(|e| e.into_inner()) = Some(args);
+        service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+            .unwrap_or_else(|e| fail("service", e));
+    }
+
+    fn service_main(_arguments: Vec<OsString>) {
+        let Some(args) = ARGS.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+            return;
+        };
+        let (requests, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let handler = move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                let _ = requests.send(ServiceRequest::Stop);
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::ParamChange => {
+                let _ = requests.send(ServiceRequest::Reload);
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        };
+        let Ok(status) = service_control_handler::register(SERVICE_NAME, handler) else {
+            return;
+        };
+        let report = |state, accepted| {
+            let _ = status.set_service_status(ServiceStatus {
+                service_type: ServiceType::OWN_PROCESS,
+                current_state: state,
+                controls_accepted: accepted,
+                exit_code: ServiceExitCode::Win32(0),
+                checkpoint: 0,
+                wait_hint: Duration::default(),
+                process_id: None,
+            });
+        };
+        report(
+            ServiceState::Running,
+            ServiceControlAccept::STOP
+                | ServiceControlAccept::SHUTDOWN
+                | ServiceControlAccept::PARAM_CHANGE,
+        );
+        run_daemon(args, receiver);
+        report(ServiceState::Stopped, ServiceControlAccept::empty());
+    }
+}
+
+fn diff(args: &[String]) {
+    let mut json = false;
+    let mut options = AuditOptions { compare_content: false, ..AuditOptions::default() };
+    let mut positional = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--content" => options.compare_content = true,
+            other => positional.push(other.to_string()),
+        }
+    }
+    let [a, b] = &positional[..] else { usage_error() };
+    let a_root = parse_root_input(a).unwrap_or_else(|e| fail(a, e)).as_dir();
+    let b_root = parse_root_input(b).unwrap_or_else(|e| fail(b, e)).as_dir();
+    let a_storage = open_storage_for(&a_root).unwrap_or_else(|e| fail(a, e));
+    let b_storage = open_storage_for(&b_root).unwrap_or_else(|e| fail(b, e));
+
+    let runtime = tokio::runtime::Runtime::new().expect("tokio runtime starts");
+    let (differences, errors) = runtime.block_on(async {
+        let mut diff = diff_roots(&*a_storage, &a_root, &*b_storage, &b_root, options);
+        let (mut differences, mut errors) = (0, 0);
+        while let Some(found) = diff.next().await {
+            match found {
+                Ok(difference) if json => {
+                    differences += 1;
+                    println!("{}", serde_json::to_string(&difference).expect("difference serializes"));
+                }
+                Ok(difference) => {
+                    differences += 1;
+                    println!("{:?}: {}: {}", difference.kind, difference.path, difference.detail);
+                }
+                Err(e) => {
+                    errors += 1;
+                    eprintln!("otolith: {}", e);
+                }
+            }
+        }
+        (differences, errors)
+    });
+    if !json {
+        println!("{} differences, {} errors", differences, errors);
+    }
+    if differences + errors > 0 {
+        std::process::exit(1);
+    }
+}
+
+fn verify(args: &[String]) {
+    let mut force = false;
+    let mut db_path = None;
+    let mut algorithm = ChecksumAlgorithm::Sha256;
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--force" => force = true,
+            "--db" => match args.next() {
+                Some(path) => db_path = Some(path.clone()),
+                None => usage_error(),
+            },
+            "--algorithm" => match args.next().map(|name| name.parse()) {
+                Some(Ok(parsed)) => algorithm = parsed,
+                Some(Err(e)) => fail("--algorithm", e),
+                None => usage_error(),
+            },
+            other => positional.push(other.to_string()),
+        }
+    }
+    let [sums, root] = &positional[..] else { usage_error() };
+    let text = std::fs::read_to_string(sums).unwrap_or_else(|e| fail(sums, e));
+    let manifest = Manifest::parse_checksum_file(&text, algorithm).unwrap_or_else(|e| fail(sums, e));
+    let root = parse_root_input(root).unwrap_or_else(|e| fail(root, e)).as_dir();
+    let storage: Arc<dyn Storage> = open_storage_for(&root).unwrap_or_else(|e| fail(&root.to_string(), e)).into();
+    let db = match &db_path {
+        Some(path) => ChecksumDb::open(path).unwrap_or_else(|e| fail(path, e)),
+        None => ChecksumDb::in_memory(),
+    };
+    let storage = IncrementalChecksums::new(storage, db.clone()).with_force(force);
+
+    let runtime = tokio::runtime::Runtime::new().expect("tokio runtime starts");
+    let check = runtime.block_on(manifest.verify(&storage, &root));
+    if let Some(path) = &db_path {
+        db.flush().unwrap_or_else(|e| fail(path, e));
+    }
+    for difference in &check.differences {
+        println!("{:?}: {}: {}", difference.kind, difference.path, difference.detail);
+    }
+    let stats = db.stats();
+    println!(
+        "{} verified, {} differ ({} hashed, {} unchanged since the last run)",
+        check.files_verified,
+        check.differences.len(),
+        stats.misses,
+        stats.hits
+    );
+    if !check.is_clean() {
+        std::process::exit(1);
+    }
+}
+
+fn fail(what: &str, error: impl std::fmt::Display) -> ! {
+    eprintln!("otolith: {}: {}", what, error);
+    std::process::exit(1);
+}
+
+fn usage_error() -> ! {
+    eprint!("{}", USAGE);
+    std::process::exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_add_root() {
+        let parsed = parse_add_root(&args(&["--label", "Music", "smb://nas/music/"])).unwrap();
+        assert_eq!(
+            parsed,
+            AddRootArgs {
+                file: None,
+                socket: None,
+                label: Some("Music".to_string()),
+                uri: "smb://nas/music/".to_string(),
+            }
+        );
+        let parsed = parse_add_root(&args(&["/srv/music", "--config", "otolith.json"])).unwrap();
+        assert_eq!(parsed.file, Some(std::path::PathBuf::from("otolith.json")));
+        assert_eq!(parsed.uri, "/srv/music");
+
+        assert_eq!(parse_add_root(&args(&[])), None);
+        assert_eq!(parse_add_root(&args(&["/a", "/b"])), None);
+        assert_eq!(parse_add_root(&args(&["/a", "--label"])), None);
+        assert_eq!(parse_add_root(&args(&["--json", "/a"])), None);
+    }
+}
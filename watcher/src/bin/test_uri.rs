@@ -1,7 +1,7 @@
 use std::env;
 
 use watcher::{
-    open_storage_for, EntryKind, EntryMetadata, Storage, StorageCapabilities, StorageError,
+    open_storage_for, EntryKind, EntryMetadata, StorageCapabilities, StorageError,
     UniversalPath, UniversalPathError,
 };
 
@@ -150,6 +150,7 @@ fn parse_input_to_universal_path(input: &str) -> Result<UniversalPath, Universal
 fn print_universal_path_details(upath: &UniversalPath) {
     println!("\nUniversalPath details:");
     println!("  backend: {:?}", upath.backend());
+    println!("  user: {:?}", upath.user());
     println!("  host: {:?}", upath.host());
     println!("  port: {:?}", upath.port());
     println!("  path: {}", upath.path());
@@ -216,7 +217,7 @@ fn truncate_str(s: &str, max_len: usize) -> String {
         s.to_string()
     } else {
         let mut out = s[..max_len].to_string();
-        out.push_str("…");
+        out.push('…');
         out
     }
 }
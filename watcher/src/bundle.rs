@@ -0,0 +1,508 @@
+use crate::audit::{Difference, DifferenceKind};
+use crate::manifest::{BAGIT_TXT, Manifest, ManifestCheck, ManifestError};
+use crate::storage::{Checksum, ChecksumAlgorithm, ChecksumHasher, Storage, StorageError};
+use crate::universal_path::UniversalPath;
+use serde::Serialize;
+use std::{
+    collections::BTreeSet,
+    pin::Pin,
+    task::{Context, Poll},
+    time::SystemTime,
+};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+const BLOCK: usize = 512;
+
+/// Largest size a ustar header can hold; bigger files get a PAX `size` record
+const USTAR_MAX_SIZE: u64 = 0o77777777777;
+
+/// Limit on manifests and PAX headers read into memory
+const MAX_METADATA: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum BundleError {
+    #[error("malformed bundle: {0}")]
+    Malformed(String),
+    #[error(transparent)]
+    Manifest(#[from] ManifestError),
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BundleReport {
+    pub files: usize,
+    /// Of file contents, not counting tar headers and padding
+    pub bytes: u64,
+}
+
+/// Write `files` below `root` to `out` as a tar for carrying to another machine,
+/// e.g. those `Index::missing_from_mount` found the other side lacks.
+///
+/// The tar unpacks to a BagIt bag: `bagit.txt` and the payload manifest come first,
+/// then each file under `data/` at its path relative to `root`. `import_bundle` reads
+/// it back, and `bagit validate` or `sha256sum -c` can check it by hand.
+pub async fn export_bundle(
+    storage: &dyn Storage,
+    root: &UniversalPath,
+    files: &[UniversalPath],
+    algorithm: ChecksumAlgorithm,
+    out: &mut (dyn AsyncWrite + Send + Unpin),
+) -> Result<BundleReport, BundleError> {
+    let manifest = Manifest::build_from(storage, root, files, algorithm).await?;
+    let mtime = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let manifest_name = format!("manifest-{}.txt", algorithm.name());
+    for (name, body) in [
+        ("bagit.txt", BAGIT_TXT.to_string()),
+        (manifest_name.as_str(), manifest.to_bagit_manifest()),
+    ] {
+        write_header(out, name, body.len() as u64, mtime).await?;
+        out.write_all(body.as_bytes()).await?;
+        pad(out, body.len() as u64).await?;
+    }
+
+    let mut report = BundleReport::default();
+    for (rel, _) in manifest.entries() {
+        let file = root.join_all(&rel.split('/').collect::<Vec<_>>());
+        let meta = storage.stat(&file).await?;
+        let size = meta.size_bytes.ok_or(StorageError::UnsupportedFeature(
+            "bundling files of unknown size",
+        ))?;
+        let modified = meta
+            .modified_at
+            .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map_or(mtime, |d| d.as_secs());
+        write_header(out, &format!("data/{rel}"), size, modified).await?;
+        let mut reader = storage.read_stream(&file).await?.take(size);
+        let copied = tokio::io::copy(&mut reader, out).await?;
+        if copied != size {
+            return Err(BundleError::Storage(StorageError::Io(
+                std::io::Error::other(format!(
+                    "{rel} shrank from {size} to {copied} bytes while bundling"
+                )),
+            )));
+        }
+        pad(out, size).await?;
+        report.files += 1;
+        report.bytes += size;
+    }
+    // End of archive: two zero blocks
+    out.write_all(&[0; 2 * BLOCK]).await?;
+    out.flush().await?;
+    Ok(report)
+}
+
+/// Unpack a bundle from `export_bundle` below `root`, checking each file against the
+/// bundle's manifest as it is written.
+///
+/// A file that fails its checksum is removed again and reported as ContentMismatch;
+/// one the manifest lists but the bundle lacks, as Missing. A file already at the
+/// destination is left alone, counted as verified if it matches and reported as
+/// ContentMismatch if not.
+pub async fn import_bundle(
+    reader: &mut (dyn AsyncRead + Send + Unpin),
+    storage: &dyn Storage,
+    root: &UniversalPath,
+) -> Result<ManifestCheck, BundleError> {
+    let mut check = ManifestCheck {
+        files_verified: 0,
+        differences: Vec::new(),
+    };
+    let mut manifest: Option<Manifest> = None;
+    let mut seen = BTreeSet::new();
+    while let Some(entry) = next_entry(reader).await? {
+        let algorithm = entry
+            .name
+            .strip_prefix("manifest-")
+            .and_then(|name| name.strip_suffix(".txt"))
+            .and_then(|name| name.parse::<ChecksumAlgorithm>().ok());
+        let rel = entry.name.strip_prefix("data/");
+        match (algorithm, rel) {
+            (Some(algorithm), _) if manifest.is_none() => {
+                let text = read_metadata(reader, entry.size).await?;
+                manifest = Some(Manifest::parse_bagit_manifest(&text, algorithm)?);
+            }
+            (_, Some(rel)) if entry.is_file() => {
+                let manifest = manifest.as_ref().ok_or_else(|| {
+                    BundleError::Malformed("payload before the manifest".to_string())
+                })?;
+                let difference = match manifest.get(rel) {
+                    Some(expected) => {
+                        seen.insert(rel.to_string());
+                        import_file(reader, entry.size, storage, root, rel, expected).await?
+                    }
+                    None => {
+                        // Listed paths are checked safe to join; anything else is not used
+                        skip(reader, entry.size).await?;
+                        Some(Difference::new(
+                            rel,
+                            DifferenceKind::Extra,
+                            "in the bundle but not its manifest; skipped".to_string(),
+                        ))
+                    }
+                };
+                match difference {
+                    Some(difference) => check.differences.push(difference),
+                    None => check.files_verified += 1,
+                }
+            }
+            // Directories, bagit.txt, tag manifests
+            _ => skip(reader, entry.size).await?,
+        }
+        skip_padding(reader, entry.size).await?;
+    }
+
+    let manifest = manifest.ok_or_else(|| BundleError::Malformed("no manifest".to_string()))?;
+    for (rel, _) in manifest.entries().filter(|(rel, _)| !seen.contains(*rel)) {
+        check.differences.push(Difference::new(
+            rel,
+            DifferenceKind::Missing,
+            "listed in the manifest but not in the bundle".to_string(),
+        ));
+    }
+    Ok(check)
+}
+
+/// Write one payload file of `size` bytes from `reader`, consuming all of them,
+/// unless it is already there; a difference if it does not match `expected`
+async fn import_file(
+    reader: &mut (dyn AsyncRead + Send + Unpin),
+    size: u64,
+    storage: &dyn Storage,
+    root: &UniversalPath,
+    rel: &str,
+    expected: &Checksum,
+) -> Result<Option<Difference>, BundleError> {
+    let file = root.join_all(&rel.split('/').collect::<Vec<_>>());
+    match storage.checksum(&file, expected.algorithm).await {
+        Ok(existing) => {
+            skip(reader, size).await?;
+            return Ok((&existing != expected).then(|| {
+                Difference::new(
+                    rel,
+                    DifferenceKind::ContentMismatch,
+                    format!("already here as {existing}, left as is"),
+                )
+            }));
+        }
+        Err(StorageError::NotFound) => {}
+        Err(e) => return Err(e.into()),
+    }
+    if let Some(parent) = file.parent() {
+        storage.create_dir(&parent).await?;
+    }
+    let mut hashing = HashingReader {
+        inner: reader.take(size),
+        hasher: Some(ChecksumHasher::new(expected.algorithm)),
+    };
+    let written = storage.write_stream(&file, &mut hashing).await?;
+    let actual = hashing.finish();
+    if written != size {
+        storage.delete(&file).await?;
+        return Err(BundleError::Malformed(format!("{rel} is cut short")));
+    }
+    if &actual == expected {
+        return Ok(None);
+    }
+    storage.delete(&file).await?;
+    Ok(Some(Difference::new(
+        rel,
+        DifferenceKind::ContentMismatch,
+        format!("{actual}, manifest has {expected}; not imported"),
+    )))
+}
+
+/// Hashes what passes through
+struct HashingReader<R> {
+    inner: R,
+    hasher: Option<ChecksumHasher>,
+}
+
+impl<R> HashingReader<R> {
+    fn finish(&mut self) -> Checksum {
+        self.hasher.take().expect("finished once").finish()
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(hasher)) = (&poll, &mut this.hasher) {
+            hasher.update(&buf.filled()[before..]);
+        }
+        poll
+    }
+}
+
+struct TarEntry {
+    name: String,
+    size: u64,
+    typeflag: u8,
+}
+
+impl TarEntry {
+    fn is_file(&self) -> bool {
+        matches!(self.typeflag, b'0' | 0)
+    }
+}
+
+fn padding(size: u64) -> u64 {
+    (BLOCK as u64 - size % BLOCK as u64) % BLOCK as u64
+}
+
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{value:0width$o}", width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+fn ustar_header(name: &[u8], size: u64, mtime: u64, typeflag: u8) -> [u8; BLOCK] {
+    let mut block = [0u8; BLOCK];
+    let name = &name[..name.len().min(100)];
+    block[..name.len()].copy_from_slice(name);
+    octal(&mut block[100..108], 0o644);
+    octal(&mut block[108..116], 0);
+    octal(&mut block[116..124], 0);
+    octal(&mut block[124..136], size.min(USTAR_MAX_SIZE));
+    octal(&mut block[136..148], mtime.min(USTAR_MAX_SIZE));
+    block[156] = typeflag;
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+    block[148..156].fill(b' ');
+    let sum: u32 = block.iter().map(|&b| u32::from(b)).sum();
+    let sum = format!("{sum:06o}\0 ");
+    block[148..156].copy_from_slice(sum.as_bytes());
+    block
+}
+
+/// A PAX record, `<length> <key>=<value>\n`, where the length counts itself
+fn pax_record(key: &str, value: &str) -> String {
+    let body = format!(" {key}={value}\n");
+    let mut len = body.len() + 1;
+    while format!("{len}").len() + body.len() != len {
+        len += 1;
+    }
+    format!("{len}{body}")
+}
+
+/// Header for a regular file, preceded by a PAX header when the name is not short
+/// ASCII or the file too large for ustar
+async fn write_header(
+    out: &mut (dyn AsyncWrite + Send + Unpin),
+    name: &str,
+    size: u64,
+    mtime: u64,
+) -> std::io::Result<()> {
+    let mut pax = String::new();
+    if name.len() > 100 || !name.is_ascii() {
+        pax.push_str(&pax_record("path", name));
+    }
+    if size > USTAR_MAX_SIZE {
+        pax.push_str(&pax_record("size", &size.to_string()));
+    }
+    if !pax.is_empty() {
+        let header = ustar_header(b"././@PaxHeader", pax.len() as u64, mtime, b'x');
+        out.write_all(&header).await?;
+        out.write_all(pax.as_bytes()).await?;
+        pad(out, pax.len() as u64).await?;
+    }
+    out.write_all(&ustar_header(name.as_bytes(), size, mtime, b'0'))
+        .await
+}
+
+async fn pad(out: &mut (dyn AsyncWrite + Send + Unpin), size: u64) -> std::io::Result<()> {
+    out.write_all(&[0; BLOCK][..padding(size) as usize]).await
+}
+
+fn field_str(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let text = field_str(field);
+    u64::from_str_radix(text.trim(), 8).ok()
+}
+
+/// The next entry's header, with any PAX overrides applied; None at the end
+async fn next_entry(
+    reader: &mut (dyn AsyncRead + Send + Unpin),
+) -> Result<Option<TarEntry>, BundleError> {
+    let malformed = |reason: &str| BundleError::Malformed(reason.to_string());
+    let (mut path, mut size_override) = (None, None);
+    loop {
+        let mut block = [0u8; BLOCK];
+        match reader.read_exact(&mut block).await {
+            Ok(_) => {}
+            // Some writers stop after the last entry without the zero blocks
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof && path.is_none() => {
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        }
+        if block.iter().all(|&b| b == 0) {
+            return Ok(None);
+        }
+        let stored = parse_octal(&block[148..156]).ok_or_else(|| malformed("bad checksum"))?;
+        let mut unsigned = block;
+        unsigned[148..156].fill(b' ');
+        if unsigned.iter().map(|&b| u64::from(b)).sum::<u64>() != stored {
+            return Err(malformed("header checksum mismatch"));
+        }
+        let size = parse_octal(&block[124..136]).ok_or_else(|| malformed("bad size"))?;
+        let typeflag = block[156];
+        match typeflag {
+            b'x' => {
+                let records = read_metadata(reader, size).await?;
+                skip_padding(reader, size).await?;
+                for record in records.lines() {
+                    let Some((_, pair)) = record.split_once(' ') else {
+                        continue;
+                    };
+                    match pair.split_once('=') {
+                        Some(("path", value)) => path = Some(value.to_string()),
+                        Some(("size", value)) => size_override = value.parse().ok(),
+                        _ => {}
+                    }
+                }
+                continue;
+            }
+            b'g' => {
+                skip(reader, size).await?;
+                skip_padding(reader, size).await?;
+                continue;
+            }
+            _ => {}
+        }
+        let name = path.take().unwrap_or_else(|| {
+            let name = field_str(&block[..100]);
+            match field_str(&block[345..500]) {
+                prefix if !prefix.is_empty() && &block[257..262] == b"ustar" => {
+                    format!("{prefix}/{name}")
+                }
+                _ => name,
+            }
+        });
+        return Ok(Some(TarEntry {
+            name,
+            size: size_override.unwrap_or(size),
+            typeflag,
+        }));
+    }
+}
+
+async fn read_metadata(
+    reader: &mut (dyn AsyncRead + Send + Unpin),
+    size: u64,
+) -> Result<String, BundleError> {
+    if size > MAX_METADATA {
+        return Err(BundleError::Malformed(format!(
+            "{size} byte manifest or PAX header"
+        )));
+    }
+    let mut data = vec![0; size as usize];
+    reader.read_exact(&mut data).await?;
+    String::from_utf8(data).map_err(|_| BundleError::Malformed("metadata is not UTF-8".into()))
+}
+
+async fn skip(reader: &mut (dyn AsyncRead + Send + Unpin), size: u64) -> std::io::Result<()> {
+    let copied = tokio::io::copy(&mut reader.take(size), &mut tokio::io::sink()).await?;
+    match copied == size {
+        true => Ok(()),
+        false => Err(std::io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+async fn skip_padding(
+    reader: &mut (dyn AsyncRead + Send + Unpin),
+    size: u64,
+) -> std::io::Result<()> {
+    skip(reader, padding(size)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::Catalog;
+    use crate::index::Index;
+    use crate::root::RootConfig;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_bundle_round_trip() {
+        let storage = MemoryStorage::new();
+        let mine = RootConfig::new(UniversalPath::from_uri_str("mem://bundle/mine/").unwrap());
+        let usb = UniversalPath::from_uri_str("mem://bundle/theirs/").unwrap();
+        let long = format!(
+            "{}/01 Jóga.flac",
+            "Björk - Homogenic (Deluxe Edition)".repeat(3)
+        );
+        for (file, data) in [("shared.flac", "both"), (long.as_str(), "jóga")] {
+            let path = mine.path.join_all(&file.split('/').collect::<Vec<_>>());
+            storage.create_dir(&path.parent().unwrap()).await.unwrap();
+            storage.write(&path, data.as_bytes()).await.unwrap();
+        }
+        storage.create_dir(&usb).await.unwrap();
+        storage
+            .write(&usb.join("shared.flac"), b"both")
+            .await
+            .unwrap();
+
+        // What the other side lacks, by their catalog
+        let mut index = Index::new();
+        index.scan(&storage, &mine).await.unwrap();
+        let algorithm = ChecksumAlgorithm::Sha256;
+        index
+            .fill_checksums(&storage, &mine.path, algorithm)
+            .await
+            .unwrap();
+        let manifest = Manifest::build(&storage, &usb, algorithm).await.unwrap();
+        index.mount(Catalog::from_manifest("them", &manifest, SystemTime::now()));
+        let missing: Vec<_> = index
+            .missing_from_mount("them", &mine.path)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.path.clone())
+            .collect();
+        assert_eq!(missing.len(), 1);
+
+        let mut tar = Vec::new();
+        let report = export_bundle(&storage, &mine.path, &missing, algorithm, &mut tar)
+            .await
+            .unwrap();
+        assert_eq!((report.files, report.bytes), (1, 5));
+        assert_eq!(tar.len() % BLOCK, 0);
+
+        let check = import_bundle(&mut tar.as_slice(), &storage, &usb)
+            .await
+            .unwrap();
+        assert!(check.is_clean(), "{:?}", check.differences);
+        assert_eq!(check.files_verified, 1);
+        let imported = usb.join_all(&long.split('/').collect::<Vec<_>>());
+        assert_eq!(storage.read(&imported).await.unwrap(), "jóga".as_bytes());
+
+        // Corrupted in transit: the file is rejected, not imported
+        storage.delete(&imported).await.unwrap();
+        let at = tar.windows(5).position(|w| w == "jóga".as_bytes()).unwrap();
+        tar[at] = b'J';
+        let check = import_bundle(&mut tar.as_slice(), &storage, &usb)
+            .await
+            .unwrap();
+        assert_eq!(check.differences[0].kind, DifferenceKind::ContentMismatch);
+        assert!(matches!(
+            storage.stat(&imported).await,
+            Err(StorageError::NotFound)
+        ));
+    }
+}
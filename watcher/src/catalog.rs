@@ -0,0 +1,178 @@
+use crate::index::Index;
+use crate::manifest::Manifest;
+use crate::storage::{Checksum, StorageBackend};
+use crate::universal_path::UniversalPath;
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+/// Scheme of the virtual root a mounted catalog's files appear under
+pub const CATALOG_SCHEME: &str = "catalog";
+
+/// One file in someone else's library.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    /// `/`-separated, relative to the exporter's root
+    pub path: String,
+    pub size_bytes: Option<u64>,
+    pub checksum: Option<Checksum>,
+}
+
+impl CatalogEntry {
+    pub fn file_name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or(&self.path)
+    }
+
+    /// The directory holding the file, e.g. its album; empty at the root
+    pub fn parent(&self) -> &str {
+        self.path.rsplit_once('/').map_or("", |(parent, _)| parent)
+    }
+}
+
+/// A library listing exported on one machine to be mounted, read-only, in the index
+/// of another, so the two collections can be compared without access to each other's
+/// storage.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Catalog {
+    /// What the catalog is mounted as, e.g. the owner's name
+    pub name: String,
+    pub exported_at: SystemTime,
+    entries: Vec<CatalogEntry>,
+}
+
+impl Catalog {
+    /// The files `index` holds below `root`, with the checksums it has for them
+    pub fn from_index(name: &str, index: &Index, root: &UniversalPath, at: SystemTime) -> Self {
+        let entries = index
+            .iter()
+            .filter_map(|entry| {
+                let segments = entry.path.relative_to(root)?;
+                Some(CatalogEntry {
+                    path: segments.join("/"),
+                    size_bytes: entry.size_bytes,
+                    checksum: entry.checksum.clone(),
+                })
+            })
+            .filter(|entry| !entry.path.is_empty())
+            .collect();
+        Catalog {
+            name: name.to_string(),
+            exported_at: at,
+            entries,
+        }
+    }
+
+    /// A catalog of the files in `manifest`, e.g. a friend's BagIt bag or checksum
+    /// file, which carries no sizes
+    pub fn from_manifest(name: &str, manifest: &Manifest, at: SystemTime) -> Self {
+        let entries = manifest
+            .entries()
+            .map(|(path, checksum)| CatalogEntry {
+                path: path.to_string(),
+                size_bytes: None,
+                checksum: Some(checksum.clone()),
+            })
+            .collect();
+        Catalog {
+            name: name.to_string(),
+            exported_at: at,
+            entries,
+        }
+    }
+
+    pub fn entries(&self) -> &[CatalogEntry] {
+        &self.entries
+    }
+
+    /// `catalog://<name>/`, the read-only root the catalog's files are shown under
+    pub fn root(&self) -> UniversalPath {
+        let backend = StorageBackend::Other(CATALOG_SCHEME.to_string());
+        UniversalPath::from_segments(backend, Some(self.name.clone()), [""; 0]).as_dir()
+    }
+
+    /// Where `entry` appears below `root()`
+    pub fn path_of(&self, entry: &CatalogEntry) -> UniversalPath {
+        self.root()
+            .join_all(&entry.path.split('/').collect::<Vec<_>>())
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("catalog is always serializable")
+    }
+
+    pub fn from_json(data: &[u8]) -> Result<Self, serde_json::Error> {
+        serde_json::from_slice(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::ChangeSource;
+    use crate::root::RootConfig;
+    use crate::storage::{ChecksumAlgorithm, MemoryStorage, Storage};
+
+    #[tokio::test]
+    async fn test_compare_with_mounted_catalog() {
+        let storage = MemoryStorage::new();
+        let theirs = UniversalPath::from_uri_str("mem://catalog/theirs/").unwrap();
+        let mine = RootConfig::new(UniversalPath::from_uri_str("mem://catalog/mine/").unwrap());
+        for (file, data) in [
+            ("Björk/Post/01.flac", "army of me"),
+            ("Björk/Post/02.flac", "hyper-ballad"),
+            ("Portishead/Dummy/01.flac", "mysterons"),
+        ] {
+            let path = theirs.join_all(&file.split('/').collect::<Vec<_>>());
+            storage.create_dir(&path.parent().unwrap()).await.unwrap();
+            storage.write(&path, data.as_bytes()).await.unwrap();
+        }
+        // Filed differently here, and only one of the Björk tracks
+        let post = mine.path.join("Post (1995)");
+        storage.create_dir(&post).await.unwrap();
+        storage
+            .write(&post.join("Army of Me.flac"), b"army of me")
+            .await
+            .unwrap();
+
+        let manifest = Manifest::build(&storage, &theirs, ChecksumAlgorithm::Sha256)
+            .await
+            .unwrap();
+        let exported = Catalog::from_manifest("ana", &manifest, SystemTime::now());
+        let catalog = Catalog::from_json(exported.to_json().as_bytes()).unwrap();
+        assert_eq!(catalog, exported);
+
+        let mut index = Index::new();
+        index.scan(&storage, &mine).await.unwrap();
+        index.mount(catalog);
+        // Without checksums only names and sizes can match, and none do
+        assert_eq!(index.only_in_mount("ana").unwrap().len(), 3);
+
+        index
+            .fill_checksums(&storage, &mine.path, ChecksumAlgorithm::Sha256)
+            .await
+            .unwrap();
+        let missing: Vec<_> = index
+            .only_in_mount("ana")
+            .unwrap()
+            .iter()
+            .map(|entry| entry.path.as_str())
+            .collect();
+        assert_eq!(missing, ["Björk/Post/02.flac", "Portishead/Dummy/01.flac"]);
+        assert_eq!(
+            index.albums_only_in_mount("ana").unwrap(),
+            ["Portishead/Dummy"]
+        );
+        let ana = index.mounted("ana").unwrap();
+        assert_eq!(
+            ana.path_of(&ana.entries()[0]).to_string(),
+            "catalog://ana/Bj%C3%B6rk/Post/01.flac"
+        );
+
+        // Changing a file forgets its checksum
+        let track = post.join("Army of Me.flac");
+        storage.write(&track, b"remastered").await.unwrap();
+        let meta = storage.stat(&track).await.unwrap();
+        index.update(&track, &meta, ChangeSource::Watch, SystemTime::now());
+        assert!(index.get(&track).unwrap().checksum.is_none());
+        assert!(index.only_in_mount("bob").is_none());
+    }
+}
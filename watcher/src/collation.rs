@@ -0,0 +1,154 @@
+use crate::universal_path::UniversalPath;
+#[cfg(feature = "collation")]
+use std::sync::Arc;
+use std::{cmp::Ordering, fmt};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CollationError {
+    #[error("invalid locale {0:?}")]
+    InvalidLocale(String),
+    #[error("no collation data for locale {0:?}")]
+    NoData(String),
+}
+
+/// How names are put in order for listings and reports.
+#[derive(Clone, Default)]
+pub enum Collation {
+    /// By Unicode code point: fast and the same everywhere, but puts "Édith Piaf"
+    /// after "Zappa" and every lowercase name after every uppercase one
+    #[default]
+    CodePoint,
+    /// The order readers of a language expect, from ICU's CLDR data
+    #[cfg(feature = "collation")]
+    Locale(Arc<LocaleCollation>),
+}
+
+#[cfg(feature = "collation")]
+pub struct LocaleCollation {
+    tag: String,
+    collator: icu_collator::CollatorBorrowed<'static>,
+}
+
+impl Collation {
+    /// Collation for a BCP 47 tag such as `sv` or `ja`. Unicode extensions apply, so
+    /// `en-u-kn` sorts "Track 2" before "Track 10".
+    #[cfg(feature = "collation")]
+    pub fn for_locale(tag: &str) -> Result<Self, CollationError> {
+        let locale: icu_locale_core::Locale = tag
+            .parse()
+            .map_err(|_| CollationError::InvalidLocale(tag.to_string()))?;
+        let collator = icu_collator::Collator::try_new((&locale).into(), Default::default())
+            .map_err(|_| CollationError::NoData(tag.to_string()))?;
+        Ok(Collation::Locale(Arc::new(LocaleCollation {
+            tag: locale.to_string(),
+            collator,
+        })))
+    }
+
+    /// The locale tag, or None for code point order
+    pub fn locale(&self) -> Option<&str> {
+        match self {
+            Collation::CodePoint => None,
+            #[cfg(feature = "collation")]
+            Collation::Locale(locale) => Some(&locale.tag),
+        }
+    }
+
+    /// Order of two names. Names the locale counts as equal fall back to code point
+    /// order, so sorting is the same on every run.
+    pub fn compare(&self, a: &str, b: &str) -> Ordering {
+        match self {
+            Collation::CodePoint => a.cmp(b),
+            #[cfg(feature = "collation")]
+            Collation::Locale(locale) => locale.collator.compare(a, b).then_with(|| a.cmp(b)),
+        }
+    }
+
+    /// Order of two paths, segment by segment, so a directory sorts right before its
+    /// contents
+    pub fn compare_paths(&self, a: &UniversalPath, b: &UniversalPath) -> Ordering {
+        self.compare_segments(a.path_segments(), b.path_segments())
+    }
+
+    pub(crate) fn compare_segments<A: AsRef<str>, B: AsRef<str>>(
+        &self,
+        a: &[A],
+        b: &[B],
+    ) -> Ordering {
+        a.iter()
+            .zip(b)
+            .map(|(a, b)| self.compare(a.as_ref(), b.as_ref()))
+            .find(|order| order.is_ne())
+            .unwrap_or_else(|| a.len().cmp(&b.len()))
+    }
+
+    pub fn sort_by_key<T, F: Fn(&T) -> &str>(&self, items: &mut [T], key: F) {
+        items.sort_by(|a, b| self.compare(key(a), key(b)));
+    }
+}
+
+impl PartialEq for Collation {
+    fn eq(&self, other: &Self) -> bool {
+        self.locale() == other.locale()
+    }
+}
+
+impl Eq for Collation {}
+
+impl fmt::Debug for Collation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.locale() {
+            None => f.write_str("CodePoint"),
+            Some(tag) => f.debug_tuple("Locale").field(&tag).finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code_point_order() {
+        let mut names = ["Zappa", "Édith Piaf", "abba", "Björk"];
+        Collation::CodePoint.sort_by_key(&mut names, |name| name);
+        assert_eq!(names, ["Björk", "Zappa", "abba", "Édith Piaf"]);
+        let album = UniversalPath::local("/music/a/");
+        assert_eq!(
+            Collation::CodePoint.compare_paths(&album, &album.join("01.flac")),
+            Ordering::Less
+        );
+    }
+
+    #[cfg(feature = "collation")]
+    #[test]
+    fn test_locale_order() {
+        let names = ["Zappa", "Ödmann", "大貫妙子", "Édith Piaf", "abba", "Björk"];
+        let sorted = |tag| {
+            let mut names = names;
+            Collation::for_locale(tag)
+                .unwrap()
+                .sort_by_key(&mut names, |name| name);
+            names
+        };
+        assert_eq!(
+            sorted("en"),
+            ["abba", "Björk", "Édith Piaf", "Ödmann", "Zappa", "大貫妙子"]
+        );
+        // Swedish sorts Ö as its own letter, after Z
+        assert_eq!(
+            sorted("sv"),
+            ["abba", "Björk", "Édith Piaf", "Zappa", "Ödmann", "大貫妙子"]
+        );
+        let mut tracks = ["Track 10", "Track 2"];
+        Collation::for_locale("en-u-kn")
+            .unwrap()
+            .sort_by_key(&mut tracks, |name| name);
+        assert_eq!(tracks, ["Track 2", "Track 10"]);
+        assert!(matches!(
+            Collation::for_locale("not a locale"),
+            Err(CollationError::InvalidLocale(_))
+        ));
+    }
+}
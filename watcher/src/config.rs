@@ -0,0 +1,894 @@
+use crate::index::FsyncPolicy;
+use crate::root::RootConfig;
+use crate::routing::{Route, Router};
+use crate::snapshot::SnapshotOptions;
+use crate::storage::{
+    CacheConfig, CallOptions, CredentialChain, CredentialError, CredentialStore, EnvCredentials,
+    FdBudget, Scanner, Storage, StorageRegistry, fd_budget,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use thiserror::Error;
+
+/// Version of the configuration file this build writes and reads
+const CONFIG_VERSION: u32 = 1;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("could not read {0}: {1}")]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("could not write {0}: {1}")]
+    Write(PathBuf, #[source] std::io::Error),
+    #[error("invalid configuration file {0}: {1}")]
+    Parse(PathBuf, String),
+    #[error("{var}={value:?}: {reason}")]
+    Env {
+        var: String,
+        value: String,
+        reason: String,
+    },
+    #[error("invalid configuration: {}", .0.join("; "))]
+    Invalid(Vec<String>),
+}
+
+/// Which backends may be opened.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistrySettings {
+    /// Schemes not to open even though this build has their backend, e.g. `ftp`
+    pub disabled_schemes: Vec<String>,
+}
+
+/// Where and how much `CachedStorage` keeps; see `CacheConfig`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheSettings {
+    pub dir: PathBuf,
+    #[serde(default = "CacheSettings::default_max_bytes")]
+    pub max_bytes: u64,
+    #[serde(default = "CacheSettings::default_block_size")]
+    pub block_size: u64,
+    #[serde(default = "CacheSettings::default_stat_ttl_secs")]
+    pub stat_ttl_secs: u64,
+}
+
+impl CacheSettings {
+    /// The defaults of `CacheConfig::new`, in `dir`
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        CacheSettings::from(&CacheConfig::new(dir))
+    }
+
+    fn default_max_bytes() -> u64 {
+        CacheConfig::new("").max_bytes
+    }
+
+    fn default_block_size() -> u64 {
+        CacheConfig::new("").block_size
+    }
+
+    fn default_stat_ttl_secs() -> u64 {
+        CacheConfig::new("").stat_ttl.as_secs()
+    }
+
+    pub fn to_cache_config(&self) -> CacheConfig {
+        CacheConfig::new(&self.dir)
+            .with_max_bytes(self.max_bytes)
+            .with_block_size(self.block_size)
+            .with_stat_ttl(Duration::from_secs(self.stat_ttl_secs))
+    }
+}
+
+impl From<&CacheConfig> for CacheSettings {
+    fn from(config: &CacheConfig) -> Self {
+        CacheSettings {
+            dir: config.dir.clone(),
+            max_bytes: config.max_bytes,
+            block_size: config.block_size,
+            stat_ttl_secs: config.stat_ttl.as_secs(),
+        }
+    }
+}
+
+/// When the index's journal is forced to disk; see `FsyncPolicy`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexFsync {
+    Always,
+    Periodic,
+    #[default]
+    OnFlush,
+}
+
+/// Where the daemon keeps its index between runs; see `FileIndexStore`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndexSettings {
+    /// `None` for `index.json` beside the configuration file
+    pub path: Option<PathBuf>,
+    pub fsync: IndexFsync,
+    /// How soon after a change `periodic` syncs it
+    pub fsync_interval_ms: u64,
+}
+
+impl Default for IndexSettings {
+    fn default() -> Self {
+        IndexSettings {
+            path: None,
+            fsync: IndexFsync::default(),
+            fsync_interval_ms: 1000,
+        }
+    }
+}
+
+impl IndexSettings {
+    pub fn fsync_policy(&self) -> FsyncPolicy {
+        match self.fsync {
+            IndexFsync::Always => FsyncPolicy::Always,
+            IndexFsync::Periodic => {
+                FsyncPolicy::Periodic(Duration::from_millis(self.fsync_interval_ms))
+            }
+            IndexFsync::OnFlush => FsyncPolicy::OnFlush,
+        }
+    }
+}
+
+/// How hard otolith works storage.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Calls a scan keeps in flight; `None` for what suits each backend
+    pub scan_concurrency: Option<usize>,
+    /// Calls a snapshot keeps in flight
+    pub snapshot_concurrency: usize,
+    /// How long a storage call may take; `None` for no limit
+    pub call_timeout_secs: Option<u64>,
+    /// Files and connections open at once across every scan and snapshot; `None`
+    /// for the open files limit less what the rest of the process needs
+    pub max_open_files: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            scan_concurrency: None,
+            snapshot_concurrency: SnapshotOptions::default().concurrency,
+            call_timeout_secs: None,
+            max_open_files: None,
+        }
+    }
+}
+
+/// Where logins come from. Only where to look is configured, never the secrets.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CredentialSettings {
+    /// Read `OTOLITH_<SCHEME>_USER` and the like; see `EnvCredentials`
+    pub environment: bool,
+    /// A credentials file; `None` for `CredentialStore::default_path` if it exists
+    pub file: Option<PathBuf>,
+}
+
+impl Default for CredentialSettings {
+    fn default() -> Self {
+        CredentialSettings {
+            environment: true,
+            file: None,
+        }
+    }
+}
+
+/// Everything otolith is configured with, in one file: which backends may be opened,
+/// the library roots, the cache, limits on how hard storage is worked, and where
+/// credentials come from.
+///
+/// Read with `load`, which starts from the file, lets `OTOLITH_*` environment
+/// variables override it and then validates the result, or put together in code with
+/// `builder`. Missing fields take their defaults, unknown ones are errors, and
+/// `schema` describes the whole format, defaults and variables included.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OtolithConfig {
+    /// Of the file format, for files written by later builds
+    pub version: u32,
+    pub registry: RegistrySettings,
+    pub roots: Vec<RootConfig>,
+    /// Where syncs and ingests send files, by the first route that takes each
+    pub routes: Vec<Route>,
+    /// No cache when `None`
+    pub cache: Option<CacheSettings>,
+    pub index: IndexSettings,
+    pub limits: Limits,
+    pub credentials: CredentialSettings,
+}
+
+impl Default for OtolithConfig {
+    fn default() -> Self {
+        OtolithConfig {
+            version: CONFIG_VERSION,
+            registry: RegistrySettings::default(),
+            roots: Vec::new(),
+            routes: Vec::new(),
+            cache: None,
+            index: IndexSettings::default(),
+            limits: Limits::default(),
+            credentials: CredentialSettings::default(),
+        }
+    }
+}
+
+/// The environment variables `OtolithConfig::apply_env` reads, with what they set
+const ENV_VARS: &[(&str, &str)] = &[
+    (
+        "OTOLITH_DISABLED_SCHEMES",
+        "registry.disabled_schemes, comma-separated",
+    ),
+    ("OTOLITH_CACHE_DIR", "cache.dir, enabling the cache"),
+    ("OTOLITH_CACHE_MAX_BYTES", "cache.max_bytes"),
+    ("OTOLITH_INDEX", "index.path"),
+    ("OTOLITH_SCAN_CONCURRENCY", "limits.scan_concurrency"),
+    (
+        "OTOLITH_SNAPSHOT_CONCURRENCY",
+        "limits.snapshot_concurrency",
+    ),
+    ("OTOLITH_CALL_TIMEOUT_SECS", "limits.call_timeout_secs"),
+    ("OTOLITH_MAX_OPEN_FILES", "limits.max_open_files"),
+    ("OTOLITH_CREDENTIALS", "credentials.file"),
+];
+
+impl OtolithConfig {
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    /// `$OTOLITH_CONFIG`, or else `config.json` in `$XDG_CONFIG_HOME/otolith` or
+    /// `~/.config/otolith`
+    pub fn default_path() -> Option<PathBuf> {
+        if let Some(path) = std::env::var_os("OTOLITH_CONFIG") {
+            return Some(PathBuf::from(path));
+        }
+        Some(crate::storage::config_dir()?.join("config.json"))
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    /// The file at `path` as it is, without overrides or validation
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let json =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        Self::from_json(&json).map_err(|e| ConfigError::Parse(path.to_path_buf(), e.to_string()))
+    }
+
+    /// Write the configuration to `path` as `from_file` reads it, replacing the file
+    /// whole so a reader never sees half of it
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ConfigError> {
+        let path = path.as_ref();
+        let json = serde_json::to_vec_pretty(self).expect("config serializes");
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, json)
+            .and_then(|()| std::fs::rename(&tmp, path))
+            .map_err(|e| ConfigError::Write(path.to_path_buf(), e))
+    }
+
+    /// The configuration to run with: the file at `path`, or at `default_path` if
+    /// there is one there, or the defaults; then the environment; then validated
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let path = match path {
+            Some(path) => Some(path.to_path_buf()),
+            None => Self::default_path().filter(|path| path.is_file()),
+        };
+        let mut config = match path {
+            Some(path) => Self::from_file(path)?,
+            None => OtolithConfig::default(),
+        };
+        config.apply_env(std::env::vars())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Override settings from those of `vars` that are `OTOLITH_*` variables otolith
+    /// knows; see `schema` for which. Empty values are ignored.
+    pub fn apply_env<K, V>(
+        &mut self,
+        vars: impl IntoIterator<Item = (K, V)>,
+    ) -> Result<(), ConfigError>
+    where
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        for (var, value) in vars {
+            let (var, value) = (var.as_ref(), value.as_ref().trim());
+            if value.is_empty() {
+                continue;
+            }
+            let error = |reason: &str| ConfigError::Env {
+                var: var.to_string(),
+                value: value.to_string(),
+                reason: reason.to_string(),
+            };
+            let number = || {
+                value
+                    .parse::<u64>()
+                    .map_err(|_| error("not a whole number"))
+            };
+            match var {
+                "OTOLITH_DISABLED_SCHEMES" => {
+                    let schemes = value.split(',').map(|s| s.trim().to_lowercase());
+                    self.registry.disabled_schemes = schemes.filter(|s| !s.is_empty()).collect();
+                }
+                "OTOLITH_CACHE_DIR" => match &mut self.cache {
+                    Some(cache) => cache.dir = PathBuf::from(value),
+                    None => self.cache = Some(CacheSettings::new(value)),
+                },
+                "OTOLITH_CACHE_MAX_BYTES" => match &mut self.cache {
+                    Some(cache) => cache.max_bytes = number()?,
+                    None => return Err(error("no cache is configured")),
+                },
+                "OTOLITH_INDEX" => self.index.path = Some(PathBuf::from(value)),
+                "OTOLITH_SCAN_CONCURRENCY" => {
+                    self.limits.scan_concurrency = Some(number()? as usize);
+                }
+                "OTOLITH_SNAPSHOT_CONCURRENCY" => {
+                    self.limits.snapshot_concurrency = number()? as usize;
+                }
+                "OTOLITH_CALL_TIMEOUT_SECS" => self.limits.call_timeout_secs = Some(number()?),
+                "OTOLITH_MAX_OPEN_FILES" => {
+                    self.limits.max_open_files = Some(number()? as usize);
+                }
+                "OTOLITH_CREDENTIALS" => self.credentials.file = Some(PathBuf::from(value)),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Every problem with the configuration, not just the first
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        if self.version > CONFIG_VERSION {
+            problems.push(format!(
+                "version {} is newer than this build reads",
+                self.version
+            ));
+        }
+        for scheme in &self.registry.disabled_schemes {
+            if scheme.is_empty() || scheme.contains(':') || *scheme != scheme.to_lowercase() {
+                problems.push(format!(
+                    "disabled scheme {scheme:?} is not a lowercase scheme"
+                ));
+            }
+        }
+        let (mut paths, mut labels) = (HashSet::new(), HashSet::new());
+        for root in &self.roots {
+            let path = root.path.as_dir().to_string();
+            if root.label.trim().is_empty() {
+                problems.push(format!("root {path} has no label"));
+            }
+            if !paths.insert(path.clone()) {
+                problems.push(format!("root {path} is configured twice"));
+            }
+            if !labels.insert(root.label.as_str()) {
+                problems.push(format!("root label {:?} is used twice", root.label));
+            }
+            if let Some(ext) = root.extensions.iter().find(|ext| ext.starts_with('.')) {
+                problems.push(format!("root {path} extension {ext:?} starts with a dot"));
+            }
+        }
+        let (registry, mut route_labels) = (self.registry(), HashSet::new());
+        for route in &self.routes {
+            if route.label.trim().is_empty() {
+                problems.push(format!("route to {} has no label", route.destination));
+            }
+            if !route_labels.insert(route.label.as_str()) {
+                problems.push(format!("route label {:?} is used twice", route.label));
+            }
+            let scheme = route.destination.backend().to_scheme();
+            // Plugins claim their schemes as they load, outside the registry
+            #[cfg(feature = "plugins")]
+            let opened =
+                registry.factory(scheme).is_some() || crate::storage::plugin::is_registered(scheme);
+            #[cfg(not(feature = "plugins"))]
+            let opened = registry.factory(scheme).is_some();
+            if !opened {
+                problems.push(format!(
+                    "route {:?} goes to {}, which no enabled backend opens",
+                    route.label, route.destination
+                ));
+            }
+        }
+        if let Some(cache) = &self.cache {
+            if cache.dir.as_os_str().is_empty() {
+                problems.push("cache dir is empty".to_string());
+            }
+            if cache.block_size == 0 {
+                problems.push("cache block_size is 0".to_string());
+            } else if cache.max_bytes < cache.block_size {
+                problems.push("cache max_bytes is smaller than one block".to_string());
+            }
+        }
+        if let Some(path) = &self.index.path
+            && path.as_os_str().is_empty()
+        {
+            problems.push("index path is empty".to_string());
+        }
+        if self.index.fsync == IndexFsync::Periodic && self.index.fsync_interval_ms == 0 {
+            problems.push("index fsync_interval_ms is 0".to_string());
+        }
+        if self.limits.scan_concurrency == Some(0) {
+            problems.push("limits scan_concurrency is 0".to_string());
+        }
+        if self.limits.snapshot_concurrency == 0 {
+            problems.push("limits snapshot_concurrency is 0".to_string());
+        }
+        if self.limits.call_timeout_secs == Some(0) {
+            problems.push("limits call_timeout_secs is 0".to_string());
+        }
+        if self.limits.max_open_files == Some(0) {
+            problems.push("limits max_open_files is 0".to_string());
+        }
+        if let Some(file) = &self.credentials.file
+            && !file.is_file()
+        {
+            problems.push(format!(
+                "credentials file {} does not exist",
+                file.display()
+            ));
+        }
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(ConfigError::Invalid(problems)),
+        }
+    }
+
+    /// The built-in backends, less those disabled
+    pub fn registry(&self) -> StorageRegistry {
+        let mut registry = StorageRegistry::with_builtins();
+        for scheme in &self.registry.disabled_schemes {
+            registry.unregister(scheme);
+        }
+        registry
+    }
+
+    /// The providers the credential settings name, in the order `CredentialChain`
+    /// asks them
+    pub fn credential_provider(&self) -> Result<CredentialChain, CredentialError> {
+        let mut chain = CredentialChain::new();
+        if self.credentials.environment {
+            chain = chain.with(EnvCredentials);
+        }
+        let file = match &self.credentials.file {
+            Some(file) => Some(file.clone()),
+            None => CredentialStore::default_path().filter(|path| path.is_file()),
+        };
+        if let Some(file) = file {
+            chain = chain.with(CredentialStore::from_file(file)?);
+        }
+        Ok(chain)
+    }
+
+    /// The routes, in the order they are tried
+    pub fn router(&self) -> Router {
+        Router::from(self.routes.clone())
+    }
+
+    /// Where the index is kept: the configured path, or `index.json` beside `file`,
+    /// the configuration file read, or beside `default_path`
+    pub fn index_path(&self, file: Option<&Path>) -> Option<PathBuf> {
+        if let Some(path) = &self.index.path {
+            return Some(path.clone());
+        }
+        let config = file.map(Path::to_path_buf).or_else(Self::default_path)?;
+        Some(config.with_file_name("index.json"))
+    }
+
+    pub fn cache_config(&self) -> Option<CacheConfig> {
+        self.cache.as_ref().map(CacheSettings::to_cache_config)
+    }
+
+    /// A scanner for `storage` within the limits
+    pub fn scanner(&self, storage: Arc<dyn Storage>) -> Scanner {
+        let scanner = Scanner::new(storage).with_fd_budget(self.fd_budget().clone());
+        match self.limits.scan_concurrency {
+            Some(concurrency) => scanner.with_concurrency(concurrency),
+            None => scanner,
+        }
+    }
+
+    /// The budget of open files every scan and snapshot shares, set to
+    /// `max_open_files` if that is configured
+    pub fn fd_budget(&self) -> &'static FdBudget {
+        let budget = fd_budget();
+        if let Some(limit) = self.limits.max_open_files {
+            budget.set_limit(limit);
+        }
+        budget
+    }
+
+    pub fn snapshot_options(&self) -> SnapshotOptions {
+        SnapshotOptions::default().with_concurrency(self.limits.snapshot_concurrency)
+    }
+
+    /// Options for a storage call within the limits
+    pub fn call_options(&self) -> CallOptions {
+        let options = CallOptions::default();
+        match self.limits.call_timeout_secs {
+            Some(secs) => options.with_timeout(Duration::from_secs(secs)),
+            None => options,
+        }
+    }
+
+    /// A JSON Schema of the configuration file, with each field's default and, as
+    /// `x-env`, the environment variable that overrides it
+    pub fn schema() -> Value {
+        let defaults = OtolithConfig::default();
+        let cache = CacheSettings::new("");
+        let env = |name: &str| {
+            let found = ENV_VARS.iter().any(|(var, _)| *var == name);
+            debug_assert!(found, "{name} is read by apply_env");
+            Value::String(name.to_string())
+        };
+        let root = json!({
+            "type": "object",
+            "description": "A library root: where it lives and what to index under it",
+            "required": ["path", "label", "extensions"],
+            "properties": {
+                "path": {"type": "string", "format": "uri", "description": "Where the root lives, as a URI"},
+                "label": {"type": "string", "description": "Display name for the root"},
+                "extensions": {
+                    "type": "array",
+                    "items": {"type": "string"},
+                    "description": "Lowercase extensions, without the dot, to index; empty for everything",
+                },
+                "quota": {
+                    "type": "object",
+                    "description": "Limits on what may be written under the root",
+                    "properties": {
+                        "max_bytes": {"type": ["integer", "null"], "minimum": 0},
+                        "max_files": {"type": ["integer", "null"], "minimum": 0},
+                    },
+                },
+                "names": {
+                    "type": "string",
+                    "enum": ["unicode", "ascii"],
+                    "default": "unicode",
+                    "description": "How names are written under the root; ascii transliterates for players that take nothing else",
+                },
+                "hooks": {
+                    "type": "array",
+                    "items": {"type": "object"},
+                    "description": "Scripts run on changes under the root, in builds with scripting",
+                },
+            },
+        });
+        let route = json!({
+            "type": "object",
+            "additionalProperties": false,
+            "description": "Sends the files its filter takes to a destination root, the first matching route winning",
+            "required": ["label", "destination"],
+            "properties": {
+                "label": {"type": "string", "description": "Names the route"},
+                "filter": {
+                    "type": "object",
+                    "description": "Which files the route takes, as walks and scans filter them",
+                    "properties": {
+                        "include": {"type": "array", "items": {"type": "string"}},
+                        "exclude": {"type": "array", "items": {"type": "string"}},
+                        "extensions": {"type": "array", "items": {"type": "string"}},
+                        "max_size_bytes": {"type": ["integer", "null"], "minimum": 0},
+                        "skip_hidden": {"type": "boolean"},
+                    },
+                },
+                "destination": {
+                    "type": "string",
+                    "format": "uri",
+                    "description": "Where the files go, opened through the storage registry",
+                },
+            },
+        });
+        json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "title": "otolith configuration",
+            "type": "object",
+            "x-env-vars": ENV_VARS
+                .iter()
+                .map(|(var, sets)| (var.to_string(), Value::from(*sets)))
+                .collect::<serde_json::Map<_, _>>(),
+            "additionalProperties": false,
+            "properties": {
+                "version": {
+                    "type": "integer",
+                    "maximum": CONFIG_VERSION,
+                    "default": defaults.version,
+                    "description": "Of the file format",
+                },
+                "registry": {
+                    "type": "object",
+                    "additionalProperties": false,
+                    "description": "Which backends may be opened",
+                    "properties": {
+                        "disabled_schemes": {
+                            "type": "array",
+                            "items": {"type": "string"},
+                            "default": defaults.registry.disabled_schemes,
+                            "description": "Schemes not to open even though this build has their backend",
+                            "x-env": env("OTOLITH_DISABLED_SCHEMES"),
+                        },
+                    },
+                },
+                "roots": {"type": "array", "items": root, "default": []},
+                "routes": {"type": "array", "items": route, "default": []},
+                "cache": {
+                    "type": ["object", "null"],
+                    "additionalProperties": false,
+                    "required": ["dir"],
+                    "default": null,
+                    "description": "Local cache of remote stats and file data; none when null",
+                    "properties": {
+                        "dir": {
+                            "type": "string",
+                            "description": "A local directory owned by the cache",
+                            "x-env": env("OTOLITH_CACHE_DIR"),
+                        },
+                        "max_bytes": {
+                            "type": "integer",
+                            "minimum": 1,
+                            "default": cache.max_bytes,
+                            "description": "File data kept before the least recently used is dropped",
+                            "x-env": env("OTOLITH_CACHE_MAX_BYTES"),
+                        },
+                        "block_size": {
+                            "type": "integer",
+                            "minimum": 1,
+                            "default": cache.block_size,
+                            "description": "Reads are cached in aligned blocks of this size",
+                        },
+                        "stat_ttl_secs": {
+                            "type": "integer",
+                            "minimum": 0,
+                            "default": cache.stat_ttl_secs,
+                            "description": "How long a stat is trusted before asking the server again",
+                        },
+                    },
+                },
+                "index": {
+                    "type": "object",
+                    "additionalProperties": false,
+                    "description": "Where the daemon keeps its index between runs",
+                    "properties": {
+                        "path": {
+                            "type": ["string", "null"],
+                            "default": null,
+                            "description": "The index file, its journal beside it; null for index.json beside the configuration file",
+                            "x-env": env("OTOLITH_INDEX"),
+                        },
+                        "fsync": {
+                            "type": "string",
+                            "enum": ["always", "periodic", "on_flush"],
+                            "default": "on_flush",
+                            "description": "When the journal is forced to disk: after every change, soon after one, or once a scan is done",
+                        },
+                        "fsync_interval_ms": {
+                            "type": "integer",
+                            "minimum": 1,
+                            "default": defaults.index.fsync_interval_ms,
+                            "description": "How soon after a change periodic syncs it",
+                        },
+                    },
+                },
+                "limits": {
+                    "type": "object",
+                    "additionalProperties": false,
+                    "description": "How hard storage is worked",
+                    "properties": {
+                        "scan_concurrency": {
+                            "type": ["integer", "null"],
+                            "minimum": 1,
+                            "default": defaults.limits.scan_concurrency,
+                            "description": "Calls a scan keeps in flight; null for what suits each backend",
+                            "x-env": env("OTOLITH_SCAN_CONCURRENCY"),
+                        },
+                        "snapshot_concurrency": {
+                            "type": "integer",
+                            "minimum": 1,
+                            "default": defaults.limits.snapshot_concurrency,
+                            "description": "Calls a snapshot keeps in flight",
+                            "x-env": env("OTOLITH_SNAPSHOT_CONCURRENCY"),
+                        },
+                        "call_timeout_secs": {
+                            "type": ["integer", "null"],
+                            "minimum": 1,
+                            "default": defaults.limits.call_timeout_secs,
+                            "description": "How long a storage call may take; null for no limit",
+                            "x-env": env("OTOLITH_CALL_TIMEOUT_SECS"),
+                        },
+                        "max_open_files": {
+                            "type": ["integer", "null"],
+                            "minimum": 1,
+                            "default": defaults.limits.max_open_files,
+                            "description": "Files and connections open at once across every scan and snapshot; null for the open files limit less a reserve",
+                            "x-env": env("OTOLITH_MAX_OPEN_FILES"),
+                        },
+                    },
+                },
+                "credentials": {
+                    "type": "object",
+                    "additionalProperties": false,
+                    "description": "Where logins come from; secrets are never kept here",
+                    "properties": {
+                        "environment": {
+                            "type": "boolean",
+                            "default": defaults.credentials.environment,
+                            "description": "Read OTOLITH_<SCHEME>_USER and the like",
+                        },
+                        "file": {
+                            "type": ["string", "null"],
+                            "default": null,
+                            "description": "A credentials file; null for the default one if it exists",
+                            "x-env": env("OTOLITH_CREDENTIALS"),
+                        },
+                    },
+                },
+            },
+        })
+    }
+}
+
+/// Puts an `OtolithConfig` together in code, validating it on `build`.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigBuilder {
+    config: OtolithConfig,
+}
+
+impl ConfigBuilder {
+    pub fn with_root(mut self, root: RootConfig) -> Self {
+        self.config.roots.push(root);
+        self
+    }
+
+    /// Tried after the routes already added
+    pub fn with_route(mut self, route: Route) -> Self {
+        self.config.routes.push(route);
+        self
+    }
+
+    pub fn with_disabled_scheme<S: Into<String>>(mut self, scheme: S) -> Self {
+        self.config.registry.disabled_schemes.push(scheme.into());
+        self
+    }
+
+    pub fn with_cache(mut self, cache: &CacheConfig) -> Self {
+        self.config.cache = Some(CacheSettings::from(cache));
+        self
+    }
+
+    pub fn with_index_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.config.index.path = Some(path.into());
+        self
+    }
+
+    pub fn with_index_fsync(mut self, fsync: IndexFsync) -> Self {
+        self.config.index.fsync = fsync;
+        self
+    }
+
+    pub fn with_scan_concurrency(mut self, concurrency: usize) -> Self {
+        self.config.limits.scan_concurrency = Some(concurrency);
+        self
+    }
+
+    pub fn with_snapshot_concurrency(mut self, concurrency: usize) -> Self {
+        self.config.limits.snapshot_concurrency = concurrency;
+        self
+    }
+
+    /// Rounded down to whole seconds
+    pub fn with_call_timeout(mut self, timeout: Duration) -> Self {
+        self.config.limits.call_timeout_secs = Some(timeout.as_secs());
+        self
+    }
+
+    pub fn with_max_open_files(mut self, limit: usize) -> Self {
+        self.config.limits.max_open_files = Some(limit);
+        self
+    }
+
+    pub fn with_credentials_file<P: Into<PathBuf>>(mut self, file: P) -> Self {
+        self.config.credentials.file = Some(file.into());
+        self
+    }
+
+    /// Leave credentials in the environment unread
+    pub fn without_env_credentials(mut self) -> Self {
+        self.config.credentials.environment = false;
+        self
+    }
+
+    pub fn build(self) -> Result<OtolithConfig, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::universal_path::UniversalPath;
+
+    #[test]
+    fn test_config() {
+        let root = |uri: &str| RootConfig::new(UniversalPath::from_uri_str(uri).unwrap());
+        let config = OtolithConfig::builder()
+            .with_root(root("smb://nas/music/"))
+            .with_cache(&CacheConfig::new("/var/cache/otolith"))
+            .with_scan_concurrency(4)
+            .with_disabled_scheme("ftp")
+            .with_index_fsync(IndexFsync::Periodic)
+            .build()
+            .unwrap();
+        assert_eq!(
+            config.index.fsync_policy(),
+            FsyncPolicy::Periodic(Duration::from_secs(1))
+        );
+        assert_eq!(
+            config.index_path(Some(Path::new("/etc/otolith/config.json"))),
+            Some(PathBuf::from("/etc/otolith/index.json"))
+        );
+        assert_eq!(config.limits.snapshot_concurrency, 8);
+        assert!(!config.registry().schemes().contains(&"ftp"));
+        assert_eq!(
+            config.cache_config().unwrap().stat_ttl,
+            Duration::from_secs(300)
+        );
+
+        // Missing fields default, unknown ones are refused
+        let json = serde_json::to_string(&config).unwrap();
+        assert_eq!(OtolithConfig::from_json(&json).unwrap(), config);
+        let parsed = OtolithConfig::from_json(r#"{"cache": {"dir": "/tmp/c"}}"#).unwrap();
+        assert_eq!(parsed.cache.unwrap().block_size, 64 * 1024);
+        assert!(OtolithConfig::from_json(r#"{"limitz": {}}"#).is_err());
+        let tmp = tempfile::tempdir().unwrap();
+        config.save(tmp.path().join("config.json")).unwrap();
+        assert_eq!(
+            OtolithConfig::from_file(tmp.path().join("config.json")).unwrap(),
+            config
+        );
+
+        let mut config = config;
+        let vars = [
+            ("OTOLITH_SCAN_CONCURRENCY", "16"),
+            ("OTOLITH_CACHE_MAX_BYTES", "1048576"),
+            ("HOME", "/home/someone"),
+        ];
+        config.apply_env(vars).unwrap();
+        assert_eq!(config.limits.scan_concurrency, Some(16));
+        assert_eq!(config.cache.as_ref().unwrap().max_bytes, 1 << 20);
+        let bad = config.apply_env([("OTOLITH_CALL_TIMEOUT_SECS", "soon")]);
+        assert!(matches!(bad, Err(ConfigError::Env { .. })));
+
+        let invalid = OtolithConfig::builder()
+            .with_root(root("smb://nas/music/"))
+            .with_root(root("smb://nas/music"))
+            .with_snapshot_concurrency(0)
+            .build();
+        let Err(ConfigError::Invalid(problems)) = invalid else {
+            panic!("duplicate roots and no concurrency should not validate");
+        };
+        assert_eq!(problems.len(), 3, "{problems:?}");
+
+        let schema = OtolithConfig::schema();
+        let limits = &schema["properties"]["limits"]["properties"];
+        assert_eq!(limits["snapshot_concurrency"]["default"], 8);
+        assert_eq!(
+            limits["scan_concurrency"]["x-env"],
+            "OTOLITH_SCAN_CONCURRENCY"
+        );
+    }
+}
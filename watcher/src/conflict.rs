@@ -0,0 +1,172 @@
+use crate::storage::{Storage, StorageError};
+use crate::universal_path::UniversalPath;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+/// Names tried before giving up on finding a free one
+const MAX_ATTEMPTS: u32 = 1000;
+
+/// What to call a file that cannot go where it belongs because a different version
+/// is already there. Every policy but `Overwrite` keeps both: the version already
+/// there stays put and the new one is written under the name the policy picks, which
+/// is checked to be free on the storage it goes to, so it works the same on every
+/// backend.
+///
+/// Templates name the file in its own directory, from `{name}` (the file name up to
+/// its extension), `{ext}` (the extension with its dot, or nothing), `{date}`
+/// (`2026-10-14`), `{time}` (`153012`, UTC) and `{n}`, which counts up from 1 until
+/// the name is free. A template without `{n}` gets ` 2`, ` 3` and so on before the
+/// extension when its name is taken too.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Replace the version already there
+    #[default]
+    Overwrite,
+    /// Name the new version from a template, e.g. `{name} (conflict {date}){ext}`
+    Suffix { template: String },
+    /// `Track (1).flac`, `Track (2).flac` and so on
+    Numbered,
+    /// Put the new version in `dir` under its own name, numbered if that is taken
+    Quarantine { dir: UniversalPath },
+}
+
+impl ConflictPolicy {
+    /// The template `Suffix` policies are usually configured with
+    pub const DEFAULT_TEMPLATE: &'static str = "{name} (conflict {date}){ext}";
+
+    pub fn suffix<S: Into<String>>(template: S) -> Self {
+        ConflictPolicy::Suffix {
+            template: template.into(),
+        }
+    }
+
+    /// Whether the policy keeps the version already there
+    pub fn keeps_both(&self) -> bool {
+        !matches!(self, ConflictPolicy::Overwrite)
+    }
+
+    /// Where a new version of `path`, arriving at `at`, goes on `storage`: `path`
+    /// itself when overwriting, otherwise the first name the policy yields that
+    /// nothing on `storage` has. Fails with `AlreadyExists` if none of the first
+    /// thousand are free.
+    pub async fn resolve(
+        &self,
+        storage: &dyn Storage,
+        path: &UniversalPath,
+        at: SystemTime,
+    ) -> Result<UniversalPath, StorageError> {
+        let (dir, template) = match self {
+            ConflictPolicy::Overwrite => return Ok(path.clone()),
+            ConflictPolicy::Suffix { template } => (None, template.as_str()),
+            ConflictPolicy::Numbered => (None, "{name} ({n}){ext}"),
+            ConflictPolicy::Quarantine { dir } => (Some(dir.as_dir()), "{name}{ext}"),
+        };
+        if let Some(dir) = &dir {
+            storage.create_dir(dir).await?;
+        }
+        for n in 1..=MAX_ATTEMPTS {
+            let Some(name) = conflict_name(template, path, at, n) else {
+                continue;
+            };
+            let candidate = match &dir {
+                Some(dir) => dir.join(&name),
+                None => path.with_file_name(&name),
+            };
+            match storage.stat(&candidate).await {
+                Err(StorageError::NotFound) => return Ok(candidate),
+                Err(e) => return Err(e),
+                Ok(_) => {}
+            }
+        }
+        Err(StorageError::AlreadyExists)
+    }
+}
+
+/// `template` filled in for attempt `n` at naming `path`, or none if it does not
+/// make a file name
+fn conflict_name(template: &str, path: &UniversalPath, at: SystemTime, n: u32) -> Option<String> {
+    let counted = template.contains("{n}");
+    let ext = path
+        .extension()
+        .map(|ext| format!(".{ext}"))
+        .unwrap_or_default();
+    let name = path.stem().unwrap_or("file");
+    let at = DateTime::<Utc>::from(at);
+    let ext_template = match !counted && n > 1 {
+        true => format!(" {n}{{ext}}"),
+        false => "{ext}".to_string(),
+    };
+    let template = match template.contains("{ext}") {
+        true => template.replace("{ext}", &ext_template),
+        false if !counted && n > 1 => format!("{template} {n}"),
+        false => template.to_string(),
+    };
+    let filled = template
+        .replace("{name}", name)
+        .replace("{ext}", &ext)
+        .replace("{date}", &at.format("%Y-%m-%d").to_string())
+        .replace("{time}", &at.format("%H%M%S").to_string())
+        .replace("{n}", &n.to_string());
+    // A name that resolves outside its directory, or to nothing, is not a name
+    match filled.is_empty() || filled.contains('/') || filled == "." || filled == ".." {
+        true => None,
+        false => Some(filled),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_conflict_names() {
+        let storage = MemoryStorage::new();
+        let album = UniversalPath::from_uri_str("mem://nas/Album/").unwrap();
+        storage.create_dir(&album).await.unwrap();
+        let track = album.join("01 Intro.flac");
+        storage.write(&track, b"ours").await.unwrap();
+        // 2026-10-14 15:30:12 UTC
+        let at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_791_991_812);
+        let name = |path: UniversalPath| path.file_name().unwrap().to_string();
+
+        let suffix = ConflictPolicy::suffix(ConflictPolicy::DEFAULT_TEMPLATE);
+        let renamed = suffix.resolve(&storage, &track, at).await.unwrap();
+        assert_eq!(name(renamed.clone()), "01 Intro (conflict 2026-10-14).flac");
+        // Taken, so counted
+        storage.write(&renamed, b"theirs").await.unwrap();
+        let again = suffix.resolve(&storage, &track, at).await.unwrap();
+        assert_eq!(name(again), "01 Intro (conflict 2026-10-14) 2.flac");
+        let timed = ConflictPolicy::suffix("{name}.{time}{ext}");
+        let timed = timed.resolve(&storage, &track, at).await.unwrap();
+        assert_eq!(name(timed), "01 Intro.153012.flac");
+
+        storage
+            .write(&album.join("01 Intro (1).flac"), b"")
+            .await
+            .unwrap();
+        let numbered = ConflictPolicy::Numbered;
+        let numbered = numbered.resolve(&storage, &track, at).await.unwrap();
+        assert_eq!(numbered, album.join("01 Intro (2).flac"));
+
+        let dir = UniversalPath::from_uri_str("mem://nas/.conflicts/").unwrap();
+        let quarantine = ConflictPolicy::Quarantine { dir: dir.clone() };
+        let first = quarantine.resolve(&storage, &track, at).await.unwrap();
+        assert_eq!(first, dir.join("01 Intro.flac"));
+        storage.write(&first, b"theirs").await.unwrap();
+        let second = quarantine.resolve(&storage, &track, at).await.unwrap();
+        assert_eq!(second, dir.join("01 Intro 2.flac"));
+
+        let overwrite = ConflictPolicy::Overwrite;
+        assert_eq!(
+            overwrite.resolve(&storage, &track, at).await.unwrap(),
+            track
+        );
+        let policy: ConflictPolicy =
+            serde_json::from_str(r#"{"policy": "suffix", "template": "{name}~{ext}"}"#).unwrap();
+        assert_eq!(policy, ConflictPolicy::suffix("{name}~{ext}"));
+    }
+}
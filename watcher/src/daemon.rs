@@ -0,0 +1,1412 @@
+use crate::{
+    index::{ChangeKind, IndexStore, IndexStoreError, SharedIndex, SharedIndexStore},
+    jobs::{Job, JobError, JobId, JobQueue},
+    redact::redactor,
+    root::RootConfig,
+    status::{DaemonStatus, StatusReport, rfc3339},
+    storage::{
+        HostHealth, OperationId, OperationInfo, OperationRegistry, QuotaStorage, Storage,
+        StorageError, TrackedStorage, open_storage_for, operations,
+    },
+    tenant::{Tenant, TenantError, Tenants},
+    universal_path::UniversalPath,
+    volumes::VolumeChange,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::{
+    collections::BTreeMap,
+    io,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::broadcast,
+};
+
+/// Events a subscriber can fall behind by before it misses some
+const EVENT_BACKLOG: usize = 1024;
+/// Longest request line read; requests are a method and a few parameters
+const MAX_REQUEST: usize = 64 * 1024;
+
+#[derive(Debug, Error)]
+pub enum DaemonError {
+    #[error("no root labelled {0:?}")]
+    UnknownRoot(String),
+    #[error("a root labelled {0:?} is already watched")]
+    DuplicateRoot(String),
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error(transparent)]
+    Index(#[from] IndexStoreError),
+    #[error(transparent)]
+    Jobs(#[from] JobError),
+    #[error("no operation {0} is running")]
+    UnknownOperation(OperationId),
+    #[error("only a daemon made for a tenant can be added as one")]
+    NotATenant,
+    #[error("root {0} overlaps a root of the daemon's own library")]
+    LibraryOverlap(Box<UniversalPath>),
+    #[error(transparent)]
+    Tenant(#[from] TenantError),
+}
+
+/// Something the daemon did, as subscribers to its control API are told of it.
+/// Paths are redacted by the process-wide `redactor()`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum DaemonEvent {
+    RootAdded {
+        label: String,
+    },
+    RootRemoved {
+        label: String,
+    },
+    /// The root's volume came back at another drive letter, so the root is now at
+    /// `path`
+    RootMoved {
+        label: String,
+        path: String,
+    },
+    /// The volume the root is on was removed
+    VolumeRemoved {
+        label: String,
+    },
+    /// The volume the root is on is back
+    VolumeArrived {
+        label: String,
+    },
+    ScanStarted {
+        label: String,
+    },
+    ScanFinished {
+        label: String,
+        changes: usize,
+    },
+    ScanFailed {
+        label: String,
+        error: String,
+    },
+    /// A change a scan recorded in the index
+    Changed {
+        label: String,
+        path: String,
+        #[serde(flatten)]
+        kind: ChangeKind,
+    },
+}
+
+type Opener = dyn Fn(&UniversalPath) -> Result<Arc<dyn Storage>, StorageError> + Send + Sync;
+
+/// The daemons serving tenants, beside the library of the daemon they were added to
+#[derive(Default)]
+struct TenantDaemons {
+    tenants: Tenants,
+    daemons: BTreeMap<String, Daemon>,
+}
+
+/// The library kept running: its roots, the index of what is under them and the
+/// status the daemon reports, with the events subscribers see as it works. Cloning
+/// gives another handle on the same daemon, for the control API and the tasks it
+/// starts.
+///
+/// Tenants' libraries are daemons of their own, made with `for_tenant` and added
+/// with `add_tenant`; control requests with a `tenant` parameter go to them.
+#[derive(Clone)]
+pub struct Daemon {
+    index: SharedIndex,
+    status: DaemonStatus,
+    roots: Arc<Mutex<BTreeMap<String, RootConfig>>>,
+    events: broadcast::Sender<DaemonEvent>,
+    open: Arc<Opener>,
+    /// What each root with a quota holds against it, by label
+    quotas: Arc<Mutex<BTreeMap<String, Arc<QuotaStorage>>>>,
+    /// Where calls to the roots' storage are registered while they run
+    operations: OperationRegistry,
+    store: Option<SharedIndexStore>,
+    /// Kept in `store` when there is one
+    jobs: Arc<Mutex<JobQueue>>,
+    /// The tenant this daemon serves, whose roots every root must be under
+    scope: Option<Arc<Tenant>>,
+    tenants: Arc<Mutex<TenantDaemons>>,
+}
+
+impl Daemon {
+    /// A daemon with no roots yet, opening storage with `open_storage_for`
+    pub fn new(index: SharedIndex, status: DaemonStatus) -> Self {
+        Daemon {
+            index,
+            status,
+            roots: Arc::default(),
+            events: broadcast::channel(EVENT_BACKLOG).0,
+            open: Arc::new(|path| open_storage_for(path).map(Arc::from)),
+            quotas: Arc::default(),
+            operations: operations().clone(),
+            store: None,
+            jobs: Arc::default(),
+            scope: None,
+            tenants: Arc::default(),
+        }
+    }
+
+    /// A daemon for `tenant` alone, watching its roots and indexing them in `index`,
+    /// apart from every other library. Storage is opened with the tenant's
+    /// credentials only, roots outside the tenant's are refused, and its events go
+    /// to its own subscribers. Fails if two of the tenant's roots share a label.
+    pub fn for_tenant(tenant: Tenant, index: SharedIndex) -> Result<Self, DaemonError> {
+        let scope = Arc::new(tenant);
+        let opening = scope.clone();
+        let mut daemon = Daemon::new(index, DaemonStatus::new()).with_opener(move |path| {
+            opening
+                .open_storage(path)
+                .map(Arc::from)
+                .map_err(|e| match e {
+                    TenantError::Storage(e) => e,
+                    e => StorageError::Io(io::Error::new(io::ErrorKind::PermissionDenied, e)),
+                })
+        });
+        // Its operations are its own to see and cancel
+        daemon.operations = OperationRegistry::new();
+        for root in &scope.roots {
+            daemon.add_root(root.clone())?;
+        }
+        daemon.scope = Some(scope);
+        Ok(daemon)
+    }
+
+    /// Write what each scan changes to `store`, flushing it once the scan is done,
+    /// and keep the job queue there, as the store has it. The index is expected to
+    /// start as the store has it, as `Index::from_store` reads it.
+    pub fn with_store<S: IndexStore + 'static>(mut self, store: S) -> Result<Self, DaemonError> {
+        let store: SharedIndexStore = Arc::new(Mutex::new(Box::new(store)));
+        self.jobs = Arc::new(Mutex::new(JobQueue::in_store(store.clone())?));
+        self.store = Some(store);
+        Ok(self)
+    }
+
+    /// Open each root's storage with `open` instead
+    pub fn with_opener<F>(mut self, open: F) -> Self
+    where
+        F: Fn(&UniversalPath) -> Result<Arc<dyn Storage>, StorageError> + Send + Sync + 'static,
+    {
+        self.open = Arc::new(open);
+        self
+    }
+
+    pub fn index(&self) -> &SharedIndex {
+        &self.index
+    }
+
+    pub fn status(&self) -> &DaemonStatus {
+        &self.status
+    }
+
+    /// The operations running on the roots' storage. A tenant's daemon has a registry
+    /// of its own; any other shares the process-wide `operations()`.
+    pub fn operations(&self) -> &OperationRegistry {
+        &self.operations
+    }
+
+    /// The queue of background jobs, for workers to claim them from and report on
+    pub fn jobs(&self) -> std::sync::MutexGuard<'_, JobQueue> {
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The tenant this daemon was made for, if any
+    pub fn tenant_scope(&self) -> Option<&Tenant> {
+        self.scope.as_deref()
+    }
+
+    /// Serve `daemon`, made with `for_tenant`, as its tenant's. No two tenants' roots
+    /// may overlap, nor may a tenant's overlap this daemon's own.
+    pub fn add_tenant(&self, daemon: Daemon) -> Result<(), DaemonError> {
+        let tenant = daemon.scope.as_deref().ok_or(DaemonError::NotATenant)?;
+        let ours: Vec<UniversalPath> = self
+            .lock_roots()
+            .values()
+            .map(|root| root.path.normalize())
+            .collect();
+        for root in &tenant.roots {
+            let theirs = root.path.normalize();
+            if ours.iter().any(|ours| {
+                theirs.relative_to(ours).is_some() || ours.relative_to(&theirs).is_some()
+            }) {
+                return Err(DaemonError::LibraryOverlap(Box::new(root.path.clone())));
+            }
+        }
+        let id = tenant.id.clone();
+        let mut tenants = self.lock_tenants();
+        tenants.tenants.insert(tenant.clone())?;
+        tenants.daemons.insert(id, daemon);
+        Ok(())
+    }
+
+    pub fn remove_tenant(&self, id: &str) -> Result<Daemon, DaemonError> {
+        let mut tenants = self.lock_tenants();
+        tenants.tenants.remove(id);
+        tenants
+            .daemons
+            .remove(id)
+            .ok_or_else(|| TenantError::UnknownTenant(id.to_string()).into())
+    }
+
+    /// The daemon serving the tenant `id`
+    pub fn tenant(&self, id: &str) -> Result<Daemon, DaemonError> {
+        self.lock_tenants()
+            .daemons
+            .get(id)
+            .cloned()
+            .ok_or_else(|| TenantError::UnknownTenant(id.to_string()).into())
+    }
+
+    /// The ids of the tenants served
+    pub fn tenants(&self) -> Vec<String> {
+        self.lock_tenants().daemons.keys().cloned().collect()
+    }
+
+    /// The roots watched, by label
+    pub fn roots(&self) -> Vec<RootConfig> {
+        self.lock_roots().values().cloned().collect()
+    }
+
+    /// Events from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<DaemonEvent> {
+        self.events.subscribe()
+    }
+
+    /// Start watching `root`; it is not scanned until asked
+    pub fn add_root(&self, root: RootConfig) -> Result<(), DaemonError> {
+        if let Some(tenant) = &self.scope
+            && tenant.root_for(&root.path).is_none()
+        {
+            return Err(TenantError::OutsideRoots {
+                tenant: tenant.id.clone(),
+                path: Box::new(root.path),
+            }
+            .into());
+        }
+        let label = root.label.clone();
+        {
+            let mut roots = self.lock_roots();
+            if roots.contains_key(&label) {
+                return Err(DaemonError::DuplicateRoot(label));
+            }
+            self.status.add_root(&label, &root.path);
+            roots.insert(label.clone(), root);
+        }
+        self.emit(DaemonEvent::RootAdded { label });
+        Ok(())
+    }
+
+    /// Stop watching the root labelled `label`. What the index holds under it is kept.
+    pub fn remove_root(&self, label: &str) -> Result<RootConfig, DaemonError> {
+        let root = self
+            .lock_roots()
+            .remove(label)
+            .ok_or_else(|| DaemonError::UnknownRoot(label.to_string()))?;
+        self.lock_quotas().remove(label);
+        self.status.remove_root(label);
+        self.emit(DaemonEvent::RootRemoved {
+            label: label.to_string(),
+        });
+        Ok(root)
+    }
+
+    /// Watch exactly `roots`, as after the configuration is read again: roots no
+    /// longer among them are removed, and new roots and roots whose configuration
+    /// changed are added. Returns the labels of those added, which want scanning.
+    pub fn reload(&self, roots: Vec<RootConfig>) -> Vec<String> {
+        let current: BTreeMap<String, RootConfig> = self.lock_roots().clone();
+        let wanted: BTreeMap<String, RootConfig> = roots
+            .into_iter()
+            .map(|root| (root.label.clone(), root))
+            .collect();
+        for (label, root) in &current {
+            if wanted.get(label) != Some(root) {
+                let _ = self.remove_root(label);
+            }
+        }
+        let mut added = Vec::new();
+        for (label, root) in wanted {
+            if current.get(&label) != Some(&root) && self.add_root(root).is_ok() {
+                added.push(label);
+            }
+        }
+        added
+    }
+
+    /// Follow roots whose volumes moved, went away or came back, as
+    /// `VolumeRegistry::update` found. A root whose volume was removed is reported
+    /// unreachable until it is back. Returns the labels of roots that want scanning.
+    pub fn apply_volume_changes(&self, changes: Vec<VolumeChange>) -> Vec<String> {
+        let mut rescan = Vec::new();
+        for change in changes {
+            match change {
+                VolumeChange::Moved { label, path } => {
+                    let Some(root) = self.lock_roots().get_mut(&label).map(|root| {
+                        root.path = path.clone();
+                        root.clone()
+                    }) else {
+                        continue;
+                    };
+                    self.lock_quotas().remove(&label);
+                    self.status.add_root(&label, &root.path);
+                    self.emit(DaemonEvent::RootMoved {
+                        label: label.clone(),
+                        path: redactor().path(&path),
+                    });
+                    rescan.push(label);
+                }
+                VolumeChange::Removed { label } => {
+                    self.status.set_root_health(&label, HostHealth::Unreachable);
+                    self.emit(DaemonEvent::VolumeRemoved { label });
+                }
+                VolumeChange::Arrived { label } => {
+                    self.status.set_root_health(&label, HostHealth::Healthy);
+                    self.emit(DaemonEvent::VolumeArrived {
+                        label: label.clone(),
+                    });
+                    rescan.push(label);
+                }
+            }
+        }
+        rescan
+    }
+
+    /// Scan the root labelled `label` and bring its part of the index up to date,
+    /// returning the number of changes recorded
+    pub async fn rescan(&self, label: &str) -> Result<usize, DaemonError> {
+        let root = self
+            .lock_roots()
+            .get(label)
+            .cloned()
+            .ok_or_else(|| DaemonError::UnknownRoot(label.to_string()))?;
+        self.emit(DaemonEvent::ScanStarted {
+            label: label.to_string(),
+        });
+        let scanned = self.scan(label, &root).await;
+        self.status.record_scan(
+            label,
+            SystemTime::now(),
+            scanned.as_ref().err().map(|e| e.to_string()),
+        );
+        match &scanned {
+            Ok(changes) => self.emit(DaemonEvent::ScanFinished {
+                label: label.to_string(),
+                changes: *changes,
+            }),
+            Err(e) => self.emit(DaemonEvent::ScanFailed {
+                label: label.to_string(),
+                error: e.to_string(),
+            }),
+        }
+        scanned
+    }
+
+    /// The storage to write under the root labelled `label` with, keeping to the
+    /// root's quota if it has one. The root's usage is measured the first time and
+    /// again by each scan; writes through the storage count against it in between.
+    pub async fn storage(&self, label: &str) -> Result<Arc<dyn Storage>, DaemonError> {
+        let root = self
+            .lock_roots()
+            .get(label)
+            .cloned()
+            .ok_or_else(|| DaemonError::UnknownRoot(label.to_string()))?;
+        if let Some(quota) = self.lock_quotas().get(label) {
+            return Ok(quota.clone());
+        }
+        let storage = self.open(&root.path)?;
+        if root.quota.is_unlimited() {
+            return Ok(storage);
+        }
+        Ok(self.measure_quota(label, &root, storage).await?)
+    }
+
+    /// `rescan` every root in turn, returning what each came to
+    pub async fn rescan_all(&self) -> Vec<(String, Result<usize, DaemonError>)> {
+        let labels: Vec<_> = self.lock_roots().keys().cloned().collect();
+        let mut results = Vec::with_capacity(labels.len());
+        for label in labels {
+            let result = self.rescan(&label).await;
+            results.push((label, result));
+        }
+        results
+    }
+
+    async fn scan(&self, label: &str, root: &RootConfig) -> Result<usize, DaemonError> {
+        let storage = self.open(&root.path)?;
+        self.status.check_root(label, &*storage, &root.path).await;
+        let mut writer = self.index.write().await;
+        let before = writer.history().len();
+        let scanning = self.operations.begin("scan", Some(&root.path));
+        let changes = scanning.run(writer.scan(&*storage, root)).await?;
+        let recorded: Vec<_> = writer.history()[before..].to_vec();
+        // Kept from the index, as of the last scan, until the store has it too
+        if let Some(store) = &self.store {
+            let mut store = store.lock().unwrap_or_else(|e| e.into_inner());
+            writer.persist(&recorded, store.as_mut())?;
+        }
+        writer.commit();
+        for record in recorded {
+            self.emit(DaemonEvent::Changed {
+                label: label.to_string(),
+                path: redactor().path(&record.path),
+                kind: record.kind,
+            });
+        }
+        if !root.quota.is_unlimited() {
+            self.measure_quota(label, root, storage).await?;
+        }
+        Ok(changes)
+    }
+
+    /// Measure what the root holds through `storage` and keep to its quota from now
+    /// on, reporting its usage in the status
+    async fn measure_quota(
+        &self,
+        label: &str,
+        root: &RootConfig,
+        storage: Arc<dyn Storage>,
+    ) -> Result<Arc<QuotaStorage>, StorageError> {
+        let quota = Arc::new(QuotaStorage::measure(storage, &root.path, root.quota).await?);
+        // Not for a root removed or changed while it was measured
+        if self.lock_roots().get(label) == Some(root) {
+            self.lock_quotas().insert(label.to_string(), quota.clone());
+            self.status.set_quota(label, quota.clone());
+        }
+        Ok(quota)
+    }
+
+    /// Storage for `path`, each call through it registered in `operations`
+    fn open(&self, path: &UniversalPath) -> Result<Arc<dyn Storage>, StorageError> {
+        let storage = (self.open)(path)?;
+        Ok(Arc::new(
+            TrackedStorage::new(storage).with_registry(self.operations.clone()),
+        ))
+    }
+
+    fn emit(&self, event: DaemonEvent) {
+        // No subscribers is not an error
+        let _ = self.events.send(event);
+    }
+
+    fn lock_roots(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, RootConfig>> {
+        self.roots.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_quotas(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, Arc<QuotaStorage>>> {
+        self.quotas.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_tenants(&self) -> std::sync::MutexGuard<'_, TenantDaemons> {
+        self.tenants.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A JSON-RPC 2.0 request, one per line. One without an id is a notification, and
+/// gets no response. Any request may name a tenant in a `tenant` parameter, to be
+/// carried out by that tenant's daemon.
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default, deserialize_with = "present")]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct AddRootParams {
+    uri: String,
+    label: Option<String>,
+    extensions: Option<Vec<String>>,
+    #[serde(default)]
+    read_only: bool,
+}
+
+#[derive(Deserialize)]
+struct RescanParams {
+    label: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CancelParams {
+    id: Option<OperationId>,
+    job: Option<JobId>,
+}
+
+#[derive(Deserialize)]
+struct JobParams {
+    job: Option<JobId>,
+}
+
+/// A JSON-RPC error: the code and what to tell the client
+type RpcError = (i64, String);
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// For errors the daemon met carrying out a request
+const DAEMON_ERROR: i64 = -32000;
+
+/// Answer control requests on one connection until the client goes away.
+///
+/// Requests are JSON-RPC 2.0, a line each, and so are the answers:
+///
+/// - `status` is the daemon's `StatusReport`.
+/// - `roots` lists the roots watched.
+/// - `add_root` with `uri` and optionally `label`, `extensions` and `read_only` starts
+///   watching a root and scans it.
+/// - `rescan` with an optional `label` scans that root, or every one, in the
+///   background; subscribers hear how it went.
+/// - `ops` lists the operations running on the roots' storage, scans among them.
+/// - `jobs` lists the job queue, and whether it is paused.
+/// - `pause` and `resume` with a `job` id hold that queued job back or let it run
+///   again; without one, they stop and restart the handing out of every job.
+/// - `cancel` with an operation's `id` asks it to stop; with a `job` id it cancels the
+///   job, stopping the operations running it.
+/// - `subscribe` sends each `DaemonEvent` from then on as an `event` notification.
+pub async fn serve_control<S>(stream: S, daemon: Daemon) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader);
+    let mut events: Option<broadcast::Receiver<DaemonEvent>> = None;
+    let mut line = Vec::new();
+    loop {
+        // Reading a line is cancelled for an event now and then, but what it has read
+        // stays in `line` for the next go
+        let mut limited = (&mut lines).take((MAX_REQUEST - line.len()) as u64);
+        let read = tokio::select! {
+            read = limited.read_until(b'\n', &mut line) => read?,
+            event = next_event(&mut events) => {
+                let notification = json!({"jsonrpc": "2.0", "method": "event", "params": event});
+                send(&mut writer, &notification).await?;
+                continue;
+            }
+        };
+        if read == 0 && line.is_empty() {
+            return Ok(());
+        }
+        if line.last() != Some(&b'\n') && read != 0 {
+            if line.len() < MAX_REQUEST {
+                continue;
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "control request too long",
+            ));
+        }
+        let request = std::mem::take(&mut line);
+        if request.trim_ascii().is_empty() {
+            continue;
+        }
+        let (id, answer) = match serde_json::from_slice::<Request>(&request) {
+            Ok(request) => {
+                let subscribing = request.method == "subscribe";
+                let answer = match target(&daemon, &request.params) {
+                    Ok(target) => {
+                        let answer = call(&target, &request.method, request.params).await;
+                        if subscribing && answer.is_ok() {
+                            events = Some(target.subscribe());
+                        }
+                        answer
+                    }
+                    Err(e) => Err(e),
+                };
+                (request.id, answer)
+            }
+            Err(e) => (Some(Value::Null), Err((PARSE_ERROR, e.to_string()))),
+        };
+        let Some(id) = id else {
+            continue;
+        };
+        let response = match answer {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {"code": code, "message": message},
+            }),
+        };
+        send(&mut writer, &response).await?;
+    }
+}
+
+/// Make one control request over `stream`, as a client of `serve_control`, and
+/// return its result. An error answer is returned as an `Other` error with its
+/// message; event notifications on the way are skipped.
+pub async fn control_call<S>(stream: S, method: &str, params: Value) -> io::Result<Value>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    send(
+        &mut writer,
+        &json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params}),
+    )
+    .await?;
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let mut answer: Value = serde_json::from_str(&line)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if answer.get("id") != Some(&json!(1)) {
+            continue;
+        }
+        if let Some(error) = answer.get("error") {
+            let message = error["message"]
+                .as_str()
+                .unwrap_or("control request failed");
+            return Err(io::Error::other(message.to_string()));
+        }
+        return Ok(answer["result"].take());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "the daemon closed the connection without answering",
+    ))
+}
+
+/// The daemon a request is for: that of the tenant its `tenant` parameter names, or
+/// else `daemon`
+fn target(daemon: &Daemon, params: &Value) -> Result<Daemon, RpcError> {
+    match params.get("tenant") {
+        None | Some(Value::Null) => Ok(daemon.clone()),
+        Some(Value::String(id)) => daemon
+            .tenant(id)
+            .map_err(|e| (INVALID_PARAMS, e.to_string())),
+        Some(_) => Err((INVALID_PARAMS, "tenant is not a string".to_string())),
+    }
+}
+
+/// Some(null) for an `id` given as null, which unlike a missing one still wants a
+/// response
+fn present<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(deserializer).map(Some)
+}
+
+/// The next event for a subscribed connection; never, for one that is not
+async fn next_event(events: &mut Option<broadcast::Receiver<DaemonEvent>>) -> Value {
+    let Some(receiver) = events else {
+        return std::future::pending().await;
+    };
+    match receiver.recv().await {
+        Ok(event) => serde_json::to_value(event).expect("event serializes"),
+        Err(broadcast::error::RecvError::Lagged(missed)) => {
+            json!({"event": "lagged", "missed": missed})
+        }
+        Err(broadcast::error::RecvError::Closed) => {
+            *events = None;
+            std::future::pending().await
+        }
+    }
+}
+
+async fn send<W: AsyncWrite + Unpin>(writer: &mut W, message: &Value) -> io::Result<()> {
+    let mut line = serde_json::to_vec(message).expect("message serializes");
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    writer.flush().await
+}
+
+async fn call(daemon: &Daemon, method: &str, params: Value) -> Result<Value, RpcError> {
+    let daemon_error = |e: DaemonError| (DAEMON_ERROR, e.to_string());
+    match method {
+        "status" => {
+            let report: StatusReport = daemon.status().report();
+            Ok(serde_json::to_value(report).expect("report serializes"))
+        }
+        "roots" => {
+            let roots = daemon.roots();
+            Ok(serde_json::to_value(roots).expect("roots serialize"))
+        }
+        "tenants" => Ok(json!(daemon.tenants())),
+        "ops" => {
+            let ops: Vec<Value> = daemon
+                .operations()
+                .list()
+                .into_iter()
+                .map(operation)
+                .collect();
+            Ok(json!(ops))
+        }
+        "jobs" => {
+            let jobs = daemon.jobs();
+            let listed: Vec<Value> = jobs.jobs().map(job).collect();
+            Ok(json!({ "paused": jobs.is_paused(), "jobs": listed }))
+        }
+        "pause" | "resume" => {
+            let params: JobParams = parse_params(params)?;
+            let mut jobs = daemon.jobs();
+            let done = match (method, params.job) {
+                ("pause", Some(id)) => jobs.pause(id),
+                ("pause", None) => jobs.pause_all(),
+                (_, Some(id)) => jobs.resume(id),
+                (_, None) => jobs.resume_all(),
+            };
+            done.map_err(|e| daemon_error(e.into()))?;
+            Ok(json!({ "paused": jobs.is_paused() }))
+        }
+        "cancel" => match parse_params(params)? {
+            CancelParams {
+                id: Some(id),
+                job: None,
+            } => {
+                if !daemon.operations().cancel(id) {
+                    return Err(daemon_error(DaemonError::UnknownOperation(id)));
+                }
+                Ok(json!({ "cancelled": id }))
+            }
+            CancelParams {
+                id: None,
+                job: Some(id),
+            } => {
+                daemon
+                    .jobs()
+                    .cancel(id)
+                    .map_err(|e| daemon_error(e.into()))?;
+                daemon.operations().cancel_job(id);
+                Ok(json!({ "cancelled": id }))
+            }
+            _ => Err((INVALID_PARAMS, "give one of id and job".to_string())),
+        },
+        "add_root" => {
+            let params: AddRootParams = parse_params(params)?;
+            let path = UniversalPath::from_uri_str(&params.uri)
+                .map_err(|e| (INVALID_PARAMS, e.to_string()))?
+                .as_dir();
+            let mut root = RootConfig::new(path);
+            if let Some(label) = params.label {
+                root.label = label;
+            }
+            if let Some(extensions) = params.extensions {
+                root.extensions = extensions;
+            }
+            root.read_only = params.read_only;
+            let label = root.label.clone();
+            daemon.add_root(root).map_err(daemon_error)?;
+            spawn_rescan(daemon, vec![label.clone()]);
+            Ok(json!({ "label": label }))
+        }
+        "rescan" => {
+            let params: RescanParams = parse_params(params)?;
+            let labels = match params.label {
+                Some(label) if daemon.lock_roots().contains_key(&label) => vec![label],
+                Some(label) => return Err(daemon_error(DaemonError::UnknownRoot(label))),
+                None => daemon.lock_roots().keys().cloned().collect(),
+            };
+            spawn_rescan(daemon, labels.clone());
+            Ok(json!({ "started": labels }))
+        }
+        "subscribe" => Ok(json!(true)),
+        _ => Err((METHOD_NOT_FOUND, format!("no method {method:?}"))),
+    }
+}
+
+fn parse_params<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, RpcError> {
+    let params = match params {
+        Value::Null => json!({}),
+        params => params,
+    };
+    serde_json::from_value(params).map_err(|e| (INVALID_PARAMS, e.to_string()))
+}
+
+/// An operation as `ops` answers with it, its path redacted
+fn operation(op: OperationInfo) -> Value {
+    json!({
+        "id": op.id,
+        "kind": op.kind,
+        "path": op.path.as_ref().map(|path| redactor().path(path)),
+        "job": op.job,
+        "label": op.label,
+        "started_at": rfc3339(op.started_at),
+        "elapsed_ms": op.elapsed.as_millis() as u64,
+        "bytes": op.bytes,
+        "cancelling": op.cancelling,
+    })
+}
+
+/// A job as `jobs` answers with it
+fn job(job: &Job) -> Value {
+    json!({
+        "id": job.id,
+        "kind": job.kind,
+        "payload": job.payload,
+        "priority": job.priority,
+        "state": job.state,
+        "attempts": job.attempts,
+        "last_error": job.last_error,
+        "created_at": rfc3339(job.created_at),
+        "updated_at": rfc3339(job.updated_at),
+        "not_before": job.not_before.map(rfc3339),
+    })
+}
+
+/// Scan `labels` one after another on a task of their own; how it goes is reported
+/// to subscribers and in the status
+fn spawn_rescan(daemon: &Daemon, labels: Vec<String>) {
+    let daemon = daemon.clone();
+    tokio::spawn(async move {
+        for label in labels {
+            let _ = daemon.rescan(&label).await;
+        }
+    });
+}
+
+/// Where the control socket is by default: `otolith.sock` in `$XDG_RUNTIME_DIR`, or
+/// where that is not set, in an `otolith-<uid>` directory of the temporary directory
+/// that only this user may enter
+#[cfg(unix)]
+pub fn default_control_socket() -> io::Result<std::path::PathBuf> {
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        Some(dir) => std::path::PathBuf::from(dir),
+        None => user_dir(&std::env::temp_dir())?,
+    };
+    Ok(dir.join("otolith.sock"))
+}
+
+/// `otolith-<uid>` in `parent`, created with mode 0700 if missing. One another user
+/// made first, to listen in on this user's daemon, is refused.
+#[cfg(unix)]
+fn user_dir(parent: &std::path::Path) -> io::Result<std::path::PathBuf> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+    // SAFETY: getuid takes nothing and cannot fail
+    let uid = unsafe { libc::getuid() };
+    let dir = parent.join(format!("otolith-{uid}"));
+    match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e),
+    }
+    let meta = std::fs::symlink_metadata(&dir)?;
+    if !meta.is_dir() || meta.uid() != uid {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not a directory of this user's", dir.display()),
+        ));
+    }
+    if meta.mode() & 0o077 != 0 {
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(dir)
+}
+
+/// The named pipe the control API is served on by default
+#[cfg(windows)]
+pub const DEFAULT_CONTROL_PIPE: &str = r"\\.\pipe\otolith";
+
+/// Answer control connections on `listener` until it fails, each on a task of its own
+#[cfg(unix)]
+pub async fn serve_control_socket(
+    listener: tokio::net::UnixListener,
+    daemon: Daemon,
+) -> io::Result<()> {
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let daemon = daemon.clone();
+        tokio::spawn(async move {
+            // Clients that go away mid-request are not our concern
+            let _ = serve_control(socket, daemon).await;
+        });
+    }
+}
+
+/// Answer control connections on the named pipe `name` until creating an instance of
+/// it fails, each on a task of its own
+#[cfg(windows)]
+pub async fn serve_control_pipe(name: &str, daemon: Daemon) -> io::Result<()> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+    let mut server = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(name)?;
+    loop {
+        server.connect().await?;
+        let connected = std::mem::replace(&mut server, ServerOptions::new().create(name)?);
+        let daemon = daemon.clone();
+        tokio::spawn(async move {
+            let _ = serve_control(connected, daemon).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryStorage, Quota};
+    use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+
+    struct Client {
+        lines: tokio::io::Lines<BufReader<ReadHalf<DuplexStream>>>,
+        writer: WriteHalf<DuplexStream>,
+    }
+
+    impl Client {
+        fn connect(daemon: &Daemon) -> Self {
+            let (ours, theirs) = tokio::io::duplex(64 * 1024);
+            tokio::spawn(serve_control(theirs, daemon.clone()));
+            let (reader, writer) = tokio::io::split(ours);
+            Client {
+                lines: BufReader::new(reader).lines(),
+                writer,
+            }
+        }
+
+        async fn send(&mut self, line: &str) {
+            self.writer.write_all(line.as_bytes()).await.unwrap();
+            self.writer.write_all(b"\n").await.unwrap();
+        }
+
+        async fn next(&mut self) -> Value {
+            let line = self.lines.next_line().await.unwrap().unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+
+        async fn call(&mut self, method: &str, params: Value) -> Value {
+            let request = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+            self.send(&request.to_string()).await;
+            self.next().await
+        }
+
+        /// The next event notification, skipping others
+        async fn event(&mut self, name: &str) -> Value {
+            loop {
+                let message = self.next().await;
+                if message["method"] == "event" && message["params"]["event"] == name {
+                    return message["params"].clone();
+                }
+            }
+        }
+    }
+
+    async fn daemon() -> (Daemon, MemoryStorage) {
+        let storage = MemoryStorage::new();
+        let music = UniversalPath::from_uri_str("mem://nas/music/").unwrap();
+        storage.create_dir(&music).await.unwrap();
+        storage
+            .write(&music.join("01.flac"), b"flac")
+            .await
+            .unwrap();
+        let shared = storage.clone();
+        let daemon = Daemon::new(SharedIndex::default(), DaemonStatus::new())
+            .with_opener(move |_| Ok(Arc::new(shared.clone()) as Arc<dyn Storage>));
+        (daemon, storage)
+    }
+
+    #[tokio::test]
+    async fn test_rescan_updates_index_and_status() {
+        let (daemon, storage) = daemon().await;
+        let music = UniversalPath::from_uri_str("mem://nas/music/").unwrap();
+        daemon.add_root(RootConfig::new(music.clone())).unwrap();
+        assert!(matches!(
+            daemon.add_root(RootConfig::new(music.clone())),
+            Err(DaemonError::DuplicateRoot(_))
+        ));
+        let mut events = daemon.subscribe();
+        assert_eq!(daemon.rescan("music").await.unwrap(), 1);
+        assert_eq!(daemon.index().snapshot().len(), 1);
+        assert!(daemon.status().report().roots[0].last_scan_at.is_some());
+        let started = events.recv().await.unwrap();
+        assert_eq!(
+            started,
+            DaemonEvent::ScanStarted {
+                label: "music".to_string()
+            }
+        );
+
+        storage
+            .write(&music.join("02.flac"), b"flac")
+            .await
+            .unwrap();
+        assert_eq!(daemon.rescan("music").await.unwrap(), 1);
+        assert!(matches!(
+            daemon.rescan("video").await,
+            Err(DaemonError::UnknownRoot(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_writes_keep_to_quota() {
+        let (daemon, storage) = daemon().await;
+        let music = UniversalPath::from_uri_str("mem://nas/music/").unwrap();
+        let mut root = RootConfig::new(music.clone());
+        root.quota = Quota::default().with_max_bytes(16);
+        daemon.add_root(root).unwrap();
+
+        let writer = daemon.storage("music").await.unwrap();
+        writer.write(&music.join("02.flac"), b"flac").await.unwrap();
+        assert!(matches!(
+            writer.write(&music.join("03.flac"), &[0; 10]).await,
+            Err(StorageError::QuotaExceeded { .. })
+        ));
+        let report = daemon.status().report();
+        assert_eq!(report.roots[0].quota_utilization, Some(0.5));
+
+        // A scan sees what was written around the daemon
+        storage.delete(&music.join("01.flac")).await.unwrap();
+        daemon.rescan("music").await.unwrap();
+        let report = daemon.status().report();
+        assert_eq!(report.roots[0].quota_utilization, Some(0.25));
+    }
+
+    #[tokio::test]
+    async fn test_store_outlives_daemon() {
+        use crate::index::{FileIndexStore, Index};
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.json");
+        let (daemon, storage) = daemon().await;
+        let daemon = daemon
+            .with_store(FileIndexStore::open(&path).unwrap())
+            .unwrap();
+        let music = UniversalPath::from_uri_str("mem://nas/music/").unwrap();
+        daemon.add_root(RootConfig::new(music.clone())).unwrap();
+        storage
+            .write(&music.join("02.flac"), b"flac")
+            .await
+            .unwrap();
+        daemon.rescan("music").await.unwrap();
+        storage.delete(&music.join("01.flac")).await.unwrap();
+        daemon.rescan("music").await.unwrap();
+        drop(daemon);
+
+        let store = FileIndexStore::open(&path).unwrap();
+        let index = Index::from_store(&store).unwrap();
+        let paths: Vec<_> = index.iter().map(|entry| entry.path.clone()).collect();
+        assert_eq!(paths, [music.join("02.flac")]);
+    }
+
+    #[tokio::test]
+    async fn test_reload_replaces_roots() {
+        let (daemon, _storage) = daemon().await;
+        let music = RootConfig::new(UniversalPath::from_uri_str("mem://nas/music/").unwrap());
+        let video = RootConfig::new(UniversalPath::from_uri_str("mem://nas/video/").unwrap());
+        daemon.add_root(music.clone()).unwrap();
+        daemon.add_root(video.clone()).unwrap();
+
+        let mut lossless = music.clone();
+        lossless.extensions = vec!["flac".to_string()];
+        let books = RootConfig::new(UniversalPath::from_uri_str("mem://nas/books/").unwrap());
+        let mut events = daemon.subscribe();
+        let added = daemon.reload(vec![lossless.clone(), books.clone()]);
+        assert_eq!(added, ["books", "music"]);
+        assert_eq!(daemon.roots(), [books, lossless]);
+        let labels: Vec<_> = daemon
+            .status()
+            .report()
+            .roots
+            .into_iter()
+            .map(|r| r.label)
+            .collect();
+        assert_eq!(labels, ["books", "music"]);
+        assert_eq!(
+            events.recv().await.unwrap(),
+            DaemonEvent::RootRemoved {
+                label: "music".to_string()
+            }
+        );
+        assert!(daemon.reload(daemon.roots()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_volume_changes() {
+        let (daemon, _storage) = daemon().await;
+        daemon
+            .add_root(RootConfig::new(
+                UniversalPath::local("E:\\Music\\").as_dir(),
+            ))
+            .unwrap();
+        let rescan = daemon.apply_volume_changes(vec![VolumeChange::Removed {
+            label: "Music".to_string(),
+        }]);
+        assert!(rescan.is_empty());
+        assert!(!daemon.status().report().roots[0].online);
+
+        let moved = UniversalPath::local("F:\\Music\\").as_dir();
+        let rescan = daemon.apply_volume_changes(vec![
+            VolumeChange::Moved {
+                label: "Music".to_string(),
+                path: moved.clone(),
+            },
+            VolumeChange::Moved {
+                label: "Video".to_string(),
+                path: moved.clone(),
+            },
+        ]);
+        assert_eq!(rescan, ["Music"]);
+        assert_eq!(daemon.roots()[0].path, moved);
+        let status = &daemon.status().report().roots[0];
+        assert!(status.online);
+        assert_eq!(status.root, redactor().path(&moved));
+    }
+
+    #[tokio::test]
+    async fn test_control_requests() {
+        let (daemon, _storage) = daemon().await;
+        let mut client = Client::connect(&daemon);
+        let answer = client.call("subscribe", Value::Null).await;
+        assert_eq!(answer["result"], true);
+
+        let answer = client
+            .call(
+                "add_root",
+                json!({"uri": "mem://nas/music/", "label": "Music"}),
+            )
+            .await;
+        assert_eq!(answer["result"]["label"], "Music");
+        let finished = client.event("scan_finished").await;
+        assert_eq!(
+            (&finished["label"], &finished["changes"]),
+            (&json!("Music"), &json!(1))
+        );
+
+        let status = client.call("status", Value::Null).await;
+        assert_eq!(status["result"]["roots"][0]["label"], "Music");
+        let roots = client.call("roots", Value::Null).await;
+        assert_eq!(roots["result"][0]["label"], "Music");
+        let answer = client.call("rescan", json!({})).await;
+        assert_eq!(answer["result"]["started"], json!(["Music"]));
+        client.event("scan_finished").await;
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(serve_control(theirs, daemon.clone()));
+        let roots = control_call(ours, "roots", Value::Null).await.unwrap();
+        assert_eq!(roots[0]["label"], "Music");
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(serve_control(theirs, daemon.clone()));
+        let error = control_call(ours, "rescan", json!({"label": "video"}))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("video"));
+    }
+
+    #[tokio::test]
+    async fn test_tenants() {
+        let (daemon, storage) = daemon().await;
+        let tenant = |id: &str| {
+            let root = UniversalPath::from_uri_str(&format!("mem://nas/{id}/")).unwrap();
+            let shared = storage.clone();
+            Daemon::for_tenant(
+                Tenant::new(id).with_root(RootConfig::new(root)),
+                SharedIndex::default(),
+            )
+            .unwrap()
+            .with_opener(move |_| Ok(Arc::new(shared.clone()) as Arc<dyn Storage>))
+        };
+        for id in ["alice", "bob"] {
+            let root = UniversalPath::from_uri_str(&format!("mem://nas/{id}/")).unwrap();
+            storage.create_dir(&root).await.unwrap();
+            storage.write(&root.join("01.flac"), b"flac").await.unwrap();
+            daemon.add_tenant(tenant(id)).unwrap();
+        }
+        assert!(matches!(
+            daemon.add_tenant(tenant("alice")),
+            Err(DaemonError::Tenant(TenantError::DuplicateTenant(_)))
+        ));
+        let alice = daemon.tenant("alice").unwrap();
+        let bob_root = RootConfig::new(UniversalPath::from_uri_str("mem://nas/bob/").unwrap());
+        assert!(matches!(
+            alice.add_root(bob_root),
+            Err(DaemonError::Tenant(TenantError::OutsideRoots { .. }))
+        ));
+
+        let mut client = Client::connect(&daemon);
+        let answer = client.call("subscribe", json!({"tenant": "alice"})).await;
+        assert_eq!(answer["result"], true);
+        let answer = client.call("rescan", json!({"tenant": "bob"})).await;
+        assert_eq!(answer["result"]["started"], json!(["bob"]));
+        let answer = client.call("rescan", json!({"tenant": "alice"})).await;
+        assert_eq!(answer["result"]["started"], json!(["alice"]));
+        // Only alice's scan is seen, each library indexed apart
+        let started = client.event("scan_started").await;
+        assert_eq!(started["label"], "alice");
+        client.event("scan_finished").await;
+        assert_eq!(alice.index().snapshot().len(), 1);
+        assert!(daemon.index().snapshot().is_empty());
+
+        let roots = client.call("roots", json!({"tenant": "alice"})).await;
+        assert_eq!(roots["result"].as_array().unwrap().len(), 1);
+        let roots = client.call("roots", Value::Null).await;
+        assert_eq!(roots["result"], json!([]));
+        let tenants = client.call("tenants", Value::Null).await;
+        assert_eq!(tenants["result"], json!(["alice", "bob"]));
+        let answer = client.call("roots", json!({"tenant": "carol"})).await;
+        assert_eq!(answer["error"]["code"], INVALID_PARAMS);
+    }
+
+    #[tokio::test]
+    async fn test_operations() {
+        let (daemon, _storage) = daemon().await;
+        let path = UniversalPath::from_uri_str("mem://nas/music/01.flac").unwrap();
+        let copying = daemon.operations().begin("copy", Some(&path));
+        let mut client = Client::connect(&daemon);
+        let ops = client.call("ops", Value::Null).await;
+        let listed = ops["result"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|op| op["id"] == copying.id())
+            .unwrap();
+        assert_eq!(listed["kind"], "copy");
+        assert_eq!(listed["path"], redactor().path(&path));
+        assert_eq!(listed["cancelling"], false);
+
+        let answer = client.call("cancel", json!({"id": copying.id()})).await;
+        assert_eq!(answer["result"]["cancelled"], copying.id());
+        assert!(copying.is_cancelled());
+        drop(copying);
+        let answer = client.call("cancel", json!({"id": 0})).await;
+        assert_eq!(answer["error"]["code"], DAEMON_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_jobs() {
+        use crate::index::FileIndexStore;
+        use crate::jobs::{JobSpec, JobState};
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.json");
+        let (daemon, _storage) = daemon().await;
+        let daemon = daemon
+            .with_store(FileIndexStore::open(&path).unwrap())
+            .unwrap();
+        let (queued, running) = {
+            let mut jobs = daemon.jobs();
+            let queued = jobs.enqueue(JobSpec::new("verify", json!({}))).unwrap();
+            jobs.enqueue(JobSpec::new("sync", json!({}))).unwrap();
+            jobs.pause(queued).unwrap();
+            let claimed = jobs.claim_next().unwrap().unwrap();
+            jobs.resume(queued).unwrap();
+            (queued, claimed.id)
+        };
+        let working = daemon
+            .operations()
+            .begin_job(daemon.jobs().get(running).unwrap());
+
+        let mut client = Client::connect(&daemon);
+        let answer = client.call("pause", json!({"job": queued})).await;
+        assert_eq!(answer["result"]["paused"], false);
+        let answer = client.call("pause", Value::Null).await;
+        assert_eq!(answer["result"]["paused"], true);
+        let listed = client.call("jobs", Value::Null).await;
+        assert_eq!(listed["result"]["paused"], true);
+        let states: Vec<_> = listed["result"]["jobs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|job| job["state"].clone())
+            .collect();
+        assert_eq!(states, [json!("Paused"), json!("Running")]);
+
+        let answer = client.call("cancel", json!({"job": running})).await;
+        assert_eq!(answer["result"]["cancelled"], running);
+        assert!(working.is_cancelled());
+        let answer = client.call("pause", json!({"job": running})).await;
+        assert_eq!(answer["error"]["code"], DAEMON_ERROR);
+        let answer = client.call("cancel", json!({})).await;
+        assert_eq!(answer["error"]["code"], INVALID_PARAMS);
+        drop(daemon);
+
+        // The queue is kept in the index store
+        let store: SharedIndexStore =
+            Arc::new(Mutex::new(Box::new(FileIndexStore::open(&path).unwrap())));
+        let jobs = JobQueue::in_store(store).unwrap();
+        assert!(jobs.is_paused());
+        assert_eq!(jobs.get(queued).unwrap().state, JobState::Paused);
+        assert_eq!(jobs.get(running).unwrap().state, JobState::Cancelled);
+    }
+
+    #[test]
+    fn test_tenant_labels_clash() {
+        let root = |uri: &str| RootConfig::new(UniversalPath::from_uri_str(uri).unwrap());
+        let tenant = Tenant::new("alice")
+            .with_root(root("mem://nas/alice/music/"))
+            .with_root(root("mem://nas/shared/music/"));
+        assert!(matches!(
+            Daemon::for_tenant(tenant, SharedIndex::default()),
+            Err(DaemonError::DuplicateRoot(label)) if label == "music"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_tenant_overlapping_library() {
+        let (daemon, _storage) = daemon().await;
+        let music = UniversalPath::from_uri_str("mem://nas/music/").unwrap();
+        daemon.add_root(RootConfig::new(music.clone())).unwrap();
+        for path in [music.join("alice/"), music.join("alice/../")] {
+            let tenant = Tenant::new("alice").with_root(RootConfig::new(path));
+            let tenant = Daemon::for_tenant(tenant, SharedIndex::default()).unwrap();
+            assert!(matches!(
+                daemon.add_tenant(tenant),
+                Err(DaemonError::LibraryOverlap(_))
+            ));
+        }
+        assert!(daemon.tenants().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_control_errors() {
+        let (daemon, _storage) = daemon().await;
+        let mut client = Client::connect(&daemon);
+        let answer = client.call("shutdown", Value::Null).await;
+        assert_eq!(answer["error"]["code"], METHOD_NOT_FOUND);
+        let answer = client.call("add_root", json!({"label": "no uri"})).await;
+        assert_eq!(answer["error"]["code"], INVALID_PARAMS);
+        let answer = client.call("rescan", json!({"label": "video"})).await;
+        assert_eq!(answer["error"]["code"], DAEMON_ERROR);
+        // Notifications, even failing ones, are not answered
+        client
+            .send(r#"{"jsonrpc": "2.0", "method": "shutdown"}"#)
+            .await;
+        client
+            .send(r#"{"jsonrpc": "2.0", "method": "rescan", "params": {}}"#)
+            .await;
+        client.send("{not json").await;
+        let answer = client.next().await;
+        assert_eq!(
+            (&answer["id"], &answer["error"]["code"]),
+            (&Value::Null, &json!(PARSE_ERROR))
+        );
+        // The connection is still usable after a bad request
+        let status = client.call("status", Value::Null).await;
+        assert_eq!(status["result"]["status"], "ok");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_control_socket() {
+        let (daemon, _storage) = daemon().await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("otolith.sock");
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(serve_control_socket(listener, daemon));
+        let socket = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (reader, mut writer) = socket.into_split();
+        writer
+            .write_all(b"{\"jsonrpc\": \"2.0\", \"id\": 7, \"method\": \"roots\"}\n")
+            .await
+            .unwrap();
+        let line = BufReader::new(reader).lines().next_line().await.unwrap();
+        let answer: Value = serde_json::from_str(&line.unwrap()).unwrap();
+        assert_eq!((&answer["id"], &answer["result"]), (&json!(7), &json!([])));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_user_dir() {
+        use std::os::unix::fs::PermissionsExt;
+        let parent = tempfile::tempdir().unwrap();
+        let dir = user_dir(parent.path()).unwrap();
+        let mode = |dir: &std::path::Path| std::fs::metadata(dir).unwrap().permissions().mode();
+        assert_eq!(mode(&dir) & 0o777, 0o700);
+        // Left open by hand, it is closed again
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(user_dir(parent.path()).unwrap(), dir);
+        assert_eq!(mode(&dir) & 0o777, 0o700);
+
+        // Something else in its place is refused
+        std::fs::remove_dir(&dir).unwrap();
+        std::fs::write(&dir, "").unwrap();
+        assert!(user_dir(parent.path()).is_err());
+    }
+}
@@ -0,0 +1,269 @@
+use crate::audit::{AuditOptions, Difference, DifferenceKind, compare_files};
+use crate::collation::Collation;
+use crate::storage::{
+    EntryKind, EntryMetadata, Storage, StorageError, StorageExt, WalkOptions, WalkOrder, WalkStream,
+};
+use crate::universal_path::UniversalPath;
+use futures::{Stream, StreamExt, stream};
+use std::{cmp::Ordering, collections::VecDeque, pin::Pin};
+
+/// Differences between two trees, as they are found.
+pub type DiffStream<'a> = Pin<Box<dyn Stream<Item = Result<Difference, StorageError>> + Send + 'a>>;
+
+/// Compare two live trees without snapshotting either: both are walked at once,
+/// depth-first in code point order, and merged by path, so memory stays bounded by the
+/// depth of the trees rather than their size. `a` plays the source and `b` the mirror,
+/// so an entry only in `a` is `Missing` and one only in `b` is `Extra`; a directory
+/// only on one side is reported once, not entry by entry.
+///
+/// Differences come out in path order. A directory that cannot be listed is yielded as
+/// an error and the rest of the trees are still compared; the stream ends early only
+/// if a root itself cannot be stat'ed.
+pub fn diff_roots<'a>(
+    a: &'a dyn Storage,
+    a_root: &UniversalPath,
+    b: &'a dyn Storage,
+    b_root: &UniversalPath,
+    options: AuditOptions,
+) -> DiffStream<'a> {
+    let walk = WalkOptions::default()
+        .with_order(WalkOrder::DepthFirst)
+        .with_sort(Collation::CodePoint);
+    let merge = Merge {
+        a: Tree::new(a, a_root, walk.clone()),
+        b: Tree::new(b, b_root, walk),
+        options,
+        pending: VecDeque::new(),
+        started: false,
+    };
+    Box::pin(stream::unfold(merge, |mut merge| async move {
+        let item = merge.next().await?;
+        Some((item, merge))
+    }))
+}
+
+struct Entry {
+    rel: Vec<String>,
+    path: UniversalPath,
+    meta: EntryMetadata,
+}
+
+/// One side of a diff, walked an entry ahead of the merge.
+struct Tree<'a> {
+    storage: &'a dyn Storage,
+    root: UniversalPath,
+    walk: WalkStream<'a>,
+    head: Option<Entry>,
+    done: bool,
+    /// A directory reported whole, whose contents are passed over
+    skip: Option<Vec<String>>,
+}
+
+impl<'a> Tree<'a> {
+    fn new(storage: &'a dyn Storage, root: &UniversalPath, options: WalkOptions) -> Self {
+        Tree {
+            storage,
+            root: root.as_dir(),
+            walk: storage.walk_with(root, options),
+            head: None,
+            done: false,
+            skip: None,
+        }
+    }
+
+    /// Walk on until there is a head or the walk ends, returning any error on the way
+    async fn fill(&mut self) -> Option<StorageError> {
+        while self.head.is_none() && !self.done {
+            let Some(next) = self.walk.next().await else {
+                self.done = true;
+                break;
+            };
+            let (path, meta) = match next {
+                Ok(entry) => entry,
+                Err(e) => return Some(e),
+            };
+            let rel = path.relative_to(&self.root).unwrap_or_default();
+            if self.skip.as_ref().is_some_and(|skip| rel.starts_with(skip)) {
+                continue;
+            }
+            self.skip = None;
+            self.head = Some(Entry { rel, path, meta });
+        }
+        None
+    }
+
+    /// Take the head, passing over its contents if it is a directory
+    fn take_whole(&mut self) -> Entry {
+        let entry = self.head.take().expect("tree has a head");
+        if entry.meta.kind == EntryKind::Directory {
+            self.skip = Some(entry.rel.clone());
+        }
+        entry
+    }
+}
+
+struct Merge<'a> {
+    a: Tree<'a>,
+    b: Tree<'a>,
+    options: AuditOptions,
+    pending: VecDeque<Result<Difference, StorageError>>,
+    started: bool,
+}
+
+impl Merge<'_> {
+    async fn next(&mut self) -> Option<Result<Difference, StorageError>> {
+        if !self.started {
+            self.started = true;
+            let (a, b) = (&self.a, &self.b);
+            let (a, b) = futures::join!(a.storage.stat(&a.root), b.storage.stat(&b.root));
+            if let Err(e) = a.and(b) {
+                self.a.done = true;
+                self.b.done = true;
+                return Some(Err(e));
+            }
+        }
+        loop {
+            if let Some(item) = self.pending.pop_front() {
+                return Some(item);
+            }
+            let (a_error, b_error) = futures::join!(self.a.fill(), self.b.fill());
+            let errors = [a_error, b_error].into_iter().flatten().map(Err);
+            self.pending.extend(errors);
+            if !self.pending.is_empty() {
+                continue;
+            }
+            let order = match (&self.a.head, &self.b.head) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(a), Some(b)) => a.rel.cmp(&b.rel),
+            };
+            match order {
+                Ordering::Less => {
+                    let only = self.a.take_whole();
+                    let detail = format!("{:?} missing from mirror", only.meta.kind);
+                    self.found(&only.rel, DifferenceKind::Missing, detail);
+                }
+                Ordering::Greater => {
+                    let only = self.b.take_whole();
+                    let detail = format!("{:?} only in mirror", only.meta.kind);
+                    self.found(&only.rel, DifferenceKind::Extra, detail);
+                }
+                Ordering::Equal => self.compare().await,
+            }
+        }
+    }
+
+    /// Compare the heads of both trees, which are at the same path
+    async fn compare(&mut self) {
+        let (a, b) = match (&self.a.head, &self.b.head) {
+            (Some(a), Some(b)) if a.meta.kind != b.meta.kind => {
+                let detail = format!("{:?} in source, {:?} in mirror", a.meta.kind, b.meta.kind);
+                let rel = a.rel.clone();
+                self.a.take_whole();
+                self.b.take_whole();
+                self.found(&rel, DifferenceKind::KindMismatch, detail);
+                return;
+            }
+            _ => (self.a.head.take().unwrap(), self.b.head.take().unwrap()),
+        };
+        if a.meta.kind != EntryKind::File {
+            return;
+        }
+        let a_side = (self.a.storage, &a.path, &a.meta);
+        let b_side = (self.b.storage, &b.path, &b.meta);
+        let rel = a.rel.join("/");
+        let (found, _) = compare_files(&rel, a_side, b_side, &self.options).await;
+        self.pending.extend(found.into_iter().map(Ok));
+    }
+
+    fn found(&mut self, rel: &[String], kind: DifferenceKind, detail: String) {
+        let difference = Difference::new(&rel.join("/"), kind, detail);
+        self.pending.push_back(Ok(difference));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_diff_live_roots() {
+        let a = MemoryStorage::new();
+        let b = MemoryStorage::new();
+        let a_root = UniversalPath::from_uri_str("mem://nas/music/").unwrap();
+        let b_root = UniversalPath::from_uri_str("mem://backup/music/").unwrap();
+        let tree = async |storage: &MemoryStorage, root: &UniversalPath, files: &[(&str, &str)]| {
+            for (path, data) in files {
+                let segments: Vec<_> = path.split('/').collect();
+                let (name, dirs) = segments.split_last().unwrap();
+                let mut dir = root.as_dir();
+                storage.create_dir(&dir).await.unwrap();
+                for segment in dirs {
+                    dir = dir.join(segment).as_dir();
+                    storage.create_dir(&dir).await.unwrap();
+                }
+                storage
+                    .write(&dir.join(name), data.as_bytes())
+                    .await
+                    .unwrap();
+            }
+        };
+        tree(
+            &a,
+            &a_root,
+            &[
+                ("A/01.flac", "same"),
+                ("A/02.flac", "longer"),
+                ("A/03.flac", "abcd"),
+                ("B/Deep/01.flac", "only in a"),
+                ("B/Deep/02.flac", "only in a"),
+                ("cover", "file here"),
+            ],
+        )
+        .await;
+        tree(
+            &b,
+            &b_root,
+            &[
+                ("A/01.flac", "same"),
+                ("A/02.flac", "short"),
+                ("A/03.flac", "abce"),
+                ("A/04.flac", "only in b"),
+                ("cover/front.jpg", "directory there"),
+            ],
+        )
+        .await;
+
+        let options = AuditOptions {
+            mtime_tolerance: std::time::Duration::from_secs(3600),
+            ..AuditOptions::default()
+        };
+        let found: Vec<_> = diff_roots(&a, &a_root, &b, &b_root, options)
+            .map(|d| {
+                let d = d.unwrap();
+                (d.path, d.kind)
+            })
+            .collect()
+            .await;
+        assert_eq!(
+            found,
+            [
+                ("A/02.flac".to_string(), DifferenceKind::SizeMismatch),
+                ("A/03.flac".to_string(), DifferenceKind::ContentMismatch),
+                ("A/04.flac".to_string(), DifferenceKind::Extra),
+                ("B".to_string(), DifferenceKind::Missing),
+                ("cover".to_string(), DifferenceKind::KindMismatch),
+            ]
+        );
+
+        let missing = UniversalPath::from_uri_str("mem://backup/nowhere/").unwrap();
+        let mut diff = diff_roots(&a, &a_root, &b, &missing, AuditOptions::default());
+        assert!(matches!(
+            diff.next().await,
+            Some(Err(StorageError::NotFound))
+        ));
+        assert!(diff.next().await.is_none());
+    }
+}
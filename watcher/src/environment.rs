@@ -0,0 +1,314 @@
+use crate::redact::redactor;
+use crate::root::RootConfig;
+use crate::storage::{
+    CallOptions, SKEW_WARNING, SkewDiagnostic, StorageBackend, clock_skew, open_storage_for,
+};
+use serde::Serialize;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// How long a root may take to answer a stat before it counts as unreachable
+const ROOT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Below these, large libraries run into the limits while scanning or watching
+const MIN_OPEN_FILES: u64 = 4096;
+const MIN_INOTIFY_WATCHES: u64 = 65536;
+const MIN_CACHE_SPACE: u64 = 1 << 30;
+
+/// Optional features compiled into this build, in `Cargo.toml` order.
+pub fn compiled_features() -> Vec<&'static str> {
+    [
+        (cfg!(feature = "ftp"), "ftp"),
+        (cfg!(feature = "sftp"), "sftp"),
+        (cfg!(feature = "s3"), "s3"),
+        (cfg!(feature = "webdav"), "webdav"),
+        (cfg!(feature = "http"), "http"),
+        (cfg!(feature = "watch"), "watch"),
+        (cfg!(feature = "webhook"), "webhook"),
+        (cfg!(feature = "musicbrainz"), "musicbrainz"),
+        (cfg!(feature = "lyrics"), "lyrics"),
+        (cfg!(feature = "media-server"), "media-server"),
+        (cfg!(feature = "plugins"), "plugins"),
+        (cfg!(feature = "scripting"), "scripting"),
+        (cfg!(feature = "chaos"), "chaos"),
+        (cfg!(feature = "collation"), "collation"),
+        (cfg!(feature = "cli"), "cli"),
+    ]
+    .into_iter()
+    .filter_map(|(enabled, name)| enabled.then_some(name))
+    .collect()
+}
+
+/// A resource limit; `None` means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ResourceLimit {
+    pub soft: Option<u64>,
+    pub hard: Option<u64>,
+}
+
+/// The kernel's limits on inotify, which native watching on Linux uses one watch per
+/// directory of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct InotifyLimits {
+    pub max_user_watches: u64,
+    pub max_user_instances: u64,
+    pub max_queued_events: u64,
+}
+
+/// Free space on the filesystem holding `path`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DiskSpace {
+    pub path: PathBuf,
+    /// What an unprivileged process may still write
+    pub available_bytes: u64,
+    pub total_bytes: u64,
+}
+
+/// Whether a configured root answered, and how quickly.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RootCheck {
+    pub label: String,
+    /// The root's path, redacted by the process-wide `redactor()`
+    pub root: String,
+    pub backend: Option<StorageBackend>,
+    pub reachable: bool,
+    /// The host gave no route or no answer, as one that is asleep does
+    pub possibly_asleep: bool,
+    pub latency_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// What `otolith env` prints: the limits, space and connectivity that most often
+/// explain a misbehaving install, gathered in one place for support issues.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EnvironmentReport {
+    pub version: &'static str,
+    pub os: &'static str,
+    pub arch: &'static str,
+    pub features: Vec<&'static str>,
+    /// `ulimit -n`, where the platform has one
+    pub open_files: Option<ResourceLimit>,
+    /// Linux only
+    pub inotify: Option<InotifyLimits>,
+    pub cache_space: Option<DiskSpace>,
+    pub roots: Vec<RootCheck>,
+    /// Problems found, in plain words
+    pub warnings: Vec<String>,
+}
+
+impl EnvironmentReport {
+    /// Check this machine, with `cache_dir` as where caches would go, and try each of
+    /// `roots` in turn
+    pub async fn gather(roots: &[RootConfig], cache_dir: Option<&Path>) -> Self {
+        let mut warnings = Vec::new();
+        let open_files = open_files_limit();
+        if let Some(soft) = open_files.and_then(|l| l.soft)
+            && soft < MIN_OPEN_FILES
+        {
+            warnings.push(format!(
+                "open file limit is {soft}; raise it to at least {MIN_OPEN_FILES} with `ulimit -n`"
+            ));
+        }
+        let inotify = inotify_limits();
+        if let Some(limits) = inotify
+            && limits.max_user_watches < MIN_INOTIFY_WATCHES
+        {
+            warnings.push(format!(
+                "inotify allows {} watches; native watching needs one per directory, so raise \
+                 fs.inotify.max_user_watches to at least {MIN_INOTIFY_WATCHES}",
+                limits.max_user_watches
+            ));
+        }
+        let cache_space = match cache_dir {
+            Some(dir) => match available_space(dir) {
+                Ok(space) => Some(space),
+                Err(e) => {
+                    warnings.push(format!("cannot check space for caches: {e}"));
+                    None
+                }
+            },
+            None => None,
+        };
+        if let Some(space) = &cache_space
+            && space.available_bytes < MIN_CACHE_SPACE
+        {
+            warnings.push(format!(
+                "only {} MiB free for caches",
+                space.available_bytes >> 20
+            ));
+        }
+
+        let mut checks = Vec::new();
+        for root in roots {
+            let check = check_root(root).await;
+            match &check.error {
+                Some(error) if check.possibly_asleep => warnings.push(format!(
+                    "root {:?} did not answer and may be asleep: {error}; give its host a \
+                     wake-on-LAN address to wake it first",
+                    check.label
+                )),
+                Some(error) => {
+                    warnings.push(format!("root {:?} is unreachable: {error}", check.label))
+                }
+                None => {}
+            }
+            checks.push(check);
+        }
+        // Stats against network roots sample their clocks
+        for diagnostic in clock_skew().diagnostics(SKEW_WARNING) {
+            let host = redactor().host(&diagnostic.host);
+            warnings.push(SkewDiagnostic { host, ..diagnostic }.to_string());
+        }
+
+        EnvironmentReport {
+            version: env!("CARGO_PKG_VERSION"),
+            os: std::env::consts::OS,
+            arch: std::env::consts::ARCH,
+            features: compiled_features(),
+            open_files,
+            inotify,
+            cache_space,
+            roots: checks,
+            warnings,
+        }
+    }
+}
+
+async fn check_root(root: &RootConfig) -> RootCheck {
+    let mut check = RootCheck {
+        label: root.label.clone(),
+        root: redactor().path(&root.path),
+        backend: None,
+        reachable: false,
+        possibly_asleep: false,
+        latency_ms: None,
+        error: None,
+    };
+    let storage = match open_storage_for(&root.path) {
+        Ok(storage) => storage,
+        Err(e) => {
+            check.error = Some(redactor().error(&e));
+            return check;
+        }
+    };
+    check.backend = Some(storage.backend());
+    let started = Instant::now();
+    let opts = CallOptions::default().with_timeout(ROOT_TIMEOUT);
+    match storage.stat_opts(&root.path, &opts).await {
+        Ok(_) => {
+            check.reachable = true;
+            check.latency_ms = Some(started.elapsed().as_millis() as u64);
+        }
+        Err(e) => {
+            check.possibly_asleep = e.may_be_asleep();
+            check.error = Some(redactor().error(&e));
+        }
+    }
+    check
+}
+
+/// The soft and hard limits on open file descriptors
+#[cfg(unix)]
+pub fn open_files_limit() -> Option<ResourceLimit> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: getrlimit only writes to the struct it is given
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return None;
+    }
+    // rlim_t is narrower on some platforms
+    #[allow(clippy::unnecessary_cast)]
+    let value = |v: libc::rlim_t| (v != libc::RLIM_INFINITY).then_some(v as u64);
+    Some(ResourceLimit {
+        soft: value(limit.rlim_cur),
+        hard: value(limit.rlim_max),
+    })
+}
+
+/// The soft and hard limits on open file descriptors
+#[cfg(not(unix))]
+pub fn open_files_limit() -> Option<ResourceLimit> {
+    None
+}
+
+/// The kernel's inotify limits, on Linux
+pub fn inotify_limits() -> Option<InotifyLimits> {
+    let read = |name: &str| -> Option<u64> {
+        std::fs::read_to_string(format!("/proc/sys/fs/inotify/{name}"))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    };
+    if !cfg!(target_os = "linux") {
+        return None;
+    }
+    Some(InotifyLimits {
+        max_user_watches: read("max_user_watches")?,
+        max_user_instances: read("max_user_instances")?,
+        max_queued_events: read("max_queued_events")?,
+    })
+}
+
+/// Free space on the filesystem holding `path`, or the nearest ancestor that exists
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> io::Result<DiskSpace> {
+    use std::os::unix::ffi::OsStrExt;
+    let existing = path
+        .ancestors()
+        .find(|p| p.exists())
+        .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+    let c_path = std::ffi::CString::new(existing.as_os_str().as_bytes())?;
+    // SAFETY: statvfs only writes to the struct it is given
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let block = stat.f_frsize as u64;
+    Ok(DiskSpace {
+        path: path.to_path_buf(),
+        available_bytes: stat.f_bavail as u64 * block,
+        total_bytes: stat.f_blocks as u64 * block,
+    })
+}
+
+/// Free space on the filesystem holding `path`, or the nearest ancestor that exists
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> io::Result<DiskSpace> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::universal_path::UniversalPath;
+
+    #[tokio::test]
+    async fn test_environment_report() {
+        let dir = std::env::temp_dir();
+        let local = |p: &Path| UniversalPath::local(p.to_string_lossy());
+        let missing = RootConfig::new(local(&dir.join("otolith-env-missing")));
+        let roots = [RootConfig::new(local(&dir)), missing];
+        let report = EnvironmentReport::gather(&roots, Some(&dir.join("cache"))).await;
+        assert!(report.roots[0].reachable, "{:?}", report.roots[0]);
+        assert_eq!(report.roots[0].backend, Some(StorageBackend::Local));
+        assert!(!report.roots[1].reachable && report.roots[1].error.is_some());
+        assert!(
+            report
+                .warnings
+                .iter()
+                .any(|w| w.contains("otolith-env-missing"))
+        );
+        if cfg!(unix) {
+            let space = report.cache_space.as_ref().unwrap();
+            assert!(space.available_bytes <= space.total_bytes);
+            assert!(report.open_files.is_some());
+        }
+        serde_json::to_string(&report).unwrap();
+    }
+}
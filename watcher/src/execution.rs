@@ -0,0 +1,683 @@
+use crate::conflict::ConflictPolicy;
+use crate::index::sync_parent;
+use crate::root::RootConfig;
+use crate::routing::RoutedDestinations;
+use crate::storage::{
+    ByteStream, Checksum, ChecksumAlgorithm, EntryKind, OperationHandle, QuotaStorage, Storage,
+    StorageError, copy_target,
+};
+use crate::universal_path::UniversalPath;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ops::Range,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, ready},
+    time::SystemTime,
+};
+use thiserror::Error;
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Version of the file `SyncExecution::save` writes
+const FORMAT_VERSION: u32 = 1;
+/// Segments are checked with a fast checksum; they only need to catch a segment
+/// that was cut short or overwritten, not tampering
+const SEGMENT_CHECKSUM: ChecksumAlgorithm = ChecksumAlgorithm::Xxh3;
+
+#[derive(Debug, Error)]
+pub enum ExecutionError {
+    #[error("sync state is version {0}, newer than this build reads")]
+    UnsupportedVersion(u32),
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// One file a sync copies. A `to` marked as a directory means the file of the same
+/// name in it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncItem {
+    pub from: UniversalPath,
+    pub to: UniversalPath,
+}
+
+impl SyncItem {
+    /// The item copying `file`, somewhere under the tree `root`, to its place under
+    /// `to`, the way rsync reads a trailing slash: `music/` copies what is in the
+    /// directory, `music/a.flac` to `to/a.flac`, while `music` copies the directory
+    /// itself, to `to/music/a.flac`. `None` if `file` is not under `root`.
+    pub fn under(root: &UniversalPath, file: &UniversalPath, to: &UniversalPath) -> Option<Self> {
+        let rel = file.relative_to(root)?;
+        let base = match (root.is_dir_hint() || root.is_root(), root.last_segment()) {
+            (false, Some(name)) => to.join(name),
+            _ => to.clone(),
+        };
+        Some(SyncItem {
+            from: file.clone(),
+            to: base.join_all(&rel).as_file(),
+        })
+    }
+}
+
+/// How far a file got.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ItemState {
+    Pending,
+    /// Some of its segments are at the destination
+    Partial(PartialTransfer),
+    Done {
+        bytes: u64,
+    },
+    /// Tried again on the next run
+    Failed {
+        error: String,
+    },
+}
+
+/// A file copied part of the way, a segment at a time.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PartialTransfer {
+    /// The source as the transfer found it; one that changed since starts over
+    pub size_bytes: u64,
+    pub modified_at: Option<SystemTime>,
+    pub segment_size: u64,
+    /// Checksums of the segments written, by index
+    pub segments: BTreeMap<u64, Checksum>,
+}
+
+impl PartialTransfer {
+    fn new(size_bytes: u64, modified_at: Option<SystemTime>, segment_size: u64) -> Self {
+        PartialTransfer {
+            size_bytes,
+            modified_at,
+            segment_size,
+            segments: BTreeMap::new(),
+        }
+    }
+
+    /// An empty file is one empty segment
+    fn segment_count(&self) -> u64 {
+        self.size_bytes.div_ceil(self.segment_size).max(1)
+    }
+
+    fn range(&self, index: u64) -> Range<u64> {
+        let start = index * self.segment_size;
+        start..(start + self.segment_size).min(self.size_bytes)
+    }
+
+    /// Bytes in the segments written so far
+    pub fn bytes_done(&self) -> u64 {
+        let lens = self.segments.keys().map(|&index| self.range(index));
+        lens.map(|range| range.end - range.start).sum()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ItemRecord {
+    item: SyncItem,
+    state: ItemState,
+}
+
+/// What one `SyncExecution::run` did.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ExecutionSummary {
+    pub completed: usize,
+    pub failed: usize,
+    /// Files picked up where an earlier run left them
+    pub resumed: usize,
+    pub bytes_transferred: u64,
+    /// Bytes an earlier run left at the destination and this one verified and kept
+    pub bytes_reused: u64,
+    /// Files that found a different version at their destination, from where they
+    /// were going to where the conflict policy put them instead
+    pub conflicts: Vec<(UniversalPath, UniversalPath)>,
+}
+
+/// The state of a sync that can be stopped and picked up again: which files are
+/// done, and for each one copied part of the way, which of its segments are at the
+/// destination. Each segment is written as its own file next to the destination, as
+/// `.<name>.otolith-part-<index>`, and the state is saved after every one, so a run
+/// interrupted at any point loses at most the segment in flight. The next run checks
+/// the segments left behind against their checksums, keeps those that match, fetches
+/// the rest from the source and only then joins them into the destination file.
+///
+/// A source whose size or modification time changed since its transfer started is
+/// copied again from the start. A file whose destination already holds a different
+/// version is written where the conflict policy says, and one whose destination
+/// already holds the same contents is not copied at all.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncExecution {
+    version: u32,
+    pub id: String,
+    pub started_at: SystemTime,
+    segment_size: u64,
+    #[serde(default)]
+    conflicts: ConflictPolicy,
+    items: Vec<ItemRecord>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    #[serde(skip)]
+    roots: Vec<RootConfig>,
+}
+
+impl SyncExecution {
+    /// A fresh execution copying each of `items`, in 16 MiB segments
+    pub fn new<S: Into<String>>(id: S, items: impl IntoIterator<Item = SyncItem>) -> Self {
+        SyncExecution {
+            version: FORMAT_VERSION,
+            id: id.into(),
+            started_at: SystemTime::now(),
+            segment_size: 16 << 20,
+            conflicts: ConflictPolicy::default(),
+            items: items
+                .into_iter()
+                .map(|item| ItemRecord {
+                    item: SyncItem {
+                        to: copy_target(&item.from, &item.to),
+                        from: item.from,
+                    },
+                    state: ItemState::Pending,
+                })
+                .collect(),
+            path: None,
+            roots: Vec::new(),
+        }
+    }
+
+    /// Segments smaller than 64 KiB are not worth a file each
+    pub fn with_segment_size(mut self, bytes: u64) -> Self {
+        self.segment_size = bytes.max(64 << 10);
+        self
+    }
+
+    /// What to do about files whose destination holds a different version, which by
+    /// default is overwritten
+    pub fn with_conflict_policy(mut self, policy: ConflictPolicy) -> Self {
+        self.conflicts = policy;
+        self
+    }
+
+    /// Keep to the quotas of `roots`, the configured roots the files may go under: a
+    /// file that would take one past its quota fails with `QuotaExceeded`. A file is
+    /// joined from its segments beside them, so needs twice its size free until it
+    /// is. Not saved with the state, so given again after `load`.
+    pub fn with_roots(mut self, roots: &[RootConfig]) -> Self {
+        self.roots = roots.to_vec();
+        self
+    }
+
+    /// Save the state to `path` as the execution goes
+    pub fn with_state_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// An execution saved at `path` by an earlier run, to carry on with
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ExecutionError> {
+        let bytes = std::fs::read(path.as_ref())?;
+        let mut execution: SyncExecution = serde_json::from_slice(&bytes)?;
+        if execution.version > FORMAT_VERSION {
+            return Err(ExecutionError::UnsupportedVersion(execution.version));
+        }
+        execution.path = Some(path.as_ref().to_path_buf());
+        Ok(execution)
+    }
+
+    /// Write the state to its file, if it has one (synced, then renamed into place,
+    /// so a crash or power loss leaves the previous version)
+    pub fn save(&self) -> Result<(), ExecutionError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let tmp = path.with_extension("tmp");
+        let mut out = std::fs::File::create(&tmp)?;
+        std::io::Write::write_all(&mut out, &serde_json::to_vec(self)?)?;
+        out.sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        sync_parent(path)?;
+        Ok(())
+    }
+
+    pub fn items(&self) -> impl Iterator<Item = (&SyncItem, &ItemState)> {
+        self.items
+            .iter()
+            .map(|record| (&record.item, &record.state))
+    }
+
+    pub fn is_complete(&self) -> bool {
+        let done = |record: &ItemRecord| matches!(record.state, ItemState::Done { .. });
+        self.items.iter().all(done)
+    }
+
+    /// Copy every file not done yet from `source` to `destination`. A file that fails
+    /// is marked failed and the rest carry on. Once `cancel` is cancelled the run
+    /// stops after the segment in flight, saves, and fails with
+    /// `StorageError::Cancelled`; running again resumes it.
+    pub async fn run(
+        &mut self,
+        source: &dyn Storage,
+        destination: Arc<dyn Storage>,
+        cancel: Option<&OperationHandle>,
+    ) -> Result<ExecutionSummary, ExecutionError> {
+        let (destination, _) = QuotaStorage::for_roots(destination, &self.quota_roots()).await?;
+        self.run_each(source, &|_| Some(&*destination), cancel)
+            .await
+    }
+
+    /// `run`, writing each file to the storage of the destination root it goes under,
+    /// as a `Router` planned. A file under none of them is marked failed.
+    pub async fn run_routed(
+        &mut self,
+        source: &dyn Storage,
+        destinations: &RoutedDestinations,
+        cancel: Option<&OperationHandle>,
+    ) -> Result<ExecutionSummary, ExecutionError> {
+        let destinations = destinations
+            .clone()
+            .with_quotas(&self.quota_roots())
+            .await?;
+        self.run_each(source, &|to| destinations.storage_for(to), cancel)
+            .await
+    }
+
+    /// The roots given with `with_roots` that a file not done yet goes under
+    fn quota_roots(&self) -> Vec<RootConfig> {
+        let pending = |root: &RootConfig| {
+            self.items.iter().any(|record| {
+                !matches!(record.state, ItemState::Done { .. })
+                    && record.item.to.relative_to(&root.path).is_some()
+            })
+        };
+        self.roots
+            .iter()
+            .filter(|root| pending(root))
+            .cloned()
+            .collect()
+    }
+
+    async fn run_each<'d>(
+        &mut self,
+        source: &dyn Storage,
+        destination_for: &(dyn Fn(&UniversalPath) -> Option<&'d dyn Storage> + Sync),
+        cancel: Option<&OperationHandle>,
+    ) -> Result<ExecutionSummary, ExecutionError> {
+        let mut summary = ExecutionSummary::default();
+        for i in 0..self.items.len() {
+            if matches!(self.items[i].state, ItemState::Done { .. }) {
+                continue;
+            }
+            let Some(destination) = destination_for(&self.items[i].item.to) else {
+                summary.failed += 1;
+                let error = format!("no destination holds {}", self.items[i].item.to);
+                self.items[i].state = ItemState::Failed { error };
+                self.save()?;
+                continue;
+            };
+            let state = match self
+                .transfer(i, source, destination, cancel, &mut summary)
+                .await
+            {
+                Ok(bytes) => {
+                    summary.completed += 1;
+                    ItemState::Done { bytes }
+                }
+                Err(ExecutionError::Storage(StorageError::Cancelled)) => {
+                    self.save()?;
+                    return Err(StorageError::Cancelled.into());
+                }
+                Err(ExecutionError::Storage(e)) => {
+                    summary.failed += 1;
+                    match &self.items[i].state {
+                        // Kept, so the segments already written count next time
+                        ItemState::Partial(_) => self.items[i].state.clone(),
+                        _ => ItemState::Failed {
+                            error: e.to_string(),
+                        },
+                    }
+                }
+                Err(e) => return Err(e),
+            };
+            self.items[i].state = state;
+            self.save()?;
+        }
+        Ok(summary)
+    }
+
+    async fn transfer(
+        &mut self,
+        i: usize,
+        source: &dyn Storage,
+        destination: &dyn Storage,
+        cancel: Option<&OperationHandle>,
+        summary: &mut ExecutionSummary,
+    ) -> Result<u64, ExecutionError> {
+        let mut item = self.items[i].item.clone();
+        let meta = source.stat(&item.from).await?;
+        let (EntryKind::File, Some(size)) = (meta.kind, meta.size_bytes) else {
+            return Err(StorageError::NotAFile.into());
+        };
+        // Anything at the destination before the transfer began is someone else's
+        let starting = !matches!(self.items[i].state, ItemState::Partial(_));
+        if starting && self.conflicts.keeps_both() {
+            match destination.stat(&item.to).await {
+                Ok(existing) => {
+                    if existing.size_bytes == Some(size)
+                        && source.checksum(&item.from, SEGMENT_CHECKSUM).await?
+                            == destination.checksum(&item.to, SEGMENT_CHECKSUM).await?
+                    {
+                        summary.bytes_reused += size;
+                        return Ok(size);
+                    }
+                    let at = SystemTime::now();
+                    let to = self.conflicts.resolve(destination, &item.to, at).await?;
+                    summary.conflicts.push((item.to.clone(), to.clone()));
+                    item.to = to.clone();
+                    self.items[i].item.to = to;
+                }
+                Err(StorageError::NotFound) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let mut partial = match std::mem::replace(&mut self.items[i].state, ItemState::Pending) {
+            ItemState::Partial(p)
+                if (p.size_bytes, p.modified_at, p.segment_size)
+                    == (size, meta.modified_at, self.segment_size) =>
+            {
+                p
+            }
+            _ => PartialTransfer::new(size, meta.modified_at, self.segment_size),
+        };
+
+        // Keep only what an earlier run left intact
+        if !partial.segments.is_empty() {
+            let mut kept = BTreeMap::new();
+            for (index, expected) in std::mem::take(&mut partial.segments) {
+                let part = segment_path(&item.to, index);
+                match destination.checksum(&part, SEGMENT_CHECKSUM).await {
+                    Ok(actual) if actual == expected => {
+                        let range = partial.range(index);
+                        summary.bytes_reused += range.end - range.start;
+                        kept.insert(index, expected);
+                    }
+                    Ok(_) | Err(StorageError::NotFound) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            summary.resumed += usize::from(!kept.is_empty());
+            partial.segments = kept;
+        }
+        self.items[i].state = ItemState::Partial(partial.clone());
+
+        if let Some(parent) = item.to.parent() {
+            destination.create_dir(&parent).await?;
+        }
+        for index in 0..partial.segment_count() {
+            if partial.segments.contains_key(&index) {
+                continue;
+            }
+            if cancel.is_some_and(OperationHandle::is_cancelled) {
+                return Err(StorageError::Cancelled.into());
+            }
+            let range = partial.range(index);
+            let data = match range.is_empty() {
+                true => Vec::new(),
+                false => source.read_range(&item.from, range.clone()).await?,
+            };
+            if data.len() as u64 != range.end - range.start {
+                let changed = std::io::Error::other("source changed size during the transfer");
+                return Err(StorageError::Io(changed).into());
+            }
+            destination
+                .write(&segment_path(&item.to, index), &data)
+                .await?;
+            partial
+                .segments
+                .insert(index, Checksum::of(SEGMENT_CHECKSUM, &data));
+            summary.bytes_transferred += data.len() as u64;
+            // Durable before the next segment is fetched
+            self.items[i].state = ItemState::Partial(partial.clone());
+            self.save()?;
+        }
+
+        let parts: Vec<_> = (0..partial.segment_count())
+            .map(|index| segment_path(&item.to, index))
+            .collect();
+        let mut readers = VecDeque::new();
+        for part in &parts {
+            readers.push_back(destination.read_stream(part).await?);
+        }
+        let written = destination
+            .write_stream(&item.to, &mut Concat { readers })
+            .await?;
+        if written != size {
+            let short = std::io::Error::other("segments were changed while being joined");
+            return Err(StorageError::Io(short).into());
+        }
+        for part in &parts {
+            destination.delete(part).await?;
+        }
+        Ok(size)
+    }
+}
+
+/// Where segment `index` of a transfer to `to` is kept until the file is joined
+fn segment_path(to: &UniversalPath, index: u64) -> UniversalPath {
+    let name = to.file_name().unwrap_or("file");
+    to.with_file_name(format!(".{name}.otolith-part-{index:05}"))
+}
+
+/// Readers one after another.
+struct Concat<'a> {
+    readers: VecDeque<ByteStream<'a>>,
+}
+
+impl AsyncRead for Concat<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        while let Some(reader) = self.readers.front_mut() {
+            let before = buf.filled().len();
+            ready!(reader.as_mut().poll_read(cx, buf))?;
+            if buf.filled().len() > before {
+                return Poll::Ready(Ok(()));
+            }
+            self.readers.pop_front();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryStorage, OperationRegistry, Quota};
+
+    #[tokio::test]
+    async fn test_resume_sync_execution() {
+        let source = MemoryStorage::new();
+        let destination = MemoryStorage::new();
+        let from = UniversalPath::from_uri_str("mem://nas/").unwrap();
+        let to = UniversalPath::from_uri_str("mem://backup/").unwrap();
+        destination.create_dir(&to).await.unwrap();
+        let big: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        source.write(&from.join("big.flac"), &big).await.unwrap();
+        source
+            .write(&from.join("small.flac"), b"small")
+            .await
+            .unwrap();
+        let items = ["big.flac", "small.flac"].map(|name| SyncItem {
+            from: from.join(name),
+            to: to.join(name),
+        });
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let state = dir.join("sync.json");
+        let mut execution = SyncExecution::new("nightly", items)
+            .with_segment_size(64 << 10)
+            .with_state_file(&state);
+        // Cancelled before it starts, it saves and stops
+        let registry = OperationRegistry::new();
+        let handle = registry.begin("job:sync", None);
+        registry.cancel(handle.id());
+        let error = execution
+            .run(&source, Arc::new(destination.clone()), Some(&handle))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            ExecutionError::Storage(StorageError::Cancelled)
+        ));
+
+        // Interrupted after two of the five segments of the big file
+        let mut saved = SyncExecution::load(&state).unwrap();
+        let first = &saved.items[0];
+        let mut partial = PartialTransfer::new(300_000, None, 64 << 10);
+        for index in 0..2 {
+            let range = partial.range(index);
+            let data = &big[range.start as usize..range.end as usize];
+            let part = segment_path(&first.item.to, index);
+            destination.write(&part, data).await.unwrap();
+            partial
+                .segments
+                .insert(index, Checksum::of(SEGMENT_CHECKSUM, data));
+        }
+        partial.modified_at = source.stat(&first.item.from).await.unwrap().modified_at;
+        saved.items[0].state = ItemState::Partial(partial);
+        // One segment left behind was damaged since
+        let damaged = segment_path(&saved.items[0].item.to, 1);
+        destination.write(&damaged, b"garbage").await.unwrap();
+
+        let summary = saved
+            .run(&source, Arc::new(destination.clone()), None)
+            .await
+            .unwrap();
+        assert_eq!((summary.completed, summary.resumed), (2, 1));
+        assert_eq!(summary.bytes_reused, 64 << 10);
+        assert_eq!(summary.bytes_transferred, 300_000 - (64 << 10) + 5);
+        assert!(saved.is_complete());
+        assert_eq!(destination.read(&to.join("big.flac")).await.unwrap(), big);
+        assert_eq!(destination.list(&to).await.unwrap().len(), 2);
+        assert!(SyncExecution::load(&state).unwrap().is_complete());
+
+        // Again, keeping both where the destination has a different version
+        source
+            .write(&from.join("big.flac"), b"remastered")
+            .await
+            .unwrap();
+        let items = ["big.flac", "small.flac"].map(|name| SyncItem {
+            from: from.join(name),
+            to: to.join(name),
+        });
+        let mut again =
+            SyncExecution::new("again", items).with_conflict_policy(ConflictPolicy::Numbered);
+        let summary = again
+            .run(&source, Arc::new(destination.clone()), None)
+            .await
+            .unwrap();
+        let kept = to.join("big (1).flac");
+        assert_eq!(summary.conflicts, [(to.join("big.flac"), kept.clone())]);
+        assert_eq!((summary.bytes_transferred, summary.bytes_reused), (10, 5));
+        assert_eq!(destination.read(&kept).await.unwrap(), b"remastered");
+        assert_eq!(destination.read(&to.join("big.flac")).await.unwrap(), big);
+    }
+
+    #[tokio::test]
+    async fn test_trailing_slash_copies_contents() {
+        let uri = |uri: &str| UniversalPath::from_uri_str(uri).unwrap();
+        let source = MemoryStorage::new();
+        let destination = MemoryStorage::new();
+        let album = uri("mem://nas/music/Post/");
+        source.create_dir(&album).await.unwrap();
+        source.write(&album.join("01.flac"), b"flac").await.unwrap();
+        let backup = uri("mem://backup/");
+        destination.create_dir(&backup).await.unwrap();
+
+        // `music/` copies what is in it, `music` the directory itself
+        let file = album.join("01.flac");
+        let contents = SyncItem::under(&uri("mem://nas/music/"), &file, &backup).unwrap();
+        assert_eq!(contents.to, uri("mem://backup/Post/01.flac"));
+        let itself = SyncItem::under(&uri("mem://nas/music"), &file, &backup).unwrap();
+        assert_eq!(itself.to, uri("mem://backup/music/Post/01.flac"));
+        assert!(SyncItem::under(&uri("mem://nas/video/"), &file, &backup).is_none());
+
+        let mut execution = SyncExecution::new("contents", [contents, itself]);
+        let summary = execution
+            .run(&source, Arc::new(destination.clone()), None)
+            .await
+            .unwrap();
+        assert_eq!((summary.completed, summary.failed), (2, 0));
+        let post = uri("mem://backup/Post/01.flac");
+        assert_eq!(destination.read(&post).await.unwrap(), b"flac");
+        let music = uri("mem://backup/music/Post/01.flac");
+        assert_eq!(destination.read(&music).await.unwrap(), b"flac");
+    }
+
+    #[tokio::test]
+    async fn test_item_to_a_directory() {
+        let uri = |uri: &str| UniversalPath::from_uri_str(uri).unwrap();
+        let storage = MemoryStorage::new();
+        storage.create_dir(&uri("mem://nas/")).await.unwrap();
+        storage
+            .write(&uri("mem://nas/01.flac"), b"flac")
+            .await
+            .unwrap();
+
+        // A destination marked as a directory gets the file of the same name in it
+        let item = SyncItem {
+            from: uri("mem://nas/01.flac"),
+            to: uri("mem://nas/backup/"),
+        };
+        let mut execution = SyncExecution::new("into", [item]);
+        let (item, _) = execution.items().next().unwrap();
+        assert_eq!(item.to, uri("mem://nas/backup/01.flac"));
+        execution
+            .run(&storage, Arc::new(storage.clone()), None)
+            .await
+            .unwrap();
+        let copied = uri("mem://nas/backup/01.flac");
+        assert_eq!(storage.read(&copied).await.unwrap(), b"flac");
+    }
+
+    #[tokio::test]
+    async fn test_keeps_to_root_quotas() {
+        let uri = |uri: &str| UniversalPath::from_uri_str(uri).unwrap();
+        let storage = MemoryStorage::new();
+        let (inbox, phone) = (uri("mem://nas/inbox/"), uri("mem://phone/music/"));
+        storage.create_dir(&inbox).await.unwrap();
+        storage.create_dir(&phone).await.unwrap();
+        storage.write(&phone.join("00.opus"), b"old").await.unwrap();
+        for name in ["01.opus", "02.opus"] {
+            storage.write(&inbox.join(name), b"opus").await.unwrap();
+        }
+        let mut root = RootConfig::new(phone.clone());
+        // Room for one more file, joined from its segment, but not for two
+        root.quota = Quota::default().with_max_bytes(12);
+
+        let items = ["01.opus", "02.opus"].map(|name| SyncItem {
+            from: inbox.join(name),
+            to: phone.join(name),
+        });
+        let mut execution = SyncExecution::new("phone", items).with_roots(&[root]);
+        let summary = execution
+            .run(&storage, Arc::new(storage.clone()), None)
+            .await
+            .unwrap();
+        assert_eq!((summary.completed, summary.failed), (1, 1));
+        // Its segment is kept for a run with more room
+        let (_, failed) = execution.items().nth(1).unwrap();
+        assert!(matches!(failed, ItemState::Partial(_)), "{failed:?}");
+        assert!(storage.stat(&phone.join("02.opus")).await.is_err());
+    }
+}
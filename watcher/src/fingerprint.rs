@@ -0,0 +1,459 @@
+mod wav;
+
+use crate::storage::{Storage, StorageError};
+use crate::universal_path::UniversalPath;
+use serde::{Deserialize, Serialize};
+use std::{f64::consts::PI, sync::Arc, time::Duration};
+use thiserror::Error;
+use tokio::io::AsyncReadExt;
+
+pub use wav::WavDecoder;
+
+/// Audio is resampled to this before hashing; nothing above a few kHz is looked at
+const SAMPLE_RATE: u32 = 11_025;
+/// Samples per analysed frame, about 186 ms
+const FRAME: usize = 2048;
+/// Samples between frames, about 46 ms
+const HOP: usize = 512;
+/// Bands whose energy differences give a hash its 32 bits
+const BANDS: usize = 33;
+const LOW_HZ: f64 = 300.0;
+const HIGH_HZ: f64 = 2000.0;
+/// Frames one fingerprint may be shifted against another when comparing, about 3 s,
+/// for copies with more or less silence at the start
+const MAX_OFFSET: isize = 64;
+/// Frames two fingerprints must overlap by to be compared at all
+const MIN_OVERLAP: usize = 32;
+/// Bytes read from the storage at a time
+const READ_CHUNK: usize = 64 * 1024;
+/// Unrelated audio scores about 0.5; copies of one recording, however encoded, score
+/// well above this
+pub const DUPLICATE_SIMILARITY: f64 = 0.75;
+
+#[derive(Debug, Error)]
+pub enum FingerprintError {
+    #[error("no decoder reads this file")]
+    Unsupported,
+    #[error("malformed audio: {0}")]
+    Malformed(&'static str),
+    #[error("too little audio to fingerprint")]
+    TooShort,
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+/// Decodes a file to PCM as its bytes arrive, for a `Fingerprinter`.
+pub trait AudioDecoder: Send {
+    /// Decode the next `bytes` of the file, appending interleaved samples between -1
+    /// and 1 to `out`
+    fn push(&mut self, bytes: &[u8], out: &mut Vec<f32>) -> Result<(), FingerprintError>;
+    /// The sample rate and channel count, once the decoder knows them
+    fn format(&self) -> Option<(u32, u16)>;
+}
+
+/// A decoder for a file starting with the given bytes, or `None` if it does not read it
+pub type DecoderFactory = Arc<dyn Fn(&[u8]) -> Option<Box<dyn AudioDecoder>> + Send + Sync>;
+
+/// An acoustic fingerprint: a 32-bit hash of the spectrum every 46 ms, after mixing to
+/// mono and resampling, so it depends on how the recording sounds rather than on how
+/// it was encoded. Each bit is whether the energy difference between two neighbouring
+/// bands between 300 Hz and 2 kHz grew or shrank since the frame before, which
+/// survives lossy encoding, resampling and changes of level.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    /// Of the audio hashed, which is at most the fingerprinter's limit
+    pub duration: Duration,
+    pub hashes: Vec<u32>,
+}
+
+impl Fingerprint {
+    /// How alike two fingerprints are, from about 0.5 for unrelated audio to 1 for
+    /// the same, at the best of the small shifts tried between them
+    pub fn similarity(&self, other: &Fingerprint) -> f64 {
+        let (a, b) = (&self.hashes, &other.hashes);
+        let mut best: f64 = 0.0;
+        for offset in -MAX_OFFSET..=MAX_OFFSET {
+            let (a_start, b_start) = match offset < 0 {
+                true => (offset.unsigned_abs(), 0),
+                false => (0, offset as usize),
+            };
+            let overlap = a
+                .len()
+                .saturating_sub(a_start)
+                .min(b.len().saturating_sub(b_start));
+            if overlap < MIN_OVERLAP.min(a.len().min(b.len())) || overlap == 0 {
+                continue;
+            }
+            let pairs = a[a_start..a_start + overlap].iter().zip(&b[b_start..]);
+            let errors: u32 = pairs.map(|(x, y)| (x ^ y).count_ones()).sum();
+            best = best.max(1.0 - errors as f64 / (32 * overlap) as f64);
+        }
+        best
+    }
+
+    pub fn is_duplicate(&self, other: &Fingerprint) -> bool {
+        self.similarity(other) >= DUPLICATE_SIMILARITY
+    }
+}
+
+/// Groups of the keys in `fingerprints` that are duplicates of one another, directly
+/// or through others in the group, leaving out keys with no duplicate
+pub fn group_duplicates<K: Clone>(fingerprints: &[(K, Fingerprint)]) -> Vec<Vec<K>> {
+    let mut group: Vec<usize> = (0..fingerprints.len()).collect();
+    fn root(group: &mut [usize], mut i: usize) -> usize {
+        while group[i] != i {
+            group[i] = group[group[i]];
+            i = group[i];
+        }
+        i
+    }
+    for i in 0..fingerprints.len() {
+        for j in i + 1..fingerprints.len() {
+            if fingerprints[i].1.is_duplicate(&fingerprints[j].1) {
+                let (a, b) = (root(&mut group, i), root(&mut group, j));
+                group[b] = a;
+            }
+        }
+    }
+    let mut groups: Vec<(usize, Vec<K>)> = Vec::new();
+    for (i, (key, _)) in fingerprints.iter().enumerate() {
+        let r = root(&mut group, i);
+        match groups.iter_mut().find(|(g, _)| *g == r) {
+            Some((_, keys)) => keys.push(key.clone()),
+            None => groups.push((r, vec![key.clone()])),
+        }
+    }
+    groups
+        .into_iter()
+        .map(|(_, keys)| keys)
+        .filter(|keys| keys.len() > 1)
+        .collect()
+}
+
+/// Computes fingerprints of files on any storage, streaming them through
+/// `read_stream` and stopping once it has heard enough.
+///
+/// WAV is decoded out of the box. Compressed formats need a decoder added with
+/// `with_decoder`, such as one wrapping a codec library; decoders are asked in the
+/// order they were added, the built-in ones last.
+#[derive(Clone)]
+pub struct Fingerprinter {
+    decoders: Vec<DecoderFactory>,
+    max_duration: Duration,
+}
+
+impl Default for Fingerprinter {
+    fn default() -> Self {
+        Fingerprinter {
+            decoders: Vec::new(),
+            max_duration: Duration::from_secs(120),
+        }
+    }
+}
+
+impl Fingerprinter {
+    pub fn new() -> Self {
+        Fingerprinter::default()
+    }
+
+    pub fn with_decoder<F>(mut self, factory: F) -> Self
+    where
+        F: Fn(&[u8]) -> Option<Box<dyn AudioDecoder>> + Send + Sync + 'static,
+    {
+        self.decoders.push(Arc::new(factory));
+        self
+    }
+
+    /// How much of each file is heard, from its start; two minutes by default
+    pub fn with_max_duration(mut self, max: Duration) -> Self {
+        self.max_duration = max;
+        self
+    }
+
+    /// The fingerprint of the audio file at `path`
+    pub async fn fingerprint(
+        &self,
+        storage: &dyn Storage,
+        path: &UniversalPath,
+    ) -> Result<Fingerprint, FingerprintError> {
+        let mut stream = storage.read_stream(path).await?;
+        let mut buf = vec![0; READ_CHUNK];
+        let mut head = 0;
+        // Enough of the start for any decoder to recognize the file
+        while head < 64 {
+            let read = stream.read(&mut buf[head..]).await.map_err(io_error)?;
+            if read == 0 {
+                break;
+            }
+            head += read;
+        }
+        let mut decoder = self
+            .decoders
+            .iter()
+            .find_map(|factory| factory(&buf[..head]))
+            .or_else(|| WavDecoder::accepts(&buf[..head]).then(|| Box::new(WavDecoder::new()) as _))
+            .ok_or(FingerprintError::Unsupported)?;
+
+        let limit = self.max_duration.as_secs_f64();
+        let mut hasher: Option<Hasher> = None;
+        let mut samples = Vec::new();
+        let mut len = head;
+        loop {
+            samples.clear();
+            decoder.push(&buf[..len], &mut samples)?;
+            if hasher.is_none()
+                && let Some((rate, channels)) = decoder.format()
+            {
+                if rate == 0 || channels == 0 {
+                    return Err(FingerprintError::Malformed("no sample rate or channels"));
+                }
+                hasher = Some(Hasher::new(rate, channels));
+            }
+            if let Some(hasher) = &mut hasher {
+                hasher.push(&samples);
+                if hasher.seconds() >= limit {
+                    break;
+                }
+            }
+            len = stream.read(&mut buf).await.map_err(io_error)?;
+            if len == 0 {
+                break;
+            }
+        }
+        let fingerprint = hasher.ok_or(FingerprintError::TooShort)?.finish();
+        if fingerprint.hashes.is_empty() {
+            return Err(FingerprintError::TooShort);
+        }
+        Ok(fingerprint)
+    }
+}
+
+fn io_error(e: std::io::Error) -> FingerprintError {
+    FingerprintError::Storage(StorageError::Io(e))
+}
+
+/// Turns interleaved samples into hashes as they come
+struct Hasher {
+    channels: usize,
+    /// Input samples per output sample
+    ratio: f64,
+    /// Into the current output sample, and the input summed over it
+    phase: f64,
+    sum: f32,
+    count: u32,
+    last: f32,
+    /// A mono frame left over from the last call
+    partial: Vec<f32>,
+    frame: Vec<f32>,
+    input_frames: u64,
+    rate: u32,
+    window: Vec<f64>,
+    band_bins: [usize; BANDS + 1],
+    previous: Option<[f64; BANDS]>,
+    hashes: Vec<u32>,
+}
+
+impl Hasher {
+    fn new(rate: u32, channels: u16) -> Self {
+        let window = (0..FRAME)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f64 / FRAME as f64).cos())
+            .collect();
+        let bin_hz = SAMPLE_RATE as f64 / FRAME as f64;
+        let band_bins = std::array::from_fn(|i| {
+            let hz = LOW_HZ * (HIGH_HZ / LOW_HZ).powf(i as f64 / BANDS as f64);
+            (hz / bin_hz).round() as usize
+        });
+        Hasher {
+            channels: channels as usize,
+            ratio: rate as f64 / SAMPLE_RATE as f64,
+            phase: 0.0,
+            sum: 0.0,
+            count: 0,
+            last: 0.0,
+            partial: Vec::new(),
+            frame: Vec::with_capacity(FRAME * 2),
+            input_frames: 0,
+            rate,
+            window,
+            band_bins,
+            previous: None,
+            hashes: Vec::new(),
+        }
+    }
+
+    fn seconds(&self) -> f64 {
+        self.input_frames as f64 / self.rate as f64
+    }
+
+    fn push(&mut self, samples: &[f32]) {
+        self.partial.extend_from_slice(samples);
+        let whole = self.partial.len() / self.channels * self.channels;
+        let partial = std::mem::take(&mut self.partial);
+        for frame in partial[..whole].chunks_exact(self.channels) {
+            let mono = frame.iter().sum::<f32>() / self.channels as f32;
+            self.resample(mono);
+        }
+        self.partial = partial[whole..].to_vec();
+    }
+
+    /// Average the input over each output sample, which keeps what would alias out
+    fn resample(&mut self, mono: f32) {
+        self.input_frames += 1;
+        self.sum += mono;
+        self.count += 1;
+        self.phase += 1.0;
+        while self.phase >= self.ratio {
+            self.phase -= self.ratio;
+            if self.count > 0 {
+                self.last = self.sum / self.count as f32;
+                (self.sum, self.count) = (0.0, 0);
+            }
+            self.frame.push(self.last);
+            if self.frame.len() == FRAME {
+                self.analyse();
+                self.frame.drain(..HOP);
+            }
+        }
+    }
+
+    fn analyse(&mut self) {
+        let mut re: Vec<f64> = self
+            .frame
+            .iter()
+            .zip(&self.window)
+            .map(|(&s, w)| s as f64 * w)
+            .collect();
+        let mut im = vec![0.0; FRAME];
+        fft(&mut re, &mut im);
+        let mut energy = [0.0; BANDS];
+        for (band, energy) in energy.iter_mut().enumerate() {
+            let bins = self.band_bins[band]..self.band_bins[band + 1].max(self.band_bins[band] + 1);
+            *energy = bins.map(|k| re[k] * re[k] + im[k] * im[k]).sum();
+        }
+        if let Some(previous) = &self.previous {
+            let mut hash = 0u32;
+            for m in 0..BANDS - 1 {
+                let now = energy[m] - energy[m + 1];
+                let before = previous[m] - previous[m + 1];
+                if now - before > 0.0 {
+                    hash |= 1 << m;
+                }
+            }
+            self.hashes.push(hash);
+        }
+        self.previous = Some(energy);
+    }
+
+    fn finish(self) -> Fingerprint {
+        Fingerprint {
+            duration: Duration::from_secs_f64(self.seconds()),
+            hashes: self.hashes,
+        }
+    }
+}
+
+/// In-place radix-2 FFT; the length must be a power of two
+fn fft(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f64;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f64).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * cos - im[b] * sin;
+                let t_im = re[b] * sin + im[b] * cos;
+                (re[b], im[b]) = (re[a] - t_re, im[a] - t_im);
+                (re[a], im[a]) = (re[a] + t_re, im[a] + t_im);
+            }
+        }
+        len <<= 1;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    /// Ten seconds of a tune over noise, the same for the same `seed`
+    pub(crate) fn recording(seed: u32, rate: u32) -> Vec<f32> {
+        let notes: Vec<f64> = (0..20)
+            .map(|i| 220.0 * 2f64.powf(((seed as usize * 7 + i * 5) % 24) as f64 / 12.0))
+            .collect();
+        (0..rate * 10)
+            .map(|i| {
+                let t = i as f64 / rate as f64;
+                let note = notes[(t * 2.0) as usize % notes.len()];
+                // The same noise at every rate, stepped at 11.025 kHz
+                let mut noise = (t * SAMPLE_RATE as f64) as u32 ^ seed.wrapping_mul(0x9E37_79B9);
+                for (shift, factor) in [(16, 0x7FEB_352D), (15, 0x846C_A68B)] {
+                    noise = (noise ^ (noise >> shift)).wrapping_mul(factor);
+                }
+                let hiss = (noise >> 16) as f64 / 65_536.0 - 0.5;
+                (0.4 * (2.0 * PI * note * t).sin() + 0.2 * (4.0 * PI * note * t).sin() + 0.1 * hiss)
+                    as f32
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_fingerprint() {
+        let storage = MemoryStorage::new();
+        let path = |name: &str| UniversalPath::from_uri_str(&format!("mem://lib/{name}")).unwrap();
+        // One recording as 16-bit stereo at 44.1 kHz and as float mono at 22.05 kHz
+        let cd = wav::tests::encode(&recording(1, 44_100), 44_100, 2, 16);
+        let low = wav::tests::encode(&recording(1, 22_050), 22_050, 1, 32);
+        let other = wav::tests::encode(&recording(2, 44_100), 44_100, 2, 16);
+        assert_ne!(cd, low);
+        for (name, bytes) in [("cd.wav", &cd), ("low.wav", &low), ("other.wav", &other)] {
+            storage.write(&path(name), bytes).await.unwrap();
+        }
+
+        let fingerprinter = Fingerprinter::new();
+        let mut prints = Vec::new();
+        for name in ["cd.wav", "low.wav", "other.wav"] {
+            let print = fingerprinter
+                .fingerprint(&storage, &path(name))
+                .await
+                .unwrap();
+            prints.push((name, print));
+        }
+        let ten = Duration::from_secs(10);
+        assert!(prints.iter().all(|(_, p)| p.duration == ten));
+        let same = prints[0].1.similarity(&prints[1].1);
+        let different = prints[0].1.similarity(&prints[2].1);
+        assert!(same > 0.9, "{same}");
+        assert!(different < 0.65, "{different}");
+        assert_eq!(group_duplicates(&prints), [["cd.wav", "low.wav"]]);
+
+        // Only as much as the limit is heard
+        let short = Fingerprinter::new().with_max_duration(Duration::from_secs(2));
+        let print = short.fingerprint(&storage, &path("cd.wav")).await.unwrap();
+        assert!(print.duration < Duration::from_secs(3));
+        assert!(print.similarity(&prints[0].1) > 0.9);
+
+        storage
+            .write(&path("notes.txt"), b"not audio at all, just words")
+            .await
+            .unwrap();
+        let text = fingerprinter
+            .fingerprint(&storage, &path("notes.txt"))
+            .await;
+        assert!(matches!(text, Err(FingerprintError::Unsupported)));
+    }
+}
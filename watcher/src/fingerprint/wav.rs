@@ -0,0 +1,192 @@
+use super::{AudioDecoder, FingerprintError};
+
+const PCM: u16 = 1;
+const FLOAT: u16 = 3;
+const EXTENSIBLE: u16 = 0xFFFE;
+
+/// Decodes RIFF WAVE files of integer PCM, 8 to 32 bits, or 32-bit float.
+#[derive(Debug, Default)]
+pub struct WavDecoder {
+    /// Past the RIFF header
+    started: bool,
+    /// Bytes not yet decoded: a chunk header, the format chunk or part of a sample
+    pending: Vec<u8>,
+    /// Of a chunk being skipped
+    skip: u64,
+    /// Left in the data chunk, once it is reached
+    data: Option<u64>,
+    format: Option<Format>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Format {
+    float: bool,
+    channels: u16,
+    rate: u32,
+    bits: u16,
+}
+
+impl WavDecoder {
+    pub fn new() -> Self {
+        WavDecoder::default()
+    }
+
+    /// Whether `head` is the start of a WAVE file
+    pub fn accepts(head: &[u8]) -> bool {
+        head.len() >= 12 && &head[..4] == b"RIFF" && &head[8..12] == b"WAVE"
+    }
+
+    /// Walk the chunks before the data, returning false until more bytes are needed
+    fn header(&mut self) -> Result<bool, FingerprintError> {
+        let mut at = 0;
+        if !self.started {
+            if self.pending.len() < 12 {
+                return Ok(false);
+            }
+            (self.started, at) = (true, 12);
+        }
+        loop {
+            let Some(header) = self.pending.get(at..at + 8) else {
+                self.pending.drain(..at);
+                return Ok(false);
+            };
+            let len = u32::from_le_bytes(header[4..8].try_into().expect("4 bytes")) as u64;
+            // Chunks are padded to an even length
+            let padded = len + (len & 1);
+            match &header[..4] {
+                b"data" => {
+                    if self.format.is_none() {
+                        return Err(FingerprintError::Malformed("data before the format"));
+                    }
+                    self.pending.drain(..at + 8);
+                    self.data = Some(len);
+                    return Ok(true);
+                }
+                b"fmt " => {
+                    let Some(body) = self.pending.get(at + 8..at + 8 + len as usize) else {
+                        self.pending.drain(..at);
+                        return Ok(false);
+                    };
+                    self.format = Some(parse_format(body)?);
+                    at += 8 + padded as usize;
+                }
+                _ => {
+                    let available = (self.pending.len() - at - 8) as u64;
+                    if available < padded {
+                        self.skip = padded - available;
+                        self.pending.clear();
+                        return Ok(false);
+                    }
+                    at += 8 + padded as usize;
+                }
+            }
+        }
+    }
+}
+
+fn parse_format(body: &[u8]) -> Result<Format, FingerprintError> {
+    let u16_at = |at: usize| {
+        body.get(at..at + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+    };
+    let malformed = || FingerprintError::Malformed("short format chunk");
+    let mut tag = u16_at(0).ok_or_else(malformed)?;
+    if tag == EXTENSIBLE {
+        // The real format is the start of the sub-format GUID
+        tag = u16_at(24).ok_or_else(malformed)?;
+    }
+    let rate = body.get(4..8).ok_or_else(malformed)?;
+    let format = Format {
+        float: tag == FLOAT,
+        channels: u16_at(2).ok_or_else(malformed)?,
+        rate: u32::from_le_bytes(rate.try_into().expect("4 bytes")),
+        bits: u16_at(14).ok_or_else(malformed)?,
+    };
+    let supported = match tag {
+        PCM => (8..=32).contains(&format.bits) && format.bits.is_multiple_of(8),
+        FLOAT => format.bits == 32,
+        _ => false,
+    };
+    match supported && format.channels > 0 {
+        true => Ok(format),
+        false => Err(FingerprintError::Unsupported),
+    }
+}
+
+impl AudioDecoder for WavDecoder {
+    fn push(&mut self, mut bytes: &[u8], out: &mut Vec<f32>) -> Result<(), FingerprintError> {
+        let skipped = self.skip.min(bytes.len() as u64);
+        self.skip -= skipped;
+        bytes = &bytes[skipped as usize..];
+        self.pending.extend_from_slice(bytes);
+        if self.data.is_none() && !self.header()? {
+            return Ok(());
+        }
+        let (Some(format), Some(left)) = (self.format, self.data.as_mut()) else {
+            return Ok(());
+        };
+        let width = (format.bits / 8) as usize;
+        let usable = (self.pending.len() as u64).min(*left) as usize / width * width;
+        for sample in self.pending[..usable].chunks_exact(width) {
+            out.push(match (format.float, width) {
+                (true, _) => f32::from_le_bytes(sample.try_into().expect("4 bytes")),
+                // 8-bit is unsigned
+                (false, 1) => (sample[0] as f32 - 128.0) / 128.0,
+                (false, _) => {
+                    let mut wide = [0; 4];
+                    wide[4 - width..].copy_from_slice(sample);
+                    i32::from_le_bytes(wide) as f32 / i32::MAX as f32
+                }
+            });
+        }
+        *left -= usable as u64;
+        self.pending.drain(..usable);
+        if *left == 0 {
+            // Whatever follows the data is not audio
+            self.pending.clear();
+        }
+        Ok(())
+    }
+
+    fn format(&self) -> Option<(u32, u16)> {
+        self.format.map(|f| (f.rate, f.channels))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    /// `mono` as a WAVE file of `channels` copies, in integer PCM of `bits` or, at 32
+    /// bits, float
+    pub(crate) fn encode(mono: &[f32], rate: u32, channels: u16, bits: u16) -> Vec<u8> {
+        let width = bits as usize / 8;
+        let mut data = Vec::with_capacity(mono.len() * width * channels as usize);
+        for &sample in mono {
+            for _ in 0..channels {
+                match bits {
+                    16 => data.extend(((sample * i16::MAX as f32) as i16).to_le_bytes()),
+                    _ => data.extend(sample.to_le_bytes()),
+                }
+            }
+        }
+        let tag: u16 = if bits == 32 { 3 } else { 1 };
+        let mut file = b"RIFF".to_vec();
+        file.extend((36 + 12 + data.len() as u32).to_le_bytes());
+        file.extend(b"WAVE");
+        file.extend(b"fmt ");
+        file.extend(16u32.to_le_bytes());
+        file.extend(tag.to_le_bytes());
+        file.extend(channels.to_le_bytes());
+        file.extend(rate.to_le_bytes());
+        file.extend((rate * channels as u32 * width as u32).to_le_bytes());
+        file.extend((channels * width as u16).to_le_bytes());
+        file.extend(bits.to_le_bytes());
+        // A chunk the decoder has to step over
+        file.extend(b"LIST");
+        file.extend(3u32.to_le_bytes());
+        file.extend(b"abc\0");
+        file.extend(b"data");
+        file.extend((data.len() as u32).to_le_bytes());
+        file.extend(data);
+        file
+    }
+}
@@ -0,0 +1,406 @@
+use crate::jobs::JobSpec;
+use crate::root::RootConfig;
+use crate::storage::{Storage, StorageError, matches_segments};
+use crate::universal_path::UniversalPath;
+use crate::watch::WatchEvent;
+use rhai::{AST, Dynamic, Engine, EvalAltResult, Map, Scope};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::runtime::Handle;
+
+/// Most bytes `read_text` hands a script, so a hook cannot pull a whole album into
+/// memory.
+pub const HOOK_READ_LIMIT: u64 = 1 << 20;
+/// Upper bound on the work one run of a hook may do, in Rhai operations.
+pub const HOOK_MAX_OPERATIONS: u64 = 1_000_000;
+
+#[derive(Debug, Error)]
+pub enum HookError {
+    #[error("hook {hook} does not compile: {message}")]
+    Compile { hook: String, message: String },
+    #[error("hook {hook} failed: {message}")]
+    Script { hook: String, message: String },
+}
+
+/// The kinds of change a hook can run on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookTrigger {
+    Created,
+    Modified,
+    Deleted,
+    Renamed,
+    /// A file has stopped changing, when events go through a `StabilityFilter`
+    Ready,
+}
+
+impl HookTrigger {
+    fn of(event: &WatchEvent) -> Option<Self> {
+        match event {
+            WatchEvent::Created(_) => Some(HookTrigger::Created),
+            WatchEvent::Modified(_) => Some(HookTrigger::Modified),
+            WatchEvent::Deleted(_) => Some(HookTrigger::Deleted),
+            WatchEvent::Renamed { .. } => Some(HookTrigger::Renamed),
+            WatchEvent::FileReady(_) => Some(HookTrigger::Ready),
+            WatchEvent::Rescan(_) => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            HookTrigger::Created => "created",
+            HookTrigger::Modified => "modified",
+            HookTrigger::Deleted => "deleted",
+            HookTrigger::Renamed => "renamed",
+            HookTrigger::Ready => "ready",
+        }
+    }
+}
+
+/// A Rhai script run on matching changes under a root, e.g.
+///
+/// ```text
+/// on: [created], pattern: "incoming/**/*.flac"
+/// enqueue("organize", #{ path: path });
+/// notify(`new file ${name}`);
+/// ```
+///
+/// Scripts see `event`, `root`, `path`, `from` (for renames, otherwise `()`), `name`,
+/// `extension` and `relative`, the path below the root. They can call `exists`,
+/// `size`, `list` and `read_text` on paths under the root, and `enqueue` and `notify`
+/// to ask for work; nothing else leaves the sandbox.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HookConfig {
+    pub name: String,
+    /// Changes that run the hook; empty means all of them
+    #[serde(default)]
+    pub on: Vec<HookTrigger>,
+    /// Glob over the path relative to the root, as for `Storage::glob`; empty matches
+    /// everything
+    #[serde(default)]
+    pub pattern: String,
+    pub script: String,
+}
+
+impl HookConfig {
+    pub fn new<N: Into<String>, S: Into<String>>(name: N, script: S) -> Self {
+        HookConfig {
+            name: name.into(),
+            on: Vec::new(),
+            pattern: String::new(),
+            script: script.into(),
+        }
+    }
+
+    pub fn on(mut self, trigger: HookTrigger) -> Self {
+        self.on.push(trigger);
+        self
+    }
+
+    pub fn with_pattern<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.pattern = pattern.into();
+        self
+    }
+}
+
+/// What one run of a hook asked for. Nothing has happened yet: the caller enqueues
+/// the jobs and delivers the notifications.
+#[derive(Debug, Clone, Default)]
+pub struct HookOutcome {
+    pub hook: String,
+    pub jobs: Vec<JobSpec>,
+    pub notifications: Vec<String>,
+    /// Lines the script printed
+    pub log: Vec<String>,
+}
+
+struct CompiledHook {
+    config: HookConfig,
+    ast: AST,
+}
+
+/// The hooks of one root, compiled once and run on each change under it.
+pub struct HookRunner {
+    root: UniversalPath,
+    storage: Arc<dyn Storage>,
+    hooks: Arc<Vec<CompiledHook>>,
+}
+
+impl HookRunner {
+    /// Compile every hook in `root.hooks`, failing on the first that does not compile
+    pub fn new(root: &RootConfig, storage: Arc<dyn Storage>) -> Result<Self, HookError> {
+        let engine = sandboxed_engine();
+        let hooks = root
+            .hooks
+            .iter()
+            .map(|config| {
+                let ast = engine
+                    .compile(&config.script)
+                    .map_err(|e| HookError::Compile {
+                        hook: config.name.clone(),
+                        message: e.to_string(),
+                    })?;
+                Ok(CompiledHook {
+                    config: config.clone(),
+                    ast,
+                })
+            })
+            .collect::<Result<Vec<_>, HookError>>()?;
+        Ok(HookRunner {
+            root: root.path.as_dir(),
+            storage,
+            hooks: Arc::new(hooks),
+        })
+    }
+
+    /// Run every hook that matches `event`, in configuration order, on the blocking
+    /// pool. One result per hook that ran, so one broken hook does not hide the others.
+    pub async fn run(&self, event: &WatchEvent) -> Vec<Result<HookOutcome, HookError>> {
+        let Some(trigger) = HookTrigger::of(event) else {
+            return Vec::new();
+        };
+        let Some(relative) = event.path().relative_to(&self.root) else {
+            return Vec::new();
+        };
+        let matching: Vec<usize> = self
+            .hooks
+            .iter()
+            .enumerate()
+            .filter(|(_, hook)| {
+                (hook.config.on.is_empty() || hook.config.on.contains(&trigger))
+                    && (hook.config.pattern.is_empty()
+                        || matches_segments(&hook.config.pattern, &relative))
+            })
+            .map(|(i, _)| i)
+            .collect();
+        if matching.is_empty() {
+            return Vec::new();
+        }
+
+        let context = Context {
+            root: self.root.clone(),
+            storage: self.storage.clone(),
+            handle: Handle::current(),
+        };
+        let hooks = self.hooks.clone();
+        let event = event.clone();
+        let task = tokio::task::spawn_blocking(move || {
+            matching
+                .into_iter()
+                .map(|i| context.run(&hooks[i], trigger, &event, &relative))
+                .collect()
+        });
+        task.await.expect("hook task panicked")
+    }
+}
+
+/// An engine with limits on work, depth and sizes, no `eval`, and no functions that
+/// reach outside the script
+fn sandboxed_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_max_operations(HOOK_MAX_OPERATIONS)
+        .set_max_call_levels(32)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(HOOK_READ_LIMIT as usize)
+        .set_max_array_size(10_000)
+        .set_max_map_size(10_000)
+        .disable_symbol("eval");
+    engine
+}
+
+/// What a run needs to reach storage from the blocking pool
+#[derive(Clone)]
+struct Context {
+    root: UniversalPath,
+    storage: Arc<dyn Storage>,
+    handle: Handle,
+}
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+impl Context {
+    fn run(
+        &self,
+        hook: &CompiledHook,
+        trigger: HookTrigger,
+        event: &WatchEvent,
+        relative: &[String],
+    ) -> Result<HookOutcome, HookError> {
+        let outcome = Arc::new(Mutex::new(HookOutcome {
+            hook: hook.config.name.clone(),
+            ..HookOutcome::default()
+        }));
+        let mut engine = sandboxed_engine();
+        self.register(&mut engine, &outcome);
+
+        let path = event.path();
+        let mut scope = Scope::new();
+        scope.push_constant("event", trigger.name());
+        scope.push_constant("root", self.root.to_string());
+        scope.push_constant("path", path.to_string());
+        scope.push_constant("relative", relative.join("/"));
+        scope.push_constant("name", path.last_segment().unwrap_or_default().to_string());
+        scope.push_constant(
+            "extension",
+            path.extension().unwrap_or_default().to_string(),
+        );
+        scope.push_constant(
+            "from",
+            match event {
+                WatchEvent::Renamed { from, .. } => Dynamic::from(from.to_string()),
+                _ => Dynamic::UNIT,
+            },
+        );
+        engine
+            .run_ast_with_scope(&mut scope, &hook.ast)
+            .map_err(|e| HookError::Script {
+                hook: hook.config.name.clone(),
+                message: e.to_string(),
+            })?;
+        drop(engine);
+        let outcome = Arc::try_unwrap(outcome).expect("engine dropped");
+        Ok(outcome.into_inner().unwrap())
+    }
+
+    fn register(&self, engine: &mut Engine, outcome: &Arc<Mutex<HookOutcome>>) {
+        let out = outcome.clone();
+        engine.on_print(move |line| out.lock().unwrap().log.push(line.to_string()));
+        let out = outcome.clone();
+        engine.on_debug(move |line, _, _| out.lock().unwrap().log.push(line.to_string()));
+
+        let out = outcome.clone();
+        engine.register_fn(
+            "enqueue",
+            move |kind: &str, payload: Map| -> ScriptResult<()> {
+                let payload: serde_json::Value = rhai::serde::from_dynamic(&payload.into())?;
+                out.lock().unwrap().jobs.push(JobSpec::new(kind, payload));
+                Ok(())
+            },
+        );
+        let out = outcome.clone();
+        engine.register_fn("enqueue", move |kind: &str| {
+            let spec = JobSpec::new(kind, serde_json::Value::Null);
+            out.lock().unwrap().jobs.push(spec);
+        });
+        let out = outcome.clone();
+        engine.register_fn("notify", move |message: &str| {
+            out.lock().unwrap().notifications.push(message.to_string());
+        });
+
+        let cx = self.clone();
+        engine.register_fn("exists", move |uri: &str| -> ScriptResult<bool> {
+            let path = cx.resolve(uri)?;
+            match cx.handle.block_on(cx.storage.stat(&path)) {
+                Ok(_) => Ok(true),
+                Err(StorageError::NotFound) => Ok(false),
+                Err(e) => Err(script_error(e)),
+            }
+        });
+        let cx = self.clone();
+        engine.register_fn("size", move |uri: &str| -> ScriptResult<Dynamic> {
+            let path = cx.resolve(uri)?;
+            let meta = cx
+                .handle
+                .block_on(cx.storage.stat(&path))
+                .map_err(script_error)?;
+            Ok(meta
+                .size_bytes
+                .and_then(|size| i64::try_from(size).ok())
+                .map_or(Dynamic::UNIT, Dynamic::from))
+        });
+        let cx = self.clone();
+        engine.register_fn("list", move |uri: &str| -> ScriptResult<rhai::Array> {
+            let path = cx.resolve(uri)?;
+            let children = cx
+                .handle
+                .block_on(cx.storage.list(&path))
+                .map_err(script_error)?;
+            Ok(children
+                .into_iter()
+                .map(|child| Dynamic::from(child.to_string()))
+                .collect())
+        });
+        let cx = self.clone();
+        engine.register_fn("read_text", move |uri: &str| -> ScriptResult<String> {
+            let path = cx.resolve(uri)?;
+            let bytes = cx
+                .handle
+                .block_on(cx.storage.read_range(&path, 0..HOOK_READ_LIMIT))
+                .map_err(script_error)?;
+            Ok(String::from_utf8_lossy(&bytes).into_owned())
+        });
+    }
+
+    /// A URI from a script, which must name something under the root
+    fn resolve(&self, uri: &str) -> ScriptResult<UniversalPath> {
+        UniversalPath::from_uri_str(uri)
+            .ok()
+            .filter(|path| path.relative_to(&self.root).is_some())
+            .ok_or_else(|| format!("{uri} is not under {}", self.root).into())
+    }
+}
+
+fn script_error(e: StorageError) -> Box<EvalAltResult> {
+    e.to_string().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LocalStorage;
+    use std::fs;
+
+    #[tokio::test]
+    async fn test_hooks_run_on_matching_changes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("incoming/Album")).unwrap();
+        fs::write(dir.join("incoming/Album/01.flac"), b"fLaC").unwrap();
+        let mut root = RootConfig::new(UniversalPath::local(dir.to_str().unwrap()));
+        root.hooks = vec![
+            HookConfig::new(
+                "organize",
+                r#"
+                    if exists(path) && size(path) == 4 {
+                        enqueue("organize", #{ path: path, top: list(root).len() });
+                        notify(`new ${extension} file ${name}`);
+                    }
+                    print(relative);
+                "#,
+            )
+            .on(HookTrigger::Created)
+            .with_pattern("incoming/**/*.flac"),
+            HookConfig::new("escape", r#"read_text("file:///etc/passwd")"#),
+        ];
+        let runner = HookRunner::new(&root, Arc::new(LocalStorage::new())).unwrap();
+        let track = root.path.join("incoming").join("Album").join("01.flac");
+
+        let outcomes = runner.run(&WatchEvent::Created(track.clone())).await;
+        assert_eq!(outcomes.len(), 2);
+        let organize = outcomes[0].as_ref().unwrap();
+        assert_eq!(organize.jobs.len(), 1);
+        assert_eq!(organize.jobs[0].kind, "organize");
+        assert_eq!(organize.jobs[0].payload["path"], track.to_string());
+        assert_eq!(organize.jobs[0].payload["top"], 1);
+        assert_eq!(organize.notifications, ["new flac file 01.flac"]);
+        assert_eq!(organize.log, ["incoming/Album/01.flac"]);
+        let escape = outcomes[1].as_ref().unwrap_err().to_string();
+        assert!(escape.contains("is not under"), "{escape}");
+
+        // Only the catch-all hook runs on a modification
+        let outcomes = runner.run(&WatchEvent::Modified(track)).await;
+        assert_eq!(outcomes.len(), 1);
+
+        root.hooks = vec![HookConfig::new("broken", "let x = ;")];
+        assert!(matches!(
+            HookRunner::new(&root, Arc::new(LocalStorage::new())),
+            Err(HookError::Compile { .. })
+        ));
+        root.hooks = vec![HookConfig::new("spin", "loop {}")];
+        let runner = HookRunner::new(&root, Arc::new(LocalStorage::new())).unwrap();
+        let outcomes = runner.run(&WatchEvent::Deleted(root.path.join("x"))).await;
+        assert!(outcomes[0].is_err());
+    }
+}
@@ -0,0 +1,706 @@
+use crate::{
+    catalog::{Catalog, CatalogEntry},
+    lyrics::LyricsRecord,
+    namespace::{FederatedEntry, LIBRARY_SCHEME, Namespace},
+    root::RootConfig,
+    storage::{
+        Checksum, ChecksumAlgorithm, EntryKind, EntryMetadata, Storage, StorageBackend,
+        StorageError, StorageExt, mtimes_match,
+    },
+    universal_path::UniversalPath,
+};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    time::{Duration, SystemTime},
+};
+
+mod shared;
+mod store;
+mod wal;
+pub use shared::{IndexWriter, SharedIndex};
+pub use store::{
+    FileIndexStore, IndexQuery, IndexStore, IndexStoreError, RecoveryReport, SharedIndexStore,
+    StoredEntries, WalkSummary, update_from_walk,
+};
+pub use wal::FsyncPolicy;
+pub(crate) use wal::sync_parent;
+
+/// What happened to an indexed file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Removed,
+    /// Moved here from `from`
+    Renamed {
+        from: UniversalPath,
+    },
+}
+
+/// How the index learned of a change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeSource {
+    /// A full pass over a root
+    Scan,
+    /// An event from a watcher
+    Watch,
+    /// Entries taken over from another index or an export
+    Import,
+}
+
+/// One change in the index's history.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChangeRecord {
+    pub path: UniversalPath,
+    pub kind: ChangeKind,
+    pub source: ChangeSource,
+    pub at: SystemTime,
+}
+
+/// How far processing of an indexed file has got since it last changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ScanState {
+    /// New or changed, waiting for tags, checksums and the like to be read
+    #[default]
+    Pending,
+    Scanned,
+    /// The last attempt failed and will not be retried until the file changes
+    Failed {
+        error: String,
+    },
+}
+
+/// A file as the index last saw it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub path: UniversalPath,
+    pub size_bytes: Option<u64>,
+    pub modified_at: Option<SystemTime>,
+    /// When the file first appeared in the index, kept across modifications and renames
+    pub added_at: SystemTime,
+    /// When the index last recorded a change to it
+    pub changed_at: SystemTime,
+    /// Of the contents as of `changed_at`, if computed; cleared when the file changes
+    #[serde(default)]
+    pub checksum: Option<Checksum>,
+    /// Reset to `Pending` when the file changes
+    #[serde(default)]
+    pub scan_state: ScanState,
+    /// Where the file was before `rename_transliterated` gave it a name the
+    /// destination can take, so the original name is not lost
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_path: Option<UniversalPath>,
+    /// The `.lrc` sidecar fetched for it, if any, and where from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lyrics: Option<LyricsRecord>,
+}
+
+impl IndexEntry {
+    /// A file first seen at `at`
+    pub fn new(path: &UniversalPath, meta: &EntryMetadata, at: SystemTime) -> Self {
+        IndexEntry {
+            path: path.as_file(),
+            size_bytes: meta.size_bytes,
+            modified_at: meta.modified_at,
+            added_at: at,
+            changed_at: at,
+            checksum: None,
+            scan_state: ScanState::Pending,
+            original_path: None,
+            lyrics: None,
+        }
+    }
+
+    /// Bring the entry up to `meta`, returning true if the file changed: its size
+    /// differs, or its modification time by more than `tolerance`. A changed file
+    /// loses its checksum and goes back to `Pending`.
+    pub fn refresh(&mut self, meta: &EntryMetadata, at: SystemTime, tolerance: Duration) -> bool {
+        if self.size_bytes == meta.size_bytes
+            && mtimes_match(self.modified_at, meta.modified_at, tolerance)
+        {
+            return false;
+        }
+        self.size_bytes = meta.size_bytes;
+        self.modified_at = meta.modified_at;
+        self.changed_at = at;
+        self.checksum = None;
+        self.scan_state = ScanState::Pending;
+        true
+    }
+}
+
+/// Every file seen under the library's roots, with the history of how each came and
+/// changed, for questions such as what was added this week.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Index {
+    /// By the path's printed URI
+    entries: BTreeMap<String, IndexEntry>,
+    /// In the order recorded
+    history: Vec<ChangeRecord>,
+    /// Other libraries' catalogs by name, read-only and never saved with the index
+    #[serde(skip)]
+    mounts: BTreeMap<String, Catalog>,
+    /// Roots federated under logical paths, by namespace name; never saved with the
+    /// index
+    #[serde(skip)]
+    namespaces: BTreeMap<String, Namespace>,
+    /// How far a modification time may move before `update` calls the file modified
+    #[serde(skip)]
+    mtime_tolerance: Duration,
+}
+
+fn key(path: &UniversalPath) -> String {
+    path.as_file().to_string()
+}
+
+impl Index {
+    pub fn new() -> Self {
+        Index::default()
+    }
+
+    /// The entries `store` holds, with no history
+    pub fn from_store(store: &dyn IndexStore) -> Result<Self, IndexStoreError> {
+        let mut index = Index::new();
+        for entry in store.entries() {
+            let entry = entry?;
+            index.entries.insert(key(&entry.path), entry);
+        }
+        Ok(index)
+    }
+
+    /// Write the files `records` changed to `store` as the index has them now, and
+    /// flush it
+    pub fn persist(
+        &self,
+        records: &[ChangeRecord],
+        store: &mut dyn IndexStore,
+    ) -> Result<(), IndexStoreError> {
+        for record in records {
+            if let ChangeKind::Renamed { from } = &record.kind {
+                store.remove(from)?;
+            }
+            match self.get(&record.path) {
+                Some(entry) => store.put(entry.clone())?,
+                None => {
+                    store.remove(&record.path)?;
+                }
+            }
+        }
+        store.flush()
+    }
+
+    /// Let modification times drift by `tolerance` without recording a change, for
+    /// roots on servers that round them, such as FAT-backed shares and FTP
+    pub fn with_mtime_tolerance(mut self, tolerance: Duration) -> Self {
+        self.mtime_tolerance = tolerance;
+        self
+    }
+
+    pub fn get(&self, path: &UniversalPath) -> Option<&IndexEntry> {
+        self.entries.get(&key(path))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Entries in path order
+    pub fn iter(&self) -> impl Iterator<Item = &IndexEntry> {
+        self.entries.values()
+    }
+
+    pub fn history(&self) -> &[ChangeRecord] {
+        &self.history
+    }
+
+    /// Entries under the query's root that match it, in path order
+    pub fn query<'a>(&'a self, query: &'a IndexQuery) -> impl Iterator<Item = &'a IndexEntry> {
+        self.entries.values().filter(move |entry| {
+            let under = query.under.as_ref();
+            under.is_none_or(|root| entry.path.relative_to(root).is_some()) && query.matches(entry)
+        })
+    }
+
+    fn push(
+        &mut self,
+        path: &UniversalPath,
+        kind: ChangeKind,
+        source: ChangeSource,
+        at: SystemTime,
+    ) {
+        self.history.push(ChangeRecord {
+            path: path.clone(),
+            kind,
+            source,
+            at,
+        });
+    }
+
+    /// Record `path` as it is now, returning the change if it is new or differs in
+    /// size or modification time, beyond the tolerance, from what was indexed
+    pub fn update(
+        &mut self,
+        path: &UniversalPath,
+        meta: &EntryMetadata,
+        source: ChangeSource,
+        at: SystemTime,
+    ) -> Option<&ChangeRecord> {
+        let kind = match self.entries.get_mut(&key(path)) {
+            Some(entry) => match entry.refresh(meta, at, self.mtime_tolerance) {
+                true => ChangeKind::Modified,
+                false => return None,
+            },
+            None => {
+                self.entries
+                    .insert(key(path), IndexEntry::new(path, meta, at));
+                ChangeKind::Added
+            }
+        };
+        self.push(path, kind, source, at);
+        self.history.last()
+    }
+
+    /// Forget `path`, returning the change if it was indexed
+    pub fn remove(
+        &mut self,
+        path: &UniversalPath,
+        source: ChangeSource,
+        at: SystemTime,
+    ) -> Option<&ChangeRecord> {
+        self.entries.remove(&key(path))?;
+        self.push(path, ChangeKind::Removed, source, at);
+        self.history.last()
+    }
+
+    /// Move the entry at `from` to `to`, keeping when it was added
+    pub fn rename(
+        &mut self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        source: ChangeSource,
+        at: SystemTime,
+    ) -> Option<&ChangeRecord> {
+        let mut entry = self.entries.remove(&key(from))?;
+        entry.path = to.as_file();
+        entry.changed_at = at;
+        self.entries.insert(key(to), entry);
+        let kind = ChangeKind::Renamed { from: from.clone() };
+        self.push(to, kind, source, at);
+        self.history.last()
+    }
+
+    /// `rename`, for a file renamed to `to` only because the destination cannot take
+    /// its name, such as by `NameMode::Ascii`. The first such `from` is kept as the
+    /// entry's `original_path` through later renames, so it can be restored.
+    pub fn rename_transliterated(
+        &mut self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        source: ChangeSource,
+        at: SystemTime,
+    ) -> Option<&ChangeRecord> {
+        let original = self.entries.get(&key(from))?.original_path.clone();
+        self.rename(from, to, source, at)?;
+        let entry = self.entries.get_mut(&key(to)).expect("entry was just renamed");
+        entry.original_path = Some(original.unwrap_or_else(|| from.as_file()));
+        self.history.last()
+    }
+
+    /// The path the file at `path` had before it was transliterated, if it was
+    pub fn original_path(&self, path: &UniversalPath) -> Option<&UniversalPath> {
+        self.entries.get(&key(path))?.original_path.as_ref()
+    }
+
+    /// Record the checksum of the indexed file at `path`, returning false if it is not
+    /// indexed
+    pub fn set_checksum(&mut self, path: &UniversalPath, checksum: Checksum) -> bool {
+        match self.entries.get_mut(&key(path)) {
+            Some(entry) => {
+                entry.checksum = Some(checksum);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Record the lyrics sidecar written for the indexed file at `path`, returning
+    /// false if it is not indexed
+    pub fn set_lyrics(&mut self, path: &UniversalPath, record: LyricsRecord) -> bool {
+        match self.entries.get_mut(&key(path)) {
+            Some(entry) => {
+                entry.lyrics = Some(record);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Checksum every file below `root` that has no `algorithm` checksum yet,
+    /// returning how many were computed
+    pub async fn fill_checksums(
+        &mut self,
+        storage: &dyn Storage,
+        root: &UniversalPath,
+        algorithm: ChecksumAlgorithm,
+    ) -> Result<usize, StorageError> {
+        let mut computed = 0;
+        for entry in self.entries.values_mut() {
+            let current = entry.checksum.as_ref().map(|c| c.algorithm);
+            if current == Some(algorithm) || entry.path.relative_to(root).is_none() {
+                continue;
+            }
+            entry.checksum = Some(storage.checksum(&entry.path, algorithm).await?);
+            computed += 1;
+        }
+        Ok(computed)
+    }
+
+    /// Walk `root` and bring its part of the index up to date: every file the root
+    /// accepts is updated, and any indexed under it but not found is removed. Returns
+    /// the number of changes recorded. The walk stops at the first error, leaving
+    /// nothing removed.
+    pub async fn scan(
+        &mut self,
+        storage: &dyn Storage,
+        root: &RootConfig,
+    ) -> Result<usize, StorageError> {
+        let at = SystemTime::now();
+        let before = self.history.len();
+        let mut seen = HashSet::new();
+        let mut walk = storage.walk(&root.path);
+        while let Some(entry) = walk.next().await {
+            let (path, meta) = entry?;
+            if meta.kind != EntryKind::File || !root.accepts_extension(path.extension()) {
+                continue;
+            }
+            self.update(&path, &meta, ChangeSource::Scan, at);
+            seen.insert(key(&path));
+        }
+        let gone: Vec<_> = self
+            .entries
+            .values()
+            .filter(|entry| entry.path.relative_to(&root.path).is_some())
+            .filter(|entry| !seen.contains(&key(&entry.path)))
+            .map(|entry| entry.path.clone())
+            .collect();
+        for path in gone {
+            self.remove(&path, ChangeSource::Scan, at);
+        }
+        Ok(self.history.len() - before)
+    }
+
+    /// Changes recorded from `from` up to but not including `to`, oldest first
+    pub fn changed_between(&self, from: SystemTime, to: SystemTime) -> Vec<&ChangeRecord> {
+        self.history
+            .iter()
+            .filter(|record| record.at >= from && record.at < to)
+            .collect()
+    }
+
+    /// Files still indexed that first appeared at or after `since`, oldest first
+    pub fn added_since(&self, since: SystemTime) -> Vec<&IndexEntry> {
+        let mut added: Vec<_> = self
+            .entries
+            .values()
+            .filter(|entry| entry.added_at >= since)
+            .collect();
+        added.sort_by_key(|entry| entry.added_at);
+        added
+    }
+
+    /// Mount `catalog` under its name, read-only, replacing any catalog mounted as that
+    pub fn mount(&mut self, catalog: Catalog) -> Option<Catalog> {
+        self.mounts.insert(catalog.name.clone(), catalog)
+    }
+
+    pub fn unmount(&mut self, name: &str) -> Option<Catalog> {
+        self.mounts.remove(name)
+    }
+
+    pub fn mounted(&self, name: &str) -> Option<&Catalog> {
+        self.mounts.get(name)
+    }
+
+    /// Names of the mounted catalogs, sorted
+    pub fn mounts(&self) -> impl Iterator<Item = &str> {
+        self.mounts.keys().map(String::as_str)
+    }
+
+    /// Federate the namespace's roots under `library://<name>/`, replacing any
+    /// namespace of that name
+    pub fn federate(&mut self, namespace: Namespace) -> Option<Namespace> {
+        self.namespaces.insert(namespace.name.clone(), namespace)
+    }
+
+    pub fn unfederate(&mut self, name: &str) -> Option<Namespace> {
+        self.namespaces.remove(name)
+    }
+
+    pub fn namespace(&self, name: &str) -> Option<&Namespace> {
+        self.namespaces.get(name)
+    }
+
+    /// Names of the federated namespaces, sorted
+    pub fn namespaces(&self) -> impl Iterator<Item = &str> {
+        self.namespaces.keys().map(String::as_str)
+    }
+
+    /// The logical paths of the file at `physical`, one for each namespace with a
+    /// root holding it
+    pub fn logical_paths(&self, physical: &UniversalPath) -> Vec<UniversalPath> {
+        self.namespaces
+            .values()
+            .filter_map(|namespace| namespace.logical_path(physical))
+            .collect()
+    }
+
+    /// The indexed file the logical path `logical` is best read from: the copy in
+    /// the most preferred layer of its namespace
+    pub fn resolve(&self, logical: &UniversalPath) -> Option<&IndexEntry> {
+        self.resolve_where(logical, |_| true)
+    }
+
+    /// `resolve`, skipping copies `usable` turns down, such as those on roots that are
+    /// offline
+    pub fn resolve_where(
+        &self,
+        logical: &UniversalPath,
+        usable: impl Fn(&UniversalPath) -> bool,
+    ) -> Option<&IndexEntry> {
+        let namespace = self.namespace_of(logical)?;
+        namespace
+            .physical_paths(logical)
+            .iter()
+            .filter(|physical| usable(physical))
+            .find_map(|physical| self.get(physical))
+    }
+
+    /// Files of the namespace `name` that match `query`, in logical path order, each
+    /// judged by its most preferred copy. The query's `under` may be a logical path or
+    /// a physical one. None if no namespace is federated as `name`.
+    pub fn query_namespace(
+        &self,
+        name: &str,
+        query: &IndexQuery,
+    ) -> Option<Vec<FederatedEntry<'_>>> {
+        let namespace = self.namespaces.get(name)?;
+        let mut files: BTreeMap<String, Vec<(usize, &IndexEntry)>> = BTreeMap::new();
+        let mut logical_paths = BTreeMap::new();
+        for entry in self.entries.values() {
+            let Some((rank, logical)) = namespace.locate(&entry.path) else {
+                continue;
+            };
+            let key = key(&logical);
+            files.entry(key.clone()).or_default().push((rank, entry));
+            logical_paths.entry(key).or_insert(logical);
+        }
+        let found = files
+            .into_iter()
+            .filter_map(|(key, mut copies)| {
+                copies.sort_by_key(|(rank, _)| *rank);
+                let logical = logical_paths.remove(&key)?;
+                let entry = copies[0].1;
+                let under = query.under.as_ref().is_none_or(|under| {
+                    logical.relative_to(under).is_some() || entry.path.relative_to(under).is_some()
+                });
+                (under && query.matches(entry)).then(|| FederatedEntry {
+                    logical,
+                    entry,
+                    copies: copies.iter().map(|(_, copy)| copy.path.clone()).collect(),
+                })
+            })
+            .collect();
+        Some(found)
+    }
+
+    fn namespace_of(&self, logical: &UniversalPath) -> Option<&Namespace> {
+        let federated = matches!(
+            logical.backend(),
+            StorageBackend::Other(scheme) if scheme == LIBRARY_SCHEME
+        );
+        self.namespaces.get(logical.host().filter(|_| federated)?)
+    }
+
+    /// Files of the mounted catalog `name` with no copy in this index, wherever they
+    /// are filed on either side. A file has a copy if an indexed file has the same
+    /// checksum; where one side has no checksums of the catalog entry's algorithm, the
+    /// same file name and size count instead. None if nothing is mounted as `name`.
+    pub fn only_in_mount(&self, name: &str) -> Option<Vec<&CatalogEntry>> {
+        let catalog = self.mounts.get(name)?;
+        let here = Copies::new(self.entries.values().filter_map(|entry| {
+            Some((
+                entry.checksum.as_ref(),
+                entry.path.last_segment()?,
+                entry.size_bytes,
+            ))
+        }));
+        Some(
+            catalog
+                .entries()
+                .iter()
+                .filter(|entry| {
+                    !here.contains(entry.checksum.as_ref(), entry.file_name(), entry.size_bytes)
+                })
+                .collect(),
+        )
+    }
+
+    /// Files indexed below `root` with no copy in the mounted catalog `name`, matched
+    /// as by `only_in_mount`: what the catalog's owner is missing. None if nothing is
+    /// mounted as `name`.
+    pub fn missing_from_mount(&self, name: &str, root: &UniversalPath) -> Option<Vec<&IndexEntry>> {
+        let catalog = self.mounts.get(name)?;
+        let there = Copies::new(
+            catalog
+                .entries()
+                .iter()
+                .map(|entry| (entry.checksum.as_ref(), entry.file_name(), entry.size_bytes)),
+        );
+        Some(
+            self.entries
+                .values()
+                .filter(|entry| entry.path.relative_to(root).is_some())
+                .filter(|entry| {
+                    let name = entry.path.last_segment().unwrap_or_default();
+                    !there.contains(entry.checksum.as_ref(), name, entry.size_bytes)
+                })
+                .collect(),
+        )
+    }
+
+    /// Directories of the mounted catalog `name`, typically albums, none of whose
+    /// files have a copy in this index, in path order
+    pub fn albums_only_in_mount(&self, name: &str) -> Option<Vec<&str>> {
+        let missing: HashSet<&str> = self
+            .only_in_mount(name)?
+            .into_iter()
+            .map(|entry| entry.path.as_str())
+            .collect();
+        let mut partial = BTreeSet::new();
+        let mut albums = BTreeSet::new();
+        for entry in self.mounts[name].entries() {
+            if missing.contains(entry.path.as_str()) {
+                albums.insert(entry.parent());
+            } else {
+                partial.insert(entry.parent());
+            }
+        }
+        Some(albums.difference(&partial).copied().collect())
+    }
+}
+
+/// The files on one side of a comparison, for finding copies of the other side's
+struct Copies<'a> {
+    checksums: HashSet<&'a Checksum>,
+    names: HashSet<(&'a str, Option<u64>)>,
+}
+
+impl<'a> Copies<'a> {
+    fn new(files: impl Iterator<Item = (Option<&'a Checksum>, &'a str, Option<u64>)>) -> Self {
+        let mut copies = Copies {
+            checksums: HashSet::new(),
+            names: HashSet::new(),
+        };
+        for (checksum, name, size) in files {
+            copies.checksums.extend(checksum);
+            copies.names.insert((name, size));
+        }
+        copies
+    }
+
+    /// By checksum when this side has any of the same algorithm, otherwise by file
+    /// name and size
+    fn contains(&self, checksum: Option<&Checksum>, name: &str, size: Option<u64>) -> bool {
+        match checksum {
+            Some(checksum)
+                if self
+                    .checksums
+                    .iter()
+                    .any(|c| c.algorithm == checksum.algorithm) =>
+            {
+                self.checksums.contains(checksum)
+            }
+            _ => self.names.contains(&(name, size)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_time_window_queries() {
+        let storage = MemoryStorage::new();
+        let root = RootConfig::new(UniversalPath::from_uri_str("mem://index/").unwrap());
+        let album = root.path.join("album");
+        storage.create_dir(&album).await.unwrap();
+        storage.write(&album.join("01.flac"), b"one").await.unwrap();
+        storage.write(&album.join("notes.txt"), b"-").await.unwrap();
+
+        let mut index = Index::new();
+        assert_eq!(index.scan(&storage, &root).await.unwrap(), 1);
+        assert_eq!(index.scan(&storage, &root).await.unwrap(), 0);
+
+        // A week later: one track added, one renamed, and a scan finds both
+        let week = SystemTime::now() + Duration::from_secs(7 * 86400);
+        let moved = album.join("01 Intro.flac");
+        index.rename(&album.join("01.flac"), &moved, ChangeSource::Watch, week);
+        storage
+            .rename(&album.join("01.flac"), &moved)
+            .await
+            .unwrap();
+        let meta = storage.stat(&moved).await.unwrap();
+        assert!(
+            index
+                .update(&moved, &meta, ChangeSource::Watch, week)
+                .is_none()
+        );
+        let new = album.join("02.flac");
+        storage.write(&new, b"two").await.unwrap();
+        index.update(
+            &new,
+            &storage.stat(&new).await.unwrap(),
+            ChangeSource::Watch,
+            week,
+        );
+
+        let this_week = index.changed_between(week, week + Duration::from_secs(1));
+        assert_eq!(this_week.len(), 2);
+        assert_eq!(
+            this_week[0].kind,
+            ChangeKind::Renamed {
+                from: album.join("01.flac")
+            }
+        );
+        let added = index.added_since(week);
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0].path, new);
+        // The rename kept when the track first arrived
+        assert!(index.get(&moved).unwrap().added_at < week);
+
+        storage.delete(&new).await.unwrap();
+        assert_eq!(index.scan(&storage, &root).await.unwrap(), 1);
+        assert_eq!(index.history().last().unwrap().kind, ChangeKind::Removed);
+        assert_eq!(index.len(), 1);
+
+        // Written for a stereo that only takes ASCII, then moved again
+        let ascii = album.join("Joga.flac");
+        let again = album.join("03 Joga.flac");
+        index.rename_transliterated(&moved, &ascii, ChangeSource::Scan, week);
+        index.rename_transliterated(&ascii, &again, ChangeSource::Scan, week);
+        assert_eq!(index.original_path(&again), Some(&moved));
+        assert_eq!(index.original_path(&new), None);
+    }
+}
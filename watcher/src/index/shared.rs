@@ -0,0 +1,200 @@
+use super::Index;
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+use tokio::sync::{Mutex, MutexGuard, watch};
+
+/// An `Index` that any number of tasks and threads can use at once, such as the
+/// gateway, search queries and ingest.
+///
+/// Readers take a `snapshot`, the index as of the last commit, without waiting. It
+/// stays the same however long they hold it, whatever is written meanwhile. Writers
+/// take turns. Each gets its own copy of the index to change, and the others see the
+/// changes only once it commits. Each commit copies the index, so changes made
+/// together belong in one write.
+///
+/// Cloning gives another handle on the same index.
+#[derive(Clone)]
+pub struct SharedIndex {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    current: watch::Sender<Arc<Index>>,
+    /// Held by the writer whose turn it is
+    turn: Mutex<()>,
+}
+
+/// The writer whose turn it is, with its copy of the index. Dropping it without
+/// calling `commit` throws its changes away.
+pub struct IndexWriter<'a> {
+    shared: &'a SharedIndex,
+    index: Index,
+    _turn: MutexGuard<'a, ()>,
+}
+
+impl SharedIndex {
+    pub fn new(index: Index) -> Self {
+        SharedIndex {
+            inner: Arc::new(Inner {
+                current: watch::Sender::new(Arc::new(index)),
+                turn: Mutex::new(()),
+            }),
+        }
+    }
+
+    /// The index as of the last commit
+    pub fn snapshot(&self) -> Arc<Index> {
+        self.inner.current.borrow().clone()
+    }
+
+    /// The index as of each commit from now on, for readers that keep up with it
+    pub fn subscribe(&self) -> watch::Receiver<Arc<Index>> {
+        self.inner.current.subscribe()
+    }
+
+    /// Start changing the index, once any other writer has committed or given up
+    pub async fn write(&self) -> IndexWriter<'_> {
+        let turn = self.inner.turn.lock().await;
+        IndexWriter {
+            shared: self,
+            index: Index::clone(&self.snapshot()),
+            _turn: turn,
+        }
+    }
+
+    /// `write`, change the index with `f` and commit
+    pub async fn update<T>(&self, f: impl FnOnce(&mut Index) -> T) -> T {
+        let mut writer = self.write().await;
+        let output = f(&mut writer);
+        writer.commit();
+        output
+    }
+}
+
+impl Default for SharedIndex {
+    fn default() -> Self {
+        SharedIndex::new(Index::new())
+    }
+}
+
+impl From<Index> for SharedIndex {
+    fn from(index: Index) -> Self {
+        SharedIndex::new(index)
+    }
+}
+
+impl fmt::Debug for SharedIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedIndex")
+            .field("entries", &self.snapshot().len())
+            .finish()
+    }
+}
+
+impl IndexWriter<'_> {
+    /// Let readers see the changes, and the next writer have its turn
+    pub fn commit(self) {
+        self.shared.inner.current.send_replace(Arc::new(self.index));
+    }
+}
+
+impl Deref for IndexWriter<'_> {
+    type Target = Index;
+
+    fn deref(&self) -> &Index {
+        &self.index
+    }
+}
+
+impl DerefMut for IndexWriter<'_> {
+    fn deref_mut(&mut self) -> &mut Index {
+        &mut self.index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{ChangeSource, IndexQuery};
+    use crate::root::RootConfig;
+    use crate::storage::{MemoryStorage, Storage};
+    use crate::universal_path::UniversalPath;
+    use futures::FutureExt;
+    use std::time::SystemTime;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_shared_index() {
+        let storage = Arc::new(MemoryStorage::new());
+        let root = RootConfig::new(UniversalPath::from_uri_str("mem://shared/").unwrap());
+        storage.create_dir(&root.path).await.unwrap();
+        for track in 0..3 {
+            let path = root.path.join(format!("{track:02}.flac"));
+            storage.write(&path, b"flac").await.unwrap();
+        }
+        let shared = SharedIndex::default();
+        let mut commits = shared.subscribe();
+
+        // A scan runs as one write; readers meanwhile see the index as it was
+        let before = shared.snapshot();
+        let mut writer = shared.write().await;
+        assert_eq!(writer.scan(&*storage, &root).await.unwrap(), 3);
+        assert!(shared.snapshot().is_empty());
+        assert!(shared.write().now_or_never().is_none());
+        writer.commit();
+        assert!(commits.has_changed().unwrap());
+        assert_eq!(commits.borrow_and_update().len(), 3);
+        assert!(before.is_empty());
+
+        // Writers from many threads take turns, and none of their changes are lost
+        let writers: Vec<_> = (3..23)
+            .map(|track| {
+                let (shared, storage) = (shared.clone(), storage.clone());
+                let path = root.path.join(format!("{track:02}.flac"));
+                tokio::spawn(async move {
+                    storage.write(&path, b"flac").await.unwrap();
+                    let meta = storage.stat(&path).await.unwrap();
+                    let at = SystemTime::now();
+                    shared
+                        .update(|index| {
+                            index
+                                .update(&path, &meta, ChangeSource::Watch, at)
+                                .is_some()
+                        })
+                        .await
+                })
+            })
+            .collect();
+        let readers: Vec<_> = (0..20)
+            .map(|_| {
+                let shared = shared.clone();
+                tokio::spawn(async move {
+                    let snapshot = shared.snapshot();
+                    let flac = IndexQuery::new().with_extension("flac");
+                    (snapshot.len(), snapshot.query(&flac).count())
+                })
+            })
+            .collect();
+        for writer in writers {
+            assert!(writer.await.unwrap());
+        }
+        for reader in readers {
+            let (len, found) = reader.await.unwrap();
+            assert!((3..=23).contains(&len));
+            assert_eq!(found, len);
+        }
+        assert_eq!(shared.snapshot().len(), 23);
+
+        // A writer that gives up leaves the index as it was
+        let mut writer = shared.write().await;
+        writer.remove(
+            &root.path.join("00.flac"),
+            ChangeSource::Watch,
+            SystemTime::now(),
+        );
+        drop(writer);
+        assert_eq!(shared.snapshot().len(), 23);
+    }
+}
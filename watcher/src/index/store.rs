@@ -0,0 +1,621 @@
+use super::wal::{self, FsyncPolicy, Wal, WalOp, WalRecord, sidecar, sync_parent};
+use super::{ChangeKind, IndexEntry, ScanState, key};
+use crate::root::RootConfig;
+use crate::storage::{
+    EntryKind, EntryMetadata, SkipCounts, SkipReason, Skipped, Storage, StorageError, StorageExt,
+    WalkItem, WalkOptions,
+};
+use crate::universal_path::UniversalPath;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use thiserror::Error;
+
+/// Version of the file `FileIndexStore` writes; 2 added the write-ahead log, 3 the
+/// documents kept beside the entries
+const FORMAT_VERSION: u32 = 3;
+/// Size the write-ahead log may grow to before `flush` folds it into a new snapshot
+const CHECKPOINT_BYTES: u64 = 64 << 20;
+
+#[derive(Debug, Error)]
+pub enum IndexStoreError {
+    #[error("index file is version {0}, newer than this build reads")]
+    UnsupportedVersion(u32),
+    #[error("index is damaged beyond what its journal can repair: {0}")]
+    Corrupt(String),
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Entries read back from a store, in path order.
+pub type StoredEntries<'a> =
+    Box<dyn Iterator<Item = Result<IndexEntry, IndexStoreError>> + Send + 'a>;
+
+/// A store shared by everything that keeps its state in it, such as a daemon's index
+/// and its job queue.
+pub type SharedIndexStore = Arc<Mutex<Box<dyn IndexStore>>>;
+
+/// Where indexed files are kept between runs: by path, with what was last seen of
+/// each, its checksum and how far it has been scanned.
+///
+/// Keys are printed URIs, so everything under a root is one contiguous range and
+/// `entries_under` can stream it without reading the rest. Changes may be buffered
+/// until `flush`. Beside the entries a store keeps documents by name, for state that
+/// should last as long as the index, such as the job queue.
+pub trait IndexStore: Send + Sync {
+    fn get(&self, path: &UniversalPath) -> Result<Option<IndexEntry>, IndexStoreError>;
+
+    /// Add or replace the entry at its path
+    fn put(&mut self, entry: IndexEntry) -> Result<(), IndexStoreError>;
+
+    fn remove(&mut self, path: &UniversalPath) -> Result<Option<IndexEntry>, IndexStoreError>;
+
+    /// Every entry, in path order
+    fn entries(&self) -> StoredEntries<'_>;
+
+    /// Every entry under `root`, in path order
+    fn entries_under<'a>(&'a self, root: &UniversalPath) -> StoredEntries<'a>;
+
+    fn len(&self) -> Result<usize, IndexStoreError>;
+
+    /// Make every change so far durable
+    fn flush(&mut self) -> Result<(), IndexStoreError>;
+
+    /// The document kept under `name`, if there is one
+    fn document(&self, name: &str) -> Result<Option<serde_json::Value>, IndexStoreError>;
+
+    /// Keep `document` under `name`, replacing what was there
+    fn put_document(
+        &mut self,
+        name: &str,
+        document: serde_json::Value,
+    ) -> Result<(), IndexStoreError>;
+
+    fn is_empty(&self) -> Result<bool, IndexStoreError> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Record `path` as it is now, returning the change if it is new or changed
+    fn upsert(
+        &mut self,
+        path: &UniversalPath,
+        meta: &EntryMetadata,
+        at: SystemTime,
+        tolerance: Duration,
+    ) -> Result<Option<ChangeKind>, IndexStoreError> {
+        let (entry, kind) = match self.get(path)? {
+            Some(mut entry) => match entry.refresh(meta, at, tolerance) {
+                true => (entry, ChangeKind::Modified),
+                false => return Ok(None),
+            },
+            None => (IndexEntry::new(path, meta, at), ChangeKind::Added),
+        };
+        self.put(entry)?;
+        Ok(Some(kind))
+    }
+
+    /// Record how scanning `path` went, returning false if it is not indexed
+    fn set_scan_state(
+        &mut self,
+        path: &UniversalPath,
+        state: ScanState,
+    ) -> Result<bool, IndexStoreError> {
+        let Some(mut entry) = self.get(path)? else {
+            return Ok(false);
+        };
+        entry.scan_state = state;
+        self.put(entry)?;
+        Ok(true)
+    }
+
+    /// Entries under the query's root that match it, in path order
+    fn query<'a>(&'a self, query: &'a IndexQuery) -> StoredEntries<'a> {
+        let all = match &query.under {
+            Some(root) => self.entries_under(root),
+            None => self.entries(),
+        };
+        Box::new(all.filter(move |entry| match entry {
+            Ok(entry) => query.matches(entry),
+            Err(_) => true,
+        }))
+    }
+}
+
+/// Which indexed files to return. Every condition set must hold.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IndexQuery {
+    pub under: Option<UniversalPath>,
+    /// Lowercase, without the dot; empty means any
+    pub extensions: Vec<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub modified_after: Option<SystemTime>,
+    pub modified_before: Option<SystemTime>,
+    pub scan_state: Option<ScanState>,
+}
+
+impl IndexQuery {
+    pub fn new() -> Self {
+        IndexQuery::default()
+    }
+
+    pub fn under(mut self, root: &UniversalPath) -> Self {
+        self.under = Some(root.as_dir());
+        self
+    }
+
+    pub fn with_extension(mut self, extension: &str) -> Self {
+        self.extensions.push(extension.to_lowercase());
+        self
+    }
+
+    /// Sizes from `min` up to and including `max`
+    pub fn with_size(mut self, min: Option<u64>, max: Option<u64>) -> Self {
+        self.min_size = min;
+        self.max_size = max;
+        self
+    }
+
+    /// Modified from `after` up to but not including `before`
+    pub fn modified_between(
+        mut self,
+        after: Option<SystemTime>,
+        before: Option<SystemTime>,
+    ) -> Self {
+        self.modified_after = after;
+        self.modified_before = before;
+        self
+    }
+
+    pub fn with_scan_state(mut self, state: ScanState) -> Self {
+        self.scan_state = Some(state);
+        self
+    }
+
+    pub fn matches(&self, entry: &IndexEntry) -> bool {
+        let extension = entry.path.extension().map(str::to_lowercase);
+        let size = entry.size_bytes;
+        let modified = entry.modified_at;
+        (self.extensions.is_empty() || extension.is_some_and(|e| self.extensions.contains(&e)))
+            && self
+                .min_size
+                .is_none_or(|min| size.is_some_and(|s| s >= min))
+            && self
+                .max_size
+                .is_none_or(|max| size.is_some_and(|s| s <= max))
+            && self
+                .modified_after
+                .is_none_or(|after| modified.is_some_and(|m| m >= after))
+            && self
+                .modified_before
+                .is_none_or(|before| modified.is_some_and(|m| m < before))
+            && self
+                .scan_state
+                .as_ref()
+                .is_none_or(|state| *state == entry.scan_state)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoreFile {
+    version: u32,
+    /// The last journal record the snapshot holds
+    #[serde(default)]
+    seq: u64,
+    entries: Vec<IndexEntry>,
+    #[serde(default)]
+    documents: BTreeMap<String, serde_json::Value>,
+}
+
+/// Read the snapshot at `path`, `None` if there is none. One that does not parse is
+/// `Corrupt`, as a torn or truncated write leaves it.
+fn read_snapshot(path: &Path) -> Result<Option<StoreFile>, IndexStoreError> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let file: StoreFile = serde_json::from_slice(&bytes)
+        .map_err(|e| IndexStoreError::Corrupt(format!("{}: {e}", path.display())))?;
+    if file.version > FORMAT_VERSION {
+        return Err(IndexStoreError::UnsupportedVersion(file.version));
+    }
+    Ok(Some(file))
+}
+
+/// How opening a `FileIndexStore` brought it back to where it was.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct RecoveryReport {
+    /// Journal records applied on top of the snapshot
+    pub replayed: usize,
+    /// Journal bytes dropped as not written whole
+    pub torn_bytes: u64,
+    /// The latest snapshot was missing or damaged, so the index was rebuilt from the
+    /// one before it and the journal since; a damaged snapshot with none before it
+    /// is rebuilt from the journal alone, if it goes back far enough
+    pub from_previous: bool,
+}
+
+/// An `IndexStore` held in memory and, when opened on a file, kept there as a JSON
+/// snapshot and a write-ahead log beside it (`<file>.wal`).
+///
+/// Every change is logged before it is applied, and `flush` syncs the log, so
+/// neither a crash nor a power loss costs a rescan: opening the store replays the log
+/// over the snapshot, dropping a record torn at its end. Once the log has grown large
+/// `flush` writes a new snapshot, keeping the one before and the log since it
+/// (`<file>.prev`, `<file>.wal.prev`), so a snapshot damaged on disk is rebuilt
+/// from them.
+#[derive(Debug, Default)]
+pub struct FileIndexStore {
+    entries: BTreeMap<String, IndexEntry>,
+    documents: BTreeMap<String, serde_json::Value>,
+    path: Option<PathBuf>,
+    dirty: bool,
+    wal: Option<Wal>,
+    seq: u64,
+    checkpoint_bytes: u64,
+    recovery: RecoveryReport,
+}
+
+impl FileIndexStore {
+    /// A store that is never saved
+    pub fn in_memory() -> Self {
+        FileIndexStore::default()
+    }
+
+    /// Open (or create) a store saved at `path`, recovering what a crash left
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, IndexStoreError> {
+        let path = path.as_ref().to_path_buf();
+        let previous = sidecar(&path, ".prev");
+        let mut recovery = RecoveryReport::default();
+        let latest = match read_snapshot(&path) {
+            Err(IndexStoreError::Corrupt(_)) => None,
+            other => other?,
+        };
+        let damaged = latest.is_none() && path.exists();
+        let base = match latest {
+            Some(file) => Some(file),
+            // Torn, or gone in the middle of writing the next
+            None if previous.exists() => {
+                recovery.from_previous = true;
+                read_snapshot(&previous)?
+            }
+            // Never, or only once, checkpointed: the journal may still go back to
+            // the first record, which replay checks
+            None => None,
+        };
+        let (mut seq, entries, mut documents) = base.map_or_else(
+            || (0, Vec::new(), BTreeMap::new()),
+            |file| (file.seq, file.entries, file.documents),
+        );
+        let mut entries: BTreeMap<_, _> = entries
+            .into_iter()
+            .map(|entry| (key(&entry.path), entry))
+            .collect();
+
+        let older = wal::read(&sidecar(&path, ".wal.prev"))?;
+        let current = wal::read(&sidecar(&path, ".wal"))?;
+        let records = older.records.into_iter().chain(current.records);
+        for WalRecord { seq: at, op } in records {
+            // Already in the snapshot
+            if at <= seq {
+                continue;
+            }
+            if at != seq + 1 {
+                let gap = format!("journal skips from record {seq} to {at}");
+                return Err(IndexStoreError::Corrupt(gap));
+            }
+            seq = at;
+            recovery.replayed += 1;
+            match op {
+                WalOp::Put(entry) => {
+                    entries.insert(key(&entry.path), *entry);
+                }
+                WalOp::Remove(key) => {
+                    entries.remove(&key);
+                }
+                WalOp::Document(name, document) => {
+                    documents.insert(name, document);
+                }
+            }
+        }
+        recovery.torn_bytes = current.torn_bytes;
+
+        let wal = Wal::open(
+            &sidecar(&path, ".wal"),
+            FsyncPolicy::default(),
+            current.torn_bytes,
+        )?;
+        let mut store = FileIndexStore {
+            entries,
+            documents,
+            path: Some(path),
+            dirty: recovery.replayed > 0,
+            wal: Some(wal),
+            seq,
+            checkpoint_bytes: CHECKPOINT_BYTES,
+            recovery,
+        };
+        if damaged || recovery.from_previous {
+            store.checkpoint()?;
+        }
+        Ok(store)
+    }
+
+    /// When changes are forced to disk between flushes
+    pub fn with_fsync(mut self, policy: FsyncPolicy) -> Self {
+        if let Some(wal) = &mut self.wal {
+            wal.set_policy(policy);
+        }
+        self
+    }
+
+    /// How large the write-ahead log may grow before `flush` writes a new snapshot
+    pub fn with_checkpoint_bytes(mut self, bytes: u64) -> Self {
+        self.checkpoint_bytes = bytes;
+        self
+    }
+
+    /// What opening the store had to recover
+    pub fn recovery(&self) -> RecoveryReport {
+        self.recovery
+    }
+
+    /// Write every entry to a new snapshot and start the log afresh, whatever its size
+    pub fn checkpoint(&mut self) -> Result<(), IndexStoreError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let file = StoreFile {
+            version: FORMAT_VERSION,
+            seq: self.seq,
+            entries: self.entries.values().cloned().collect(),
+            documents: self.documents.clone(),
+        };
+        let tmp = path.with_extension("tmp");
+        let mut out = std::fs::File::create(&tmp)?;
+        std::io::Write::write_all(&mut out, &serde_json::to_vec(&file)?)?;
+        out.sync_all()?;
+        // Until the new snapshot is in place the old one, as `.prev`, and the log
+        // since it still make the whole index
+        if path.exists() {
+            std::fs::rename(path, sidecar(path, ".prev"))?;
+        }
+        std::fs::rename(&tmp, path)?;
+        sync_parent(path)?;
+        if let Some(wal) = &mut self.wal {
+            wal.rotate(&sidecar(path, ".wal.prev"))?;
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn log(&mut self, op: WalOp) -> Result<(), IndexStoreError> {
+        let Some(wal) = &mut self.wal else {
+            return Ok(());
+        };
+        wal.append(&WalRecord {
+            seq: self.seq + 1,
+            op,
+        })?;
+        self.seq += 1;
+        Ok(())
+    }
+}
+
+impl IndexStore for FileIndexStore {
+    fn get(&self, path: &UniversalPath) -> Result<Option<IndexEntry>, IndexStoreError> {
+        Ok(self.entries.get(&key(path)).cloned())
+    }
+
+    fn put(&mut self, entry: IndexEntry) -> Result<(), IndexStoreError> {
+        self.log(WalOp::Put(Box::new(entry.clone())))?;
+        self.entries.insert(key(&entry.path), entry);
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &UniversalPath) -> Result<Option<IndexEntry>, IndexStoreError> {
+        let key = key(path);
+        if !self.entries.contains_key(&key) {
+            return Ok(None);
+        }
+        self.log(WalOp::Remove(key.clone()))?;
+        self.dirty = true;
+        Ok(self.entries.remove(&key))
+    }
+
+    fn entries_under<'a>(&'a self, root: &UniversalPath) -> StoredEntries<'a> {
+        let prefix = root.as_dir().to_string();
+        let range = self.entries.range(prefix.clone()..);
+        Box::new(
+            range
+                .take_while(move |(key, _)| key.starts_with(&prefix))
+                .map(|(_, entry)| Ok(entry.clone())),
+        )
+    }
+
+    fn entries(&self) -> StoredEntries<'_> {
+        Box::new(self.entries.values().map(|entry| Ok(entry.clone())))
+    }
+
+    fn len(&self) -> Result<usize, IndexStoreError> {
+        Ok(self.entries.len())
+    }
+
+    fn flush(&mut self) -> Result<(), IndexStoreError> {
+        let Some(wal) = &mut self.wal else {
+            return Ok(());
+        };
+        wal.sync()?;
+        if self.dirty && wal.bytes() >= self.checkpoint_bytes {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    fn document(&self, name: &str) -> Result<Option<serde_json::Value>, IndexStoreError> {
+        Ok(self.documents.get(name).cloned())
+    }
+
+    fn put_document(
+        &mut self,
+        name: &str,
+        document: serde_json::Value,
+    ) -> Result<(), IndexStoreError> {
+        self.log(WalOp::Document(name.to_string(), document.clone()))?;
+        self.documents.insert(name.to_string(), document);
+        self.dirty = true;
+        Ok(())
+    }
+}
+
+/// What `update_from_walk` changed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct WalkSummary {
+    pub added: usize,
+    pub modified: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+    /// Entries not indexed, by reason
+    pub skipped: SkipCounts,
+}
+
+/// Walk `root` and bring its part of `store` up to date, as `Index::scan` does for
+/// the in-memory index, then flush. The walk stops at the first error, leaving
+/// nothing removed. Entries under a directory that could not be listed are kept, as
+/// the walk could not tell whether they are still there.
+pub async fn update_from_walk(
+    store: &mut dyn IndexStore,
+    storage: &dyn Storage,
+    root: &RootConfig,
+    tolerance: Duration,
+) -> Result<WalkSummary, IndexStoreError> {
+    let at = SystemTime::now();
+    let mut summary = WalkSummary::default();
+    let mut seen = HashSet::new();
+    let mut unlisted = Vec::new();
+    let mut walk = storage.walk_items(&root.path, WalkOptions::default());
+    while let Some(item) = walk.next().await {
+        let (path, meta) = match item? {
+            WalkItem::Entry(path, meta) => (path, meta),
+            WalkItem::Skipped(Skipped { path, reason }) => {
+                if matches!(reason, SkipReason::PermissionDenied | SkipReason::TooDeep) {
+                    unlisted.push(path);
+                }
+                summary.skipped.record(reason);
+                continue;
+            }
+        };
+        match meta.kind {
+            EntryKind::File if root.accepts_extension(path.extension()) => {}
+            EntryKind::File => {
+                summary.skipped.record(SkipReason::Filtered);
+                continue;
+            }
+            EntryKind::Directory | EntryKind::Symlink => continue,
+            EntryKind::Other => {
+                summary.skipped.record(SkipReason::UnsupportedKind);
+                continue;
+            }
+        }
+        match store.upsert(&path, &meta, at, tolerance)? {
+            Some(ChangeKind::Added) => summary.added += 1,
+            Some(_) => summary.modified += 1,
+            None => summary.unchanged += 1,
+        }
+        seen.insert(key(&path));
+    }
+    let gone = store
+        .entries_under(&root.path)
+        .filter_map(|entry| match entry {
+            Ok(entry) if seen.contains(&key(&entry.path)) => None,
+            Ok(entry) if unlisted.iter().any(|dir| entry.path.relative_to(dir).is_some()) => None,
+            other => Some(other.map(|entry| entry.path)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    for path in gone {
+        store.remove(&path)?;
+        summary.removed += 1;
+    }
+    store.flush()?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_file_index_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("index.json");
+        let storage = MemoryStorage::new();
+        let root = RootConfig::new(UniversalPath::from_uri_str("mem://lib/").unwrap());
+        let track = |name: &str| root.path.join(name);
+        storage.write(&track("01.flac"), &[0; 300]).await.unwrap();
+        storage.write(&track("02.mp3"), &[0; 100]).await.unwrap();
+        storage.write(&track("cover.jpg"), &[0; 50]).await.unwrap();
+
+        let mut store = FileIndexStore::open(&file).unwrap();
+        let summary = update_from_walk(&mut store, &storage, &root, Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!((summary.added, summary.unchanged), (2, 0));
+        store
+            .set_scan_state(&track("01.flac"), ScanState::Scanned)
+            .unwrap();
+        store.flush().unwrap();
+
+        // Another run sees what the last one did
+        let mut store = FileIndexStore::open(&file).unwrap();
+        assert_eq!(store.len().unwrap(), 2);
+        storage.delete(&track("02.mp3")).await.unwrap();
+        let summary = update_from_walk(&mut store, &storage, &root, Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!((summary.removed, summary.unchanged), (1, 1));
+        let entry = store.get(&track("01.flac")).unwrap().unwrap();
+        assert_eq!(entry.scan_state, ScanState::Scanned);
+
+        storage.write(&track("03.flac"), &[0; 10]).await.unwrap();
+        update_from_walk(&mut store, &storage, &root, Duration::ZERO)
+            .await
+            .unwrap();
+        let query = IndexQuery::new()
+            .under(&root.path)
+            .with_extension("FLAC")
+            .with_scan_state(ScanState::Pending);
+        let pending: Vec<_> = store.query(&query).map(|e| e.unwrap().path).collect();
+        assert_eq!(pending, vec![track("03.flac")]);
+        let large = IndexQuery::new().with_size(Some(200), None);
+        assert_eq!(store.query(&large).count(), 1);
+        let elsewhere = UniversalPath::from_uri_str("mem://other/").unwrap();
+        assert_eq!(store.entries_under(&elsewhere).count(), 0);
+
+        // Documents come back from the log, and from a snapshot once checkpointed
+        let document = serde_json::json!({"next_id": 2});
+        store.put_document("jobs", document.clone()).unwrap();
+        store.flush().unwrap();
+        let mut store = FileIndexStore::open(&file).unwrap();
+        assert_eq!(store.document("jobs").unwrap(), Some(document.clone()));
+        store.checkpoint().unwrap();
+        let store = FileIndexStore::open(&file).unwrap();
+        assert_eq!(store.document("jobs").unwrap(), Some(document));
+        assert_eq!(store.document("schedule").unwrap(), None);
+        for suffix in ["", ".wal", ".prev", ".wal.prev"] {
+            std::fs::remove_file(sidecar(&file, suffix)).ok();
+        }
+    }
+}
@@ -0,0 +1,454 @@
+use super::IndexEntry;
+use super::store::IndexStoreError;
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsString,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+use xxhash_rust::xxh3::xxh3_64;
+
+/// Length and checksum in front of each record
+const RECORD_HEADER: usize = 12;
+/// Longer than any entry; a length beyond it is a torn or garbled header
+const MAX_RECORD: usize = 16 << 20;
+
+/// When the index's write-ahead log is forced to disk. Whatever the policy, `flush`
+/// syncs it, and a crash never leaves the index half-applied: a record torn by a
+/// power loss is dropped on the next open, along with everything after it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// After every change; nothing acknowledged is lost, at a sync per change
+    Always,
+    /// At most this long after a change, by a thread that syncs in the background
+    /// once the time is up, whether or not more changes follow
+    Periodic(Duration),
+    /// Only on `flush`; a power loss loses changes since the last one
+    #[default]
+    OnFlush,
+}
+
+/// A change logged before it is applied to the entries in memory.
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) enum WalOp {
+    Put(Box<IndexEntry>),
+    /// By key
+    Remove(String),
+    /// A document kept beside the entries, replaced whole
+    Document(String, serde_json::Value),
+}
+
+/// Records are numbered from 1 across the life of the store, so replay can tell
+/// which a snapshot already holds and whether any are missing.
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct WalRecord {
+    pub seq: u64,
+    pub op: WalOp,
+}
+
+/// The log being appended to: each record is its length and xxh3 as little-endian
+/// u32 and u64, then its JSON.
+#[derive(Debug)]
+pub(super) struct Wal {
+    file: File,
+    path: PathBuf,
+    bytes: u64,
+    policy: FsyncPolicy,
+    unsynced: bool,
+    /// Started by the first change under `FsyncPolicy::Periodic`
+    periodic: Option<PeriodicSync>,
+    /// A failed append left the log in a state this handle cannot vouch for
+    poisoned: bool,
+}
+
+/// The background syncing of a log under `FsyncPolicy::Periodic`. The thread stops,
+/// syncing whatever is left first, when this is dropped.
+#[derive(Debug)]
+struct PeriodicSync {
+    shared: Arc<(Mutex<SyncState>, Condvar)>,
+}
+
+#[derive(Debug)]
+struct SyncState {
+    file: Arc<File>,
+    /// When the oldest change not yet synced was written
+    dirty_since: Option<Instant>,
+    /// From a background sync, for the next change or sync to return
+    error: Option<io::Error>,
+    stop: bool,
+}
+
+/// What a log held, up to the first record that was not written whole.
+pub(super) struct WalContents {
+    pub records: Vec<WalRecord>,
+    /// Bytes from the first bad record on
+    pub torn_bytes: u64,
+}
+
+/// `path` with `suffix` after its file name, for the files kept beside a store
+pub(super) fn sidecar(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Make renames and creations in the directory holding `path` durable
+pub(crate) fn sync_parent(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+pub(super) fn read(path: &Path) -> Result<WalContents, IndexStoreError> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let mut records = Vec::new();
+    let mut at = 0;
+    while let Some(header) = bytes.get(at..at + RECORD_HEADER) {
+        let len = u32::from_le_bytes(header[..4].try_into().expect("4 bytes")) as usize;
+        let sum = u64::from_le_bytes(header[4..].try_into().expect("8 bytes"));
+        let start = at + RECORD_HEADER;
+        let Some(payload) = bytes.get(start..start + len).filter(|_| len <= MAX_RECORD) else {
+            break;
+        };
+        if xxh3_64(payload) != sum {
+            break;
+        }
+        let Ok(record) = serde_json::from_slice(payload) else {
+            break;
+        };
+        records.push(record);
+        at = start + len;
+    }
+    Ok(WalContents {
+        records,
+        torn_bytes: (bytes.len() - at) as u64,
+    })
+}
+
+impl Wal {
+    /// Append to the log at `path`, first cutting off the `torn_bytes` at its end
+    pub fn open(path: &Path, policy: FsyncPolicy, torn_bytes: u64) -> io::Result<Wal> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)?;
+        let bytes = file.metadata()?.len() - torn_bytes;
+        if torn_bytes > 0 {
+            file.set_len(bytes)?;
+            file.sync_all()?;
+        }
+        let mut wal = Wal {
+            file,
+            path: path.to_path_buf(),
+            bytes,
+            policy,
+            unsynced: false,
+            periodic: None,
+            poisoned: false,
+        };
+        io::Seek::seek(&mut wal.file, io::SeekFrom::End(0))?;
+        Ok(wal)
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn set_policy(&mut self, policy: FsyncPolicy) {
+        if policy != self.policy {
+            self.periodic = None;
+        }
+        self.policy = policy;
+    }
+
+    /// Write `record` at the end of the log. A write that fails part way is cut off
+    /// again, so the next record does not land after bytes replay would stop at; if
+    /// that fails too, or syncing the record does, the log refuses further records.
+    pub fn append(&mut self, record: &WalRecord) -> Result<(), IndexStoreError> {
+        if self.poisoned {
+            let e = io::Error::other("write-ahead log is unusable after a failed write");
+            return Err(e.into());
+        }
+        let payload = serde_json::to_vec(record)?;
+        let mut buf = Vec::with_capacity(RECORD_HEADER + payload.len());
+        buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&xxh3_64(&payload).to_le_bytes());
+        buf.extend_from_slice(&payload);
+        if let Err(e) = self.file.write_all(&buf) {
+            self.poisoned = self.rollback().is_err();
+            return Err(e.into());
+        }
+        self.bytes += buf.len() as u64;
+        self.unsynced = true;
+        // The record is in the log but may not be on disk, and the store will not
+        // count it; another with its number would be skipped on replay
+        let synced = self.sync_appended();
+        self.poisoned = synced.is_err();
+        synced
+    }
+
+    fn sync_appended(&mut self) -> Result<(), IndexStoreError> {
+        match self.policy {
+            FsyncPolicy::Always => self.sync()?,
+            FsyncPolicy::Periodic(every) => {
+                let periodic = match &mut self.periodic {
+                    Some(periodic) => periodic,
+                    periodic => periodic.insert(PeriodicSync::start(&self.file, every)?),
+                };
+                periodic.written()?;
+            }
+            FsyncPolicy::OnFlush => {}
+        }
+        Ok(())
+    }
+
+    /// Cut off anything written after the last whole record
+    fn rollback(&mut self) -> io::Result<()> {
+        self.file.set_len(self.bytes)?;
+        io::Seek::seek(&mut self.file, io::SeekFrom::Start(self.bytes))?;
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        if let Some(periodic) = &self.periodic {
+            periodic.synced()?;
+        }
+        if self.unsynced {
+            self.file.sync_data()?;
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+
+    /// Keep this log as `previous` and start an empty one in its place, once a
+    /// snapshot holds everything in it
+    pub fn rotate(&mut self, previous: &Path) -> io::Result<()> {
+        self.sync()?;
+        std::fs::rename(&self.path, previous)?;
+        sync_parent(&self.path)?;
+        let fresh = Wal::open(&self.path, self.policy, 0)?;
+        sync_parent(&self.path)?;
+        *self = fresh;
+        Ok(())
+    }
+}
+
+impl PeriodicSync {
+    fn start(file: &File, every: Duration) -> io::Result<Self> {
+        let state = SyncState {
+            file: Arc::new(file.try_clone()?),
+            dirty_since: None,
+            error: None,
+            stop: false,
+        };
+        let shared = Arc::new((Mutex::new(state), Condvar::new()));
+        let worker = shared.clone();
+        thread::Builder::new()
+            .name("otolith-wal-sync".to_string())
+            .spawn(move || PeriodicSync::run(&worker, every))?;
+        Ok(PeriodicSync { shared })
+    }
+
+    /// Note a change written but not synced, returning any error a background sync
+    /// has met since the last call
+    fn written(&self) -> io::Result<()> {
+        let (state, wake) = &*self.shared;
+        let mut state = lock(state);
+        if let Some(e) = state.error.take() {
+            return Err(e);
+        }
+        if state.dirty_since.is_none() {
+            state.dirty_since = Some(Instant::now());
+            wake.notify_one();
+        }
+        Ok(())
+    }
+
+    /// Note that the log is about to be synced in the foreground
+    fn synced(&self) -> io::Result<()> {
+        let mut state = lock(&self.shared.0);
+        state.dirty_since = None;
+        state.error.take().map_or(Ok(()), Err)
+    }
+
+    fn run(shared: &(Mutex<SyncState>, Condvar), every: Duration) {
+        let (state, wake) = shared;
+        let mut guard = lock(state);
+        loop {
+            let due = guard.dirty_since.map(|since| since + every);
+            match due {
+                Some(due) if !guard.stop && Instant::now() < due => {
+                    let wait = due.saturating_duration_since(Instant::now());
+                    guard = wake
+                        .wait_timeout(guard, wait)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0;
+                    continue;
+                }
+                None if guard.stop => return,
+                None => {
+                    guard = wake.wait(guard).unwrap_or_else(|e| e.into_inner());
+                    continue;
+                }
+                Some(_) => {}
+            }
+            // Due, or stopping with changes left: sync without holding up writers
+            guard.dirty_since = None;
+            let file = guard.file.clone();
+            drop(guard);
+            let synced = file.sync_data();
+            guard = lock(state);
+            if let Err(e) = synced {
+                guard.error = Some(e);
+            }
+        }
+    }
+}
+
+impl Drop for PeriodicSync {
+    fn drop(&mut self) {
+        let (state, wake) = &*self.shared;
+        lock(state).stop = true;
+        wake.notify_one();
+    }
+}
+
+fn lock(state: &Mutex<SyncState>) -> MutexGuard<'_, SyncState> {
+    state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{FileIndexStore, IndexStore};
+    use crate::storage::{MemoryStorage, Storage};
+    use crate::universal_path::UniversalPath;
+    use std::time::SystemTime;
+
+    #[tokio::test]
+    async fn test_recover_after_crash() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let file = dir.join("index.json");
+        let storage = MemoryStorage::new();
+        let track =
+            |n: u32| UniversalPath::from_uri_str(&format!("mem://lib/{n:02}.flac")).unwrap();
+        let mut entries = Vec::new();
+        for n in 0..4 {
+            storage.write(&track(n), &[0; 10]).await.unwrap();
+            let meta = storage.stat(&track(n)).await.unwrap();
+            entries.push(IndexEntry::new(&track(n), &meta, SystemTime::now()));
+        }
+
+        // Changes logged but never flushed, and a record torn by the power going
+        let mut store = FileIndexStore::open(&file)
+            .unwrap()
+            .with_fsync(FsyncPolicy::Always);
+        store.put(entries[0].clone()).unwrap();
+        store.put(entries[1].clone()).unwrap();
+        store.remove(&track(0)).unwrap();
+        drop(store);
+        let wal = sidecar(&file, ".wal");
+        let mut log = OpenOptions::new().append(true).open(&wal).unwrap();
+        log.write_all(&[200, 0, 0, 0, 1, 2, 3]).unwrap();
+
+        let mut store = FileIndexStore::open(&file).unwrap();
+        assert_eq!(store.recovery().replayed, 3);
+        assert_eq!(store.recovery().torn_bytes, 7);
+        assert_eq!(store.len().unwrap(), 1);
+        assert!(store.get(&track(1)).unwrap().is_some());
+
+        // A snapshot torn on disk is rebuilt from the previous one and the journal
+        store.checkpoint().unwrap();
+        store.put(entries[2].clone()).unwrap();
+        store.checkpoint().unwrap();
+        store.put(entries[3].clone()).unwrap();
+        store.flush().unwrap();
+        let snapshot = std::fs::read(&file).unwrap();
+        std::fs::write(&file, &snapshot[..snapshot.len() / 2]).unwrap();
+        let store = FileIndexStore::open(&file).unwrap();
+        assert!(store.recovery().from_previous);
+        let paths: Vec<_> = store.entries().map(|e| e.unwrap().path).collect();
+        assert_eq!(paths, vec![track(1), track(2), track(3)]);
+    }
+
+    #[test]
+    fn test_failed_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.json.wal");
+        let mut wal = Wal::open(&path, FsyncPolicy::OnFlush, 0).unwrap();
+        let record = |seq| WalRecord {
+            seq,
+            op: WalOp::Remove(format!("mem://lib/{seq:02}.flac")),
+        };
+        wal.append(&record(1)).unwrap();
+        // What a write that failed part way leaves behind
+        let mut other = OpenOptions::new().append(true).open(&path).unwrap();
+        other.write_all(&[200, 0, 0, 0, 1, 2]).unwrap();
+        wal.rollback().unwrap();
+        wal.append(&record(2)).unwrap();
+        let contents = read(&path).unwrap();
+        let seqs: Vec<_> = contents.records.iter().map(|r| r.seq).collect();
+        assert_eq!((seqs, contents.torn_bytes), (vec![1, 2], 0));
+
+        // Unable to write or cut off, the log takes nothing more
+        let Ok(full) = OpenOptions::new().write(true).open("/dev/full") else {
+            return;
+        };
+        wal.file = full;
+        assert!(wal.append(&record(3)).is_err());
+        wal.file = OpenOptions::new().append(true).open(&path).unwrap();
+        assert!(wal.append(&record(3)).is_err());
+        assert_eq!(read(&path).unwrap().records.len(), 2);
+    }
+
+    #[test]
+    fn test_periodic_sync_without_later_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.json.wal");
+        let every = Duration::from_millis(100);
+        let mut wal = Wal::open(&path, FsyncPolicy::Periodic(every), 0).unwrap();
+        let record = WalRecord {
+            seq: 1,
+            op: WalOp::Remove("mem://lib/01.flac".to_string()),
+        };
+        wal.append(&record).unwrap();
+        let pending = || lock(&wal.periodic.as_ref().unwrap().shared.0).dirty_since;
+        assert!(pending().is_some());
+
+        // Synced once the time is up, with nothing more appended
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pending().is_some() {
+            assert!(Instant::now() < deadline, "never synced in the background");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(
+            lock(&wal.periodic.as_ref().unwrap().shared.0)
+                .error
+                .is_none()
+        );
+
+        // A change of policy stops the thread
+        wal.set_policy(FsyncPolicy::OnFlush);
+        assert!(wal.periodic.is_none());
+    }
+}
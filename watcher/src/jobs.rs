@@ -0,0 +1,477 @@
+use crate::index::{IndexStore, IndexStoreError, SharedIndexStore};
+use crate::retry::RetryPolicy;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, time::SystemTime};
+use thiserror::Error;
+
+pub type JobId = u64;
+
+/// The name the queue is kept under among an `IndexStore`'s documents
+const JOBS_DOCUMENT: &str = "jobs";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum JobState {
+    Queued,
+    Running,
+    Paused,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    /// Finished states never change again
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            JobState::Succeeded | JobState::Failed | JobState::Cancelled
+        )
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum JobError {
+    #[error("no job with id {0}")]
+    NotFound(JobId),
+    #[error("cannot {action} job {id} while it is {from:?}")]
+    InvalidTransition {
+        id: JobId,
+        from: JobState,
+        action: &'static str,
+    },
+    #[error("corrupt job queue state: {0}")]
+    CorruptState(#[from] serde_json::Error),
+    #[error(transparent)]
+    Store(#[from] IndexStoreError),
+}
+
+/// A unit of background work and its bookkeeping.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Job {
+    pub id: JobId,
+    /// What the job does, e.g. `"sync"` or `"verify"`; interpreted by the worker.
+    pub kind: String,
+    pub payload: serde_json::Value,
+    /// Higher runs first; ties go to the oldest job.
+    pub priority: i32,
+    pub state: JobState,
+    /// Attempts started so far, including the current one while running.
+    pub attempts: u32,
+    pub retry: RetryPolicy,
+    pub last_error: Option<String>,
+    pub created_at: SystemTime,
+    pub updated_at: SystemTime,
+    /// Earliest time a retried job may be claimed again.
+    pub not_before: Option<SystemTime>,
+}
+
+/// What to enqueue; `JobSpec::new(kind, payload)` gives normal priority and the
+/// default retry policy.
+#[derive(Debug, Clone)]
+pub struct JobSpec {
+    pub kind: String,
+    pub payload: serde_json::Value,
+    pub priority: i32,
+    pub retry: RetryPolicy,
+}
+
+impl JobSpec {
+    pub fn new<S: Into<String>>(kind: S, payload: serde_json::Value) -> Self {
+        JobSpec {
+            kind: kind.into(),
+            payload,
+            priority: 0,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct QueueState {
+    next_id: JobId,
+    paused: bool,
+    jobs: BTreeMap<JobId, Job>,
+}
+
+/// A durable priority queue of background jobs.
+///
+/// Kept in an index store, every change is written through and flushed, so queued
+/// work survives restarts along with the index. Jobs that were running when the
+/// process died are put back in the queue on open; their interrupted attempt still
+/// counts toward the retry limit.
+pub struct JobQueue {
+    state: QueueState,
+    store: Option<SharedIndexStore>,
+}
+
+impl Default for JobQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JobQueue {
+    /// Queue kept in memory only
+    pub fn new() -> Self {
+        JobQueue {
+            state: QueueState {
+                next_id: 1,
+                ..QueueState::default()
+            },
+            store: None,
+        }
+    }
+
+    /// Open the queue kept in `store`, or start one there if it has none
+    pub fn in_store(store: SharedIndexStore) -> Result<Self, JobError> {
+        let stored = lock(&store).document(JOBS_DOCUMENT)?;
+        let mut state: QueueState = match stored {
+            Some(document) => serde_json::from_value(document)?,
+            None => QueueState {
+                next_id: 1,
+                ..QueueState::default()
+            },
+        };
+
+        let now = SystemTime::now();
+        for job in state.jobs.values_mut() {
+            if job.state == JobState::Running {
+                job.state = JobState::Queued;
+                job.last_error = Some("interrupted by restart".to_string());
+                job.updated_at = now;
+            }
+        }
+
+        let queue = JobQueue {
+            state,
+            store: Some(store),
+        };
+        queue.save()?;
+        Ok(queue)
+    }
+
+    pub fn enqueue(&mut self, spec: JobSpec) -> Result<JobId, JobError> {
+        let id = self.state.next_id;
+        self.state.next_id += 1;
+        let now = SystemTime::now();
+        self.state.jobs.insert(
+            id,
+            Job {
+                id,
+                kind: spec.kind,
+                payload: spec.payload,
+                priority: spec.priority,
+                state: JobState::Queued,
+                attempts: 0,
+                retry: spec.retry,
+                last_error: None,
+                created_at: now,
+                updated_at: now,
+                not_before: None,
+            },
+        );
+        self.save()?;
+        Ok(id)
+    }
+
+    pub fn get(&self, id: JobId) -> Option<&Job> {
+        self.state.jobs.get(&id)
+    }
+
+    /// All jobs in id (submission) order
+    pub fn jobs(&self) -> impl Iterator<Item = &Job> {
+        self.state.jobs.values()
+    }
+
+    pub fn jobs_in_state(&self, state: JobState) -> impl Iterator<Item = &Job> {
+        self.jobs().filter(move |j| j.state == state)
+    }
+
+    /// Take the highest-priority runnable job at `now`, marking it running.
+    /// Returns None while the whole queue is paused.
+    pub fn claim_next_at(&mut self, now: SystemTime) -> Result<Option<Job>, JobError> {
+        if self.state.paused {
+            return Ok(None);
+        }
+        let next = self
+            .state
+            .jobs
+            .values()
+            .filter(|j| j.state == JobState::Queued && j.not_before.is_none_or(|t| t <= now))
+            .max_by(|a, b| a.priority.cmp(&b.priority).then(b.id.cmp(&a.id)))
+            .map(|j| j.id);
+
+        let Some(id) = next else {
+            return Ok(None);
+        };
+        let job = self.state.jobs.get_mut(&id).expect("claimed job exists");
+        job.state = JobState::Running;
+        job.attempts += 1;
+        job.not_before = None;
+        job.updated_at = now;
+        let claimed = job.clone();
+        self.save()?;
+        Ok(Some(claimed))
+    }
+
+    pub fn claim_next(&mut self) -> Result<Option<Job>, JobError> {
+        self.claim_next_at(SystemTime::now())
+    }
+
+    /// Mark a running job as done. Jobs cancelled while running stay cancelled.
+    pub fn complete(&mut self, id: JobId) -> Result<(), JobError> {
+        let job = self.running_or_cancelled(id, "complete")?;
+        if job.state == JobState::Running {
+            job.state = JobState::Succeeded;
+            job.last_error = None;
+            job.updated_at = SystemTime::now();
+        }
+        self.save()
+    }
+
+    /// Record a failed attempt at `now`. The job is requeued after its backoff if
+    /// its retry policy allows, otherwise it ends as `Failed`. Returns the new state.
+    pub fn fail_at<E: Into<String>>(
+        &mut self,
+        id: JobId,
+        error: E,
+        now: SystemTime,
+    ) -> Result<JobState, JobError> {
+        let job = self.running_or_cancelled(id, "fail")?;
+        job.last_error = Some(error.into());
+        job.updated_at = now;
+        if job.state == JobState::Running {
+            if job.retry.should_retry(job.attempts) {
+                job.state = JobState::Queued;
+                job.not_before = Some(now + job.retry.delay(job.attempts));
+            } else {
+                job.state = JobState::Failed;
+            }
+        }
+        let state = job.state;
+        self.save()?;
+        Ok(state)
+    }
+
+    pub fn fail<E: Into<String>>(&mut self, id: JobId, error: E) -> Result<JobState, JobError> {
+        self.fail_at(id, error, SystemTime::now())
+    }
+
+    /// Hold a queued job so it is not claimed until resumed
+    pub fn pause(&mut self, id: JobId) -> Result<(), JobError> {
+        self.transition(id, "pause", JobState::Queued, JobState::Paused)
+    }
+
+    pub fn resume(&mut self, id: JobId) -> Result<(), JobError> {
+        self.transition(id, "resume", JobState::Paused, JobState::Queued)
+    }
+
+    /// Cancel any unfinished job. Workers should check `is_cancelled` between steps
+    /// of long-running work.
+    pub fn cancel(&mut self, id: JobId) -> Result<(), JobError> {
+        let job = self.state.jobs.get_mut(&id).ok_or(JobError::NotFound(id))?;
+        if job.state.is_terminal() {
+            return Err(JobError::InvalidTransition {
+                id,
+                from: job.state,
+                action: "cancel",
+            });
+        }
+        job.state = JobState::Cancelled;
+        job.updated_at = SystemTime::now();
+        self.save()
+    }
+
+    pub fn is_cancelled(&self, id: JobId) -> bool {
+        self.get(id).is_some_and(|j| j.state == JobState::Cancelled)
+    }
+
+    /// Stop handing out jobs without touching their individual states
+    pub fn pause_all(&mut self) -> Result<(), JobError> {
+        self.state.paused = true;
+        self.save()
+    }
+
+    pub fn resume_all(&mut self) -> Result<(), JobError> {
+        self.state.paused = false;
+        self.save()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.paused
+    }
+
+    /// Drop finished jobs from the queue, returning how many were removed
+    pub fn purge_finished(&mut self) -> Result<usize, JobError> {
+        let before = self.state.jobs.len();
+        self.state.jobs.retain(|_, j| !j.state.is_terminal());
+        let removed = before - self.state.jobs.len();
+        if removed > 0 {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    fn running_or_cancelled(
+        &mut self,
+        id: JobId,
+        action: &'static str,
+    ) -> Result<&mut Job, JobError> {
+        let job = self.state.jobs.get_mut(&id).ok_or(JobError::NotFound(id))?;
+        match job.state {
+            JobState::Running | JobState::Cancelled => Ok(job),
+            from => Err(JobError::InvalidTransition { id, from, action }),
+        }
+    }
+
+    fn transition(
+        &mut self,
+        id: JobId,
+        action: &'static str,
+        from: JobState,
+        to: JobState,
+    ) -> Result<(), JobError> {
+        let job = self.state.jobs.get_mut(&id).ok_or(JobError::NotFound(id))?;
+        if job.state != from {
+            return Err(JobError::InvalidTransition {
+                id,
+                from: job.state,
+                action,
+            });
+        }
+        job.state = to;
+        job.updated_at = SystemTime::now();
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), JobError> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        let mut store = lock(store);
+        store.put_document(JOBS_DOCUMENT, serde_json::to_value(&self.state)?)?;
+        store.flush()?;
+        Ok(())
+    }
+}
+
+fn lock(store: &SharedIndexStore) -> std::sync::MutexGuard<'_, Box<dyn IndexStore>> {
+    store.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::FileIndexStore;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    fn spec(kind: &str) -> JobSpec {
+        JobSpec::new(kind, serde_json::json!({ "root": "file:/music" }))
+    }
+
+    #[test]
+    fn test_priority_and_lifecycle() {
+        let mut queue = JobQueue::new();
+        let low = queue.enqueue(spec("verify")).unwrap();
+        let high = queue.enqueue(spec("sync").with_priority(10)).unwrap();
+        let low2 = queue.enqueue(spec("fingerprint")).unwrap();
+
+        let first = queue.claim_next().unwrap().unwrap();
+        assert_eq!(first.id, high);
+        assert_eq!(first.attempts, 1);
+        queue.complete(high).unwrap();
+        assert_eq!(queue.get(high).unwrap().state, JobState::Succeeded);
+
+        queue.pause(low).unwrap();
+        assert_eq!(queue.claim_next().unwrap().unwrap().id, low2);
+        assert!(queue.claim_next().unwrap().is_none());
+        queue.resume(low).unwrap();
+
+        queue.pause_all().unwrap();
+        assert!(queue.claim_next().unwrap().is_none());
+        queue.resume_all().unwrap();
+        assert_eq!(queue.claim_next().unwrap().unwrap().id, low);
+
+        queue.cancel(low).unwrap();
+        assert!(queue.is_cancelled(low));
+        queue.complete(low).unwrap();
+        assert_eq!(queue.get(low).unwrap().state, JobState::Cancelled);
+        assert!(queue.cancel(high).is_err());
+        assert!(matches!(queue.pause(99), Err(JobError::NotFound(99))));
+
+        assert_eq!(queue.purge_finished().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_retry_with_backoff() {
+        let mut queue = JobQueue::new();
+        let retry = RetryPolicy {
+            max_attempts: 2,
+            initial_backoff: Duration::from_secs(30),
+            ..RetryPolicy::default()
+        };
+        let id = queue.enqueue(spec("sync").with_retry(retry)).unwrap();
+        let t0 = SystemTime::now();
+
+        queue.claim_next_at(t0).unwrap().unwrap();
+        assert_eq!(queue.fail_at(id, "timeout", t0).unwrap(), JobState::Queued);
+        // Not claimable until the backoff elapses
+        assert!(
+            queue
+                .claim_next_at(t0 + Duration::from_secs(10))
+                .unwrap()
+                .is_none()
+        );
+        let job = queue
+            .claim_next_at(t0 + Duration::from_secs(30))
+            .unwrap()
+            .unwrap();
+        assert_eq!(job.attempts, 2);
+
+        assert_eq!(
+            queue.fail_at(id, "timeout again", t0).unwrap(),
+            JobState::Failed
+        );
+        assert_eq!(
+            queue.get(id).unwrap().last_error.as_deref(),
+            Some("timeout again")
+        );
+    }
+
+    #[test]
+    fn test_persistence_requeues_interrupted_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.json");
+        let open = || -> SharedIndexStore {
+            Arc::new(Mutex::new(Box::new(FileIndexStore::open(&path).unwrap())))
+        };
+
+        let (running, queued) = {
+            let mut queue = JobQueue::in_store(open()).unwrap();
+            let running = queue.enqueue(spec("sync").with_priority(1)).unwrap();
+            let queued = queue.enqueue(spec("verify")).unwrap();
+            queue.claim_next().unwrap();
+            (running, queued)
+        };
+
+        let mut queue = JobQueue::in_store(open()).unwrap();
+        assert_eq!(queue.get(running).unwrap().state, JobState::Queued);
+        assert_eq!(queue.get(running).unwrap().attempts, 1);
+        assert_eq!(queue.get(queued).unwrap().state, JobState::Queued);
+        let next_id = queue.enqueue(spec("transcode")).unwrap();
+        assert_eq!(next_id, 3);
+    }
+}
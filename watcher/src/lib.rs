@@ -1,10 +1,188 @@
+mod audit;
+mod bundle;
+mod catalog;
+mod collation;
+mod config;
+mod conflict;
+mod daemon;
+mod diff;
+mod environment;
+mod execution;
+mod fingerprint;
+#[cfg(feature = "scripting")]
+mod hooks;
+mod index;
+mod jobs;
+mod lyrics;
+mod manifest;
+mod media;
+#[cfg(feature = "media-server")]
+mod media_server;
+mod media_stream;
+mod mime;
+#[cfg(feature = "musicbrainz")]
+mod musicbrainz;
+mod namespace;
+mod playlist;
+mod preflight;
+mod probe;
+mod provenance;
+mod redact;
+mod retry;
+mod root;
+mod routing;
+mod schedule;
+#[cfg(unix)]
+mod service;
+mod smart_playlist;
+mod snapshot;
+mod status;
 mod storage;
+mod tenant;
+mod transliterate;
 mod universal_path;
+mod volumes;
+mod watch;
+#[cfg(feature = "webhook")]
+mod webhook;
 
+pub use audit::{AuditOptions, AuditReport, Difference, DifferenceKind, Severity, audit};
+pub use bundle::{BundleError, BundleReport, export_bundle, import_bundle};
+pub use catalog::{CATALOG_SCHEME, Catalog, CatalogEntry};
+#[cfg(feature = "collation")]
+pub use collation::LocaleCollation;
+pub use collation::{Collation, CollationError};
+pub use config::{
+    CacheSettings, ConfigBuilder, ConfigError, CredentialSettings, IndexFsync, IndexSettings,
+    Limits, OtolithConfig, RegistrySettings,
+};
+pub use conflict::ConflictPolicy;
+#[cfg(windows)]
+pub use daemon::{DEFAULT_CONTROL_PIPE, serve_control_pipe};
+pub use daemon::{Daemon, DaemonError, DaemonEvent, control_call, serve_control};
+#[cfg(unix)]
+pub use daemon::{default_control_socket, serve_control_socket};
+pub use diff::{DiffStream, diff_roots};
+pub use environment::{
+    DiskSpace, EnvironmentReport, InotifyLimits, ResourceLimit, RootCheck, available_space,
+    compiled_features, inotify_limits, open_files_limit,
+};
+pub use execution::{
+    ExecutionError, ExecutionSummary, ItemState, PartialTransfer, SyncExecution, SyncItem,
+};
+pub use fingerprint::{
+    AudioDecoder, DUPLICATE_SIMILARITY, DecoderFactory, Fingerprint, FingerprintError,
+    Fingerprinter, WavDecoder, group_duplicates,
+};
+#[cfg(feature = "scripting")]
+pub use hooks::{
+    HOOK_MAX_OPERATIONS, HOOK_READ_LIMIT, HookConfig, HookError, HookOutcome, HookRunner,
+    HookTrigger,
+};
+pub use index::{
+    ChangeKind, ChangeRecord, ChangeSource, FileIndexStore, FsyncPolicy, Index, IndexEntry,
+    IndexQuery, IndexStore, IndexStoreError, IndexWriter, RecoveryReport, ScanState, SharedIndex,
+    SharedIndexStore, StoredEntries, WalkSummary, update_from_walk,
+};
+pub use jobs::{Job, JobError, JobId, JobQueue, JobSpec, JobState};
+#[cfg(feature = "lyrics")]
+pub use lyrics::{LRCLIB_API, LrclibProvider};
+pub use lyrics::{
+    LyricsError, LyricsFetcher, LyricsProvider, LyricsQuery, LyricsRecord, LyricsSummary,
+    is_synced as is_synced_lyrics, sidecar_path as lyrics_sidecar_path,
+};
+pub use manifest::{Manifest, ManifestCheck, ManifestError};
+pub use media::{
+    AudioFormat, MediaError, MediaFormat, Tags, TrackMetadata, detect_format, read_track_metadata,
+};
+#[cfg(feature = "media-server")]
+pub use media_server::{MediaServer, MediaServerConfig, MediaServerError, MediaServerNotifier};
+pub use media_stream::{BufferStatus, MediaStream, MediaStreamOptions, next_in_album};
+pub use mime::{FALLBACK_CONTENT_TYPE, MimeConfig, MimeHandler, MimeMapping, MimeRegistry};
+#[cfg(feature = "musicbrainz")]
+pub use musicbrainz::{
+    MUSICBRAINZ_API, MusicBrainzClient, MusicBrainzError, RecordingMatch, TrackQuery,
+};
+pub use namespace::{FederatedEntry, LIBRARY_SCHEME, Layer, LayerRole, Namespace};
+pub use playlist::{
+    EntryStyle, Playlist, PlaylistEntry, PlaylistError, PlaylistRebaser, RewriteRule, RewriteRules,
+    UrlSigner, resolve_entry as resolve_playlist_entry,
+};
+pub use preflight::{
+    PlannedWrite, PreflightError, PreflightProblem, PreflightReport, check_path, preflight,
+};
+pub use probe::{
+    DEFAULT_SAMPLE_LIMIT, FormatStats, ProbeError, ProbeReport, parse_root_input, probe_root,
+    probe_root_with_limit,
+};
+pub use provenance::{PROVENANCE_ATTRIBUTE, Provenance, read_provenance, record_provenance};
+pub use redact::{NameRedaction, PrivacyConfig, Redacted, Redactor, redactor, set_privacy_config};
+pub use retry::RetryPolicy;
+pub use root::{AUDIO_EXTENSIONS, RootConfig};
+pub use routing::{Route, RoutePlan, RoutedDestinations, Router};
+pub use schedule::{CronExpr, JobFuture, Schedule, ScheduleError, Scheduler};
+#[cfg(unix)]
+pub use service::{ActivatedSockets, ServiceNotifier};
+pub use smart_playlist::{PlaylistSort, SmartPlaylist, playlist_under};
+pub use snapshot::{
+    Snapshot, SnapshotChange, SnapshotChanges, SnapshotDiff, SnapshotEntry, SnapshotFileError,
+    SnapshotOptions, SnapshotReader, SnapshotWriter, capture_snapshot_file, diff_snapshot_files,
+};
+pub use status::{DaemonStatus, HealthState, RootStatus, StatusReport, serve_status};
+pub use storage::FilterSet;
+#[cfg(feature = "http")]
+pub use storage::HttpStorage;
+pub use storage::RetryingStorage;
 pub use storage::{
-    open_storage_for, EntryKind, EntryMetadata, Storage, StorageBackend, StorageCapabilities,
-    StorageError,
+    AuthMethod, BackendInfo, ByteStream, CallOptions, CallPriority, Checksum, ChecksumAlgorithm,
+    CommitReport, CredentialChain, CredentialError, CredentialProvider, CredentialStore,
+    Credentials, EntryKind, EntryMetadata, EntryPermissions, EnvCredentials, LOCK_FILE_NAME,
+    LinkPolicy, LocalStorage, LockInfo, MemoryStorage, Precondition, Quota, QuotaStorage,
+    QuotaUsage, READ_STREAM_CHUNK, RangePart, RangeReader, SmbMount, SmbStorage, Storage,
+    StorageAccount, StorageBackend, StorageCapabilities, StorageError, StorageExt, StorageFactory,
+    StorageLock, StorageManager, StorageRegistry, StorageTransaction, SymlinkPolicy,
+    TransactionError, WalkOptions, WalkOrder, WalkStream, capability_matrix, clamp_range,
+    credential_provider, open_storage_for, open_storage_with, register_backend,
+    set_credential_provider, set_storage_registry, storage_registry,
+};
+pub use storage::{CacheConfig, CacheStats, CachedStorage};
+#[cfg(feature = "chaos")]
+pub use storage::{ChaosConfig, ChaosStorage, Latency};
+pub use storage::{ChecksumDb, ChecksumDbError, ChecksumDbStats, FileId, IncrementalChecksums};
+pub use storage::{ClockSkew, SKEW_WARNING, SkewDiagnostic, SkewEstimate, clock_skew, host_key};
+pub use storage::{FdBudget, FdBudgetStats, FdDiagnostic, FdPermit, fd_budget};
+#[cfg(feature = "ftp")]
+pub use storage::{FtpConfig, FtpStorage};
+pub use storage::{HostHealth, check_health};
+pub use storage::{LabelledEntry, MultiScanStream, MultiScanner};
+pub use storage::{MacAddress, WakeConfig, WakingStorage, magic_packet, send_magic_packet, wake};
+pub use storage::{
+    OperationHandle, OperationId, OperationInfo, OperationRegistry, TrackedStorage, operations,
+};
+#[cfg(feature = "plugins")]
+pub use storage::{PluginError, PluginStorage, load_plugins, plugin};
+#[cfg(feature = "s3")]
+pub use storage::{S3Config, S3Credentials, S3Storage};
+pub use storage::{ScanItemStream, ScanProgress, ScanStream, Scanner, default_scan_concurrency};
+#[cfg(feature = "sftp")]
+pub use storage::{SftpAuth, SftpConfig, SftpStorage};
+pub use storage::{SkipCounts, SkipReason, Skipped, WalkItem, WalkItemStream};
+pub use storage::{TransferProgress, TransferReport, copy_target, transfer, transfer_move};
+#[cfg(feature = "webdav")]
+pub use storage::{WebDavConfig, WebDavStorage};
+pub use tenant::{Tenant, TenantError, Tenants};
+pub use transliterate::{NameMode, to_ascii};
+pub use universal_path::{PathFlavor, UniversalPath, UniversalPathError};
+#[cfg(windows)]
+pub use volumes::mounted_volumes;
+pub use volumes::{VolumeChange, VolumeError, VolumeId, VolumeRegistry};
+#[cfg(feature = "watch")]
+pub use watch::LocalWatcher;
+pub use watch::{
+    EventDebouncer, PollingWatcher, StabilityFilter, WatchEvent, WatchStream, Watcher,
+};
+#[cfg(feature = "webhook")]
+pub use webhook::{
+    EVENT_HEADER, SIGNATURE_HEADER, WebhookConfig, WebhookError, WebhookEvent, WebhookSink,
+    sign as sign_webhook_body,
 };
-pub use universal_path::{UniversalPath, UniversalPathError};
-
- 
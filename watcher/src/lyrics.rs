@@ -0,0 +1,449 @@
+use crate::{
+    index::Index,
+    media::{MediaError, TrackMetadata, read_track_metadata},
+    root::RootConfig,
+    storage::{Storage, StorageError},
+    universal_path::UniversalPath,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+#[cfg(feature = "lyrics")]
+pub const LRCLIB_API: &str = "https://lrclib.net/api";
+
+#[derive(Debug, Error)]
+pub enum LyricsError {
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error(transparent)]
+    Media(#[from] MediaError),
+    #[cfg(feature = "lyrics")]
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error("{provider} answered {status}")]
+    Status { provider: String, status: u16 },
+    #[error("unexpected response from {provider}: {message}")]
+    Decode { provider: String, message: String },
+}
+
+/// What a provider is asked for lyrics by, from the track's tags.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LyricsQuery {
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub duration: Option<Duration>,
+}
+
+impl LyricsQuery {
+    /// The query for a track, if it has a title to look up
+    pub fn from_metadata(meta: &TrackMetadata) -> Option<Self> {
+        Some(LyricsQuery {
+            title: meta.title.clone()?,
+            artist: meta.artist.clone().or(meta.album_artist.clone()),
+            album: meta.album.clone(),
+            duration: meta.duration,
+        })
+    }
+}
+
+/// A source of time-synced lyrics, such as LRCLIB.
+#[async_trait]
+pub trait LyricsProvider: Send + Sync {
+    /// Recorded in the index as where a sidecar's lyrics came from
+    fn name(&self) -> &str;
+
+    /// The track's lyrics in LRC, if the provider has them time-synced
+    async fn fetch(&self, query: &LyricsQuery) -> Result<Option<String>, LyricsError>;
+}
+
+/// Where lyrics written into the library came from, kept on the track's index entry.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LyricsRecord {
+    pub sidecar: UniversalPath,
+    pub provider: String,
+    pub fetched_at: SystemTime,
+}
+
+/// What `LyricsFetcher::fetch_root` did, by track.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct LyricsSummary {
+    pub written: usize,
+    /// Tracks no provider had synced lyrics for, or with no title to ask by
+    pub not_found: usize,
+    /// Tracks with a sidecar already, fetched by us or put there by someone else
+    pub already_present: usize,
+    /// Tracks under a read-only root, left alone
+    pub read_only: usize,
+    pub failed: Vec<(UniversalPath, String)>,
+}
+
+/// The `.lrc` sidecar of the track at `path`, next to it with the same stem
+pub fn sidecar_path(path: &UniversalPath) -> UniversalPath {
+    path.with_extension("lrc")
+}
+
+/// Whether `lrc` has at least one line with a `[mm:ss.xx]` time, rather than being
+/// plain text
+pub fn is_synced(lrc: &str) -> bool {
+    lrc.lines().any(|line| {
+        let Some((time, _)) = line
+            .trim_start()
+            .strip_prefix('[')
+            .and_then(|l| l.split_once(']'))
+        else {
+            return false;
+        };
+        let Some((minutes, seconds)) = time.split_once(':') else {
+            return false;
+        };
+        !minutes.is_empty()
+            && minutes.bytes().all(|b| b.is_ascii_digit())
+            && seconds.parse::<f64>().is_ok()
+    })
+}
+
+/// Fetches time-synced lyrics for indexed tracks and writes them next to each as an
+/// `.lrc` sidecar, asking the providers in the order added until one has them.
+///
+/// Nothing is written under a root marked `read_only`, and a track that already has
+/// a sidecar keeps it. Each sidecar written is recorded on the track's index entry,
+/// so later runs skip it without looking.
+#[derive(Default)]
+pub struct LyricsFetcher {
+    providers: Vec<Box<dyn LyricsProvider>>,
+}
+
+impl LyricsFetcher {
+    pub fn new() -> Self {
+        LyricsFetcher::default()
+    }
+
+    /// Asked after the providers already added
+    pub fn with_provider<P: LyricsProvider + 'static>(mut self, provider: P) -> Self {
+        self.providers.push(Box::new(provider));
+        self
+    }
+
+    /// Fetch lyrics for every track indexed under `root` that has none yet. A track
+    /// that cannot be read, or that every provider failed on, is listed in the
+    /// summary's `failed` and the rest carry on.
+    pub async fn fetch_root(
+        &self,
+        index: &mut Index,
+        storage: &dyn Storage,
+        root: &RootConfig,
+    ) -> LyricsSummary {
+        let mut summary = LyricsSummary::default();
+        let tracks: Vec<_> = index
+            .iter()
+            .filter(|entry| entry.path.relative_to(&root.path).is_some())
+            .filter(|entry| entry.lyrics.is_none())
+            .map(|entry| entry.path.clone())
+            .collect();
+        if root.read_only {
+            summary.read_only = tracks.len();
+            return summary;
+        }
+        for track in tracks {
+            match self.fetch_track(storage, &track).await {
+                Ok(Fetched::Written(record)) => {
+                    index.set_lyrics(&track, record);
+                    summary.written += 1;
+                }
+                Ok(Fetched::Present) => summary.already_present += 1,
+                Ok(Fetched::NotFound) => summary.not_found += 1,
+                Err(e) => summary.failed.push((track, e.to_string())),
+            }
+        }
+        summary
+    }
+
+    async fn fetch_track(
+        &self,
+        storage: &dyn Storage,
+        track: &UniversalPath,
+    ) -> Result<Fetched, LyricsError> {
+        let sidecar = sidecar_path(track);
+        match storage.stat(&sidecar).await {
+            Ok(_) => return Ok(Fetched::Present),
+            Err(StorageError::NotFound) => {}
+            Err(e) => return Err(e.into()),
+        }
+        let meta = read_track_metadata(storage, track).await?;
+        let Some(query) = LyricsQuery::from_metadata(&meta) else {
+            return Ok(Fetched::NotFound);
+        };
+        let mut failure = None;
+        for provider in &self.providers {
+            let lrc = match provider.fetch(&query).await {
+                Ok(Some(lrc)) if is_synced(&lrc) => lrc,
+                Ok(_) => continue,
+                Err(e) => {
+                    failure = Some(e);
+                    continue;
+                }
+            };
+            storage.write(&sidecar, lrc.as_bytes()).await?;
+            return Ok(Fetched::Written(LyricsRecord {
+                sidecar,
+                provider: provider.name().to_string(),
+                fetched_at: SystemTime::now(),
+            }));
+        }
+        failure.map_or(Ok(Fetched::NotFound), Err)
+    }
+}
+
+enum Fetched {
+    Written(LyricsRecord),
+    Present,
+    NotFound,
+}
+
+/// Synced lyrics from LRCLIB, an open database that needs no key.
+#[cfg(feature = "lyrics")]
+pub struct LrclibProvider {
+    base_url: String,
+    user_agent: String,
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "lyrics")]
+impl LrclibProvider {
+    pub fn new() -> Self {
+        LrclibProvider {
+            base_url: LRCLIB_API.to_string(),
+            user_agent: format!("otolith/{}", env!("CARGO_PKG_VERSION")),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Point at a mirror or a self-hosted instance
+    pub fn with_base_url<S: Into<String>>(mut self, base_url: S) -> Self {
+        self.base_url = base_url.into();
+        self
+    }
+}
+
+#[cfg(feature = "lyrics")]
+impl Default for LrclibProvider {
+    fn default() -> Self {
+        LrclibProvider::new()
+    }
+}
+
+#[cfg(feature = "lyrics")]
+#[async_trait]
+impl LyricsProvider for LrclibProvider {
+    fn name(&self) -> &str {
+        "lrclib"
+    }
+
+    async fn fetch(&self, query: &LyricsQuery) -> Result<Option<String>, LyricsError> {
+        let mut params = vec![("track_name", query.title.clone())];
+        params.extend(query.artist.clone().map(|a| ("artist_name", a)));
+        params.extend(query.album.clone().map(|a| ("album_name", a)));
+        params.extend(
+            query
+                .duration
+                .map(|d| ("duration", d.as_secs().to_string())),
+        );
+        let resp = self
+            .client
+            .get(format!("{}/get", self.base_url.trim_end_matches('/')))
+            .header(reqwest::header::USER_AGENT, &self.user_agent)
+            .query(&params)
+            .send()
+            .await?;
+        match resp.status().as_u16() {
+            404 => Ok(None),
+            200..=299 => parse_lrclib(&resp.text().await?),
+            status => Err(LyricsError::Status {
+                provider: self.name().to_string(),
+                status,
+            }),
+        }
+    }
+}
+
+#[cfg(feature = "lyrics")]
+fn parse_lrclib(body: &str) -> Result<Option<String>, LyricsError> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Record {
+        synced_lyrics: Option<String>,
+    }
+    let record: Record = serde_json::from_str(body).map_err(|e| LyricsError::Decode {
+        provider: "lrclib".to_string(),
+        message: e.to_string(),
+    })?;
+    Ok(record.synced_lyrics.filter(|lrc| !lrc.trim().is_empty()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::ChangeSource;
+    use crate::media::tests::comments;
+    use crate::storage::MemoryStorage;
+    use std::sync::Mutex;
+
+    const LRC: &str = "[ar:Björk]\n[00:12.30]Emotional landscapes\n[00:17.85]They puzzle me\n";
+
+    /// Answers from a fixed list of titles, remembering what it was asked
+    struct Fixed {
+        name: &'static str,
+        lyrics: Vec<(&'static str, &'static str)>,
+        asked: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl LyricsProvider for Fixed {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        async fn fetch(&self, query: &LyricsQuery) -> Result<Option<String>, LyricsError> {
+            self.asked.lock().unwrap().push(query.title.clone());
+            if query.title == "Broken" {
+                return Err(LyricsError::Status {
+                    provider: self.name.to_string(),
+                    status: 503,
+                });
+            }
+            let found = self.lyrics.iter().find(|(title, _)| *title == query.title);
+            Ok(found.map(|(_, lrc)| lrc.to_string()))
+        }
+    }
+
+    fn provider(name: &'static str, lyrics: Vec<(&'static str, &'static str)>) -> Fixed {
+        Fixed {
+            name,
+            lyrics,
+            asked: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// A FLAC with just enough of a header to be read, titled `title`
+    fn flac(title: &str) -> Vec<u8> {
+        let mut file = b"fLaC".to_vec();
+        let packed: u64 = (44_100 << 44) | (1 << 41) | (15 << 36) | (44_100 * 180);
+        let mut info = vec![0; 10];
+        info.extend_from_slice(&packed.to_be_bytes());
+        info.extend_from_slice(&[0; 16]);
+        file.extend_from_slice(&[0, 0, 0, info.len() as u8]);
+        file.extend(info);
+        let tags = comments(&[&format!("TITLE={title}"), "ARTIST=Björk"]);
+        let len = (tags.len() as u32).to_be_bytes();
+        file.extend_from_slice(&[0x84, len[1], len[2], len[3]]);
+        file.extend(tags);
+        file
+    }
+
+    async fn library(titles: &[&str]) -> (MemoryStorage, RootConfig, Index) {
+        let storage = MemoryStorage::new();
+        let root = RootConfig::new(UniversalPath::from_uri_str("mem://nas/music/").unwrap());
+        storage.create_dir(&root.path).await.unwrap();
+        for (n, title) in titles.iter().enumerate() {
+            let path = root.path.join(format!("{n:02} {title}.flac"));
+            storage.write(&path, &flac(title)).await.unwrap();
+        }
+        let mut index = Index::new();
+        index.scan(&storage, &root).await.unwrap();
+        (storage, root, index)
+    }
+
+    #[test]
+    fn test_is_synced() {
+        assert!(is_synced(LRC));
+        assert!(is_synced("[01:02]Line"));
+        assert!(!is_synced("[ar:Björk]\nJust the words\n"));
+        assert!(!is_synced("[:12.3]Line"));
+        assert!(!is_synced(""));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_writes_sidecars() {
+        let (storage, root, mut index) = library(&["Jóga", "Unknown"]).await;
+        let fetcher = LyricsFetcher::new()
+            .with_provider(provider("first", vec![]))
+            .with_provider(provider("second", vec![("Jóga", LRC)]));
+        let summary = fetcher.fetch_root(&mut index, &storage, &root).await;
+        assert_eq!((summary.written, summary.not_found), (1, 1));
+        assert!(summary.failed.is_empty());
+
+        let track = root.path.join("00 Jóga.flac");
+        let sidecar = root.path.join("00 Jóga.lrc");
+        assert_eq!(storage.read(&sidecar).await.unwrap(), LRC.as_bytes());
+        let record = index.get(&track).unwrap().lyrics.as_ref().unwrap();
+        assert_eq!(
+            (&record.sidecar, record.provider.as_str()),
+            (&sidecar, "second")
+        );
+
+        // Tracks with lyrics recorded are not looked up again
+        let again = fetcher.fetch_root(&mut index, &storage, &root).await;
+        assert_eq!((again.written, again.not_found), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn test_existing_sidecar_is_kept() {
+        let (storage, root, mut index) = library(&["Jóga"]).await;
+        let sidecar = root.path.join("00 Jóga.lrc");
+        storage.write(&sidecar, b"[00:01.00]Mine").await.unwrap();
+        let fetcher = LyricsFetcher::new().with_provider(provider("lrc", vec![("Jóga", LRC)]));
+        let summary = fetcher.fetch_root(&mut index, &storage, &root).await;
+        assert_eq!((summary.written, summary.already_present), (0, 1));
+        assert_eq!(storage.read(&sidecar).await.unwrap(), b"[00:01.00]Mine");
+    }
+
+    #[tokio::test]
+    async fn test_read_only_root_is_left_alone() {
+        let (storage, mut root, mut index) = library(&["Jóga"]).await;
+        root.read_only = true;
+        let fetcher = LyricsFetcher::new().with_provider(provider("lrc", vec![("Jóga", LRC)]));
+        let summary = fetcher.fetch_root(&mut index, &storage, &root).await;
+        assert_eq!((summary.written, summary.read_only), (0, 1));
+        let sidecar = root.path.join("00 Jóga.lrc");
+        assert!(matches!(
+            storage.stat(&sidecar).await,
+            Err(StorageError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_provider_errors_and_plain_lyrics() {
+        let (storage, root, mut index) = library(&["Broken", "Plain"]).await;
+        // Words without times are not what a sidecar is for
+        let fetcher =
+            LyricsFetcher::new().with_provider(provider("lrc", vec![("Plain", "Just words")]));
+        let summary = fetcher.fetch_root(&mut index, &storage, &root).await;
+        assert_eq!((summary.written, summary.not_found), (0, 1));
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.failed[0].0, root.path.join("00 Broken.flac"));
+        assert!(summary.failed[0].1.contains("503"));
+
+        // A track that is not audio fails on its own
+        let noise = root.path.join("noise.flac");
+        storage.write(&noise, b"not audio").await.unwrap();
+        let meta = storage.stat(&noise).await.unwrap();
+        index.update(&noise, &meta, ChangeSource::Scan, SystemTime::now());
+        let summary = fetcher.fetch_root(&mut index, &storage, &root).await;
+        assert!(summary.failed.iter().any(|(path, _)| *path == noise));
+    }
+
+    #[cfg(feature = "lyrics")]
+    #[test]
+    fn test_parse_lrclib() {
+        let body = r#"{"id": 1, "trackName": "Jóga", "plainLyrics": "Emotional landscapes",
+            "syncedLyrics": "[00:12.30]Emotional landscapes"}"#;
+        let lrc = parse_lrclib(body).unwrap();
+        assert_eq!(lrc.as_deref(), Some("[00:12.30]Emotional landscapes"));
+        let plain = r#"{"id": 2, "plainLyrics": "Words", "syncedLyrics": null}"#;
+        assert_eq!(parse_lrclib(plain).unwrap(), None);
+        assert!(parse_lrclib("not json").is_err());
+    }
+}
@@ -0,0 +1,464 @@
+use crate::audit::{Difference, DifferenceKind};
+use crate::storage::{Checksum, ChecksumAlgorithm, EntryKind, Storage, StorageError, StorageExt};
+use crate::universal_path::UniversalPath;
+use futures::StreamExt;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use thiserror::Error;
+
+/// Declaration every BagIt bag starts with (RFC 8493 §2.1.1)
+pub(crate) const BAGIT_TXT: &str = "BagIt-Version: 1.0\nTag-File-Character-Encoding: UTF-8\n";
+
+/// Algorithms tried, in order, when reading a bag
+const BAG_ALGORITHMS: [ChecksumAlgorithm; 4] = [
+    ChecksumAlgorithm::Sha256,
+    ChecksumAlgorithm::Blake3,
+    ChecksumAlgorithm::Md5,
+    ChecksumAlgorithm::Xxh3,
+];
+
+#[derive(Debug, Error)]
+pub enum ManifestError {
+    #[error("line {line}: {reason}")]
+    Malformed { line: usize, reason: String },
+    #[error("no manifest-<algorithm>.txt in {0}")]
+    NotABag(Box<UniversalPath>),
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+/// Checksums of files below a root, by `/`-separated relative path, readable and
+/// writable as `sha256sum`-style checksum files and BagIt manifests so whoever
+/// receives an archive can check it with standard tools.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    algorithm: ChecksumAlgorithm,
+    entries: BTreeMap<String, Checksum>,
+}
+
+/// Result of checking a tree against a manifest.
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestCheck {
+    pub files_verified: usize,
+    /// Missing, ContentMismatch and Unreadable for listed files; Extra for unlisted
+    /// ones when the check was complete
+    pub differences: Vec<Difference>,
+}
+
+impl ManifestCheck {
+    pub fn is_clean(&self) -> bool {
+        self.differences.is_empty()
+    }
+}
+
+impl Manifest {
+    pub fn new(algorithm: ChecksumAlgorithm) -> Self {
+        Manifest {
+            algorithm,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    pub fn algorithm(&self) -> ChecksumAlgorithm {
+        self.algorithm
+    }
+
+    /// Entries in path order
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &Checksum)> {
+        self.entries.iter().map(|(path, sum)| (path.as_str(), sum))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, path: &str) -> Option<&Checksum> {
+        self.entries.get(path)
+    }
+
+    /// Add or replace the checksum for `path`, relative to the manifest's root
+    pub fn insert(&mut self, path: &str, checksum: Checksum) {
+        debug_assert_eq!(checksum.algorithm, self.algorithm);
+        self.entries.insert(path.to_string(), checksum);
+    }
+
+    /// Checksum every file below `root`
+    pub async fn build(
+        storage: &dyn Storage,
+        root: &UniversalPath,
+        algorithm: ChecksumAlgorithm,
+    ) -> Result<Self, StorageError> {
+        let mut files = Vec::new();
+        let mut walk = storage.walk(root);
+        while let Some(entry) = walk.next().await {
+            let (path, meta) = entry?;
+            if meta.kind == EntryKind::File {
+                files.push(path);
+            }
+        }
+        Manifest::build_from(storage, root, &files, algorithm).await
+    }
+
+    /// Checksum just `files`, e.g. the entries of an `Index` added this month. Files
+    /// outside `root` are skipped.
+    pub async fn build_from(
+        storage: &dyn Storage,
+        root: &UniversalPath,
+        files: &[UniversalPath],
+        algorithm: ChecksumAlgorithm,
+    ) -> Result<Self, StorageError> {
+        let mut manifest = Manifest::new(algorithm);
+        for file in files {
+            let Some(rel) = relative(file, root) else {
+                continue;
+            };
+            let checksum = storage.checksum(file, algorithm).await?;
+            manifest.entries.insert(rel, checksum);
+        }
+        Ok(manifest)
+    }
+
+    /// The manifest as `sha256sum` (or `md5sum`, `b3sum`) prints it, paths relative
+    /// to the root, so `sha256sum -c` run in the root checks it
+    pub fn to_checksum_file(&self) -> String {
+        let mut out = String::new();
+        for (path, checksum) in &self.entries {
+            // GNU coreutils marks names with a backslash or newline by a leading `\`
+            if path.contains(['\\', '\n', '\r']) {
+                let escaped = path
+                    .replace('\\', "\\\\")
+                    .replace('\n', "\\n")
+                    .replace('\r', "\\r");
+                out.push_str(&format!("\\{}  {escaped}\n", checksum.to_hex()));
+            } else {
+                out.push_str(&format!("{}  {path}\n", checksum.to_hex()));
+            }
+        }
+        out
+    }
+
+    /// Read a checksum file in the format `to_checksum_file` writes; `*` binary markers
+    /// and escaped names from GNU tools are accepted
+    pub fn parse_checksum_file(
+        text: &str,
+        algorithm: ChecksumAlgorithm,
+    ) -> Result<Self, ManifestError> {
+        let mut manifest = Manifest::new(algorithm);
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (escaped, line) = match line.strip_prefix('\\') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            let (hex, name) = line
+                .split_once(' ')
+                .ok_or_else(|| malformed(i, "expected `<checksum>  <path>`"))?;
+            let name = name
+                .strip_prefix([' ', '*'])
+                .ok_or_else(|| malformed(i, "expected two spaces or ` *` after the checksum"))?;
+            let name = match escaped {
+                true => unescape_gnu(name).ok_or_else(|| malformed(i, "bad escape"))?,
+                false => name.to_string(),
+            };
+            manifest.insert_parsed(i, &name, hex)?;
+        }
+        Ok(manifest)
+    }
+
+    /// The manifest as a BagIt `manifest-<algorithm>.txt`, for a bag whose payload is
+    /// the manifest's root under `data/`
+    pub fn to_bagit_manifest(&self) -> String {
+        let mut out = String::new();
+        for (path, checksum) in &self.entries {
+            let encoded = path
+                .replace('%', "%25")
+                .replace('\n', "%0A")
+                .replace('\r', "%0D");
+            out.push_str(&format!("{}  data/{encoded}\n", checksum.to_hex()));
+        }
+        out
+    }
+
+    /// Read a BagIt payload manifest; paths come back relative to `data/`
+    pub fn parse_bagit_manifest(
+        text: &str,
+        algorithm: ChecksumAlgorithm,
+    ) -> Result<Self, ManifestError> {
+        let mut manifest = Manifest::new(algorithm);
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (hex, path) = line
+                .split_once([' ', '\t'])
+                .ok_or_else(|| malformed(i, "expected `<checksum> <filepath>`"))?;
+            let path = path.trim_start_matches([' ', '\t']);
+            let path = path
+                .strip_prefix("data/")
+                .ok_or_else(|| malformed(i, "payload paths must start with data/"))?;
+            let path = path
+                .replace("%0A", "\n")
+                .replace("%0a", "\n")
+                .replace("%0D", "\r")
+                .replace("%0d", "\r")
+                .replace("%25", "%");
+            manifest.insert_parsed(i, &path, hex)?;
+        }
+        Ok(manifest)
+    }
+
+    fn insert_parsed(&mut self, line: usize, path: &str, hex: &str) -> Result<(), ManifestError> {
+        let bytes = hex::decode(hex).map_err(|_| malformed(line, "checksum is not hex"))?;
+        if bytes.len() != Checksum::of(self.algorithm, b"").bytes.len() {
+            return Err(malformed(
+                line,
+                &format!("not a {} checksum", self.algorithm.name()),
+            ));
+        }
+        if path.is_empty()
+            || path
+                .split('/')
+                .any(|s| s.is_empty() || s == "." || s == "..")
+        {
+            return Err(malformed(line, &format!("unsafe path {path:?}")));
+        }
+        let checksum = Checksum {
+            algorithm: self.algorithm,
+            bytes,
+        };
+        self.entries.insert(path.to_string(), checksum);
+        Ok(())
+    }
+
+    /// Write `bagit.txt`, the payload manifest and a tag manifest covering both into
+    /// `bag`, making it a complete bag once the manifest's files sit in `bag/data/`
+    pub async fn write_bag(
+        &self,
+        storage: &dyn Storage,
+        bag: &UniversalPath,
+    ) -> Result<(), StorageError> {
+        let name = self.algorithm.name();
+        let manifest_name = format!("manifest-{name}.txt");
+        let manifest = self.to_bagit_manifest();
+        let mut tags = String::new();
+        for (file, body) in [
+            ("bagit.txt", BAGIT_TXT),
+            (manifest_name.as_str(), &manifest),
+        ] {
+            storage.write(&bag.join(file), body.as_bytes()).await?;
+            let checksum = Checksum::of(self.algorithm, body.as_bytes());
+            tags.push_str(&format!("{}  {file}\n", checksum.to_hex()));
+        }
+        storage
+            .write(
+                &bag.join(format!("tagmanifest-{name}.txt")),
+                tags.as_bytes(),
+            )
+            .await
+    }
+
+    /// The payload manifest of the bag at `bag`, under the first of SHA-256, BLAKE3,
+    /// MD5 and XXH3 it has one for
+    pub async fn read_bag(
+        storage: &dyn Storage,
+        bag: &UniversalPath,
+    ) -> Result<Self, ManifestError> {
+        for algorithm in BAG_ALGORITHMS {
+            let file = bag.join(format!("manifest-{}.txt", algorithm.name()));
+            match storage.read(&file).await {
+                Ok(data) => {
+                    let text = String::from_utf8(data)
+                        .map_err(|_| malformed(0, "manifest is not UTF-8"))?;
+                    return Manifest::parse_bagit_manifest(&text, algorithm);
+                }
+                Err(StorageError::NotFound) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(ManifestError::NotABag(Box::new(bag.clone())))
+    }
+
+    /// Check every listed file below `root`. Failing to read one is reported, not
+    /// returned.
+    pub async fn verify(&self, storage: &dyn Storage, root: &UniversalPath) -> ManifestCheck {
+        let mut check = ManifestCheck {
+            files_verified: 0,
+            differences: Vec::new(),
+        };
+        for (rel, expected) in &self.entries {
+            let file = root.join_all(&rel.split('/').collect::<Vec<_>>());
+            let differ = |kind, detail| Difference::new(rel, kind, detail);
+            match storage.checksum(&file, self.algorithm).await {
+                Ok(actual) if &actual == expected => check.files_verified += 1,
+                Ok(actual) => check.differences.push(differ(
+                    DifferenceKind::ContentMismatch,
+                    format!("{actual}, manifest has {expected}"),
+                )),
+                Err(StorageError::NotFound) => check.differences.push(differ(
+                    DifferenceKind::Missing,
+                    "listed in the manifest".into(),
+                )),
+                Err(e) => check
+                    .differences
+                    .push(differ(DifferenceKind::Unreadable, e.to_string())),
+            }
+        }
+        check
+    }
+
+    /// `verify`, and also report files below `root` the manifest does not list, as a
+    /// BagIt validator does for a bag's payload
+    pub async fn verify_complete(
+        &self,
+        storage: &dyn Storage,
+        root: &UniversalPath,
+    ) -> Result<ManifestCheck, StorageError> {
+        let mut check = self.verify(storage, root).await;
+        let mut unlisted = BTreeSet::new();
+        let mut walk = storage.walk(root);
+        while let Some(entry) = walk.next().await {
+            let (path, meta) = entry?;
+            if meta.kind != EntryKind::File {
+                continue;
+            }
+            if let Some(rel) = relative(&path, root).filter(|rel| !self.entries.contains_key(rel)) {
+                unlisted.insert(rel);
+            }
+        }
+        for rel in unlisted {
+            let detail = "not listed in the manifest".to_string();
+            check
+                .differences
+                .push(Difference::new(&rel, DifferenceKind::Extra, detail));
+        }
+        Ok(check)
+    }
+
+    /// `verify_complete` on the bag at `bag` against its own payload manifest
+    pub async fn verify_bag(
+        storage: &dyn Storage,
+        bag: &UniversalPath,
+    ) -> Result<ManifestCheck, ManifestError> {
+        let manifest = Manifest::read_bag(storage, bag).await?;
+        Ok(manifest.verify_complete(storage, &bag.join("data")).await?)
+    }
+}
+
+fn relative(file: &UniversalPath, root: &UniversalPath) -> Option<String> {
+    let segments = file.relative_to(root)?;
+    (!segments.is_empty()).then(|| segments.join("/"))
+}
+
+fn malformed(line: usize, reason: &str) -> ManifestError {
+    ManifestError::Malformed {
+        line: line + 1,
+        reason: reason.to_string(),
+    }
+}
+
+fn unescape_gnu(name: &str) -> Option<String> {
+    let mut out = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                '\\' => out.push('\\'),
+                'n' => out.push('\n'),
+                'r' => out.push('\r'),
+                _ => return None,
+            },
+            c => out.push(c),
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_bag_round_trip() {
+        let storage = MemoryStorage::new();
+        let bag = UniversalPath::from_uri_str("mem://manifest/bag/").unwrap();
+        let data = bag.join("data");
+        storage.create_dir(&data.join("album")).await.unwrap();
+        storage
+            .write(&data.join("album").join("01.flac"), b"one")
+            .await
+            .unwrap();
+        storage
+            .write(&data.join("100% live.flac"), b"two")
+            .await
+            .unwrap();
+
+        let manifest = Manifest::build(&storage, &data, ChecksumAlgorithm::Sha256)
+            .await
+            .unwrap();
+        assert_eq!(manifest.len(), 2);
+        manifest.write_bag(&storage, &bag).await.unwrap();
+        let text = String::from_utf8(
+            storage
+                .read(&bag.join("manifest-sha256.txt"))
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        assert!(text.contains("  data/100%25 live.flac\n"));
+        assert_eq!(Manifest::read_bag(&storage, &bag).await.unwrap(), manifest);
+        assert!(
+            Manifest::verify_bag(&storage, &bag)
+                .await
+                .unwrap()
+                .is_clean()
+        );
+
+        // Damage the payload: one file changed, one added
+        storage
+            .write(&data.join("album").join("01.flac"), b"1")
+            .await
+            .unwrap();
+        storage.write(&data.join("stray.txt"), b"-").await.unwrap();
+        let check = Manifest::verify_bag(&storage, &bag).await.unwrap();
+        let kinds: Vec<_> = check
+            .differences
+            .iter()
+            .map(|d| (d.path.as_str(), d.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                ("album/01.flac", DifferenceKind::ContentMismatch),
+                ("stray.txt", DifferenceKind::Extra),
+            ]
+        );
+        assert_eq!(check.files_verified, 1);
+    }
+
+    #[test]
+    fn test_checksum_file_format() {
+        // As GNU sha256sum prints two empty files, one named "a\nb"
+        let text = "\\e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  a\\nb\n\
+                    e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  cover.jpg\n";
+        let manifest = Manifest::parse_checksum_file(text, ChecksumAlgorithm::Sha256).unwrap();
+        assert!(manifest.get("a\nb").is_some());
+        assert_eq!(manifest.to_checksum_file(), text);
+
+        for bad in [
+            "e3b0  cover.jpg",
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  ../etc/passwd",
+        ] {
+            assert!(matches!(
+                Manifest::parse_checksum_file(bad, ChecksumAlgorithm::Sha256),
+                Err(ManifestError::Malformed { line: 1, .. })
+            ));
+        }
+    }
+}
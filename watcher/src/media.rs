@@ -0,0 +1,342 @@
+mod flac;
+mod id3;
+mod mp4;
+mod mpeg;
+mod ogg;
+mod sniff;
+
+pub use sniff::{MediaFormat, detect_format};
+
+use crate::storage::{Storage, StorageError};
+use crate::universal_path::UniversalPath;
+use serde::Serialize;
+use std::{collections::BTreeMap, time::Duration};
+use thiserror::Error;
+
+/// Bytes asked of the storage at a time; headers and tags are served from blocks this
+/// big, so a parser reading a few bytes here and there costs one call per block
+const BLOCK: u64 = 64 * 1024;
+/// Most read whole for one header or tag; anything claiming more is taken as corrupt
+const MAX_CHUNK: u64 = 16 << 20;
+
+#[derive(Debug, Error)]
+pub enum MediaError {
+    #[error("not an audio format tags are read from")]
+    Unrecognized,
+    #[error("malformed {0:?} file: {1}")]
+    Malformed(AudioFormat, &'static str),
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioFormat {
+    Flac,
+    /// MPEG audio, layer 3 or earlier, with or without ID3 tags
+    Mp3,
+    /// AAC or ALAC in an MP4 container
+    Mp4,
+    /// Vorbis in Ogg
+    Vorbis,
+    /// Opus in Ogg
+    Opus,
+}
+
+/// Every tag as the file stores it, by its key in the file's own scheme: Vorbis field
+/// names upper-cased, ID3v2 frame IDs, MP4 atom names
+pub type Tags = BTreeMap<String, Vec<String>>;
+
+/// What `read_track_metadata` found in an audio file.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TrackMetadata {
+    pub format: AudioFormat,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub genre: Option<String>,
+    /// As tagged: a year, or a full or partial ISO 8601 date
+    pub date: Option<String>,
+    pub track_number: Option<u32>,
+    pub track_total: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub disc_total: Option<u32>,
+    pub duration: Option<Duration>,
+    /// In Hz
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u8>,
+    /// Of lossless formats
+    pub bits_per_sample: Option<u8>,
+    /// Average, in bits per second
+    pub bitrate: Option<u32>,
+    pub tags: Tags,
+}
+
+/// Read the tags and technical details of the audio file at `path`, fetching only
+/// the headers and tags through `read_range` rather than the whole file. FLAC, MP3
+/// (ID3v2, ID3v1 and Xing or VBRI headers), MP4, Ogg Vorbis and Opus are recognized by
+/// their contents, whatever the file is called.
+pub async fn read_track_metadata(
+    storage: &dyn Storage,
+    path: &UniversalPath,
+) -> Result<TrackMetadata, MediaError> {
+    let size = storage
+        .stat(path)
+        .await?
+        .size_bytes
+        .ok_or(MediaError::Unrecognized)?;
+    let mut src = Source::new(storage, path, size);
+    let mut tags = Tags::new();
+    let mut start = 0;
+    if src.read(0, 3).await? == b"ID3" {
+        start = id3::read_v2(&mut src, &mut tags).await?;
+    }
+    let head = src.read(start, 12).await?;
+    let mut meta = if head.starts_with(b"fLaC") {
+        flac::read(&mut src, start).await?
+    } else if head.starts_with(b"OggS") {
+        ogg::read(&mut src).await?
+    } else if head.get(4..8) == Some(b"ftyp") {
+        mp4::read(&mut src).await?
+    } else if start > 0 || mpeg::is_frame(&head) {
+        let mut meta = TrackMetadata::new(AudioFormat::Mp3);
+        meta.tags = tags;
+        let end = id3::read_v1(&mut src, &mut meta.tags).await?;
+        mpeg::read(&mut src, start, end, &mut meta).await?;
+        meta.apply_tags(TagScheme::Id3);
+        meta
+    } else {
+        return Err(MediaError::Unrecognized);
+    };
+    if meta.bitrate.is_none() {
+        meta.set_bitrate(size.saturating_sub(start));
+    }
+    Ok(meta)
+}
+
+/// Which keys hold the common fields
+#[derive(Debug, Clone, Copy)]
+enum TagScheme {
+    Vorbis,
+    Id3,
+    Mp4,
+}
+
+impl TrackMetadata {
+    fn new(format: AudioFormat) -> Self {
+        TrackMetadata {
+            format,
+            title: None,
+            artist: None,
+            album: None,
+            album_artist: None,
+            genre: None,
+            date: None,
+            track_number: None,
+            track_total: None,
+            disc_number: None,
+            disc_total: None,
+            duration: None,
+            sample_rate: None,
+            channels: None,
+            bits_per_sample: None,
+            bitrate: None,
+            tags: Tags::new(),
+        }
+    }
+
+    /// Fill the common fields from `tags`
+    fn apply_tags(&mut self, scheme: TagScheme) {
+        #[rustfmt::skip]
+        let keys: [&[&str]; 10] = match scheme {
+            TagScheme::Vorbis => [
+                &["TITLE"], &["ARTIST"], &["ALBUM"], &["ALBUMARTIST", "ALBUM ARTIST"],
+                &["GENRE"], &["DATE", "YEAR"], &["TRACKNUMBER"], &["TRACKTOTAL", "TOTALTRACKS"],
+                &["DISCNUMBER"], &["DISCTOTAL", "TOTALDISCS"],
+            ],
+            TagScheme::Id3 => [
+                &["TIT2"], &["TPE1"], &["TALB"], &["TPE2"], &["TCON"], &["TDRC", "TYER"],
+                &["TRCK"], &[], &["TPOS"], &[],
+            ],
+            TagScheme::Mp4 => [
+                &["©nam"], &["©ART"], &["©alb"], &["aART"], &["©gen", "gnre"], &["©day"],
+                &["trkn"], &[], &["disk"], &[],
+            ],
+        };
+        let first = |keys: &[&str]| {
+            keys.iter()
+                .find_map(|k| self.tags.get(*k)?.first())
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+        let [
+            title,
+            artist,
+            album,
+            album_artist,
+            genre,
+            date,
+            track,
+            tracks,
+            disc,
+            discs,
+        ] = keys.map(first);
+        let (track_number, track_total) = number_pair(track.as_deref());
+        let (disc_number, disc_total) = number_pair(disc.as_deref());
+        let number = |n: Option<String>| n.and_then(|n| n.parse().ok());
+        self.title = title;
+        self.artist = artist;
+        self.album = album;
+        self.album_artist = album_artist;
+        self.genre = genre;
+        self.date = date;
+        self.track_number = track_number;
+        self.track_total = number(tracks).or(track_total);
+        self.disc_number = disc_number;
+        self.disc_total = number(discs).or(disc_total);
+    }
+
+    /// The average bitrate of `audio_bytes` over the duration, if it is known
+    fn set_bitrate(&mut self, audio_bytes: u64) {
+        let Some(secs) = self.duration.map(|d| d.as_secs_f64()).filter(|&s| s > 0.0) else {
+            return;
+        };
+        self.bitrate = Some((audio_bytes as f64 * 8.0 / secs).round() as u32);
+    }
+}
+
+/// `3` or `3/12`, as track and disc numbers are tagged
+fn number_pair(value: Option<&str>) -> (Option<u32>, Option<u32>) {
+    let Some(value) = value else {
+        return (None, None);
+    };
+    let (number, total) = match value.split_once('/') {
+        Some((number, total)) => (number, Some(total)),
+        None => (value, None),
+    };
+    let parse = |s: &str| s.trim().parse().ok().filter(|&n| n > 0);
+    (parse(number), total.and_then(parse))
+}
+
+/// Vorbis comments, as FLAC, Vorbis and Opus store them: a vendor string, then
+/// `KEY=value` fields, every length little-endian. None if cut short.
+fn vorbis_comments(data: &[u8], tags: &mut Tags) -> Option<()> {
+    let mut at = 0;
+    let vendor = le_u32(data, &mut at)?;
+    at = at.checked_add(vendor)?;
+    let count = le_u32(data, &mut at)?;
+    for _ in 0..count {
+        let len = le_u32(data, &mut at)?;
+        let field = data.get(at..at.checked_add(len)?)?;
+        at += len;
+        let field = String::from_utf8_lossy(field);
+        if let Some((key, value)) = field.split_once('=') {
+            let key = key.to_ascii_uppercase();
+            tags.entry(key).or_default().push(value.to_string());
+        }
+    }
+    Some(())
+}
+
+/// The little-endian u32 at `at`, moving past it
+fn le_u32(data: &[u8], at: &mut usize) -> Option<usize> {
+    let bytes = data.get(*at..*at + 4)?.try_into().ok()?;
+    *at += 4;
+    Some(u32::from_le_bytes(bytes) as usize)
+}
+
+/// Reads a file by ranges for the parsers, a block at a time.
+struct Source<'a> {
+    storage: &'a dyn Storage,
+    path: &'a UniversalPath,
+    size: u64,
+    block_start: u64,
+    block: Vec<u8>,
+}
+
+impl<'a> Source<'a> {
+    fn new(storage: &'a dyn Storage, path: &'a UniversalPath, size: u64) -> Self {
+        Source {
+            storage,
+            path,
+            size,
+            block_start: 0,
+            block: Vec::new(),
+        }
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Up to `len` bytes at `offset`; fewer at the end of the file, none past it
+    async fn read(&mut self, offset: u64, len: u64) -> Result<Vec<u8>, StorageError> {
+        let end = offset.saturating_add(len).min(self.size);
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+        let block_end = self.block_start + self.block.len() as u64;
+        if offset >= self.block_start && end <= block_end {
+            let at = (offset - self.block_start) as usize;
+            return Ok(self.block[at..at + (end - offset) as usize].to_vec());
+        }
+        if end - offset > BLOCK {
+            return self.storage.read_range(self.path, offset..end).await;
+        }
+        let fetch = offset..(offset + BLOCK).min(self.size);
+        self.block = self.storage.read_range(self.path, fetch).await?;
+        self.block_start = offset;
+        let len = ((end - offset) as usize).min(self.block.len());
+        Ok(self.block[..len].to_vec())
+    }
+
+    /// Exactly `len` bytes at `offset`, or `Malformed` for `format` if the file ends
+    /// first or `len` is beyond reason
+    async fn exact(
+        &mut self,
+        offset: u64,
+        len: u64,
+        format: AudioFormat,
+    ) -> Result<Vec<u8>, MediaError> {
+        if len > MAX_CHUNK {
+            return Err(MediaError::Malformed(
+                format,
+                "a header claims too much data",
+            ));
+        }
+        let bytes = self.read(offset, len).await?;
+        if (bytes.len() as u64) < len {
+            return Err(MediaError::Malformed(format, "the file is cut short"));
+        }
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    /// Vorbis comments holding `fields`, for the parsers' tests
+    pub(crate) fn comments(fields: &[&str]) -> Vec<u8> {
+        fn put(data: &mut Vec<u8>, bytes: &[u8]) {
+            data.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            data.extend_from_slice(bytes);
+        }
+        let mut data = Vec::new();
+        put(&mut data, b"otolith");
+        data.extend_from_slice(&(fields.len() as u32).to_le_bytes());
+        for field in fields {
+            put(&mut data, field.as_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_number_pair() {
+        assert_eq!(number_pair(Some("3/12")), (Some(3), Some(12)));
+        assert_eq!(number_pair(Some(" 7 ")), (Some(7), None));
+        assert_eq!(number_pair(Some("0/0")), (None, None));
+        assert_eq!(number_pair(None), (None, None));
+    }
+}
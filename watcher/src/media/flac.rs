@@ -0,0 +1,104 @@
+use super::{AudioFormat, MediaError, Source, TagScheme, TrackMetadata, vorbis_comments};
+use std::time::Duration;
+
+const STREAMINFO: u8 = 0;
+const VORBIS_COMMENT: u8 = 4;
+const STREAMINFO_LEN: u64 = 34;
+
+/// The metadata blocks of the FLAC stream whose `fLaC` marker is at `start`. Only
+/// block headers are read past those wanted, so embedded pictures cost nothing.
+pub(super) async fn read(src: &mut Source<'_>, start: u64) -> Result<TrackMetadata, MediaError> {
+    let mut meta = TrackMetadata::new(AudioFormat::Flac);
+    let mut at = start + 4;
+    let mut samples = None;
+    loop {
+        let header = src.exact(at, 4, AudioFormat::Flac).await?;
+        let (last, kind) = (header[0] & 0x80 != 0, header[0] & 0x7f);
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as u64;
+        at += 4;
+        match kind {
+            STREAMINFO if len >= STREAMINFO_LEN => {
+                let info = src.exact(at, STREAMINFO_LEN, AudioFormat::Flac).await?;
+                // 20 bits of sample rate, 3 of channels and 5 of bits per sample, less
+                // one each, then 36 of total samples
+                let packed = u64::from_be_bytes(info[10..18].try_into().expect("8 bytes"));
+                let rate = (packed >> 44) as u32;
+                meta.sample_rate = Some(rate).filter(|&r| r > 0);
+                meta.channels = Some(((packed >> 41) & 0x7) as u8 + 1);
+                meta.bits_per_sample = Some(((packed >> 36) & 0x1f) as u8 + 1);
+                samples = Some(packed & 0xf_ffff_ffff).filter(|&n| n > 0);
+            }
+            VORBIS_COMMENT => {
+                let data = src.exact(at, len, AudioFormat::Flac).await?;
+                vorbis_comments(&data, &mut meta.tags).ok_or(MediaError::Malformed(
+                    AudioFormat::Flac,
+                    "bad Vorbis comments",
+                ))?;
+            }
+            _ => {}
+        }
+        at += len;
+        if last {
+            break;
+        }
+    }
+    if let (Some(samples), Some(rate)) = (samples, meta.sample_rate) {
+        meta.duration = Some(Duration::from_secs_f64(samples as f64 / rate as f64));
+    }
+    meta.set_bitrate(src.size().saturating_sub(at));
+    meta.apply_tags(TagScheme::Vorbis);
+    Ok(meta)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::media::{AudioFormat, read_track_metadata, tests::comments};
+    use crate::storage::{MemoryStorage, Storage};
+    use crate::universal_path::UniversalPath;
+    use std::time::Duration;
+
+    fn block(kind: u8, last: bool, body: &[u8]) -> Vec<u8> {
+        let len = (body.len() as u32).to_be_bytes();
+        let mut block = vec![kind | if last { 0x80 } else { 0 }, len[1], len[2], len[3]];
+        block.extend_from_slice(body);
+        block
+    }
+
+    #[tokio::test]
+    async fn test_read_flac() {
+        // 44.1 kHz, stereo, 16 bits, 3 minutes
+        let samples: u64 = 44_100 * 180;
+        let packed: u64 = (44_100 << 44) | (1 << 41) | (15 << 36) | samples;
+        let mut info = vec![0; 10];
+        info.extend_from_slice(&packed.to_be_bytes());
+        info.extend_from_slice(&[0; 16]);
+        let mut file = b"fLaC".to_vec();
+        file.extend(block(0, false, &info));
+        // A picture block, skipped unread
+        file.extend(block(6, false, &[0xAB; 100_000]));
+        let tags = comments(&[
+            "TITLE=Jóga",
+            "artist=Björk",
+            "TRACKNUMBER=3/11",
+            "DATE=1997",
+        ]);
+        file.extend(block(4, true, &tags));
+        file.extend_from_slice(&[0; 4096]);
+
+        let storage = MemoryStorage::new();
+        let path = UniversalPath::from_uri_str("mem://lib/03.flac").unwrap();
+        storage.write(&path, &file).await.unwrap();
+        let meta = read_track_metadata(&storage, &path).await.unwrap();
+        assert_eq!(meta.format, AudioFormat::Flac);
+        assert_eq!(meta.title.as_deref(), Some("Jóga"));
+        assert_eq!(meta.artist.as_deref(), Some("Björk"));
+        assert_eq!((meta.track_number, meta.track_total), (Some(3), Some(11)));
+        assert_eq!(meta.date.as_deref(), Some("1997"));
+        assert_eq!(meta.duration, Some(Duration::from_secs(180)));
+        assert_eq!(
+            (meta.sample_rate, meta.channels, meta.bits_per_sample),
+            (Some(44_100), Some(2), Some(16))
+        );
+        assert_eq!(meta.bitrate, Some(4096 * 8 / 180));
+    }
+}
@@ -0,0 +1,365 @@
+use super::{AudioFormat, MediaError, Source, Tags};
+
+const HEADER_LEN: u64 = 10;
+const V1_LEN: u64 = 128;
+
+/// The ID3v2 tag at the start of the file, into `tags` by v2.3 and v2.4 frame ID.
+/// Returns where the audio after it starts.
+pub(super) async fn read_v2(src: &mut Source<'_>, tags: &mut Tags) -> Result<u64, MediaError> {
+    let header = src.exact(0, HEADER_LEN, AudioFormat::Mp3).await?;
+    let (version, flags) = (header[3], header[5]);
+    let size = syncsafe(&header[6..10]) as u64;
+    let end = v2_len(&header).ok_or(MALFORMED)?;
+    if end > src.size() {
+        return Err(MediaError::Malformed(
+            AudioFormat::Mp3,
+            "the ID3v2 tag runs past the end of the file",
+        ));
+    }
+    if !(2..=4).contains(&version) {
+        // A version from the future: skip it whole rather than guess at its frames
+        return Ok(end);
+    }
+    let mut tag = src.exact(HEADER_LEN, size, AudioFormat::Mp3).await?;
+    if flags & 0x80 != 0 && version < 4 {
+        tag = unsynchronize(&tag);
+    }
+    let mut at = 0;
+    if flags & 0x40 != 0 && version > 2 {
+        let len = tag.get(..4).ok_or(MALFORMED)?;
+        at = match version {
+            3 => u32::from_be_bytes(len.try_into().expect("4 bytes")) as usize + 4,
+            _ => syncsafe(len) as usize,
+        };
+    }
+    while let Some(frame) = next_frame(&tag, &mut at, version) {
+        let Some(value) = frame.text() else {
+            continue;
+        };
+        tags.entry(frame.key()).or_default().extend(value);
+    }
+    Ok(end)
+}
+
+/// The ID3v1 tag in the last 128 bytes, if there is one, for the frames the ID3v2 tag
+/// left unset. Returns where the audio before it ends.
+pub(super) async fn read_v1(src: &mut Source<'_>, tags: &mut Tags) -> Result<u64, MediaError> {
+    let Some(offset) = src.size().checked_sub(V1_LEN) else {
+        return Ok(src.size());
+    };
+    let tag = src.read(offset, V1_LEN).await?;
+    if !tag.starts_with(b"TAG") {
+        return Ok(src.size());
+    }
+    let field = |range: std::ops::Range<usize>| {
+        let bytes = &tag[range];
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        Some(latin1(&bytes[..end]).trim().to_string()).filter(|s| !s.is_empty())
+    };
+    // v1.1 puts the track number in the comment's last byte, after a NUL
+    let track = (tag[125] == 0 && tag[126] != 0).then(|| tag[126].to_string());
+    let genre = GENRES.get(tag[127] as usize).map(|g| g.to_string());
+    let fields = [
+        ("TIT2", field(3..33)),
+        ("TPE1", field(33..63)),
+        ("TALB", field(63..93)),
+        ("TYER", field(93..97)),
+        ("TRCK", track),
+        ("TCON", genre),
+    ];
+    for (key, value) in fields {
+        if let Some(value) = value {
+            tags.entry(key.to_string()).or_insert_with(|| vec![value]);
+        }
+    }
+    Ok(offset)
+}
+
+const MALFORMED: MediaError = MediaError::Malformed(AudioFormat::Mp3, "bad ID3v2 tag");
+
+struct Frame {
+    id: String,
+    data: Vec<u8>,
+}
+
+/// The frame at `at`, moving past it; None at the padding or the end of the tag
+fn next_frame(tag: &[u8], at: &mut usize, version: u8) -> Option<Frame> {
+    loop {
+        let (id_len, header_len) = if version == 2 { (3, 6) } else { (4, 10) };
+        let header = tag.get(*at..*at + header_len)?;
+        let id = &header[..id_len];
+        if !id
+            .iter()
+            .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+        {
+            return None;
+        }
+        let size = match version {
+            2 => u32::from_be_bytes([0, header[3], header[4], header[5]]) as usize,
+            3 => u32::from_be_bytes(header[4..8].try_into().expect("4 bytes")) as usize,
+            _ => syncsafe(&header[4..8]) as usize,
+        };
+        let flags = if version == 2 { 0 } else { header[9] };
+        let start = *at + header_len;
+        let mut data = tag.get(start..start.checked_add(size)?)?;
+        *at = start + size;
+
+        let id = std::str::from_utf8(id).expect("ASCII");
+        let id = match version {
+            2 => match v2_2_id(id) {
+                Some(id) => id.to_string(),
+                None => continue,
+            },
+            _ => id.to_string(),
+        };
+        let (compressed, encrypted, extra) = match version {
+            2 => (false, false, 0),
+            // A grouping byte, then a decompressed size if compressed
+            3 => (
+                flags & 0x80 != 0,
+                flags & 0x40 != 0,
+                (flags & 0x20 != 0) as usize,
+            ),
+            // A grouping byte, then a data length if flagged
+            _ => (
+                flags & 0x08 != 0,
+                flags & 0x04 != 0,
+                (flags & 0x40 != 0) as usize + 4 * (flags & 0x01 != 0) as usize,
+            ),
+        };
+        if compressed || encrypted {
+            continue;
+        }
+        data = data.get(extra..)?;
+        let data = match version == 4 && flags & 0x02 != 0 {
+            true => unsynchronize(data),
+            false => data.to_vec(),
+        };
+        return Some(Frame { id, data });
+    }
+}
+
+impl Frame {
+    /// The key it is filed under: the frame ID, with the description of user-defined
+    /// text after a colon
+    fn key(&self) -> String {
+        match self.id.as_str() {
+            "TXXX" => {
+                let description = self.strings().next().unwrap_or_default();
+                format!("TXXX:{description}")
+            }
+            _ => self.id.clone(),
+        }
+    }
+
+    /// The values of a text frame, or the text of a comment; None for any other frame
+    fn text(&self) -> Option<Vec<String>> {
+        let values: Vec<String> = match self.id.as_str() {
+            "TXXX" => self.strings().skip(1).collect(),
+            "COMM" => {
+                // A language code and a description before the text
+                let (&encoding, rest) = self.data.split_first()?;
+                let rest = rest.get(3..)?;
+                decode(encoding, rest).into_iter().skip(1).take(1).collect()
+            }
+            "TCON" => self.strings().map(|g| genre(&g)).collect(),
+            id if id.starts_with('T') => self.strings().collect(),
+            _ => return None,
+        };
+        let values: Vec<String> = values.into_iter().filter(|v| !v.is_empty()).collect();
+        (!values.is_empty()).then_some(values)
+    }
+
+    fn strings(&self) -> impl Iterator<Item = String> {
+        let decoded = match self.data.split_first() {
+            Some((&encoding, rest)) => decode(encoding, rest),
+            None => Vec::new(),
+        };
+        decoded.into_iter()
+    }
+}
+
+/// Text in an ID3v2 encoding, split at the NULs between values
+fn decode(encoding: u8, bytes: &[u8]) -> Vec<String> {
+    let text = match encoding {
+        0 => latin1(bytes),
+        1 | 2 => {
+            // Big-endian unless a byte order mark says otherwise; in encoding 1 every
+            // value has its own
+            let mut swap = false;
+            let units: Vec<u16> = bytes
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .filter_map(|unit| match unit {
+                    0xFEFF | 0xFFFE => {
+                        swap = unit == 0xFFFE;
+                        None
+                    }
+                    _ if swap => Some(unit.swap_bytes()),
+                    _ => Some(unit),
+                })
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::from_utf8_lossy(bytes).into_owned(),
+    };
+    let text = text.trim_end_matches('\0');
+    text.split('\0').map(|s| s.to_string()).collect()
+}
+
+fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+/// The length of the ID3v2 tag `head` starts with, header and footer included, or
+/// `None` if it does not start with one
+pub(super) fn v2_len(head: &[u8]) -> Option<u64> {
+    let header = head
+        .get(..HEADER_LEN as usize)
+        .filter(|h| h.starts_with(b"ID3"))?;
+    let footer = if header[5] & 0x10 != 0 { HEADER_LEN } else { 0 };
+    Some(HEADER_LEN + syncsafe(&header[6..10]) as u64 + footer)
+}
+
+/// A 28-bit size stored 7 bits to a byte
+fn syncsafe(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |n, &b| (n << 7) | (b & 0x7f) as u32)
+}
+
+/// `data` with the zero byte put after every `0xFF` to keep it from looking like an
+/// MPEG sync taken out again
+fn unsynchronize(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut after_ff = false;
+    for &b in data {
+        if !(after_ff && b == 0) {
+            out.push(b);
+        }
+        after_ff = b == 0xFF;
+    }
+    out
+}
+
+/// The v2.3 ID of a v2.2 frame kept
+fn v2_2_id(id: &str) -> Option<&'static str> {
+    Some(match id {
+        "TT2" => "TIT2",
+        "TP1" => "TPE1",
+        "TP2" => "TPE2",
+        "TAL" => "TALB",
+        "TCO" => "TCON",
+        "TYE" => "TYER",
+        "TRK" => "TRCK",
+        "TPA" => "TPOS",
+        "COM" => "COMM",
+        "TXX" => "TXXX",
+        _ => return None,
+    })
+}
+
+/// A genre as tagged, with ID3v1 numbers such as `(17)` or `17` resolved to names
+fn genre(value: &str) -> String {
+    let number = value
+        .strip_prefix('(')
+        .and_then(|v| v.split_once(')'))
+        .map_or(value, |(n, _)| n);
+    match number.parse::<usize>().ok().and_then(|n| GENRES.get(n)) {
+        Some(name) => name.to_string(),
+        None => value.to_string(),
+    }
+}
+
+#[rustfmt::skip]
+pub(super) const GENRES: [&str; 80] = [
+    "Blues", "Classic Rock", "Country", "Dance", "Disco", "Funk", "Grunge", "Hip-Hop",
+    "Jazz", "Metal", "New Age", "Oldies", "Other", "Pop", "R&B", "Rap", "Reggae", "Rock",
+    "Techno", "Industrial", "Alternative", "Ska", "Death Metal", "Pranks", "Soundtrack",
+    "Euro-Techno", "Ambient", "Trip-Hop", "Vocal", "Jazz+Funk", "Fusion", "Trance",
+    "Classical", "Instrumental", "Acid", "House", "Game", "Sound Clip", "Gospel", "Noise",
+    "AlternRock", "Bass", "Soul", "Punk", "Space", "Meditative", "Instrumental Pop",
+    "Instrumental Rock", "Ethnic", "Gothic", "Darkwave", "Techno-Industrial", "Electronic",
+    "Pop-Folk", "Eurodance", "Dream", "Southern Rock", "Comedy", "Cult", "Gangsta",
+    "Top 40", "Christian Rap", "Pop/Funk", "Jungle", "Native American", "Cabaret",
+    "New Wave", "Psychadelic", "Rave", "Showtunes", "Trailer", "Lo-Fi", "Tribal",
+    "Acid Punk", "Acid Jazz", "Polka", "Retro", "Musical", "Rock & Roll", "Hard Rock",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::{AudioFormat, read_track_metadata};
+    use crate::storage::{MemoryStorage, Storage};
+    use crate::universal_path::UniversalPath;
+
+    fn frame(id: &str, data: &[u8]) -> Vec<u8> {
+        let mut frame = id.as_bytes().to_vec();
+        frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    #[tokio::test]
+    async fn test_read_id3() {
+        let mut frames = frame("TIT2", b"\x03Hyperballad");
+        // UTF-16 with a little-endian byte order mark
+        let mut artist = vec![1, 0xFF, 0xFE];
+        artist.extend("Björk".encode_utf16().flat_map(u16::to_le_bytes));
+        frames.extend(frame("TPE1", &artist));
+        frames.extend(frame("TCON", b"\x00(17)"));
+        frames.extend(frame("TXXX", b"\x00MusicBrainz Album Id\x00b1a1"));
+        frames.extend(frame("COMM", b"\x00engdesc\x00Ripped 2003"));
+        frames.extend(frame("APIC", &[0; 300]));
+        frames.extend([0; 64]);
+        let mut tag = b"ID3\x03\x00\x00".to_vec();
+        let size = frames.len() as u32;
+        tag.extend((0..4).rev().map(|i| ((size >> (7 * i)) & 0x7f) as u8));
+        tag.extend(frames);
+        let audio_start = tag.len() as u64;
+        // Not a valid MPEG stream, so only the tags are found
+        tag.extend([0; 1000]);
+        let mut v1 = b"TAG".to_vec();
+        v1.extend(b"Ignored title".iter().chain(&[0; 17]));
+        v1.extend([0; 60]);
+        v1.extend(b"1996");
+        v1.extend([0; 28]);
+        v1.extend([0, 4, 13]);
+        tag.extend(v1);
+
+        let storage = MemoryStorage::new();
+        let path = UniversalPath::from_uri_str("mem://lib/04.mp3").unwrap();
+        storage.write(&path, &tag).await.unwrap();
+        let meta = read_track_metadata(&storage, &path).await.unwrap();
+        assert_eq!(meta.format, AudioFormat::Mp3);
+        assert_eq!(meta.title.as_deref(), Some("Hyperballad"));
+        assert_eq!(meta.artist.as_deref(), Some("Björk"));
+        assert_eq!(meta.genre.as_deref(), Some("Rock"));
+        assert_eq!(meta.date.as_deref(), Some("1996"));
+        assert_eq!(meta.track_number, Some(4));
+        assert_eq!(meta.tags["TXXX:MusicBrainz Album Id"], ["b1a1"]);
+        assert_eq!(meta.tags["COMM"], ["Ripped 2003"]);
+        assert!(!meta.tags.contains_key("APIC"));
+
+        let mut src = Source::new(&storage, &path, tag.len() as u64);
+        assert_eq!(
+            read_v2(&mut src, &mut Tags::new()).await.unwrap(),
+            audio_start
+        );
+        assert_eq!(
+            unsynchronize(&[0xFF, 0, 0xE0, 0, 0xFF]),
+            [0xFF, 0xE0, 0, 0xFF]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tag_longer_than_file() {
+        // A tag of a version not read, claiming far more than the file holds
+        let mut file = b"ID3\x05\x00\x00\x00\x06\x00\x00".to_vec();
+        file.extend([0; 10]);
+        let storage = MemoryStorage::new();
+        let path = UniversalPath::from_uri_str("mem://lib/short.mp3").unwrap();
+        storage.write(&path, &file).await.unwrap();
+        let error = read_track_metadata(&storage, &path).await.unwrap_err();
+        assert!(matches!(error, MediaError::Malformed(AudioFormat::Mp3, _)));
+    }
+}
@@ -0,0 +1,414 @@
+use super::{AudioFormat, MediaError, Source, TagScheme, TrackMetadata, id3::GENRES};
+use std::time::Duration;
+
+const MALFORMED: MediaError = MediaError::Malformed(AudioFormat::Mp4, "bad atom");
+
+/// An atom whose body runs from `start` to `end`
+#[derive(Debug, Clone, Copy)]
+struct Atom {
+    kind: [u8; 4],
+    start: u64,
+    end: u64,
+}
+
+/// The atoms of an MP4 file, walked by their headers so that only the small ones
+/// wanted are read: the movie and track headers, the sample description and the
+/// iTunes-style tags. Cover art and the media data are never fetched.
+pub(super) async fn read(src: &mut Source<'_>) -> Result<TrackMetadata, MediaError> {
+    let mut meta = TrackMetadata::new(AudioFormat::Mp4);
+    let top = atoms(src, 0, src.size()).await?;
+    let moov =
+        find(&top, b"moov").ok_or(MediaError::Malformed(AudioFormat::Mp4, "no moov atom"))?;
+    let moov = atoms(src, moov.start, moov.end).await?;
+
+    let mut duration = match find(&moov, b"mvhd") {
+        Some(mvhd) => header_duration(src, mvhd).await?,
+        None => None,
+    };
+    let mut bitrate = None;
+    for trak in moov.iter().filter(|a| &a.kind == b"trak") {
+        let Some(mdia) = child(src, trak, b"mdia").await? else {
+            continue;
+        };
+        let mdia_children = atoms(src, mdia.start, mdia.end).await?;
+        let Some(hdlr) = find(&mdia_children, b"hdlr") else {
+            continue;
+        };
+        if src.exact(hdlr.start + 8, 4, AudioFormat::Mp4).await? != b"soun" {
+            continue;
+        }
+        if let Some(mdhd) = find(&mdia_children, b"mdhd") {
+            duration = header_duration(src, mdhd).await?.or(duration);
+        }
+        let mut stsd = find(&mdia_children, b"minf").copied();
+        for kind in [b"stbl", b"stsd"] {
+            stsd = match stsd {
+                Some(parent) => child(src, &parent, kind).await?,
+                None => None,
+            };
+        }
+        if let Some(stsd) = stsd {
+            bitrate = sample_description(src, &stsd, &mut meta).await?;
+        }
+        break;
+    }
+    meta.duration = duration;
+
+    // The tags are in moov/udta/meta, or sometimes moov/meta
+    let mut meta_atom = find(&moov, b"meta").copied();
+    if let Some(udta) = find(&moov, b"udta") {
+        meta_atom = child(src, udta, b"meta").await?.or(meta_atom);
+    }
+    if let Some(meta_atom) = meta_atom {
+        // A full atom, with version and flags before its children
+        let children = atoms(src, meta_atom.start + 4, meta_atom.end).await?;
+        if let Some(ilst) = find(&children, b"ilst") {
+            item_list(src, ilst, &mut meta).await?;
+        }
+    }
+
+    meta.bitrate = bitrate.filter(|&b| b > 0);
+    if meta.bitrate.is_none() {
+        let mdat = top.iter().filter(|a| &a.kind == b"mdat");
+        meta.set_bitrate(mdat.map(|a| a.end - a.start).sum());
+    }
+    meta.apply_tags(TagScheme::Mp4);
+    Ok(meta)
+}
+
+/// The atoms between `start` and `end`, by their headers alone
+async fn atoms(src: &mut Source<'_>, start: u64, end: u64) -> Result<Vec<Atom>, MediaError> {
+    let mut found = Vec::new();
+    let mut at = start;
+    while at + 8 <= end {
+        let header = src.exact(at, 8, AudioFormat::Mp4).await?;
+        let kind = header[4..8].try_into().expect("4 bytes");
+        let (size, header_len) = match be_u32(&header, 0).expect("8 bytes") {
+            // 64-bit size follows
+            1 => {
+                let large = src.exact(at + 8, 8, AudioFormat::Mp4).await?;
+                (
+                    u64::from_be_bytes(large[..].try_into().expect("8 bytes")),
+                    16,
+                )
+            }
+            // Runs to the end
+            0 => (end - at, 8),
+            size => (size as u64, 8),
+        };
+        // Too small for its own header, or running past its parent
+        let atom_end = at.checked_add(size).ok_or(MALFORMED)?;
+        if size < header_len || atom_end > end {
+            return Err(MALFORMED);
+        }
+        found.push(Atom {
+            kind,
+            start: at + header_len,
+            end: atom_end,
+        });
+        at = atom_end;
+    }
+    Ok(found)
+}
+
+fn find<'a>(atoms: &'a [Atom], kind: &[u8; 4]) -> Option<&'a Atom> {
+    atoms.iter().find(|a| &a.kind == kind)
+}
+
+async fn child(
+    src: &mut Source<'_>,
+    parent: &Atom,
+    kind: &[u8; 4],
+) -> Result<Option<Atom>, MediaError> {
+    Ok(find(&atoms(src, parent.start, parent.end).await?, kind).copied())
+}
+
+/// The duration of an `mvhd` or `mdhd`, which lay out their time scale and duration
+/// alike
+async fn header_duration(
+    src: &mut Source<'_>,
+    atom: &Atom,
+) -> Result<Option<Duration>, MediaError> {
+    let body = src.read(atom.start, 32).await?;
+    let (scale, duration) = match body.first() {
+        Some(1) => (be_u32(&body, 20), be_u64(&body, 24)),
+        _ => (be_u32(&body, 12), be_u32(&body, 16).map(u64::from)),
+    };
+    let (Some(scale), Some(duration)) = (scale.filter(|&s| s > 0), duration) else {
+        return Ok(None);
+    };
+    // Left unknown if too long to be a real one
+    Ok(Duration::try_from_secs_f64(duration as f64 / scale as f64).ok())
+}
+
+/// The channels, sample size and rate of an audio track's first sample entry, and the
+/// average bitrate its `esds` gives
+async fn sample_description(
+    src: &mut Source<'_>,
+    stsd: &Atom,
+    meta: &mut TrackMetadata,
+) -> Result<Option<u32>, MediaError> {
+    let body = src
+        .exact(stsd.start, stsd.end - stsd.start, AudioFormat::Mp4)
+        .await?;
+    // Version and flags, an entry count, then the entry's size and format
+    let entry = body.get(8..).ok_or(MALFORMED)?;
+    let format = entry.get(4..8).ok_or(MALFORMED)?;
+    meta.channels = be_u16(entry, 24).map(|c| c as u8);
+    if format == b"alac" {
+        meta.bits_per_sample = be_u16(entry, 26).map(|b| b as u8);
+    }
+    // 16.16 fixed point
+    meta.sample_rate = be_u32(entry, 32).map(|r| r >> 16).filter(|&r| r > 0);
+    let entry_len = (be_u32(entry, 0).ok_or(MALFORMED)? as usize).min(entry.len());
+    let Some(esds) = entry[..entry_len]
+        .windows(4)
+        .position(|w| w == b"esds")
+        .map(|at| &entry[at + 4..entry_len])
+    else {
+        return Ok(None);
+    };
+    Ok(esds_bitrate(esds))
+}
+
+/// The average bitrate in an `esds` body's decoder configuration
+fn esds_bitrate(esds: &[u8]) -> Option<u32> {
+    // Descriptor lengths take up to four bytes, seven bits each
+    fn descriptor(data: &[u8], at: &mut usize, tag: u8) -> Option<()> {
+        if *data.get(*at)? != tag {
+            return None;
+        }
+        *at += 1;
+        for _ in 0..4 {
+            let b = *data.get(*at)?;
+            *at += 1;
+            if b & 0x80 == 0 {
+                break;
+            }
+        }
+        Some(())
+    }
+    // Version and flags
+    let mut at = 4;
+    descriptor(esds, &mut at, 0x03)?;
+    let flags = *esds.get(at + 2)?;
+    at += 3;
+    if flags & 0x80 != 0 {
+        at += 2;
+    }
+    if flags & 0x40 != 0 {
+        at += 1 + *esds.get(at)? as usize;
+    }
+    if flags & 0x20 != 0 {
+        at += 2;
+    }
+    descriptor(esds, &mut at, 0x04)?;
+    // Object and stream type, buffer size and maximum bitrate first
+    be_u32(esds, at + 9)
+}
+
+/// The `ilst` items into `meta.tags` by atom name, `----` ones as `----:name`
+async fn item_list(
+    src: &mut Source<'_>,
+    ilst: &Atom,
+    meta: &mut TrackMetadata,
+) -> Result<(), MediaError> {
+    for item in atoms(src, ilst.start, ilst.end).await? {
+        if &item.kind == b"covr" {
+            continue;
+        }
+        let body = src
+            .exact(item.start, item.end - item.start, AudioFormat::Mp4)
+            .await?;
+        let mut key: String = item.kind.iter().map(|&b| b as char).collect();
+        let mut values = Vec::new();
+        let mut at = 0;
+        while let Some(len) = be_u32(&body, at).map(|l| l as usize) {
+            let child = body.get(at..(at + len).min(body.len()));
+            let Some(child) = child.filter(|c| c.len() >= 8) else {
+                break;
+            };
+            at += len.max(8);
+            match &child[4..8] {
+                b"name" if child.len() > 12 => {
+                    key = format!("----:{}", String::from_utf8_lossy(&child[12..]));
+                }
+                b"data" if child.len() >= 16 => {
+                    let kind = be_u32(child, 8).unwrap_or(0) & 0xFF_FFFF;
+                    values.extend(item_value(&item.kind, kind, &child[16..]));
+                }
+                _ => {}
+            }
+        }
+        if !values.is_empty() {
+            meta.tags.entry(key).or_default().extend(values);
+        }
+    }
+    Ok(())
+}
+
+/// The value of a `data` atom of well-known type `kind`, as a string
+fn item_value(item: &[u8; 4], kind: u32, data: &[u8]) -> Option<String> {
+    match (item, kind) {
+        // Number and total, after two padding bytes
+        (b"trkn" | b"disk", _) => {
+            let (number, total) = (be_u16(data, 2)?, be_u16(data, 4).unwrap_or(0));
+            Some(match total {
+                0 => number.to_string(),
+                _ => format!("{number}/{total}"),
+            })
+        }
+        (b"gnre", _) => {
+            let genre = be_u16(data, 0)?.checked_sub(1)?;
+            GENRES.get(genre as usize).map(|g| g.to_string())
+        }
+        // UTF-8, or an implicit type some taggers write text as
+        (_, 1 | 0) => Some(String::from_utf8_lossy(data).into_owned()),
+        // Big-endian signed integer
+        (_, 21) => {
+            let mut n: i64 = if data.first()? & 0x80 != 0 { -1 } else { 0 };
+            for &b in data.iter().take(8) {
+                n = (n << 8) | b as i64;
+            }
+            Some(n.to_string())
+        }
+        _ => None,
+    }
+}
+
+fn be_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn be_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn be_u64(data: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::media::{AudioFormat, MediaError, read_track_metadata};
+    use crate::storage::{MemoryStorage, Storage};
+    use crate::universal_path::UniversalPath;
+    use std::time::Duration;
+
+    fn atom(kind: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut atom = (body.len() as u32 + 8).to_be_bytes().to_vec();
+        atom.extend_from_slice(kind);
+        atom.extend_from_slice(body);
+        atom
+    }
+
+    fn item(kind: &[u8], data_type: u32, value: &[u8]) -> Vec<u8> {
+        let mut data = data_type.to_be_bytes().to_vec();
+        data.extend([0; 4]);
+        data.extend_from_slice(value);
+        atom(kind, &atom(b"data", &data))
+    }
+
+    #[tokio::test]
+    async fn test_read_mp4() {
+        // A 44.1 kHz time scale and four minutes
+        let mut mdhd = vec![0; 12];
+        mdhd.extend(44_100u32.to_be_bytes());
+        mdhd.extend((44_100u32 * 240).to_be_bytes());
+        mdhd.extend([0; 4]);
+        let mut hdlr = vec![0; 8];
+        hdlr.extend(b"soun");
+        hdlr.extend([0; 12]);
+        let mut esds = vec![0; 4];
+        esds.extend([0x03, 0x80, 0x80, 0x80, 25, 0, 1, 0]);
+        esds.extend([0x04, 17, 0x40, 0x15, 0, 0, 0]);
+        esds.extend(320_000u32.to_be_bytes());
+        esds.extend(256_000u32.to_be_bytes());
+        let mut mp4a = vec![0; 16];
+        mp4a.extend(2u16.to_be_bytes());
+        mp4a.extend(16u16.to_be_bytes());
+        mp4a.extend([0; 4]);
+        mp4a.extend((44_100u32 << 16).to_be_bytes());
+        mp4a.extend(atom(b"esds", &esds));
+        let mut stsd = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        stsd.extend(atom(b"mp4a", &mp4a));
+        let stbl = atom(b"stbl", &atom(b"stsd", &stsd));
+        let minf = atom(b"minf", &stbl);
+        let mdia = [atom(b"mdhd", &mdhd), atom(b"hdlr", &hdlr), minf].concat();
+        let trak = atom(b"trak", &atom(b"mdia", &mdia));
+
+        let ilst = [
+            // `©` is 0xA9 in atom names
+            item(b"\xA9nam", 1, "Jóga".as_bytes()),
+            item(b"\xA9ART", 1, "Björk".as_bytes()),
+            item(b"trkn", 0, &[0, 0, 0, 3, 0, 10, 0, 0]),
+            item(b"gnre", 0, &[0, 28]),
+            item(b"tmpo", 21, &[0, 96]),
+            item(b"covr", 13, &[0xAB; 4000]),
+        ]
+        .concat();
+        let mut meta = vec![0; 4];
+        meta.extend(atom(b"ilst", &ilst));
+        let udta = atom(b"udta", &atom(b"meta", &meta));
+        let moov = atom(b"moov", &[trak, udta].concat());
+        let mdat = atom(b"mdat", &[0; 1000]);
+        let file = [atom(b"ftyp", b"M4A \0\0\0\0"), moov, mdat].concat();
+
+        let storage = MemoryStorage::new();
+        let path = UniversalPath::from_uri_str("mem://lib/02.m4a").unwrap();
+        storage.write(&path, &file).await.unwrap();
+        let meta = read_track_metadata(&storage, &path).await.unwrap();
+        assert_eq!(meta.format, AudioFormat::Mp4);
+        assert_eq!(meta.title.as_deref(), Some("Jóga"));
+        assert_eq!(meta.artist.as_deref(), Some("Björk"));
+        assert_eq!((meta.track_number, meta.track_total), (Some(3), Some(10)));
+        assert_eq!(meta.genre.as_deref(), Some("Trip-Hop"));
+        assert_eq!(meta.tags["tmpo"], ["96"]);
+        assert!(!meta.tags.contains_key("covr"));
+        assert_eq!(meta.duration, Some(Duration::from_secs(240)));
+        assert_eq!((meta.sample_rate, meta.channels), (Some(44_100), Some(2)));
+        assert_eq!(meta.bitrate, Some(256_000));
+    }
+
+    #[tokio::test]
+    async fn test_atom_smaller_than_its_header() {
+        // A 64-bit size of 8, short of the 16 bytes its own header takes
+        let mut moov = 1u32.to_be_bytes().to_vec();
+        moov.extend(b"moov");
+        moov.extend(8u64.to_be_bytes());
+        moov.extend([0; 16]);
+        let file = [atom(b"ftyp", b"M4A \0\0\0\0"), moov].concat();
+        let storage = MemoryStorage::new();
+        let path = UniversalPath::from_uri_str("mem://lib/bad.m4a").unwrap();
+        storage.write(&path, &file).await.unwrap();
+        let error = read_track_metadata(&storage, &path).await.unwrap_err();
+        assert!(matches!(error, MediaError::Malformed(AudioFormat::Mp4, _)));
+
+        // One running past the end of the file
+        let file = [
+            atom(b"ftyp", b"M4A \0\0\0\0"),
+            4000u32.to_be_bytes().to_vec(),
+        ]
+        .concat();
+        let file = [file, b"moov".to_vec(), vec![0; 100]].concat();
+        storage.write(&path, &file).await.unwrap();
+        let error = read_track_metadata(&storage, &path).await.unwrap_err();
+        assert!(matches!(error, MediaError::Malformed(AudioFormat::Mp4, _)));
+    }
+
+    #[tokio::test]
+    async fn test_duration_too_long() {
+        // A version 1 movie header of a second's time scale and the longest duration
+        let mut mvhd = vec![1, 0, 0, 0];
+        mvhd.extend([0; 16]);
+        mvhd.extend(1u32.to_be_bytes());
+        mvhd.extend(u64::MAX.to_be_bytes());
+        let moov = atom(b"moov", &atom(b"mvhd", &mvhd));
+        let file = [atom(b"ftyp", b"M4A \0\0\0\0"), moov].concat();
+        let storage = MemoryStorage::new();
+        let path = UniversalPath::from_uri_str("mem://lib/long.m4a").unwrap();
+        storage.write(&path, &file).await.unwrap();
+        let meta = read_track_metadata(&storage, &path).await.unwrap();
+        assert_eq!(meta.duration, None);
+    }
+}
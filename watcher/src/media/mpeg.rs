@@ -0,0 +1,205 @@
+use super::{BLOCK, MediaError, Source, TrackMetadata};
+use std::time::Duration;
+
+#[rustfmt::skip]
+const BITRATES: [[[u16; 15]; 3]; 2] = [
+    // MPEG 1, layers 1 to 3
+    [
+        [0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448],
+        [0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384],
+        [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320],
+    ],
+    // MPEG 2 and 2.5
+    [
+        [0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256],
+        [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+        [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+    ],
+];
+const SAMPLE_RATES: [u32; 3] = [44_100, 48_000, 32_000];
+
+/// A frame header, as its four bytes give it
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    mpeg1: bool,
+    layer: u8,
+    /// In bits per second
+    bitrate: u32,
+    sample_rate: u32,
+    mono: bool,
+    len: usize,
+}
+
+impl Header {
+    fn parse(bytes: &[u8]) -> Option<Header> {
+        let &[b0, b1, b2, b3, ..] = bytes else {
+            return None;
+        };
+        if b0 != 0xFF || b1 & 0xE0 != 0xE0 {
+            return None;
+        }
+        // 0 is MPEG 2.5, 2 is MPEG 2 and 3 is MPEG 1
+        let version = (b1 >> 3) & 0x3;
+        let layer = 4 - ((b1 >> 1) & 0x3);
+        let (bitrate, rate) = ((b2 >> 4) as usize, ((b2 >> 2) & 0x3) as usize);
+        if version == 1 || layer == 4 || bitrate == 0 || bitrate == 15 || rate == 3 {
+            return None;
+        }
+        let mpeg1 = version == 3;
+        let bitrate = BITRATES[!mpeg1 as usize][layer as usize - 1][bitrate] as u32 * 1000;
+        let sample_rate = SAMPLE_RATES[rate] >> (3 - version.max(1));
+        let padding = ((b2 >> 1) & 0x1) as u32;
+        let samples = samples_per_frame(mpeg1, layer);
+        let len = match layer {
+            1 => (12 * bitrate / sample_rate + padding) * 4,
+            _ => samples / 8 * bitrate / sample_rate + padding,
+        };
+        Some(Header {
+            mpeg1,
+            layer,
+            bitrate,
+            sample_rate,
+            mono: b3 >> 6 == 3,
+            len: len as usize,
+        })
+    }
+
+    /// Where a Xing or Info header would start, after the side information
+    fn side_info_end(&self) -> usize {
+        4 + match (self.mpeg1, self.mono) {
+            (true, false) => 32,
+            (true, true) | (false, false) => 17,
+            (false, true) => 9,
+        }
+    }
+}
+
+fn samples_per_frame(mpeg1: bool, layer: u8) -> u32 {
+    match (layer, mpeg1) {
+        (1, _) => 384,
+        (3, false) => 576,
+        _ => 1152,
+    }
+}
+
+/// Whether `head` starts with what looks like an MPEG audio frame header
+pub(super) fn is_frame(head: &[u8]) -> bool {
+    Header::parse(head).is_some()
+}
+
+/// The stream details of MPEG audio that starts around `start` and ends at `end`,
+/// from the Xing, Info or VBRI header if the encoder wrote one, otherwise estimated
+/// from the first frame as if the bitrate were constant. Finding no frame is not an
+/// error, as the tags may still be of use.
+pub(super) async fn read(
+    src: &mut Source<'_>,
+    start: u64,
+    end: u64,
+    meta: &mut TrackMetadata,
+) -> Result<(), MediaError> {
+    let data = src.read(start, BLOCK).await?;
+    // A frame is only believed if the next one follows where it says
+    let found = (0..data.len()).find_map(|at| {
+        let header = Header::parse(&data[at..])?;
+        let next = data.get(at + header.len..).unwrap_or_default();
+        (next.len() < 4 || is_frame(next)).then_some((at, header))
+    });
+    let Some((at, header)) = found else {
+        return Ok(());
+    };
+    meta.sample_rate = Some(header.sample_rate);
+    meta.channels = Some(if header.mono { 1 } else { 2 });
+
+    let frame = &data[at..];
+    let frame_start = start + at as u64;
+    let (frames, bytes) = vbr_header(frame, &header).unwrap_or_default();
+    let samples = samples_per_frame(header.mpeg1, header.layer) as u64;
+    match frames {
+        Some(frames) => {
+            let secs = (frames * samples) as f64 / header.sample_rate as f64;
+            meta.duration = Some(Duration::from_secs_f64(secs));
+            meta.set_bitrate(bytes.unwrap_or(end.saturating_sub(frame_start)));
+        }
+        None => {
+            let audio_bytes = end.saturating_sub(frame_start);
+            let secs = audio_bytes as f64 * 8.0 / header.bitrate as f64;
+            meta.duration = Some(Duration::from_secs_f64(secs));
+            meta.bitrate = Some(header.bitrate);
+        }
+    }
+    Ok(())
+}
+
+/// The frame and byte counts a Xing, Info or VBRI header in the first frame gives
+fn vbr_header(frame: &[u8], header: &Header) -> Option<(Option<u64>, Option<u64>)> {
+    let be_u32 = |at: usize| -> Option<u64> {
+        let bytes = frame.get(at..at + 4)?;
+        Some(u32::from_be_bytes(bytes.try_into().ok()?) as u64)
+    };
+    let xing = header.side_info_end();
+    if matches!(frame.get(xing..xing + 4), Some(b"Xing" | b"Info")) {
+        let flags = be_u32(xing + 4)?;
+        let mut at = xing + 8;
+        let mut field = |flag: u64| {
+            if flags & flag == 0 {
+                return None;
+            }
+            at += 4;
+            be_u32(at - 4)
+        };
+        let frames = field(0x1);
+        let bytes = field(0x2);
+        return Some((frames, bytes));
+    }
+    // Fraunhofer's, always 32 bytes after the header
+    if frame.get(36..40) == Some(b"VBRI") {
+        return Some((be_u32(36 + 14), be_u32(36 + 10)));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::media::{AudioFormat, read_track_metadata};
+    use crate::storage::{MemoryStorage, Storage};
+    use crate::universal_path::UniversalPath;
+    use std::time::Duration;
+
+    /// MPEG 1 layer 3 at 128 kb/s and 44.1 kHz, in stereo
+    const HEADER: [u8; 4] = [0xFF, 0xFB, 0x90, 0x00];
+    const FRAME_LEN: usize = 417;
+
+    fn frame() -> Vec<u8> {
+        let mut frame = HEADER.to_vec();
+        frame.resize(FRAME_LEN, 0);
+        frame
+    }
+
+    #[tokio::test]
+    async fn test_read_mpeg() {
+        let storage = MemoryStorage::new();
+        // Constant bitrate
+        let cbr: Vec<u8> = (0..100).flat_map(|_| frame()).collect();
+        let path = UniversalPath::from_uri_str("mem://lib/cbr.mp3").unwrap();
+        storage.write(&path, &cbr).await.unwrap();
+        let meta = read_track_metadata(&storage, &path).await.unwrap();
+        assert_eq!(meta.format, AudioFormat::Mp3);
+        assert_eq!((meta.sample_rate, meta.channels), (Some(44_100), Some(2)));
+        assert_eq!(meta.bitrate, Some(128_000));
+        assert_eq!(meta.duration, Some(Duration::from_secs_f64(2.60625)));
+
+        // Variable, with an Xing header counting 1000 frames
+        let mut vbr = frame();
+        vbr[36..40].copy_from_slice(b"Xing");
+        vbr[40..44].copy_from_slice(&3u32.to_be_bytes());
+        vbr[44..48].copy_from_slice(&1000u32.to_be_bytes());
+        vbr[48..52].copy_from_slice(&1_000_000u32.to_be_bytes());
+        vbr.extend(frame());
+        let path = UniversalPath::from_uri_str("mem://lib/vbr.mp3").unwrap();
+        storage.write(&path, &vbr).await.unwrap();
+        let meta = read_track_metadata(&storage, &path).await.unwrap();
+        let secs = 1000.0 * 1152.0 / 44_100.0;
+        assert_eq!(meta.duration, Some(Duration::from_secs_f64(secs)));
+        assert_eq!(meta.bitrate, Some((8_000_000.0 / secs).round() as u32));
+    }
+}
@@ -0,0 +1,210 @@
+use super::{
+    AudioFormat, BLOCK, MAX_CHUNK, MediaError, Source, TagScheme, TrackMetadata, vorbis_comments,
+};
+use std::time::Duration;
+
+const PAGE_HEADER_LEN: u64 = 27;
+const MALFORMED: MediaError = MediaError::Malformed(AudioFormat::Vorbis, "bad Ogg page");
+/// Opus granule positions count samples at 48 kHz, whatever the input rate was
+const OPUS_RATE: u32 = 48_000;
+
+/// The identification and comment headers of the first logical stream, which are its
+/// first two packets, and the duration from the granule position of its last page.
+pub(super) async fn read(src: &mut Source<'_>) -> Result<TrackMetadata, MediaError> {
+    let (serial, packets) = first_packets(src, 2).await?;
+    let [ident, comments] = &packets[..] else {
+        return Err(MediaError::Malformed(
+            AudioFormat::Vorbis,
+            "no comment header",
+        ));
+    };
+    let le_u32 = |data: &[u8], at: usize| {
+        data.get(at..at + 4)
+            .map(|b| u32::from_le_bytes(b.try_into().expect("4 bytes")))
+    };
+    let (mut meta, rate, pre_skip, tags) = if ident.starts_with(b"\x01vorbis") {
+        let mut meta = TrackMetadata::new(AudioFormat::Vorbis);
+        let rate = le_u32(ident, 12).filter(|&r| r > 0);
+        meta.channels = ident.get(11).copied();
+        meta.sample_rate = rate;
+        meta.bitrate = le_u32(ident, 20).filter(|&b| b > 0 && b < i32::MAX as u32);
+        (meta, rate, 0, comments.strip_prefix(b"\x03vorbis"))
+    } else if ident.starts_with(b"OpusHead") {
+        let mut meta = TrackMetadata::new(AudioFormat::Opus);
+        meta.channels = ident.get(9).copied();
+        meta.sample_rate = le_u32(ident, 12).filter(|&r| r > 0).or(Some(OPUS_RATE));
+        let pre_skip = ident
+            .get(10..12)
+            .map_or(0, |b| u16::from_le_bytes([b[0], b[1]]));
+        (
+            meta,
+            Some(OPUS_RATE),
+            pre_skip as u64,
+            comments.strip_prefix(b"OpusTags"),
+        )
+    } else {
+        // Ogg FLAC, Speex, Theora and the like
+        return Err(MediaError::Unrecognized);
+    };
+    let format = meta.format;
+    let tags = tags.ok_or(MediaError::Malformed(format, "no comment header"))?;
+    vorbis_comments(tags, &mut meta.tags).ok_or(MediaError::Malformed(format, "bad comments"))?;
+
+    if let (Some(granule), Some(rate)) = (last_granule(src, serial).await?, rate) {
+        let samples = granule.saturating_sub(pre_skip);
+        // Left unknown if too long to be a real one
+        meta.duration = Duration::try_from_secs_f64(samples as f64 / rate as f64).ok();
+    }
+    if meta.bitrate.is_none() {
+        meta.set_bitrate(src.size());
+    }
+    meta.apply_tags(TagScheme::Vorbis);
+    Ok(meta)
+}
+
+/// The serial number of the first logical stream and its first `count` packets, which
+/// may span pages
+async fn first_packets(
+    src: &mut Source<'_>,
+    count: usize,
+) -> Result<(u32, Vec<Vec<u8>>), MediaError> {
+    let mut packets = Vec::new();
+    let mut packet = Vec::new();
+    let mut serial = None;
+    let mut at = 0;
+    while packets.len() < count {
+        let header = src.exact(at, PAGE_HEADER_LEN, AudioFormat::Vorbis).await?;
+        if !header.starts_with(b"OggS") {
+            return Err(MALFORMED);
+        }
+        let page_serial = u32::from_le_bytes(header[14..18].try_into().expect("4 bytes"));
+        let segments = header[26] as u64;
+        let lacing = src
+            .exact(at + PAGE_HEADER_LEN, segments, AudioFormat::Vorbis)
+            .await?;
+        let body_len: u64 = lacing.iter().map(|&l| l as u64).sum();
+        let body_start = at + PAGE_HEADER_LEN + segments;
+        at = body_start + body_len;
+        // Pages of other streams multiplexed in
+        if *serial.get_or_insert(page_serial) != page_serial {
+            continue;
+        }
+        let body = src.exact(body_start, body_len, AudioFormat::Vorbis).await?;
+        let mut pos = 0;
+        for &len in &lacing {
+            packet.extend_from_slice(&body[pos..pos + len as usize]);
+            pos += len as usize;
+            // A lacing value under 255 ends the packet
+            if len < 255 {
+                packets.push(std::mem::take(&mut packet));
+                if packets.len() == count {
+                    break;
+                }
+            }
+        }
+        if packet.len() as u64 > MAX_CHUNK {
+            return Err(MALFORMED);
+        }
+    }
+    Ok((serial.unwrap_or_default(), packets))
+}
+
+/// The granule position of the last page of stream `serial`, looked for in the last
+/// block of the file
+async fn last_granule(src: &mut Source<'_>, serial: u32) -> Result<Option<u64>, MediaError> {
+    let start = src.size().saturating_sub(BLOCK);
+    let tail = src.read(start, BLOCK).await?;
+    let granule = (0..tail.len().saturating_sub(PAGE_HEADER_LEN as usize))
+        .rev()
+        .filter(|&at| tail[at..].starts_with(b"OggS"))
+        .find(|&at| tail[at + 14..at + 18] == serial.to_le_bytes())
+        .map(|at| u64::from_le_bytes(tail[at + 6..at + 14].try_into().expect("8 bytes")));
+    // -1 on a page where no packet ends
+    Ok(granule.filter(|&g| g != u64::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::media::{AudioFormat, read_track_metadata, tests::comments};
+    use crate::storage::{MemoryStorage, Storage};
+    use crate::universal_path::UniversalPath;
+    use std::time::Duration;
+
+    fn page(granule: u64, lacing: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS\0\0".to_vec();
+        page.extend(granule.to_le_bytes());
+        page.extend(7u32.to_le_bytes());
+        page.extend([0; 8]);
+        page.push(lacing.len() as u8);
+        page.extend_from_slice(lacing);
+        page.extend_from_slice(body);
+        page
+    }
+
+    #[tokio::test]
+    async fn test_read_ogg() {
+        let mut ident = b"\x01vorbis".to_vec();
+        ident.extend([0; 4]);
+        ident.push(2);
+        ident.extend(48_000u32.to_le_bytes());
+        ident.extend([0; 4]);
+        ident.extend(192_000u32.to_le_bytes());
+        ident.extend([0; 6]);
+        let mut tags = b"\x03vorbis".to_vec();
+        tags.extend(comments(&[
+            "TITLE=Hoppípolla",
+            "ARTIST=Sigur Rós",
+            "TRACKNUMBER=2",
+        ]));
+        tags.push(1);
+        tags.resize(300, 0);
+
+        // The comment header runs over onto a second page
+        let mut file = page(
+            0,
+            &[ident.len() as u8, 255],
+            &[&ident[..], &tags[..255]].concat(),
+        );
+        file.extend(page(0, &[45], &tags[255..]));
+        file.extend(page(u64::MAX, &[255], &[0; 255]));
+        file.extend(page(48_000 * 268, &[100], &[0; 100]));
+
+        let storage = MemoryStorage::new();
+        let path = UniversalPath::from_uri_str("mem://lib/02.ogg").unwrap();
+        storage.write(&path, &file).await.unwrap();
+        let meta = read_track_metadata(&storage, &path).await.unwrap();
+        assert_eq!(meta.format, AudioFormat::Vorbis);
+        assert_eq!(meta.title.as_deref(), Some("Hoppípolla"));
+        assert_eq!(meta.artist.as_deref(), Some("Sigur Rós"));
+        assert_eq!(meta.track_number, Some(2));
+        assert_eq!(meta.duration, Some(Duration::from_secs(268)));
+        assert_eq!((meta.sample_rate, meta.channels), (Some(48_000), Some(2)));
+        assert_eq!(meta.bitrate, Some(192_000));
+    }
+
+    #[tokio::test]
+    async fn test_duration_too_long() {
+        // One sample a second, and the last granule as far on as it goes
+        let mut ident = b"\x01vorbis".to_vec();
+        ident.extend([0; 4]);
+        ident.push(1);
+        ident.extend(1u32.to_le_bytes());
+        ident.extend([0; 14]);
+        let mut tags = b"\x03vorbis".to_vec();
+        tags.extend(comments(&[]));
+        tags.push(1);
+        let mut file = page(
+            0,
+            &[ident.len() as u8, tags.len() as u8],
+            &[ident, tags].concat(),
+        );
+        file.extend(page(u64::MAX - 1, &[10], &[0; 10]));
+
+        let storage = MemoryStorage::new();
+        let path = UniversalPath::from_uri_str("mem://lib/long.ogg").unwrap();
+        storage.write(&path, &file).await.unwrap();
+        let meta = read_track_metadata(&storage, &path).await.unwrap();
+        assert_eq!(meta.format, AudioFormat::Vorbis);
+        assert_eq!(meta.duration, None);
+    }
+}
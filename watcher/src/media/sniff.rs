@@ -0,0 +1,315 @@
+use super::{id3, mpeg};
+use crate::mime::FALLBACK_CONTENT_TYPE;
+use crate::storage::{Storage, StorageError};
+use crate::universal_path::UniversalPath;
+use serde::Serialize;
+
+/// Bytes read to tell a format by, and again past an ID3v2 tag, which can be far
+/// bigger when it holds cover art
+const SNIFF_LEN: u64 = 4096;
+
+/// What a file is by its contents, whatever its extension says.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaFormat {
+    /// MPEG audio, with or without ID3 tags
+    Mp3,
+    Flac,
+    /// Vorbis in Ogg
+    Vorbis,
+    /// Opus in Ogg
+    Opus,
+    /// Ogg of another codec: FLAC, Speex, Theora
+    Ogg,
+    Wav,
+    /// AIFF or AIFF-C
+    Aiff,
+    /// AAC or ALAC in an MP4 container, by its brand
+    M4a,
+    /// MP4 or QuickTime of any other brand, usually video
+    Mp4,
+    /// Raw AAC in ADTS frames
+    Aac,
+    WavPack,
+    /// Monkey's Audio
+    Ape,
+    Dsf,
+    Jpeg,
+    Png,
+    Gif,
+    Webp,
+    Pdf,
+    Zip,
+    /// UTF-8 text: cue sheets, playlists, logs, lyrics
+    Text,
+    /// None of the above, or empty
+    Unknown,
+}
+
+impl MediaFormat {
+    pub fn is_audio(&self) -> bool {
+        use MediaFormat::*;
+        matches!(
+            self,
+            Mp3 | Flac | Vorbis | Opus | Ogg | Wav | Aiff | M4a | Aac | WavPack | Ape | Dsf
+        )
+    }
+
+    /// The content type to serve it as, as `MimeRegistry` has it by default
+    pub fn content_type(&self) -> &'static str {
+        use MediaFormat::*;
+        match self {
+            Mp3 => "audio/mpeg",
+            Flac => "audio/flac",
+            Vorbis | Opus | Ogg => "audio/ogg",
+            Wav => "audio/wav",
+            Aiff => "audio/aiff",
+            M4a => "audio/mp4",
+            Mp4 => "video/mp4",
+            Aac => "audio/aac",
+            WavPack => "audio/wavpack",
+            Ape => "audio/ape",
+            Dsf => "audio/x-dsf",
+            Jpeg => "image/jpeg",
+            Png => "image/png",
+            Gif => "image/gif",
+            Webp => "image/webp",
+            Pdf => "application/pdf",
+            Zip => "application/zip",
+            Text => "text/plain; charset=utf-8",
+            Unknown => FALLBACK_CONTENT_TYPE,
+        }
+    }
+
+    /// Lowercase extensions files of the format go by, the usual one first; none for
+    /// `Text` and `Unknown`, which go by any
+    pub fn extensions(&self) -> &'static [&'static str] {
+        use MediaFormat::*;
+        match self {
+            Mp3 => &["mp3", "mp2", "mpga"],
+            Flac => &["flac"],
+            Vorbis => &["ogg", "oga"],
+            Opus => &["opus", "ogg"],
+            Ogg => &["ogg", "oga", "ogv", "spx"],
+            Wav => &["wav", "wave"],
+            Aiff => &["aiff", "aif", "aifc"],
+            M4a => &["m4a", "m4b", "m4p", "mp4", "alac"],
+            Mp4 => &["mp4", "m4v", "mov"],
+            Aac => &["aac"],
+            WavPack => &["wv"],
+            Ape => &["ape"],
+            Dsf => &["dsf"],
+            Jpeg => &["jpg", "jpeg"],
+            Png => &["png"],
+            Gif => &["gif"],
+            Webp => &["webp"],
+            Pdf => &["pdf"],
+            Zip => &["zip"],
+            Text | Unknown => &[],
+        }
+    }
+
+    /// Whether `path`'s extension is one the format goes by, which it always is for
+    /// formats that go by any
+    pub fn matches_extension(&self, path: &UniversalPath) -> bool {
+        let extensions = self.extensions();
+        let extension = path
+            .last_segment()
+            .and_then(|name| name.rsplit_once('.'))
+            .map(|(_, ext)| ext.to_ascii_lowercase());
+        extensions.is_empty() || extension.is_some_and(|ext| extensions.contains(&&*ext))
+    }
+
+    /// The format of a file starting with `head`, which should be its first few KB,
+    /// past any ID3v2 tag
+    fn of(head: &[u8]) -> MediaFormat {
+        use MediaFormat::*;
+        let at = |range: std::ops::Range<usize>| head.get(range).unwrap_or_default();
+        match at(0..4) {
+            b"fLaC" => return Flac,
+            b"OggS" => return ogg_codec(head),
+            b"RIFF" if at(8..12) == b"WAVE" => return Wav,
+            b"RIFF" if at(8..12) == b"WEBP" => return Webp,
+            b"FORM" if matches!(at(8..12), b"AIFF" | b"AIFC") => return Aiff,
+            b"wvpk" => return WavPack,
+            b"MAC " => return Ape,
+            b"DSD " => return Dsf,
+            b"%PDF" => return Pdf,
+            b"PK\x03\x04" => return Zip,
+            b"GIF8" => return Gif,
+            _ => {}
+        }
+        if at(4..8) == b"ftyp" {
+            return mp4_brand(head);
+        }
+        if head.starts_with(b"\x89PNG\r\n\x1a\n") {
+            return Png;
+        }
+        if head.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            return Jpeg;
+        }
+        if mpeg::is_frame(head) {
+            return Mp3;
+        }
+        // Sync word, then layer 0, which MPEG audio never uses
+        if let [0xFF, b1, ..] = head
+            && b1 & 0xF6 == 0xF0
+        {
+            return Aac;
+        }
+        if is_text(head) {
+            return Text;
+        }
+        Unknown
+    }
+}
+
+/// The codec of an Ogg stream, from the start of its first packet
+fn ogg_codec(page: &[u8]) -> MediaFormat {
+    let segments = page.get(26).copied().unwrap_or_default() as usize;
+    let packet = page.get(27 + segments..).unwrap_or_default();
+    if packet.starts_with(b"\x01vorbis") {
+        MediaFormat::Vorbis
+    } else if packet.starts_with(b"OpusHead") {
+        MediaFormat::Opus
+    } else {
+        MediaFormat::Ogg
+    }
+}
+
+/// Audio brands among the major and compatible brands of an `ftyp` atom
+fn mp4_brand(head: &[u8]) -> MediaFormat {
+    let len = head.get(..4).map_or(0, |b| {
+        u32::from_be_bytes(b.try_into().expect("4 bytes")) as usize
+    });
+    let atom = head.get(8..len.min(head.len())).unwrap_or_default();
+    // The major brand, the minor version, then compatible brands
+    let mut brands = atom
+        .chunks_exact(4)
+        .enumerate()
+        .filter(|(at, _)| *at != 1)
+        .map(|(_, brand)| brand);
+    let audio = [b"M4A ", b"M4B ", b"M4P ", b"F4A ", b"F4B "];
+    match brands.any(|b| audio.iter().any(|a| b == *a)) {
+        true => MediaFormat::M4a,
+        false => MediaFormat::Mp4,
+    }
+}
+
+/// Whether `head` reads as UTF-8 text, allowing for a character cut off at the end
+fn is_text(head: &[u8]) -> bool {
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&head[..e.valid_up_to()]).expect("valid up to here")
+        }
+        Err(_) => return false,
+    };
+    let control = |c: char| c.is_control() && !matches!(c, '\n' | '\r' | '\t');
+    !text.is_empty() && !text.chars().any(control)
+}
+
+/// What the file at `path` is by its first few KB, read through `read_range`, so a
+/// FLAC named `.mp3` or an HTML error page saved as `.flac` is told apart. MP3s are
+/// found past an ID3v2 tag however big, with one more read.
+pub async fn detect_format(
+    storage: &dyn Storage,
+    path: &UniversalPath,
+) -> Result<MediaFormat, StorageError> {
+    let head = read_head(storage, path, 0).await?;
+    let Some(tag_len) = id3::v2_len(&head) else {
+        return Ok(MediaFormat::of(&head));
+    };
+    let head = match head.get(tag_len as usize..) {
+        Some(rest) if rest.len() >= 4 => rest.to_vec(),
+        _ => read_head(storage, path, tag_len).await?,
+    };
+    // An ID3 tag on anything else is sometimes seen, on AAC and FLAC
+    Ok(match MediaFormat::of(&head) {
+        MediaFormat::Text | MediaFormat::Unknown => MediaFormat::Mp3,
+        format => format,
+    })
+}
+
+async fn read_head(
+    storage: &dyn Storage,
+    path: &UniversalPath,
+    start: u64,
+) -> Result<Vec<u8>, StorageError> {
+    match storage.read_range(path, start..start + SNIFF_LEN).await {
+        Err(StorageError::RangeNotSatisfiable) => Ok(Vec::new()),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{FilterSet, MemoryStorage, Scanner};
+    use futures::StreamExt;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_detect_format() {
+        let storage = MemoryStorage::new();
+        let dir = UniversalPath::from_uri_str("mem://lib/sniff/").unwrap();
+        storage.create_dir(&dir).await.unwrap();
+        let mut ogg = b"OggS\0\x02".to_vec();
+        ogg.extend([0; 20]);
+        ogg.extend([1, 30]);
+        ogg.extend(b"OpusHead\x01\x02");
+        let mut m4a = 24u32.to_be_bytes().to_vec();
+        m4a.extend(b"ftypmp42\0\0\0\0isomM4A ");
+        let mut mp4 = 20u32.to_be_bytes().to_vec();
+        mp4.extend(b"ftypisom\0\0\x02\0mp41");
+        // An ID3 tag bigger than one read, with cover art, ahead of an MPEG frame
+        let mut mp3 = b"ID3\x04\0\0".to_vec();
+        mp3.extend([0, 0, 0x40, 0]);
+        mp3.resize(10 + 8192, 0);
+        mp3.extend([0xFF, 0xFB, 0x90, 0x64]);
+        let files: [(&str, &[u8], MediaFormat); 11] = [
+            ("01.mp3", b"fLaC\0\0\0\x22", MediaFormat::Flac),
+            ("02.mp3", &mp3, MediaFormat::Mp3),
+            ("03.ogg", &ogg, MediaFormat::Opus),
+            ("04.wav", b"RIFF\0\0\0\0WAVEfmt ", MediaFormat::Wav),
+            ("05.aif", b"FORM\0\0\0\0AIFCFVER", MediaFormat::Aiff),
+            ("06.mp4", &m4a, MediaFormat::M4a),
+            ("clip.mp4", &mp4, MediaFormat::Mp4),
+            ("cover.jpg", b"\x89PNG\r\n\x1a\n\0\0", MediaFormat::Png),
+            ("07.flac", b"<html>Not Found</html>", MediaFormat::Text),
+            (
+                "album.cue",
+                "TITLE \"Ágætis byrjun\"\r\n".as_bytes(),
+                MediaFormat::Text,
+            ),
+            ("empty.flac", b"", MediaFormat::Unknown),
+        ];
+        for (name, data, expected) in files {
+            let path = dir.join(name);
+            storage.write(&path, data).await.unwrap();
+            let format = detect_format(&storage, &path).await.unwrap();
+            assert_eq!(format, expected, "{name}");
+        }
+        let lying = dir.join("01.mp3");
+        assert!(!MediaFormat::Flac.matches_extension(&lying));
+        assert!(MediaFormat::Mp3.matches_extension(&dir.join("LOUD.MP3")));
+        assert!(MediaFormat::Text.matches_extension(&dir.join("album.cue")));
+        assert_eq!(MediaFormat::M4a.content_type(), "audio/mp4");
+
+        // A scan says what each file it takes is
+        let storage = Arc::new(storage);
+        let mut scan = Scanner::new(storage.clone())
+            .with_filter(FilterSet::new().with_exclude(["*.cue"]))
+            .with_format_detection(true)
+            .scan(&dir);
+        let mut found = Vec::new();
+        while let Some(entry) = scan.next().await {
+            let (path, meta) = entry.unwrap();
+            found.push((path.last_segment().unwrap().to_string(), meta.format));
+        }
+        found.sort();
+        assert_eq!(found.len(), 10);
+        assert_eq!(found[0], ("01.mp3".to_string(), Some(MediaFormat::Flac)));
+        assert_eq!(found[6], ("07.flac".to_string(), Some(MediaFormat::Text)));
+    }
+}
@@ -0,0 +1,206 @@
+use crate::universal_path::UniversalPath;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeSet, time::Duration};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum MediaServerError {
+    #[error("{0} is not under the configured root")]
+    OutsideRoot(String),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error("media server answered {0}")]
+    Status(u16),
+}
+
+/// Which media server to poke and how to authenticate.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum MediaServer {
+    Jellyfin {
+        base_url: String,
+        api_key: String,
+    },
+    Plex {
+        base_url: String,
+        token: String,
+        /// Library section id as shown in Plex's URLs
+        section_id: u32,
+    },
+}
+
+/// Refresh settings for one library root.
+///
+/// The media server usually sees the library under a different path than we do
+/// (`s3://bucket/music` here, `/mnt/music` in the container), so changed paths are
+/// re-rooted onto `server_root` before being sent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MediaServerConfig {
+    pub server: MediaServer,
+    pub root: UniversalPath,
+    /// The same directory as `root`, as the media server's filesystem sees it.
+    pub server_root: String,
+}
+
+/// Asks Jellyfin or Plex to rescan just the folders that changed, instead of
+/// waiting for their periodic library scan.
+pub struct MediaServerNotifier {
+    config: MediaServerConfig,
+    client: reqwest::Client,
+}
+
+impl MediaServerNotifier {
+    pub fn new(config: MediaServerConfig) -> Self {
+        MediaServerNotifier {
+            config,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .expect("static client configuration"),
+        }
+    }
+
+    pub fn config(&self) -> &MediaServerConfig {
+        &self.config
+    }
+
+    /// The folder to refresh for a changed path, in the server's namespace. Files map
+    /// to their parent directory; directories (by hint) map to themselves.
+    pub fn server_folder_for(&self, changed: &UniversalPath) -> Result<String, MediaServerError> {
+        let folder = if changed.is_dir_hint() {
+            changed.clone()
+        } else {
+            changed.parent().unwrap_or_else(|| changed.clone())
+        };
+        let relative = folder
+            .relative_to(&self.config.root)
+            .ok_or_else(|| MediaServerError::OutsideRoot(changed.to_string()))?;
+
+        let base = self.config.server_root.trim_end_matches(['/', '\\']);
+        let sep = if base.contains('\\') && !base.contains('/') {
+            "\\"
+        } else {
+            "/"
+        };
+        let mut out = base.to_string();
+        for segment in relative {
+            out.push_str(sep);
+            out.push_str(&segment);
+        }
+        if out.is_empty() {
+            out.push_str(sep);
+        }
+        Ok(out)
+    }
+
+    /// Refresh every distinct folder touched by `changed`, returning the folders sent.
+    /// Paths outside the root are an error and nothing is sent.
+    pub async fn notify_changed(
+        &self,
+        changed: &[UniversalPath],
+    ) -> Result<Vec<String>, MediaServerError> {
+        let folders: BTreeSet<String> = changed
+            .iter()
+            .map(|p| self.server_folder_for(p))
+            .collect::<Result<_, _>>()?;
+        if folders.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        match &self.config.server {
+            MediaServer::Jellyfin { base_url, api_key } => {
+                let updates: Vec<_> = folders
+                    .iter()
+                    .map(|path| serde_json::json!({ "Path": path, "UpdateType": "Modified" }))
+                    .collect();
+                let resp = self
+                    .client
+                    .post(format!(
+                        "{}/Library/Media/Updated",
+                        base_url.trim_end_matches('/')
+                    ))
+                    .header("X-Emby-Token", api_key)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(serde_json::json!({ "Updates": updates }).to_string())
+                    .send()
+                    .await?;
+                check_status(resp.status())?;
+            }
+            MediaServer::Plex {
+                base_url,
+                token,
+                section_id,
+            } => {
+                let url = format!(
+                    "{}/library/sections/{}/refresh",
+                    base_url.trim_end_matches('/'),
+                    section_id
+                );
+                for folder in &folders {
+                    let resp = self
+                        .client
+                        .get(&url)
+                        .query(&[("path", folder.as_str()), ("X-Plex-Token", token.as_str())])
+                        .send()
+                        .await?;
+                    check_status(resp.status())?;
+                }
+            }
+        }
+        Ok(folders.into_iter().collect())
+    }
+}
+
+fn check_status(status: reqwest::StatusCode) -> Result<(), MediaServerError> {
+    if status.is_success() {
+        Ok(())
+    } else {
+        Err(MediaServerError::Status(status.as_u16()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notifier(server_root: &str) -> MediaServerNotifier {
+        MediaServerNotifier::new(MediaServerConfig {
+            server: MediaServer::Plex {
+                base_url: "http://plex:32400".to_string(),
+                token: "t".to_string(),
+                section_id: 1,
+            },
+            root: UniversalPath::from_uri_str("s3://bucket/music").unwrap(),
+            server_root: server_root.to_string(),
+        })
+    }
+
+    #[test]
+    fn test_server_folder_mapping() {
+        let n = notifier("/mnt/music/");
+        let root = n.config().root.clone();
+        let track = root.join_all(&["Björk", "Homogenic", "01.flac"]);
+        assert_eq!(
+            n.server_folder_for(&track).unwrap(),
+            "/mnt/music/Björk/Homogenic"
+        );
+
+        let album = root.join("Björk/");
+        assert_eq!(n.server_folder_for(&album).unwrap(), "/mnt/music/Björk");
+
+        let root_file = UniversalPath::from_uri_str("s3://bucket/music/cover.jpg").unwrap();
+        assert_eq!(n.server_folder_for(&root_file).unwrap(), "/mnt/music");
+
+        let windows = notifier("D:\\Media\\Music");
+        assert_eq!(
+            windows.server_folder_for(&track).unwrap(),
+            "D:\\Media\\Music\\Björk\\Homogenic"
+        );
+
+        let outside = UniversalPath::from_uri_str("s3://bucket/video/x.mkv").unwrap();
+        assert!(matches!(
+            n.server_folder_for(&outside),
+            Err(MediaServerError::OutsideRoot(_))
+        ));
+    }
+}
@@ -0,0 +1,596 @@
+use crate::root::AUDIO_EXTENSIONS;
+use crate::storage::{CallOptions, CallPriority, EntryKind, Storage, StorageError};
+use crate::universal_path::UniversalPath;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{task::JoinHandle, time::Instant};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaStreamOptions {
+    /// Size of each ranged read, and of the unit the cache keeps
+    pub block_size: u64,
+    /// Blocks fetched in the background beyond the one being read
+    pub read_ahead: usize,
+    /// Blocks kept in memory, including the read-ahead window; raised to fit the
+    /// window if smaller
+    pub cache_blocks: usize,
+    /// Pace reads to about this many bytes per second, after an initial burst of one
+    /// read-ahead window, so a fast consumer does not pull the whole file over a
+    /// slow link
+    pub realtime_rate: Option<u64>,
+    /// Start prefetching the next track once this few bytes are left in the
+    /// current one
+    pub prefetch_next_within: u64,
+}
+
+impl Default for MediaStreamOptions {
+    fn default() -> Self {
+        MediaStreamOptions {
+            block_size: 256 * 1024,
+            read_ahead: 8,
+            cache_blocks: 32,
+            realtime_rate: None,
+            prefetch_next_within: 2 * 1024 * 1024,
+        }
+    }
+}
+
+impl MediaStreamOptions {
+    /// Pace reads to `bits_per_second`, the track's encoded bitrate
+    pub fn with_bitrate(mut self, bits_per_second: u64) -> Self {
+        self.realtime_rate = Some((bits_per_second / 8).max(1));
+        self
+    }
+}
+
+/// Snapshot of how much of a stream is ready to play.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferStatus {
+    pub position: u64,
+    pub length: u64,
+    /// Bytes from `position` onwards that are already in memory, without gaps
+    pub buffered_ahead: u64,
+    /// Background fetches still running
+    pub fetching: usize,
+}
+
+impl BufferStatus {
+    /// Whether the next read has to wait on the backend
+    pub fn is_buffering(&self) -> bool {
+        self.buffered_ahead == 0 && !self.at_end()
+    }
+
+    pub fn at_end(&self) -> bool {
+        self.position >= self.length
+    }
+}
+
+type Blocks = Arc<Mutex<HashMap<u64, Vec<u8>>>>;
+
+/// Length and leading blocks of the next track, fetched before it is opened
+type Prefetched = JoinHandle<Result<(u64, HashMap<u64, Vec<u8>>), StorageError>>;
+
+/// Sequential reader over one file in a `Storage`, built for audio playback.
+///
+/// The file is fetched in fixed-size blocks with ranged reads. While the caller
+/// consumes one block the next `read_ahead` blocks are fetched in the background at
+/// `CallPriority::Background`, so a decoder reading at playback speed rarely waits on
+/// the network. Blocks stay cached after they are read, which makes seeking back, or
+/// re-reading a header after probing the end of the file, free.
+///
+/// For gapless playback, give the stream the track that follows with `set_next`.
+/// Once fewer than `prefetch_next_within` bytes remain, the start of that track is
+/// fetched too, and `open_next` hands it over without a round trip.
+pub struct MediaStream {
+    storage: Arc<dyn Storage>,
+    path: UniversalPath,
+    options: MediaStreamOptions,
+    length: u64,
+    position: u64,
+    blocks: Blocks,
+    pending: HashMap<u64, JoinHandle<()>>,
+    /// When pacing started and how much has been delivered since
+    pacing: (Instant, u64),
+    next: Option<UniversalPath>,
+    prefetched: Option<Prefetched>,
+}
+
+impl MediaStream {
+    pub async fn open(
+        storage: Arc<dyn Storage>,
+        path: UniversalPath,
+        options: MediaStreamOptions,
+    ) -> Result<Self, StorageError> {
+        let meta = storage.stat(&path).await?;
+        if meta.kind != EntryKind::File {
+            return Err(StorageError::NotAFile);
+        }
+        let length = meta.size_bytes.ok_or(StorageError::UnsupportedFeature(
+            "streaming without a known length",
+        ))?;
+        Ok(MediaStream::from_parts(
+            storage,
+            path,
+            options,
+            length,
+            HashMap::new(),
+        ))
+    }
+
+    fn from_parts(
+        storage: Arc<dyn Storage>,
+        path: UniversalPath,
+        mut options: MediaStreamOptions,
+        length: u64,
+        blocks: HashMap<u64, Vec<u8>>,
+    ) -> Self {
+        options.block_size = options.block_size.max(1);
+        options.cache_blocks = options.cache_blocks.max(options.read_ahead + 1);
+        MediaStream {
+            storage,
+            path,
+            options,
+            length,
+            position: 0,
+            blocks: Arc::new(Mutex::new(blocks)),
+            pending: HashMap::new(),
+            pacing: (Instant::now(), 0),
+            next: None,
+            prefetched: None,
+        }
+    }
+
+    /// The track to prefetch near the end of this one, on the same storage.
+    /// Replacing it cancels any prefetch already started.
+    pub fn set_next(&mut self, next: Option<UniversalPath>) {
+        if self.next != next {
+            if let Some(handle) = self.prefetched.take() {
+                handle.abort();
+            }
+            self.next = next;
+        }
+    }
+
+    pub fn next(&self) -> Option<&UniversalPath> {
+        self.next.as_ref()
+    }
+
+    /// Whether the start of the next track has been fetched or is being fetched
+    pub fn is_prefetching_next(&self) -> bool {
+        self.prefetched.is_some()
+    }
+
+    /// Open the next track with the same options, reusing whatever was prefetched.
+    /// Returns `None` if no next track is set.
+    pub async fn open_next(&mut self) -> Result<Option<MediaStream>, StorageError> {
+        let Some(next) = self.next.take() else {
+            return Ok(None);
+        };
+        if let Some(handle) = self.prefetched.take() {
+            // A failed prefetch is retried by a plain open, which reports the error
+            if let Ok(Ok((length, blocks))) = handle.await {
+                let mut stream = MediaStream::from_parts(
+                    self.storage.clone(),
+                    next,
+                    self.options.clone(),
+                    length,
+                    blocks,
+                );
+                stream.schedule_read_ahead();
+                return Ok(Some(stream));
+            }
+        }
+        MediaStream::open(self.storage.clone(), next, self.options.clone())
+            .await
+            .map(Some)
+    }
+
+    pub fn path(&self) -> &UniversalPath {
+        &self.path
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn options(&self) -> &MediaStreamOptions {
+        &self.options
+    }
+
+    /// Read up to `buf.len()` bytes at the current position, returning 0 at the end.
+    ///
+    /// A read never spans blocks, so it may return fewer bytes than asked for before
+    /// the end of the file.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, StorageError> {
+        if buf.is_empty() || self.position >= self.length {
+            return Ok(0);
+        }
+        let index = self.position / self.options.block_size;
+        self.ensure_block(index).await?;
+        let n = {
+            let blocks = self.lock_blocks();
+            let block = &blocks[&index];
+            let offset = (self.position - index * self.options.block_size) as usize;
+            let n = buf.len().min(block.len().saturating_sub(offset));
+            buf[..n].copy_from_slice(&block[offset..offset + n]);
+            n
+        };
+        self.position += n as u64;
+        self.schedule_read_ahead();
+        self.maybe_prefetch_next();
+        self.evict();
+        self.pace(n as u64).await;
+        Ok(n)
+    }
+
+    /// Read from the current position to the end of the file.
+    pub async fn read_to_end(&mut self) -> Result<Vec<u8>, StorageError> {
+        let mut out = Vec::with_capacity(self.length.saturating_sub(self.position) as usize);
+        let mut buf = vec![0u8; self.options.block_size as usize];
+        loop {
+            let n = self.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(out);
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    /// Move to `position`, clamped to the length, and return the new position.
+    ///
+    /// Cached blocks are kept; background fetches outside the new read-ahead window
+    /// are cancelled and pacing restarts.
+    pub fn seek(&mut self, position: u64) -> u64 {
+        self.position = position.min(self.length);
+        let (first, last) = self.window();
+        self.pending.retain(|index, handle| {
+            let keep = (first..=last).contains(index) && !handle.is_finished();
+            if !keep {
+                handle.abort();
+            }
+            keep
+        });
+        self.pacing = (Instant::now(), 0);
+        self.schedule_read_ahead();
+        self.position
+    }
+
+    pub fn status(&self) -> BufferStatus {
+        let block_size = self.options.block_size;
+        let blocks = self.lock_blocks();
+        let mut buffered_ahead = 0;
+        let mut index = self.position / block_size;
+        while let Some(block) = blocks.get(&index) {
+            let block_start = index * block_size;
+            let skip = self.position.saturating_sub(block_start);
+            buffered_ahead += (block.len() as u64).saturating_sub(skip);
+            index += 1;
+        }
+        BufferStatus {
+            position: self.position,
+            length: self.length,
+            buffered_ahead: buffered_ahead.min(self.length - self.position),
+            fetching: self.pending.values().filter(|h| !h.is_finished()).count(),
+        }
+    }
+
+    fn lock_blocks(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Vec<u8>>> {
+        self.blocks.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn block_count(&self) -> u64 {
+        self.length.div_ceil(self.options.block_size)
+    }
+
+    /// Block indices of the current block and its read-ahead, clamped to the file
+    fn window(&self) -> (u64, u64) {
+        let first = self.position / self.options.block_size;
+        let last =
+            (first + self.options.read_ahead as u64).min(self.block_count().saturating_sub(1));
+        (first, last)
+    }
+
+    fn block_range(&self, index: u64) -> std::ops::Range<u64> {
+        let start = index * self.options.block_size;
+        start..start.saturating_add(self.options.block_size).min(self.length)
+    }
+
+    async fn ensure_block(&mut self, index: u64) -> Result<(), StorageError> {
+        if self.lock_blocks().contains_key(&index) {
+            return Ok(());
+        }
+        if let Some(handle) = self.pending.remove(&index) {
+            // A failed or cancelled background fetch falls through to a direct read,
+            // which reports the error
+            let _ = handle.await;
+            if self.lock_blocks().contains_key(&index) {
+                return Ok(());
+            }
+        }
+        let opts = CallOptions::default().with_priority(CallPriority::Interactive);
+        let data = self
+            .storage
+            .read_range_opts(&self.path, self.block_range(index), &opts)
+            .await?;
+        self.lock_blocks().insert(index, data);
+        Ok(())
+    }
+
+    fn schedule_read_ahead(&mut self) {
+        self.pending.retain(|_, handle| !handle.is_finished());
+        if self.position >= self.length {
+            return;
+        }
+        let (first, last) = self.window();
+        for index in first + 1..=last {
+            if self.pending.contains_key(&index) || self.lock_blocks().contains_key(&index) {
+                continue;
+            }
+            let storage = self.storage.clone();
+            let path = self.path.clone();
+            let range = self.block_range(index);
+            let blocks = self.blocks.clone();
+            let handle = tokio::spawn(async move {
+                let opts = CallOptions::default().with_priority(CallPriority::Background);
+                if let Ok(data) = storage.read_range_opts(&path, range, &opts).await {
+                    blocks
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .insert(index, data);
+                }
+            });
+            self.pending.insert(index, handle);
+        }
+    }
+
+    fn maybe_prefetch_next(&mut self) {
+        if self.prefetched.is_some()
+            || self.length - self.position > self.options.prefetch_next_within
+        {
+            return;
+        }
+        let Some(next) = self.next.clone() else {
+            return;
+        };
+        let storage = self.storage.clone();
+        let block_size = self.options.block_size;
+        let blocks_wanted = self.options.read_ahead as u64 + 1;
+        self.prefetched = Some(tokio::spawn(async move {
+            let opts = CallOptions::default().with_priority(CallPriority::Background);
+            let meta = storage.stat_opts(&next, &opts).await?;
+            let length = meta.size_bytes.ok_or(StorageError::UnsupportedFeature(
+                "streaming without a known length",
+            ))?;
+            let mut blocks = HashMap::new();
+            for index in 0..blocks_wanted.min(length.div_ceil(block_size)) {
+                let start = index * block_size;
+                let range = start..start.saturating_add(block_size).min(length);
+                blocks.insert(index, storage.read_range_opts(&next, range, &opts).await?);
+            }
+            Ok((length, blocks))
+        }));
+    }
+
+    /// Drop the blocks farthest from the read position until the cache fits, never
+    /// touching the read-ahead window.
+    fn evict(&mut self) {
+        let (first, last) = self.window();
+        let limit = self.options.cache_blocks;
+        let mut blocks = self.blocks.lock().unwrap_or_else(|e| e.into_inner());
+        if blocks.len() <= limit {
+            return;
+        }
+        let mut candidates: Vec<u64> = blocks
+            .keys()
+            .copied()
+            .filter(|index| !(first..=last).contains(index))
+            .collect();
+        candidates.sort_by_key(|index| std::cmp::Reverse(index.abs_diff(first)));
+        let excess = blocks.len() - limit;
+        for index in candidates.into_iter().take(excess) {
+            blocks.remove(&index);
+        }
+    }
+
+    async fn pace(&mut self, delivered: u64) {
+        let Some(rate) = self.options.realtime_rate else {
+            return;
+        };
+        self.pacing.1 += delivered;
+        let burst = self.options.block_size * self.options.read_ahead as u64;
+        let (started, total) = self.pacing;
+        if total > burst {
+            let due = started + Duration::from_secs_f64((total - burst) as f64 / rate as f64);
+            tokio::time::sleep_until(due).await;
+        }
+    }
+}
+
+impl Drop for MediaStream {
+    fn drop(&mut self) {
+        for handle in self.pending.values() {
+            handle.abort();
+        }
+        if let Some(handle) = &self.prefetched {
+            handle.abort();
+        }
+    }
+}
+
+/// The audio file after `track` in its directory, by name, which is album order for
+/// the usual `NN - Title` naming.
+pub async fn next_in_album(
+    storage: &dyn Storage,
+    track: &UniversalPath,
+) -> Result<Option<UniversalPath>, StorageError> {
+    let Some(album) = track.parent() else {
+        return Ok(None);
+    };
+    let mut tracks: Vec<UniversalPath> = storage
+        .list(&album)
+        .await?
+        .into_iter()
+        .filter(|p| !p.is_dir_hint())
+        .filter(|p| {
+            p.extension()
+                .is_some_and(|ext| AUDIO_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+        })
+        .collect();
+    tracks.sort_by(|a, b| a.last_segment().cmp(&b.last_segment()));
+    let current = track.last_segment();
+    Ok(tracks.into_iter().find(|p| p.last_segment() > current))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{EntryMetadata, LocalStorage, StorageBackend, StorageCapabilities};
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Local storage that counts ranged reads
+    #[derive(Default)]
+    struct Counting {
+        inner: LocalStorage,
+        ranged_reads: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Storage for Counting {
+        fn backend(&self) -> StorageBackend {
+            self.inner.backend()
+        }
+
+        fn capabilities(&self) -> StorageCapabilities {
+            self.inner.capabilities()
+        }
+
+        async fn stat_opts(
+            &self,
+            path: &UniversalPath,
+            opts: &CallOptions,
+        ) -> Result<EntryMetadata, StorageError> {
+            self.inner.stat_opts(path, opts).await
+        }
+
+        async fn read_opts(
+            &self,
+            path: &UniversalPath,
+            opts: &CallOptions,
+        ) -> Result<Vec<u8>, StorageError> {
+            self.inner.read_opts(path, opts).await
+        }
+
+        async fn read_range_opts(
+            &self,
+            path: &UniversalPath,
+            range: std::ops::Range<u64>,
+            opts: &CallOptions,
+        ) -> Result<Vec<u8>, StorageError> {
+            self.ranged_reads.fetch_add(1, Ordering::SeqCst);
+            self.inner.read_range_opts(path, range, opts).await
+        }
+
+        async fn list_opts(
+            &self,
+            path: &UniversalPath,
+            opts: &CallOptions,
+        ) -> Result<Vec<UniversalPath>, StorageError> {
+            self.inner.list_opts(path, opts).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reads_seeks_and_reuses_blocks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let file = dir.join("track.flac");
+        let content: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&file, &content).unwrap();
+
+        let storage = Arc::new(Counting::default());
+        let options = MediaStreamOptions {
+            block_size: 1024,
+            read_ahead: 2,
+            cache_blocks: 16,
+            realtime_rate: None,
+            prefetch_next_within: 0,
+        };
+        let mut stream = MediaStream::open(
+            storage.clone(),
+            UniversalPath::local(file.to_str().unwrap()),
+            options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(stream.length(), 10_000);
+        assert!(stream.status().is_buffering());
+
+        assert_eq!(stream.read_to_end().await.unwrap(), content);
+        assert!(stream.status().at_end());
+        let fetched = storage.ranged_reads.load(Ordering::SeqCst);
+        assert_eq!(fetched, 10);
+
+        assert_eq!(stream.seek(5000), 5000);
+        let status = stream.status();
+        assert_eq!(status.buffered_ahead, 5000);
+        let mut buf = [0u8; 100];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 100);
+        assert_eq!(&buf[..], &content[5000..5100]);
+        assert_eq!(stream.seek(u64::MAX), 10_000);
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+        assert_eq!(storage.ranged_reads.load(Ordering::SeqCst), fetched);
+    }
+
+    #[tokio::test]
+    async fn test_prefetches_next_track_in_album() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        std::fs::write(dir.join("01 - Hunter.flac"), vec![1u8; 3000]).unwrap();
+        std::fs::write(dir.join("02 - Jóga.flac"), vec![2u8; 1500]).unwrap();
+        std::fs::write(dir.join("cover.jpg"), b"jpeg").unwrap();
+
+        let storage = Arc::new(Counting::default());
+        let first = UniversalPath::local(dir.join("01 - Hunter.flac").to_str().unwrap());
+        let second = next_in_album(storage.as_ref(), &first)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(second.last_segment(), Some("02 - Jóga.flac"));
+        assert_eq!(
+            next_in_album(storage.as_ref(), &second).await.unwrap(),
+            None
+        );
+
+        let options = MediaStreamOptions {
+            block_size: 1000,
+            read_ahead: 1,
+            cache_blocks: 4,
+            realtime_rate: None,
+            prefetch_next_within: 1000,
+        };
+        let mut stream = MediaStream::open(storage.clone(), first, options)
+            .await
+            .unwrap();
+        stream.set_next(Some(second));
+        let mut buf = [0u8; 1000];
+        stream.read(&mut buf).await.unwrap();
+        assert!(!stream.is_prefetching_next());
+        stream.read(&mut buf).await.unwrap();
+        assert!(stream.is_prefetching_next());
+        stream.read_to_end().await.unwrap();
+
+        let mut next = stream.open_next().await.unwrap().unwrap();
+        let reads = storage.ranged_reads.load(Ordering::SeqCst);
+        assert_eq!(next.read_to_end().await.unwrap(), vec![2u8; 1500]);
+        assert_eq!(storage.ranged_reads.load(Ordering::SeqCst), reads);
+        assert!(stream.open_next().await.unwrap().is_none());
+    }
+}
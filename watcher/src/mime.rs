@@ -0,0 +1,193 @@
+use crate::universal_path::UniversalPath;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// Content type served for extensions nobody registered.
+pub const FALLBACK_CONTENT_TYPE: &str = "application/octet-stream";
+
+const DEFAULT_TYPES: &[(&str, &str)] = &[
+    ("aac", "audio/aac"),
+    ("aif", "audio/aiff"),
+    ("aiff", "audio/aiff"),
+    ("alac", "audio/mp4"),
+    ("ape", "audio/ape"),
+    ("dff", "audio/x-dff"),
+    ("dsf", "audio/x-dsf"),
+    ("flac", "audio/flac"),
+    ("m4a", "audio/mp4"),
+    ("mp3", "audio/mpeg"),
+    ("mpc", "audio/musepack"),
+    ("ogg", "audio/ogg"),
+    ("opus", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("wma", "audio/x-ms-wma"),
+    ("wv", "audio/wavpack"),
+    ("m3u", "audio/x-mpegurl"),
+    ("m3u8", "application/vnd.apple.mpegurl"),
+    ("pls", "audio/x-scpls"),
+    ("cue", "application/x-cue"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("png", "image/png"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("log", "text/plain; charset=utf-8"),
+    ("nfo", "text/plain; charset=utf-8"),
+    ("lrc", "text/plain; charset=utf-8"),
+    ("json", "application/json"),
+    ("pdf", "application/pdf"),
+];
+
+/// Executables and scripts, which a music library has no business serving.
+const DEFAULT_BLOCKED: &[&str] = &["bat", "cmd", "com", "dll", "exe", "msi", "ps1", "scr"];
+
+/// What the gateway does with a file of a given extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MimeHandler {
+    /// Served for display or playback in place
+    Inline,
+    /// Served with `Content-Disposition: attachment`
+    Download,
+    /// Refused
+    Block,
+}
+
+/// User overrides, keyed by lowercase extension without the dot.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MimeConfig {
+    /// Content type per extension, e.g. `cue = "text/plain"`
+    pub types: BTreeMap<String, String>,
+    /// Handler per extension, e.g. `exe = "block"`
+    pub handlers: BTreeMap<String, MimeHandler>,
+}
+
+/// Resolved answer for one path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MimeMapping<'a> {
+    pub content_type: &'a str,
+    pub handler: MimeHandler,
+}
+
+/// Extension-to-content-type and handler table for serving library files.
+///
+/// Registered types are served inline and unknown extensions as downloads of
+/// `application/octet-stream`; explicit handlers override both.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MimeRegistry {
+    types: HashMap<String, String>,
+    handlers: HashMap<String, MimeHandler>,
+}
+
+impl Default for MimeRegistry {
+    fn default() -> Self {
+        MimeRegistry {
+            types: DEFAULT_TYPES
+                .iter()
+                .map(|(ext, ty)| (ext.to_string(), ty.to_string()))
+                .collect(),
+            handlers: DEFAULT_BLOCKED
+                .iter()
+                .map(|ext| (ext.to_string(), MimeHandler::Block))
+                .collect(),
+        }
+    }
+}
+
+impl MimeRegistry {
+    /// The built-in defaults
+    pub fn new() -> Self {
+        MimeRegistry::default()
+    }
+
+    /// Defaults with `config` layered on top
+    pub fn from_config(config: &MimeConfig) -> Self {
+        let mut registry = MimeRegistry::new();
+        for (ext, ty) in &config.types {
+            registry.register_type(ext, ty);
+        }
+        for (ext, handler) in &config.handlers {
+            registry.register_handler(ext, *handler);
+        }
+        registry
+    }
+
+    pub fn register_type(&mut self, ext: &str, content_type: &str) {
+        self.types.insert(normalize(ext), content_type.to_string());
+    }
+
+    pub fn register_handler(&mut self, ext: &str, handler: MimeHandler) {
+        self.handlers.insert(normalize(ext), handler);
+    }
+
+    pub fn with_type(mut self, ext: &str, content_type: &str) -> Self {
+        self.register_type(ext, content_type);
+        self
+    }
+
+    pub fn with_handler(mut self, ext: &str, handler: MimeHandler) -> Self {
+        self.register_handler(ext, handler);
+        self
+    }
+
+    /// Mapping for an extension given with or without the leading dot
+    pub fn lookup(&self, ext: Option<&str>) -> MimeMapping<'_> {
+        let ext = ext.map(normalize);
+        let content_type = ext.as_ref().and_then(|e| self.types.get(e));
+        let handler = ext
+            .as_ref()
+            .and_then(|e| self.handlers.get(e))
+            .copied()
+            .unwrap_or(match content_type {
+                Some(_) => MimeHandler::Inline,
+                None => MimeHandler::Download,
+            });
+        MimeMapping {
+            content_type: content_type.map_or(FALLBACK_CONTENT_TYPE, |t| t.as_str()),
+            handler,
+        }
+    }
+
+    pub fn lookup_path(&self, path: &UniversalPath) -> MimeMapping<'_> {
+        self.lookup(path.extension())
+    }
+}
+
+fn normalize(ext: &str) -> String {
+    ext.trim_start_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults() {
+        let registry = MimeRegistry::new();
+        let flac = registry.lookup_path(&UniversalPath::local("/music/a.FLAC"));
+        assert_eq!(flac.content_type, "audio/flac");
+        assert_eq!(flac.handler, MimeHandler::Inline);
+        assert_eq!(registry.lookup(Some(".exe")).handler, MimeHandler::Block);
+        let unknown = registry.lookup(Some("xyz"));
+        assert_eq!(unknown.content_type, FALLBACK_CONTENT_TYPE);
+        assert_eq!(unknown.handler, MimeHandler::Download);
+        assert_eq!(registry.lookup(None).handler, MimeHandler::Download);
+    }
+
+    #[test]
+    fn test_config_overrides() {
+        let config: MimeConfig = serde_json::from_str(
+            r#"{"types": {"cue": "text/plain", ".SFV": "text/plain"},
+                "handlers": {"exe": "download", "m3u": "block"}}"#,
+        )
+        .unwrap();
+        let registry = MimeRegistry::from_config(&config);
+        assert_eq!(registry.lookup(Some("cue")).content_type, "text/plain");
+        assert_eq!(registry.lookup(Some("sfv")).handler, MimeHandler::Inline);
+        assert_eq!(registry.lookup(Some("exe")).handler, MimeHandler::Download);
+        assert_eq!(registry.lookup(Some("m3u")).handler, MimeHandler::Block);
+        assert_eq!(registry.lookup(Some("msi")).handler, MimeHandler::Block);
+    }
+}
@@ -0,0 +1,288 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::{sync::Mutex, time::Instant};
+
+pub const MUSICBRAINZ_API: &str = "https://musicbrainz.org/ws/2";
+
+/// MusicBrainz asks anonymous clients to stay at or below one request per second.
+pub const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Error)]
+pub enum MusicBrainzError {
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error("MusicBrainz answered {0}")]
+    Status(u16),
+    #[error("unexpected MusicBrainz response: {0}")]
+    Decode(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// What we know about a track from its tags.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackQuery {
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub duration: Option<Duration>,
+}
+
+impl TrackQuery {
+    /// Lucene query for the recording search endpoint. Durations match within ±3s.
+    pub fn to_lucene(&self) -> String {
+        let mut clauses = vec![format!("recording:{}", quote(&self.title))];
+        if let Some(artist) = &self.artist {
+            clauses.push(format!("artist:{}", quote(artist)));
+        }
+        if let Some(album) = &self.album {
+            clauses.push(format!("release:{}", quote(album)));
+        }
+        if let Some(duration) = self.duration {
+            let ms = duration.as_millis() as u64;
+            clauses.push(format!(
+                "dur:[{} TO {}]",
+                ms.saturating_sub(3000),
+                ms + 3000
+            ));
+        }
+        clauses.join(" AND ")
+    }
+}
+
+fn quote(s: &str) -> String {
+    let escaped = s.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("\"{escaped}\"")
+}
+
+/// A candidate recording with the releases it appears on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordingMatch {
+    pub recording_id: String,
+    pub title: String,
+    /// Search relevance, 0-100
+    pub score: u8,
+    pub artist: Option<String>,
+    pub release_ids: Vec<String>,
+    pub length: Option<Duration>,
+}
+
+#[derive(Deserialize)]
+struct SearchResponse {
+    #[serde(default)]
+    recordings: Vec<RawRecording>,
+}
+
+#[derive(Deserialize)]
+struct RawRecording {
+    id: String,
+    title: String,
+    #[serde(default)]
+    score: u8,
+    length: Option<u64>,
+    #[serde(default, rename = "artist-credit")]
+    artist_credit: Vec<RawCredit>,
+    #[serde(default)]
+    releases: Vec<RawRelease>,
+}
+
+#[derive(Deserialize)]
+struct RawCredit {
+    name: String,
+    #[serde(default)]
+    joinphrase: String,
+}
+
+#[derive(Deserialize)]
+struct RawRelease {
+    id: String,
+}
+
+fn parse_search(body: &str) -> Result<Vec<RecordingMatch>, MusicBrainzError> {
+    let resp: SearchResponse = serde_json::from_str(body)?;
+    Ok(resp
+        .recordings
+        .into_iter()
+        .map(|r| RecordingMatch {
+            recording_id: r.id,
+            title: r.title,
+            score: r.score,
+            artist: (!r.artist_credit.is_empty()).then(|| {
+                r.artist_credit
+                    .iter()
+                    .map(|c| format!("{}{}", c.name, c.joinphrase))
+                    .collect()
+            }),
+            release_ids: r.releases.into_iter().map(|rel| rel.id).collect(),
+            length: r.length.map(Duration::from_millis),
+        })
+        .collect())
+}
+
+/// Rate-limited, caching MusicBrainz recording search.
+///
+/// Results are cached by query, including empty ones, so rescans do not hit the
+/// service again for tracks already looked up. With a cache file the cache persists.
+pub struct MusicBrainzClient {
+    base_url: String,
+    user_agent: String,
+    min_interval: Duration,
+    client: reqwest::Client,
+    last_request: Mutex<Option<Instant>>,
+    cache: Mutex<HashMap<String, Vec<RecordingMatch>>>,
+    cache_path: Option<PathBuf>,
+}
+
+impl MusicBrainzClient {
+    /// `contact` goes into the User-Agent as MusicBrainz requires (an email or URL).
+    pub fn new(contact: &str) -> Self {
+        MusicBrainzClient {
+            base_url: MUSICBRAINZ_API.to_string(),
+            user_agent: format!("otolith/{} ( {} )", env!("CARGO_PKG_VERSION"), contact),
+            min_interval: DEFAULT_MIN_INTERVAL,
+            client: reqwest::Client::new(),
+            last_request: Mutex::new(None),
+            cache: Mutex::new(HashMap::new()),
+            cache_path: None,
+        }
+    }
+
+    /// Point at a mirror or local MusicBrainz server
+    pub fn with_base_url<S: Into<String>>(mut self, base_url: S) -> Self {
+        self.base_url = base_url.into();
+        self
+    }
+
+    pub fn with_min_interval(mut self, min_interval: Duration) -> Self {
+        self.min_interval = min_interval;
+        self
+    }
+
+    /// Load and persist the lookup cache at `path`
+    pub fn with_cache_file<P: AsRef<Path>>(mut self, path: P) -> Result<Self, MusicBrainzError> {
+        let path = path.as_ref().to_path_buf();
+        match std::fs::read(&path) {
+            Ok(bytes) => *self.cache.get_mut() = serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        self.cache_path = Some(path);
+        Ok(self)
+    }
+
+    /// Candidate recordings for the query, best first
+    pub async fn lookup(
+        &self,
+        query: &TrackQuery,
+    ) -> Result<Vec<RecordingMatch>, MusicBrainzError> {
+        let lucene = query.to_lucene();
+        if let Some(hit) = self.cache.lock().await.get(&lucene) {
+            return Ok(hit.clone());
+        }
+
+        self.wait_for_slot().await;
+        let resp = self
+            .client
+            .get(format!("{}/recording", self.base_url.trim_end_matches('/')))
+            .header(reqwest::header::USER_AGENT, &self.user_agent)
+            .query(&[("query", lucene.as_str()), ("fmt", "json"), ("limit", "10")])
+            .send()
+            .await?;
+        if !resp.status().is_success() {
+            return Err(MusicBrainzError::Status(resp.status().as_u16()));
+        }
+        let mut matches = parse_search(&resp.text().await?)?;
+        matches.sort_by_key(|m| std::cmp::Reverse(m.score));
+
+        let mut cache = self.cache.lock().await;
+        cache.insert(lucene, matches.clone());
+        if let Some(path) = &self.cache_path {
+            let tmp = path.with_extension("tmp");
+            std::fs::write(&tmp, serde_json::to_vec(&*cache)?)?;
+            std::fs::rename(&tmp, path)?;
+        }
+        Ok(matches)
+    }
+
+    /// The top candidate if it scores at least `min_score`
+    pub async fn best_match(
+        &self,
+        query: &TrackQuery,
+        min_score: u8,
+    ) -> Result<Option<RecordingMatch>, MusicBrainzError> {
+        Ok(self
+            .lookup(query)
+            .await?
+            .into_iter()
+            .next()
+            .filter(|m| m.score >= min_score))
+    }
+
+    async fn wait_for_slot(&self) {
+        let mut last = self.last_request.lock().await;
+        if let Some(prev) = *last {
+            tokio::time::sleep_until(prev + self.min_interval).await;
+        }
+        *last = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lucene_query() {
+        let query = TrackQuery {
+            title: "Jóga".to_string(),
+            artist: Some("Björk".to_string()),
+            album: Some("Say \"Hi\"".to_string()),
+            duration: Some(Duration::from_millis(305_000)),
+        };
+        assert_eq!(
+            query.to_lucene(),
+            r#"recording:"Jóga" AND artist:"Björk" AND release:"Say \"Hi\"" AND dur:[302000 TO 308000]"#
+        );
+        let bare = TrackQuery {
+            title: "Intro".to_string(),
+            ..TrackQuery::default()
+        };
+        assert_eq!(bare.to_lucene(), r#"recording:"Intro""#);
+    }
+
+    #[test]
+    fn test_parse_search_response() {
+        let body = r#"{
+            "created": "2024-01-01T00:00:00Z",
+            "count": 1,
+            "recordings": [{
+                "id": "rec-1",
+                "score": 97,
+                "title": "Jóga",
+                "length": 305000,
+                "artist-credit": [
+                    { "name": "Björk", "joinphrase": " & " },
+                    { "name": "Someone" }
+                ],
+                "releases": [{ "id": "rel-1", "title": "Homogenic" }, { "id": "rel-2" }]
+            }, {
+                "id": "rec-2",
+                "title": "Jóga (live)"
+            }]
+        }"#;
+        let matches = parse_search(body).unwrap();
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].recording_id, "rec-1");
+        assert_eq!(matches[0].score, 97);
+        assert_eq!(matches[0].artist.as_deref(), Some("Björk & Someone"));
+        assert_eq!(matches[0].release_ids, vec!["rel-1", "rel-2"]);
+        assert_eq!(matches[0].length, Some(Duration::from_secs(305)));
+        assert_eq!(matches[1].artist, None);
+        assert!(parse_search("not json").is_err());
+    }
+}
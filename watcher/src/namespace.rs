@@ -0,0 +1,234 @@
+use crate::index::IndexEntry;
+use crate::storage::StorageBackend;
+use crate::universal_path::UniversalPath;
+use serde::{Deserialize, Serialize};
+
+/// Scheme of the logical paths a namespace's files are known by
+pub const LIBRARY_SCHEME: &str = "library";
+
+/// What a layer's files are to the namespace.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LayerRole {
+    /// Its files are the namespace's, shadowing those at the same logical path in
+    /// overlay layers after it
+    #[default]
+    Overlay,
+    /// A copy of the overlay layers, resolved to only for files none of them has
+    Mirror,
+}
+
+/// One root of a namespace.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Layer {
+    pub root: UniversalPath,
+    /// Where the root's files appear in the namespace, `/`-separated; empty for its
+    /// top
+    #[serde(default)]
+    pub at: String,
+    #[serde(default)]
+    pub role: LayerRole,
+}
+
+impl Layer {
+    fn at_segments(&self) -> impl Iterator<Item = &str> {
+        self.at.split('/').filter(|s| !s.is_empty())
+    }
+}
+
+/// Many roots seen as one library, each file known by a logical path such as
+/// `library://main/Björk/Post/01.flac` whichever root, or roots, hold it.
+///
+/// Roots are layered: where several hold a file at the same logical path, overlays
+/// are preferred in the order given, then mirrors in the order given.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Namespace {
+    pub name: String,
+    layers: Vec<Layer>,
+}
+
+/// A file of a namespace as an index query finds it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FederatedEntry<'a> {
+    pub logical: UniversalPath,
+    /// The copy in the most preferred layer
+    pub entry: &'a IndexEntry,
+    /// Every indexed copy, most preferred first
+    pub copies: Vec<UniversalPath>,
+}
+
+impl Namespace {
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Namespace {
+            name: name.into(),
+            layers: Vec::new(),
+        }
+    }
+
+    /// Add `root` as a layer, its files appearing at `at` in the namespace
+    pub fn with_layer(mut self, root: &UniversalPath, at: &str, role: LayerRole) -> Self {
+        self.layers.push(Layer {
+            root: root.as_dir(),
+            at: at.to_string(),
+            role,
+        });
+        self
+    }
+
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    /// `library://<name>/`, the root the namespace's files are shown under
+    pub fn root(&self) -> UniversalPath {
+        let backend = StorageBackend::Other(LIBRARY_SCHEME.to_string());
+        UniversalPath::from_segments(backend, Some(self.name.clone()), [""; 0]).as_dir()
+    }
+
+    /// The layers, most preferred first
+    fn preferred(&self) -> impl Iterator<Item = &Layer> {
+        let overlays = self.layers.iter().filter(|l| l.role == LayerRole::Overlay);
+        let mirrors = self.layers.iter().filter(|l| l.role == LayerRole::Mirror);
+        overlays.chain(mirrors)
+    }
+
+    /// The logical path of the file at `physical`, and how preferred the layer it
+    /// is found through is, 0 being most. None if no layer holds it.
+    pub(crate) fn locate(&self, physical: &UniversalPath) -> Option<(usize, UniversalPath)> {
+        self.preferred().enumerate().find_map(|(rank, layer)| {
+            let below = physical.relative_to(&layer.root)?;
+            let segments: Vec<&str> = layer
+                .at_segments()
+                .chain(below.iter().map(String::as_str))
+                .collect();
+            let logical = self.root().join_all(&segments);
+            Some((
+                rank,
+                if physical.is_dir_hint() {
+                    logical.as_dir()
+                } else {
+                    logical
+                },
+            ))
+        })
+    }
+
+    /// The logical path of the file at `physical`, if a layer holds it
+    pub fn logical_path(&self, physical: &UniversalPath) -> Option<UniversalPath> {
+        self.locate(physical).map(|(_, logical)| logical)
+    }
+
+    /// Where the file at `logical` would be in each layer mounted at or above it,
+    /// most preferred first. Empty if `logical` is not in this namespace.
+    pub fn physical_paths(&self, logical: &UniversalPath) -> Vec<UniversalPath> {
+        let Some(below) = logical.relative_to(&self.root()) else {
+            return Vec::new();
+        };
+        self.preferred()
+            .filter_map(|layer| {
+                let mut rest = below.iter();
+                for segment in layer.at_segments() {
+                    if rest.next().map(String::as_str) != Some(segment) {
+                        return None;
+                    }
+                }
+                let rest: Vec<&str> = rest.map(String::as_str).collect();
+                let physical = layer.root.join_all(&rest);
+                Some(if logical.is_dir_hint() || rest.is_empty() {
+                    physical.as_dir()
+                } else {
+                    physical.as_file()
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{Index, IndexQuery};
+    use crate::root::RootConfig;
+    use crate::storage::{MemoryStorage, Storage};
+
+    #[tokio::test]
+    async fn test_federated_roots() {
+        let storage = MemoryStorage::new();
+        let nas = UniversalPath::from_uri_str("mem://nas/music/").unwrap();
+        let laptop = UniversalPath::from_uri_str("mem://laptop/rips/").unwrap();
+        let backup = UniversalPath::from_uri_str("mem://usb/backup/").unwrap();
+        let podcasts = UniversalPath::from_uri_str("mem://nas/podcasts/").unwrap();
+        for (root, file, data) in [
+            (&nas, "Björk/Post/01.flac", "army of me"),
+            (&nas, "Björk/Post/02.flac", "hyper-ballad"),
+            // Re-ripped on the laptop, which shadows the NAS copy
+            (&laptop, "Björk/Post/01.flac", "army of me (2024)"),
+            (&backup, "Björk/Post/02.flac", "hyper-ballad"),
+            (&backup, "Portishead/Dummy/01.flac", "mysterons"),
+            (&podcasts, "ep1.mp3", "hello"),
+        ] {
+            let path = root.join_all(&file.split('/').collect::<Vec<_>>());
+            storage.create_dir(&path.parent().unwrap()).await.unwrap();
+            storage.write(&path, data.as_bytes()).await.unwrap();
+        }
+        let mut index = Index::new();
+        for root in [&nas, &laptop, &backup, &podcasts] {
+            index
+                .scan(&storage, &RootConfig::new(root.clone()))
+                .await
+                .unwrap();
+        }
+
+        let main = Namespace::new("main")
+            .with_layer(&backup, "", LayerRole::Mirror)
+            .with_layer(&laptop, "", LayerRole::Overlay)
+            .with_layer(&nas, "", LayerRole::Overlay)
+            .with_layer(&podcasts, "Podcasts", LayerRole::Overlay);
+        assert!(index.federate(main).is_none());
+
+        let first = UniversalPath::from_uri_str("library://main/Bj%C3%B6rk/Post/01.flac").unwrap();
+        let resolved = index.resolve(&first).unwrap();
+        assert_eq!(
+            resolved.path,
+            laptop.join_all(&["Björk", "Post", "01.flac"])
+        );
+        // With the laptop away, the NAS copy is next best
+        let resolved = index
+            .resolve_where(&first, |path| path.relative_to(&laptop).is_none())
+            .unwrap();
+        assert_eq!(resolved.path, nas.join_all(&["Björk", "Post", "01.flac"]));
+        let episode = podcasts.join("ep1.mp3");
+        assert_eq!(
+            index.logical_paths(&episode),
+            [UniversalPath::from_uri_str("library://main/Podcasts/ep1.mp3").unwrap()]
+        );
+
+        let all = index.query_namespace("main", &IndexQuery::new()).unwrap();
+        let logical: Vec<_> = all.iter().map(|f| f.logical.path()).collect();
+        assert_eq!(
+            logical,
+            [
+                "/Björk/Post/01.flac",
+                "/Björk/Post/02.flac",
+                "/Podcasts/ep1.mp3",
+                "/Portishead/Dummy/01.flac"
+            ]
+        );
+        // Only on the backup, so resolved there
+        assert_eq!(all[3].entry.path.host(), Some("usb"));
+        assert_eq!(
+            all[1].copies,
+            [
+                nas.join_all(&["Björk", "Post", "02.flac"]),
+                backup.join_all(&["Björk", "Post", "02.flac"])
+            ]
+        );
+
+        let post = UniversalPath::from_uri_str("library://main/Bj%C3%B6rk/").unwrap();
+        let query = IndexQuery::new().under(&post).with_size(Some(13), None);
+        let found = index.query_namespace("main", &query).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].entry.size_bytes, Some(17));
+        assert!(index.query_namespace("other", &query).is_none());
+    }
+}
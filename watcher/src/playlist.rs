@@ -0,0 +1,433 @@
+use crate::{
+    storage::{StorageBackend, StorageError},
+    universal_path::UniversalPath,
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PlaylistError {
+    #[error("cannot resolve playlist entry {0:?}")]
+    InvalidEntry(String),
+    #[error("cannot sign {0}: {1}")]
+    Signing(String, StorageError),
+}
+
+/// One track reference as written in the playlist file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlaylistEntry {
+    /// Path or URL exactly as it appears in the file
+    pub location: String,
+    /// `#EXTINF` duration in seconds; -1 means unknown
+    pub duration: Option<i64>,
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Playlist {
+    pub entries: Vec<PlaylistEntry>,
+}
+
+impl Playlist {
+    /// Parse plain or extended M3U/M3U8. Comments other than `#EXTINF` are dropped.
+    pub fn parse_m3u(text: &str) -> Self {
+        let mut entries = Vec::new();
+        let mut pending: Option<(Option<i64>, Option<String>)> = None;
+        for line in text.trim_start_matches('\u{feff}').lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(info) = line.strip_prefix("#EXTINF:") {
+                let (duration, title) = info.split_once(',').unwrap_or((info, ""));
+                let title = title.trim();
+                pending = Some((
+                    duration.trim().parse().ok(),
+                    (!title.is_empty()).then(|| title.to_string()),
+                ));
+            } else if !line.starts_with('#') {
+                let (duration, title) = pending.take().unwrap_or_default();
+                entries.push(PlaylistEntry {
+                    location: line.to_string(),
+                    duration,
+                    title,
+                });
+            }
+        }
+        Playlist { entries }
+    }
+
+    /// The resolvable entry following the first one that resolves to `current`
+    pub fn track_after(
+        &self,
+        playlist_dir: &UniversalPath,
+        current: &UniversalPath,
+    ) -> Option<UniversalPath> {
+        let mut tracks = self
+            .entries
+            .iter()
+            .filter_map(|entry| resolve_entry(&entry.location, playlist_dir).ok());
+        tracks.find(|track| track == current)?;
+        tracks.next()
+    }
+
+    /// Extended M3U text with an `#EXTINF` line for entries that have a duration or title
+    pub fn to_m3u(&self) -> String {
+        let mut out = String::from("#EXTM3U\n");
+        for entry in &self.entries {
+            if entry.duration.is_some() || entry.title.is_some() {
+                out.push_str(&format!(
+                    "#EXTINF:{},{}\n",
+                    entry.duration.unwrap_or(-1),
+                    entry.title.as_deref().unwrap_or("")
+                ));
+            }
+            out.push_str(&entry.location);
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Resolve a playlist entry to a path. Relative entries are taken against
+/// `playlist_dir`; absolute paths without a drive or UNC host stay on the playlist's
+/// own backend.
+pub fn resolve_entry(
+    location: &str,
+    playlist_dir: &UniversalPath,
+) -> Result<UniversalPath, PlaylistError> {
+    let invalid = || PlaylistError::InvalidEntry(location.to_string());
+    if location.contains("://") || location.starts_with("file:") {
+        return UniversalPath::from_uri_str(location).map_err(|_| invalid());
+    }
+    if let Some(unc) = location.strip_prefix("\\\\") {
+        let mut parts = unc.split(['\\', '/']).filter(|s| !s.is_empty());
+        let host = parts.next().ok_or_else(invalid)?;
+        return Ok(UniversalPath::from_segments(
+            StorageBackend::NetworkDrive,
+            Some(host),
+            parts,
+        ));
+    }
+    if location.as_bytes().get(1) == Some(&b':') {
+        return Ok(UniversalPath::local(location));
+    }
+
+    let segments = location.split(['/', '\\']).filter(|s| !s.is_empty());
+    if location.starts_with(['/', '\\']) {
+        let mut root = playlist_dir.clone();
+        root.path_segments.clear();
+        root.is_dir_hint = false;
+        Ok(root.join_all(&segments.collect::<Vec<_>>()))
+    } else {
+        Ok(playlist_dir
+            .as_dir()
+            .join_all(&segments.collect::<Vec<_>>()))
+    }
+}
+
+/// Replaces the `from` prefix of a path with `to`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RewriteRule {
+    pub from: UniversalPath,
+    pub to: UniversalPath,
+}
+
+/// Ordered set of prefix rewrites; the rule with the deepest matching `from` wins.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RewriteRules {
+    pub rules: Vec<RewriteRule>,
+}
+
+impl RewriteRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_rule(mut self, from: UniversalPath, to: UniversalPath) -> Self {
+        self.rules.push(RewriteRule { from, to });
+        self
+    }
+
+    /// The rewritten path, or None if no rule matches
+    pub fn apply(&self, path: &UniversalPath) -> Option<UniversalPath> {
+        self.rules
+            .iter()
+            .filter_map(|rule| Some((rule, path.relative_to(&rule.from)?)))
+            .max_by_key(|(rule, _)| rule.from.depth())
+            .map(|(rule, rest)| {
+                let mut out = rule.to.as_dir().join_all(&rest);
+                out.is_dir_hint = path.is_dir_hint() && !out.is_root();
+                out
+            })
+    }
+}
+
+/// Produces directly fetchable URLs, e.g. presigned S3 GETs, for paths it handles.
+pub trait UrlSigner: Send + Sync {
+    fn handles(&self, path: &UniversalPath) -> bool;
+    fn sign_url(&self, path: &UniversalPath, expires_in: Duration) -> Result<String, StorageError>;
+}
+
+/// How rebased entries are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum EntryStyle {
+    /// Local paths as native absolute paths, everything else as URIs
+    #[default]
+    Absolute,
+    /// Relative to the playlist's directory when on the same backend and host,
+    /// otherwise absolute
+    Relative,
+}
+
+/// Rewrites playlist entries between roots, backends and layouts.
+///
+/// Each entry is resolved against the playlist's source directory, rewritten by the
+/// most specific matching rule, and then rendered for the playlist's destination directory:
+/// through the signer if it handles the path, otherwise per `style`.
+pub struct PlaylistRebaser {
+    rules: RewriteRules,
+    style: EntryStyle,
+    signer: Option<(Box<dyn UrlSigner>, Duration)>,
+}
+
+impl PlaylistRebaser {
+    pub fn new(rules: RewriteRules, style: EntryStyle) -> Self {
+        PlaylistRebaser {
+            rules,
+            style,
+            signer: None,
+        }
+    }
+
+    /// Render paths the signer handles as signed URLs valid for `expires_in`
+    pub fn with_signer<S: UrlSigner + 'static>(mut self, signer: S, expires_in: Duration) -> Self {
+        self.signer = Some((Box::new(signer), expires_in));
+        self
+    }
+
+    /// Rebase every entry of a playlist read from `source_dir` that will be written to
+    /// `target_dir`. Titles and durations are kept.
+    pub fn rebase(
+        &self,
+        playlist: &Playlist,
+        source_dir: &UniversalPath,
+        target_dir: &UniversalPath,
+    ) -> Result<Playlist, PlaylistError> {
+        let entries = playlist
+            .entries
+            .iter()
+            .map(|entry| {
+                let resolved = resolve_entry(&entry.location, source_dir)?;
+                let rewritten = self.rules.apply(&resolved).unwrap_or(resolved);
+                Ok(PlaylistEntry {
+                    location: self.render(&rewritten, target_dir)?,
+                    ..entry.clone()
+                })
+            })
+            .collect::<Result<_, PlaylistError>>()?;
+        Ok(Playlist { entries })
+    }
+
+    fn render(
+        &self,
+        path: &UniversalPath,
+        target_dir: &UniversalPath,
+    ) -> Result<String, PlaylistError> {
+        if let Some((signer, expires_in)) = &self.signer
+            && signer.handles(path)
+        {
+            return signer
+                .sign_url(path, *expires_in)
+                .map_err(|e| PlaylistError::Signing(path.to_string(), e));
+        }
+        if self.style == EntryStyle::Relative
+            && let Some(relative) = relative_location(path, target_dir)
+        {
+            return Ok(relative);
+        }
+        absolute_location(path)
+    }
+}
+
+pub(crate) fn relative_location(path: &UniversalPath, dir: &UniversalPath) -> Option<String> {
+    let ancestor = path.common_ancestor(dir)?;
+    let is_drive = |p: &UniversalPath| p.path_segments().first().is_some_and(|s| s.ends_with(':'));
+    if ancestor.is_root() && (is_drive(path) || is_drive(dir)) {
+        return None;
+    }
+    let ups = dir.depth() - ancestor.depth();
+    let mut parts = vec![".."; ups];
+    parts.extend(
+        path.path_segments()[ancestor.depth()..]
+            .iter()
+            .map(String::as_str),
+    );
+    Some(parts.join("/"))
+}
+
+pub(crate) fn absolute_location(path: &UniversalPath) -> Result<String, PlaylistError> {
+    if path.backend() != &StorageBackend::Local {
+        // A playlist is written out and shared, so it keeps no password
+        return path
+            .without_password()
+            .to_uri()
+            .map_err(|_| PlaylistError::InvalidEntry(path.to_string()));
+    }
+    let segments = path.path_segments();
+    match segments.first() {
+        Some(drive) if drive.ends_with(':') => Ok(segments.join("\\")),
+        _ => Ok(path.path()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_write_m3u() {
+        let text = "\u{feff}#EXTM3U\r\n#EXTINF:305,Björk - Jóga\r\nHomogenic/02 Jóga.flac\r\n\r\n# a comment\r\n/music/loose.mp3\r\n";
+        let playlist = Playlist::parse_m3u(text);
+        assert_eq!(
+            playlist.entries,
+            vec![
+                PlaylistEntry {
+                    location: "Homogenic/02 Jóga.flac".to_string(),
+                    duration: Some(305),
+                    title: Some("Björk - Jóga".to_string()),
+                },
+                PlaylistEntry {
+                    location: "/music/loose.mp3".to_string(),
+                    duration: None,
+                    title: None,
+                },
+            ]
+        );
+        assert_eq!(
+            playlist.to_m3u(),
+            "#EXTM3U\n#EXTINF:305,Björk - Jóga\nHomogenic/02 Jóga.flac\n/music/loose.mp3\n"
+        );
+        assert_eq!(Playlist::parse_m3u(&playlist.to_m3u()), playlist);
+    }
+
+    #[test]
+    fn test_resolve_entry() {
+        let dir = UniversalPath::local("/music/playlists/");
+        assert_eq!(
+            resolve_entry("../Björk/song.flac", &dir).unwrap(),
+            UniversalPath::local("/music").join_all(&["Björk", "song.flac"])
+        );
+        assert_eq!(
+            resolve_entry("/other/a.mp3", &dir).unwrap(),
+            UniversalPath::local("/other/a.mp3")
+        );
+        assert_eq!(
+            resolve_entry("C:\\Music\\a.mp3", &dir).unwrap(),
+            UniversalPath::local("C:\\Music\\a.mp3")
+        );
+        let unc = resolve_entry("\\\\nas\\share\\a.mp3", &dir).unwrap();
+        assert_eq!(unc.backend(), &StorageBackend::NetworkDrive);
+        assert_eq!(unc.host(), Some("nas"));
+        assert_eq!(unc.path(), "/share/a.mp3");
+
+        let s3_dir = UniversalPath::from_uri_str("s3://bucket/lists/").unwrap();
+        assert_eq!(
+            resolve_entry("/music/a.mp3", &s3_dir).unwrap(),
+            UniversalPath::from_uri_str("s3://bucket/music/a.mp3").unwrap()
+        );
+
+        let playlist = Playlist::parse_m3u("a.mp3\nb.mp3\n");
+        let a = UniversalPath::local("/music/playlists/a.mp3");
+        assert_eq!(
+            playlist.track_after(&dir, &a),
+            Some(UniversalPath::local("/music/playlists/b.mp3"))
+        );
+        assert_eq!(playlist.track_after(&dir, &dir.join("b.mp3")), None);
+    }
+
+    #[test]
+    fn test_rebase_to_new_root() {
+        let playlist = Playlist::parse_m3u(
+            "#EXTINF:10,One\nC:\\Music\\Björk\\01.flac\nC:\\Music\\Live\\02.flac\nD:\\Elsewhere\\x.mp3\n",
+        );
+        let rules = RewriteRules::new()
+            .with_rule(
+                UniversalPath::local("C:\\Music"),
+                UniversalPath::local("/srv/music"),
+            )
+            .with_rule(
+                UniversalPath::local("C:\\Music\\Live"),
+                UniversalPath::from_uri_str("s3://archive/live").unwrap(),
+            );
+        let source = UniversalPath::local("C:\\Playlists\\");
+        let target = UniversalPath::local("/srv/music/playlists/");
+
+        let relative = PlaylistRebaser::new(rules.clone(), EntryStyle::Relative)
+            .rebase(&playlist, &source, &target)
+            .unwrap();
+        let locations: Vec<_> = relative
+            .entries
+            .iter()
+            .map(|e| e.location.as_str())
+            .collect();
+        assert_eq!(
+            locations,
+            vec![
+                "../Björk/01.flac",
+                "s3://archive/live/02.flac",
+                "D:\\Elsewhere\\x.mp3"
+            ]
+        );
+        assert_eq!(relative.entries[0].title.as_deref(), Some("One"));
+
+        let absolute = PlaylistRebaser::new(rules, EntryStyle::Absolute)
+            .rebase(&playlist, &source, &target)
+            .unwrap();
+        assert_eq!(absolute.entries[0].location, "/srv/music/Björk/01.flac");
+    }
+
+    struct FakeSigner;
+
+    impl UrlSigner for FakeSigner {
+        fn handles(&self, path: &UniversalPath) -> bool {
+            path.backend() == &StorageBackend::S3
+        }
+
+        fn sign_url(
+            &self,
+            path: &UniversalPath,
+            expires_in: Duration,
+        ) -> Result<String, StorageError> {
+            Ok(format!(
+                "https://{}.example{}?expires={}",
+                path.host().unwrap_or_default(),
+                path.path(),
+                expires_in.as_secs()
+            ))
+        }
+    }
+
+    #[test]
+    fn test_rebase_with_signer() {
+        let playlist = Playlist::parse_m3u("a.flac\n/local/b.flac\n");
+        let rules = RewriteRules::new().with_rule(
+            UniversalPath::local("/music"),
+            UniversalPath::from_uri_str("s3://bucket/music").unwrap(),
+        );
+        let rebased = PlaylistRebaser::new(rules, EntryStyle::Absolute)
+            .with_signer(FakeSigner, Duration::from_secs(3600))
+            .rebase(
+                &playlist,
+                &UniversalPath::local("/music/"),
+                &UniversalPath::local("/tmp/"),
+            )
+            .unwrap();
+        assert_eq!(
+            rebased.entries[0].location,
+            "https://bucket.example/music/a.flac?expires=3600"
+        );
+        assert_eq!(rebased.entries[1].location, "/local/b.flac");
+    }
+}
@@ -0,0 +1,356 @@
+use crate::environment::available_space;
+use crate::storage::{LocalStorage, Storage, StorageBackend, StorageError};
+use crate::universal_path::{PathFlavor, UniversalPath};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Names Windows reserves for devices, with or without an extension
+const WINDOWS_DEVICES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// A file a sync is about to write.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlannedWrite {
+    pub path: UniversalPath,
+    pub size_bytes: u64,
+}
+
+/// What the destination can hold, for the backends that say.
+struct PathRules {
+    max_segment_bytes: usize,
+    max_path_bytes: usize,
+    /// Windows forbids `<>:"|?*`, trailing dots and spaces, and device names
+    windows: bool,
+}
+
+fn path_rules(path: &UniversalPath) -> Option<PathRules> {
+    let windows = path.flavor() != PathFlavor::Posix;
+    match path.backend() {
+        StorageBackend::Local if windows || cfg!(windows) => Some(PathRules {
+            max_segment_bytes: 255,
+            max_path_bytes: 260,
+            windows: true,
+        }),
+        StorageBackend::Local => Some(PathRules {
+            max_segment_bytes: 255,
+            max_path_bytes: 4096,
+            windows: false,
+        }),
+        // Shares are usually served from NTFS, or by Samba, which maps the same names
+        StorageBackend::NetworkDrive => Some(PathRules {
+            max_segment_bytes: 255,
+            max_path_bytes: 260,
+            windows: true,
+        }),
+        StorageBackend::S3 => Some(PathRules {
+            max_segment_bytes: 1024,
+            max_path_bytes: 1024,
+            windows: false,
+        }),
+        StorageBackend::Ftp
+        | StorageBackend::Sftp
+        | StorageBackend::WebDav
+        | StorageBackend::WebDavs => Some(PathRules {
+            max_segment_bytes: 255,
+            max_path_bytes: 4096,
+            windows: false,
+        }),
+        _ => None,
+    }
+}
+
+/// Why a planned write would fail, found before any of the plan ran.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PreflightProblem {
+    /// The filesystem holding `dir` has less free than the plan writes
+    InsufficientSpace {
+        dir: UniversalPath,
+        needed: u64,
+        available: u64,
+    },
+    /// A test file could not be written in `dir`
+    NotWritable { dir: UniversalPath, error: String },
+    /// The destination would reject the name
+    InvalidName {
+        path: UniversalPath,
+        reason: &'static str,
+    },
+    PathTooLong {
+        path: UniversalPath,
+        bytes: usize,
+        max: usize,
+    },
+}
+
+impl fmt::Display for PreflightProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PreflightProblem::InsufficientSpace {
+                dir,
+                needed,
+                available,
+            } => write!(
+                f,
+                "{dir}: needs {} MiB but only {} MiB are free",
+                needed.div_ceil(1 << 20),
+                available >> 20
+            ),
+            PreflightProblem::NotWritable { dir, error } => {
+                write!(f, "{dir}: cannot write here: {error}")
+            }
+            PreflightProblem::InvalidName { path, reason } => write!(f, "{path}: {reason}"),
+            PreflightProblem::PathTooLong { path, bytes, max } => {
+                write!(f, "{path}: path is {bytes} bytes, over the limit of {max}")
+            }
+        }
+    }
+}
+
+/// Every problem found, so all of them can be fixed before trying again.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PreflightError {
+    pub problems: Vec<PreflightProblem>,
+}
+
+impl fmt::Display for PreflightError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} problem(s) with the plan", self.problems.len())?;
+        for problem in &self.problems {
+            write!(f, "\n  {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for PreflightError {}
+
+/// What a successful check covered.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PreflightReport {
+    pub files: usize,
+    pub bytes: u64,
+    /// Destination directories a test file was written in
+    pub dirs_checked: usize,
+    /// Free space is only known for local destinations; false when none were
+    pub space_checked: bool,
+}
+
+/// Why the destination would not take `path`, by the rules of its backend, if it
+/// would not
+pub fn check_path(path: &UniversalPath) -> Option<PreflightProblem> {
+    let rules = path_rules(path)?;
+    let invalid = |reason| PreflightProblem::InvalidName {
+        path: path.clone(),
+        reason,
+    };
+    let bytes = path.path().len();
+    if bytes > rules.max_path_bytes {
+        return Some(PreflightProblem::PathTooLong {
+            path: path.clone(),
+            bytes,
+            max: rules.max_path_bytes,
+        });
+    }
+    // A drive letter is not a name
+    let skip = usize::from(path.flavor() == PathFlavor::WindowsDrive);
+    for segment in &path.path_segments()[skip..] {
+        if segment.len() > rules.max_segment_bytes {
+            return Some(invalid("a name is longer than the destination allows"));
+        }
+        if segment.chars().any(char::is_control) {
+            return Some(invalid("a name contains control characters"));
+        }
+        if !rules.windows {
+            continue;
+        }
+        if segment.contains(['<', '>', ':', '"', '|', '?', '*', '\\']) {
+            return Some(invalid("a name contains one of <>:\"|?*\\"));
+        }
+        if segment.ends_with(['.', ' ']) {
+            return Some(invalid("a name ends in a dot or space"));
+        }
+        let stem = segment.split('.').next().unwrap_or_default();
+        if WINDOWS_DEVICES
+            .iter()
+            .any(|d| d.eq_ignore_ascii_case(stem.trim_end()))
+        {
+            return Some(invalid("a name is reserved for a device on Windows"));
+        }
+    }
+    None
+}
+
+/// The nearest ancestor of `dir`, or `dir` itself, that exists
+async fn existing_ancestor(
+    storage: &dyn Storage,
+    dir: &UniversalPath,
+) -> Result<UniversalPath, StorageError> {
+    let mut dir = dir.as_dir();
+    loop {
+        match storage.stat(&dir).await {
+            Ok(_) => return Ok(dir),
+            Err(StorageError::NotFound) => match dir.parent() {
+                Some(parent) => dir = parent.as_dir(),
+                None => return Err(StorageError::NotFound),
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Whether a file can be created in `dir`, by writing and deleting one
+async fn probe_write(storage: &dyn Storage, dir: &UniversalPath) -> Result<(), StorageError> {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let name = format!(
+        ".otolith-preflight-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    );
+    let probe = dir.join(&name);
+    storage.write(&probe, b"").await?;
+    storage.delete(&probe).await
+}
+
+/// Check that `writes` can all go ahead before any of them runs: every name is valid
+/// for its backend, each destination directory, or the nearest one that exists, takes
+/// a test file, and a local destination has room for the whole plan. Fails with every
+/// problem found rather than the first.
+pub async fn preflight(
+    storage: &dyn Storage,
+    writes: &[PlannedWrite],
+) -> Result<PreflightReport, PreflightError> {
+    let mut problems = Vec::new();
+    let mut report = PreflightReport {
+        files: writes.len(),
+        bytes: writes.iter().map(|w| w.size_bytes).sum(),
+        ..PreflightReport::default()
+    };
+
+    let mut dirs = BTreeMap::new();
+    for write in writes {
+        if let Some(problem) = check_path(&write.path) {
+            problems.push(problem);
+        }
+        if let Some(parent) = write.path.parent() {
+            dirs.entry(parent.as_dir().to_string()).or_insert(parent);
+        }
+    }
+
+    // Directories the plan creates are checked where they would be created
+    let mut existing = BTreeMap::new();
+    for dir in dirs.values() {
+        match existing_ancestor(storage, dir).await {
+            Ok(found) => {
+                existing.entry(found.to_string()).or_insert(found);
+            }
+            Err(e) => problems.push(PreflightProblem::NotWritable {
+                dir: dir.clone(),
+                error: e.to_string(),
+            }),
+        }
+    }
+    for dir in existing.values() {
+        report.dirs_checked += 1;
+        if let Err(e) = probe_write(storage, dir).await {
+            problems.push(PreflightProblem::NotWritable {
+                dir: dir.clone(),
+                error: e.to_string(),
+            });
+        }
+    }
+
+    // A plan usually lands on one filesystem; checking the directory it has in common
+    // errs on the side of what the whole plan needs
+    let local = existing
+        .values()
+        .filter(|dir| dir.backend() == &StorageBackend::Local);
+    let common = local.fold(None, |common: Option<UniversalPath>, dir| match common {
+        None => Some(dir.clone()),
+        Some(common) => common.common_ancestor(dir),
+    });
+    if let Some(dir) = common
+        && let Ok(native) = LocalStorage::new().to_pathbuf(&dir)
+        && let Ok(space) = available_space(&native)
+    {
+        report.space_checked = true;
+        if space.available_bytes < report.bytes {
+            problems.push(PreflightProblem::InsufficientSpace {
+                dir,
+                needed: report.bytes,
+                available: space.available_bytes,
+            });
+        }
+    }
+
+    match problems.is_empty() {
+        true => Ok(report),
+        false => Err(PreflightError { problems }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_preflight() {
+        let share = UniversalPath::from_uri_str("smb://nas/music/").unwrap();
+        assert!(check_path(&share.join("Björk – Post.flac")).is_none());
+        for bad in ["AUX.flac", "What?.flac", "Trailing. ", "tab\there"] {
+            let problem = check_path(&share.join(bad)).unwrap();
+            assert!(
+                matches!(problem, PreflightProblem::InvalidName { .. }),
+                "{bad}"
+            );
+        }
+        let deep = share.join_all(&["x".repeat(200), "x".repeat(200)]);
+        assert!(matches!(
+            check_path(&deep),
+            Some(PreflightProblem::PathTooLong { .. })
+        ));
+
+        let storage = MemoryStorage::new();
+        let root = UniversalPath::from_uri_str("mem://dest/").unwrap();
+        storage.create_dir(&root).await.unwrap();
+        let plan: Vec<_> = ["01.flac", "new/02.flac"]
+            .iter()
+            .map(|name| PlannedWrite {
+                path: root.join_all(&name.split('/').collect::<Vec<_>>()),
+                size_bytes: 1000,
+            })
+            .collect();
+        let report = preflight(&storage, &plan).await.unwrap();
+        assert_eq!(
+            (report.files, report.bytes, report.dirs_checked),
+            (2, 2000, 1)
+        );
+        assert!(storage.list(&root).await.unwrap().is_empty());
+
+        // Local destinations are checked for space too
+        let dir = std::env::temp_dir();
+        let huge = PlannedWrite {
+            path: UniversalPath::local(dir.join("huge.flac").to_string_lossy()),
+            size_bytes: u64::MAX / 2,
+        };
+        if cfg!(unix) {
+            let error = preflight(&LocalStorage::new(), &[huge]).await.unwrap_err();
+            assert!(matches!(
+                error.problems[..],
+                [PreflightProblem::InsufficientSpace { .. }]
+            ));
+            assert!(
+                error
+                    .to_string()
+                    .starts_with("1 problem(s) with the plan\n  ")
+            );
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use crate::root::{AUDIO_EXTENSIONS, RootConfig};
+use crate::storage::{EntryKind, StorageCapabilities, StorageError, open_storage_for};
+use crate::universal_path::{UniversalPath, UniversalPathError};
+use std::collections::{BTreeMap, VecDeque};
+use thiserror::Error;
+
+/// How many entries `probe_root` samples unless told otherwise.
+pub const DEFAULT_SAMPLE_LIMIT: usize = 300;
+
+#[derive(Debug, Error)]
+pub enum ProbeError {
+    #[error(transparent)]
+    Path(#[from] UniversalPathError),
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+/// Count and byte total for one extension in the sample.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FormatStats {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// What `probe_root` learned about a prospective root.
+#[derive(Debug, Clone)]
+pub struct ProbeReport {
+    pub root: UniversalPath,
+    pub capabilities: StorageCapabilities,
+    pub root_kind: EntryKind,
+    pub sampled_files: usize,
+    pub sampled_dirs: usize,
+    pub sampled_bytes: u64,
+    /// Files per lowercase extension; files without one are counted under `""`.
+    pub formats: BTreeMap<String, FormatStats>,
+    /// True if the whole tree fit in the sample, making the totals exact.
+    pub complete: bool,
+    /// Total size of the tree, extrapolated from the sample when it is incomplete.
+    pub estimated_total_bytes: u64,
+    /// Entries that could not be listed or stat'ed.
+    pub errors: Vec<(UniversalPath, String)>,
+    pub suggested_config: RootConfig,
+}
+
+impl ProbeReport {
+    /// Sampled files whose extension is a known audio format
+    pub fn audio_files(&self) -> usize {
+        self.formats
+            .iter()
+            .filter(|(ext, _)| AUDIO_EXTENSIONS.contains(&ext.as_str()))
+            .map(|(_, stats)| stats.files)
+            .sum()
+    }
+}
+
+/// Parse user input either as a URI or, when it has no scheme, as a local path.
+pub fn parse_root_input(input: &str) -> Result<UniversalPath, UniversalPathError> {
+    if input.contains("://") || input.starts_with("file:") {
+        UniversalPath::from_uri_str(input)
+    } else {
+        Ok(UniversalPath::local(input))
+    }
+}
+
+/// Inspect a prospective root before adding it: detect the backend, check the root is
+/// reachable, sample up to `DEFAULT_SAMPLE_LIMIT` entries breadth-first, and suggest a
+/// `RootConfig` based on what was found.
+pub async fn probe_root(input: &str) -> Result<ProbeReport, ProbeError> {
+    probe_root_with_limit(input, DEFAULT_SAMPLE_LIMIT).await
+}
+
+/// Same as `probe_root` with an explicit cap on sampled entries.
+pub async fn probe_root_with_limit(
+    input: &str,
+    sample_limit: usize,
+) -> Result<ProbeReport, ProbeError> {
+    let root = parse_root_input(input)?;
+    let storage = open_storage_for(&root)?;
+    let capabilities = storage.capabilities();
+
+    let root_kind = if capabilities.can_stat {
+        storage.stat(&root).await?.kind
+    } else {
+        EntryKind::Directory
+    };
+
+    let mut report = ProbeReport {
+        root: root.clone(),
+        capabilities,
+        root_kind: root_kind.clone(),
+        sampled_files: 0,
+        sampled_dirs: 0,
+        sampled_bytes: 0,
+        formats: BTreeMap::new(),
+        complete: true,
+        estimated_total_bytes: 0,
+        errors: Vec::new(),
+        suggested_config: RootConfig::new(root.as_dir()),
+    };
+
+    if root_kind != EntryKind::Directory || !capabilities.can_list {
+        report.suggested_config = suggest_config(&report);
+        return Ok(report);
+    }
+
+    let mut pending = VecDeque::from([root.clone()]);
+    let mut sampled = 0usize;
+    'outer: while let Some(dir) = pending.pop_front() {
+        report.sampled_dirs += 1;
+        let children = match storage.list(&dir).await {
+            Ok(children) => children,
+            Err(e) => {
+                report.errors.push((dir, e.to_string()));
+                continue;
+            }
+        };
+
+        for child in children {
+            if sampled >= sample_limit {
+                report.complete = false;
+                break 'outer;
+            }
+            sampled += 1;
+
+            let meta = match storage.stat(&child).await {
+                Ok(meta) => meta,
+                Err(e) => {
+                    report.errors.push((child, e.to_string()));
+                    continue;
+                }
+            };
+            match meta.kind {
+                EntryKind::Directory => pending.push_back(child),
+                EntryKind::File => {
+                    let bytes = meta.size_bytes.unwrap_or(0);
+                    let ext = child.extension().unwrap_or("").to_lowercase();
+                    let stats = report.formats.entry(ext).or_default();
+                    stats.files += 1;
+                    stats.bytes += bytes;
+                    report.sampled_files += 1;
+                    report.sampled_bytes += bytes;
+                }
+                EntryKind::Symlink | EntryKind::Other => {}
+            }
+        }
+    }
+    if !pending.is_empty() {
+        report.complete = false;
+    }
+
+    report.estimated_total_bytes = if report.complete {
+        report.sampled_bytes
+    } else {
+        // Assume unvisited directories hold roughly as much as the ones we saw.
+        let seen = report.sampled_dirs.max(1) as u64;
+        let known = (report.sampled_dirs + pending.len()) as u64;
+        report.sampled_bytes.saturating_mul(known) / seen
+    };
+    report.suggested_config = suggest_config(&report);
+    Ok(report)
+}
+
+fn suggest_config(report: &ProbeReport) -> RootConfig {
+    let mut config = RootConfig::new(report.root.as_dir());
+    let found: Vec<String> = report
+        .formats
+        .keys()
+        .filter(|ext| AUDIO_EXTENSIONS.contains(&ext.as_str()))
+        .cloned()
+        .collect();
+    if !found.is_empty() {
+        config.extensions = found;
+    }
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_probe_local_tree() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        std::fs::create_dir_all(dir.join("album")).unwrap();
+        std::fs::write(dir.join("album/01.flac"), vec![0u8; 100]).unwrap();
+        std::fs::write(dir.join("album/02.FLAC"), vec![0u8; 50]).unwrap();
+        std::fs::write(dir.join("album/cover.jpg"), vec![0u8; 10]).unwrap();
+        std::fs::write(dir.join("notes"), b"hi").unwrap();
+
+        let report = probe_root(dir.to_str().unwrap()).await.unwrap();
+        assert_eq!(report.root_kind, EntryKind::Directory);
+        assert!(report.complete);
+        assert_eq!(report.sampled_files, 4);
+        assert_eq!(report.sampled_bytes, 162);
+        assert_eq!(report.estimated_total_bytes, 162);
+        assert_eq!(
+            report.formats["flac"],
+            FormatStats {
+                files: 2,
+                bytes: 150
+            }
+        );
+        assert_eq!(report.formats[""].files, 1);
+        assert_eq!(report.audio_files(), 2);
+        assert_eq!(report.suggested_config.extensions, vec!["flac".to_string()]);
+
+        let partial = probe_root_with_limit(dir.to_str().unwrap(), 1)
+            .await
+            .unwrap();
+        assert!(!partial.complete);
+    }
+}
@@ -0,0 +1,127 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+/// Exponential backoff: attempt `n` (1-based) waits
+/// `initial_backoff * multiplier^(n-1)`, capped at `max_backoff`, less a random
+/// share of up to `jitter` of that so clients that failed together do not all retry
+/// together.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// Total attempts including the first; 1 disables retries.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+    /// From 0.0, always the full backoff, to 1.0, anywhere between none and all of it
+    #[serde(default)]
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(300),
+            multiplier: 2.0,
+            jitter: 0.0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub const fn never() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+            multiplier: 1.0,
+            jitter: 0.0,
+        }
+    }
+
+    /// For calls to network storage: 4 attempts, starting at 200ms and doubling up
+    /// to 10s, half of it jittered
+    pub const fn network() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.5,
+        }
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Whether another attempt is allowed after `attempts` have been made
+    pub fn should_retry(&self, attempts: u32) -> bool {
+        attempts < self.max_attempts
+    }
+
+    /// How long to wait before retrying after the `attempt`-th failure
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self.multiplier.powi(attempt.saturating_sub(1) as i32);
+        let secs = self.initial_backoff.as_secs_f64() * exp;
+        if !secs.is_finite() || secs >= self.max_backoff.as_secs_f64() {
+            self.max_backoff
+        } else {
+            Duration::from_secs_f64(secs)
+        }
+    }
+
+    /// `backoff` with the jitter applied, what to actually wait
+    pub fn delay(&self, attempt: u32) -> Duration {
+        // A fresh RandomState is randomly keyed, which is all the randomness needed
+        let bits = RandomState::new().build_hasher().finish();
+        self.delay_at(attempt, (bits >> 11) as f64 / (1u64 << 53) as f64)
+    }
+
+    /// The delay for a `roll` from 0.0 up to 1.0
+    fn delay_at(&self, attempt: u32, roll: f64) -> Duration {
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        self.backoff(attempt).mul_f64(1.0 - jitter * roll)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_growth_and_cap() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+            multiplier: 2.0,
+            jitter: 0.0,
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+        assert_eq!(policy.backoff(4), Duration::from_millis(500));
+        assert_eq!(policy.backoff(100), Duration::from_millis(500));
+        assert!(policy.should_retry(4));
+        assert!(!policy.should_retry(5));
+        assert!(!RetryPolicy::never().should_retry(1));
+
+        let jittered = policy.with_jitter(0.5);
+        assert_eq!(jittered.delay_at(2, 0.0), Duration::from_millis(200));
+        assert_eq!(jittered.delay_at(2, 0.5), Duration::from_millis(150));
+        let delay = jittered.delay(3);
+        assert!(delay > Duration::from_millis(200) && delay <= Duration::from_millis(400));
+    }
+}
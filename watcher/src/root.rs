@@ -0,0 +1,66 @@
+#[cfg(feature = "scripting")]
+use crate::hooks::HookConfig;
+use crate::storage::Quota;
+use crate::transliterate::NameMode;
+use crate::universal_path::UniversalPath;
+use serde::{Deserialize, Serialize};
+
+/// Extensions treated as audio when suggesting what a root should index.
+pub const AUDIO_EXTENSIONS: &[&str] = &[
+    "aac", "aif", "aiff", "alac", "ape", "dff", "dsf", "flac", "m4a", "mp3", "mpc", "ogg", "opus",
+    "wav", "wma", "wv",
+];
+
+/// A library root the user has added: where it lives and what to index under it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RootConfig {
+    pub path: UniversalPath,
+    /// Display name for the root; defaults to the last path segment or host.
+    pub label: String,
+    /// Lowercase extensions (without the dot) to index. Empty means everything.
+    pub extensions: Vec<String>,
+    /// Limits on what may be written under the root; see `QuotaStorage`
+    #[serde(default)]
+    pub quota: Quota,
+    /// How names are written under the root, for devices that cannot show every name
+    #[serde(default)]
+    pub names: NameMode,
+    /// Nothing is written under the root, such as lyrics sidecars
+    #[serde(default)]
+    pub read_only: bool,
+    /// Scripts run on changes under the root
+    #[cfg(feature = "scripting")]
+    #[serde(default)]
+    pub hooks: Vec<HookConfig>,
+}
+
+impl RootConfig {
+    pub fn new(path: UniversalPath) -> Self {
+        let label = path
+            .last_segment()
+            .or(path.host())
+            .unwrap_or("/")
+            .to_string();
+        RootConfig {
+            path,
+            label,
+            extensions: AUDIO_EXTENSIONS.iter().map(|e| e.to_string()).collect(),
+            quota: Quota::default(),
+            names: NameMode::default(),
+            read_only: false,
+            #[cfg(feature = "scripting")]
+            hooks: Vec::new(),
+        }
+    }
+
+    /// Whether a file with this extension should be indexed under the root
+    pub fn accepts_extension(&self, ext: Option<&str>) -> bool {
+        if self.extensions.is_empty() {
+            return true;
+        }
+        match ext {
+            Some(ext) => self.extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)),
+            None => false,
+        }
+    }
+}
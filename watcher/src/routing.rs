@@ -0,0 +1,374 @@
+use crate::execution::SyncItem;
+use crate::root::RootConfig;
+use crate::storage::{
+    CredentialProvider, EntryKind, FilterSet, QuotaStorage, Storage, StorageError, StorageRegistry,
+};
+use crate::universal_path::UniversalPath;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Sends the files it matches to a destination root of their own.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// Names the route in plans and configuration errors
+    pub label: String,
+    /// Which files it takes, by the rules walking and scanning use: extensions,
+    /// include and exclude patterns and a size limit
+    #[serde(default)]
+    pub filter: FilterSet,
+    /// Where they go, each at its path under the source root
+    pub destination: UniversalPath,
+}
+
+impl Route {
+    pub fn new(label: &str, filter: FilterSet, destination: &UniversalPath) -> Self {
+        Route {
+            label: label.to_string(),
+            filter,
+            destination: destination.as_dir(),
+        }
+    }
+}
+
+/// Decides which destination each file of a sync or ingest goes to, by the first of
+/// its routes whose filter takes the file. Files no route takes go to the fallback,
+/// if there is one, and are otherwise left where they are.
+///
+/// For example FLAC to the archive NAS, Opus to the phone's sync folder and artwork
+/// to a CDN bucket, each on whatever backend its destination URI names.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Option<UniversalPath>,
+}
+
+/// The items a `Router` sends somewhere, and the files it does not.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RoutePlan {
+    /// Ready for a `SyncExecution`; see `RoutedDestinations` for running them
+    pub items: Vec<SyncItem>,
+    /// With the route that took each item, `None` for the fallback
+    pub labels: Vec<Option<String>>,
+    pub unrouted: Vec<UniversalPath>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    /// Tried after the routes already added
+    pub fn with_route(mut self, route: Route) -> Self {
+        self.routes.push(route);
+        self
+    }
+
+    /// Where files no route takes go
+    pub fn with_fallback(mut self, destination: &UniversalPath) -> Self {
+        self.fallback = Some(destination.as_dir());
+        self
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    /// The route that takes the file at `rel`, a path from the source root, of
+    /// `size_bytes` if known
+    pub fn route_for(&self, rel: &[String], size_bytes: Option<u64>) -> Option<&Route> {
+        let file = EntryKind::File;
+        self.routes
+            .iter()
+            .find(|route| route.filter.allows(rel, &file, size_bytes))
+    }
+
+    /// Where `path` under `source_root` goes, if anywhere
+    pub fn destination_for(
+        &self,
+        source_root: &UniversalPath,
+        path: &UniversalPath,
+        size_bytes: Option<u64>,
+    ) -> Option<UniversalPath> {
+        self.resolve(source_root, path, size_bytes)
+            .map(|(to, _)| to)
+    }
+
+    /// Where `path` goes and the route sending it there, `None` for the fallback
+    fn resolve(
+        &self,
+        source_root: &UniversalPath,
+        path: &UniversalPath,
+        size_bytes: Option<u64>,
+    ) -> Option<(UniversalPath, Option<&Route>)> {
+        let rel = path.relative_to(source_root)?;
+        let route = self.route_for(&rel, size_bytes);
+        let destination = match route {
+            Some(route) => &route.destination,
+            None => self.fallback.as_ref()?,
+        };
+        let item = SyncItem::under(source_root, path, destination)?;
+        Some((item.to, route))
+    }
+
+    /// Route each of `files` under `source_root`, each with its size if known. As
+    /// with rsync, `inbox/` sends what is in the directory and `inbox` the directory
+    /// itself; see `SyncItem::under`.
+    pub fn plan(
+        &self,
+        source_root: &UniversalPath,
+        files: impl IntoIterator<Item = (UniversalPath, Option<u64>)>,
+    ) -> RoutePlan {
+        let mut plan = RoutePlan::default();
+        for (path, size_bytes) in files {
+            match self.resolve(source_root, &path, size_bytes) {
+                Some((to, route)) => {
+                    plan.labels.push(route.map(|r| r.label.clone()));
+                    plan.items.push(SyncItem { from: path, to });
+                }
+                None => plan.unrouted.push(path),
+            }
+        }
+        plan
+    }
+
+    /// Open every destination through `registry`, logging in with credentials from
+    /// `provider`, so one storage serves each destination however many files go there
+    pub fn open(
+        &self,
+        registry: &StorageRegistry,
+        provider: &dyn CredentialProvider,
+    ) -> Result<RoutedDestinations, StorageError> {
+        let mut destinations = RoutedDestinations::default();
+        for destination in self.destinations() {
+            let storage = registry.open_with(destination, provider)?;
+            destinations = destinations.with_destination(destination, Arc::from(storage));
+        }
+        Ok(destinations)
+    }
+
+    /// Each destination once, in the order the routes name them
+    fn destinations(&self) -> Vec<&UniversalPath> {
+        let all = self.routes.iter().map(|r| &r.destination);
+        let mut destinations: Vec<&UniversalPath> = Vec::new();
+        for destination in all.chain(&self.fallback) {
+            if !destinations.contains(&destination) {
+                destinations.push(destination);
+            }
+        }
+        destinations
+    }
+}
+
+impl From<Vec<Route>> for Router {
+    fn from(routes: Vec<Route>) -> Self {
+        Router {
+            routes,
+            fallback: None,
+        }
+    }
+}
+
+/// The storages a routed sync writes to, by destination root.
+#[derive(Clone, Default)]
+pub struct RoutedDestinations {
+    storages: Vec<(UniversalPath, Arc<dyn Storage>)>,
+}
+
+impl RoutedDestinations {
+    /// Write what goes under `root` to `storage`
+    pub fn with_destination(mut self, root: &UniversalPath, storage: Arc<dyn Storage>) -> Self {
+        let root = root.as_dir();
+        self.storages.retain(|(r, _)| *r != root);
+        self.storages.push((root, storage));
+        self
+    }
+
+    /// The storage for `path`, from the deepest destination root holding it
+    pub fn storage_for(&self, path: &UniversalPath) -> Option<&dyn Storage> {
+        self.storages
+            .iter()
+            .filter(|(root, _)| path.relative_to(root).is_some())
+            .max_by_key(|(root, _)| root.path_segments.len())
+            .map(|(_, storage)| &**storage)
+    }
+
+    /// The same destinations with the quota of each of `roots` enforced, measured now.
+    /// Destinations at or under a root, or holding one, share the storage keeping its
+    /// quota, so together they keep to it.
+    pub(crate) async fn with_quotas(mut self, roots: &[RootConfig]) -> Result<Self, StorageError> {
+        for root in roots.iter().filter(|root| !root.quota.is_unlimited()) {
+            let related = |destination: &UniversalPath| {
+                destination.relative_to(&root.path).is_some()
+                    || root.path.relative_to(destination).is_some()
+            };
+            let Some((_, storage)) = self.storages.iter().find(|(d, _)| related(d)) else {
+                continue;
+            };
+            let roots = std::slice::from_ref(root);
+            let (quota, _) = QuotaStorage::for_roots(storage.clone(), roots).await?;
+            for (destination, storage) in &mut self.storages {
+                if related(destination) {
+                    *storage = quota.clone();
+                }
+            }
+        }
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ConfigError, OtolithConfig};
+    use crate::execution::SyncExecution;
+    use crate::storage::{CredentialChain, MemoryStorage, Quota};
+
+    #[tokio::test]
+    async fn test_routed_sync() {
+        let uri = |uri: &str| UniversalPath::from_uri_str(uri).unwrap();
+        let config = OtolithConfig::builder()
+            .with_route(Route::new(
+                "archive",
+                FilterSet::new().with_extensions(["flac"]),
+                &uri("mem://nas/archive/"),
+            ))
+            .with_route(Route::new(
+                "phone",
+                FilterSet::new()
+                    .with_extensions(["opus"])
+                    .with_max_size(100),
+                &uri("mem://phone/sync/"),
+            ))
+            .with_route(Route::new(
+                "cdn",
+                FilterSet::new().with_include(["cover.*", "folder.jpg"]),
+                &uri("mem://cdn/art/"),
+            ))
+            .build()
+            .unwrap();
+        let router = config.router();
+        let storage = MemoryStorage::new();
+        let inbox = uri("mem://nas/inbox/");
+        let album = inbox.join_all(&["Björk", "Post"]);
+        let files = [
+            ("01.flac", 50),
+            ("01.opus", 20),
+            ("cover.jpg", 10),
+            ("notes.txt", 5),
+        ];
+        storage.create_dir(&album).await.unwrap();
+        for (name, size) in files {
+            storage
+                .write(&album.join(name), &vec![1; size])
+                .await
+                .unwrap();
+        }
+        // An Opus too big for the phone goes nowhere
+        let live = album.join("02 live.opus");
+        storage.write(&live, &[1; 500]).await.unwrap();
+
+        let listed = storage.list(&album).await.unwrap();
+        let sized = listed.into_iter().map(|path| {
+            let size = files.iter().find(|(n, _)| path.last_segment() == Some(*n));
+            let size = size.map_or(500, |(_, size)| *size as u64);
+            (path, Some(size))
+        });
+        let plan = router.plan(&inbox, sized);
+        let to = |root: &str, name: &str| uri(root).join_all(&["Björk", "Post", name]);
+        let flac = to("mem://nas/archive/", "01.flac");
+        let mut routed: Vec<_> = plan.items.iter().map(|i| i.to.clone()).collect();
+        routed.sort_by_key(|p| p.to_string());
+        let mut expected = [
+            flac.clone(),
+            to("mem://phone/sync/", "01.opus"),
+            to("mem://cdn/art/", "cover.jpg"),
+        ];
+        expected.sort_by_key(|p| p.to_string());
+        assert_eq!(routed, expected);
+        let mut unrouted = plan.unrouted.clone();
+        unrouted.sort_by_key(|p| p.to_string());
+        assert_eq!(unrouted, [live, album.join("notes.txt")]);
+
+        // Each destination is opened once through the registry
+        let mut registry = StorageRegistry::empty();
+        let opened = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (shared, log) = (storage.clone(), opened.clone());
+        registry.register("mem", move |path, _| {
+            log.lock()
+                .unwrap()
+                .push(path.host().unwrap_or_default().to_string());
+            Ok(Box::new(shared.clone()))
+        });
+        let destinations = router.open(&registry, &CredentialChain::new()).unwrap();
+        assert_eq!(*opened.lock().unwrap(), ["nas", "phone", "cdn"]);
+
+        let mut execution = SyncExecution::new("ingest", plan.items);
+        let summary = execution
+            .run_routed(&storage, &destinations, None)
+            .await
+            .unwrap();
+        assert_eq!((summary.completed, summary.failed), (3, 0));
+        assert_eq!(storage.read(&flac).await.unwrap(), [1; 50]);
+
+        // A destination the registry will not open does not validate
+        let disabled = OtolithConfig::builder()
+            .with_disabled_scheme("smb")
+            .with_route(Route::new(
+                "nas",
+                FilterSet::new(),
+                &uri("smb://nas/music/"),
+            ))
+            .build();
+        let Err(ConfigError::Invalid(problems)) = disabled else {
+            panic!("a route to a disabled scheme should not validate");
+        };
+        assert_eq!(problems.len(), 1, "{problems:?}");
+    }
+
+    #[tokio::test]
+    async fn test_routed_ingest_keeps_to_quota() {
+        let uri = |uri: &str| UniversalPath::from_uri_str(uri).unwrap();
+        let storage = MemoryStorage::new();
+        let inbox = uri("mem://nas/inbox/");
+        storage.create_dir(&inbox).await.unwrap();
+        for name in ["01.flac", "01.opus"] {
+            storage.write(&inbox.join(name), &[0; 40]).await.unwrap();
+        }
+        // Both destinations are under the one root, and together they may not
+        // go past its quota
+        let router = Router::from(vec![
+            Route::new(
+                "lossless",
+                FilterSet::new().with_extensions(["flac"]),
+                &uri("mem://nas/music/flac/"),
+            ),
+            Route::new(
+                "lossy",
+                FilterSet::new().with_extensions(["opus"]),
+                &uri("mem://nas/music/opus/"),
+            ),
+        ]);
+        let files = ["01.flac", "01.opus"].map(|name| (inbox.join(name), Some(40)));
+        let plan = router.plan(&inbox, files);
+        let shared = Arc::new(storage.clone());
+        let destinations = RoutedDestinations::default()
+            .with_destination(&uri("mem://nas/music/flac/"), shared.clone())
+            .with_destination(&uri("mem://nas/music/opus/"), shared);
+        let mut root = RootConfig::new(uri("mem://nas/music/"));
+        root.quota = Quota::default().with_max_bytes(100);
+
+        let mut execution = SyncExecution::new("ingest", plan.items).with_roots(&[root]);
+        let summary = execution
+            .run_routed(&storage, &destinations, None)
+            .await
+            .unwrap();
+        assert_eq!((summary.completed, summary.failed), (1, 1));
+        assert!(
+            storage
+                .stat(&uri("mem://nas/music/opus/01.opus"))
+                .await
+                .is_err()
+        );
+    }
+}
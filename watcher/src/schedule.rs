@@ -0,0 +1,645 @@
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, Local, NaiveDateTime, Timelike};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ScheduleError {
+    #[error("invalid cron expression: {0}")]
+    InvalidCron(String),
+    #[error("invalid interval: {0}")]
+    InvalidInterval(String),
+    #[error("duplicate job name: {0}")]
+    DuplicateJob(String),
+    #[error("corrupt schedule state: {0}")]
+    CorruptState(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// A set of allowed values for one cron field, stored as a bitmask.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CronField {
+    bits: u64,
+    restricted: bool,
+}
+
+impl CronField {
+    fn parse(spec: &str, min: u32, max: u32) -> Result<Self, ScheduleError> {
+        let invalid = || ScheduleError::InvalidCron(format!("bad field {spec:?}"));
+        let mut bits = 0u64;
+        for part in spec.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+                None => (part, 1),
+            };
+            if step == 0 {
+                return Err(invalid());
+            }
+            let (lo, hi) = if range == "*" {
+                (min, max)
+            } else if let Some((lo, hi)) = range.split_once('-') {
+                (
+                    lo.parse().map_err(|_| invalid())?,
+                    hi.parse().map_err(|_| invalid())?,
+                )
+            } else {
+                let v: u32 = range.parse().map_err(|_| invalid())?;
+                // `5/15` means "from 5 to the end, every 15"
+                if part.contains('/') { (v, max) } else { (v, v) }
+            };
+            if lo < min || hi > max || lo > hi {
+                return Err(invalid());
+            }
+            for v in (lo..=hi).step_by(step as usize) {
+                bits |= 1 << v;
+            }
+        }
+        Ok(CronField {
+            bits,
+            restricted: spec != "*",
+        })
+    }
+
+    fn contains(&self, v: u32) -> bool {
+        self.bits & (1 << v) != 0
+    }
+}
+
+/// A standard five-field cron expression (`minute hour day-of-month month day-of-week`),
+/// evaluated in local time. Supports `*`, lists, ranges, steps, and the `@daily`-style
+/// aliases. Day-of-week accepts 0-7 with both 0 and 7 meaning Sunday.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronExpr {
+    source: String,
+    minute: CronField,
+    hour: CronField,
+    day_of_month: CronField,
+    month: CronField,
+    day_of_week: CronField,
+}
+
+impl CronExpr {
+    pub fn parse(expr: &str) -> Result<Self, ScheduleError> {
+        let expanded = match expr.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(ScheduleError::InvalidCron(format!(
+                "expected 5 fields, got {} in {expr:?}",
+                fields.len()
+            )));
+        }
+
+        let mut day_of_week = CronField::parse(fields[4], 0, 7)?;
+        if day_of_week.contains(7) {
+            day_of_week.bits = (day_of_week.bits & !(1 << 7)) | 1;
+        }
+
+        Ok(CronExpr {
+            source: expr.trim().to_string(),
+            minute: CronField::parse(fields[0], 0, 59)?,
+            hour: CronField::parse(fields[1], 0, 23)?,
+            day_of_month: CronField::parse(fields[2], 1, 31)?,
+            month: CronField::parse(fields[3], 1, 12)?,
+            day_of_week,
+        })
+    }
+
+    fn day_matches(&self, t: &NaiveDateTime) -> bool {
+        let dom = self.day_of_month.contains(t.day());
+        let dow = self
+            .day_of_week
+            .contains(t.weekday().num_days_from_sunday());
+        // Classic cron: when both day fields are restricted, either one may match.
+        if self.day_of_month.restricted && self.day_of_week.restricted {
+            dom || dow
+        } else {
+            dom && dow
+        }
+    }
+
+    /// First matching minute strictly after `after`, or None if nothing matches within
+    /// the next five years (e.g. `0 0 31 2 *`).
+    pub fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut t = after.with_second(0)?.with_nanosecond(0)? + ChronoDuration::minutes(1);
+        let limit_year = after.year() + 5;
+
+        while t.year() <= limit_year {
+            if !self.month.contains(t.month()) {
+                let (y, m) = if t.month() == 12 {
+                    (t.year() + 1, 1)
+                } else {
+                    (t.year(), t.month() + 1)
+                };
+                t = chrono::NaiveDate::from_ymd_opt(y, m, 1)?.and_hms_opt(0, 0, 0)?;
+                continue;
+            }
+            if !self.day_matches(&t) {
+                t = (t.date() + ChronoDuration::days(1)).and_hms_opt(0, 0, 0)?;
+                continue;
+            }
+            if !self.hour.contains(t.hour()) {
+                t = t.with_minute(0)? + ChronoDuration::hours(1);
+                continue;
+            }
+            if !self.minute.contains(t.minute()) {
+                t += ChronoDuration::minutes(1);
+                continue;
+            }
+            return Some(t);
+        }
+        None
+    }
+}
+
+impl fmt::Display for CronExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// When a periodic job should run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+    /// Run, then wait this long after the start of the previous run.
+    Interval(Duration),
+    Cron(CronExpr),
+}
+
+impl Schedule {
+    /// Next time the job is due given when it last started. Jobs that have never run
+    /// are due immediately on an interval, or at the next cron match after `now`.
+    pub fn next_run(&self, last_run: Option<SystemTime>, now: SystemTime) -> Option<SystemTime> {
+        match (self, last_run) {
+            (Schedule::Interval(every), Some(last)) => Some(last + *every),
+            (Schedule::Interval(_), None) => Some(now),
+            (Schedule::Cron(expr), last) => {
+                let from: DateTime<Local> = last.unwrap_or(now).into();
+                let next = expr.next_after(from.naive_local())?;
+                // Ambiguous times (DST fall-back) take the first occurrence; times inside
+                // a spring-forward gap run just after the jump.
+                let local = next.and_local_timezone(Local).earliest().or_else(|| {
+                    (next + ChronoDuration::hours(1))
+                        .and_local_timezone(Local)
+                        .earliest()
+                })?;
+                Some(local.into())
+            }
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = ScheduleError;
+
+    /// Accepts `every <n><unit>` (units `s`, `m`, `h`, `d`, `w`) or a cron expression.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.strip_prefix("every ") {
+            Some(spec) => parse_interval(spec.trim()).map(Schedule::Interval),
+            None => CronExpr::parse(s).map(Schedule::Cron),
+        }
+    }
+}
+
+fn parse_interval(spec: &str) -> Result<Duration, ScheduleError> {
+    let invalid = || ScheduleError::InvalidInterval(spec.to_string());
+    let split = spec
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let (n, unit) = spec.split_at(split);
+    let n: u64 = n.parse().map_err(|_| invalid())?;
+    let secs = match unit.trim() {
+        "s" => n,
+        "m" => n * 60,
+        "h" => n * 3600,
+        "d" => n * 86_400,
+        "w" => n * 604_800,
+        _ => return Err(invalid()),
+    };
+    if secs == 0 {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+pub type JobFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+type JobFn = Arc<dyn Fn() -> JobFuture + Send + Sync>;
+
+struct ScheduledJob {
+    name: String,
+    /// None for a job only ever started by `run_now`
+    schedule: Option<Schedule>,
+    run: JobFn,
+    running: Arc<AtomicBool>,
+}
+
+/// Persisted record of when each job last started, keyed by job name.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct ScheduleState {
+    last_runs: HashMap<String, SystemTime>,
+}
+
+/// Runs named jobs on their schedules, or when asked with `run_now`. A job that is
+/// still running when it comes due again is skipped rather than started twice. With
+/// a state file, last-run times survive restarts, so a 3am job missed while the
+/// process was down runs on startup.
+pub struct Scheduler {
+    jobs: Vec<ScheduledJob>,
+    state: ScheduleState,
+    state_path: Option<PathBuf>,
+    started_at: SystemTime,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler {
+    /// Scheduler that keeps last-run times in memory only
+    pub fn new() -> Self {
+        Scheduler {
+            jobs: Vec::new(),
+            state: ScheduleState::default(),
+            state_path: None,
+            started_at: SystemTime::now(),
+        }
+    }
+
+    /// Scheduler persisting last-run times to `path`, loading any existing state.
+    pub fn with_state_file<P: AsRef<Path>>(path: P) -> Result<Self, ScheduleError> {
+        let path = path.as_ref().to_path_buf();
+        let state = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ScheduleState::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Scheduler {
+            state,
+            state_path: Some(path),
+            ..Self::new()
+        })
+    }
+
+    pub fn add<F, Fut>(
+        &mut self,
+        name: &str,
+        schedule: Schedule,
+        job: F,
+    ) -> Result<(), ScheduleError>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.insert(name, Some(schedule), job)
+    }
+
+    /// Add a job that only runs when started with `run_now`
+    pub fn add_unscheduled<F, Fut>(&mut self, name: &str, job: F) -> Result<(), ScheduleError>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.insert(name, None, job)
+    }
+
+    fn insert<F, Fut>(
+        &mut self,
+        name: &str,
+        schedule: Option<Schedule>,
+        job: F,
+    ) -> Result<(), ScheduleError>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        if self.jobs.iter().any(|j| j.name == name) {
+            return Err(ScheduleError::DuplicateJob(name.to_string()));
+        }
+        self.jobs.push(ScheduledJob {
+            name: name.to_string(),
+            schedule,
+            run: Arc::new(move || Box::pin(job()) as JobFuture),
+            running: Arc::new(AtomicBool::new(false)),
+        });
+        Ok(())
+    }
+
+    /// Drop the named job, returning false if there was none. A run in progress is
+    /// left to finish, and the job's last run is remembered in case it is added again.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.jobs.len();
+        self.jobs.retain(|j| j.name != name);
+        self.jobs.len() < before
+    }
+
+    /// The names of the jobs, in the order they were added
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.jobs.iter().map(|j| j.name.as_str())
+    }
+
+    pub fn last_run(&self, name: &str) -> Option<SystemTime> {
+        self.state.last_runs.get(name).copied()
+    }
+
+    /// When the named job is next due, if it is registered and has a future match
+    pub fn next_run(&self, name: &str) -> Option<SystemTime> {
+        let job = self.jobs.iter().find(|j| j.name == name)?;
+        self.next_run_of(job, SystemTime::now())
+    }
+
+    pub fn is_running(&self, name: &str) -> bool {
+        self.jobs
+            .iter()
+            .any(|j| j.name == name && j.running.load(Ordering::SeqCst))
+    }
+
+    fn next_run_of(&self, job: &ScheduledJob, now: SystemTime) -> Option<SystemTime> {
+        let last = self.last_run(&job.name);
+        // Cron jobs that never ran count from scheduler start, not from the epoch.
+        let schedule = job.schedule.as_ref()?;
+        let anchor = match (schedule, last) {
+            (Schedule::Cron(_), None) => Some(self.started_at),
+            _ => last,
+        };
+        schedule.next_run(anchor, now)
+    }
+
+    /// Start every job due at `now` that is not already running, returning their names.
+    pub fn run_pending_at(&mut self, now: SystemTime) -> Result<Vec<String>, ScheduleError> {
+        let mut started = Vec::new();
+        for job in &self.jobs {
+            let due = self.next_run_of(job, now).is_some_and(|t| t <= now);
+            if due && start(job) {
+                started.push(job.name.clone());
+            }
+        }
+
+        if !started.is_empty() {
+            for name in &started {
+                self.state.last_runs.insert(name.clone(), now);
+            }
+            self.save()?;
+        }
+        Ok(started)
+    }
+
+    pub fn run_pending(&mut self) -> Result<Vec<String>, ScheduleError> {
+        self.run_pending_at(SystemTime::now())
+    }
+
+    /// Start the named job now, whatever its schedule, unless it is already running.
+    /// Returns whether it was started; false too if there is no such job.
+    pub fn run_now(&mut self, name: &str) -> Result<bool, ScheduleError> {
+        let Some(job) = self.jobs.iter().find(|j| j.name == name) else {
+            return Ok(false);
+        };
+        if !start(job) {
+            return Ok(false);
+        }
+        self.state
+            .last_runs
+            .insert(name.to_string(), SystemTime::now());
+        self.save()?;
+        Ok(true)
+    }
+
+    /// How long to wait before calling `run_pending` again: until the next job is
+    /// due, but at least once a minute so clock changes are picked up
+    pub fn next_wait(&self) -> Duration {
+        let now = SystemTime::now();
+        let next = self
+            .jobs
+            .iter()
+            .filter_map(|j| self.next_run_of(j, now))
+            .min();
+        let wait = match next {
+            Some(t) => t.duration_since(now).unwrap_or(Duration::ZERO),
+            None => Duration::from_secs(60),
+        };
+        wait.clamp(Duration::from_millis(100), Duration::from_secs(60))
+    }
+
+    /// Drive the scheduler forever, sleeping until the next job is due.
+    pub async fn run(mut self) -> Result<(), ScheduleError> {
+        loop {
+            self.run_pending()?;
+            tokio::time::sleep(self.next_wait()).await;
+        }
+    }
+
+    fn save(&self) -> Result<(), ScheduleError> {
+        let Some(path) = &self.state_path else {
+            return Ok(());
+        };
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&self.state)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Spawn a run of `job` unless one is in progress, returning whether it was started
+fn start(job: &ScheduledJob) -> bool {
+    if job.running.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+    let running = job.running.clone();
+    let fut = (job.run)();
+    tokio::spawn(async move {
+        fut.await;
+        running.store(false, Ordering::SeqCst);
+    });
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, mo, d)
+            .unwrap()
+            .and_hms_opt(h, mi, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_cron_next_after() {
+        let nightly = CronExpr::parse("0 3 * * *").unwrap();
+        assert_eq!(
+            nightly.next_after(at(2024, 5, 1, 2, 59)),
+            Some(at(2024, 5, 1, 3, 0))
+        );
+        assert_eq!(
+            nightly.next_after(at(2024, 5, 1, 3, 0)),
+            Some(at(2024, 5, 2, 3, 0))
+        );
+
+        let quarter = CronExpr::parse("*/15 9-17 * * 1-5").unwrap();
+        // Saturday 2024-05-04 -> Monday 09:00
+        assert_eq!(
+            quarter.next_after(at(2024, 5, 4, 12, 0)),
+            Some(at(2024, 5, 6, 9, 0))
+        );
+        assert_eq!(
+            quarter.next_after(at(2024, 5, 6, 9, 1)),
+            Some(at(2024, 5, 6, 9, 15))
+        );
+
+        let monthly = CronExpr::parse("@monthly").unwrap();
+        assert_eq!(
+            monthly.next_after(at(2024, 12, 15, 0, 0)),
+            Some(at(2025, 1, 1, 0, 0))
+        );
+
+        // Sunday as 7, and day-of-month OR day-of-week when both are set
+        let sunday = CronExpr::parse("0 0 * * 7").unwrap();
+        assert_eq!(
+            sunday.next_after(at(2024, 5, 1, 0, 0)),
+            Some(at(2024, 5, 5, 0, 0))
+        );
+        let either = CronExpr::parse("0 0 13 * 5").unwrap();
+        assert_eq!(
+            either.next_after(at(2024, 5, 1, 0, 0)),
+            Some(at(2024, 5, 3, 0, 0))
+        );
+
+        assert_eq!(
+            CronExpr::parse("0 0 31 2 *")
+                .unwrap()
+                .next_after(at(2024, 1, 1, 0, 0)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_cron_parse_errors() {
+        assert!(CronExpr::parse("* * * *").is_err());
+        assert!(CronExpr::parse("60 * * * *").is_err());
+        assert!(CronExpr::parse("*/0 * * * *").is_err());
+        assert!(CronExpr::parse("5-1 * * * *").is_err());
+        assert!(CronExpr::parse("a * * * *").is_err());
+    }
+
+    #[test]
+    fn test_schedule_from_str() {
+        assert_eq!(
+            "every 6h".parse::<Schedule>().unwrap(),
+            Schedule::Interval(Duration::from_secs(6 * 3600))
+        );
+        assert_eq!(
+            "every 1w".parse::<Schedule>().unwrap(),
+            Schedule::Interval(Duration::from_secs(604_800))
+        );
+        assert!("every 0m".parse::<Schedule>().is_err());
+        assert!("every soon".parse::<Schedule>().is_err());
+        assert!(matches!(
+            "0 3 * * 0".parse::<Schedule>().unwrap(),
+            Schedule::Cron(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_scheduler_prevents_overlap_and_persists() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("schedule.json");
+
+        let (release_tx, release_rx) = tokio::sync::watch::channel(false);
+        let mut scheduler = Scheduler::with_state_file(&path).unwrap();
+        scheduler
+            .add(
+                "rescan",
+                Schedule::Interval(Duration::from_secs(1)),
+                move || {
+                    let mut rx = release_rx.clone();
+                    async move {
+                        let _ = rx.wait_for(|released| *released).await;
+                    }
+                },
+            )
+            .unwrap();
+        assert!(
+            scheduler
+                .add(
+                    "rescan",
+                    Schedule::Interval(Duration::from_secs(1)),
+                    || async {}
+                )
+                .is_err()
+        );
+
+        let t0 = SystemTime::now();
+        assert_eq!(
+            scheduler.run_pending_at(t0).unwrap(),
+            vec!["rescan".to_string()]
+        );
+        assert!(scheduler.is_running("rescan"));
+
+        // Due again, but the first run has not finished
+        let t1 = t0 + Duration::from_secs(5);
+        assert!(scheduler.run_pending_at(t1).unwrap().is_empty());
+
+        release_tx.send(true).unwrap();
+        while scheduler.is_running("rescan") {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(scheduler.run_pending_at(t1).unwrap().len(), 1);
+
+        let reloaded = Scheduler::with_state_file(&path).unwrap();
+        assert_eq!(reloaded.last_run("rescan"), Some(t1));
+    }
+
+    #[tokio::test]
+    async fn test_run_now_shares_the_overlap_guard() {
+        let (release_tx, release_rx) = tokio::sync::watch::channel(false);
+        let mut scheduler = Scheduler::new();
+        scheduler
+            .add_unscheduled("rescan", move || {
+                let mut rx = release_rx.clone();
+                async move {
+                    let _ = rx.wait_for(|released| *released).await;
+                }
+            })
+            .unwrap();
+        let later = SystemTime::now() + Duration::from_secs(3600);
+        // Never due by itself
+        assert!(scheduler.run_pending_at(later).unwrap().is_empty());
+        assert_eq!(scheduler.next_run("rescan"), None);
+
+        assert!(scheduler.run_now("rescan").unwrap());
+        assert!(scheduler.last_run("rescan").is_some());
+        assert!(!scheduler.run_now("rescan").unwrap());
+        release_tx.send(true).unwrap();
+        while scheduler.is_running("rescan") {
+            tokio::task::yield_now().await;
+        }
+        assert!(scheduler.run_now("rescan").unwrap());
+
+        assert!(scheduler.remove("rescan"));
+        assert!(!scheduler.run_now("rescan").unwrap());
+        assert_eq!(scheduler.names().count(), 0);
+    }
+}
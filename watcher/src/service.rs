@@ -0,0 +1,398 @@
+use std::{
+    io,
+    net::TcpListener,
+    os::{
+        fd::{FromRawFd, OwnedFd, RawFd},
+        unix::net::{UnixDatagram, UnixListener},
+    },
+    path::PathBuf,
+    time::Duration,
+};
+
+/// The first descriptor systemd passes; the rest follow it
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Tells systemd how the daemon is doing, through `$NOTIFY_SOCKET` as
+/// `sd_notify(3)` does. Without that variable, as when not started by systemd with
+/// `Type=notify`, every notification is quietly dropped.
+#[derive(Debug, Clone, Default)]
+pub struct ServiceNotifier {
+    socket: Option<NotifySocket>,
+    watchdog: Option<Duration>,
+}
+
+#[derive(Debug, Clone)]
+enum NotifySocket {
+    Path(PathBuf),
+    /// A Linux abstract socket, given with a leading `@`
+    Abstract(Vec<u8>),
+}
+
+impl ServiceNotifier {
+    /// The notifier systemd set up for this process, if any. The watchdog interval is
+    /// taken from `$WATCHDOG_USEC` when `$WATCHDOG_PID` is this process or unset.
+    pub fn from_env() -> Self {
+        let socket = std::env::var_os("NOTIFY_SOCKET").and_then(|s| notify_socket(&s));
+        let watchdog_pid = std::env::var("WATCHDOG_PID").ok();
+        let watchdog_usec = std::env::var("WATCHDOG_USEC").ok();
+        ServiceNotifier {
+            socket,
+            watchdog: watchdog_timeout(
+                watchdog_usec.as_deref(),
+                watchdog_pid.as_deref(),
+                std::process::id(),
+            ),
+        }
+    }
+
+    /// A notifier sending to the socket at `path`, with watchdog pings expected every
+    /// `watchdog` if given
+    pub fn new<P: Into<PathBuf>>(path: P, watchdog: Option<Duration>) -> Self {
+        ServiceNotifier {
+            socket: Some(NotifySocket::Path(path.into())),
+            watchdog,
+        }
+    }
+
+    /// Whether there is a service manager listening
+    pub fn is_enabled(&self) -> bool {
+        self.socket.is_some()
+    }
+
+    /// How often to call `watchdog`: half the timeout systemd enforces, so one late
+    /// ping does not get the daemon killed
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog.map(|timeout| timeout / 2)
+    }
+
+    /// Startup is finished and the control socket is being served
+    pub fn ready(&self) -> io::Result<()> {
+        self.notify("READY=1")
+    }
+
+    /// The configuration is being reloaded; follow with `ready` once it is
+    pub fn reloading(&self) -> io::Result<()> {
+        // systemd wants the time the reload began, for Type=notify-reload
+        let mut now = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // SAFETY: clock_gettime only writes to the struct it is given
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+        let usec = now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1_000;
+        self.notify(&format!("RELOADING=1\nMONOTONIC_USEC={usec}"))
+    }
+
+    /// The daemon is shutting down
+    pub fn stopping(&self) -> io::Result<()> {
+        self.notify("STOPPING=1")
+    }
+
+    /// The daemon is still alive
+    pub fn watchdog(&self) -> io::Result<()> {
+        self.notify("WATCHDOG=1")
+    }
+
+    /// A line for `systemctl status` to show
+    pub fn status(&self, status: &str) -> io::Result<()> {
+        self.notify(&format!("STATUS={}", status.replace('\n', " ")))
+    }
+
+    /// Send `state`, newline-separated `KEY=value` assignments, to the service manager
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        let Some(socket) = &self.socket else {
+            return Ok(());
+        };
+        let datagram = UnixDatagram::unbound()?;
+        match socket {
+            NotifySocket::Path(path) => datagram.send_to(state.as_bytes(), path)?,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            NotifySocket::Abstract(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                datagram.send_to_addr(state.as_bytes(), &addr)?
+            }
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            NotifySocket::Abstract(_) => return Err(io::ErrorKind::Unsupported.into()),
+        };
+        Ok(())
+    }
+}
+
+fn notify_socket(value: &std::ffi::OsStr) -> Option<NotifySocket> {
+    use std::os::unix::ffi::OsStrExt;
+    match value.as_bytes() {
+        [] => None,
+        [b'@', name @ ..] => Some(NotifySocket::Abstract(name.to_vec())),
+        [b'/', ..] => Some(NotifySocket::Path(PathBuf::from(value))),
+        // vsock and other addresses are not supported
+        _ => None,
+    }
+}
+
+fn watchdog_timeout(usec: Option<&str>, pid: Option<&str>, ours: u32) -> Option<Duration> {
+    if pid.is_some_and(|pid| pid.parse() != Ok(ours)) {
+        return None;
+    }
+    let usec: u64 = usec?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Listening sockets the service manager opened for the daemon before starting it,
+/// so it can be started on the first connection and restarted without refusing any.
+///
+/// Under systemd these are the sockets of the matching `.socket` unit, named with
+/// `FileDescriptorName=`. Under launchd they are the `Sockets` entries of the job's
+/// plist, checked in by name as they are taken.
+#[derive(Debug, Default)]
+pub struct ActivatedSockets {
+    sockets: Vec<(Option<String>, OwnedFd)>,
+}
+
+impl ActivatedSockets {
+    /// The sockets systemd passed this process in `$LISTEN_FDS`, if it was meant for
+    /// this process rather than a parent's
+    pub fn from_env() -> Self {
+        let fds = std::env::var("LISTEN_FDS").ok();
+        let pid = std::env::var("LISTEN_PID").ok();
+        let names = std::env::var("LISTEN_FDNAMES").ok();
+        let Some(names) = listen_fds(
+            fds.as_deref(),
+            pid.as_deref(),
+            names.as_deref(),
+            std::process::id(),
+        ) else {
+            return ActivatedSockets::default();
+        };
+        let sockets = names
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let fd = LISTEN_FDS_START + i as RawFd;
+                // SAFETY: systemd passed these descriptors to this process and nothing
+                // else takes them; they must not leak into processes we start
+                unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+                (name, unsafe { OwnedFd::from_raw_fd(fd) })
+            })
+            .collect();
+        ActivatedSockets { sockets }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sockets.is_empty()
+    }
+
+    /// The Unix socket named `name`, or else the first unnamed Unix socket left
+    pub fn take_unix(&mut self, name: &str) -> Option<UnixListener> {
+        if let Some(listener) = self.take(name, |fd| {
+            let listener = UnixListener::from(fd);
+            match listener.local_addr() {
+                Ok(_) => Ok(listener),
+                Err(_) => Err(OwnedFd::from(listener)),
+            }
+        }) {
+            return Some(listener);
+        }
+        launchd_sockets(name)
+            .into_iter()
+            .next()
+            .map(UnixListener::from)
+    }
+
+    /// The TCP socket named `name`, or else the first unnamed TCP socket left
+    pub fn take_tcp(&mut self, name: &str) -> Option<TcpListener> {
+        if let Some(listener) = self.take(name, |fd| {
+            let listener = TcpListener::from(fd);
+            match listener.local_addr() {
+                Ok(_) => Ok(listener),
+                Err(_) => Err(OwnedFd::from(listener)),
+            }
+        }) {
+            return Some(listener);
+        }
+        launchd_sockets(name)
+            .into_iter()
+            .next()
+            .map(TcpListener::from)
+    }
+
+    fn take<T>(&mut self, name: &str, kind: impl Fn(OwnedFd) -> Result<T, OwnedFd>) -> Option<T> {
+        if let Some(i) = self
+            .sockets
+            .iter()
+            .position(|(n, _)| n.as_deref() == Some(name))
+        {
+            let (name, fd) = self.sockets.remove(i);
+            return match kind(fd) {
+                Ok(socket) => Some(socket),
+                Err(fd) => {
+                    // A socket of the wrong kind is left for whoever wants that kind
+                    self.sockets.insert(i, (name, fd));
+                    None
+                }
+            };
+        }
+        let mut i = 0;
+        while i < self.sockets.len() {
+            if self.sockets[i].0.is_some() {
+                i += 1;
+                continue;
+            }
+            let (_, fd) = self.sockets.remove(i);
+            match kind(fd) {
+                Ok(socket) => return Some(socket),
+                Err(fd) => {
+                    self.sockets.insert(i, (None, fd));
+                    i += 1;
+                }
+            }
+        }
+        None
+    }
+
+    #[cfg(test)]
+    fn from_fds(sockets: Vec<(Option<String>, OwnedFd)>) -> Self {
+        ActivatedSockets { sockets }
+    }
+}
+
+/// The name each passed descriptor has, or `None` where the environment does not
+/// pass any to process `ours`. systemd names sockets without `FileDescriptorName=`
+/// after their unit, which is no help in telling them apart, so "unknown" and
+/// names ending in `.socket` count as unnamed.
+fn listen_fds(
+    fds: Option<&str>,
+    pid: Option<&str>,
+    names: Option<&str>,
+    ours: u32,
+) -> Option<Vec<Option<String>>> {
+    if pid?.parse::<u32>().ok()? != ours {
+        return None;
+    }
+    let count: usize = fds?.parse().ok()?;
+    let mut names: Vec<_> = names
+        .map(|names| names.split(':').map(str::to_string).collect())
+        .unwrap_or_default();
+    names.resize(count, String::new());
+    Some(
+        names
+            .into_iter()
+            .take(count)
+            .map(|name| {
+                let unnamed = name.is_empty() || name == "unknown" || name.ends_with(".socket");
+                (!unnamed).then_some(name)
+            })
+            .collect(),
+    )
+}
+
+/// The sockets launchd opened for the `Sockets` entry `name` of this job, or none when
+/// it has no such entry or the process was not started by launchd
+#[cfg(target_os = "macos")]
+fn launchd_sockets(name: &str) -> Vec<OwnedFd> {
+    use std::os::raw::{c_char, c_int};
+    unsafe extern "C" {
+        fn launch_activate_socket(
+            name: *const c_char,
+            fds: *mut *mut c_int,
+            cnt: *mut libc::size_t,
+        ) -> c_int;
+    }
+    let Ok(name) = std::ffi::CString::new(name) else {
+        return Vec::new();
+    };
+    let mut fds: *mut c_int = std::ptr::null_mut();
+    let mut count: libc::size_t = 0;
+    // SAFETY: on success launchd hands over `count` descriptors in an array it
+    // allocated with malloc, which is ours to free
+    unsafe {
+        if launch_activate_socket(name.as_ptr(), &mut fds, &mut count) != 0 || fds.is_null() {
+            return Vec::new();
+        }
+        let sockets = std::slice::from_raw_parts(fds, count)
+            .iter()
+            .map(|&fd| OwnedFd::from_raw_fd(fd))
+            .collect();
+        libc::free(fds.cast());
+        sockets
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn launchd_sockets(_name: &str) -> Vec<OwnedFd> {
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notifications() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let manager = UnixDatagram::bind(&path).unwrap();
+        let notifier = ServiceNotifier::new(&path, Some(Duration::from_secs(30)));
+        assert_eq!(notifier.watchdog_interval(), Some(Duration::from_secs(15)));
+
+        let mut buf = [0; 256];
+        notifier.ready().unwrap();
+        let n = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        notifier.status("watching\n2 roots").unwrap();
+        let n = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"STATUS=watching 2 roots");
+        notifier.reloading().unwrap();
+        let n = manager.recv(&mut buf).unwrap();
+        assert!(buf[..n].starts_with(b"RELOADING=1\nMONOTONIC_USEC="));
+
+        let unmanaged = ServiceNotifier::default();
+        assert!(!unmanaged.is_enabled());
+        unmanaged.ready().unwrap();
+    }
+
+    #[test]
+    fn test_environment_parsing() {
+        assert!(matches!(
+            notify_socket("@/org/freedesktop/systemd1/notify".as_ref()),
+            Some(NotifySocket::Abstract(name)) if name == b"/org/freedesktop/systemd1/notify"
+        ));
+        assert!(notify_socket("vsock:2:1234".as_ref()).is_none());
+
+        let timeout = Some(Duration::from_secs(20));
+        assert_eq!(watchdog_timeout(Some("20000000"), None, 7), timeout);
+        assert_eq!(watchdog_timeout(Some("20000000"), Some("7"), 7), timeout);
+        assert_eq!(watchdog_timeout(Some("20000000"), Some("8"), 7), None);
+        assert_eq!(watchdog_timeout(Some("0"), None, 7), None);
+
+        assert_eq!(listen_fds(Some("2"), Some("8"), None, 7), None);
+        assert_eq!(
+            listen_fds(Some("3"), Some("7"), Some("control:otolith.socket"), 7),
+            Some(vec![Some("control".to_string()), None, None])
+        );
+    }
+
+    #[test]
+    fn test_take_sockets_by_name_and_kind() {
+        let dir = tempfile::tempdir().unwrap();
+        let unix = UnixListener::bind(dir.path().join("control")).unwrap();
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let mut sockets = ActivatedSockets::from_fds(vec![
+            (None, OwnedFd::from(tcp)),
+            (None, OwnedFd::from(unix)),
+        ]);
+        // The TCP socket is passed over, not consumed, looking for a Unix one
+        let control = sockets.take_unix("control").unwrap();
+        assert!(control.local_addr().unwrap().as_pathname().is_some());
+        let status = sockets.take_tcp("status").unwrap();
+        assert_eq!(status.local_addr().unwrap(), addr);
+        assert!(sockets.is_empty());
+
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sockets =
+            ActivatedSockets::from_fds(vec![(Some("status".to_string()), OwnedFd::from(tcp))]);
+        assert!(sockets.take_tcp("gateway").is_none());
+        assert!(sockets.take_tcp("status").is_some());
+    }
+}
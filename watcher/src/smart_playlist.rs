@@ -0,0 +1,298 @@
+use crate::{
+    index::{Index, IndexEntry, IndexQuery, SharedIndex},
+    playlist::{Playlist, PlaylistEntry, PlaylistError, absolute_location, relative_location},
+    universal_path::UniversalPath,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    time::{Duration, SystemTime},
+};
+use tokio::sync::watch;
+
+/// What a smart playlist's tracks are ordered by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaylistSort {
+    #[default]
+    Path,
+    /// When each first appeared in the index
+    Added,
+    Modified,
+    Size,
+}
+
+/// A playlist kept as the index query that picks its tracks, such as "FLAC added in
+/// the last 30 days, newest first", rather than as a list of them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SmartPlaylist {
+    pub name: String,
+    #[serde(default)]
+    pub query: IndexQuery,
+    /// Only tracks added to the index this long before the playlist is evaluated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub added_within: Option<Duration>,
+    #[serde(default)]
+    pub sort: PlaylistSort,
+    #[serde(default)]
+    pub descending: bool,
+    /// At most this many tracks, the first after sorting
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+impl SmartPlaylist {
+    /// Every indexed track matching `query`, in path order
+    pub fn new<S: Into<String>>(name: S, query: IndexQuery) -> Self {
+        SmartPlaylist {
+            name: name.into(),
+            query,
+            added_within: None,
+            sort: PlaylistSort::default(),
+            descending: false,
+            limit: None,
+        }
+    }
+
+    pub fn with_added_within(mut self, within: Duration) -> Self {
+        self.added_within = Some(within);
+        self
+    }
+
+    pub fn with_sort(mut self, sort: PlaylistSort, descending: bool) -> Self {
+        self.sort = sort;
+        self.descending = descending;
+        self
+    }
+
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// The tracks of the playlist as of `now`, sorted and limited. Ties keep path
+    /// order.
+    pub fn evaluate<'a>(&'a self, index: &'a Index, now: SystemTime) -> Vec<&'a IndexEntry> {
+        let since = self
+            .added_within
+            .map(|within| now.checked_sub(within).unwrap_or(SystemTime::UNIX_EPOCH));
+        let mut tracks: Vec<_> = index
+            .query(&self.query)
+            .filter(|entry| since.is_none_or(|since| entry.added_at >= since))
+            .collect();
+        tracks.sort_by(|a, b| {
+            let order = match self.sort {
+                PlaylistSort::Path => Ordering::Equal,
+                PlaylistSort::Added => a.added_at.cmp(&b.added_at),
+                PlaylistSort::Modified => a.modified_at.cmp(&b.modified_at),
+                PlaylistSort::Size => a.size_bytes.cmp(&b.size_bytes),
+            };
+            match self.descending {
+                true => order.reverse(),
+                false => order,
+            }
+        });
+        if self.sort == PlaylistSort::Path && self.descending {
+            tracks.reverse();
+        }
+        tracks.truncate(self.limit.unwrap_or(usize::MAX));
+        tracks
+    }
+
+    /// The paths of the tracks as of now
+    pub fn paths(&self, index: &Index) -> Vec<UniversalPath> {
+        let tracks = self.evaluate(index, SystemTime::now());
+        tracks.into_iter().map(|entry| entry.path.clone()).collect()
+    }
+
+    /// Keep the playlist up to date with `index`: the receiver holds its tracks and
+    /// sees a change whenever a commit changes them. It stops following once every
+    /// receiver is dropped. Tracks that age out of `added_within` go at the next commit.
+    pub fn follow(&self, index: &SharedIndex) -> watch::Receiver<Vec<UniversalPath>> {
+        let mut commits = index.subscribe();
+        let (tx, rx) = watch::channel(self.paths(&commits.borrow_and_update()));
+        let playlist = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    changed = commits.changed() => {
+                        if changed.is_err() {
+                            return;
+                        }
+                    }
+                    _ = tx.closed() => return,
+                }
+                let paths = playlist.paths(&commits.borrow_and_update());
+                tx.send_if_modified(|current| {
+                    let modified = *current != paths;
+                    if modified {
+                        *current = paths;
+                    }
+                    modified
+                });
+            }
+        });
+        rx
+    }
+}
+
+/// `tracks` as a playlist to be written in `dir`, each entry relative to it where it
+/// can be and absolute otherwise, with `to_m3u` giving the .m3u8 text
+pub fn playlist_under(
+    tracks: &[UniversalPath],
+    dir: &UniversalPath,
+) -> Result<Playlist, PlaylistError> {
+    let entries = tracks
+        .iter()
+        .map(|track| {
+            let location = match relative_location(track, dir) {
+                Some(relative) => relative,
+                None => absolute_location(track)?,
+            };
+            Ok(PlaylistEntry {
+                location,
+                duration: None,
+                title: None,
+            })
+        })
+        .collect::<Result<_, PlaylistError>>()?;
+    Ok(Playlist { entries })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::ChangeSource;
+    use crate::storage::{EntryKind, EntryMetadata, EntryPermissions};
+
+    const DAY: Duration = Duration::from_secs(86_400);
+
+    fn file(size: u64) -> EntryMetadata {
+        EntryMetadata {
+            kind: EntryKind::File,
+            size_bytes: Some(size),
+            modified_at: None,
+            created_at: None,
+            permissions: EntryPermissions::default(),
+            is_hidden: false,
+            format: None,
+        }
+    }
+
+    /// Tracks under `mem://nas/music/` added `days` before `now`, of the given size
+    fn library(now: SystemTime, tracks: &[(&str, u64, u64)]) -> Index {
+        let mut index = Index::new();
+        let root = UniversalPath::from_uri_str("mem://nas/music/").unwrap();
+        for &(name, days, size) in tracks {
+            let at = now - DAY * days as u32;
+            index.update(&root.join(name), &file(size), ChangeSource::Scan, at);
+        }
+        index
+    }
+
+    fn names(tracks: &[&IndexEntry]) -> Vec<String> {
+        let name = |entry: &&IndexEntry| entry.path.file_name().unwrap().to_string();
+        tracks.iter().map(name).collect()
+    }
+
+    #[test]
+    fn test_recent_flac() {
+        let now = SystemTime::now();
+        let index = library(
+            now,
+            &[
+                ("a.flac", 40, 30),
+                ("b.flac", 2, 10),
+                ("c.mp3", 1, 20),
+                ("d.flac", 10, 20),
+                ("e.flac", 29, 40),
+            ],
+        );
+        let recent = SmartPlaylist::new("Recent FLAC", IndexQuery::new().with_extension("flac"))
+            .with_added_within(DAY * 30)
+            .with_sort(PlaylistSort::Added, true);
+        assert_eq!(
+            names(&recent.evaluate(&index, now)),
+            ["b.flac", "d.flac", "e.flac"]
+        );
+
+        let biggest = recent
+            .clone()
+            .with_sort(PlaylistSort::Size, true)
+            .with_limit(2);
+        assert_eq!(names(&biggest.evaluate(&index, now)), ["e.flac", "d.flac"]);
+        let by_path = recent.with_sort(PlaylistSort::Path, true);
+        assert_eq!(
+            names(&by_path.evaluate(&index, now)),
+            ["e.flac", "d.flac", "b.flac"]
+        );
+    }
+
+    #[test]
+    fn test_saved_definition() {
+        let playlist = SmartPlaylist::new("Recent FLAC", IndexQuery::new().with_extension("flac"))
+            .with_added_within(DAY * 30)
+            .with_sort(PlaylistSort::Added, true)
+            .with_limit(50);
+        let json = serde_json::to_string(&playlist).unwrap();
+        assert_eq!(
+            serde_json::from_str::<SmartPlaylist>(&json).unwrap(),
+            playlist
+        );
+        let bare: SmartPlaylist = serde_json::from_str(r#"{"name": "Everything"}"#).unwrap();
+        assert_eq!(bare, SmartPlaylist::new("Everything", IndexQuery::new()));
+    }
+
+    #[test]
+    fn test_export_relative_to_root() {
+        let uri = |uri: &str| UniversalPath::from_uri_str(uri).unwrap();
+        let music = uri("mem://nas/music/");
+        let tracks = [
+            music.join_all(&["Björk", "Post", "01 Army of Me.flac"]),
+            music.join("Loose.flac"),
+            uri("s3://bucket/elsewhere.flac"),
+        ];
+        let playlist = playlist_under(&tracks, &music.join("Playlists/")).unwrap();
+        assert_eq!(
+            playlist.to_m3u(),
+            "#EXTM3U\n../Björk/Post/01 Army of Me.flac\n../Loose.flac\ns3://bucket/elsewhere.flac\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_follow_changes() {
+        let now = SystemTime::now();
+        let shared = SharedIndex::new(library(now, &[("a.flac", 1, 10)]));
+        let playlist = SmartPlaylist::new("FLAC", IndexQuery::new().with_extension("flac"));
+        let mut tracks = playlist.follow(&shared);
+        assert_eq!(tracks.borrow_and_update().len(), 1);
+
+        // A commit that adds a match is seen, one that does not is not
+        let root = UniversalPath::from_uri_str("mem://nas/music/").unwrap();
+        shared
+            .update(|index| {
+                index
+                    .update(&root.join("b.flac"), &file(5), ChangeSource::Watch, now)
+                    .is_some()
+            })
+            .await;
+        tracks.changed().await.unwrap();
+        assert_eq!(tracks.borrow_and_update().len(), 2);
+        shared
+            .update(|index| {
+                index
+                    .update(&root.join("c.mp3"), &file(5), ChangeSource::Watch, now)
+                    .is_some()
+            })
+            .await;
+        shared
+            .update(|index| {
+                index
+                    .remove(&root.join("a.flac"), ChangeSource::Watch, now)
+                    .is_some()
+            })
+            .await;
+        tracks.changed().await.unwrap();
+        assert_eq!(*tracks.borrow_and_update(), [root.join("b.flac")]);
+    }
+}
@@ -0,0 +1,462 @@
+use crate::storage::{
+    Checksum, ChecksumAlgorithm, EntryKind, EntryMetadata, Storage, StorageError, fd_budget,
+    mtimes_match,
+};
+use crate::universal_path::UniversalPath;
+use futures::{StreamExt, stream};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime},
+};
+
+mod file;
+
+pub use file::{
+    SnapshotChange, SnapshotChanges, SnapshotFileError, SnapshotReader, SnapshotWriter,
+    capture_snapshot_file, diff_snapshot_files,
+};
+
+/// One file or directory as a snapshot saw it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotEntry {
+    /// Directories end in `/`
+    pub path: UniversalPath,
+    pub is_dir: bool,
+    /// Files only
+    pub size_bytes: Option<u64>,
+    pub modified_at: Option<SystemTime>,
+    /// Of the contents, when the snapshot was taken with checksums
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<Checksum>,
+}
+
+impl SnapshotEntry {
+    /// Whether matching entries say enough to call a removal and an addition a move
+    fn identifies(&self) -> bool {
+        !self.is_dir
+            && self.size_bytes.is_some()
+            && (self.modified_at.is_some() || self.checksum.is_some())
+    }
+
+    /// Whether `other` looks like the same contents, allowing modification times to
+    /// drift by `tolerance`. Checksums are compared when both sides have one.
+    pub fn matches(&self, other: &SnapshotEntry, tolerance: Duration) -> bool {
+        let same_contents = match (&self.checksum, &other.checksum) {
+            (Some(a), Some(b)) if a.algorithm == b.algorithm => a == b,
+            _ => true,
+        };
+        self.is_dir == other.is_dir
+            && self.size_bytes == other.size_bytes
+            && same_contents
+            && mtimes_match(self.modified_at, other.modified_at, tolerance)
+    }
+
+    /// Whether `other` could be this file under another name: the same checksum and
+    /// size when both have a checksum, as copying between filesystems does not always
+    /// keep modification times, and otherwise the same size and modification time
+    fn same_file(&self, other: &SnapshotEntry, tolerance: Duration) -> bool {
+        match (&self.checksum, &other.checksum) {
+            (Some(a), Some(b)) if a.algorithm == b.algorithm => {
+                !self.is_dir && !other.is_dir && self.size_bytes == other.size_bytes && a == b
+            }
+            _ => self.matches(other, tolerance),
+        }
+    }
+}
+
+/// How `Snapshot::capture` walks a tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotOptions {
+    /// `list` or `stat` calls kept in flight at once
+    pub concurrency: usize,
+    /// Checksum every file, which reads all of them
+    pub checksum: Option<ChecksumAlgorithm>,
+}
+
+impl Default for SnapshotOptions {
+    fn default() -> Self {
+        SnapshotOptions {
+            concurrency: 8,
+            checksum: None,
+        }
+    }
+}
+
+impl SnapshotOptions {
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    pub fn with_checksums(mut self, algorithm: ChecksumAlgorithm) -> Self {
+        self.checksum = Some(algorithm);
+        self
+    }
+}
+
+/// What changed between two snapshots, each list in path order.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SnapshotDiff {
+    pub added: Vec<SnapshotEntry>,
+    pub removed: Vec<SnapshotEntry>,
+    /// Files whose size, modification time or checksum changed, as they are now
+    pub modified: Vec<SnapshotEntry>,
+    /// From the old path to the new, for files that disappeared in one place and
+    /// appeared unchanged in exactly one other, and for directories whose whole
+    /// contents moved with them, which are not listed entry by entry
+    pub moved: Vec<(UniversalPath, UniversalPath)>,
+}
+
+impl SnapshotDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.modified.is_empty()
+            && self.moved.is_empty()
+    }
+}
+
+/// Every entry under a root at one moment: path, size, modification time and,
+/// optionally, a checksum of the contents. Snapshots serialize, so one taken on an
+/// earlier run can be compared with a fresh one to see what changed in between.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    root: UniversalPath,
+    taken_at: SystemTime,
+    /// By the path's printed URI
+    entries: BTreeMap<String, SnapshotEntry>,
+}
+
+impl Snapshot {
+    /// An empty snapshot of `root`
+    pub fn new(root: UniversalPath) -> Self {
+        Snapshot {
+            root: root.as_dir(),
+            taken_at: SystemTime::now(),
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Walk everything under `root`, a level at a time. Entries that vanish between
+    /// the listing and the stat are left out; any other error fails the snapshot, so
+    /// a flaky connection does not look like everything was deleted. Each call, and
+    /// each file read for its checksum, takes a descriptor from `fd_budget()`.
+    pub async fn capture(
+        storage: &dyn Storage,
+        root: &UniversalPath,
+        options: &SnapshotOptions,
+    ) -> Result<Self, StorageError> {
+        let mut snapshot = Snapshot::new(root.clone());
+        let mut dirs = vec![root.as_dir()];
+        while !dirs.is_empty() {
+            let listed: Vec<_> = stream::iter(dirs.drain(..))
+                .map(|dir| async move {
+                    let _fd = fd_budget().acquire().await;
+                    match storage.list(&dir).await {
+                        Err(StorageError::NotFound) => Ok(Vec::new()),
+                        other => other,
+                    }
+                })
+                .buffer_unordered(options.concurrency)
+                .collect()
+                .await;
+            let children = listed.into_iter().collect::<Result<Vec<_>, _>>()?;
+            let stats: Vec<_> = stream::iter(children.into_iter().flatten())
+                .map(|child| async move {
+                    let _fd = fd_budget().acquire().await;
+                    entry(storage, &child, options.checksum).await
+                })
+                .buffer_unordered(options.concurrency)
+                .collect()
+                .await;
+            for entry in stats {
+                if let Some(entry) = entry? {
+                    if entry.is_dir {
+                        dirs.push(entry.path.clone());
+                    }
+                    snapshot.insert(entry);
+                }
+            }
+        }
+        Ok(snapshot)
+    }
+
+    pub fn root(&self) -> &UniversalPath {
+        &self.root
+    }
+
+    /// When the snapshot was started
+    pub fn taken_at(&self) -> SystemTime {
+        self.taken_at
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, path: &UniversalPath) -> Option<&SnapshotEntry> {
+        self.entries.get(&path.to_string())
+    }
+
+    /// Entries in path order
+    pub fn iter(&self) -> impl Iterator<Item = &SnapshotEntry> {
+        self.entries.values()
+    }
+
+    /// Add or replace the entry at its path
+    pub fn insert(&mut self, entry: SnapshotEntry) {
+        self.entries.insert(entry.path.to_string(), entry);
+    }
+
+    /// Files with exactly these contents
+    pub fn with_checksum<'a>(
+        &'a self,
+        checksum: &'a Checksum,
+    ) -> impl Iterator<Item = &'a SnapshotEntry> + 'a {
+        self.iter()
+            .filter(move |entry| entry.checksum.as_ref() == Some(checksum))
+    }
+
+    /// What changed from `old` to `new`, with modification times compared exactly
+    pub fn diff(old: &Snapshot, new: &Snapshot) -> SnapshotDiff {
+        Snapshot::diff_with_tolerance(old, new, Duration::ZERO)
+    }
+
+    /// What changed from `old` to `new`, not counting modification times that moved by
+    /// no more than `tolerance`, as servers that round times report them
+    pub fn diff_with_tolerance(
+        old: &Snapshot,
+        new: &Snapshot,
+        tolerance: Duration,
+    ) -> SnapshotDiff {
+        let mut removed: Vec<_> = old
+            .entries
+            .iter()
+            .filter(|(key, _)| !new.entries.contains_key(*key))
+            .map(|(_, entry)| entry)
+            .collect();
+        let mut added: Vec<_> = new
+            .entries
+            .iter()
+            .filter(|(key, _)| !old.entries.contains_key(*key))
+            .map(|(_, entry)| entry)
+            .collect();
+
+        // A move only when the contents are unique on both sides
+        let count = |entries: &[&SnapshotEntry], like: &SnapshotEntry| {
+            entries
+                .iter()
+                .filter(|other| other.same_file(like, tolerance))
+                .count()
+        };
+        let mut moved: Vec<_> = removed
+            .iter()
+            .filter(|entry| {
+                entry.identifies() && count(&removed, entry) == 1 && count(&added, entry) == 1
+            })
+            .filter_map(|from| {
+                let to = added
+                    .iter()
+                    .find(|other| other.same_file(from, tolerance))?;
+                Some((from.path.clone(), to.path.clone()))
+            })
+            .collect();
+        removed.retain(|entry| !moved.iter().any(|(from, _)| *from == entry.path));
+        added.retain(|entry| !moved.iter().any(|(_, to)| *to == entry.path));
+        collapse_dir_moves(old, new, tolerance, &mut removed, &mut added, &mut moved);
+
+        let modified = new
+            .entries
+            .iter()
+            .filter_map(|(key, entry)| {
+                let before = old.entries.get(key)?;
+                (!entry.is_dir && !before.matches(entry, tolerance)).then(|| entry.clone())
+            })
+            .collect();
+        SnapshotDiff {
+            added: added.into_iter().cloned().collect(),
+            removed: removed.into_iter().cloned().collect(),
+            modified,
+            moved,
+        }
+    }
+}
+
+/// Replace the moves of everything under a directory with one move of the directory,
+/// where all of it turned up at the same place under a new one. Files that were not
+/// unique enough to move on their own count when they turned up unchanged.
+fn collapse_dir_moves(
+    old: &Snapshot,
+    new: &Snapshot,
+    tolerance: Duration,
+    removed: &mut Vec<&SnapshotEntry>,
+    added: &mut Vec<&SnapshotEntry>,
+    moved: &mut Vec<(UniversalPath, UniversalPath)>,
+) {
+    let under = |path: &UniversalPath, dir: &UniversalPath| {
+        path.relative_to(dir).is_some_and(|rel| !rel.is_empty())
+    };
+    let in_list = |list: &[&SnapshotEntry], path: &UniversalPath| {
+        list.iter().any(|entry| entry.path == *path)
+    };
+    // Parents come before their children, so the outermost move wins
+    let dirs: Vec<_> = removed
+        .iter()
+        .filter(|entry| entry.is_dir)
+        .map(|entry| entry.path.clone())
+        .collect();
+    let mut dir_moves: Vec<(UniversalPath, UniversalPath)> = Vec::new();
+    for from_dir in dirs {
+        if dir_moves.iter().any(|(from, _)| under(&from_dir, from)) {
+            continue;
+        }
+        // Where one of its files went says where the directory went
+        let Some(to_dir) = moved.iter().find_map(|(from, to)| {
+            let rel = from.relative_to(&from_dir)?;
+            let mut to_dir = to.clone();
+            for _ in &rel {
+                to_dir.pop()?;
+            }
+            (to.relative_to(&to_dir)? == rel).then_some(to_dir)
+        }) else {
+            continue;
+        };
+        if !in_list(added, &to_dir) {
+            continue;
+        }
+        let before: Vec<_> = old.iter().filter(|e| under(&e.path, &from_dir)).collect();
+        let after = new.iter().filter(|e| under(&e.path, &to_dir)).count();
+        let whole = before.len() == after
+            && before.iter().all(|entry| {
+                let rel = entry.path.relative_to(&from_dir).unwrap_or_default();
+                let to = to_dir.join_all(&rel);
+                if entry.is_dir {
+                    return in_list(removed, &entry.path) && in_list(added, &to.as_dir());
+                }
+                moved.iter().any(|(f, t)| *f == entry.path && *t == to)
+                    || (in_list(removed, &entry.path)
+                        && new.get(&to).is_some_and(|e| {
+                            in_list(added, &e.path) && entry.same_file(e, tolerance)
+                        }))
+            });
+        if !whole {
+            continue;
+        }
+        moved.retain(|(from, _)| !under(from, &from_dir));
+        removed.retain(|entry| entry.path != from_dir && !under(&entry.path, &from_dir));
+        added.retain(|entry| entry.path != to_dir && !under(&entry.path, &to_dir));
+        dir_moves.push((from_dir, to_dir));
+    }
+    moved.extend(dir_moves);
+    moved.sort_by_cached_key(|(from, _)| from.to_string());
+}
+
+async fn entry(
+    storage: &dyn Storage,
+    path: &UniversalPath,
+    checksum: Option<ChecksumAlgorithm>,
+) -> Result<Option<SnapshotEntry>, StorageError> {
+    match storage.stat(path).await {
+        Ok(meta) => snapshot_entry(storage, path, meta, checksum).await,
+        // Gone between the listing and the stat
+        Err(StorageError::NotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// The entry for `path`, stat'ed as `meta`, checksummed if asked; none for links and
+/// anything else that is not a file or directory, or a file that vanished before it
+/// was read
+async fn snapshot_entry(
+    storage: &dyn Storage,
+    path: &UniversalPath,
+    meta: EntryMetadata,
+    checksum: Option<ChecksumAlgorithm>,
+) -> Result<Option<SnapshotEntry>, StorageError> {
+    let is_dir = match meta.kind {
+        EntryKind::File => false,
+        EntryKind::Directory => true,
+        EntryKind::Symlink | EntryKind::Other => return Ok(None),
+    };
+    let checksum = match checksum {
+        Some(algorithm) if !is_dir => match storage.checksum(path, algorithm).await {
+            Ok(checksum) => Some(checksum),
+            Err(StorageError::NotFound) => return Ok(None),
+            Err(e) => return Err(e),
+        },
+        _ => None,
+    };
+    Ok(Some(SnapshotEntry {
+        path: if is_dir { path.as_dir() } else { path.clone() },
+        is_dir,
+        size_bytes: meta.size_bytes.filter(|_| !is_dir),
+        modified_at: meta.modified_at,
+        checksum,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_snapshot_diff() {
+        let storage = MemoryStorage::new();
+        let root = UniversalPath::from_uri_str("mem://snap/").unwrap();
+        let file = |name: &str| root.join(name);
+        storage.write(&file("01.flac"), b"one").await.unwrap();
+        storage.write(&file("02.flac"), b"two").await.unwrap();
+        storage.write(&file("03.flac"), b"three").await.unwrap();
+        let options = SnapshotOptions::default().with_checksums(ChecksumAlgorithm::Xxh3);
+        let old = Snapshot::capture(&storage, &root, &options).await.unwrap();
+        assert_eq!(old.len(), 3);
+        // Survives a round trip, e.g. saved between runs
+        let saved = serde_json::to_string(&old).unwrap();
+        let old: Snapshot = serde_json::from_str(&saved).unwrap();
+        assert!(Snapshot::diff(&old, &old).is_empty());
+
+        storage
+            .rename(&file("02.flac"), &file("04.flac"))
+            .await
+            .unwrap();
+        storage
+            .write(&file("03.flac"), b"three, remastered")
+            .await
+            .unwrap();
+        storage.delete(&file("01.flac")).await.unwrap();
+        storage.write(&file("05.flac"), b"five").await.unwrap();
+        let new = Snapshot::capture(&storage, &root, &options).await.unwrap();
+        let diff = Snapshot::diff(&old, &new);
+        assert_eq!(diff.moved, vec![(file("02.flac"), file("04.flac"))]);
+        let paths = |entries: &[SnapshotEntry]| -> Vec<_> {
+            entries.iter().map(|e| e.path.clone()).collect()
+        };
+        assert_eq!(paths(&diff.removed), vec![file("01.flac")]);
+        assert_eq!(paths(&diff.added), vec![file("05.flac")]);
+        assert_eq!(paths(&diff.modified), vec![file("03.flac")]);
+
+        let four = new.get(&file("04.flac")).unwrap().checksum.clone().unwrap();
+        assert_eq!(new.with_checksum(&four).count(), 1);
+
+        // A renamed album is one move, even with two identical tracks in it
+        let album = root.join("Album").as_dir();
+        storage.create_dir(&album).await.unwrap();
+        for name in ["01.flac", "02.flac", "03.flac"] {
+            let contents: &[u8] = if name == "03.flac" { b"three" } else { b"same" };
+            storage.write(&album.join(name), contents).await.unwrap();
+        }
+        let old = Snapshot::capture(&storage, &root, &options).await.unwrap();
+        let renamed = root.join("Album (Deluxe)").as_dir();
+        storage.rename(&album, &renamed).await.unwrap();
+        let new = Snapshot::capture(&storage, &root, &options).await.unwrap();
+        let diff = Snapshot::diff(&old, &new);
+        assert_eq!(diff.moved, vec![(album, renamed)]);
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+    }
+}
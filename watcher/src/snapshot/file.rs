@@ -0,0 +1,481 @@
+use super::{SnapshotEntry, SnapshotOptions, snapshot_entry};
+use crate::collation::Collation;
+use crate::storage::{Checksum, Storage, StorageError, StorageExt, WalkOptions, WalkOrder};
+use crate::universal_path::UniversalPath;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use thiserror::Error;
+
+/// Start of every snapshot file
+const MAGIC: &[u8; 8] = b"OTSNAP\0\n";
+
+/// Version of the file `SnapshotWriter` writes
+const FORMAT_VERSION: u32 = 1;
+
+/// Longest record read back, so a damaged length cannot make a reader allocate
+/// gigabytes
+const MAX_RECORD: u32 = 16 << 20;
+
+#[derive(Debug, Error)]
+pub enum SnapshotFileError {
+    #[error("not a snapshot file")]
+    NotASnapshot,
+    #[error("snapshot file is version {0}, newer than this build reads")]
+    UnsupportedVersion(u32),
+    #[error("snapshot file ends early")]
+    Truncated,
+    #[error("snapshot entry {0} is out of order or outside the root")]
+    OutOfOrder(String),
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    root: UniversalPath,
+    taken_at: SystemTime,
+}
+
+/// An entry as kept on disk, by its path from the root
+#[derive(Serialize, Deserialize)]
+struct Record {
+    path: Vec<String>,
+    is_dir: bool,
+    size_bytes: Option<u64>,
+    modified_at: Option<SystemTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum: Option<Checksum>,
+}
+
+/// Writes a snapshot to disk an entry at a time, for trees too big to hold as a
+/// `Snapshot`.
+///
+/// The file is a header and then one length-prefixed record per entry, in path order
+/// from the root (segment by segment, by code point, as a sorted depth-first walk
+/// yields them), so two files can be diffed by reading both once. Entries must be
+/// appended in that order. Nothing is at `path` until `finish`, which moves the
+/// complete file into place.
+pub struct SnapshotWriter {
+    file: BufWriter<File>,
+    path: PathBuf,
+    tmp: PathBuf,
+    root: UniversalPath,
+    last: Option<Vec<String>>,
+    count: u64,
+}
+
+impl SnapshotWriter {
+    /// Start a snapshot of `root`, taken at `taken_at`, to be saved at `path`
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        root: &UniversalPath,
+        taken_at: SystemTime,
+    ) -> Result<Self, SnapshotFileError> {
+        let path = path.as_ref().to_path_buf();
+        let tmp = path.with_extension("tmp");
+        let mut file = BufWriter::new(File::create(&tmp)?);
+        file.write_all(MAGIC)?;
+        let root = root.as_dir();
+        let header = Header {
+            version: FORMAT_VERSION,
+            root: root.clone(),
+            taken_at,
+        };
+        write_record(&mut file, &serde_json::to_vec(&header)?)?;
+        Ok(SnapshotWriter {
+            file,
+            path,
+            tmp,
+            root,
+            last: None,
+            count: 0,
+        })
+    }
+
+    /// Add the next entry, which must come after the last one and lie under the root
+    pub fn append(&mut self, entry: &SnapshotEntry) -> Result<(), SnapshotFileError> {
+        let rel = entry
+            .path
+            .relative_to(&self.root)
+            .filter(|rel| !rel.is_empty())
+            .ok_or_else(|| SnapshotFileError::OutOfOrder(entry.path.to_string()))?;
+        if self.last.as_ref().is_some_and(|last| *last >= rel) {
+            return Err(SnapshotFileError::OutOfOrder(entry.path.to_string()));
+        }
+        let record = Record {
+            path: rel,
+            is_dir: entry.is_dir,
+            size_bytes: entry.size_bytes,
+            modified_at: entry.modified_at,
+            checksum: entry.checksum.clone(),
+        };
+        write_record(&mut self.file, &serde_json::to_vec(&record)?)?;
+        self.last = Some(record.path);
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Entries appended so far
+    pub fn len(&self) -> u64 {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Write the end of the file and move it into place, returning how many entries
+    /// it holds
+    pub fn finish(mut self) -> Result<u64, SnapshotFileError> {
+        self.file.write_all(&0u32.to_le_bytes())?;
+        self.file.write_all(&self.count.to_le_bytes())?;
+        let file = self.file.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        std::fs::rename(&self.tmp, &self.path)?;
+        Ok(self.count)
+    }
+}
+
+fn write_record(file: &mut impl Write, bytes: &[u8]) -> std::io::Result<()> {
+    let len = u32::try_from(bytes.len())
+        .ok()
+        .filter(|len| (1..=MAX_RECORD).contains(len))
+        .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidData, "record too long"))?;
+    file.write_all(&len.to_le_bytes())?;
+    file.write_all(bytes)
+}
+
+/// Reads a file `SnapshotWriter` wrote: entries in path order, one at a time. A file
+/// cut short yields `Truncated` rather than ending quietly.
+pub struct SnapshotReader {
+    file: BufReader<File>,
+    root: UniversalPath,
+    taken_at: SystemTime,
+    count: u64,
+    done: bool,
+}
+
+impl SnapshotReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, SnapshotFileError> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0; MAGIC.len()];
+        match file.read_exact(&mut magic) {
+            Ok(()) if magic == *MAGIC => {}
+            Ok(()) => return Err(SnapshotFileError::NotASnapshot),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                return Err(SnapshotFileError::NotASnapshot);
+            }
+            Err(e) => return Err(e.into()),
+        }
+        let header = read_record(&mut file)?.ok_or(SnapshotFileError::Truncated)?;
+        let header: Header = serde_json::from_slice(&header)?;
+        if header.version > FORMAT_VERSION {
+            return Err(SnapshotFileError::UnsupportedVersion(header.version));
+        }
+        Ok(SnapshotReader {
+            file,
+            root: header.root,
+            taken_at: header.taken_at,
+            count: 0,
+            done: false,
+        })
+    }
+
+    pub fn root(&self) -> &UniversalPath {
+        &self.root
+    }
+
+    /// When the snapshot was started
+    pub fn taken_at(&self) -> SystemTime {
+        self.taken_at
+    }
+
+    fn next_entry(&mut self) -> Result<Option<SnapshotEntry>, SnapshotFileError> {
+        let Some(bytes) = read_record(&mut self.file)? else {
+            let mut count = [0; 8];
+            self.file.read_exact(&mut count).map_err(eof_truncated)?;
+            if u64::from_le_bytes(count) != self.count {
+                return Err(SnapshotFileError::Truncated);
+            }
+            return Ok(None);
+        };
+        let record: Record = serde_json::from_slice(&bytes)?;
+        self.count += 1;
+        let path = self.root.join_all(&record.path);
+        Ok(Some(SnapshotEntry {
+            path: if record.is_dir { path.as_dir() } else { path },
+            is_dir: record.is_dir,
+            size_bytes: record.size_bytes,
+            modified_at: record.modified_at,
+            checksum: record.checksum,
+        }))
+    }
+}
+
+impl Iterator for SnapshotReader {
+    type Item = Result<SnapshotEntry, SnapshotFileError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let next = self.next_entry();
+        self.done = !matches!(next, Ok(Some(_)));
+        next.transpose()
+    }
+}
+
+/// The next record, or none at the end marker
+fn read_record(file: &mut impl Read) -> Result<Option<Vec<u8>>, SnapshotFileError> {
+    let mut len = [0; 4];
+    file.read_exact(&mut len).map_err(eof_truncated)?;
+    let len = u32::from_le_bytes(len);
+    if len == 0 {
+        return Ok(None);
+    }
+    if len > MAX_RECORD {
+        return Err(SnapshotFileError::Truncated);
+    }
+    let mut bytes = vec![0; len as usize];
+    file.read_exact(&mut bytes).map_err(eof_truncated)?;
+    Ok(Some(bytes))
+}
+
+fn eof_truncated(e: std::io::Error) -> SnapshotFileError {
+    match e.kind() {
+        ErrorKind::UnexpectedEof => SnapshotFileError::Truncated,
+        _ => e.into(),
+    }
+}
+
+/// Walk everything under `root` depth-first and write it to a snapshot file at
+/// `path` as it is found, so memory stays bounded by the depth of the tree rather
+/// than its size. Like `Snapshot::capture`, entries that vanish mid-walk are left out
+/// and any other error fails the snapshot. Returns how many entries were written.
+pub async fn capture_snapshot_file<P: AsRef<Path>>(
+    storage: &dyn Storage,
+    root: &UniversalPath,
+    options: &SnapshotOptions,
+    path: P,
+) -> Result<u64, SnapshotFileError> {
+    let mut writer = SnapshotWriter::create(path, root, SystemTime::now())?;
+    let walk = WalkOptions::default()
+        .with_order(WalkOrder::DepthFirst)
+        .with_sort(Collation::CodePoint);
+    let mut entries = storage
+        .walk_with(root, walk)
+        .map(|found| async move {
+            match found {
+                Ok((path, meta)) => snapshot_entry(storage, &path, meta, options.checksum).await,
+                Err(StorageError::NotFound) => Ok(None),
+                Err(e) => Err(e),
+            }
+        })
+        .buffered(options.concurrency);
+    while let Some(entry) = entries.next().await {
+        if let Some(entry) = entry? {
+            writer.append(&entry)?;
+        }
+    }
+    writer.finish()
+}
+
+/// A difference between two snapshot files.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "change", content = "entry", rename_all = "snake_case")]
+pub enum SnapshotChange {
+    Added(SnapshotEntry),
+    Removed(SnapshotEntry),
+    /// A file whose size, modification time or checksum changed, as it is now
+    Modified(SnapshotEntry),
+}
+
+/// What changed from `old` to `new`, read from both files at once and yielded in
+/// path order, holding only the current entry of each. Entries are matched by path
+/// from each root, so snapshots of a tree and its mirror compare too.
+///
+/// Unlike `Snapshot::diff`, moves are not found, as that needs every removal and
+/// addition at hand: a moved file is a removal and an addition, and each entry of a
+/// moved directory is listed.
+pub fn diff_snapshot_files(
+    old: SnapshotReader,
+    new: SnapshotReader,
+    tolerance: Duration,
+) -> SnapshotChanges {
+    SnapshotChanges {
+        old: Side::new(old),
+        new: Side::new(new),
+        tolerance,
+    }
+}
+
+/// Changes between two snapshot files, as `diff_snapshot_files` finds them.
+pub struct SnapshotChanges {
+    old: Side,
+    new: Side,
+    tolerance: Duration,
+}
+
+/// A reader an entry ahead, with that entry's path from its root
+struct Side {
+    reader: SnapshotReader,
+    head: Option<(Vec<String>, SnapshotEntry)>,
+}
+
+impl Side {
+    fn new(reader: SnapshotReader) -> Self {
+        Side { reader, head: None }
+    }
+
+    fn fill(&mut self) -> Result<(), SnapshotFileError> {
+        if self.head.is_some() {
+            return Ok(());
+        }
+        if let Some(entry) = self.reader.next().transpose()? {
+            let rel = entry
+                .path
+                .relative_to(&self.reader.root)
+                .unwrap_or_default();
+            self.head = Some((rel, entry));
+        }
+        Ok(())
+    }
+}
+
+impl SnapshotChanges {
+    fn next_change(&mut self) -> Result<Option<SnapshotChange>, SnapshotFileError> {
+        loop {
+            self.old.fill()?;
+            self.new.fill()?;
+            let order = match (&self.old.head, &self.new.head) {
+                (None, None) => return Ok(None),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((old, _)), Some((new, _))) => old.cmp(new),
+            };
+            let take = |side: &mut Side| side.head.take().expect("side has a head");
+            let (old, new) = match order {
+                Ordering::Less => return Ok(Some(SnapshotChange::Removed(take(&mut self.old).1))),
+                Ordering::Greater => return Ok(Some(SnapshotChange::Added(take(&mut self.new).1))),
+                Ordering::Equal => (take(&mut self.old).1, take(&mut self.new)),
+            };
+            match (old.is_dir, new.1.is_dir) {
+                (true, true) => {}
+                (false, false) if old.matches(&new.1, self.tolerance) => {}
+                (false, false) => return Ok(Some(SnapshotChange::Modified(new.1))),
+                // A file became a directory or the other way round: the old one went,
+                // and the new one is added on the next round
+                _ => {
+                    self.new.head = Some(new);
+                    return Ok(Some(SnapshotChange::Removed(old)));
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for SnapshotChanges {
+    type Item = Result<SnapshotChange, SnapshotFileError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_change().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::Snapshot;
+    use crate::storage::{ChecksumAlgorithm, MemoryStorage};
+
+    #[tokio::test]
+    async fn test_snapshot_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let storage = MemoryStorage::new();
+        let root = UniversalPath::from_uri_str("mem://nas/music/").unwrap();
+        let write = async |path: &str, data: &[u8]| {
+            let parts: Vec<_> = path.split('/').collect();
+            let mut at = root.clone();
+            storage.create_dir(&at).await.unwrap();
+            for part in &parts[..parts.len() - 1] {
+                at = at.join(part).as_dir();
+                storage.create_dir(&at).await.unwrap();
+            }
+            storage.write(&root.join_all(&parts), data).await.unwrap();
+        };
+        write("Album/01.flac", b"one").await;
+        write("Album/02.flac", b"two").await;
+        write("Album-B/01.flac", b"b").await;
+        write("cover.jpg", b"jpeg").await;
+        let options = SnapshotOptions::default().with_checksums(ChecksumAlgorithm::Xxh3);
+        let old_path = dir.join("old.snap");
+        let written = capture_snapshot_file(&storage, &root, &options, &old_path)
+            .await
+            .unwrap();
+        assert_eq!(written, 6);
+
+        // The same entries as an in-memory snapshot
+        let reader = SnapshotReader::open(&old_path).unwrap();
+        assert_eq!(reader.root(), &root);
+        let read: Vec<_> = reader.map(Result::unwrap).collect();
+        let captured = Snapshot::capture(&storage, &root, &options).await.unwrap();
+        let mut expected: Vec<_> = captured.iter().cloned().collect();
+        let rel = |e: &SnapshotEntry| e.path.relative_to(&root).unwrap();
+        expected.sort_by_key(rel);
+        assert_eq!(read, expected);
+
+        write("Album/02.flac", b"two, remastered").await;
+        storage.delete(&root.join("cover.jpg")).await.unwrap();
+        write("cover.jpg/front.jpg", b"jpeg").await;
+        write("Album/03.flac", b"three").await;
+        let new_path = dir.join("new.snap");
+        capture_snapshot_file(&storage, &root, &options, &new_path)
+            .await
+            .unwrap();
+        let old = SnapshotReader::open(&old_path).unwrap();
+        let new = SnapshotReader::open(&new_path).unwrap();
+        let changes: Vec<_> = diff_snapshot_files(old, new, Duration::ZERO)
+            .map(|change| match change.unwrap() {
+                SnapshotChange::Added(e) => format!("+{}", rel(&e).join("/")),
+                SnapshotChange::Removed(e) => format!("-{}", rel(&e).join("/")),
+                SnapshotChange::Modified(e) => format!("~{}", rel(&e).join("/")),
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                "~Album/02.flac",
+                "+Album/03.flac",
+                "-cover.jpg",
+                "+cover.jpg",
+                "+cover.jpg/front.jpg"
+            ]
+        );
+
+        // Out of order, and cut short
+        let mut writer =
+            SnapshotWriter::create(dir.join("bad.snap"), &root, SystemTime::now()).unwrap();
+        writer.append(&expected[1]).unwrap();
+        assert!(matches!(
+            writer.append(&expected[0]),
+            Err(SnapshotFileError::OutOfOrder(_))
+        ));
+        let bytes = std::fs::read(&old_path).unwrap();
+        std::fs::write(&old_path, &bytes[..bytes.len() - 20]).unwrap();
+        let last = SnapshotReader::open(&old_path).unwrap().last().unwrap();
+        assert!(matches!(last, Err(SnapshotFileError::Truncated)));
+    }
+}
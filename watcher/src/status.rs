@@ -0,0 +1,396 @@
+use crate::redact::redactor;
+use crate::storage::{CacheStats, HostHealth, QuotaStorage, QuotaUsage, Storage, check_health};
+use crate::universal_path::UniversalPath;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Longest request head read; monitors send a line and a few headers
+const MAX_REQUEST: usize = 8 * 1024;
+/// How long a client gets to send its request before the connection is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a running daemon knows about itself, kept up to date by the daemon as it
+/// works and served as JSON by `serve_status`. Clones share the same state.
+#[derive(Clone)]
+pub struct DaemonStatus {
+    started_at: SystemTime,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    roots: BTreeMap<String, Root>,
+    queues: BTreeMap<String, usize>,
+    caches: BTreeMap<String, CacheStats>,
+}
+
+struct Root {
+    root: String,
+    health: Option<HostHealth>,
+    checked_at: Option<SystemTime>,
+    last_scan_at: Option<SystemTime>,
+    last_error: Option<String>,
+    quota: Option<Arc<QuotaStorage>>,
+}
+
+/// One root in a `StatusReport`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RootStatus {
+    pub label: String,
+    /// The root's path, redacted by the process-wide `redactor()`
+    pub root: String,
+    /// Answered the last check; a root not checked yet counts as online
+    pub online: bool,
+    pub health: Option<HostHealth>,
+    pub checked_at: Option<String>,
+    pub last_scan_at: Option<String>,
+    pub last_error: Option<String>,
+    /// For a root with a quota, what it holds against it
+    pub quota: Option<QuotaUsage>,
+    /// `QuotaUsage::utilization` of `quota`
+    pub quota_utilization: Option<f64>,
+}
+
+/// Whether the daemon is fit to serve, as `/healthz` reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthState {
+    Ok,
+    /// Some roots are offline; the rest are still watched
+    Degraded,
+    /// Every root is offline
+    Unavailable,
+}
+
+/// What `/status` serves. Times are RFC 3339, in UTC.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatusReport {
+    pub status: HealthState,
+    pub version: &'static str,
+    pub started_at: String,
+    pub uptime_secs: u64,
+    pub roots: Vec<RootStatus>,
+    /// Work waiting, by queue
+    pub queues: BTreeMap<String, usize>,
+    pub caches: BTreeMap<String, CacheStats>,
+}
+
+impl Default for DaemonStatus {
+    fn default() -> Self {
+        DaemonStatus::new()
+    }
+}
+
+impl DaemonStatus {
+    /// Status for a daemon starting now, with nothing to report yet
+    pub fn new() -> Self {
+        DaemonStatus {
+            started_at: SystemTime::now(),
+            state: Arc::default(),
+        }
+    }
+
+    /// Report on `root` under `label`, replacing any root of that label
+    pub fn add_root(&self, label: &str, root: &UniversalPath) {
+        let root = Root {
+            root: redactor().path(root),
+            health: None,
+            checked_at: None,
+            last_scan_at: None,
+            last_error: None,
+            quota: None,
+        };
+        self.state().roots.insert(label.to_string(), root);
+    }
+
+    /// Stop reporting on the root labelled `label`
+    pub fn remove_root(&self, label: &str) {
+        self.state().roots.remove(label);
+    }
+
+    /// Stat `root` through `storage` and record whether it answered
+    pub async fn check_root(&self, label: &str, storage: &dyn Storage, root: &UniversalPath) {
+        let health = check_health(storage, root).await;
+        self.set_root_health(label, health);
+    }
+
+    pub fn set_root_health(&self, label: &str, health: HostHealth) {
+        self.update_root(label, |root| {
+            root.health = Some(health);
+            root.checked_at = Some(SystemTime::now());
+        });
+    }
+
+    /// A scan of the root finished at `at`, with the last error it met, if any
+    pub fn record_scan(&self, label: &str, at: SystemTime, error: Option<String>) {
+        self.update_root(label, |root| {
+            root.last_scan_at = Some(at);
+            root.last_error = error;
+        });
+    }
+
+    /// Report the root's usage against its quota as `quota` keeps it, from now on
+    pub fn set_quota(&self, label: &str, quota: Arc<QuotaStorage>) {
+        self.update_root(label, |root| root.quota = Some(quota));
+    }
+
+    pub fn set_queue_depth(&self, queue: &str, depth: usize) {
+        self.state().queues.insert(queue.to_string(), depth);
+    }
+
+    pub fn set_cache_stats(&self, cache: &str, stats: CacheStats) {
+        self.state().caches.insert(cache.to_string(), stats);
+    }
+
+    pub fn health(&self) -> HealthState {
+        self.report().status
+    }
+
+    pub fn report(&self) -> StatusReport {
+        let state = self.state();
+        let roots: Vec<_> = state
+            .roots
+            .iter()
+            .map(|(label, root)| {
+                let quota = root.quota.as_ref().map(|quota| quota.usage());
+                RootStatus {
+                    label: label.clone(),
+                    root: root.root.clone(),
+                    online: root.health.is_none_or(|h| h == HostHealth::Healthy),
+                    health: root.health,
+                    checked_at: root.checked_at.map(rfc3339),
+                    last_scan_at: root.last_scan_at.map(rfc3339),
+                    last_error: root.last_error.clone(),
+                    quota,
+                    quota_utilization: quota.map(|usage| usage.utilization()),
+                }
+            })
+            .collect();
+        let offline = roots.iter().filter(|r| !r.online).count();
+        let status = match offline {
+            0 => HealthState::Ok,
+            n if n == roots.len() => HealthState::Unavailable,
+            _ => HealthState::Degraded,
+        };
+        let uptime = self.started_at.elapsed().unwrap_or_default();
+        StatusReport {
+            status,
+            version: env!("CARGO_PKG_VERSION"),
+            started_at: rfc3339(self.started_at),
+            uptime_secs: uptime.as_secs(),
+            roots,
+            queues: state.queues.clone(),
+            caches: state.caches.clone(),
+        }
+    }
+
+    fn update_root(&self, label: &str, update: impl FnOnce(&mut Root)) {
+        if let Some(root) = self.state().roots.get_mut(label) {
+            update(root);
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+pub(crate) fn rfc3339(at: SystemTime) -> String {
+    DateTime::<Utc>::from(at).to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Answer status requests on `listener` until it fails, each connection on a task of
+/// its own:
+///
+/// - `GET /healthz` is `{"status": ...}`, with 200 while any root is online (or there
+///   are none) and 503 once every root is offline, so a root that is merely asleep
+///   does not get the daemon restarted.
+/// - `GET /status` is the whole `StatusReport`.
+/// - `GET /metrics` is `otolith_quota_utilization`, a gauge of how full each root
+///   with a quota is, in the Prometheus text format.
+///
+/// `HEAD` is answered too. Anything else is a 404 or 405.
+pub async fn serve_status(listener: TcpListener, status: DaemonStatus) -> io::Result<()> {
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let status = status.clone();
+        tokio::spawn(async move {
+            let answer = tokio::time::timeout(REQUEST_TIMEOUT, answer(socket, &status));
+            // Clients that go away or never finish their request are not our concern
+            let _ = answer.await;
+        });
+    }
+}
+
+async fn answer(mut socket: TcpStream, status: &DaemonStatus) -> io::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_REQUEST {
+        let n = socket.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut line = head.lines().next().unwrap_or_default().split(' ');
+    let (method, target) = (line.next().unwrap_or_default(), line.next().unwrap_or("/"));
+    let path = target.split('?').next().unwrap_or_default();
+    let mut content_type = "application/json";
+    let (code, body) = match (method, path) {
+        ("GET" | "HEAD", "/healthz") => {
+            let health = status.health();
+            let code = match health {
+                HealthState::Unavailable => "503 Service Unavailable",
+                HealthState::Ok | HealthState::Degraded => "200 OK",
+            };
+            (code, serde_json::json!({ "status": health }).to_string())
+        }
+        ("GET" | "HEAD", "/status") => {
+            let report = serde_json::to_string(&status.report()).expect("report serializes");
+            ("200 OK", report)
+        }
+        ("GET" | "HEAD", "/metrics") => {
+            content_type = "text/plain; version=0.0.4";
+            ("200 OK", metrics(&status.report()))
+        }
+        ("GET" | "HEAD", _) => ("404 Not Found", r#"{"error":"not found"}"#.to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            r#"{"error":"method not allowed"}"#.to_string(),
+        ),
+    };
+    let mut response = format!(
+        "HTTP/1.1 {code}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Cache-Control: no-store\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    if method != "HEAD" {
+        response.push_str(&body);
+    }
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+/// `report` in the Prometheus text exposition format
+fn metrics(report: &StatusReport) -> String {
+    let mut out = String::from(
+        "# HELP otolith_quota_utilization How full the root is against its quota, 1 at \
+         the limit\n# TYPE otolith_quota_utilization gauge\n",
+    );
+    for root in &report.roots {
+        if let Some(utilization) = root.quota_utilization {
+            let label = escape_label(&root.label);
+            out.push_str(&format!(
+                "otolith_quota_utilization{{root=\"{label}\"}} {utilization}\n"
+            ));
+        }
+    }
+    out
+}
+
+/// `value` as a Prometheus label value, whose backslashes, quotes and newlines are
+/// escaped
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryStorage, Quota};
+
+    async fn get(port: u16, target: &str) -> (String, serde_json::Value) {
+        let (code, body) = get_text(port, target).await;
+        (code, serde_json::from_str(&body).unwrap_or_default())
+    }
+
+    async fn get_text(port: u16, target: &str) -> (String, String) {
+        let mut socket = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let request = format!("GET {target} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        socket.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let code = head.split(' ').nth(1).unwrap().to_string();
+        (code, body.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_status_endpoints() {
+        let status = DaemonStatus::new();
+        let storage = MemoryStorage::new();
+        let music = UniversalPath::from_uri_str("mem://nas/music/").unwrap();
+        storage.create_dir(&music).await.unwrap();
+        status.add_root("music", &music);
+        status.add_root(
+            "archive",
+            &UniversalPath::from_uri_str("mem://cold/").unwrap(),
+        );
+        status.check_root("music", &storage, &music).await;
+        status.record_scan("music", SystemTime::UNIX_EPOCH, None);
+        status.set_queue_depth("checksums", 12);
+        storage
+            .write(&music.join("01.flac"), &[0; 30])
+            .await
+            .unwrap();
+        let quota = Quota::default().with_max_bytes(120);
+        let storage = Arc::new(storage) as Arc<dyn Storage>;
+        let quota = QuotaStorage::measure(storage.clone(), &music, quota).await;
+        let quota = Arc::new(quota.unwrap());
+        status.set_quota("music", quota.clone());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve_status(listener, status.clone()));
+
+        assert_eq!(get(port, "/healthz").await.0, "200");
+        let (code, report) = get(port, "/status").await;
+        assert_eq!(code, "200");
+        assert_eq!(report["status"], "ok");
+        assert_eq!(report["queues"]["checksums"], 12);
+        assert_eq!(report["roots"][1]["label"], "music");
+        assert_eq!(report["roots"][1]["health"], "healthy");
+        assert_eq!(report["roots"][1]["last_scan_at"], "1970-01-01T00:00:00Z");
+        assert_eq!(report["roots"][1]["quota"]["bytes"], 30);
+        assert_eq!(report["roots"][1]["quota_utilization"], 0.25);
+        assert_eq!(report["roots"][0]["quota"], serde_json::Value::Null);
+
+        // Writes through the quota show at once
+        quota.write(&music.join("02.flac"), &[0; 30]).await.unwrap();
+        let (code, metrics) = get_text(port, "/metrics").await;
+        assert_eq!(code, "200");
+        assert!(
+            metrics.contains("# TYPE otolith_quota_utilization gauge\n"),
+            "{metrics}"
+        );
+        assert!(metrics.ends_with("otolith_quota_utilization{root=\"music\"} 0.5\n"));
+
+        status.set_root_health("archive", HostHealth::PossiblyAsleep);
+        let (code, health) = get(port, "/healthz?verbose").await;
+        assert_eq!(
+            (code.as_str(), &health["status"]),
+            ("200", &"degraded".into())
+        );
+        status.set_root_health("music", HostHealth::Unreachable);
+        let (code, health) = get(port, "/healthz").await;
+        assert_eq!(
+            (code.as_str(), &health["status"]),
+            ("503", &"unavailable".into())
+        );
+        assert_eq!(get(port, "/prometheus").await.0, "404");
+    }
+}
@@ -1,10 +1,59 @@
+mod cache;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod checksum;
+mod checksum_db;
+mod credentials;
+mod fd_budget;
+mod filter;
+#[cfg(feature = "ftp")]
+pub mod ftp;
+mod glob;
+#[cfg(feature = "http")]
+pub mod http;
 pub mod local;
+mod lock;
+mod manager;
+mod matrix;
+pub mod memory;
+mod multi_scan;
+mod operations;
+#[cfg(feature = "plugins")]
+pub mod plugin;
+mod quota;
+mod registry;
+mod retrying;
+#[cfg(feature = "s3")]
+pub mod s3;
+mod scan;
+#[cfg(feature = "sftp")]
+pub mod sftp;
+mod skew;
+pub mod smb;
+mod stream;
+mod transaction;
+mod transfer;
+mod wake;
+mod walk;
+#[cfg(feature = "webdav")]
+pub mod webdav;
 
+use crate::media::MediaFormat;
 use crate::universal_path::UniversalPath;
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use std::{ops::Range, time::SystemTime};
+use std::{
+    future::Future,
+    ops::Range,
+    time::{Duration, SystemTime},
+};
 use thiserror::Error;
+use tokio::io::AsyncRead;
+
+/// Stats the default `stat_many_opts` keeps in flight
+const STAT_MANY_CONCURRENCY: usize = 8;
+/// Children the default `list_stream_opts` stats at a time
+const LIST_STREAM_BATCH: usize = 256;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum StorageBackend {
@@ -12,15 +61,41 @@ pub enum StorageBackend {
     Ftp,
     Sftp,
     S3,
+    NetworkDrive,
+    Http,
+    Https,
+    /// WebDAV over HTTP
+    WebDav,
+    /// WebDAV over HTTPS
+    WebDavs,
+    /// `MemoryStorage`, for tests and dry runs
+    Memory,
+    /// A scheme no built-in backend handles. The path still parses and round-trips;
+    /// opening it goes to the plugin that has claimed the scheme by then, if any, and
+    /// otherwise fails with `UnsupportedBackend`.
+    Other(String),
 }
 
 impl StorageBackend {
-    pub(crate) fn from_scheme(scheme: &str) -> Option<Self> {
-        match scheme.to_lowercase().as_str() {
+    pub(crate) fn from_scheme(scheme: &str) -> Self {
+        let scheme = scheme.to_lowercase();
+        Self::builtin_scheme(&scheme).unwrap_or(StorageBackend::Other(scheme))
+    }
+
+    /// The backend compiled into otolith for a lowercase scheme, whether or not its
+    /// cargo feature is enabled
+    pub(crate) fn builtin_scheme(scheme: &str) -> Option<Self> {
+        match scheme {
             "file" | "" => Some(StorageBackend::Local),
             "ftp" => Some(StorageBackend::Ftp),
             "sftp" => Some(StorageBackend::Sftp),
             "s3" => Some(StorageBackend::S3),
+            "smb" | "cifs" => Some(StorageBackend::NetworkDrive),
+            "http" => Some(StorageBackend::Http),
+            "https" => Some(StorageBackend::Https),
+            "dav" | "webdav" => Some(StorageBackend::WebDav),
+            "davs" | "webdavs" => Some(StorageBackend::WebDavs),
+            "mem" | "memory" => Some(StorageBackend::Memory),
             _ => None,
         }
     }
@@ -31,6 +106,13 @@ impl StorageBackend {
             StorageBackend::Ftp => "ftp",
             StorageBackend::Sftp => "sftp",
             StorageBackend::S3 => "s3",
+            StorageBackend::NetworkDrive => "smb",
+            StorageBackend::Http => "http",
+            StorageBackend::Https => "https",
+            StorageBackend::WebDav => "dav",
+            StorageBackend::WebDavs => "davs",
+            StorageBackend::Memory => "mem",
+            StorageBackend::Other(scheme) => scheme,
         }
     }
 }
@@ -39,6 +121,8 @@ impl StorageBackend {
 pub enum EntryKind {
     File,
     Directory,
+    /// A symbolic link itself, as `stat` reports it when asked not to follow links
+    Symlink,
     Other,
 }
 
@@ -48,15 +132,59 @@ pub struct EntryMetadata {
     pub size_bytes: Option<u64>,
     pub modified_at: Option<SystemTime>,
     pub created_at: Option<SystemTime>,
+    /// As far as the backend reports them; all unknown on most remote backends
+    pub permissions: EntryPermissions,
+    /// The name starts with `.`, or the entry has the Windows hidden attribute
+    pub is_hidden: bool,
+    /// What its contents say the file is, for files a `Scanner` with format detection
+    /// sniffed; backends leave it `None`
+    pub format: Option<MediaFormat>,
+}
+
+/// Ownership and permissions of an entry. Local disks report what the OS does, SFTP
+/// servers their attributes and S3 the `x-amz-meta-mode`, `-uid` and `-gid` headers
+/// s3fs-style tools set; anything a backend does not know is `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntryPermissions {
+    /// POSIX permission bits, e.g. `0o644`, without the file type
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// No one may write it: the Windows read-only attribute, or no write bit set
+    pub readonly: Option<bool>,
+}
+
+impl EntryPermissions {
+    /// Permissions from POSIX mode bits, which may include the file type
+    pub fn from_mode(mode: u32) -> Self {
+        let mode = mode & 0o7777;
+        EntryPermissions {
+            mode: Some(mode),
+            readonly: Some(mode & 0o222 == 0),
+            ..EntryPermissions::default()
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Whether `path` names a dot file, hidden by convention on every backend
+pub(crate) fn is_hidden_name(path: &UniversalPath) -> bool {
+    path.file_name().is_some_and(|name| name.starts_with('.'))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub struct StorageCapabilities {
     pub can_stat: bool,
     pub can_read: bool,
     pub can_read_range: bool,
     pub can_list: bool,
     pub can_glob: bool,
+    /// `write`, `write_stream` and `create_dir`
+    pub can_write: bool,
+    pub can_delete: bool,
+    pub can_rename: bool,
+    /// `checksum` is answered from checksums the server keeps, at least for some
+    /// algorithms, instead of by reading the whole file
+    pub can_checksum: bool,
 }
 
 impl StorageCapabilities {
@@ -67,6 +195,79 @@ impl StorageCapabilities {
             can_read_range: false,
             can_list: false,
             can_glob: false,
+            can_write: false,
+            can_delete: false,
+            can_rename: false,
+            can_checksum: false,
+        }
+    }
+}
+
+/// Scheduling hint for a single storage call. Backends and middleware may use it to
+/// order or throttle work; it never changes the result of a call.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CallPriority {
+    Background,
+    #[default]
+    Normal,
+    Interactive,
+}
+
+/// Per-call overrides threaded through every `Storage` method.
+///
+/// The plain trait methods (`stat`, `read`, ...) use `CallOptions::default()`; the
+/// `*_opts` variants take an explicit value so one operation can be tuned without
+/// touching shared configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CallOptions {
+    /// Upper bound on the whole call, overriding any backend default.
+    pub timeout: Option<Duration>,
+    pub priority: CallPriority,
+    /// Skip caching layers and go to the underlying backend.
+    pub bypass_cache: bool,
+    /// Free-form label carried into logs and traces for this call.
+    pub trace_label: Option<String>,
+    /// Have `stat` describe a symlink itself, as `EntryKind::Symlink`, rather than what
+    /// it points to, like `lstat`. Backends without links ignore it.
+    pub no_follow: bool,
+}
+
+impl CallOptions {
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_priority(mut self, priority: CallPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_cache_bypass(mut self) -> Self {
+        self.bypass_cache = true;
+        self
+    }
+
+    pub fn with_trace_label<S: Into<String>>(mut self, label: S) -> Self {
+        self.trace_label = Some(label.into());
+        self
+    }
+
+    pub fn with_no_follow(mut self) -> Self {
+        self.no_follow = true;
+        self
+    }
+
+    /// Run `fut`, failing with `StorageError::Timeout` if it outlives `self.timeout`.
+    pub async fn enforce_timeout<T, F>(&self, fut: F) -> Result<T, StorageError>
+    where
+        F: Future<Output = Result<T, StorageError>>,
+    {
+        match self.timeout {
+            Some(limit) => tokio::time::timeout(limit, fut)
+                .await
+                .map_err(|_| StorageError::Timeout(limit))?,
+            None => fut.await,
         }
     }
 }
@@ -85,35 +286,867 @@ pub enum StorageError {
     NotAFile,
     #[error("not a directory")]
     NotADirectory,
+    #[error("already exists")]
+    AlreadyExists,
+    /// The path is a symlink and the storage is set to refuse them
+    #[error("is a symlink")]
+    IsSymlink,
+    #[error("locked by {} until {:?}", .0.owner(), .0.expires_at)]
+    Locked(Box<LockInfo>),
+    #[error("quota exceeded: {used} {resource} over a limit of {limit}")]
+    QuotaExceeded {
+        resource: &'static str,
+        limit: u64,
+        used: u64,
+    },
     #[error("range not satisfiable")]
     RangeNotSatisfiable,
+    #[error("operation timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("connection failed: {0}")]
+    Connection(String),
+    /// The server is throttling or briefly down: HTTP 429 and 5xx answers
+    #[error("server unavailable: {0}")]
+    Unavailable(String),
+    /// Stopped on request through the `OperationRegistry`
+    #[error("operation cancelled")]
+    Cancelled,
+    #[error(transparent)]
+    Credentials(#[from] CredentialError),
     #[error(transparent)]
     Io(#[from] std::io::Error),
 }
 
+impl StorageError {
+    /// Whether the same call might succeed if made again: timeouts, dropped
+    /// connections and busy servers, as opposed to answers about the path itself
+    pub fn is_retryable(&self) -> bool {
+        use std::io::ErrorKind;
+        match self {
+            StorageError::Timeout(_)
+            | StorageError::Connection(_)
+            | StorageError::Unavailable(_) => true,
+            StorageError::Io(e) => matches!(
+                e.kind(),
+                ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::ConnectionRefused
+                    | ErrorKind::NotConnected
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::TimedOut
+                    | ErrorKind::Interrupted
+                    | ErrorKind::UnexpectedEof
+            ),
+            _ => false,
+        }
+    }
+
+    /// Whether the error is what a host that is asleep, or still waking, gives: no
+    /// route to it, or no answer in time
+    pub fn may_be_asleep(&self) -> bool {
+        use std::io::ErrorKind;
+        match self {
+            StorageError::Timeout(_) => true,
+            StorageError::Connection(message) => {
+                let message = message.to_ascii_lowercase();
+                [
+                    "unreachable",
+                    "no route to host",
+                    "timed out",
+                    "host is down",
+                ]
+                .iter()
+                .any(|m| message.contains(m))
+            }
+            StorageError::Io(e) => matches!(
+                e.kind(),
+                ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable | ErrorKind::TimedOut
+            ),
+            _ => false,
+        }
+    }
+
+    /// Whether the backend refused the call for want of permission
+    pub fn is_permission_denied(&self) -> bool {
+        matches!(self, StorageError::Io(e) if e.kind() == std::io::ErrorKind::PermissionDenied)
+    }
+
+    /// Whether the process, or the whole system, has run out of file descriptors
+    pub fn is_fd_exhausted(&self) -> bool {
+        match self {
+            #[cfg(unix)]
+            StorageError::Io(e) => {
+                matches!(e.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+            }
+            _ => false,
+        }
+    }
+}
+
+/// One part of a multi-range read, labelled with the range asked for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangePart {
+    pub requested: Range<u64>,
+    /// Bytes from `requested.start`, cut short at the end of the object
+    pub data: Vec<u8>,
+}
+
+impl RangePart {
+    /// The bytes actually covered by `data`
+    pub fn range(&self) -> Range<u64> {
+        self.requested.start..self.requested.start + self.data.len() as u64
+    }
+}
+
+/// Apply the `read_range` contract to an object of `len` bytes: an empty or reversed
+/// range stays empty, a start at or past the end is `RangeNotSatisfiable`, and the end
+/// is clamped to `len`.
+pub fn clamp_range(range: Range<u64>, len: u64) -> Result<Range<u64>, StorageError> {
+    if range.start >= range.end {
+        return Ok(range.start..range.start);
+    }
+    if range.start >= len {
+        return Err(StorageError::RangeNotSatisfiable);
+    }
+    Ok(range.start..range.end.min(len))
+}
+
+/// Parse a `Content-Range: bytes first-last/total` value into the inclusive first and
+/// last byte and the total length, if the server gave one.
+#[cfg(any(feature = "http", feature = "s3", feature = "webdav"))]
+pub(crate) fn parse_content_range(value: &str) -> Option<(u64, u64, Option<u64>)> {
+    let spec = value.trim().strip_prefix("bytes")?.trim_start();
+    let (span, total) = spec.split_once('/')?;
+    let (first, last) = span.split_once('-')?;
+    let (first, last) = (first.trim().parse().ok()?, last.trim().parse().ok()?);
+    if last < first {
+        return None;
+    }
+    Some((first, last, total.trim().parse().ok()))
+}
+
+/// Check a `206 Partial Content` body against the range that was asked for. A server
+/// may start elsewhere, which is an error, or send more than asked, which is trimmed.
+#[cfg(any(feature = "http", feature = "s3", feature = "webdav"))]
+pub(crate) fn check_partial(
+    range: &Range<u64>,
+    content_range: Option<&str>,
+    mut body: Vec<u8>,
+) -> Result<Vec<u8>, StorageError> {
+    if let Some((first, last, _)) = content_range.and_then(parse_content_range)
+        && first != range.start
+    {
+        return Err(StorageError::Io(std::io::Error::other(format!(
+            "asked for bytes {}-{} but got {first}-{last}",
+            range.start,
+            range.end - 1
+        ))));
+    }
+    body.truncate((range.end - range.start).min(body.len() as u64) as usize);
+    Ok(body)
+}
+
+/// Keep the last `len` bytes of an answer to a `bytes=-len` request. A `206` must
+/// run to the end of the file; a whole-file `200` carries no `Content-Range`.
+#[cfg(any(feature = "http", feature = "s3"))]
+pub(crate) fn check_suffix(
+    len: u64,
+    content_range: Option<&str>,
+    mut body: Vec<u8>,
+) -> Result<Vec<u8>, StorageError> {
+    if let Some((first, last, Some(total))) = content_range.and_then(parse_content_range)
+        && last + 1 != total
+    {
+        return Err(StorageError::Io(std::io::Error::other(format!(
+            "asked for the last {len} bytes but got {first}-{last} of {total}"
+        ))));
+    }
+    let skip = body
+        .len()
+        .saturating_sub(usize::try_from(len).unwrap_or(usize::MAX));
+    body.drain(..skip);
+    Ok(body)
+}
+
 #[async_trait]
 pub trait Storage: Send + Sync {
     fn backend(&self) -> StorageBackend;
     fn capabilities(&self) -> StorageCapabilities;
 
-    async fn stat(&self, path: &UniversalPath) -> Result<EntryMetadata, StorageError>;
-    async fn read(&self, path: &UniversalPath) -> Result<Vec<u8>, StorageError>;
-    async fn read_range(&self, path: &UniversalPath, range: Range<u64>)
-        -> Result<Vec<u8>, StorageError>;
-    async fn list(&self, path: &UniversalPath) -> Result<Vec<UniversalPath>, StorageError>;
+    async fn stat_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<EntryMetadata, StorageError>;
+    async fn read_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError>;
+    /// Bytes in `range` of the file at `path`.
+    ///
+    /// Every backend follows the same rules, see `clamp_range`: an empty or reversed
+    /// range returns no bytes without touching the backend, a start at or past the end
+    /// of the file is `RangeNotSatisfiable`, and an end past it is clamped, so
+    /// `start..u64::MAX` reads to the end.
+    async fn read_range_opts(
+        &self,
+        path: &UniversalPath,
+        range: Range<u64>,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError>;
+    async fn list_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<UniversalPath>, StorageError>;
 
     // Optional features with default implementations
-    async fn glob(&self, _pattern: &UniversalPath) -> Result<Vec<UniversalPath>, StorageError> {
-        Err(StorageError::UnsupportedFeature("glob"))
+
+    /// Every file and directory matching `pattern`, sorted by path. Segments of the
+    /// pattern may use `*`, `?` and `[...]` classes, negated with `!` or `^`, and a
+    /// whole `**` segment for any number of directories. Wildcards do not match names
+    /// starting with a dot.
+    ///
+    /// The default expands the pattern with `list` and `stat` on backends that can
+    /// list, walking the tree only below `**`.
+    async fn glob_opts(
+        &self,
+        pattern: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<UniversalPath>, StorageError> {
+        if !self.capabilities().can_list {
+            return Err(StorageError::UnsupportedFeature("glob"));
+        }
+        opts.enforce_timeout(glob::glob(self, pattern, opts)).await
+    }
+
+    /// Where the symlink at `path` finally points, or `None` if it is not a symlink.
+    /// Backends without symlinks keep the default, which always says `None`.
+    async fn symlink_target_opts(
+        &self,
+        _path: &UniversalPath,
+        _opts: &CallOptions,
+    ) -> Result<Option<UniversalPath>, StorageError> {
+        Ok(None)
+    }
+
+    /// The last `len` bytes of the file at `path`, or all of it if it is shorter, for
+    /// footers such as ID3v1 and APE tags. Never `RangeNotSatisfiable`.
+    ///
+    /// The default stats for the length and reads the range; backends that can seek
+    /// from the end or ask for a suffix range do it in one round trip.
+    async fn read_suffix_opts(
+        &self,
+        path: &UniversalPath,
+        len: u64,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        let size = self.stat_opts(path, opts).await?.size_bytes.ok_or(
+            StorageError::UnsupportedFeature("suffix reads without a known length"),
+        )?;
+        if len == 0 || size == 0 {
+            return Ok(Vec::new());
+        }
+        self.read_range_opts(path, size.saturating_sub(len)..size, opts)
+            .await
+    }
+
+    /// The file at `path` as a reader, for files too large to hold in memory. A
+    /// timeout in `opts` covers opening the stream, not reading it.
+    ///
+    /// The default stats the file and then reads it chunk by chunk through
+    /// `read_range_opts`; backends that can stream natively override it.
+    async fn read_stream_opts<'a>(
+        &'a self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<ByteStream<'a>, StorageError> {
+        let reader = opts
+            .enforce_timeout(RangeReader::open(self, path, opts))
+            .await?;
+        Ok(Box::pin(reader))
+    }
+
+    /// Stats of many paths, one result per path in the order given, so one that is
+    /// missing or unreadable does not fail the rest. A timeout in `opts` covers each
+    /// stat on its own.
+    ///
+    /// The default keeps a few stats in flight at once; backends that can answer for
+    /// many at once, from a listing or with pipelined requests, override it.
+    async fn stat_many_opts(
+        &self,
+        paths: &[UniversalPath],
+        opts: &CallOptions,
+    ) -> Vec<Result<EntryMetadata, StorageError>> {
+        use futures::StreamExt;
+        let mut stats = Vec::with_capacity(paths.len());
+        for path in paths {
+            stats.push(self.stat_opts(path, opts));
+        }
+        futures::stream::iter(stats)
+            .buffered(STAT_MANY_CONCURRENCY)
+            .collect()
+            .await
+    }
+
+    /// The children of the directory at `path` with their metadata, as `list` and a
+    /// `stat` of each would give them; directories have a directory hint. A child that
+    /// goes away before it is stat'ed is left out.
+    ///
+    /// The default lists and then stats the children as one batch through
+    /// `stat_many_opts`; backends whose listings already carry attributes (readdir,
+    /// MLSD, ListObjectsV2, PROPFIND) override it to answer in one round trip.
+    async fn list_with_metadata_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<(UniversalPath, EntryMetadata)>, StorageError> {
+        let children = self.list_opts(path, opts).await?;
+        let stats = self.stat_many_opts(&children, opts).await;
+        let mut listed = Vec::with_capacity(children.len());
+        for (child, stat) in children.into_iter().zip(stats) {
+            match stat {
+                Ok(meta) if meta.kind == EntryKind::Directory => {
+                    listed.push((child.as_dir(), meta))
+                }
+                Ok(meta) => listed.push((child, meta)),
+                Err(StorageError::NotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(listed)
+    }
+
+    /// The children of the directory at `path` with their metadata, as
+    /// `list_with_metadata_opts` gives them but fetched as they are read, so a directory
+    /// of millions of entries is never held at once. Errors with the directory itself
+    /// come back before the stream does. A timeout in `opts` covers opening the
+    /// stream, not reading it.
+    ///
+    /// The default lists the paths and stats them a batch at a time as the stream is
+    /// read; backends that page their listings (S3 continuation tokens, local
+    /// directory handles) fetch page by page.
+    async fn list_stream_opts<'a>(
+        &'a self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<ListStream<'a>, StorageError> {
+        let children = self.list_opts(path, opts).await?;
+        let batches = children
+            .chunks(LIST_STREAM_BATCH)
+            .map(<[_]>::to_vec)
+            .collect::<Vec<_>>();
+        let stat = move |batch: Vec<UniversalPath>, opts: CallOptions| async move {
+            let stats = self.stat_many_opts(&batch, &opts).await;
+            let listed = batch
+                .into_iter()
+                .zip(stats)
+                .filter_map(|(child, stat)| match stat {
+                    Ok(meta) if meta.kind == EntryKind::Directory => {
+                        Some(Ok((child.as_dir(), meta)))
+                    }
+                    Ok(meta) => Some(Ok((child, meta))),
+                    Err(StorageError::NotFound) => None,
+                    Err(e) => Some(Err(e)),
+                });
+            (futures::stream::iter(listed.collect::<Vec<_>>()), opts)
+        };
+        let listing = futures::stream::unfold(
+            (batches.into_iter(), opts.clone()),
+            move |(mut batches, opts)| async move {
+                let (listed, opts) = stat(batches.next()?, opts).await;
+                Some((listed, (batches, opts)))
+            },
+        );
+        Ok(Box::pin(futures::StreamExt::flatten(listing)))
+    }
+
+    /// Several ranges of one file, one part per range in the order given, each under
+    /// the `read_range_opts` rules; any unsatisfiable range fails the whole call.
+    /// Backends that can fetch them in one round trip override this.
+    async fn read_ranges_opts(
+        &self,
+        path: &UniversalPath,
+        ranges: &[Range<u64>],
+        opts: &CallOptions,
+    ) -> Result<Vec<RangePart>, StorageError> {
+        let mut parts = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            let data = self.read_range_opts(path, range.clone(), opts).await?;
+            parts.push(RangePart {
+                requested: range.clone(),
+                data,
+            });
+        }
+        Ok(parts)
+    }
+
+    /// Checksum of the file's contents under `algorithm`. The default streams the
+    /// file through a hasher; backends with `can_checksum` ask the server first.
+    async fn checksum_opts(
+        &self,
+        path: &UniversalPath,
+        algorithm: ChecksumAlgorithm,
+        opts: &CallOptions,
+    ) -> Result<Checksum, StorageError> {
+        checksum::compute(self, path, algorithm, opts).await
+    }
+
+    /// Replace the file at `path` with `data`. The parent directory must exist.
+    async fn write_opts(
+        &self,
+        _path: &UniversalPath,
+        _data: &[u8],
+        _opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        Err(StorageError::UnsupportedFeature("write"))
+    }
+
+    /// Replace the file at `path` with everything `reader` yields, returning the
+    /// number of bytes written.
+    async fn write_stream_opts(
+        &self,
+        _path: &UniversalPath,
+        _reader: &mut (dyn AsyncRead + Send + Unpin),
+        _opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        Err(StorageError::UnsupportedFeature("write"))
+    }
+
+    /// Create a directory and any missing parents; an existing directory is fine.
+    async fn create_dir_opts(
+        &self,
+        _path: &UniversalPath,
+        _opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        Err(StorageError::UnsupportedFeature("create_dir"))
+    }
+
+    /// Remove a file or an empty directory.
+    async fn delete_opts(
+        &self,
+        _path: &UniversalPath,
+        _opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        Err(StorageError::UnsupportedFeature("delete"))
+    }
+
+    /// Move `from` to `to` on the same storage, failing with `AlreadyExists` rather
+    /// than replacing anything at `to`.
+    async fn rename_opts(
+        &self,
+        _from: &UniversalPath,
+        _to: &UniversalPath,
+        _opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        Err(StorageError::UnsupportedFeature("rename"))
+    }
+
+    /// Copy the file `from` to `to` on the same storage, replacing a file at `to` as
+    /// `write` would, and return the bytes copied. The default streams it through
+    /// `read_stream` and `write_stream`; backends that can copy without the data
+    /// leaving the server do so.
+    async fn copy_opts(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        if self.stat_opts(from, opts).await?.kind != EntryKind::File {
+            return Err(StorageError::NotAFile);
+        }
+        let mut reader = self.read_stream_opts(from, opts).await?;
+        self.write_stream_opts(to, &mut reader, opts).await
+    }
+
+    /// Set the modification time of an existing file, where the backend lets it be set.
+    async fn set_modified_opts(
+        &self,
+        _path: &UniversalPath,
+        _modified_at: SystemTime,
+        _opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        Err(StorageError::UnsupportedFeature("set_modified"))
+    }
+
+    /// Read the extended attribute or object metadata entry `name`, or None if the
+    /// file has no such entry.
+    async fn attribute_opts(
+        &self,
+        _path: &UniversalPath,
+        _name: &str,
+        _opts: &CallOptions,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        Err(StorageError::UnsupportedFeature("attributes"))
+    }
+
+    /// Set the extended attribute or object metadata entry `name` on an existing file.
+    async fn set_attribute_opts(
+        &self,
+        _path: &UniversalPath,
+        _name: &str,
+        _value: &[u8],
+        _opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        Err(StorageError::UnsupportedFeature("attributes"))
+    }
+
+    /// Take an advisory lock on `path` for `ttl`, failing with `Locked` while someone
+    /// else's unexpired lock is in place. The lock is a file naming its holder, inside
+    /// `path` for a directory or next to it for a file; an expired one is taken over.
+    /// The default creates it with `write` and `rename`.
+    async fn acquire_lock_opts(
+        &self,
+        path: &UniversalPath,
+        ttl: Duration,
+        opts: &CallOptions,
+    ) -> Result<StorageLock, StorageError> {
+        lock::acquire(self, path, ttl, opts, lock::create_by_rename(self, opts)).await
+    }
+
+    /// Push a held lock's expiry out to `ttl` from now.
+    async fn renew_lock_opts(
+        &self,
+        lock: &StorageLock,
+        ttl: Duration,
+        opts: &CallOptions,
+    ) -> Result<StorageLock, StorageError> {
+        lock::renew(self, lock, ttl, opts).await
+    }
+
+    /// Give up a lock. One that has lapsed and been taken over is left alone.
+    async fn release_lock_opts(
+        &self,
+        lock: &StorageLock,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        lock::release(self, lock, opts).await
+    }
+
+    // Convenience wrappers using default call options
+    async fn stat(&self, path: &UniversalPath) -> Result<EntryMetadata, StorageError> {
+        self.stat_opts(path, &CallOptions::default()).await
+    }
+
+    async fn stat_many(&self, paths: &[UniversalPath]) -> Vec<Result<EntryMetadata, StorageError>> {
+        self.stat_many_opts(paths, &CallOptions::default()).await
+    }
+
+    async fn read(&self, path: &UniversalPath) -> Result<Vec<u8>, StorageError> {
+        self.read_opts(path, &CallOptions::default()).await
+    }
+
+    async fn read_range(
+        &self,
+        path: &UniversalPath,
+        range: Range<u64>,
+    ) -> Result<Vec<u8>, StorageError> {
+        self.read_range_opts(path, range, &CallOptions::default())
+            .await
+    }
+
+    async fn symlink_target(
+        &self,
+        path: &UniversalPath,
+    ) -> Result<Option<UniversalPath>, StorageError> {
+        self.symlink_target_opts(path, &CallOptions::default())
+            .await
+    }
+
+    async fn read_suffix(&self, path: &UniversalPath, len: u64) -> Result<Vec<u8>, StorageError> {
+        self.read_suffix_opts(path, len, &CallOptions::default())
+            .await
+    }
+
+    async fn read_stream<'a>(
+        &'a self,
+        path: &UniversalPath,
+    ) -> Result<ByteStream<'a>, StorageError> {
+        self.read_stream_opts(path, &CallOptions::default()).await
+    }
+
+    async fn read_ranges(
+        &self,
+        path: &UniversalPath,
+        ranges: &[Range<u64>],
+    ) -> Result<Vec<RangePart>, StorageError> {
+        self.read_ranges_opts(path, ranges, &CallOptions::default())
+            .await
+    }
+
+    async fn list(&self, path: &UniversalPath) -> Result<Vec<UniversalPath>, StorageError> {
+        self.list_opts(path, &CallOptions::default()).await
+    }
+
+    async fn list_with_metadata(
+        &self,
+        path: &UniversalPath,
+    ) -> Result<Vec<(UniversalPath, EntryMetadata)>, StorageError> {
+        self.list_with_metadata_opts(path, &CallOptions::default())
+            .await
+    }
+
+    async fn list_stream<'a>(
+        &'a self,
+        path: &UniversalPath,
+    ) -> Result<ListStream<'a>, StorageError> {
+        self.list_stream_opts(path, &CallOptions::default()).await
+    }
+
+    async fn glob(&self, pattern: &UniversalPath) -> Result<Vec<UniversalPath>, StorageError> {
+        self.glob_opts(pattern, &CallOptions::default()).await
+    }
+
+    async fn write(&self, path: &UniversalPath, data: &[u8]) -> Result<(), StorageError> {
+        self.write_opts(path, data, &CallOptions::default()).await
+    }
+
+    async fn write_stream(
+        &self,
+        path: &UniversalPath,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> Result<u64, StorageError> {
+        self.write_stream_opts(path, reader, &CallOptions::default())
+            .await
+    }
+
+    async fn create_dir(&self, path: &UniversalPath) -> Result<(), StorageError> {
+        self.create_dir_opts(path, &CallOptions::default()).await
+    }
+
+    async fn delete(&self, path: &UniversalPath) -> Result<(), StorageError> {
+        self.delete_opts(path, &CallOptions::default()).await
+    }
+
+    async fn rename(&self, from: &UniversalPath, to: &UniversalPath) -> Result<(), StorageError> {
+        self.rename_opts(from, to, &CallOptions::default()).await
+    }
+
+    async fn copy(&self, from: &UniversalPath, to: &UniversalPath) -> Result<u64, StorageError> {
+        self.copy_opts(from, to, &CallOptions::default()).await
+    }
+
+    async fn set_modified(
+        &self,
+        path: &UniversalPath,
+        modified_at: SystemTime,
+    ) -> Result<(), StorageError> {
+        self.set_modified_opts(path, modified_at, &CallOptions::default())
+            .await
+    }
+
+    async fn checksum(
+        &self,
+        path: &UniversalPath,
+        algorithm: ChecksumAlgorithm,
+    ) -> Result<Checksum, StorageError> {
+        self.checksum_opts(path, algorithm, &CallOptions::default())
+            .await
+    }
+
+    async fn attribute(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        self.attribute_opts(path, name, &CallOptions::default())
+            .await
+    }
+
+    async fn set_attribute(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+        value: &[u8],
+    ) -> Result<(), StorageError> {
+        self.set_attribute_opts(path, name, value, &CallOptions::default())
+            .await
+    }
+
+    async fn acquire_lock(
+        &self,
+        path: &UniversalPath,
+        ttl: Duration,
+    ) -> Result<StorageLock, StorageError> {
+        self.acquire_lock_opts(path, ttl, &CallOptions::default())
+            .await
+    }
+
+    async fn renew_lock(
+        &self,
+        lock: &StorageLock,
+        ttl: Duration,
+    ) -> Result<StorageLock, StorageError> {
+        self.renew_lock_opts(lock, ttl, &CallOptions::default())
+            .await
+    }
+
+    async fn release_lock(&self, lock: &StorageLock) -> Result<(), StorageError> {
+        self.release_lock_opts(lock, &CallOptions::default()).await
     }
 }
 
-/// Factory that returns a storage implementation for the given path's backend.
+/// Account-level view of a backend: the top-level roots (buckets, containers, drives)
+/// a set of credentials can reach, so callers can offer them before a path is known.
+#[async_trait]
+pub trait StorageAccount: Send + Sync {
+    fn backend(&self) -> StorageBackend;
+
+    /// Every root this account can see, as paths suitable for `open_storage_for`.
+    async fn list_roots(&self) -> Result<Vec<UniversalPath>, StorageError>;
+}
+
+/// Factory that returns a storage implementation for the given path's backend, or
+/// `UnsupportedBackend` if that backend's cargo feature is not enabled. Backends that
+/// log in ask the process-wide `credential_provider` how; see `open_storage_with`.
 pub fn open_storage_for(path: &UniversalPath) -> Result<Box<dyn Storage>, StorageError> {
+    open_storage_with(path, &*provider_for(path)?)
+}
+
+/// The process-wide provider for backends that log in, and an empty one for the rest,
+/// so they never fail on a broken credentials file
+pub(crate) fn provider_for(
+    path: &UniversalPath,
+) -> Result<std::sync::Arc<dyn CredentialProvider>, StorageError> {
     match path.backend() {
-        StorageBackend::Local => Ok(Box::new(local::LocalStorage::default())),
-        other => Err(StorageError::UnsupportedBackend(other.clone())),
+        // Custom backends may log in too
+        StorageBackend::S3
+        | StorageBackend::Sftp
+        | StorageBackend::Ftp
+        | StorageBackend::WebDav
+        | StorageBackend::WebDavs
+        | StorageBackend::Other(_) => Ok(credentials::credential_provider()?),
+        _ => Ok(std::sync::Arc::new(credentials::CredentialChain::new())),
+    }
+}
+
+/// `open_storage_for` with credentials from `provider`. Where it has none for the
+/// path's scheme and host, each backend falls back to its own defaults: the `AWS_*`
+/// environment for S3, the URI's user or the local user and SSH agent or keys for
+/// SFTP, and the URI's user or an anonymous login for FTP. A path whose URI carries a
+/// password logs in with it and skips `provider`.
+///
+/// Schemes are dispatched through the process-wide `storage_registry`, where custom
+/// backends can be added with `register_backend`.
+pub fn open_storage_with(
+    path: &UniversalPath,
+    provider: &dyn CredentialProvider,
+) -> Result<Box<dyn Storage>, StorageError> {
+    registry::open_global(path, provider)
+}
+
+/// The factory every built-in scheme is registered with
+#[cfg_attr(
+    not(any(feature = "s3", feature = "sftp", feature = "ftp")),
+    allow(unused_variables)
+)]
+fn open_builtin(
+    path: &UniversalPath,
+    provider: &dyn CredentialProvider,
+) -> Result<Box<dyn Storage>, StorageError> {
+    match path.backend() {
+        StorageBackend::Local => Ok(Box::new(local::LocalStorage::new())),
+        #[cfg(feature = "s3")]
+        StorageBackend::S3 => {
+            let mut config = s3::S3Config::from_env();
+            if let Some(credentials) = provider.credentials_for(path) {
+                config.apply_credentials(&credentials)?;
+            }
+            Ok(Box::new(s3::S3Storage::new(config)))
+        }
+        #[cfg(feature = "sftp")]
+        StorageBackend::Sftp => {
+            let mut config = sftp::SftpConfig::for_path(path)?;
+            if let Some(credentials) = provider.credentials_for(path) {
+                config.apply_credentials(&credentials)?;
+            }
+            Ok(Box::new(sftp::SftpStorage::shared(config)))
+        }
+        #[cfg(feature = "ftp")]
+        StorageBackend::Ftp => {
+            let mut config = ftp::FtpConfig::for_path(path)?;
+            if let Some(credentials) = provider.credentials_for(path) {
+                config.apply_credentials(&credentials)?;
+            }
+            Ok(Box::new(ftp::FtpStorage::shared(config)))
+        }
+        #[cfg(feature = "http")]
+        StorageBackend::Http => Ok(Box::new(http::HttpStorage::http())),
+        #[cfg(feature = "http")]
+        StorageBackend::Https => Ok(Box::new(http::HttpStorage::https())),
+        #[cfg(feature = "webdav")]
+        backend @ (StorageBackend::WebDav | StorageBackend::WebDavs) => {
+            let mut config = webdav::WebDavConfig::for_path(path)?;
+            if let Some(credentials) = provider.credentials_for(path) {
+                config.apply_credentials(&credentials)?;
+            }
+            Ok(Box::new(webdav::WebDavStorage::new(
+                backend.clone(),
+                config,
+            )))
+        }
+        StorageBackend::NetworkDrive => Ok(Box::new(smb::SmbStorage::detected())),
+        StorageBackend::Memory => Ok(Box::new(memory::MemoryStorage::named(
+            path.host().unwrap_or_default(),
+        ))),
+        // Only ever registered for the schemes above
+        backend => Err(StorageError::UnsupportedBackend(backend.clone())),
     }
 }
 
-pub use local::LocalStorage;
+pub use cache::{CacheConfig, CacheStats, CachedStorage};
+#[cfg(feature = "chaos")]
+pub use chaos::{ChaosConfig, ChaosStorage, Latency};
+pub(crate) use checksum::ChecksumHasher;
+pub use checksum::{Checksum, ChecksumAlgorithm};
+pub use checksum_db::{ChecksumDb, ChecksumDbError, ChecksumDbStats, FileId, IncrementalChecksums};
+pub(crate) use credentials::config_dir;
+pub use credentials::{
+    CredentialChain, CredentialError, CredentialProvider, CredentialStore, Credentials,
+    EnvCredentials, credential_provider, set_credential_provider,
+};
+pub use fd_budget::{FdBudget, FdBudgetStats, FdDiagnostic, FdPermit, fd_budget};
+pub use filter::FilterSet;
+#[cfg(feature = "ftp")]
+pub use ftp::{FtpConfig, FtpStorage};
+#[cfg(feature = "scripting")]
+pub(crate) use glob::matches_segments;
+#[cfg(feature = "http")]
+pub use http::HttpStorage;
+pub use local::{LinkPolicy, LocalStorage};
+pub use lock::{LOCK_FILE_NAME, LockInfo, StorageLock};
+pub use manager::{HostHealth, StorageManager, check_health};
+pub use matrix::{AuthMethod, BackendInfo, capability_matrix};
+pub use memory::MemoryStorage;
+pub use multi_scan::{LabelledEntry, MultiScanStream, MultiScanner};
+pub use operations::{
+    OperationHandle, OperationId, OperationInfo, OperationRegistry, TrackedStorage, operations,
+};
+#[cfg(feature = "plugins")]
+pub use plugin::{PluginError, PluginStorage, load_plugins};
+pub use quota::{Quota, QuotaStorage, QuotaUsage};
+pub use registry::{
+    StorageFactory, StorageRegistry, register_backend, set_storage_registry, storage_registry,
+};
+pub use retrying::RetryingStorage;
+#[cfg(feature = "s3")]
+pub use s3::{S3Config, S3Credentials, S3Storage};
+pub use scan::{ScanItemStream, ScanProgress, ScanStream, Scanner, default_scan_concurrency};
+#[cfg(feature = "sftp")]
+pub use sftp::{SftpAuth, SftpConfig, SftpStorage};
+pub(crate) use skew::mtimes_match;
+#[cfg(any(feature = "http", feature = "s3", feature = "webdav"))]
+pub(crate) use skew::observe_date_header;
+pub use skew::{ClockSkew, SKEW_WARNING, SkewDiagnostic, SkewEstimate, clock_skew, host_key};
+pub use smb::{SmbMount, SmbStorage};
+pub use stream::{ByteStream, READ_STREAM_CHUNK, RangeReader};
+pub use transaction::{CommitReport, Precondition, StorageTransaction, TransactionError};
+pub use transfer::{TransferProgress, TransferReport, copy_target, transfer, transfer_move};
+pub use wake::{MacAddress, WakeConfig, WakingStorage, magic_packet, send_magic_packet, wake};
+pub use walk::{
+    ListStream, SkipCounts, SkipReason, Skipped, StorageExt, SymlinkPolicy, WalkItem,
+    WalkItemStream, WalkOptions, WalkOrder, WalkStream,
+};
+#[cfg(feature = "webdav")]
+pub use webdav::{WebDavConfig, WebDavStorage};
@@ -0,0 +1,799 @@
+use super::{
+    ByteStream, CallOptions, Checksum, ChecksumAlgorithm, EntryKind, EntryMetadata,
+    EntryPermissions, ListStream, Storage, StorageBackend, StorageCapabilities, StorageError,
+    StorageLock, clamp_range,
+};
+use crate::universal_path::UniversalPath;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Range,
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::io::AsyncRead;
+
+/// Where and how much `CachedStorage` keeps.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// A local directory owned by the cache; anything else in it may be deleted
+    pub dir: PathBuf,
+    /// Bytes of file data kept before the least recently used blocks are dropped
+    pub max_bytes: u64,
+    /// Reads are cached in aligned blocks of this size, so nearby reads share them
+    pub block_size: u64,
+    /// How long a stat is trusted before the server is asked again. Cached data is
+    /// only used while its file's size and modification time match the stat.
+    pub stat_ttl: Duration,
+}
+
+impl CacheConfig {
+    /// 1 GiB in `dir`, in 64 KiB blocks, with stats trusted for 5 minutes
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        CacheConfig {
+            dir: dir.into(),
+            max_bytes: 1 << 30,
+            block_size: 64 * 1024,
+            stat_ttl: Duration::from_secs(300),
+        }
+    }
+
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    pub fn with_block_size(mut self, block_size: u64) -> Self {
+        self.block_size = block_size.max(1);
+        self
+    }
+
+    pub fn with_stat_ttl(mut self, stat_ttl: Duration) -> Self {
+        self.stat_ttl = stat_ttl;
+        self
+    }
+}
+
+/// How well the cache is doing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    /// Stats and blocks served from the cache
+    pub hits: u64,
+    /// Stats and blocks fetched from the wrapped storage
+    pub misses: u64,
+    pub bytes: u64,
+    pub entries: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+enum CachedKind {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+/// A stat as kept on disk
+#[derive(Serialize, Deserialize)]
+struct CachedStat {
+    kind: CachedKind,
+    size_bytes: Option<u64>,
+    modified_at: Option<SystemTime>,
+    created_at: Option<SystemTime>,
+    #[serde(default)]
+    permissions: EntryPermissions,
+    #[serde(default)]
+    is_hidden: bool,
+    fetched_at: SystemTime,
+}
+
+impl CachedStat {
+    fn new(meta: &EntryMetadata, fetched_at: SystemTime) -> Self {
+        let kind = match meta.kind {
+            EntryKind::File => CachedKind::File,
+            EntryKind::Directory => CachedKind::Directory,
+            EntryKind::Symlink => CachedKind::Symlink,
+            EntryKind::Other => CachedKind::Other,
+        };
+        CachedStat {
+            kind,
+            size_bytes: meta.size_bytes,
+            modified_at: meta.modified_at,
+            created_at: meta.created_at,
+            permissions: meta.permissions,
+            is_hidden: meta.is_hidden,
+            fetched_at,
+        }
+    }
+
+    fn metadata(&self) -> EntryMetadata {
+        let kind = match self.kind {
+            CachedKind::File => EntryKind::File,
+            CachedKind::Directory => EntryKind::Directory,
+            CachedKind::Symlink => EntryKind::Symlink,
+            CachedKind::Other => EntryKind::Other,
+        };
+        EntryMetadata {
+            kind,
+            size_bytes: self.size_bytes,
+            modified_at: self.modified_at,
+            created_at: self.created_at,
+            permissions: self.permissions,
+            is_hidden: self.is_hidden,
+            format: None,
+        }
+    }
+}
+
+/// Cache files by name, in order of use
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<String, (u64, u64)>,
+    by_use: BTreeMap<u64, String>,
+    bytes: u64,
+    clock: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, name: &str) {
+        self.clock += 1;
+        if let Some((_, used)) = self.entries.get_mut(name) {
+            self.by_use.remove(used);
+            *used = self.clock;
+            self.by_use.insert(self.clock, name.to_string());
+        }
+    }
+
+    /// Record `name` as just used, returning what must go to stay within `max_bytes`
+    fn insert(&mut self, name: &str, size: u64, max_bytes: u64) -> Vec<String> {
+        self.remove(name);
+        self.clock += 1;
+        self.entries.insert(name.to_string(), (size, self.clock));
+        self.by_use.insert(self.clock, name.to_string());
+        self.bytes += size;
+        let mut evicted = Vec::new();
+        while self.bytes > max_bytes {
+            let Some((_, oldest)) = self.by_use.pop_first() else {
+                break;
+            };
+            if let Some((size, _)) = self.entries.remove(&oldest) {
+                self.bytes -= size;
+            }
+            evicted.push(oldest);
+        }
+        evicted
+    }
+
+    fn remove(&mut self, name: &str) {
+        if let Some((size, used)) = self.entries.remove(name) {
+            self.by_use.remove(&used);
+            self.bytes -= size;
+        }
+    }
+}
+
+/// Wraps remote storage with a read-through cache on local disk, so repeated scans
+/// of a library read tag headers and stat files from the disk instead of the network.
+///
+/// `stat` answers are kept for the `stat_ttl`, and `read_range` data in blocks keyed
+/// by the path, size and modification time of the file, so a file that changes is
+/// fetched afresh once its stat does. Writes, deletes and renames through the wrapper
+/// drop the stats they affect; changes made elsewhere go unseen until the TTL runs
+/// out. Calls with `bypass_cache` go straight to the wrapped storage, as do whole-file
+/// reads, streams and listings. Both kinds of entry survive restarts and share the
+/// size limit, least recently used first.
+pub struct CachedStorage<S: Storage + ?Sized = dyn Storage> {
+    inner: Arc<S>,
+    config: CacheConfig,
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    next_temp: AtomicU64,
+}
+
+impl<S: Storage + ?Sized> CachedStorage<S> {
+    /// Cache `inner` in `config.dir`, creating it if needed and taking over whatever
+    /// an earlier run left there
+    pub async fn open(inner: Arc<S>, config: CacheConfig) -> Result<Self, StorageError> {
+        tokio::fs::create_dir_all(&config.dir).await?;
+        let mut found = Vec::new();
+        let mut dir = tokio::fs::read_dir(&config.dir).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let meta = entry.metadata().await?;
+            if !is_entry_name(&name) {
+                // Half-written by a run that stopped mid-way
+                tokio::fs::remove_file(entry.path()).await.ok();
+                continue;
+            }
+            let written = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            found.push((written, name, meta.len()));
+        }
+        found.sort();
+        let storage = CachedStorage {
+            inner,
+            config,
+            lru: Mutex::new(Lru::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            next_temp: AtomicU64::new(0),
+        };
+        for (_, name, size) in found {
+            storage.remember(&name, size).await;
+        }
+        Ok(storage)
+    }
+
+    pub fn inner(&self) -> &Arc<S> {
+        &self.inner
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let lru = self.lru.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            bytes: lru.bytes,
+            entries: lru.entries.len(),
+        }
+    }
+
+    /// Drop everything cached
+    pub async fn clear(&self) -> Result<(), StorageError> {
+        let names: Vec<_> = {
+            let mut lru = self.lru.lock().unwrap();
+            let names = lru.entries.keys().cloned().collect();
+            *lru = Lru::default();
+            names
+        };
+        for name in names {
+            remove_entry(&self.config.dir.join(name)).await?;
+        }
+        Ok(())
+    }
+
+    /// Forget the stat of `path`, e.g. after changing it some other way
+    pub async fn invalidate(&self, path: &UniversalPath) {
+        let name = stat_name(path);
+        self.lru.lock().unwrap().remove(&name);
+        remove_entry(&self.config.dir.join(name)).await.ok();
+    }
+
+    async fn remember(&self, name: &str, size: u64) {
+        let evicted = {
+            let mut lru = self.lru.lock().unwrap();
+            lru.insert(name, size, self.config.max_bytes)
+        };
+        for name in evicted {
+            remove_entry(&self.config.dir.join(name)).await.ok();
+        }
+    }
+
+    /// The entry `name`, if cached and readable
+    async fn load(&self, name: &str) -> Option<Vec<u8>> {
+        if !self.lru.lock().unwrap().entries.contains_key(name) {
+            return None;
+        }
+        match tokio::fs::read(self.config.dir.join(name)).await {
+            Ok(data) => {
+                self.lru.lock().unwrap().touch(name);
+                Some(data)
+            }
+            Err(_) => {
+                self.lru.lock().unwrap().remove(name);
+                None
+            }
+        }
+    }
+
+    /// Keep `data` as `name`. The cache is only an optimisation, so a full or broken
+    /// disk just means the entry is not kept.
+    async fn store(&self, name: &str, data: &[u8]) {
+        let temp = self.config.dir.join(format!(
+            "{name}.{}-{}",
+            std::process::id(),
+            self.next_temp.fetch_add(1, Ordering::Relaxed)
+        ));
+        let stored = async {
+            tokio::fs::write(&temp, data).await?;
+            tokio::fs::rename(&temp, self.config.dir.join(name)).await
+        };
+        match stored.await {
+            Ok(()) => self.remember(name, data.len() as u64).await,
+            Err(_) => {
+                tokio::fs::remove_file(&temp).await.ok();
+            }
+        }
+    }
+
+    async fn cached_stat(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<EntryMetadata, StorageError> {
+        if let Some(meta) = self.fresh_stat(path).await {
+            return Ok(meta);
+        }
+        let fetched_at = SystemTime::now();
+        let meta = self.inner.stat_opts(path, opts).await?;
+        self.remember_stat(path, &meta, fetched_at).await;
+        Ok(meta)
+    }
+
+    /// The cached stat of `path` if it is still trusted, counting a hit or a miss
+    async fn fresh_stat(&self, path: &UniversalPath) -> Option<EntryMetadata> {
+        if let Some(data) = self.load(&stat_name(path)).await
+            && let Ok(stat) = serde_json::from_slice::<CachedStat>(&data)
+            && stat
+                .fetched_at
+                .elapsed()
+                .is_ok_and(|age| age < self.config.stat_ttl)
+        {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Some(stat.metadata());
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    async fn remember_stat(
+        &self,
+        path: &UniversalPath,
+        meta: &EntryMetadata,
+        fetched_at: SystemTime,
+    ) {
+        let stat = CachedStat::new(meta, fetched_at);
+        let data = serde_json::to_vec(&stat).expect("stats serialize");
+        self.store(&stat_name(path), &data).await;
+    }
+
+    /// The file's `blocks`, each `None` if not cached
+    async fn cached_blocks(&self, key: &str, blocks: Range<u64>) -> Vec<Option<Vec<u8>>> {
+        let mut found = Vec::new();
+        for index in blocks {
+            found.push(self.load(&block_name(key, index)).await);
+        }
+        found
+    }
+}
+
+/// Cache entry names are hex SHA-256 digests; anything else is a leftover temp file
+fn is_entry_name(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn digest(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    hex::encode(hasher.finalize())
+}
+
+fn stat_name(path: &UniversalPath) -> String {
+    digest(&["stat", &path.as_file().to_string()])
+}
+
+/// What the file's blocks are keyed on: which file, as of which change
+fn file_key(path: &UniversalPath, size: u64, modified: SystemTime, block_size: u64) -> String {
+    let modified = modified
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    format!("{}\0{size}\0{modified}\0{block_size}", path.as_file())
+}
+
+fn block_name(key: &str, index: u64) -> String {
+    digest(&["block", key, &index.to_string()])
+}
+
+async fn remove_entry(path: &std::path::Path) -> Result<(), StorageError> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[async_trait]
+impl<S: Storage + ?Sized> Storage for CachedStorage<S> {
+    fn backend(&self) -> StorageBackend {
+        self.inner.backend()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.inner.capabilities()
+    }
+
+    async fn stat_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<EntryMetadata, StorageError> {
+        if opts.bypass_cache {
+            return self.inner.stat_opts(path, opts).await;
+        }
+        self.cached_stat(path, opts).await
+    }
+
+    /// Cached stats are answered from the cache and the rest asked for in one batch
+    async fn stat_many_opts(
+        &self,
+        paths: &[UniversalPath],
+        opts: &CallOptions,
+    ) -> Vec<Result<EntryMetadata, StorageError>> {
+        if opts.bypass_cache {
+            return self.inner.stat_many_opts(paths, opts).await;
+        }
+        let mut stats = Vec::with_capacity(paths.len());
+        let mut missing = Vec::new();
+        for (index, path) in paths.iter().enumerate() {
+            let cached = self.fresh_stat(path).await;
+            if cached.is_none() {
+                missing.push(index);
+            }
+            stats.push(cached.ok_or(StorageError::NotFound));
+        }
+        if missing.is_empty() {
+            return stats;
+        }
+        let fetched_at = SystemTime::now();
+        let asked: Vec<_> = missing.iter().map(|&index| paths[index].clone()).collect();
+        let fetched = self.inner.stat_many_opts(&asked, opts).await;
+        for (index, stat) in missing.into_iter().zip(fetched) {
+            if let Ok(meta) = &stat {
+                self.remember_stat(&paths[index], meta, fetched_at).await;
+            }
+            stats[index] = stat;
+        }
+        stats
+    }
+
+    async fn read_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        self.inner.read_opts(path, opts).await
+    }
+
+    async fn read_range_opts(
+        &self,
+        path: &UniversalPath,
+        range: Range<u64>,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        if opts.bypass_cache {
+            return self.inner.read_range_opts(path, range, opts).await;
+        }
+        let meta = self.cached_stat(path, opts).await?;
+        let (Some(size), Some(modified), EntryKind::File) =
+            (meta.size_bytes, meta.modified_at, &meta.kind)
+        else {
+            // Nothing to tell a changed file by
+            return self.inner.read_range_opts(path, range, opts).await;
+        };
+        let range = clamp_range(range, size)?;
+        if range.is_empty() {
+            return Ok(Vec::new());
+        }
+        let bs = self.config.block_size;
+        let key = file_key(path, size, modified, bs);
+        let blocks = range.start / bs..(range.end - 1) / bs + 1;
+        let mut cached = self.cached_blocks(&key, blocks.clone()).await;
+        let hits = cached.iter().filter(|b| b.is_some()).count() as u64;
+        self.hits.fetch_add(hits, Ordering::Relaxed);
+        self.misses
+            .fetch_add(cached.len() as u64 - hits, Ordering::Relaxed);
+
+        // Fetch each run of missing blocks in one call
+        let mut i = 0;
+        while i < cached.len() {
+            if cached[i].is_some() {
+                i += 1;
+                continue;
+            }
+            let run_end = (i..cached.len())
+                .find(|&j| cached[j].is_some())
+                .unwrap_or(cached.len());
+            let start = (blocks.start + i as u64) * bs;
+            let end = ((blocks.start + run_end as u64) * bs).min(size);
+            let data = self.inner.read_range_opts(path, start..end, opts).await?;
+            if data.len() as u64 != end - start {
+                // Changed since the stat; cache nothing of it
+                self.invalidate(path).await;
+                return self.inner.read_range_opts(path, range, opts).await;
+            }
+            for (n, block) in data.chunks(bs as usize).enumerate() {
+                let index = blocks.start + (i + n) as u64;
+                self.store(&block_name(&key, index), block).await;
+                cached[i + n] = Some(block.to_vec());
+            }
+            i = run_end;
+        }
+
+        let mut out = Vec::with_capacity((range.end - range.start) as usize);
+        for (n, block) in cached.into_iter().enumerate() {
+            let block = block.expect("every block fetched");
+            let block_start = (blocks.start + n as u64) * bs;
+            let from = range.start.saturating_sub(block_start) as usize;
+            let to = ((range.end - block_start) as usize).min(block.len());
+            out.extend_from_slice(&block[from.min(to)..to]);
+        }
+        Ok(out)
+    }
+
+    async fn list_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<UniversalPath>, StorageError> {
+        self.inner.list_opts(path, opts).await
+    }
+
+    /// Forwarded, keeping what the listing says of each child as its stat
+    async fn list_with_metadata_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<(UniversalPath, EntryMetadata)>, StorageError> {
+        let fetched_at = SystemTime::now();
+        let listed = self.inner.list_with_metadata_opts(path, opts).await?;
+        if !opts.bypass_cache {
+            for (child, meta) in &listed {
+                self.remember_stat(child, meta, fetched_at).await;
+            }
+        }
+        Ok(listed)
+    }
+
+    /// Forwarded, keeping each child's stat as it streams past
+    async fn list_stream_opts<'a>(
+        &'a self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<ListStream<'a>, StorageError> {
+        use futures::StreamExt;
+        let fetched_at = SystemTime::now();
+        let listed = self.inner.list_stream_opts(path, opts).await?;
+        if opts.bypass_cache {
+            return Ok(listed);
+        }
+        let remembered = listed.then(move |item| async move {
+            if let Ok((child, meta)) = &item {
+                self.remember_stat(child, meta, fetched_at).await;
+            }
+            item
+        });
+        Ok(Box::pin(remembered))
+    }
+
+    async fn glob_opts(
+        &self,
+        pattern: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<UniversalPath>, StorageError> {
+        self.inner.glob_opts(pattern, opts).await
+    }
+
+    async fn symlink_target_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Option<UniversalPath>, StorageError> {
+        self.inner.symlink_target_opts(path, opts).await
+    }
+
+    async fn read_stream_opts<'a>(
+        &'a self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<ByteStream<'a>, StorageError> {
+        self.inner.read_stream_opts(path, opts).await
+    }
+
+    async fn checksum_opts(
+        &self,
+        path: &UniversalPath,
+        algorithm: ChecksumAlgorithm,
+        opts: &CallOptions,
+    ) -> Result<Checksum, StorageError> {
+        self.inner.checksum_opts(path, algorithm, opts).await
+    }
+
+    async fn write_opts(
+        &self,
+        path: &UniversalPath,
+        data: &[u8],
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        let result = self.inner.write_opts(path, data, opts).await;
+        self.invalidate(path).await;
+        result
+    }
+
+    async fn write_stream_opts(
+        &self,
+        path: &UniversalPath,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        let result = self.inner.write_stream_opts(path, reader, opts).await;
+        self.invalidate(path).await;
+        result
+    }
+
+    async fn create_dir_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        let result = self.inner.create_dir_opts(path, opts).await;
+        self.invalidate(path).await;
+        result
+    }
+
+    async fn delete_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        let result = self.inner.delete_opts(path, opts).await;
+        self.invalidate(path).await;
+        result
+    }
+
+    async fn rename_opts(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        let result = self.inner.rename_opts(from, to, opts).await;
+        self.invalidate(from).await;
+        self.invalidate(to).await;
+        result
+    }
+
+    async fn copy_opts(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        let result = self.inner.copy_opts(from, to, opts).await;
+        self.invalidate(to).await;
+        result
+    }
+
+    async fn set_modified_opts(
+        &self,
+        path: &UniversalPath,
+        modified_at: SystemTime,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        let result = self.inner.set_modified_opts(path, modified_at, opts).await;
+        self.invalidate(path).await;
+        result
+    }
+
+    async fn attribute_opts(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+        opts: &CallOptions,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        self.inner.attribute_opts(path, name, opts).await
+    }
+
+    async fn set_attribute_opts(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+        value: &[u8],
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.inner.set_attribute_opts(path, name, value, opts).await
+    }
+
+    async fn acquire_lock_opts(
+        &self,
+        path: &UniversalPath,
+        ttl: Duration,
+        opts: &CallOptions,
+    ) -> Result<StorageLock, StorageError> {
+        self.inner.acquire_lock_opts(path, ttl, opts).await
+    }
+
+    async fn renew_lock_opts(
+        &self,
+        lock: &StorageLock,
+        ttl: Duration,
+        opts: &CallOptions,
+    ) -> Result<StorageLock, StorageError> {
+        self.inner.renew_lock_opts(lock, ttl, opts).await
+    }
+
+    async fn release_lock_opts(
+        &self,
+        lock: &StorageLock,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.inner.release_lock_opts(lock, opts).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_read_through_cache() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let remote = Arc::new(MemoryStorage::new());
+        let path = UniversalPath::from_uri_str("mem://cache/01.flac").unwrap();
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        remote.write(&path, &data).await.unwrap();
+        let config = CacheConfig::new(dir).with_block_size(1024);
+
+        let cache = CachedStorage::open(remote.clone(), config.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            cache.read_range(&path, 100..3000).await.unwrap(),
+            data[100..3000]
+        );
+        // The stat and three blocks
+        assert_eq!((cache.stats().hits, cache.stats().misses), (0, 4));
+        assert_eq!(
+            cache.read_range(&path, 0..2048).await.unwrap(),
+            data[..2048]
+        );
+        assert_eq!(cache.stats().hits, 3);
+        assert_eq!(
+            cache.read_suffix(&path, 128).await.unwrap(),
+            data[data.len() - 128..]
+        );
+
+        // Another run finds it all still there
+        drop(cache);
+        let cache = CachedStorage::open(remote.clone(), config.clone())
+            .await
+            .unwrap();
+        assert_eq!(cache.stats().entries, 5);
+        assert_eq!(
+            cache.read_range(&path, 500..1500).await.unwrap(),
+            data[500..1500]
+        );
+        assert_eq!(cache.stats().misses, 0);
+        // A batch stat answers what it can from the cache and asks for the rest at once
+        let missing = path.parent().unwrap().join("02.flac");
+        let stats = cache.stat_many(&[missing, path.clone()]).await;
+        assert!(matches!(stats[0], Err(StorageError::NotFound)));
+        assert_eq!(stats[1].as_ref().unwrap().size_bytes, Some(10_000));
+        assert_eq!(cache.stats().misses, 1);
+
+        // Written through the cache: the next read sees the new contents
+        cache.write(&path, b"retagged").await.unwrap();
+        assert_eq!(cache.read_range(&path, 0..100).await.unwrap(), b"retagged");
+
+        // Staying within the limit drops the least recently used
+        let small = CachedStorage::open(remote, config.with_max_bytes(2048))
+            .await
+            .unwrap();
+        assert!(small.stats().bytes <= 2048);
+        small.clear().await.unwrap();
+        assert_eq!(small.stats().entries, 0);
+    }
+}
@@ -0,0 +1,423 @@
+use super::{
+    CallOptions, EntryMetadata, Storage, StorageBackend, StorageCapabilities, StorageError,
+};
+use crate::universal_path::UniversalPath;
+use async_trait::async_trait;
+use std::{
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How long each simulated round trip takes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Latency {
+    None,
+    Fixed(Duration),
+    /// Anywhere between the two, evenly spread
+    Uniform {
+        min: Duration,
+        max: Duration,
+    },
+    /// Mostly short with the occasional long stall, averaging `mean`: the shape of a
+    /// congested WAN link
+    Exponential {
+        mean: Duration,
+    },
+}
+
+impl Latency {
+    fn sample(&self, rng: &mut Rng) -> Duration {
+        match *self {
+            Latency::None => Duration::ZERO,
+            Latency::Fixed(d) => d,
+            Latency::Uniform { min, max } => {
+                let spread = max.saturating_sub(min).as_secs_f64();
+                min + Duration::from_secs_f64(spread * rng.next_f64())
+            }
+            Latency::Exponential { mean } => {
+                // Inverse transform; 1 - u keeps the logarithm finite
+                let u = 1.0 - rng.next_f64();
+                Duration::from_secs_f64(-mean.as_secs_f64() * u.ln())
+            }
+        }
+    }
+}
+
+/// What `ChaosStorage` does to the calls passing through it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChaosConfig {
+    /// Added before every call
+    pub latency: Latency,
+    /// Bytes per second that reads and writes are held to
+    pub bandwidth: Option<u64>,
+    /// Fraction of calls, from 0.0 to 1.0, that fail with `StorageError::Connection`
+    /// instead of reaching the wrapped storage
+    pub error_rate: f64,
+    /// Fraction of calls that hang until `timeout_after` and then fail with
+    /// `StorageError::Timeout`
+    pub timeout_rate: f64,
+    pub timeout_after: Duration,
+    /// Seeds the random choices, so a run can be repeated exactly
+    pub seed: u64,
+}
+
+impl Default for ChaosConfig {
+    /// No chaos at all, seeded from the clock
+    fn default() -> Self {
+        ChaosConfig {
+            latency: Latency::None,
+            bandwidth: None,
+            error_rate: 0.0,
+            timeout_rate: 0.0,
+            timeout_after: Duration::from_secs(30),
+            seed: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or(1),
+        }
+    }
+}
+
+impl ChaosConfig {
+    pub fn with_latency(mut self, latency: Latency) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    pub fn with_bandwidth(mut self, bytes_per_sec: u64) -> Self {
+        self.bandwidth = Some(bytes_per_sec);
+        self
+    }
+
+    pub fn with_error_rate(mut self, error_rate: f64) -> Self {
+        self.error_rate = error_rate.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_timeouts(mut self, timeout_rate: f64, timeout_after: Duration) -> Self {
+        self.timeout_rate = timeout_rate.clamp(0.0, 1.0);
+        self.timeout_after = timeout_after;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+/// xorshift64*: plenty for picking delays, and no dependency
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // Zero is the one state xorshift never leaves
+        Rng(seed.max(1))
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        let x = self.0.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Wraps any storage to make it behave like a slow, unreliable remote: latency on
+/// every call, a bandwidth cap on transfers, and injected failures. Meant for
+/// benchmarking how scanning and streaming hold up before deploying against a real
+/// WAN link. Composite calls such as `read_stream` and `glob` are built from the
+/// wrapped calls by the `Storage` defaults, so each of their round trips pays too.
+pub struct ChaosStorage {
+    inner: Arc<dyn Storage>,
+    config: ChaosConfig,
+    rng: Mutex<Rng>,
+}
+
+impl ChaosStorage {
+    pub fn new(inner: Arc<dyn Storage>, config: ChaosConfig) -> Self {
+        let rng = Mutex::new(Rng::new(config.seed));
+        ChaosStorage { inner, config, rng }
+    }
+
+    pub fn config(&self) -> &ChaosConfig {
+        &self.config
+    }
+
+    /// The delay and any failure for one call
+    async fn round_trip(&self, op: &'static str) -> Result<(), StorageError> {
+        let (delay, fails, hangs) = {
+            let mut rng = self.rng.lock().unwrap();
+            let delay = self.config.latency.sample(&mut rng);
+            let roll = rng.next_f64();
+            let fails = roll < self.config.error_rate;
+            let hangs = !fails && roll < self.config.error_rate + self.config.timeout_rate;
+            (delay, fails, hangs)
+        };
+        tokio::time::sleep(delay).await;
+        if fails {
+            return Err(StorageError::Connection(format!(
+                "injected failure in {op}"
+            )));
+        }
+        if hangs {
+            tokio::time::sleep(self.config.timeout_after).await;
+            return Err(StorageError::Timeout(self.config.timeout_after));
+        }
+        Ok(())
+    }
+
+    /// Hold a transfer of `bytes` to the bandwidth cap
+    async fn transfer(&self, bytes: usize) {
+        if let Some(rate) = self.config.bandwidth.filter(|r| *r > 0) {
+            tokio::time::sleep(Duration::from_secs_f64(bytes as f64 / rate as f64)).await;
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for ChaosStorage {
+    fn backend(&self) -> StorageBackend {
+        self.inner.backend()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.inner.capabilities()
+    }
+
+    async fn stat_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<EntryMetadata, StorageError> {
+        opts.enforce_timeout(async {
+            self.round_trip("stat").await?;
+            self.inner.stat_opts(path, opts).await
+        })
+        .await
+    }
+
+    async fn read_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        opts.enforce_timeout(async {
+            self.round_trip("read").await?;
+            let data = self.inner.read_opts(path, opts).await?;
+            self.transfer(data.len()).await;
+            Ok(data)
+        })
+        .await
+    }
+
+    async fn read_range_opts(
+        &self,
+        path: &UniversalPath,
+        range: Range<u64>,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        opts.enforce_timeout(async {
+            self.round_trip("read_range").await?;
+            let data = self.inner.read_range_opts(path, range, opts).await?;
+            self.transfer(data.len()).await;
+            Ok(data)
+        })
+        .await
+    }
+
+    async fn list_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<UniversalPath>, StorageError> {
+        opts.enforce_timeout(async {
+            self.round_trip("list").await?;
+            self.inner.list_opts(path, opts).await
+        })
+        .await
+    }
+
+    async fn symlink_target_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Option<UniversalPath>, StorageError> {
+        opts.enforce_timeout(async {
+            self.round_trip("symlink_target").await?;
+            self.inner.symlink_target_opts(path, opts).await
+        })
+        .await
+    }
+
+    async fn write_opts(
+        &self,
+        path: &UniversalPath,
+        data: &[u8],
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        opts.enforce_timeout(async {
+            self.round_trip("write").await?;
+            self.transfer(data.len()).await;
+            self.inner.write_opts(path, data, opts).await
+        })
+        .await
+    }
+
+    async fn write_stream_opts(
+        &self,
+        path: &UniversalPath,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        // Read it all first so the cap applies to the whole upload
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await?;
+        self.write_opts(path, &data, opts).await?;
+        Ok(data.len() as u64)
+    }
+
+    async fn create_dir_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        opts.enforce_timeout(async {
+            self.round_trip("create_dir").await?;
+            self.inner.create_dir_opts(path, opts).await
+        })
+        .await
+    }
+
+    async fn delete_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        opts.enforce_timeout(async {
+            self.round_trip("delete").await?;
+            self.inner.delete_opts(path, opts).await
+        })
+        .await
+    }
+
+    async fn rename_opts(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        opts.enforce_timeout(async {
+            self.round_trip("rename").await?;
+            self.inner.rename_opts(from, to, opts).await
+        })
+        .await
+    }
+
+    async fn copy_opts(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        opts.enforce_timeout(async {
+            self.round_trip("copy").await?;
+            self.inner.copy_opts(from, to, opts).await
+        })
+        .await
+    }
+
+    async fn set_modified_opts(
+        &self,
+        path: &UniversalPath,
+        modified_at: SystemTime,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        opts.enforce_timeout(async {
+            self.round_trip("set_modified").await?;
+            self.inner.set_modified_opts(path, modified_at, opts).await
+        })
+        .await
+    }
+
+    async fn attribute_opts(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+        opts: &CallOptions,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        opts.enforce_timeout(async {
+            self.round_trip("attribute").await?;
+            self.inner.attribute_opts(path, name, opts).await
+        })
+        .await
+    }
+
+    async fn set_attribute_opts(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+        value: &[u8],
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        opts.enforce_timeout(async {
+            self.round_trip("set_attribute").await?;
+            self.inner.set_attribute_opts(path, name, value, opts).await
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LocalStorage;
+    use std::{fs, time::Instant};
+
+    #[tokio::test]
+    async fn test_latency_bandwidth_and_failures() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("a.flac"), vec![0u8; 200]).unwrap();
+        let file = UniversalPath::local(dir.join("a.flac").to_str().unwrap());
+
+        let slow = ChaosStorage::new(
+            Arc::new(LocalStorage::new()),
+            ChaosConfig::default()
+                .with_latency(Latency::Fixed(Duration::from_millis(20)))
+                .with_bandwidth(2_000),
+        );
+        let started = Instant::now();
+        assert_eq!(slow.read(&file).await.unwrap().len(), 200);
+        // 20ms of latency plus 200 bytes at 2000 B/s
+        assert!(started.elapsed() >= Duration::from_millis(120));
+
+        let broken = ChaosStorage::new(
+            Arc::new(LocalStorage::new()),
+            ChaosConfig::default().with_error_rate(1.0),
+        );
+        assert!(matches!(
+            broken.stat(&file).await,
+            Err(StorageError::Connection(_))
+        ));
+
+        // The same seed fails the same calls
+        let flaky = |seed| {
+            ChaosStorage::new(
+                Arc::new(LocalStorage::new()),
+                ChaosConfig::default().with_error_rate(0.5).with_seed(seed),
+            )
+        };
+        let (a, b) = (flaky(42), flaky(42));
+        let mut outcomes = Vec::new();
+        for _ in 0..20 {
+            let (x, y) = (a.stat(&file).await.is_ok(), b.stat(&file).await.is_ok());
+            assert_eq!(x, y);
+            outcomes.push(x);
+        }
+        assert!(outcomes.contains(&true) && outcomes.contains(&false));
+    }
+}
@@ -0,0 +1,177 @@
+use super::{CallOptions, Storage, StorageError};
+use crate::universal_path::UniversalPath;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{fmt, str::FromStr};
+use tokio::io::AsyncReadExt;
+
+/// Bytes hashed per read while streaming a file through a hasher.
+const HASH_CHUNK: usize = 256 * 1024;
+
+/// A hash function `Storage::checksum` can compute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgorithm {
+    /// Only for comparing with what servers report, such as S3 ETags; not
+    /// collision-resistant
+    Md5,
+    Sha256,
+    Blake3,
+    /// XXH3, 64-bit: fast, for spotting likely duplicates rather than proving them
+    Xxh3,
+}
+
+impl ChecksumAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Md5 => "md5",
+            ChecksumAlgorithm::Sha256 => "sha256",
+            ChecksumAlgorithm::Blake3 => "blake3",
+            ChecksumAlgorithm::Xxh3 => "xxh3",
+        }
+    }
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = StorageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "md5" => Ok(ChecksumAlgorithm::Md5),
+            "sha256" | "sha-256" => Ok(ChecksumAlgorithm::Sha256),
+            "blake3" => Ok(ChecksumAlgorithm::Blake3),
+            "xxh3" => Ok(ChecksumAlgorithm::Xxh3),
+            _ => Err(StorageError::UnsupportedFeature("checksum algorithm")),
+        }
+    }
+}
+
+/// The checksum of a file's contents. Prints as `algorithm:hex`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Checksum {
+    pub algorithm: ChecksumAlgorithm,
+    #[serde(with = "hex::serde")]
+    pub bytes: Vec<u8>,
+}
+
+impl Checksum {
+    /// The checksum of `data`, computed here
+    pub fn of(algorithm: ChecksumAlgorithm, data: &[u8]) -> Self {
+        let mut hasher = ChecksumHasher::new(algorithm);
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(&self.bytes)
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm.name(), self.to_hex())
+    }
+}
+
+/// Incremental hashing under any `ChecksumAlgorithm`
+pub(crate) enum ChecksumHasher {
+    Md5(md5::Md5),
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+    Xxh3(Box<xxhash_rust::xxh3::Xxh3>),
+}
+
+impl ChecksumHasher {
+    pub(crate) fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Md5 => ChecksumHasher::Md5(md5::Md5::new()),
+            ChecksumAlgorithm::Sha256 => ChecksumHasher::Sha256(Sha256::new()),
+            ChecksumAlgorithm::Blake3 => ChecksumHasher::Blake3(Box::default()),
+            ChecksumAlgorithm::Xxh3 => ChecksumHasher::Xxh3(Box::default()),
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            ChecksumHasher::Md5(h) => h.update(data),
+            ChecksumHasher::Sha256(h) => h.update(data),
+            ChecksumHasher::Blake3(h) => {
+                h.update(data);
+            }
+            ChecksumHasher::Xxh3(h) => h.update(data),
+        }
+    }
+
+    pub(crate) fn finish(self) -> Checksum {
+        let (algorithm, bytes) = match self {
+            ChecksumHasher::Md5(h) => (ChecksumAlgorithm::Md5, h.finalize().to_vec()),
+            ChecksumHasher::Sha256(h) => (ChecksumAlgorithm::Sha256, h.finalize().to_vec()),
+            ChecksumHasher::Blake3(h) => {
+                (ChecksumAlgorithm::Blake3, h.finalize().as_bytes().to_vec())
+            }
+            ChecksumHasher::Xxh3(h) => (ChecksumAlgorithm::Xxh3, h.digest().to_be_bytes().to_vec()),
+        };
+        Checksum { algorithm, bytes }
+    }
+}
+
+/// Stream the file at `path` through a hasher
+pub(crate) async fn compute<S: Storage + ?Sized>(
+    storage: &S,
+    path: &UniversalPath,
+    algorithm: ChecksumAlgorithm,
+    opts: &CallOptions,
+) -> Result<Checksum, StorageError> {
+    let mut reader = storage.read_stream_opts(path, opts).await?;
+    let mut hasher = ChecksumHasher::new(algorithm);
+    let mut buf = vec![0; HASH_CHUNK];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_streamed_checksums() {
+        let storage = MemoryStorage::new();
+        let path = UniversalPath::from_uri_str("mem://checksum/a.flac").unwrap();
+        let data: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+        storage.write(&path, &data).await.unwrap();
+
+        for algorithm in [
+            ChecksumAlgorithm::Md5,
+            ChecksumAlgorithm::Sha256,
+            ChecksumAlgorithm::Blake3,
+            ChecksumAlgorithm::Xxh3,
+        ] {
+            let streamed = storage.checksum(&path, algorithm).await.unwrap();
+            assert_eq!(streamed, Checksum::of(algorithm, &data));
+            assert_eq!(
+                algorithm.name().parse::<ChecksumAlgorithm>().unwrap(),
+                algorithm
+            );
+        }
+        assert_eq!(
+            Checksum::of(ChecksumAlgorithm::Sha256, b"").to_string(),
+            "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            Checksum::of(ChecksumAlgorithm::Md5, b"").to_hex(),
+            "d41d8cd98f00b204e9800998ecf8427e"
+        );
+        assert!(matches!(
+            storage
+                .checksum(&path.parent().unwrap(), ChecksumAlgorithm::Sha256)
+                .await,
+            Err(StorageError::NotAFile)
+        ));
+    }
+}
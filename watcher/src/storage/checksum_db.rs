@@ -0,0 +1,604 @@
+use super::{
+    ByteStream, CallOptions, Checksum, ChecksumAlgorithm, EntryKind, EntryMetadata, ListStream,
+    LocalStorage, Storage, StorageBackend, StorageCapabilities, StorageError, StorageLock,
+};
+use crate::universal_path::UniversalPath;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use thiserror::Error;
+use tokio::io::AsyncRead;
+
+/// Version of the file `ChecksumDb` writes
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Error)]
+pub enum ChecksumDbError {
+    #[error("checksum database is version {0}, newer than this build reads")]
+    UnsupportedVersion(u32),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// What identifies a file across runs: its device and inode where the filesystem has
+/// them, so a checksum survives the file being renamed, and its path elsewhere.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FileId {
+    Inode {
+        device: u64,
+        inode: u64,
+    },
+    /// The printed URI, without credentials
+    Path {
+        uri: String,
+    },
+}
+
+impl FileId {
+    /// The id of the file at `path`. Local files on Unix are looked up by inode; a
+    /// file that cannot be looked up falls back to its path.
+    pub async fn of(path: &UniversalPath) -> FileId {
+        #[cfg(unix)]
+        if path.backend() == &StorageBackend::Local
+            && let Ok(native) = LocalStorage::new().to_pathbuf(path)
+            && let Ok(meta) = tokio::fs::metadata(&native).await
+        {
+            use std::os::unix::fs::MetadataExt;
+            return FileId::Inode {
+                device: meta.dev(),
+                inode: meta.ino(),
+            };
+        }
+        FileId::Path {
+            uri: path.without_user().to_string(),
+        }
+    }
+}
+
+/// How often the database saved hashing a file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ChecksumDbStats {
+    /// Checksums answered from the database
+    pub hits: u64,
+    /// Checksums `IncrementalChecksums` computed, because the file was new, changed
+    /// or forced
+    pub misses: u64,
+    pub entries: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Record {
+    file: FileId,
+    /// Where the file was when last hashed, for whoever reads the database
+    path: String,
+    size_bytes: u64,
+    modified_at: SystemTime,
+    checksum: Checksum,
+    /// When the checksum was last computed or used
+    seen_at: SystemTime,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DbFile {
+    version: u32,
+    records: Vec<Record>,
+}
+
+#[derive(Debug, Default)]
+struct DbState {
+    records: HashMap<(FileId, ChecksumAlgorithm), Record>,
+    hits: u64,
+    misses: u64,
+    dirty: bool,
+}
+
+/// Checksums already computed, by file, size and modification time, so a run that
+/// verifies or deduplicates a library only reads the files that changed since the
+/// last one. A checksum is used only while both the size and the modification time
+/// are exactly what they were when it was computed; a file with no modification time
+/// is always hashed again.
+///
+/// Cloning shares the database. Changes are saved on `flush`, as versioned JSON
+/// written then renamed into place, so a crash leaves the previous version.
+#[derive(Debug, Clone, Default)]
+pub struct ChecksumDb {
+    state: Arc<Mutex<DbState>>,
+    path: Option<PathBuf>,
+}
+
+impl ChecksumDb {
+    /// A database that is never saved
+    pub fn in_memory() -> Self {
+        ChecksumDb::default()
+    }
+
+    /// Open (or create) a database saved at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, ChecksumDbError> {
+        let path = path.as_ref().to_path_buf();
+        let records = match std::fs::read(&path) {
+            Ok(bytes) => {
+                let file: DbFile = serde_json::from_slice(&bytes)?;
+                if file.version > FORMAT_VERSION {
+                    return Err(ChecksumDbError::UnsupportedVersion(file.version));
+                }
+                let records = file.records.into_iter();
+                records
+                    .map(|r| ((r.file.clone(), r.checksum.algorithm), r))
+                    .collect()
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(ChecksumDb {
+            state: Arc::new(Mutex::new(DbState {
+                records,
+                ..DbState::default()
+            })),
+            path: Some(path),
+        })
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, DbState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The checksum recorded for `file`, if it was computed when the file had
+    /// exactly the size and modification time in `meta`
+    pub fn lookup(
+        &self,
+        file: &FileId,
+        meta: &EntryMetadata,
+        algorithm: ChecksumAlgorithm,
+    ) -> Option<Checksum> {
+        let mut state = self.state();
+        let (Some(size), Some(modified)) = (meta.size_bytes, meta.modified_at) else {
+            return None;
+        };
+        let found = match state.records.get_mut(&(file.clone(), algorithm)) {
+            Some(record) if record.size_bytes == size && record.modified_at == modified => {
+                record.seen_at = SystemTime::now();
+                Some(record.checksum.clone())
+            }
+            _ => None,
+        };
+        if found.is_some() {
+            state.hits += 1;
+            state.dirty = true;
+        }
+        found
+    }
+
+    /// Remember `checksum` for `file` as it is in `meta`. Files without a size or
+    /// modification time are not recorded, as nothing would tell when they changed.
+    pub fn record(
+        &self,
+        file: FileId,
+        path: &UniversalPath,
+        meta: &EntryMetadata,
+        checksum: Checksum,
+    ) {
+        let (Some(size_bytes), Some(modified_at)) = (meta.size_bytes, meta.modified_at) else {
+            return;
+        };
+        let record = Record {
+            file: file.clone(),
+            path: path.without_user().to_string(),
+            size_bytes,
+            modified_at,
+            checksum,
+            seen_at: SystemTime::now(),
+        };
+        let mut state = self.state();
+        state
+            .records
+            .insert((file, record.checksum.algorithm), record);
+        state.dirty = true;
+    }
+
+    /// Drop every checksum recorded for `file`
+    pub fn forget(&self, file: &FileId) {
+        let mut state = self.state();
+        let before = state.records.len();
+        state.records.retain(|(id, _), _| id != file);
+        state.dirty |= state.records.len() != before;
+    }
+
+    /// Drop checksums not computed or used since `cutoff`, e.g. of files deleted
+    /// since, returning how many were dropped
+    pub fn prune(&self, cutoff: SystemTime) -> usize {
+        let mut state = self.state();
+        let before = state.records.len();
+        state.records.retain(|_, record| record.seen_at >= cutoff);
+        let pruned = before - state.records.len();
+        state.dirty |= pruned > 0;
+        pruned
+    }
+
+    pub fn len(&self) -> usize {
+        self.state().records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> ChecksumDbStats {
+        let state = self.state();
+        ChecksumDbStats {
+            hits: state.hits,
+            misses: state.misses,
+            entries: state.records.len(),
+        }
+    }
+
+    /// Save every change so far
+    pub fn flush(&self) -> Result<(), ChecksumDbError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut state = self.state();
+        if !state.dirty {
+            return Ok(());
+        }
+        let mut records: Vec<_> = state.records.values().cloned().collect();
+        records.sort_by(|a, b| a.path.cmp(&b.path));
+        let file = DbFile {
+            version: FORMAT_VERSION,
+            records,
+        };
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&file)?)?;
+        std::fs::rename(&tmp, path)?;
+        state.dirty = false;
+        Ok(())
+    }
+}
+
+/// Storage whose `checksum` is answered from a `ChecksumDb` for files that have not
+/// changed since they were last hashed, and recorded in it otherwise, so anything
+/// that verifies through it, such as `Manifest::verify`, only reads changed files.
+/// A checksum is only recorded if the file looks the same after hashing as before,
+/// so one that changed meanwhile is hashed again next time. Writes and deletes
+/// through it forget what was recorded for the file.
+pub struct IncrementalChecksums<S: Storage + ?Sized = dyn Storage> {
+    inner: Arc<S>,
+    db: ChecksumDb,
+    force: bool,
+}
+
+impl<S: Storage + ?Sized> IncrementalChecksums<S> {
+    pub fn new(inner: Arc<S>, db: ChecksumDb) -> Self {
+        IncrementalChecksums {
+            inner,
+            db,
+            force: false,
+        }
+    }
+
+    /// Hash every file again, and record the fresh checksums
+    pub fn with_force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    pub fn db(&self) -> &ChecksumDb {
+        &self.db
+    }
+
+    pub fn inner(&self) -> &Arc<S> {
+        &self.inner
+    }
+}
+
+#[async_trait]
+impl<S: Storage + ?Sized> Storage for IncrementalChecksums<S> {
+    fn backend(&self) -> StorageBackend {
+        self.inner.backend()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.inner.capabilities()
+    }
+
+    async fn stat_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<EntryMetadata, StorageError> {
+        self.inner.stat_opts(path, opts).await
+    }
+
+    async fn stat_many_opts(
+        &self,
+        paths: &[UniversalPath],
+        opts: &CallOptions,
+    ) -> Vec<Result<EntryMetadata, StorageError>> {
+        self.inner.stat_many_opts(paths, opts).await
+    }
+
+    async fn read_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        self.inner.read_opts(path, opts).await
+    }
+
+    async fn read_range_opts(
+        &self,
+        path: &UniversalPath,
+        range: Range<u64>,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        self.inner.read_range_opts(path, range, opts).await
+    }
+
+    async fn list_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<UniversalPath>, StorageError> {
+        self.inner.list_opts(path, opts).await
+    }
+
+    async fn list_with_metadata_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<(UniversalPath, EntryMetadata)>, StorageError> {
+        self.inner.list_with_metadata_opts(path, opts).await
+    }
+
+    async fn list_stream_opts<'a>(
+        &'a self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<ListStream<'a>, StorageError> {
+        self.inner.list_stream_opts(path, opts).await
+    }
+
+    async fn glob_opts(
+        &self,
+        pattern: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<UniversalPath>, StorageError> {
+        self.inner.glob_opts(pattern, opts).await
+    }
+
+    async fn symlink_target_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Option<UniversalPath>, StorageError> {
+        self.inner.symlink_target_opts(path, opts).await
+    }
+
+    async fn read_suffix_opts(
+        &self,
+        path: &UniversalPath,
+        len: u64,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        self.inner.read_suffix_opts(path, len, opts).await
+    }
+
+    async fn read_stream_opts<'a>(
+        &'a self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<ByteStream<'a>, StorageError> {
+        self.inner.read_stream_opts(path, opts).await
+    }
+
+    async fn checksum_opts(
+        &self,
+        path: &UniversalPath,
+        algorithm: ChecksumAlgorithm,
+        opts: &CallOptions,
+    ) -> Result<Checksum, StorageError> {
+        let before = self.inner.stat_opts(path, opts).await?;
+        if before.kind != EntryKind::File {
+            return self.inner.checksum_opts(path, algorithm, opts).await;
+        }
+        let file = FileId::of(path).await;
+        if !self.force
+            && let Some(checksum) = self.db.lookup(&file, &before, algorithm)
+        {
+            return Ok(checksum);
+        }
+        self.db.state().misses += 1;
+        let checksum = self.inner.checksum_opts(path, algorithm, opts).await?;
+        let after = self.inner.stat_opts(path, opts).await?;
+        if (after.size_bytes, after.modified_at) == (before.size_bytes, before.modified_at) {
+            self.db.record(file, path, &after, checksum.clone());
+        }
+        Ok(checksum)
+    }
+
+    async fn write_opts(
+        &self,
+        path: &UniversalPath,
+        data: &[u8],
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        let result = self.inner.write_opts(path, data, opts).await;
+        self.db.forget(&FileId::of(path).await);
+        result
+    }
+
+    async fn write_stream_opts(
+        &self,
+        path: &UniversalPath,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        let result = self.inner.write_stream_opts(path, reader, opts).await;
+        self.db.forget(&FileId::of(path).await);
+        result
+    }
+
+    async fn create_dir_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.inner.create_dir_opts(path, opts).await
+    }
+
+    async fn delete_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        // Looked up first, as an inode cannot be once the file is gone
+        let file = FileId::of(path).await;
+        self.inner.delete_opts(path, opts).await?;
+        self.db.forget(&file);
+        Ok(())
+    }
+
+    async fn rename_opts(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        // A file kept by inode keeps its checksum; one kept by path is hashed again
+        let file = FileId::of(from).await;
+        let replaced = FileId::of(to).await;
+        self.inner.rename_opts(from, to, opts).await?;
+        if matches!(file, FileId::Path { .. }) {
+            self.db.forget(&file);
+        }
+        if replaced != file {
+            self.db.forget(&replaced);
+        }
+        Ok(())
+    }
+
+    async fn copy_opts(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        let replaced = FileId::of(to).await;
+        let copied = self.inner.copy_opts(from, to, opts).await;
+        self.db.forget(&replaced);
+        copied
+    }
+
+    async fn set_modified_opts(
+        &self,
+        path: &UniversalPath,
+        modified_at: SystemTime,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.inner.set_modified_opts(path, modified_at, opts).await
+    }
+
+    async fn attribute_opts(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+        opts: &CallOptions,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        self.inner.attribute_opts(path, name, opts).await
+    }
+
+    async fn set_attribute_opts(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+        value: &[u8],
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.inner.set_attribute_opts(path, name, value, opts).await
+    }
+
+    async fn acquire_lock_opts(
+        &self,
+        path: &UniversalPath,
+        ttl: Duration,
+        opts: &CallOptions,
+    ) -> Result<StorageLock, StorageError> {
+        self.inner.acquire_lock_opts(path, ttl, opts).await
+    }
+
+    async fn renew_lock_opts(
+        &self,
+        lock: &StorageLock,
+        ttl: Duration,
+        opts: &CallOptions,
+    ) -> Result<StorageLock, StorageError> {
+        self.inner.renew_lock_opts(lock, ttl, opts).await
+    }
+
+    async fn release_lock_opts(
+        &self,
+        lock: &StorageLock,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.inner.release_lock_opts(lock, opts).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::Manifest;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_incremental_checksums() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let db_path = dir.join("checksums.json");
+        let memory = Arc::new(MemoryStorage::new());
+        let root = UniversalPath::from_uri_str("mem://sums/").unwrap();
+        for name in ["01.flac", "02.flac", "03.flac"] {
+            memory
+                .write(&root.join(name), name.as_bytes())
+                .await
+                .unwrap();
+        }
+
+        let db = ChecksumDb::open(&db_path).unwrap();
+        let storage = IncrementalChecksums::new(memory.clone(), db.clone());
+        let manifest = Manifest::build(&storage, &root, ChecksumAlgorithm::Sha256)
+            .await
+            .unwrap();
+        assert_eq!((db.stats().hits, db.stats().misses, db.len()), (0, 3, 3));
+        db.flush().unwrap();
+
+        // The next run only hashes the file that changed
+        let db = ChecksumDb::open(&db_path).unwrap();
+        let storage = IncrementalChecksums::new(memory.clone(), db.clone());
+        memory
+            .write(&root.join("02.flac"), b"bit rotted")
+            .await
+            .unwrap();
+        let check = manifest.verify(&storage, &root).await;
+        assert_eq!(check.files_verified, 2);
+        assert_eq!(check.differences[0].path, "02.flac");
+        assert_eq!((db.stats().hits, db.stats().misses), (2, 1));
+
+        // Forced, everything is hashed again
+        let storage = storage.with_force(true);
+        manifest.verify(&storage, &root).await;
+        assert_eq!(db.stats().misses, 4);
+
+        // Deleting through the storage forgets the file
+        storage.delete(&root.join("03.flac")).await.unwrap();
+        assert_eq!(db.len(), 2);
+    }
+}
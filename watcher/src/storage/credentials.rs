@@ -0,0 +1,399 @@
+use crate::universal_path::UniversalPath;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, RwLock},
+};
+use thiserror::Error;
+
+/// Host that matches every host of a scheme in a `CredentialStore`.
+pub const ANY_HOST: &str = "*";
+
+/// A way to log in to one server, independent of the backend that uses it.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Credentials {
+    /// Log in as nobody in particular: anonymous FTP, unsigned S3 requests
+    Anonymous,
+    Password {
+        username: String,
+        password: String,
+    },
+    /// An SSH private key
+    KeyFile {
+        username: String,
+        path: PathBuf,
+        #[serde(default)]
+        passphrase: Option<String>,
+    },
+    /// Identities offered by the SSH agent at `SSH_AUTH_SOCK`
+    Agent {
+        username: String,
+    },
+    AccessKey {
+        access_key_id: String,
+        secret_access_key: String,
+        #[serde(default)]
+        session_token: Option<String>,
+    },
+    /// A named profile in the AWS shared credentials file, read when used
+    AwsProfile {
+        name: String,
+    },
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credentials::Anonymous => f.write_str("Anonymous"),
+            Credentials::Password { username, .. } => f
+                .debug_struct("Password")
+                .field("username", username)
+                .field("password", &"<redacted>")
+                .finish(),
+            Credentials::KeyFile {
+                username,
+                path,
+                passphrase,
+            } => f
+                .debug_struct("KeyFile")
+                .field("username", username)
+                .field("path", path)
+                .field("passphrase", &passphrase.as_ref().map(|_| "<redacted>"))
+                .finish(),
+            Credentials::Agent { username } => {
+                f.debug_struct("Agent").field("username", username).finish()
+            }
+            Credentials::AccessKey {
+                access_key_id,
+                session_token,
+                ..
+            } => f
+                .debug_struct("AccessKey")
+                .field("access_key_id", access_key_id)
+                .field("secret_access_key", &"<redacted>")
+                .field(
+                    "session_token",
+                    &session_token.as_ref().map(|_| "<redacted>"),
+                )
+                .finish(),
+            Credentials::AwsProfile { name } => {
+                f.debug_struct("AwsProfile").field("name", name).finish()
+            }
+        }
+    }
+}
+
+impl Credentials {
+    pub fn password<U: Into<String>, P: Into<String>>(username: U, password: P) -> Self {
+        Credentials::Password {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    pub fn access_key<A: Into<String>, S: Into<String>>(access_key_id: A, secret: S) -> Self {
+        Credentials::AccessKey {
+            access_key_id: access_key_id.into(),
+            secret_access_key: secret.into(),
+            session_token: None,
+        }
+    }
+
+    /// An `AwsProfile` read out of the shared credentials file as an `AccessKey`; other
+    /// credentials as they are
+    pub fn resolve(&self) -> Result<Credentials, CredentialError> {
+        let Credentials::AwsProfile { name } = self else {
+            return Ok(self.clone());
+        };
+        let file = std::env::var_os("AWS_SHARED_CREDENTIALS_FILE")
+            .map(PathBuf::from)
+            .or_else(|| home().map(|h| h.join(".aws").join("credentials")))
+            .ok_or_else(|| CredentialError::Profile(name.clone()))?;
+        let text = std::fs::read_to_string(&file).map_err(|e| CredentialError::Read(file, e))?;
+        aws_profile(&text, name).ok_or_else(|| CredentialError::Profile(name.clone()))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CredentialError {
+    #[error("could not read {0}: {1}")]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("invalid credentials file {0}: {1}")]
+    Parse(PathBuf, String),
+    #[error("no access key for AWS profile {0:?}")]
+    Profile(String),
+}
+
+/// Looks up how to log in to the server a path is on.
+pub trait CredentialProvider: Send + Sync {
+    fn credentials_for(&self, path: &UniversalPath) -> Option<Credentials>;
+}
+
+/// Credentials registered by scheme and host, in code or from a JSON file of
+/// `{"scheme", "host", "credentials"}` entries. A host of `user@host` is only used
+/// for paths naming that user, and a host of `*` covers every host of the scheme that
+/// has no entry of its own.
+#[derive(Debug, Clone, Default)]
+pub struct CredentialStore {
+    entries: HashMap<(String, String), Credentials>,
+}
+
+#[derive(Deserialize)]
+struct StoreEntry {
+    scheme: String,
+    host: String,
+    credentials: Credentials,
+}
+
+impl CredentialStore {
+    pub fn new() -> Self {
+        CredentialStore::default()
+    }
+
+    pub fn insert<S: AsRef<str>, H: AsRef<str>>(
+        &mut self,
+        scheme: S,
+        host: H,
+        credentials: Credentials,
+    ) {
+        let key = (scheme.as_ref().to_lowercase(), host.as_ref().to_lowercase());
+        self.entries.insert(key, credentials);
+    }
+
+    pub fn with<S: AsRef<str>, H: AsRef<str>>(
+        mut self,
+        scheme: S,
+        host: H,
+        credentials: Credentials,
+    ) -> Self {
+        self.insert(scheme, host, credentials);
+        self
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        let entries: Vec<StoreEntry> = serde_json::from_str(json)?;
+        let mut store = CredentialStore::new();
+        for entry in entries {
+            store.insert(entry.scheme, entry.host, entry.credentials);
+        }
+        Ok(store)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, CredentialError> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path)
+            .map_err(|e| CredentialError::Read(path.to_path_buf(), e))?;
+        Self::from_json(&json)
+            .map_err(|e| CredentialError::Parse(path.to_path_buf(), e.to_string()))
+    }
+
+    /// `$OTOLITH_CREDENTIALS`, or else `credentials.json` in `$XDG_CONFIG_HOME/otolith`
+    /// or `~/.config/otolith`
+    pub fn default_path() -> Option<PathBuf> {
+        if let Some(path) = std::env::var_os("OTOLITH_CREDENTIALS") {
+            return Some(PathBuf::from(path));
+        }
+        Some(config_dir()?.join("credentials.json"))
+    }
+}
+
+impl CredentialProvider for CredentialStore {
+    fn credentials_for(&self, path: &UniversalPath) -> Option<Credentials> {
+        let scheme = path.backend().to_scheme().to_string();
+        let host = path.host().unwrap_or_default().to_lowercase();
+        let account = path.user().and_then(|user| {
+            self.entries
+                .get(&(scheme.clone(), format!("{user}@{host}").to_lowercase()))
+        });
+        account
+            .or_else(|| self.entries.get(&(scheme.clone(), host)))
+            .or_else(|| self.entries.get(&(scheme, ANY_HOST.to_string())))
+            .cloned()
+    }
+}
+
+/// Credentials from the environment, for any host of a scheme:
+///
+/// - `OTOLITH_<SCHEME>_USER` with `OTOLITH_<SCHEME>_PASSWORD`, or with
+///   `OTOLITH_<SCHEME>_KEY` for a key file, e.g. `OTOLITH_SFTP_KEY`
+/// - for S3, `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`,
+///   or else `AWS_PROFILE`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EnvCredentials;
+
+impl CredentialProvider for EnvCredentials {
+    fn credentials_for(&self, path: &UniversalPath) -> Option<Credentials> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        let scheme = path
+            .backend()
+            .to_scheme()
+            .to_uppercase()
+            .replace(['+', '-', '.'], "_");
+        if scheme == "S3" {
+            if let (Some(access_key_id), Some(secret_access_key)) =
+                (var("AWS_ACCESS_KEY_ID"), var("AWS_SECRET_ACCESS_KEY"))
+            {
+                return Some(Credentials::AccessKey {
+                    access_key_id,
+                    secret_access_key,
+                    session_token: var("AWS_SESSION_TOKEN"),
+                });
+            }
+            return var("AWS_PROFILE").map(|name| Credentials::AwsProfile { name });
+        }
+        let username = var(&format!("OTOLITH_{scheme}_USER"))?;
+        if let Some(password) = var(&format!("OTOLITH_{scheme}_PASSWORD")) {
+            return Some(Credentials::Password { username, password });
+        }
+        var(&format!("OTOLITH_{scheme}_KEY")).map(|key| Credentials::KeyFile {
+            username,
+            path: PathBuf::from(key),
+            passphrase: var(&format!("OTOLITH_{scheme}_PASSPHRASE")),
+        })
+    }
+}
+
+/// Several providers asked in order, the first answer winning.
+#[derive(Clone, Default)]
+pub struct CredentialChain {
+    providers: Vec<Arc<dyn CredentialProvider>>,
+}
+
+impl CredentialChain {
+    pub fn new() -> Self {
+        CredentialChain::default()
+    }
+
+    pub fn with<P: CredentialProvider + 'static>(mut self, provider: P) -> Self {
+        self.providers.push(Arc::new(provider));
+        self
+    }
+
+    /// The environment, then the credentials file at `CredentialStore::default_path`
+    /// if there is one. A file that cannot be read or parsed is an error rather than
+    /// silently logging in as someone else.
+    pub fn from_environment() -> Result<Self, CredentialError> {
+        let mut chain = CredentialChain::new().with(EnvCredentials);
+        if let Some(path) = CredentialStore::default_path().filter(|p| p.is_file()) {
+            chain = chain.with(CredentialStore::from_file(path)?);
+        }
+        Ok(chain)
+    }
+}
+
+impl CredentialProvider for CredentialChain {
+    fn credentials_for(&self, path: &UniversalPath) -> Option<Credentials> {
+        self.providers.iter().find_map(|p| p.credentials_for(path))
+    }
+}
+
+static PROVIDER: LazyLock<RwLock<Option<Arc<dyn CredentialProvider>>>> =
+    LazyLock::new(Default::default);
+
+/// Replace the provider `open_storage_for` consults, which is
+/// `CredentialChain::from_environment()` until this is called.
+pub fn set_credential_provider(provider: Arc<dyn CredentialProvider>) {
+    *PROVIDER.write().unwrap() = Some(provider);
+}
+
+/// The provider `open_storage_for` consults
+pub fn credential_provider() -> Result<Arc<dyn CredentialProvider>, CredentialError> {
+    if let Some(provider) = PROVIDER.read().unwrap().clone() {
+        return Ok(provider);
+    }
+    let provider: Arc<dyn CredentialProvider> = Arc::new(CredentialChain::from_environment()?);
+    Ok(PROVIDER.write().unwrap().get_or_insert(provider).clone())
+}
+
+/// `$XDG_CONFIG_HOME/otolith` or `~/.config/otolith`, where otolith keeps its own
+/// files
+pub(crate) fn config_dir() -> Option<PathBuf> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| home().map(|h| h.join(".config")))?;
+    Some(config.join("otolith"))
+}
+
+fn home() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+}
+
+/// The access key of `[name]` in an AWS shared credentials file
+fn aws_profile(text: &str, name: &str) -> Option<Credentials> {
+    let mut in_profile = false;
+    let mut values = HashMap::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with(['#', ';']) {
+            continue;
+        }
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            in_profile = section.trim() == name;
+            continue;
+        }
+        if in_profile && let Some((key, value)) = line.split_once('=') {
+            values.insert(key.trim().to_string(), value.trim().to_string());
+        }
+    }
+    Some(Credentials::AccessKey {
+        access_key_id: values.remove("aws_access_key_id")?,
+        secret_access_key: values.remove("aws_secret_access_key")?,
+        session_token: values.remove("aws_session_token"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_lookup_and_file_format() {
+        let store = CredentialStore::from_json(
+            r#"[
+                {"scheme": "sftp", "host": "NAS.local", "credentials":
+                    {"type": "key_file", "username": "music", "path": "/keys/nas"}},
+                {"scheme": "ftp", "host": "*", "credentials": {"type": "anonymous"}},
+                {"scheme": "ftp", "host": "seedbox", "credentials":
+                    {"type": "password", "username": "me", "password": "hunter2"}},
+                {"scheme": "ftp", "host": "guest@seedbox", "credentials":
+                    {"type": "password", "username": "guest", "password": "guest"}}
+            ]"#,
+        )
+        .unwrap();
+        let at = |uri: &str| store.credentials_for(&UniversalPath::from_uri_str(uri).unwrap());
+
+        assert_eq!(
+            at("sftp://nas.local/music"),
+            Some(Credentials::KeyFile {
+                username: "music".into(),
+                path: "/keys/nas".into(),
+                passphrase: None,
+            })
+        );
+        assert_eq!(at("sftp://other/music"), None);
+        assert_eq!(at("ftp://mirror/pub"), Some(Credentials::Anonymous));
+        let seedbox = at("ftp://seedbox/");
+        assert_eq!(seedbox, Some(Credentials::password("me", "hunter2")));
+        assert!(!format!("{seedbox:?}").contains("hunter2"));
+        assert_eq!(
+            at("ftp://guest@seedbox/"),
+            Some(Credentials::password("guest", "guest"))
+        );
+        assert_eq!(at("ftp://other@seedbox/"), seedbox);
+    }
+
+    #[test]
+    fn test_aws_profile() {
+        let file = "[default]\naws_access_key_id = A\naws_secret_access_key = B\n\n\
+                    [archive]\n# cold storage\naws_access_key_id=C\naws_secret_access_key=D\n";
+        assert_eq!(
+            aws_profile(file, "archive"),
+            Some(Credentials::access_key("C", "D"))
+        );
+        assert_eq!(aws_profile(file, "missing"), None);
+    }
+}
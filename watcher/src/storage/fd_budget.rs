@@ -0,0 +1,334 @@
+use super::StorageError;
+use crate::environment::{ResourceLimit, open_files_limit};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, LazyLock, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+use tokio::sync::oneshot;
+
+/// Descriptors kept out of the budget for everything else the process has open:
+/// the standard streams, the index and its log, sockets for status and webhooks
+const FD_RESERVE: usize = 64;
+
+/// The budget when the system will not say how many files may be open
+const FALLBACK_LIMIT: usize = 256;
+
+/// Calls that had to wait, as a share of those let through, past which the budget
+/// is what holds a scan up
+const BOTTLENECK_SHARE: f64 = 0.1;
+
+/// How a budget has been used so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct FdBudgetStats {
+    pub limit: usize,
+    pub in_use: usize,
+    /// Most in use at once
+    pub peak: usize,
+    pub waiting: usize,
+    /// Permits handed out
+    pub granted: u64,
+    /// Of those, how many had to wait for one
+    pub waited: u64,
+    /// Spent waiting, by every call together
+    pub wait_ms: u64,
+    /// Calls that failed for want of descriptors anyway, because something outside
+    /// the budget holds them
+    pub exhausted: u64,
+}
+
+/// A budget that has held work up, with what to do about it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FdDiagnostic {
+    pub stats: FdBudgetStats,
+}
+
+impl fmt::Display for FdDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stats = &self.stats;
+        if stats.exhausted > 0 {
+            return write!(
+                f,
+                "ran out of file descriptors {} times with {} of a budget of {} open; \
+                 raise the open files limit (ulimit -n) or lower max_open_files",
+                stats.exhausted, stats.peak, stats.limit
+            );
+        }
+        write!(
+            f,
+            "{} of {} calls waited {:.1}s in all for one of {} open files; raise the \
+             open files limit (ulimit -n) and max_open_files, or lower scan_concurrency",
+            stats.waited,
+            stats.granted,
+            stats.wait_ms as f64 / 1000.0,
+            stats.limit
+        )
+    }
+}
+
+/// A cap on the files and connections open at once across every scanner and
+/// snapshot sharing it, so many parallel scans of big local trees queue for
+/// descriptors instead of failing with `EMFILE`. Calls past the limit wait their
+/// turn, first come first served.
+///
+/// Cloning gives another handle on the same budget. Unless told otherwise, scans
+/// share `fd_budget()`, sized to the process's open files limit.
+#[derive(Clone)]
+pub struct FdBudget {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    stats: FdBudgetStats,
+    wait: Duration,
+    waiting: VecDeque<oneshot::Sender<FdPermit>>,
+}
+
+/// A descriptor the holder may use, given back to its budget when dropped.
+pub struct FdPermit {
+    budget: FdBudget,
+}
+
+impl FdBudget {
+    /// At most `limit` open at once
+    pub fn new(limit: usize) -> Self {
+        let stats = FdBudgetStats {
+            limit: limit.max(1),
+            ..FdBudgetStats::default()
+        };
+        FdBudget {
+            state: Arc::new(Mutex::new(State {
+                stats,
+                ..State::default()
+            })),
+        }
+    }
+
+    /// The process's soft open files limit, less what the rest of the process needs
+    pub fn from_system() -> Self {
+        let limit = match open_files_limit() {
+            Some(ResourceLimit {
+                soft: Some(soft), ..
+            }) => {
+                let soft = soft as usize;
+                soft.saturating_sub(FD_RESERVE).max(soft / 2)
+            }
+            Some(ResourceLimit { soft: None, .. }) => usize::MAX,
+            None => FALLBACK_LIMIT,
+        };
+        FdBudget::new(limit)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.lock().stats.limit
+    }
+
+    /// Change the limit. Lowering it takes effect as permits are given back; raising
+    /// it lets waiting calls through at once.
+    pub fn set_limit(&self, limit: usize) {
+        let unclaimed = {
+            let mut state = self.lock();
+            state.stats.limit = limit.max(1);
+            self.hand_out(&mut state)
+        };
+        drop(unclaimed);
+    }
+
+    /// A permit, once one is free
+    pub async fn acquire(&self) -> FdPermit {
+        let rx = {
+            let mut state = self.lock();
+            if let Some(permit) = self.try_take(&mut state) {
+                return permit;
+            }
+            let (tx, rx) = oneshot::channel();
+            state.waiting.push_back(tx);
+            rx
+        };
+        let started = Instant::now();
+        let permit = rx.await.expect("the budget outlives its waiters");
+        let mut state = self.lock();
+        state.stats.waited += 1;
+        state.wait += started.elapsed();
+        permit
+    }
+
+    /// A permit if one is free now and nobody is waiting for one
+    pub fn try_acquire(&self) -> Option<FdPermit> {
+        self.try_take(&mut self.lock())
+    }
+
+    /// Note `error` if it comes of running out of descriptors, returning whether it
+    /// did
+    pub fn record(&self, error: &StorageError) -> bool {
+        let exhausted = error.is_fd_exhausted();
+        if exhausted {
+            self.lock().stats.exhausted += 1;
+        }
+        exhausted
+    }
+
+    pub fn stats(&self) -> FdBudgetStats {
+        let state = self.lock();
+        FdBudgetStats {
+            waiting: state.waiting.iter().filter(|tx| !tx.is_closed()).count(),
+            wait_ms: state.wait.as_millis() as u64,
+            ..state.stats
+        }
+    }
+
+    /// What went wrong, if the budget has been what holds work up, or descriptors
+    /// ran out despite it
+    pub fn diagnostic(&self) -> Option<FdDiagnostic> {
+        let stats = self.stats();
+        let held_up =
+            stats.waited > 0 && stats.waited as f64 >= stats.granted as f64 * BOTTLENECK_SHARE;
+        (held_up || stats.exhausted > 0).then_some(FdDiagnostic { stats })
+    }
+
+    fn try_take(&self, state: &mut State) -> Option<FdPermit> {
+        let stats = &mut state.stats;
+        if stats.in_use >= stats.limit || state.waiting.iter().any(|tx| !tx.is_closed()) {
+            return None;
+        }
+        stats.in_use += 1;
+        stats.peak = stats.peak.max(stats.in_use);
+        stats.granted += 1;
+        Some(FdPermit {
+            budget: self.clone(),
+        })
+    }
+
+    /// Let waiting calls through while the limit allows, returning the permits of those
+    /// that went away, to be given back once the lock is let go
+    fn hand_out(&self, state: &mut State) -> Vec<FdPermit> {
+        let mut unclaimed = Vec::new();
+        while state.stats.in_use < state.stats.limit {
+            let Some(tx) = state.waiting.pop_front() else {
+                break;
+            };
+            if tx.is_closed() {
+                continue;
+            }
+            let stats = &mut state.stats;
+            stats.in_use += 1;
+            stats.peak = stats.peak.max(stats.in_use);
+            stats.granted += 1;
+            let permit = FdPermit {
+                budget: self.clone(),
+            };
+            if let Err(permit) = tx.send(permit) {
+                unclaimed.push(permit);
+            }
+        }
+        unclaimed
+    }
+
+    fn release(&self) {
+        let unclaimed = {
+            let mut state = self.lock();
+            state.stats.in_use -= 1;
+            self.hand_out(&mut state)
+        };
+        drop(unclaimed);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl fmt::Debug for FdBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FdBudget")
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl Drop for FdPermit {
+    fn drop(&mut self) {
+        self.budget.release();
+    }
+}
+
+static FD_BUDGET: LazyLock<FdBudget> = LazyLock::new(FdBudget::from_system);
+
+/// The budget scanners and snapshots share unless given one of their own
+pub fn fd_budget() -> &'static FdBudget {
+    &FD_BUDGET
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryStorage, Scanner, Storage};
+    use crate::universal_path::UniversalPath;
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn test_fd_budget() {
+        let storage = Arc::new(MemoryStorage::new());
+        let root = UniversalPath::from_uri_str("mem://fds/music/").unwrap();
+        storage.create_dir(&root).await.unwrap();
+        for track in 0..20 {
+            storage
+                .write(&root.join(format!("{track:02}.flac")), b"flac")
+                .await
+                .unwrap();
+        }
+
+        // Two scans wait while every descriptor is taken, and finish once they are free
+        let budget = FdBudget::new(2);
+        let held: Vec<_> = (0..2).map(|_| budget.try_acquire().unwrap()).collect();
+        let scanner = Scanner::new(storage.clone())
+            .with_concurrency(8)
+            .with_fd_budget(budget.clone());
+        let scans = [scanner.scan(&root), scanner.scan(&root)]
+            .map(|scan| tokio::spawn(scan.collect::<Vec<_>>()));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(budget.stats().waiting, 2);
+        drop(held);
+        for scan in scans {
+            assert_eq!(scan.await.unwrap().len(), 20);
+        }
+        let stats = budget.stats();
+        assert_eq!((stats.in_use, stats.peak, stats.waiting), (0, 2, 0));
+        assert!(stats.waited >= 2, "{stats:?}");
+        // A few waits among many calls hold nothing up
+        assert_eq!(budget.diagnostic(), None);
+
+        // Raising the limit lets a waiting call straight through
+        let budget = FdBudget::new(1);
+        let held = budget.try_acquire().unwrap();
+        let waiting = tokio::spawn({
+            let budget = budget.clone();
+            async move { budget.acquire().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(budget.try_acquire().is_none());
+        budget.set_limit(2);
+        let permit = waiting.await.unwrap();
+        drop((held, permit));
+        assert_eq!(budget.stats().in_use, 0);
+        let diagnostic = budget.diagnostic().expect("half the calls waited");
+        assert!(diagnostic.to_string().starts_with("1 of 2 calls waited"));
+
+        // Running out anyway is told apart from other errors
+        #[cfg(unix)]
+        {
+            let emfile = StorageError::Io(std::io::Error::from_raw_os_error(libc::EMFILE));
+            assert!(budget.record(&emfile));
+            assert!(!budget.record(&StorageError::NotFound));
+            let diagnostic = budget.diagnostic().unwrap();
+            assert!(
+                diagnostic
+                    .to_string()
+                    .starts_with("ran out of file descriptors 1 times")
+            );
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use super::{EntryKind, glob::matches_segments};
+use crate::root::AUDIO_EXTENSIONS;
+use crate::universal_path::UniversalPath;
+use crate::watch::{WatchEvent, WatchStream};
+use futures::{StreamExt, future};
+use serde::{Deserialize, Serialize};
+
+/// Which entries under a root walking, scanning, globbing and watching see, so every
+/// way of finding files skips the same ones.
+///
+/// Patterns use the syntax of `Storage::glob`. One without a `/` is matched against
+/// each name on the way down, so `.git` or `*.part` skips any entry of that name
+/// wherever it is; one with a `/` is matched against the path from the root, so
+/// `Podcasts/**` skips that tree only. An excluded or hidden directory is not
+/// descended into. Include patterns, extensions and the size limit apply to files
+/// only: when any are given, a file must pass all of them.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FilterSet {
+    /// A file must match one of these, if any are given
+    pub include: Vec<String>,
+    /// Entries matching any of these are left out, with everything below them
+    pub exclude: Vec<String>,
+    /// Lowercase extensions, without the dot, a file must have one of; empty for any
+    pub extensions: Vec<String>,
+    pub max_size_bytes: Option<u64>,
+    /// Leave out entries whose name starts with `.`
+    pub skip_hidden: bool,
+}
+
+impl FilterSet {
+    /// Everything, skipping nothing
+    pub fn new() -> Self {
+        FilterSet::default()
+    }
+
+    /// Audio files only, leaving out hidden files and the folders NAS boxes and
+    /// desktops keep their own data in
+    pub fn audio() -> Self {
+        FilterSet::new()
+            .with_extensions(AUDIO_EXTENSIONS.iter().copied())
+            .with_exclude(["@eaDir", "#recycle", "#snapshot", "$RECYCLE.BIN"])
+            .with_exclude(["System Volume Information", "lost+found"])
+            .with_skip_hidden(true)
+    }
+
+    pub fn with_include<S: Into<String>>(mut self, patterns: impl IntoIterator<Item = S>) -> Self {
+        self.include.extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn with_exclude<S: Into<String>>(mut self, patterns: impl IntoIterator<Item = S>) -> Self {
+        self.exclude.extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn with_extensions<S: AsRef<str>>(
+        mut self,
+        extensions: impl IntoIterator<Item = S>,
+    ) -> Self {
+        let extensions = extensions.into_iter();
+        let lower = extensions.map(|ext| ext.as_ref().trim_start_matches('.').to_lowercase());
+        self.extensions.extend(lower);
+        self
+    }
+
+    pub fn with_max_size(mut self, bytes: u64) -> Self {
+        self.max_size_bytes = Some(bytes);
+        self
+    }
+
+    pub fn with_skip_hidden(mut self, skip: bool) -> Self {
+        self.skip_hidden = skip;
+        self
+    }
+
+    /// Whether the entry at `rel`, a path from the root, is left out by its own name
+    /// or path, whatever it is. Only the last segment is checked; walkers check each
+    /// directory on the way down.
+    pub fn excludes(&self, rel: &[String]) -> bool {
+        let Some(name) = rel.last() else {
+            return false;
+        };
+        if self.skip_hidden && name.starts_with('.') {
+            return true;
+        }
+        self.exclude
+            .iter()
+            .any(|pattern| match pattern.contains('/') {
+                true => matches_segments(pattern, rel),
+                false => matches_segments(pattern, std::slice::from_ref(name)),
+            })
+    }
+
+    /// Whether a file at `rel` of `size_bytes`, if known, passes the rules for files
+    pub fn accepts_file(&self, rel: &[String], size_bytes: Option<u64>) -> bool {
+        let Some(name) = rel.last() else {
+            return false;
+        };
+        let extension = UniversalPath::split_name(name).1;
+        let extension_ok = self.extensions.is_empty()
+            || extension
+                .is_some_and(|ext| self.extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)));
+        let size_ok = match (self.max_size_bytes, size_bytes) {
+            (Some(max), Some(size)) => size <= max,
+            _ => true,
+        };
+        let included = self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|pattern| match pattern.contains('/') {
+                    true => matches_segments(pattern, rel),
+                    false => matches_segments(pattern, std::slice::from_ref(name)),
+                });
+        extension_ok && size_ok && included
+    }
+
+    /// Whether an entry of `kind` at `rel`, with nothing above it checked yet, is seen
+    pub fn allows(&self, rel: &[String], kind: &EntryKind, size_bytes: Option<u64>) -> bool {
+        let excluded = (1..=rel.len()).any(|len| self.excludes(&rel[..len]));
+        !excluded && (*kind == EntryKind::Directory || self.accepts_file(rel, size_bytes))
+    }
+
+    /// Whether `path` under `root` is seen, going by its directory hint for its kind and
+    /// leaving the size limit to those who know the size. Paths outside `root` are not.
+    pub fn allows_path(&self, root: &UniversalPath, path: &UniversalPath) -> bool {
+        let Some(rel) = path.relative_to(root) else {
+            return false;
+        };
+        let kind = match path.is_dir_hint() {
+            true => EntryKind::Directory,
+            false => EntryKind::File,
+        };
+        self.allows(&rel, &kind, None)
+    }
+
+    /// `events` from a watcher on `root`, without those about entries the filter
+    /// leaves out. A rename out of what is seen is a deletion, and one into it a
+    /// creation. `Rescan`s of the root itself and errors always pass.
+    pub fn filter_events(&self, root: &UniversalPath, events: WatchStream) -> WatchStream {
+        let (filter, root) = (self.clone(), root.as_dir());
+        let seen = move |path: &UniversalPath| path == &root || filter.allows_path(&root, path);
+        Box::pin(events.filter_map(move |event| {
+            let event = match event {
+                Ok(WatchEvent::Renamed { from, to }) => match (seen(&from), seen(&to)) {
+                    (true, true) => Some(WatchEvent::Renamed { from, to }),
+                    (true, false) => Some(WatchEvent::Deleted(from)),
+                    (false, true) => Some(WatchEvent::Created(to)),
+                    (false, false) => None,
+                },
+                Ok(event) => seen(event.path()).then_some(event),
+                Err(e) => return future::ready(Some(Err(e))),
+            };
+            future::ready(event.map(Ok))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryStorage, Scanner, Storage, StorageExt, WalkOptions};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_filter_set() {
+        let storage = Arc::new(MemoryStorage::new());
+        let root = UniversalPath::from_uri_str("mem://nas/music/").unwrap();
+        for dir in ["Album", "Album/@eaDir", ".git", "Podcasts"] {
+            let dir = root.join_all(&dir.split('/').collect::<Vec<_>>()).as_dir();
+            storage.create_dir(&dir).await.unwrap();
+        }
+        for (file, size) in [
+            ("Album/01.flac", 10),
+            ("Album/02.FLAC", 10),
+            ("Album/cover.jpg", 10),
+            ("Album/.01.flac.part", 10),
+            ("Album/@eaDir/01.flac", 10),
+            ("Album/huge.wav", 1000),
+            (".git/HEAD.mp3", 10),
+            ("Podcasts/01.mp3", 10),
+        ] {
+            let path = root.join_all(&file.split('/').collect::<Vec<_>>());
+            storage.write(&path, &vec![0; size]).await.unwrap();
+        }
+        let filter = FilterSet::audio()
+            .with_exclude(["Podcasts/**"])
+            .with_max_size(100);
+        let rel = |path: &UniversalPath| path.relative_to(&root).unwrap().join("/");
+        let expected = ["Album", "Album/01.flac", "Album/02.FLAC"];
+
+        let options = WalkOptions::default().with_filter(filter.clone());
+        let walked: Vec<_> = storage.walk_with(&root, options).collect().await;
+        let mut walked: Vec<_> = walked.iter().map(|e| rel(&e.as_ref().unwrap().0)).collect();
+        walked.sort();
+        assert_eq!(walked, expected);
+
+        let scan = Scanner::new(storage.clone()).with_filter(filter.clone());
+        let scanned: Vec<_> = scan.scan(&root).collect().await;
+        let mut scanned: Vec<_> = scanned
+            .iter()
+            .map(|e| rel(&e.as_ref().unwrap().0))
+            .collect();
+        scanned.sort();
+        assert_eq!(scanned, expected);
+
+        let pattern = root.join_all(&["**", "*"]);
+        let globbed = storage.glob_filtered(&pattern, &filter).await.unwrap();
+        let globbed: Vec<_> = globbed.iter().map(rel).collect();
+        assert_eq!(globbed, expected);
+
+        let (tx, raw) = crate::watch::channel();
+        let mut events = filter.filter_events(&root, raw);
+        let file = |path: &str| root.join_all(&path.split('/').collect::<Vec<_>>());
+        for event in [
+            WatchEvent::Created(file(".git/objects/ab")),
+            WatchEvent::Modified(file("Album/@eaDir/01.flac")),
+            WatchEvent::Renamed {
+                from: file("Album/.03.flac.part"),
+                to: file("Album/03.flac"),
+            },
+            WatchEvent::Deleted(file("Album/cover.jpg")),
+            WatchEvent::Rescan(root.clone()),
+        ] {
+            tx.send(Ok(event)).unwrap();
+        }
+        drop(tx);
+        let events: Vec<_> = events.by_ref().map(Result::unwrap).collect().await;
+        assert_eq!(
+            events,
+            [
+                WatchEvent::Created(file("Album/03.flac")),
+                WatchEvent::Rescan(root.clone()),
+            ]
+        );
+    }
+}
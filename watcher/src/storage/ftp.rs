@@ -0,0 +1,638 @@
+use super::{
+    CallOptions, Credentials, EntryKind, EntryMetadata, EntryPermissions, Storage, StorageBackend,
+    StorageCapabilities, StorageError, clamp_range,
+};
+use crate::universal_path::UniversalPath;
+use async_trait::async_trait;
+use chrono::{TimeZone, Utc};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, UNIX_EPOCH},
+};
+use suppaftp::{
+    FtpError, Mode, Status,
+    list::{File as ListedFile, ListParser, PosixPexQuery},
+    tokio::AsyncFtpStream,
+};
+use tokio::{
+    io::AsyncReadExt,
+    sync::{Mutex, MutexGuard},
+    time::error::Elapsed,
+};
+
+pub const DEFAULT_FTP_PORT: u16 = 21;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    /// Passive (the default) has the client open data connections, which works through
+    /// NAT; active has the server connect back.
+    pub passive: bool,
+    /// Limit on connecting and logging in
+    pub connect_timeout: Duration,
+    /// Limit on each individual command, including transfers
+    pub operation_timeout: Duration,
+}
+
+impl FtpConfig {
+    /// Passive mode, anonymous login and 30 second timeouts
+    pub fn new<H: Into<String>>(host: H) -> Self {
+        FtpConfig {
+            host: host.into(),
+            port: DEFAULT_FTP_PORT,
+            username: "anonymous".to_string(),
+            password: "anonymous@".to_string(),
+            passive: true,
+            connect_timeout: Duration::from_secs(30),
+            operation_timeout: Duration::from_secs(30),
+        }
+    }
+
+    pub fn with_login<U: Into<String>, P: Into<String>>(
+        mut self,
+        username: U,
+        password: P,
+    ) -> Self {
+        self.username = username.into();
+        self.password = password.into();
+        self
+    }
+
+    pub fn for_path(path: &UniversalPath) -> Result<Self, StorageError> {
+        if path.backend() != &StorageBackend::Ftp {
+            return Err(StorageError::InvalidPath);
+        }
+        let mut config = FtpConfig::new(path.host().ok_or(StorageError::InvalidPath)?);
+        config.port = path.port().unwrap_or(DEFAULT_FTP_PORT);
+        if let Some(user) = path.user() {
+            config.username = user.to_string();
+            config.password = path.password().unwrap_or_default().to_string();
+        }
+        Ok(config)
+    }
+
+    /// Log in with `credentials` rather than anonymously
+    pub fn apply_credentials(&mut self, credentials: &Credentials) -> Result<(), StorageError> {
+        match credentials {
+            Credentials::Anonymous => {
+                let anonymous = FtpConfig::new("");
+                self.username = anonymous.username;
+                self.password = anonymous.password;
+            }
+            Credentials::Password { username, password } => {
+                self.username = username.clone();
+                self.password = password.clone();
+            }
+            _ => {
+                return Err(StorageError::Connection(format!(
+                    "FTP needs a password or anonymous login, not {credentials:?}"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+type PoolKey = (String, u16, String);
+
+pub(crate) const CAPABILITIES: StorageCapabilities = StorageCapabilities {
+    can_stat: true,
+    can_read: true,
+    can_read_range: true,
+    can_list: true,
+    can_glob: true,
+    can_write: false,
+    can_delete: false,
+    can_rename: false,
+    can_checksum: false,
+};
+
+/// Pool of storages by host, port and user, so repeated `open_storage_for` calls share
+/// one control connection.
+static SHARED: LazyLock<std::sync::Mutex<HashMap<PoolKey, FtpStorage>>> =
+    LazyLock::new(Default::default);
+
+/// Storage over plain FTP for a single server.
+///
+/// FTP runs one command at a time per control connection, so calls are serialized on
+/// a single reused connection. Failures other than a server refusal drop it, and the
+/// next call reconnects.
+#[derive(Clone)]
+pub struct FtpStorage {
+    config: Arc<FtpConfig>,
+    connection: Arc<Mutex<Option<AsyncFtpStream>>>,
+    /// Cleared once the server rejects MLSD, so later listings go straight to LIST
+    mlsd_supported: Arc<AtomicBool>,
+}
+
+type Connection<'a> = MutexGuard<'a, Option<AsyncFtpStream>>;
+
+impl FtpStorage {
+    pub fn new(config: FtpConfig) -> Self {
+        FtpStorage {
+            config: Arc::new(config),
+            connection: Arc::new(Mutex::new(None)),
+            mlsd_supported: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    /// A storage sharing its connection with every other `shared` call for the same
+    /// server and user.
+    pub fn shared(config: FtpConfig) -> Self {
+        let key = (config.host.clone(), config.port, config.username.clone());
+        let mut pool = SHARED.lock().unwrap_or_else(|e| e.into_inner());
+        pool.entry(key)
+            .or_insert_with(|| FtpStorage::new(config))
+            .clone()
+    }
+
+    pub fn config(&self) -> &FtpConfig {
+        &self.config
+    }
+
+    /// The server-side path for `upath`, which must be on this storage's host and port
+    fn remote_path(&self, upath: &UniversalPath) -> Result<String, StorageError> {
+        let same_host = upath
+            .host()
+            .is_some_and(|h| h.eq_ignore_ascii_case(&self.config.host));
+        let same_port = upath.port().unwrap_or(DEFAULT_FTP_PORT) == self.config.port;
+        if upath.backend() != &StorageBackend::Ftp || !same_host || !same_port {
+            return Err(StorageError::InvalidPath);
+        }
+        Ok(upath.path())
+    }
+
+    async fn connection(&self) -> Result<Connection<'_>, StorageError> {
+        let mut slot = self.connection.lock().await;
+        if slot.is_none() {
+            let connect = self.connect();
+            let ftp = tokio::time::timeout(self.config.connect_timeout, connect)
+                .await
+                .map_err(|_| StorageError::Timeout(self.config.connect_timeout))??;
+            *slot = Some(ftp);
+        }
+        Ok(slot)
+    }
+
+    async fn connect(&self) -> Result<AsyncFtpStream, StorageError> {
+        let config = &self.config;
+        let mut ftp = AsyncFtpStream::connect((config.host.as_str(), config.port))
+            .await
+            .map_err(map_ftp_error)?;
+        if !config.passive {
+            ftp = ftp.active_mode(config.operation_timeout);
+        } else {
+            ftp.set_mode(Mode::Passive);
+        }
+        ftp.login(config.username.as_str(), config.password.as_str())
+            .await
+            .map_err(|e| match e {
+                FtpError::UnexpectedResponse(_) => StorageError::Connection(format!(
+                    "FTP login failed for {}@{}",
+                    config.username, config.host
+                )),
+                other => map_ftp_error(other),
+            })?;
+        ftp.transfer_type(suppaftp::types::FileType::Binary)
+            .await
+            .map_err(map_ftp_error)?;
+        Ok(ftp)
+    }
+
+    async fn timed<T, Fut>(&self, op: Fut) -> Result<Result<T, FtpError>, Elapsed>
+    where
+        Fut: Future<Output = Result<T, FtpError>>,
+    {
+        tokio::time::timeout(self.config.operation_timeout, op).await
+    }
+
+    /// Translate the outcome of a timed command, dropping the connection if it failed
+    /// in a way that leaves the control channel in an unknown state.
+    fn settle<T>(
+        &self,
+        conn: &mut Connection<'_>,
+        outcome: Result<Result<T, FtpError>, Elapsed>,
+    ) -> Result<T, StorageError> {
+        match outcome {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(e)) => {
+                if !matches!(e, FtpError::UnexpectedResponse(_)) {
+                    **conn = None;
+                }
+                Err(map_ftp_error(e))
+            }
+            Err(_) => {
+                **conn = None;
+                Err(StorageError::Timeout(self.config.operation_timeout))
+            }
+        }
+    }
+
+    async fn is_dir(&self, conn: &mut Connection<'_>, remote: &str) -> Result<bool, StorageError> {
+        let ftp = conn.as_mut().expect("connected");
+        let outcome = self.timed(ftp.cwd(remote)).await;
+        match self.settle(conn, outcome) {
+            Ok(()) => Ok(true),
+            Err(StorageError::NotFound) | Err(StorageError::Io(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn stat_inner(&self, path: &UniversalPath) -> Result<EntryMetadata, StorageError> {
+        let remote = self.remote_path(path)?;
+        let directory = EntryMetadata {
+            kind: EntryKind::Directory,
+            size_bytes: None,
+            modified_at: None,
+            created_at: None,
+            permissions: EntryPermissions::default(),
+            is_hidden: super::is_hidden_name(path),
+            format: None,
+        };
+        if path.is_root() {
+            return Ok(directory);
+        }
+
+        let mut conn = self.connection().await?;
+        let ftp = conn.as_mut().expect("connected");
+        let outcome = self.timed(ftp.size(&remote)).await;
+        match self.settle(&mut conn, outcome) {
+            Ok(size) => {
+                // MDTM is an extension; a server without it still gives a usable stat
+                let modified_at = match conn.as_mut() {
+                    Some(ftp) => {
+                        let outcome = self.timed(ftp.mdtm(&remote)).await;
+                        self.settle(&mut conn, outcome).ok()
+                    }
+                    None => None,
+                }
+                .map(|naive| Utc.from_utc_datetime(&naive).into());
+                Ok(EntryMetadata {
+                    kind: EntryKind::File,
+                    size_bytes: Some(size as u64),
+                    modified_at,
+                    created_at: None,
+                    permissions: EntryPermissions::default(),
+                    is_hidden: super::is_hidden_name(path),
+                    format: None,
+                })
+            }
+            Err(StorageError::NotFound) | Err(StorageError::Io(_)) => {
+                if self.is_dir(&mut conn, &remote).await? {
+                    Ok(directory)
+                } else {
+                    Err(StorageError::NotFound)
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn read_inner(&self, path: &UniversalPath) -> Result<Vec<u8>, StorageError> {
+        let remote = self.remote_path(path)?;
+        let mut conn = self.connection().await?;
+        let ftp = conn.as_mut().expect("connected");
+        let outcome = self
+            .timed(async {
+                let mut stream = ftp.retr_as_stream(&remote).await?;
+                let mut buf = Vec::new();
+                stream
+                    .read_to_end(&mut buf)
+                    .await
+                    .map_err(FtpError::ConnectionError)?;
+                stream.finish().await?;
+                Ok(buf)
+            })
+            .await;
+        self.settle(&mut conn, outcome)
+    }
+
+    async fn read_range_inner(
+        &self,
+        path: &UniversalPath,
+        range: std::ops::Range<u64>,
+    ) -> Result<Vec<u8>, StorageError> {
+        if range.start >= range.end {
+            return Ok(Vec::new());
+        }
+        let remote = self.remote_path(path)?;
+        let mut conn = self.connection().await?;
+        let ftp = conn.as_mut().expect("connected");
+        let outcome = self.timed(ftp.size(&remote)).await;
+        let len = self.settle(&mut conn, outcome)? as u64;
+        let range = clamp_range(range, len)?;
+        let to_read = (range.end - range.start) as usize;
+
+        let ftp = conn.as_mut().expect("connected");
+        let outcome = self
+            .timed(async {
+                ftp.resume_transfer(range.start as usize).await?;
+                let mut stream = ftp.retr_as_stream(&remote).await?;
+                let mut buf = vec![0u8; to_read];
+                let mut read_so_far = 0usize;
+                while read_so_far < to_read {
+                    let n = stream
+                        .read(&mut buf[read_so_far..])
+                        .await
+                        .map_err(FtpError::ConnectionError)?;
+                    if n == 0 {
+                        break;
+                    }
+                    read_so_far += n;
+                }
+                buf.truncate(read_so_far);
+                if range.start + read_so_far as u64 >= len {
+                    stream.finish().await?;
+                } else {
+                    // Stop the server sending the rest of the file
+                    ftp.abort(stream).await?;
+                }
+                Ok(buf)
+            })
+            .await;
+        self.settle(&mut conn, outcome)
+    }
+
+    async fn list_inner(&self, path: &UniversalPath) -> Result<Vec<UniversalPath>, StorageError> {
+        let dir = path.as_dir();
+        Ok(self
+            .listing(path)
+            .await?
+            .into_iter()
+            .map(|file| {
+                let child = dir.join(file.name());
+                if file.is_directory() {
+                    child.as_dir()
+                } else {
+                    child
+                }
+            })
+            .collect())
+    }
+
+    /// From the one MLSD or LIST; only symlinks, which a listing does not resolve, are
+    /// stat'ed on their own
+    async fn list_with_metadata_inner(
+        &self,
+        path: &UniversalPath,
+    ) -> Result<Vec<(UniversalPath, EntryMetadata)>, StorageError> {
+        let dir = path.as_dir();
+        let mut listed = Vec::new();
+        for file in self.listing(path).await? {
+            let child = dir.join(file.name());
+            let meta = match file.is_symlink() {
+                true => match self.stat_inner(&child).await {
+                    Ok(meta) => meta,
+                    Err(StorageError::NotFound) => continue,
+                    Err(e) => return Err(e),
+                },
+                false => listed_metadata(&file),
+            };
+            let child = match meta.kind {
+                EntryKind::Directory => child.as_dir(),
+                _ => child,
+            };
+            listed.push((child, meta));
+        }
+        Ok(listed)
+    }
+
+    /// The entries of the directory at `path`, through MLSD where the server has it
+    async fn listing(&self, path: &UniversalPath) -> Result<Vec<ListedFile>, StorageError> {
+        let remote = self.remote_path(path)?;
+        let mut conn = self.connection().await?;
+        if !self.is_dir(&mut conn, &remote).await? {
+            let ftp = conn.as_mut().expect("connected");
+            let outcome = self.timed(ftp.size(&remote)).await;
+            return match self.settle(&mut conn, outcome) {
+                Ok(_) => Err(StorageError::NotADirectory),
+                Err(_) => Err(StorageError::NotFound),
+            };
+        }
+
+        let mut entries = None;
+        if self.mlsd_supported.load(Ordering::Relaxed) {
+            let ftp = conn.as_mut().expect("connected");
+            let outcome = self.timed(ftp.mlsd(Some(&remote))).await;
+            match self.settle(&mut conn, outcome) {
+                Ok(lines) => entries = Some(parse_mlsd_lines(&lines)),
+                Err(StorageError::Io(_)) => self.mlsd_supported.store(false, Ordering::Relaxed),
+                Err(e) => return Err(e),
+            }
+        }
+        let entries = match entries {
+            Some(entries) => entries,
+            None => {
+                let ftp = conn.as_mut().expect("connected");
+                let outcome = self.timed(ftp.list(Some(&remote))).await;
+                let lines = self.settle(&mut conn, outcome)?;
+                parse_list_lines(&lines)
+            }
+        };
+
+        Ok(entries
+            .into_iter()
+            .filter(|file| !matches!(file.name(), "." | ".." | ""))
+            .collect())
+    }
+}
+
+/// What a listing line says of a file. Servers that give no time or permissions
+/// (DOS listings, MLSD without `perm`) leave them unknown.
+fn listed_metadata(file: &ListedFile) -> EntryMetadata {
+    let kind = match () {
+        _ if file.is_directory() => EntryKind::Directory,
+        _ if file.is_file() => EntryKind::File,
+        _ => EntryKind::Other,
+    };
+    let mut mode = 0;
+    for (shift, who) in [
+        (6, PosixPexQuery::Owner),
+        (3, PosixPexQuery::Group),
+        (0, PosixPexQuery::Others),
+    ] {
+        let bits = u32::from(file.can_read(who)) << 2
+            | u32::from(file.can_write(who)) << 1
+            | u32::from(file.can_execute(who));
+        mode |= bits << shift;
+    }
+    let permissions = match mode {
+        0 => EntryPermissions::default(),
+        mode => EntryPermissions::from_mode(mode),
+    };
+    EntryMetadata {
+        size_bytes: (kind == EntryKind::File).then_some(file.size() as u64),
+        kind,
+        modified_at: Some(file.modified()).filter(|&at| at != UNIX_EPOCH),
+        created_at: None,
+        permissions: EntryPermissions {
+            uid: file.uid(),
+            gid: file.gid(),
+            ..permissions
+        },
+        is_hidden: file.name().starts_with('.'),
+        format: None,
+    }
+}
+
+fn parse_mlsd_lines(lines: &[String]) -> Vec<ListedFile> {
+    lines
+        .iter()
+        .filter_map(|line| ListParser::parse_mlsd(line).ok())
+        .collect()
+}
+
+/// Parse LIST output in either Unix `ls -l` or DOS format, skipping `total` lines and
+/// anything else unparseable.
+fn parse_list_lines(lines: &[String]) -> Vec<ListedFile> {
+    lines
+        .iter()
+        .filter(|line| !line.starts_with("total "))
+        .filter_map(|line| line.parse::<ListedFile>().ok())
+        .collect()
+}
+
+fn map_ftp_error(e: FtpError) -> StorageError {
+    match e {
+        FtpError::UnexpectedResponse(resp) if resp.status == Status::FileUnavailable => {
+            StorageError::NotFound
+        }
+        // 4xx replies are transient by RFC 959: busy, out of connections, aborted
+        FtpError::UnexpectedResponse(resp) if (400..500).contains(&resp.status.code()) => {
+            StorageError::Unavailable(format!(
+                "FTP server answered {:?}: {}",
+                resp.status,
+                String::from_utf8_lossy(&resp.body).trim()
+            ))
+        }
+        FtpError::UnexpectedResponse(resp) => StorageError::Io(std::io::Error::other(format!(
+            "FTP server answered {:?}: {}",
+            resp.status,
+            String::from_utf8_lossy(&resp.body).trim()
+        ))),
+        other => StorageError::Connection(other.to_string()),
+    }
+}
+
+#[async_trait]
+impl Storage for FtpStorage {
+    fn backend(&self) -> StorageBackend {
+        StorageBackend::Ftp
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        CAPABILITIES
+    }
+
+    async fn stat_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<EntryMetadata, StorageError> {
+        opts.enforce_timeout(self.stat_inner(path)).await
+    }
+
+    async fn read_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        opts.enforce_timeout(self.read_inner(path)).await
+    }
+
+    async fn read_range_opts(
+        &self,
+        path: &UniversalPath,
+        range: std::ops::Range<u64>,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        opts.enforce_timeout(self.read_range_inner(path, range))
+            .await
+    }
+
+    async fn list_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<UniversalPath>, StorageError> {
+        opts.enforce_timeout(self.list_inner(path)).await
+    }
+
+    async fn list_with_metadata_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<(UniversalPath, EntryMetadata)>, StorageError> {
+        opts.enforce_timeout(self.list_with_metadata_inner(path))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_and_paths() {
+        let path = UniversalPath::from_uri_str("ftp://nas:2121/music/a.flac").unwrap();
+        let config = FtpConfig::for_path(&path).unwrap();
+        assert_eq!(config.host, "nas");
+        assert_eq!(config.port, 2121);
+        assert_eq!(config.username, "anonymous");
+        assert!(config.passive);
+
+        let storage = FtpStorage::new(config);
+        assert_eq!(storage.remote_path(&path).unwrap(), "/music/a.flac");
+        let elsewhere = UniversalPath::from_uri_str("ftp://nas/music/a.flac").unwrap();
+        assert!(matches!(
+            storage.remote_path(&elsewhere),
+            Err(StorageError::InvalidPath)
+        ));
+    }
+
+    #[test]
+    fn test_parse_listings() {
+        let mlsd = [
+            "type=cdir;modify=20240101000000; .",
+            "type=pdir;modify=20240101000000; ..",
+            "type=dir;modify=20240101000000; Björk",
+            "type=file;size=1234;modify=20240101000000; cover.jpg",
+        ]
+        .map(String::from);
+        let parsed: Vec<_> = parse_mlsd_lines(&mlsd)
+            .into_iter()
+            .filter(|f| !matches!(f.name(), "." | ".."))
+            .collect();
+        assert_eq!(parsed.len(), 2);
+        assert!(parsed[0].is_directory());
+        assert_eq!(parsed[1].size(), 1234);
+        let cover = listed_metadata(&parsed[1]);
+        assert_eq!((cover.kind, cover.size_bytes), (EntryKind::File, Some(1234)));
+        let modified: chrono::DateTime<Utc> = cover.modified_at.unwrap().into();
+        assert_eq!(modified.to_rfc3339(), "2024-01-01T00:00:00+00:00");
+        assert_eq!(listed_metadata(&parsed[0]).kind, EntryKind::Directory);
+
+        let list = vec![
+            "total 8".to_string(),
+            "drwxr-xr-x    2 ftp      ftp          4096 Jan 01 12:00 Homogenic".to_string(),
+            "-rw-r--r--    1 ftp      ftp           512 Jan 01 12:00 notes.txt".to_string(),
+            "01-01-24  12:00PM       <DIR>          Live".to_string(),
+        ];
+        let parsed = parse_list_lines(&list);
+        let names: Vec<_> = parsed.iter().map(|f| f.name()).collect();
+        assert_eq!(names, vec!["Homogenic", "notes.txt", "Live"]);
+        assert!(parsed[0].is_directory() && parsed[2].is_directory());
+        assert!(parsed[1].is_file());
+        let notes = listed_metadata(&parsed[1]);
+        assert_eq!(notes.permissions.mode, Some(0o644));
+        assert_eq!(notes.permissions.readonly, Some(false));
+    }
+}
@@ -0,0 +1,354 @@
+use super::{CallOptions, EntryKind, Storage, StorageError, StorageExt};
+use crate::universal_path::UniversalPath;
+use futures::StreamExt;
+use std::collections::BTreeMap;
+
+/// One path segment of a glob pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    /// No wildcards, so the child can be named without listing
+    Literal(String),
+    Wild(Vec<Token>),
+    /// `**`: any number of directories, including none
+    AnyDepth,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Char(char),
+    /// `?`
+    AnyChar,
+    /// `*`
+    Star,
+    /// `[a-z_]` or, negated, `[!a-z_]`
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl Segment {
+    fn parse(segment: &str) -> Segment {
+        if segment == "**" {
+            return Segment::AnyDepth;
+        }
+        let chars: Vec<char> = segment.chars().collect();
+        let mut tokens = Vec::with_capacity(chars.len());
+        let mut i = 0;
+        while i < chars.len() {
+            match chars[i] {
+                '?' => tokens.push(Token::AnyChar),
+                // Runs of stars mean the same as one
+                '*' if tokens.last() != Some(&Token::Star) => tokens.push(Token::Star),
+                '*' => {}
+                '[' => match parse_class(&chars[i + 1..]) {
+                    Some((class, used)) => {
+                        tokens.push(class);
+                        i += used;
+                    }
+                    // An unclosed bracket is just a bracket
+                    None => tokens.push(Token::Char('[')),
+                },
+                c => tokens.push(Token::Char(c)),
+            }
+            i += 1;
+        }
+        if tokens.iter().all(|t| matches!(t, Token::Char(_))) {
+            Segment::Literal(segment.to_string())
+        } else {
+            Segment::Wild(tokens)
+        }
+    }
+
+    /// Whether a name in a listing matches. Wildcards never match a leading `.`, as
+    /// in the shell, so `*` skips `.DS_Store` and AppleDouble `._` files.
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Segment::Literal(literal) => literal == name,
+            Segment::AnyDepth => !name.starts_with('.'),
+            Segment::Wild(tokens) => {
+                if name.starts_with('.') && tokens.first() != Some(&Token::Char('.')) {
+                    return false;
+                }
+                let name: Vec<char> = name.chars().collect();
+                match_tokens(tokens, &name)
+            }
+        }
+    }
+}
+
+/// The class after an opening `[`, and how many characters it took including the
+/// closing `]`. A `]` right after the opening bracket, or after `!` or `^`, is a
+/// member rather than the end.
+fn parse_class(chars: &[char]) -> Option<(Token, usize)> {
+    let negated = matches!(chars.first(), Some('!' | '^'));
+    let mut i = usize::from(negated);
+    let mut ranges = Vec::new();
+    let mut first = true;
+    loop {
+        let c = *chars.get(i)?;
+        if c == ']' && !first {
+            return Some((Token::Class { negated, ranges }, i + 1));
+        }
+        first = false;
+        match (chars.get(i + 1), chars.get(i + 2)) {
+            (Some('-'), Some(&end)) if end != ']' => {
+                ranges.push((c, end));
+                i += 3;
+            }
+            _ => {
+                ranges.push((c, c));
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Shell-style matching with backtracking to the last star only, which is enough
+/// because a later star can absorb anything an earlier one would have
+fn match_tokens(tokens: &[Token], name: &[char]) -> bool {
+    let (mut t, mut n) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while n < name.len() {
+        let advance = match tokens.get(t) {
+            Some(Token::Star) => {
+                backtrack = Some((t, n));
+                t += 1;
+                continue;
+            }
+            Some(Token::Char(c)) => *c == name[n],
+            Some(Token::AnyChar) => true,
+            Some(Token::Class { negated, ranges }) => {
+                ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&name[n])) != *negated
+            }
+            None => false,
+        };
+        if advance {
+            t += 1;
+            n += 1;
+        } else if let Some((star, from)) = backtrack {
+            // Let the last star take one more character and try again
+            t = star + 1;
+            n = from + 1;
+            backtrack = Some((star, from + 1));
+        } else {
+            return false;
+        }
+    }
+    tokens[t..].iter().all(|t| *t == Token::Star)
+}
+
+/// Whether path segments, e.g. a path relative to a root, match a `/`-separated glob
+/// with the same syntax as `Storage::glob`
+pub(crate) fn matches_segments(pattern: &str, path: &[String]) -> bool {
+    let pattern: Vec<Segment> = pattern
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(Segment::parse)
+        .collect();
+    match_segments(&pattern, path)
+}
+
+fn match_segments(pattern: &[Segment], path: &[String]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((Segment::AnyDepth, rest)) => (0..=path.len()).any(|skip| {
+            path[..skip].iter().all(|s| Segment::AnyDepth.matches(s))
+                && match_segments(rest, &path[skip..])
+        }),
+        Some((segment, rest)) => path
+            .split_first()
+            .is_some_and(|(name, path)| segment.matches(name) && match_segments(rest, path)),
+    }
+}
+
+/// The directory a pattern's matches are all under: its segments up to the first
+/// with a wildcard
+pub(crate) fn literal_base(pattern: &UniversalPath) -> UniversalPath {
+    let mut base = pattern.as_file();
+    let literal = pattern
+        .path_segments()
+        .iter()
+        .take_while(|s| matches!(Segment::parse(s), Segment::Literal(_)))
+        .count();
+    while base.path_segments().len() > literal {
+        base.pop();
+    }
+    base.as_dir()
+}
+
+/// Everything on `storage` that `pattern` matches, sorted by path, found with `list`
+/// and `stat` alone. Literal segments are looked up directly, wildcard segments list
+/// one directory, and `**` walks the tree below it.
+pub(crate) async fn glob<S: Storage + ?Sized>(
+    storage: &S,
+    pattern: &UniversalPath,
+    opts: &CallOptions,
+) -> Result<Vec<UniversalPath>, StorageError> {
+    let segments: Vec<Segment> = pattern
+        .path_segments()
+        .iter()
+        .map(|s| Segment::parse(s))
+        .fold(Vec::new(), |mut segments, segment| {
+            // `**/**` means the same as `**`
+            if !(segment == Segment::AnyDepth && segments.last() == Some(&Segment::AnyDepth)) {
+                segments.push(segment);
+            }
+            segments
+        });
+    let literal = segments
+        .iter()
+        .take_while(|s| matches!(s, Segment::Literal(_)))
+        .count();
+    if literal == segments.len() {
+        // Nothing to expand, so the pattern matches itself if it exists
+        return match storage.stat_opts(pattern, opts).await {
+            Ok(_) => Ok(vec![pattern.clone()]),
+            Err(StorageError::NotFound) => Ok(Vec::new()),
+            Err(e) => Err(e),
+        };
+    }
+    let mut base = pattern.as_file();
+    while base.path_segments().len() > literal {
+        base.pop();
+    }
+
+    // Keyed without the directory hint, so a path reached twice is kept once
+    let mut found = BTreeMap::new();
+    let mut pending = vec![(base.as_dir(), literal)];
+    while let Some((dir, index)) = pending.pop() {
+        let last = index + 1 == segments.len();
+        match &segments[index] {
+            Segment::Literal(name) => {
+                let child = dir.join(name);
+                if last {
+                    match storage.stat_opts(&child, opts).await {
+                        Ok(_) => {
+                            found.insert(child.as_file().to_string(), child);
+                        }
+                        Err(StorageError::NotFound) => {}
+                        Err(e) => return Err(e),
+                    }
+                } else {
+                    pending.push((child.as_dir(), index + 1));
+                }
+            }
+            Segment::Wild(_) => {
+                let children = match storage.list_opts(&dir, opts).await {
+                    Ok(children) => children,
+                    Err(StorageError::NotFound | StorageError::NotADirectory) => continue,
+                    Err(e) => return Err(e),
+                };
+                for child in children {
+                    if !child
+                        .last_segment()
+                        .is_some_and(|n| segments[index].matches(n))
+                    {
+                        continue;
+                    }
+                    if last {
+                        found.insert(child.as_file().to_string(), child);
+                    } else {
+                        pending.push((child.as_dir(), index + 1));
+                    }
+                }
+            }
+            Segment::AnyDepth => {
+                // Zero directories deep, then every directory below
+                if !last {
+                    pending.push((dir.clone(), index + 1));
+                }
+                let mut walk = storage.walk(&dir);
+                while let Some(entry) = walk.next().await {
+                    let (path, meta) = match entry {
+                        Ok(entry) => entry,
+                        Err(StorageError::NotFound | StorageError::NotADirectory) => continue,
+                        Err(e) => return Err(e),
+                    };
+                    let hidden = path
+                        .relative_to(&dir)
+                        .is_some_and(|rel| rel.iter().any(|s| !segments[index].matches(s)));
+                    if hidden {
+                        continue;
+                    }
+                    if last {
+                        found.insert(path.as_file().to_string(), path);
+                    } else if meta.kind == EntryKind::Directory {
+                        pending.push((path, index + 1));
+                    }
+                }
+            }
+        }
+    }
+    Ok(found.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LocalStorage;
+    use std::fs;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        Segment::parse(pattern).matches(name)
+    }
+
+    #[test]
+    fn test_segment_matching() {
+        assert!(matches("*.flac", "01 Intro.flac"));
+        assert!(!matches("*.flac", "01 Intro.flac.part"));
+        assert!(matches("0?-*", "01-Intro"));
+        assert!(matches("*a*b*", "xxaxxbxx"));
+        assert!(!matches("*a*b*", "xxbxxaxx"));
+        assert!(matches("[0-9][0-9] *", "07 Song"));
+        assert!(!matches("[!0-9]*", "07 Song"));
+        assert!(matches("[]x]", "]"));
+        assert!(matches("[abc", "[abc"));
+        assert!(!matches("*", ".DS_Store"));
+        assert!(matches(".*", ".DS_Store"));
+        assert_eq!(
+            Segment::parse("cover.jpg"),
+            Segment::Literal("cover.jpg".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_glob_local_tree() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("A/Album 1/CD2")).unwrap();
+        fs::create_dir_all(dir.join("B/Album 2")).unwrap();
+        for file in [
+            "A/Album 1/01.flac",
+            "A/Album 1/cover.jpg",
+            "A/Album 1/CD2/01.flac",
+            "A/Album 1/._01.flac",
+            "B/Album 2/01.mp3",
+            "top.flac",
+        ] {
+            fs::write(dir.join(file), b"x").unwrap();
+        }
+        let root = UniversalPath::local(dir.to_str().unwrap());
+        let glob = async |pattern: &str| -> Vec<String> {
+            let pattern = root.join_all(&pattern.split('/').collect::<Vec<_>>());
+            LocalStorage::new()
+                .glob(&pattern)
+                .await
+                .unwrap()
+                .iter()
+                .map(|p| p.relative_to(&root).unwrap().join("/"))
+                .collect()
+        };
+
+        assert_eq!(glob("*/*/*.flac").await, ["A/Album 1/01.flac"]);
+        assert_eq!(
+            glob("**/*.flac").await,
+            ["A/Album 1/01.flac", "A/Album 1/CD2/01.flac", "top.flac"]
+        );
+        assert_eq!(glob("?/Album [0-9]").await, ["A/Album 1", "B/Album 2"]);
+        assert_eq!(glob("A/**/CD?/*").await, ["A/Album 1/CD2/01.flac"]);
+        assert_eq!(glob("A/Album 1/cover.jpg").await, ["A/Album 1/cover.jpg"]);
+        assert!(glob("C/*").await.is_empty());
+        assert_eq!(glob("B/**").await, ["B/Album 2", "B/Album 2/01.mp3"]);
+    }
+}
@@ -0,0 +1,579 @@
+use super::{
+    CallOptions, EntryKind, EntryMetadata, EntryPermissions, RangePart, Storage, StorageBackend, StorageCapabilities,
+    StorageError, check_partial, check_suffix, clamp_range, parse_content_range,
+};
+use crate::universal_path::UniversalPath;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use reqwest::{StatusCode, header};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+pub(crate) const CAPABILITIES: StorageCapabilities = StorageCapabilities {
+    can_stat: true,
+    can_read: true,
+    can_read_range: true,
+    can_list: false,
+    can_glob: false,
+    can_write: false,
+    can_delete: false,
+    can_rename: false,
+    can_checksum: false,
+};
+
+/// Read-only storage over plain HTTP or HTTPS, for files published on a web server.
+///
+/// There is no directory listing, so `list` is unsupported. Paths map to URLs with
+/// `UniversalPath::to_uri`, which means query strings are not carried.
+#[derive(Clone)]
+pub struct HttpStorage {
+    backend: StorageBackend,
+    client: reqwest::Client,
+    /// Whether each origin honours `Range`, learned from `Accept-Ranges` and from
+    /// how it answered earlier ranged requests
+    ranges_by_origin: Arc<Mutex<HashMap<String, bool>>>,
+}
+
+impl HttpStorage {
+    pub fn http() -> Self {
+        HttpStorage::with_client(StorageBackend::Http, reqwest::Client::new())
+    }
+
+    pub fn https() -> Self {
+        HttpStorage::with_client(StorageBackend::Https, reqwest::Client::new())
+    }
+
+    /// Storage for `backend`, which must be `Http` or `Https`, sending requests
+    /// through `client`
+    pub fn with_client(backend: StorageBackend, client: reqwest::Client) -> Self {
+        HttpStorage {
+            backend,
+            client,
+            ranges_by_origin: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn url(&self, path: &UniversalPath) -> Result<String, StorageError> {
+        if path.backend() != &self.backend || path.host().is_none() {
+            return Err(StorageError::InvalidPath);
+        }
+        path.to_uri().map_err(|_| StorageError::InvalidPath)
+    }
+
+    fn origin(path: &UniversalPath) -> String {
+        format!(
+            "{:?}://{}:{}",
+            path.backend(),
+            path.host().unwrap_or_default(),
+            path.port().unwrap_or_default()
+        )
+    }
+
+    fn ranges_supported(&self, path: &UniversalPath) -> Option<bool> {
+        let known = self
+            .ranges_by_origin
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        known.get(&Self::origin(path)).copied()
+    }
+
+    fn remember_ranges(&self, path: &UniversalPath, supported: bool) {
+        let mut known = self
+            .ranges_by_origin
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        known.insert(Self::origin(path), supported);
+    }
+
+    async fn stat_inner(&self, path: &UniversalPath) -> Result<EntryMetadata, StorageError> {
+        let sent_at = SystemTime::now();
+        let resp = self
+            .client
+            .head(self.url(path)?)
+            .send()
+            .await
+            .map_err(connection_error)?;
+        if !resp.status().is_success() {
+            return Err(status_error(resp.status()));
+        }
+        let value = |name| resp.headers().get(name).and_then(|v| v.to_str().ok());
+        super::observe_date_header(&super::host_key(path), sent_at, value(header::DATE));
+        if let Some(accept) = value(header::ACCEPT_RANGES) {
+            self.remember_ranges(path, accept.eq_ignore_ascii_case("bytes"));
+        }
+        let modified_at = value(header::LAST_MODIFIED)
+            .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+            .map(|d| d.with_timezone(&Utc).into());
+        Ok(EntryMetadata {
+            kind: EntryKind::File,
+            size_bytes: value(header::CONTENT_LENGTH).and_then(|v| v.parse().ok()),
+            modified_at,
+            created_at: None,
+            permissions: EntryPermissions::default(),
+            is_hidden: super::is_hidden_name(path),
+            format: None,
+        })
+    }
+
+    async fn read_inner(&self, path: &UniversalPath) -> Result<Vec<u8>, StorageError> {
+        let resp = self
+            .client
+            .get(self.url(path)?)
+            .send()
+            .await
+            .map_err(connection_error)?;
+        if !resp.status().is_success() {
+            return Err(status_error(resp.status()));
+        }
+        Ok(resp.bytes().await.map_err(connection_error)?.to_vec())
+    }
+
+    async fn read_range_inner(
+        &self,
+        path: &UniversalPath,
+        range: std::ops::Range<u64>,
+    ) -> Result<Vec<u8>, StorageError> {
+        if range.start >= range.end {
+            return Ok(Vec::new());
+        }
+        let mut request = self.client.get(self.url(path)?);
+        if self.ranges_supported(path) != Some(false) {
+            request = request.header(
+                header::RANGE,
+                format!("bytes={}-{}", range.start, range.end - 1),
+            );
+        }
+        let resp = request.send().await.map_err(connection_error)?;
+        let status = resp.status();
+        if !status.is_success() {
+            return Err(status_error(status));
+        }
+        if status == StatusCode::PARTIAL_CONTENT {
+            self.remember_ranges(path, true);
+            let content_range = header_value(&resp, header::CONTENT_RANGE);
+            let body = resp.bytes().await.map_err(connection_error)?;
+            return check_partial(&range, content_range.as_deref(), body.to_vec());
+        }
+        // No range support: take the slice out of the whole body
+        self.remember_ranges(path, false);
+        let body = resp.bytes().await.map_err(connection_error)?;
+        let range = clamp_range(range, body.len() as u64)?;
+        Ok(body[range.start as usize..range.end as usize].to_vec())
+    }
+
+    /// A `bytes=-len` request, so no `HEAD` is needed to find where the file ends
+    async fn read_suffix_inner(
+        &self,
+        path: &UniversalPath,
+        len: u64,
+    ) -> Result<Vec<u8>, StorageError> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let mut request = self.client.get(self.url(path)?);
+        if self.ranges_supported(path) != Some(false) {
+            request = request.header(header::RANGE, format!("bytes=-{len}"));
+        }
+        let resp = request.send().await.map_err(connection_error)?;
+        let status = resp.status();
+        // Only an empty file has no suffix to give
+        if status == StatusCode::RANGE_NOT_SATISFIABLE {
+            return Ok(Vec::new());
+        }
+        if !status.is_success() {
+            return Err(status_error(status));
+        }
+        let partial = status == StatusCode::PARTIAL_CONTENT;
+        self.remember_ranges(path, partial);
+        let content_range = header_value(&resp, header::CONTENT_RANGE).filter(|_| partial);
+        let body = resp.bytes().await.map_err(connection_error)?;
+        check_suffix(len, content_range.as_deref(), body.to_vec())
+    }
+
+    /// All ranges in one request. The server may answer `multipart/byteranges`, a
+    /// single coalesced range, or the whole file; each is sliced the same way.
+    async fn read_ranges_inner(
+        &self,
+        path: &UniversalPath,
+        ranges: &[std::ops::Range<u64>],
+    ) -> Result<Vec<RangePart>, StorageError> {
+        let wanted: Vec<_> = ranges.iter().filter(|r| r.start < r.end).collect();
+        if wanted.len() < 2 && self.ranges_supported(path) != Some(false) {
+            let mut parts = Vec::with_capacity(ranges.len());
+            for range in ranges {
+                parts.push(RangePart {
+                    requested: range.clone(),
+                    data: self.read_range_inner(path, range.clone()).await?,
+                });
+            }
+            return Ok(parts);
+        }
+
+        let mut request = self.client.get(self.url(path)?);
+        if self.ranges_supported(path) != Some(false) {
+            let spec: Vec<_> = wanted
+                .iter()
+                .map(|r| format!("{}-{}", r.start, r.end - 1))
+                .collect();
+            request = request.header(header::RANGE, format!("bytes={}", spec.join(",")));
+        }
+        let resp = request.send().await.map_err(connection_error)?;
+        let status = resp.status();
+        if !status.is_success() {
+            return Err(status_error(status));
+        }
+        if status != StatusCode::PARTIAL_CONTENT {
+            self.remember_ranges(path, false);
+            let body = resp.bytes().await.map_err(connection_error)?.to_vec();
+            let total = Some(body.len() as u64);
+            return slice_segments(ranges, &[(0, body)], total);
+        }
+
+        self.remember_ranges(path, true);
+        let content_type = header_value(&resp, header::CONTENT_TYPE).unwrap_or_default();
+        let content_range = header_value(&resp, header::CONTENT_RANGE);
+        let body = resp.bytes().await.map_err(connection_error)?;
+        let malformed =
+            || StorageError::Io(std::io::Error::other("malformed multipart/byteranges"));
+        let (segments, total) = if content_type.starts_with("multipart/byteranges") {
+            let boundary = content_type
+                .split(';')
+                .find_map(|p| p.trim().strip_prefix("boundary="))
+                .map(|b| b.trim_matches('"'))
+                .ok_or_else(malformed)?;
+            parse_byteranges(&body, boundary).ok_or_else(malformed)?
+        } else {
+            let (first, _, total) = content_range
+                .as_deref()
+                .and_then(parse_content_range)
+                .ok_or_else(malformed)?;
+            (vec![(first, body.to_vec())], total)
+        };
+        slice_segments(ranges, &segments, total)
+    }
+}
+
+fn header_value(resp: &reqwest::Response, name: header::HeaderName) -> Option<String> {
+    resp.headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Byte ranges a server sent back, as `(first byte, data)`
+type Segments = Vec<(u64, Vec<u8>)>;
+
+/// Split a `multipart/byteranges` body into `(first byte, data)` pairs, sizing each
+/// part by its `Content-Range` rather than by searching for the next boundary.
+fn parse_byteranges(body: &[u8], boundary: &str) -> Option<(Segments, Option<u64>)> {
+    fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+        haystack
+            .get(from..)?
+            .windows(needle.len())
+            .position(|w| w == needle)
+            .map(|p| p + from)
+    }
+
+    let delimiter = format!("--{boundary}");
+    let mut parts = Vec::new();
+    let mut total = None;
+    let mut pos = find(body, delimiter.as_bytes(), 0)? + delimiter.len();
+    while !body.get(pos..)?.starts_with(b"--") {
+        let headers_start = pos
+            + body
+                .get(pos..)?
+                .iter()
+                .take_while(|b| b.is_ascii_whitespace())
+                .count();
+        let headers_end = find(body, b"\r\n\r\n", headers_start)?;
+        let headers = std::str::from_utf8(&body[headers_start..headers_end]).ok()?;
+        let content_range = headers.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("content-range")
+                .then_some(value)
+        })?;
+        let (first, last, part_total) = parse_content_range(content_range)?;
+        total = total.or(part_total);
+        let data_start = headers_end + 4;
+        let data_end = data_start + usize::try_from(last - first + 1).ok()?;
+        parts.push((first, body.get(data_start..data_end)?.to_vec()));
+        pos = find(body, delimiter.as_bytes(), data_end)? + delimiter.len();
+    }
+    Some((parts, total))
+}
+
+/// Cut each requested range out of the segments a server sent back
+fn slice_segments(
+    ranges: &[std::ops::Range<u64>],
+    segments: &[(u64, Vec<u8>)],
+    total: Option<u64>,
+) -> Result<Vec<RangePart>, StorageError> {
+    let missing = |range: &std::ops::Range<u64>| {
+        StorageError::Io(std::io::Error::other(format!(
+            "server left out bytes {}-{}",
+            range.start,
+            range.end - 1
+        )))
+    };
+    ranges
+        .iter()
+        .map(|range| {
+            if range.start >= range.end {
+                return Ok(RangePart {
+                    requested: range.clone(),
+                    data: Vec::new(),
+                });
+            }
+            if total.is_some_and(|total| range.start >= total) {
+                return Err(StorageError::RangeNotSatisfiable);
+            }
+            let (seg_start, seg) = segments
+                .iter()
+                .find(|(start, data)| {
+                    *start <= range.start && range.start < start + data.len() as u64
+                })
+                .ok_or_else(|| missing(range))?;
+            let seg_end = seg_start + seg.len() as u64;
+            let end = range.end.min(seg_end);
+            if total.is_some_and(|total| range.end.min(total) > end) {
+                return Err(missing(range));
+            }
+            Ok(RangePart {
+                requested: range.clone(),
+                data: seg[(range.start - seg_start) as usize..(end - seg_start) as usize].to_vec(),
+            })
+        })
+        .collect()
+}
+
+fn connection_error(e: reqwest::Error) -> StorageError {
+    StorageError::Connection(e.to_string())
+}
+
+fn status_error(status: StatusCode) -> StorageError {
+    match status {
+        StatusCode::NOT_FOUND | StatusCode::GONE => StorageError::NotFound,
+        StatusCode::RANGE_NOT_SATISFIABLE => StorageError::RangeNotSatisfiable,
+        StatusCode::TOO_MANY_REQUESTS
+        | StatusCode::INTERNAL_SERVER_ERROR
+        | StatusCode::BAD_GATEWAY
+        | StatusCode::SERVICE_UNAVAILABLE
+        | StatusCode::GATEWAY_TIMEOUT => StorageError::Unavailable(format!("server answered {status}")),
+        StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED => StorageError::Io(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("server answered {status}"),
+        )),
+        _ => StorageError::Io(std::io::Error::other(format!("server answered {status}"))),
+    }
+}
+
+#[async_trait]
+impl Storage for HttpStorage {
+    fn backend(&self) -> StorageBackend {
+        self.backend.clone()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        CAPABILITIES
+    }
+
+    async fn stat_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<EntryMetadata, StorageError> {
+        opts.enforce_timeout(self.stat_inner(path)).await
+    }
+
+    async fn read_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        opts.enforce_timeout(self.read_inner(path)).await
+    }
+
+    async fn read_range_opts(
+        &self,
+        path: &UniversalPath,
+        range: std::ops::Range<u64>,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        opts.enforce_timeout(self.read_range_inner(path, range))
+            .await
+    }
+
+    async fn read_suffix_opts(
+        &self,
+        path: &UniversalPath,
+        len: u64,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        opts.enforce_timeout(self.read_suffix_inner(path, len))
+            .await
+    }
+
+    async fn read_ranges_opts(
+        &self,
+        path: &UniversalPath,
+        ranges: &[std::ops::Range<u64>],
+        opts: &CallOptions,
+    ) -> Result<Vec<RangePart>, StorageError> {
+        opts.enforce_timeout(self.read_ranges_inner(path, ranges))
+            .await
+    }
+
+    async fn list_opts(
+        &self,
+        _path: &UniversalPath,
+        _opts: &CallOptions,
+    ) -> Result<Vec<UniversalPath>, StorageError> {
+        Err(StorageError::UnsupportedFeature("listing over HTTP"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    const BODY: &[u8] = b"0123456789abcdef";
+
+    /// Answers one request per connection; honours `Range`, including several ranges
+    /// as `multipart/byteranges`, only if `ranges` is set
+    async fn serve(ranges: bool) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = vec![0u8; 4096];
+                let n = socket.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).to_string();
+                let head = request.starts_with("HEAD");
+                let found = request.contains(" /music/a.flac ");
+                let requested: Vec<_> = request
+                    .lines()
+                    .find_map(|l| l.strip_prefix("range: bytes="))
+                    .into_iter()
+                    .flat_map(|spec| spec.split(','))
+                    .filter_map(|r| r.split_once('-'))
+                    .map(|(a, b)| {
+                        let b = b.parse::<usize>().unwrap();
+                        match a.parse::<usize>() {
+                            Ok(a) => a..(b + 1).min(BODY.len()),
+                            // Suffix range, the last `b` bytes
+                            Err(_) => BODY.len().saturating_sub(b)..BODY.len(),
+                        }
+                    })
+                    .collect();
+                let mut headers = String::new();
+                let (status, body) = if !found {
+                    ("404 Not Found", Vec::new())
+                } else if !ranges || requested.is_empty() {
+                    ("200 OK", BODY.to_vec())
+                } else if requested.iter().any(|r| r.start >= BODY.len()) {
+                    ("416 Range Not Satisfiable", Vec::new())
+                } else if let [r] = requested.as_slice() {
+                    headers = format!("Content-Range: bytes {}-{}/16\r\n", r.start, r.end - 1);
+                    ("206 Partial Content", BODY[r.clone()].to_vec())
+                } else {
+                    headers = "Content-Type: multipart/byteranges; boundary=SEP\r\n".to_string();
+                    let mut body = Vec::new();
+                    for r in &requested {
+                        body.extend_from_slice(
+                            format!(
+                                "\r\n--SEP\r\nContent-Type: audio/flac\r\n\
+                                 Content-Range: bytes {}-{}/16\r\n\r\n",
+                                r.start,
+                                r.end - 1
+                            )
+                            .as_bytes(),
+                        );
+                        body.extend_from_slice(&BODY[r.clone()]);
+                    }
+                    body.extend_from_slice(b"\r\n--SEP--\r\n");
+                    ("206 Partial Content", body)
+                };
+                let accept = if ranges { "bytes" } else { "none" };
+                let mut response = format!(
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nAccept-Ranges: {accept}\r\n\
+                     Last-Modified: Tue, 05 Mar 2024 10:00:00 GMT\r\n{headers}\
+                     Connection: close\r\n\r\n",
+                    body.len()
+                )
+                .into_bytes();
+                if !head {
+                    response.extend_from_slice(&body);
+                }
+                socket.write_all(&response).await.unwrap();
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn test_stat_and_ranges() {
+        for ranges in [true, false] {
+            let port = serve(ranges).await;
+            let storage = HttpStorage::http();
+            let file =
+                UniversalPath::from_uri_str(&format!("http://127.0.0.1:{port}/music/a.flac"))
+                    .unwrap();
+
+            let meta = storage.stat(&file).await.unwrap();
+            assert_eq!(meta.kind, EntryKind::File);
+            assert_eq!(meta.size_bytes, Some(16));
+            assert!(meta.modified_at.is_some());
+            assert_eq!(storage.ranges_supported(&file), Some(ranges));
+
+            assert_eq!(storage.read(&file).await.unwrap(), BODY);
+            assert_eq!(storage.read_range(&file, 4..8).await.unwrap(), b"4567");
+            assert!(matches!(
+                storage.read_range(&file, 40..48).await,
+                Err(StorageError::RangeNotSatisfiable)
+            ));
+            assert!(
+                storage
+                    .read_range(&file, std::ops::Range { start: 8, end: 2 })
+                    .await
+                    .unwrap()
+                    .is_empty()
+            );
+            assert_eq!(
+                storage.read_range(&file, 12..u64::MAX).await.unwrap(),
+                b"cdef"
+            );
+
+            assert_eq!(storage.read_suffix(&file, 3).await.unwrap(), b"def");
+            assert_eq!(storage.read_suffix(&file, 40).await.unwrap(), BODY);
+
+            let parts = storage
+                .read_ranges(&file, &[0..4, 3..3, 12..20])
+                .await
+                .unwrap();
+            let data: Vec<_> = parts.iter().map(|p| p.data.as_slice()).collect();
+            assert_eq!(data, vec![&b"0123"[..], b"", b"cdef"]);
+            assert_eq!(parts[2].range(), 12..16);
+            assert!(matches!(
+                storage.read_ranges(&file, &[0..4, 30..40]).await,
+                Err(StorageError::RangeNotSatisfiable)
+            ));
+            let missing = file.parent().unwrap().join("b.flac");
+            assert!(matches!(
+                storage.stat(&missing).await,
+                Err(StorageError::NotFound)
+            ));
+            assert!(matches!(
+                storage.list(&file.parent().unwrap()).await,
+                Err(StorageError::UnsupportedFeature(_))
+            ));
+        }
+    }
+}
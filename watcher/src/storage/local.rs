@@ -1,13 +1,84 @@
-use super::{EntryKind, EntryMetadata, Storage, StorageBackend, StorageCapabilities, StorageError};
+use super::{
+    clamp_range, lock, ByteStream, CallOptions, EntryKind, EntryMetadata, EntryPermissions, ListStream, Storage, StorageAccount, StorageBackend,
+    StorageCapabilities, StorageError, StorageLock,
+};
 use crate::universal_path::UniversalPath;
 use async_trait::async_trait;
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tokio::io::AsyncRead;
 
-#[derive(Default)]
-pub struct LocalStorage;
+/// Children `list_stream` stats at once, which pays off on network mounts
+const LIST_STAT_CONCURRENCY: usize = 8;
+
+pub(crate) const CAPABILITIES: StorageCapabilities = StorageCapabilities {
+    can_stat: true,
+    can_read: true,
+    can_read_range: true,
+    can_list: true,
+    can_glob: true,
+    can_write: true,
+    can_delete: true,
+    can_rename: true,
+    can_checksum: false,
+};
+
+/// What `LocalStorage` does with paths that are symbolic links. Only the last
+/// segment of a path is checked; links to directories further up are followed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkPolicy {
+    /// Act on what the link points to, as the filesystem does
+    #[default]
+    Follow,
+    /// Treat links as if they were not there: listings leave them out and anything
+    /// else fails with `NotFound`
+    Skip,
+    /// Fail with `StorageError::IsSymlink`, for trees that should not have links
+    Error,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LocalStorage {
+    links: LinkPolicy,
+}
 
 impl LocalStorage {
-    fn to_pathbuf(&self, upath: &UniversalPath) -> Result<PathBuf, StorageError> {
+    pub const fn new() -> Self {
+        LocalStorage {
+            links: LinkPolicy::Follow,
+        }
+    }
+
+    pub fn with_links(mut self, links: LinkPolicy) -> Self {
+        self.links = links;
+        self
+    }
+
+    pub fn links(&self) -> LinkPolicy {
+        self.links
+    }
+
+    /// The native path to read `upath` through, once the link policy has allowed it
+    async fn resolve(&self, upath: &UniversalPath) -> Result<PathBuf, StorageError> {
+        let pb = self.to_pathbuf(upath)?;
+        if self.links == LinkPolicy::Follow {
+            return Ok(pb);
+        }
+        match tokio::fs::symlink_metadata(&pb).await {
+            Ok(md) if md.file_type().is_symlink() => match self.links {
+                LinkPolicy::Error => Err(StorageError::IsSymlink),
+                _ => Err(StorageError::NotFound),
+            },
+            _ => Ok(pb),
+        }
+    }
+
+    pub(crate) fn to_pathbuf(&self, upath: &UniversalPath) -> Result<PathBuf, StorageError> {
         if upath.backend() != &StorageBackend::Local {
             return Err(StorageError::InvalidPath);
         }
@@ -15,10 +86,19 @@ impl LocalStorage {
         let segments = upath.path_segments();
         #[cfg(windows)]
         {
+            use crate::universal_path::PathFlavor;
             use std::path::Path;
-            if segments.first().map(|s| s.ends_with(':')).unwrap_or(false) {
-                let mut pb = PathBuf::from(segments[0].clone());
-                for seg in &segments[1..] {
+            // `C:\` rather than `C:`, which would make the rest drive-relative
+            let prefix = match upath.flavor() {
+                PathFlavor::WindowsDrive => Some((format!("{}\\", segments[0]), 1)),
+                PathFlavor::WindowsUnc if segments.len() >= 2 => {
+                    Some((format!("\\\\{}\\{}\\", segments[0], segments[1]), 2))
+                }
+                _ => None,
+            };
+            if let Some((prefix, skip)) = prefix {
+                let mut pb = PathBuf::from(prefix);
+                for seg in &segments[skip..] {
                     pb.push(seg);
                 }
                 return Ok(pb);
@@ -48,24 +128,216 @@ impl Storage for LocalStorage {
     }
 
     fn capabilities(&self) -> StorageCapabilities {
-        StorageCapabilities {
-            can_stat: true,
-            can_read: true,
-            can_read_range: true,
-            can_list: true,
-            can_glob: false,
+        CAPABILITIES
+    }
+
+    async fn stat_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<EntryMetadata, StorageError> {
+        opts.enforce_timeout(self.stat_inner(path, opts.no_follow))
+            .await
+    }
+
+    async fn read_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        opts.enforce_timeout(self.read_inner(path)).await
+    }
+
+    async fn read_range_opts(
+        &self,
+        path: &UniversalPath,
+        range: std::ops::Range<u64>,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        opts.enforce_timeout(self.read_range_inner(path, range)).await
+    }
+
+    async fn list_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<UniversalPath>, StorageError> {
+        opts.enforce_timeout(self.list_inner(path)).await
+    }
+
+    async fn list_stream_opts<'a>(
+        &'a self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<ListStream<'a>, StorageError> {
+        opts.enforce_timeout(self.list_stream_inner(path)).await
+    }
+
+    async fn read_suffix_opts(
+        &self,
+        path: &UniversalPath,
+        len: u64,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        opts.enforce_timeout(self.read_suffix_inner(path, len)).await
+    }
+
+    async fn read_stream_opts<'a>(
+        &'a self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<ByteStream<'a>, StorageError> {
+        let file = opts.enforce_timeout(self.open_file(path)).await?;
+        Ok(Box::pin(file))
+    }
+
+    async fn symlink_target_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Option<UniversalPath>, StorageError> {
+        opts.enforce_timeout(self.symlink_target_inner(path)).await
+    }
+
+    async fn write_opts(
+        &self,
+        path: &UniversalPath,
+        data: &[u8],
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        opts.enforce_timeout(self.write_inner(path, data)).await
+    }
+
+    async fn write_stream_opts(
+        &self,
+        path: &UniversalPath,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        opts.enforce_timeout(self.write_stream_inner(path, reader)).await
+    }
+
+    async fn create_dir_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        opts.enforce_timeout(self.create_dir_inner(path)).await
+    }
+
+    async fn delete_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        opts.enforce_timeout(self.delete_inner(path)).await
+    }
+
+    async fn rename_opts(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        opts.enforce_timeout(self.rename_inner(from, to)).await
+    }
+
+    async fn copy_opts(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        opts.enforce_timeout(self.copy_inner(from, to)).await
+    }
+
+    async fn set_modified_opts(
+        &self,
+        path: &UniversalPath,
+        modified_at: SystemTime,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        opts.enforce_timeout(self.set_modified_inner(path, modified_at)).await
+    }
+
+    async fn attribute_opts(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+        opts: &CallOptions,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        opts.enforce_timeout(self.attribute_inner(path, name)).await
+    }
+
+    async fn set_attribute_opts(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+        value: &[u8],
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        opts.enforce_timeout(self.set_attribute_inner(path, name, value))
+            .await
+    }
+
+    async fn acquire_lock_opts(
+        &self,
+        path: &UniversalPath,
+        ttl: Duration,
+        opts: &CallOptions,
+    ) -> Result<StorageLock, StorageError> {
+        // Linking the lock file into place is atomic, where the default's rename checks first
+        let create: lock::CreateExclusive<'_> =
+            Box::new(move |path, bytes| Box::pin(self.create_new_inner(path, bytes)));
+        opts.enforce_timeout(lock::acquire(self, path, ttl, opts, create)).await
+    }
+}
+
+#[async_trait]
+impl StorageAccount for LocalStorage {
+    fn backend(&self) -> StorageBackend {
+        StorageBackend::Local
+    }
+
+    async fn list_roots(&self) -> Result<Vec<UniversalPath>, StorageError> {
+        #[cfg(windows)]
+        {
+            let mut roots = Vec::new();
+            for letter in b'A'..=b'Z' {
+                let drive = format!("{}:\\", letter as char);
+                if tokio::fs::metadata(&drive).await.is_ok() {
+                    roots.push(UniversalPath::local(drive));
+                }
+            }
+            Ok(roots)
+        }
+        #[cfg(not(windows))]
+        {
+            Ok(vec![UniversalPath::local("/")])
         }
     }
+}
 
-    async fn stat(&self, path: &UniversalPath) -> Result<EntryMetadata, StorageError> {
+impl LocalStorage {
+    async fn stat_inner(
+        &self,
+        path: &UniversalPath,
+        no_follow: bool,
+    ) -> Result<EntryMetadata, StorageError> {
         use tokio::fs;
-        let pb = self.to_pathbuf(path)?;
-        let md = fs::metadata(pb).await.map_err(|e| match e.kind() {
+        let pb = self.resolve(path).await?;
+        let md = match no_follow {
+            true => fs::symlink_metadata(pb).await,
+            false => fs::metadata(pb).await,
+        };
+        let md = md.map_err(|e| match e.kind() {
             std::io::ErrorKind::NotFound => StorageError::NotFound,
             _ => StorageError::Io(e),
         })?;
 
-        let kind = if md.is_dir() {
+        let kind = if md.file_type().is_symlink() {
+            EntryKind::Symlink
+        } else if md.is_dir() {
             EntryKind::Directory
         } else if md.is_file() {
             EntryKind::File
@@ -77,17 +349,43 @@ impl Storage for LocalStorage {
         let modified_at = md.modified().ok();
         let created_at = md.created().ok();
 
+        #[cfg(unix)]
+        let permissions = {
+            use std::os::unix::fs::MetadataExt;
+            EntryPermissions {
+                uid: Some(md.uid()),
+                gid: Some(md.gid()),
+                ..EntryPermissions::from_mode(md.mode())
+            }
+        };
+        #[cfg(not(unix))]
+        let permissions = EntryPermissions {
+            readonly: Some(md.permissions().readonly()),
+            ..EntryPermissions::default()
+        };
+        #[cfg(windows)]
+        let is_hidden = {
+            use std::os::windows::fs::MetadataExt;
+            const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+            super::is_hidden_name(path) || md.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0
+        };
+        #[cfg(not(windows))]
+        let is_hidden = super::is_hidden_name(path);
+
         Ok(EntryMetadata {
             kind,
             size_bytes,
             modified_at,
             created_at,
+            permissions,
+            is_hidden,
+            format: None,
         })
     }
 
-    async fn read(&self, path: &UniversalPath) -> Result<Vec<u8>, StorageError> {
+    async fn read_inner(&self, path: &UniversalPath) -> Result<Vec<u8>, StorageError> {
         use tokio::{fs::File, io::AsyncReadExt};
-        let pb = self.to_pathbuf(path)?;
+        let pb = self.resolve(path).await?;
         let mut file = File::open(pb).await.map_err(|e| match e.kind() {
             std::io::ErrorKind::NotFound => StorageError::NotFound,
             _ => StorageError::Io(e),
@@ -97,7 +395,7 @@ impl Storage for LocalStorage {
         Ok(buf)
     }
 
-    async fn read_range(
+    async fn read_range_inner(
         &self,
         path: &UniversalPath,
         range: std::ops::Range<u64>,
@@ -111,7 +409,7 @@ impl Storage for LocalStorage {
             return Ok(Vec::new());
         }
 
-        let pb = self.to_pathbuf(path)?;
+        let pb = self.resolve(path).await?;
         let mut file = File::open(pb).await.map_err(|e| match e.kind() {
             std::io::ErrorKind::NotFound => StorageError::NotFound,
             _ => StorageError::Io(e),
@@ -121,13 +419,8 @@ impl Storage for LocalStorage {
         if !md.is_file() {
             return Err(StorageError::NotAFile);
         }
-        let len = md.len();
-        if range.start >= len {
-            return Err(StorageError::RangeNotSatisfiable);
-        }
-
-        let end = range.end.min(len);
-        let to_read = (end - range.start) as usize;
+        let range = clamp_range(range, md.len())?;
+        let to_read = (range.end - range.start) as usize;
 
         file.seek(std::io::SeekFrom::Start(range.start)).await?;
         let mut buf = vec![0u8; to_read];
@@ -143,10 +436,10 @@ impl Storage for LocalStorage {
         Ok(buf)
     }
 
-    async fn list(&self, path: &UniversalPath) -> Result<Vec<UniversalPath>, StorageError> {
+    async fn list_inner(&self, path: &UniversalPath) -> Result<Vec<UniversalPath>, StorageError> {
         use tokio::fs;
-        let pb = self.to_pathbuf(path)?;
-        let md = fs::metadata(&pb).await?;
+        let pb = self.resolve(path).await?;
+        let md = fs::metadata(&pb).await.map_err(map_io_error)?;
         if !md.is_dir() {
             return Err(StorageError::NotADirectory);
         }
@@ -156,10 +449,421 @@ impl Storage for LocalStorage {
         while let Some(entry) = rd.next_entry().await? {
             let child_pb = entry.path();
             let display = child_pb.to_string_lossy().to_string();
-            entries.push(UniversalPath::local(display));
+            let child = UniversalPath::local(display);
+            let file_type = entry.file_type().await;
+            if self.links == LinkPolicy::Skip && file_type.as_ref().is_ok_and(|t| t.is_symlink()) {
+                continue;
+            }
+            let is_dir = file_type.map(|t| t.is_dir()).unwrap_or(false);
+            entries.push(if is_dir { child.as_dir() } else { child });
         }
         Ok(entries)
     }
+
+    /// Read from the directory handle as the stream is, each child stat'ed as `stat`
+    /// would
+    async fn list_stream_inner<'a>(
+        &'a self,
+        path: &UniversalPath,
+    ) -> Result<ListStream<'a>, StorageError> {
+        use futures::{StreamExt, stream};
+        use tokio::fs;
+        let pb = self.resolve(path).await?;
+        let md = fs::metadata(&pb).await.map_err(map_io_error)?;
+        if !md.is_dir() {
+            return Err(StorageError::NotADirectory);
+        }
+        let rd = fs::read_dir(&pb).await?;
+        let entries = stream::unfold(Some(rd), |rd| async move {
+            let mut rd = rd?;
+            match rd.next_entry().await {
+                Ok(Some(entry)) => Some((Ok(entry), Some(rd))),
+                Ok(None) => None,
+                Err(e) => Some((Err(e), None)),
+            }
+        });
+        let listing = entries
+            .map(move |entry| async move {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => return Some(Err(e.into())),
+                };
+                let child = UniversalPath::local(entry.path().to_string_lossy());
+                let is_link = entry.file_type().await.is_ok_and(|t| t.is_symlink());
+                if self.links == LinkPolicy::Skip && is_link {
+                    return None;
+                }
+                match self.stat_inner(&child, false).await {
+                    Ok(meta) if meta.kind == EntryKind::Directory => Some(Ok((child.as_dir(), meta))),
+                    Ok(meta) => Some(Ok((child, meta))),
+                    // A broken link, listed as the link itself so walks can say so
+                    Err(StorageError::NotFound) if is_link => {
+                        self.stat_inner(&child, true).await.ok().map(|meta| Ok((child, meta)))
+                    }
+                    // Gone since it was read
+                    Err(StorageError::NotFound) => None,
+                    Err(e) => Some(Err(e)),
+                }
+            })
+            .buffered(LIST_STAT_CONCURRENCY)
+            .filter_map(std::future::ready);
+        Ok(Box::pin(listing))
+    }
+
+    async fn read_suffix_inner(
+        &self,
+        path: &UniversalPath,
+        len: u64,
+    ) -> Result<Vec<u8>, StorageError> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        let mut file = self.open_file(path).await?;
+        let size = file.metadata().await?.len();
+        file.seek(std::io::SeekFrom::Start(size.saturating_sub(len)))
+            .await?;
+        let mut buf = Vec::with_capacity(len.min(size) as usize);
+        file.read_to_end(&mut buf).await?;
+        Ok(buf)
+    }
+
+    async fn symlink_target_inner(
+        &self,
+        path: &UniversalPath,
+    ) -> Result<Option<UniversalPath>, StorageError> {
+        let pb = self.to_pathbuf(path)?;
+        let md = tokio::fs::symlink_metadata(&pb).await.map_err(map_io_error)?;
+        if !md.file_type().is_symlink() {
+            return Ok(None);
+        }
+        let target = tokio::fs::canonicalize(&pb).await.map_err(map_io_error)?;
+        Ok(Some(UniversalPath::local(target.to_string_lossy())))
+    }
+
+    async fn open_file(&self, path: &UniversalPath) -> Result<tokio::fs::File, StorageError> {
+        let file = tokio::fs::File::open(self.resolve(path).await?)
+            .await
+            .map_err(map_io_error)?;
+        if !file.metadata().await?.is_file() {
+            return Err(StorageError::NotAFile);
+        }
+        Ok(file)
+    }
+
+    /// Writes go to a hidden sibling first and are renamed into place, so readers never
+    /// see a half-written file.
+    fn temp_path(pb: &Path) -> Result<PathBuf, StorageError> {
+        let name = pb.file_name().ok_or(StorageError::InvalidPath)?;
+        let mut temp = OsString::from(".");
+        temp.push(name);
+        temp.push(".otolith-part");
+        Ok(pb.with_file_name(temp))
+    }
+
+    async fn writable_target(&self, path: &UniversalPath) -> Result<PathBuf, StorageError> {
+        let pb = self.to_pathbuf(path)?;
+        if tokio::fs::metadata(&pb).await.is_ok_and(|md| md.is_dir()) {
+            return Err(StorageError::NotAFile);
+        }
+        Ok(pb)
+    }
+
+    async fn commit(temp: &Path, target: &Path) -> Result<(), StorageError> {
+        if let Err(e) = tokio::fs::rename(temp, target).await {
+            let _ = tokio::fs::remove_file(temp).await;
+            return Err(map_io_error(e));
+        }
+        Ok(())
+    }
+
+    async fn write_inner(&self, path: &UniversalPath, data: &[u8]) -> Result<(), StorageError> {
+        let pb = self.writable_target(path).await?;
+        let temp = Self::temp_path(&pb)?;
+        if let Err(e) = tokio::fs::write(&temp, data).await {
+            let _ = tokio::fs::remove_file(&temp).await;
+            return Err(map_io_error(e));
+        }
+        Self::commit(&temp, &pb).await
+    }
+
+    async fn write_stream_inner(
+        &self,
+        path: &UniversalPath,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> Result<u64, StorageError> {
+        use tokio::io::AsyncWriteExt;
+        let pb = self.writable_target(path).await?;
+        let temp = Self::temp_path(&pb)?;
+        let mut file = tokio::fs::File::create(&temp).await.map_err(map_io_error)?;
+        let copied = async {
+            let n = tokio::io::copy(reader, &mut file).await?;
+            file.flush().await?;
+            Ok::<_, std::io::Error>(n)
+        }
+        .await;
+        drop(file);
+        match copied {
+            Ok(n) => Self::commit(&temp, &pb).await.map(|_| n),
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&temp).await;
+                Err(StorageError::Io(e))
+            }
+        }
+    }
+
+    async fn create_dir_inner(&self, path: &UniversalPath) -> Result<(), StorageError> {
+        let pb = self.to_pathbuf(path)?;
+        tokio::fs::create_dir_all(&pb).await.map_err(|e| match e.kind() {
+            std::io::ErrorKind::AlreadyExists | std::io::ErrorKind::NotADirectory => {
+                StorageError::NotADirectory
+            }
+            _ => map_io_error(e),
+        })
+    }
+
+    async fn delete_inner(&self, path: &UniversalPath) -> Result<(), StorageError> {
+        let pb = self.to_pathbuf(path)?;
+        let md = tokio::fs::symlink_metadata(&pb).await.map_err(map_io_error)?;
+        if md.is_dir() {
+            tokio::fs::remove_dir(&pb).await.map_err(map_io_error)
+        } else {
+            tokio::fs::remove_file(&pb).await.map_err(map_io_error)
+        }
+    }
+
+    async fn rename_inner(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+    ) -> Result<(), StorageError> {
+        let (source, target) = (self.to_pathbuf(from)?, self.to_pathbuf(to)?);
+        let md = tokio::fs::symlink_metadata(&source).await.map_err(map_io_error)?;
+        if tokio::fs::symlink_metadata(&target).await.is_ok() {
+            return Err(StorageError::AlreadyExists);
+        }
+        match tokio::fs::rename(&source, &target).await {
+            Ok(()) => Ok(()),
+            // Moving a file to another filesystem: copy it over, then drop the original
+            Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices && md.is_file() => {
+                let temp = Self::temp_path(&target)?;
+                if let Err(e) = tokio::fs::copy(&source, &temp).await {
+                    let _ = tokio::fs::remove_file(&temp).await;
+                    return Err(map_io_error(e));
+                }
+                Self::commit(&temp, &target).await?;
+                tokio::fs::remove_file(&source).await.map_err(map_io_error)
+            }
+            Err(e) => Err(map_io_error(e)),
+        }
+    }
+
+    /// Copied to a temporary file beside `to` first, like a write
+    async fn copy_inner(&self, from: &UniversalPath, to: &UniversalPath) -> Result<u64, StorageError> {
+        let source = self.resolve(from).await?;
+        if !tokio::fs::metadata(&source).await.map_err(map_io_error)?.is_file() {
+            return Err(StorageError::NotAFile);
+        }
+        let target = self.writable_target(to).await?;
+        let temp = Self::temp_path(&target)?;
+        match tokio::fs::copy(&source, &temp).await {
+            Ok(n) => Self::commit(&temp, &target).await.map(|_| n),
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&temp).await;
+                Err(map_io_error(e))
+            }
+        }
+    }
+
+    async fn set_modified_inner(
+        &self,
+        path: &UniversalPath,
+        modified_at: SystemTime,
+    ) -> Result<(), StorageError> {
+        let pb = self.resolve(path).await?;
+        if !tokio::fs::metadata(&pb).await.map_err(map_io_error)?.is_file() {
+            return Err(StorageError::NotAFile);
+        }
+        let file = tokio::fs::File::options()
+            .write(true)
+            .open(&pb)
+            .await
+            .map_err(map_io_error)?
+            .into_std()
+            .await;
+        tokio::task::spawn_blocking(move || file.set_modified(modified_at))
+            .await
+            .map_err(|e| StorageError::Io(std::io::Error::other(e)))?
+            .map_err(map_io_error)
+    }
+
+    #[cfg(unix)]
+    async fn attribute_inner(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        let (pb, name) = (self.to_pathbuf(path)?, name.to_string());
+        tokio::task::spawn_blocking(move || xattr::get(pb, name))
+            .await
+            .map_err(|e| StorageError::Io(std::io::Error::other(e)))?
+            .map_err(map_xattr_error)
+    }
+
+    #[cfg(unix)]
+    async fn set_attribute_inner(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+        value: &[u8],
+    ) -> Result<(), StorageError> {
+        let (pb, name, value) = (self.to_pathbuf(path)?, name.to_string(), value.to_vec());
+        tokio::task::spawn_blocking(move || xattr::set(pb, name, &value))
+            .await
+            .map_err(|e| StorageError::Io(std::io::Error::other(e)))?
+            .map_err(map_xattr_error)
+    }
+
+    #[cfg(not(unix))]
+    async fn attribute_inner(
+        &self,
+        _path: &UniversalPath,
+        _name: &str,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        Err(StorageError::UnsupportedFeature("attributes"))
+    }
+
+    #[cfg(not(unix))]
+    async fn set_attribute_inner(
+        &self,
+        _path: &UniversalPath,
+        _name: &str,
+        _value: &[u8],
+    ) -> Result<(), StorageError> {
+        Err(StorageError::UnsupportedFeature("attributes"))
+    }
+
+    /// Create `path` holding `data`, failing with `AlreadyExists` if it is there. The
+    /// data goes to a temp file that is then hard-linked into place, so nobody sees
+    /// the file half-written.
+    async fn create_new_inner(&self, path: UniversalPath, data: Vec<u8>) -> Result<(), StorageError> {
+        static NEXT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        let target = self.to_pathbuf(&path)?;
+        // Unique per writer, so contenders never write into each other's temp file
+        let mut temp = Self::temp_path(&target)?.into_os_string();
+        temp.push(format!(
+            "-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        ));
+        tokio::fs::write(&temp, &data).await.map_err(map_io_error)?;
+        let linked = tokio::fs::hard_link(&temp, &target).await;
+        let _ = tokio::fs::remove_file(&temp).await;
+        linked.map_err(map_io_error)
+    }
 }
 
+/// Filesystems without user extended attributes (FAT, some network mounts) report
+/// `Unsupported`
+#[cfg(unix)]
+fn map_xattr_error(e: std::io::Error) -> StorageError {
+    match e.kind() {
+        std::io::ErrorKind::Unsupported => StorageError::UnsupportedFeature("attributes"),
+        _ => map_io_error(e),
+    }
+}
 
+fn map_io_error(e: std::io::Error) -> StorageError {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => StorageError::NotFound,
+        std::io::ErrorKind::AlreadyExists => StorageError::AlreadyExists,
+        _ => StorageError::Io(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+
+    #[tokio::test]
+    async fn test_write_rename_delete() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let root = UniversalPath::local(dir.to_str().unwrap()).as_dir();
+        let album = root.join("Björk").join("Post").as_dir();
+        let local = LocalStorage::new();
+        local.create_dir(&album).await.unwrap();
+        local.create_dir(&album).await.unwrap();
+
+        let track = album.join("01 Army of Me.flac");
+        local.write(&track, b"first").await.unwrap();
+        let mut reader: &[u8] = b"second take";
+        assert_eq!(local.write_stream(&track, &mut reader).await.unwrap(), 11);
+        assert_eq!(local.read(&track).await.unwrap(), b"second take");
+        assert_eq!(local.read_suffix(&track, 4).await.unwrap(), b"take");
+        assert_eq!(local.read_suffix(&track, 64).await.unwrap(), b"second take");
+        assert_eq!(local.list(&album).await.unwrap().len(), 1);
+        let meta = local.stat(&track).await.unwrap();
+        assert!(!meta.is_hidden);
+        assert!(meta.permissions.readonly.is_some());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let file = local.to_pathbuf(&track).unwrap();
+            std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o444)).unwrap();
+            let permissions = local.stat(&track).await.unwrap().permissions;
+            assert_eq!(permissions.mode, Some(0o444));
+            assert_eq!(permissions.readonly, Some(true));
+            assert!(permissions.uid.is_some());
+            std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o644)).unwrap();
+        }
+        assert!(matches!(
+            local.create_dir(&track).await,
+            Err(StorageError::NotADirectory)
+        ));
+
+        let renamed = album.join("01 - Army of Me.flac");
+        local.rename(&track, &renamed).await.unwrap();
+        local.write(&track, b"again").await.unwrap();
+        assert!(matches!(
+            local.rename(&track, &renamed).await,
+            Err(StorageError::AlreadyExists)
+        ));
+
+        local.delete(&track).await.unwrap();
+        local.delete(&renamed).await.unwrap();
+        local.delete(&album).await.unwrap();
+        assert!(matches!(
+            local.delete(&album).await,
+            Err(StorageError::NotFound)
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_link_policies() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        std::fs::write(dir.join("01.flac"), b"flac").unwrap();
+        std::os::unix::fs::symlink(dir.join("01.flac"), dir.join("link.flac")).unwrap();
+        let root = UniversalPath::local(dir.to_str().unwrap()).as_dir();
+        let link = root.join("link.flac");
+
+        let follow = LocalStorage::new();
+        assert_eq!(follow.stat(&link).await.unwrap().kind, EntryKind::File);
+        let lstat = CallOptions::default().with_no_follow();
+        let meta = follow.stat_opts(&link, &lstat).await.unwrap();
+        assert_eq!(meta.kind, EntryKind::Symlink);
+        assert_eq!(follow.list(&root).await.unwrap().len(), 2);
+
+        let skip = LocalStorage::new().with_links(LinkPolicy::Skip);
+        assert_eq!(skip.list(&root).await.unwrap(), [root.join("01.flac")]);
+        let streamed: Vec<_> = skip.list_stream(&root).await.unwrap().try_collect().await.unwrap();
+        assert_eq!(streamed.len(), 1);
+        assert_eq!(streamed[0].0, root.join("01.flac"));
+        assert_eq!(streamed[0].1.size_bytes, Some(4));
+        assert!(matches!(skip.read(&link).await, Err(StorageError::NotFound)));
+        let error = LocalStorage::new().with_links(LinkPolicy::Error);
+        assert!(matches!(error.stat(&link).await, Err(StorageError::IsSymlink)));
+        assert_eq!(error.read(&root.join("01.flac")).await.unwrap(), b"flac");
+    }
+}
@@ -0,0 +1,269 @@
+use super::{CallOptions, EntryKind, Storage, StorageError};
+use crate::universal_path::UniversalPath;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Name of the lock file inside a locked directory.
+pub const LOCK_FILE_NAME: &str = ".otolith.lock";
+
+/// Who holds a lock and until when, as stored in the lock file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockInfo {
+    pub host: String,
+    pub pid: u32,
+    /// Tells this holder's lock apart from a later one on the same path
+    pub token: String,
+    pub acquired_at: SystemTime,
+    pub expires_at: SystemTime,
+}
+
+impl LockInfo {
+    fn new(ttl: Duration) -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let now = SystemTime::now();
+        let nanos = now
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let pid = std::process::id();
+        LockInfo {
+            host: hostname(),
+            pid,
+            token: format!(
+                "{pid:x}-{nanos:x}-{:x}",
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ),
+            acquired_at: now,
+            expires_at: now + ttl,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= SystemTime::now()
+    }
+
+    /// `host:pid`, for messages
+    pub fn owner(&self) -> String {
+        format!("{}:{}", self.host, self.pid)
+    }
+}
+
+/// An advisory lock held through `Storage::acquire_lock`. Dropping it does not
+/// release it; it lapses at `info.expires_at` unless released or renewed first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageLock {
+    /// Where the lock file lives
+    pub path: UniversalPath,
+    pub info: LockInfo,
+}
+
+/// Creates the lock file with the given contents, failing with `AlreadyExists` if
+/// there is one
+pub(crate) type CreateExclusive<'a> = Box<
+    dyn Fn(UniversalPath, Vec<u8>) -> BoxFuture<'a, Result<(), StorageError>> + Send + Sync + 'a,
+>;
+
+/// The lock file for `path`: inside it for a directory, otherwise a hidden sibling
+async fn lock_path<S: Storage + ?Sized>(
+    storage: &S,
+    path: &UniversalPath,
+    opts: &CallOptions,
+) -> Result<UniversalPath, StorageError> {
+    let is_dir = path.is_dir_hint()
+        || match storage.stat_opts(path, opts).await {
+            Ok(meta) => meta.kind == EntryKind::Directory,
+            Err(StorageError::NotFound) => false,
+            Err(e) => return Err(e),
+        };
+    if is_dir {
+        return Ok(path.as_dir().join(LOCK_FILE_NAME));
+    }
+    let name = path.last_segment().ok_or(StorageError::InvalidPath)?;
+    let parent = path.parent().ok_or(StorageError::InvalidPath)?;
+    Ok(parent.join(format!(".{name}.otolith.lock")))
+}
+
+async fn read_lock<S: Storage + ?Sized>(
+    storage: &S,
+    path: &UniversalPath,
+    opts: &CallOptions,
+) -> Result<Option<LockInfo>, StorageError> {
+    let bytes = match storage.read_opts(path, opts).await {
+        Ok(bytes) => bytes,
+        Err(StorageError::NotFound) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    // A lock file left unreadable by a crash mid-write holds nothing
+    Ok(serde_json::from_slice(&bytes).ok())
+}
+
+/// Exclusive creation out of `write` and `rename`: write a uniquely named file, then
+/// move it into place, which the `rename` contract refuses to do over an existing
+/// file.
+pub(crate) fn create_by_rename<'a, S: Storage + ?Sized>(
+    storage: &'a S,
+    opts: &'a CallOptions,
+) -> CreateExclusive<'a> {
+    Box::new(move |path: UniversalPath, bytes: Vec<u8>| {
+        Box::pin(async move {
+            let name = path.last_segment().ok_or(StorageError::InvalidPath)?;
+            let parent = path.parent().ok_or(StorageError::InvalidPath)?;
+            let temp = parent.join(format!("{name}.{}", LockInfo::new(Duration::ZERO).token));
+            storage.write_opts(&temp, &bytes, opts).await?;
+            let moved = storage.rename_opts(&temp, &path, opts).await;
+            if moved.is_err() {
+                let _ = storage.delete_opts(&temp, opts).await;
+            }
+            moved
+        })
+    })
+}
+
+/// Take the lock on `path` with `create`, clearing an expired holder out of the way
+pub(crate) async fn acquire<S: Storage + ?Sized>(
+    storage: &S,
+    path: &UniversalPath,
+    ttl: Duration,
+    opts: &CallOptions,
+    create: CreateExclusive<'_>,
+) -> Result<StorageLock, StorageError> {
+    let path = lock_path(storage, path, opts).await?;
+    let mut holder = None;
+    // Each retry follows a holder going away, so this only gives up under contention
+    for _ in 0..3 {
+        let info = LockInfo::new(ttl);
+        let bytes = serde_json::to_vec(&info).expect("lock info serializes");
+        match create(path.clone(), bytes).await {
+            Ok(()) => return Ok(StorageLock { path, info }),
+            Err(StorageError::AlreadyExists) => {}
+            Err(e) => return Err(e),
+        }
+        match read_lock(storage, &path, opts).await? {
+            Some(held) if !held.is_expired() => return Err(StorageError::Locked(Box::new(held))),
+            held => {
+                holder = held;
+                match storage.delete_opts(&path, opts).await {
+                    Ok(()) | Err(StorageError::NotFound) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+    Err(match holder {
+        Some(held) => StorageError::Locked(Box::new(held)),
+        None => StorageError::AlreadyExists,
+    })
+}
+
+/// The current lock file, if it is still `lock`'s
+async fn check_held<S: Storage + ?Sized>(
+    storage: &S,
+    lock: &StorageLock,
+    opts: &CallOptions,
+) -> Result<bool, StorageError> {
+    match read_lock(storage, &lock.path, opts).await? {
+        Some(held) if held.token == lock.info.token => Ok(true),
+        Some(held) if !held.is_expired() => Err(StorageError::Locked(Box::new(held))),
+        _ => Ok(false),
+    }
+}
+
+pub(crate) async fn renew<S: Storage + ?Sized>(
+    storage: &S,
+    lock: &StorageLock,
+    ttl: Duration,
+    opts: &CallOptions,
+) -> Result<StorageLock, StorageError> {
+    if !check_held(storage, lock, opts).await? {
+        return Err(StorageError::NotFound);
+    }
+    let mut renewed = lock.clone();
+    renewed.info.expires_at = SystemTime::now() + ttl;
+    let bytes = serde_json::to_vec(&renewed.info).expect("lock info serializes");
+    storage.write_opts(&lock.path, &bytes, opts).await?;
+    Ok(renewed)
+}
+
+pub(crate) async fn release<S: Storage + ?Sized>(
+    storage: &S,
+    lock: &StorageLock,
+    opts: &CallOptions,
+) -> Result<(), StorageError> {
+    if !check_held(storage, lock, opts).await? {
+        // Lapsed and gone, or taken over after lapsing: nothing of ours to remove
+        return Ok(());
+    }
+    match storage.delete_opts(&lock.path, opts).await {
+        Ok(()) | Err(StorageError::NotFound) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{LocalStorage, SmbMount, SmbStorage};
+
+    async fn exercise(storage: &dyn Storage, root: &UniversalPath) {
+        let ttl = Duration::from_secs(60);
+        let lock = storage.acquire_lock(root, ttl).await.unwrap();
+        assert_eq!(lock.path.last_segment(), Some(LOCK_FILE_NAME));
+        match storage.acquire_lock(root, ttl).await {
+            Err(StorageError::Locked(held)) => assert_eq!(held.token, lock.info.token),
+            other => panic!("second lock taken: {other:?}"),
+        }
+        let renewed = storage.renew_lock(&lock, ttl * 2).await.unwrap();
+        assert!(renewed.info.expires_at > lock.info.expires_at);
+        storage.release_lock(&renewed).await.unwrap();
+
+        // An expired lock is taken over, and its old holder cannot release the new one
+        let stale = storage.acquire_lock(root, Duration::ZERO).await.unwrap();
+        let fresh = storage.acquire_lock(root, ttl).await.unwrap();
+        assert!(matches!(
+            storage.release_lock(&stale).await,
+            Err(StorageError::Locked(_))
+        ));
+        storage.release_lock(&fresh).await.unwrap();
+        assert!(matches!(
+            storage.stat(&fresh.path).await,
+            Err(StorageError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_lock_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let root = UniversalPath::local(dir.to_str().unwrap());
+        // Local locks use exclusive creation, SMB the generic write and rename
+        exercise(&LocalStorage::new(), &root).await;
+        let smb = SmbStorage::new(vec![SmbMount::new("nas", "music", dir)]);
+        exercise(
+            &smb,
+            &UniversalPath::from_uri_str("smb://nas/music/").unwrap(),
+        )
+        .await;
+
+        let track = root.join("01.flac");
+        let lock = LocalStorage::new()
+            .acquire_lock(&track, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(lock.path.last_segment(), Some(".01.flac.otolith.lock"));
+        LocalStorage::new().release_lock(&lock).await.unwrap();
+    }
+}
@@ -0,0 +1,334 @@
+use super::{
+    CredentialProvider, Storage, StorageError, StorageRegistry, WakeConfig, WakingStorage,
+    provider_for, registry,
+};
+use crate::universal_path::UniversalPath;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// What makes two paths able to share a handle: the same server, reached as the
+/// same account with the same secrets.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct HandleKey {
+    scheme: String,
+    host: Option<String>,
+    port: Option<u16>,
+    user: Option<String>,
+    /// SHA-256 of the credentials used, so no secret is kept in the key itself
+    credentials: Option<String>,
+}
+
+impl HandleKey {
+    fn new(path: &UniversalPath, provider: &dyn CredentialProvider) -> Self {
+        let credentials = match path.password() {
+            Some(password) => Some(hex::encode(Sha256::digest(password))),
+            None => provider.credentials_for(path).map(|credentials| {
+                let json = serde_json::to_vec(&credentials).expect("credentials serialize");
+                hex::encode(Sha256::digest(json))
+            }),
+        };
+        HandleKey {
+            scheme: path.backend().to_scheme().to_string(),
+            host: path.host().map(str::to_lowercase),
+            port: path.port(),
+            user: path.user().map(String::from),
+            credentials,
+        }
+    }
+}
+
+struct Cached {
+    storage: Arc<dyn Storage>,
+    last_used: Instant,
+    last_checked: Instant,
+}
+
+/// Keeps storage handles open between uses, so paths on the same server share one
+/// connection and login instead of each `open_storage_for` making its own.
+///
+/// A handle unused for the idle timeout is dropped. One that has not been checked
+/// for the health interval is probed with a `stat` before being handed out again,
+/// and replaced if the probe fails to reach the server.
+///
+/// Hosts given a `WakeConfig` are woken before their first call, and again after
+/// they have been left alone long enough to fall asleep; see `WakingStorage`.
+pub struct StorageManager {
+    /// None for the process-wide registry
+    registry: Option<StorageRegistry>,
+    idle_timeout: Duration,
+    health_interval: Duration,
+    /// By lowercase host
+    wake: HashMap<String, WakeConfig>,
+    handles: Mutex<HashMap<HandleKey, Cached>>,
+}
+
+/// What a probe of a storage's server found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HostHealth {
+    /// The server answered, even if only that the path is missing
+    Healthy,
+    /// No route to the host or no answer in time, as from one that is asleep
+    PossiblyAsleep,
+    /// The connection failed some other way
+    Unreachable,
+}
+
+impl Default for StorageManager {
+    fn default() -> Self {
+        StorageManager::new()
+    }
+}
+
+impl StorageManager {
+    /// Handles opened through the process-wide registry, dropped after 5 minutes idle
+    /// and checked at most once a minute
+    pub fn new() -> Self {
+        StorageManager {
+            registry: None,
+            idle_timeout: Duration::from_secs(300),
+            health_interval: Duration::from_secs(60),
+            wake: HashMap::new(),
+            handles: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_registry(mut self, registry: StorageRegistry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn with_health_interval(mut self, health_interval: Duration) -> Self {
+        self.health_interval = health_interval;
+        self
+    }
+
+    /// Wake `host` as `config` says before using it
+    pub fn with_wake_on_lan(mut self, host: &str, config: WakeConfig) -> Self {
+        self.wake.insert(host.to_lowercase(), config);
+        self
+    }
+
+    /// A handle for `path`, logging in like `open_storage_for` when a new one is needed
+    pub async fn open(&self, path: &UniversalPath) -> Result<Arc<dyn Storage>, StorageError> {
+        self.open_with(path, &*provider_for(path)?).await
+    }
+
+    /// A handle for `path` with credentials from `provider`. Handles are only shared
+    /// between paths that would log in the same way.
+    pub async fn open_with(
+        &self,
+        path: &UniversalPath,
+        provider: &dyn CredentialProvider,
+    ) -> Result<Arc<dyn Storage>, StorageError> {
+        let key = HandleKey::new(path, provider);
+        let now = Instant::now();
+        let cached = {
+            let mut handles = self.handles.lock().unwrap();
+            self.evict_locked(&mut handles, now);
+            handles.get_mut(&key).map(|cached| {
+                cached.last_used = now;
+                let due = now.duration_since(cached.last_checked) >= self.health_interval;
+                (cached.storage.clone(), due)
+            })
+        };
+        match cached {
+            Some((storage, false)) => return Ok(storage),
+            Some((storage, true)) => {
+                if check_health(&*storage, path).await == HostHealth::Healthy {
+                    if let Some(cached) = self.handles.lock().unwrap().get_mut(&key) {
+                        cached.last_checked = Instant::now();
+                    }
+                    return Ok(storage);
+                }
+                self.handles.lock().unwrap().remove(&key);
+            }
+            None => {}
+        }
+
+        let mut storage: Arc<dyn Storage> = match &self.registry {
+            Some(registry) => registry.open_with(path, provider)?,
+            None => registry::open_global(path, provider)?,
+        }
+        .into();
+        let wake = path.host().and_then(|host| self.wake.get(&host.to_lowercase()));
+        if let Some(config) = wake {
+            storage = Arc::new(WakingStorage::new(storage, config.clone(), path.clone()));
+        }
+        let now = Instant::now();
+        // Another caller may have opened one meanwhile; keep the first
+        let mut handles = self.handles.lock().unwrap();
+        let cached = handles.entry(key).or_insert(Cached {
+            storage,
+            last_used: now,
+            last_checked: now,
+        });
+        Ok(cached.storage.clone())
+    }
+
+    /// Drop every handle idle for longer than the timeout, returning how many went
+    pub fn evict_idle(&self) -> usize {
+        let mut handles = self.handles.lock().unwrap();
+        self.evict_locked(&mut handles, Instant::now())
+    }
+
+    fn evict_locked(&self, handles: &mut HashMap<HandleKey, Cached>, now: Instant) -> usize {
+        let before = handles.len();
+        handles.retain(|_, cached| now.duration_since(cached.last_used) < self.idle_timeout);
+        before - handles.len()
+    }
+
+    /// Number of handles held
+    pub fn len(&self) -> usize {
+        self.handles.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drop every handle, e.g. after credentials were rotated
+    pub fn clear(&self) {
+        self.handles.lock().unwrap().clear();
+    }
+}
+
+/// Whether a `stat` of `path` through `storage` still reaches its server
+pub async fn check_health(storage: &dyn Storage, path: &UniversalPath) -> HostHealth {
+    match storage.stat(path).await {
+        Err(e) if e.may_be_asleep() => HostHealth::PossiblyAsleep,
+        Err(StorageError::Connection(_) | StorageError::Timeout(_) | StorageError::Io(_)) => {
+            HostHealth::Unreachable
+        }
+        _ => HostHealth::Healthy,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{
+        CallOptions, CredentialStore, Credentials, EntryMetadata, MemoryStorage, StorageBackend,
+        StorageCapabilities,
+    };
+    use async_trait::async_trait;
+    use std::{
+        ops::Range,
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    };
+
+    /// Memory that can lose its connection
+    struct Flaky {
+        inner: MemoryStorage,
+        up: Arc<AtomicBool>,
+    }
+
+    impl Flaky {
+        fn check(&self) -> Result<(), StorageError> {
+            match self.up.load(Ordering::SeqCst) {
+                true => Ok(()),
+                false => Err(StorageError::Connection("gone".into())),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Storage for Flaky {
+        fn backend(&self) -> StorageBackend {
+            StorageBackend::Memory
+        }
+
+        fn capabilities(&self) -> StorageCapabilities {
+            self.inner.capabilities()
+        }
+
+        async fn stat_opts(
+            &self,
+            path: &UniversalPath,
+            opts: &CallOptions,
+        ) -> Result<EntryMetadata, StorageError> {
+            self.check()?;
+            self.inner.stat_opts(path, opts).await
+        }
+
+        async fn read_opts(
+            &self,
+            path: &UniversalPath,
+            opts: &CallOptions,
+        ) -> Result<Vec<u8>, StorageError> {
+            self.check()?;
+            self.inner.read_opts(path, opts).await
+        }
+
+        async fn read_range_opts(
+            &self,
+            path: &UniversalPath,
+            range: Range<u64>,
+            opts: &CallOptions,
+        ) -> Result<Vec<u8>, StorageError> {
+            self.check()?;
+            self.inner.read_range_opts(path, range, opts).await
+        }
+
+        async fn list_opts(
+            &self,
+            path: &UniversalPath,
+            opts: &CallOptions,
+        ) -> Result<Vec<UniversalPath>, StorageError> {
+            self.check()?;
+            self.inner.list_opts(path, opts).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handles_are_shared_and_replaced() {
+        let opened = Arc::new(AtomicUsize::new(0));
+        let up = Arc::new(AtomicBool::new(true));
+        let mut registry = StorageRegistry::empty();
+        let (counter, link) = (opened.clone(), up.clone());
+        registry.register("mem", move |_, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            link.store(true, Ordering::SeqCst);
+            Ok(Box::new(Flaky {
+                inner: MemoryStorage::new(),
+                up: link.clone(),
+            }))
+        });
+        let manager = StorageManager::new()
+            .with_registry(registry)
+            .with_health_interval(Duration::ZERO);
+        let nas = UniversalPath::from_uri_str("mem://nas/music/a.flac").unwrap();
+        let none = CredentialStore::new();
+
+        let first = manager.open_with(&nas, &none).await.unwrap();
+        let again = manager
+            .open_with(&nas.parent().unwrap(), &none)
+            .await
+            .unwrap();
+        assert!(Arc::ptr_eq(&first, &again));
+        // Another account on the same server gets its own handle
+        let login = CredentialStore::new().with("mem", "nas", Credentials::password("dj", "pw"));
+        manager.open_with(&nas, &login).await.unwrap();
+        assert_eq!((opened.load(Ordering::SeqCst), manager.len()), (2, 2));
+
+        // A handle that cannot reach its server is replaced
+        up.store(false, Ordering::SeqCst);
+        let fresh = manager.open_with(&nas, &none).await.unwrap();
+        assert!(!Arc::ptr_eq(&first, &fresh));
+        assert_eq!(opened.load(Ordering::SeqCst), 3);
+
+        let manager = manager.with_idle_timeout(Duration::ZERO);
+        assert_eq!(manager.evict_idle(), 2);
+        assert!(manager.is_empty());
+    }
+}
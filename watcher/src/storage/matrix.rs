@@ -0,0 +1,224 @@
+#[cfg(feature = "ftp")]
+use super::ftp;
+#[cfg(feature = "http")]
+use super::http;
+#[cfg(feature = "s3")]
+use super::s3;
+#[cfg(feature = "sftp")]
+use super::sftp;
+#[cfg(feature = "webdav")]
+use super::webdav;
+use super::{StorageBackend, StorageCapabilities, local, memory};
+use serde::Serialize;
+
+/// A way a backend can prove who the caller is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    /// Nothing beyond what the OS already grants the process
+    None,
+    /// An anonymous login the server accepts from anyone
+    Anonymous,
+    Password,
+    /// A private key file, e.g. `~/.ssh/id_ed25519`
+    KeyFile,
+    /// Identities offered by the SSH agent
+    SshAgent,
+    /// An access key pair, optionally with a session token
+    AccessKey,
+    /// Whatever the OS used to mount the share
+    OsMount,
+}
+
+/// What one backend supports, as data for `otolith backends` and for UIs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BackendInfo {
+    pub backend: StorageBackend,
+    /// URI schemes that map to the backend, preferred first
+    pub schemes: &'static [&'static str],
+    pub capabilities: StorageCapabilities,
+    /// Operations done in one round trip or natively rather than by the trait's default
+    /// built on the required methods
+    pub native: &'static [&'static str],
+    pub auth: &'static [AuthMethod],
+    pub limitations: &'static [&'static str],
+}
+
+impl StorageCapabilities {
+    /// Names of the supported operations, in trait order
+    pub fn operations(&self) -> Vec<&'static str> {
+        [
+            (self.can_stat, "stat"),
+            (self.can_read, "read"),
+            (self.can_read_range, "read_range"),
+            (self.can_list, "list"),
+            (self.can_glob, "glob"),
+            (self.can_write, "write"),
+            (self.can_delete, "delete"),
+            (self.can_rename, "rename"),
+        ]
+        .into_iter()
+        .filter_map(|(supported, name)| supported.then_some(name))
+        .collect()
+    }
+}
+
+/// Every backend `open_storage_for` can return in this build, with its capabilities,
+/// how it authenticates and where it falls short.
+pub fn capability_matrix() -> Vec<BackendInfo> {
+    // Only pushed to when an optional backend is compiled in
+    #[allow(unused_mut)]
+    let mut matrix = vec![
+        BackendInfo {
+            backend: StorageBackend::Local,
+            schemes: &["file"],
+            capabilities: local::CAPABILITIES,
+            native: if cfg!(feature = "watch") {
+                &["read_suffix", "read_stream", "watch"]
+            } else {
+                &["read_suffix", "read_stream"]
+            },
+            auth: &[AuthMethod::None],
+            limitations: &[],
+        },
+        BackendInfo {
+            backend: StorageBackend::NetworkDrive,
+            schemes: &["smb", "cifs"],
+            capabilities: local::CAPABILITIES,
+            native: &["read_suffix", "read_stream"],
+            auth: &[AuthMethod::OsMount],
+            limitations: &["only shares the OS has already mounted, found in /proc/mounts"],
+        },
+        BackendInfo {
+            backend: StorageBackend::Memory,
+            schemes: &["mem", "memory"],
+            capabilities: memory::CAPABILITIES,
+            native: &[],
+            auth: &[AuthMethod::None],
+            limitations: &[
+                "contents last only as long as the process",
+                "the host names the store, shared by every path with it",
+            ],
+        },
+    ];
+    #[cfg(feature = "sftp")]
+    matrix.push(BackendInfo {
+        backend: StorageBackend::Sftp,
+        schemes: &["sftp"],
+        capabilities: sftp::CAPABILITIES,
+        native: &[],
+        auth: &[
+            AuthMethod::SshAgent,
+            AuthMethod::KeyFile,
+            AuthMethod::Password,
+        ],
+        limitations: &[
+            "read-only",
+            "without stored credentials, the user name comes from $USER and the key from the agent or ~/.ssh",
+        ],
+    });
+    #[cfg(feature = "ftp")]
+    matrix.push(BackendInfo {
+        backend: StorageBackend::Ftp,
+        schemes: &["ftp"],
+        capabilities: ftp::CAPABILITIES,
+        native: &[],
+        auth: &[AuthMethod::Anonymous, AuthMethod::Password],
+        limitations: &[
+            "read-only",
+            "plain FTP only, no FTPS",
+            "listings fall back to LIST parsing where MLSD is missing",
+        ],
+    });
+    #[cfg(feature = "s3")]
+    matrix.push(BackendInfo {
+        backend: StorageBackend::S3,
+        schemes: &["s3"],
+        capabilities: s3::CAPABILITIES,
+        native: &["read_suffix", "checksum"],
+        auth: &[AuthMethod::AccessKey, AuthMethod::Anonymous],
+        limitations: &[
+            "read-only",
+            "directories are key prefixes",
+            "region and endpoint come from the AWS_* environment",
+        ],
+    });
+    #[cfg(feature = "http")]
+    matrix.push(BackendInfo {
+        backend: StorageBackend::Http,
+        schemes: &["http"],
+        capabilities: http::CAPABILITIES,
+        native: &["read_ranges", "read_suffix"],
+        auth: &[AuthMethod::None],
+        limitations: &[
+            "read-only",
+            "no directory listing",
+            "ranges need server support; otherwise the whole file is fetched",
+        ],
+    });
+    #[cfg(feature = "http")]
+    matrix.push(BackendInfo {
+        backend: StorageBackend::Https,
+        schemes: &["https"],
+        capabilities: http::CAPABILITIES,
+        native: &["read_ranges", "read_suffix"],
+        auth: &[AuthMethod::None],
+        limitations: &[
+            "read-only",
+            "no directory listing",
+            "ranges need server support; otherwise the whole file is fetched",
+        ],
+    });
+    #[cfg(feature = "webdav")]
+    for (backend, schemes) in [
+        (StorageBackend::WebDav, &["dav", "webdav"]),
+        (StorageBackend::WebDavs, &["davs", "webdavs"]),
+    ] {
+        matrix.push(BackendInfo {
+            backend,
+            schemes,
+            capabilities: webdav::CAPABILITIES,
+            native: &[],
+            auth: &[AuthMethod::None, AuthMethod::Password],
+            limitations: &[
+                "Basic authentication only, no Digest",
+                "streamed writes are buffered in memory before the PUT",
+            ],
+        });
+    }
+    matrix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::open_storage_for, universal_path::UniversalPath};
+
+    #[test]
+    fn test_matrix_matches_backends() {
+        let matrix = capability_matrix();
+        for info in &matrix {
+            for scheme in info.schemes {
+                assert_eq!(StorageBackend::from_scheme(scheme), info.backend);
+            }
+        }
+        // Capabilities agree with what the storages themselves report
+        let local = open_storage_for(&UniversalPath::local("/")).unwrap();
+        assert_eq!(matrix[0].capabilities, local.capabilities());
+        assert_eq!(
+            matrix[0].capabilities.operations(),
+            [
+                "stat",
+                "read",
+                "read_range",
+                "list",
+                "glob",
+                "write",
+                "delete",
+                "rename"
+            ]
+        );
+        let json = serde_json::to_value(&matrix[1]).unwrap();
+        assert_eq!(json["auth"][0], "os_mount");
+    }
+}
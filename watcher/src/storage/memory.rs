@@ -0,0 +1,452 @@
+use super::{
+    CallOptions, EntryKind, EntryMetadata, EntryPermissions, Storage, StorageBackend, StorageCapabilities,
+    StorageError, clamp_range,
+};
+use crate::universal_path::UniversalPath;
+use async_trait::async_trait;
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::{Bound, Range},
+    sync::{Arc, Mutex, MutexGuard, OnceLock},
+    time::SystemTime,
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub(crate) const CAPABILITIES: StorageCapabilities = StorageCapabilities {
+    can_stat: true,
+    can_read: true,
+    can_read_range: true,
+    can_list: true,
+    can_glob: true,
+    can_write: true,
+    can_delete: true,
+    can_rename: true,
+    can_checksum: false,
+};
+
+#[derive(Debug, Clone)]
+struct Node {
+    /// None for a directory
+    data: Option<Vec<u8>>,
+    created: SystemTime,
+    modified: SystemTime,
+    attributes: BTreeMap<String, Vec<u8>>,
+}
+
+impl Node {
+    fn new(data: Option<Vec<u8>>) -> Self {
+        let now = SystemTime::now();
+        Node {
+            data,
+            created: now,
+            modified: now,
+            attributes: BTreeMap::new(),
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        self.data.is_none()
+    }
+}
+
+/// Every node by its path segments. Sorting by segments keeps a directory's
+/// descendants right after it.
+type Tree = BTreeMap<Vec<String>, Node>;
+
+/// A tree of files held in memory, for tests, examples and dry runs. Paths are looked
+/// up by their segments alone, so any backend's paths can be used; `mem://name/...`
+/// URIs open the store shared under `name` through `open_storage_for`.
+///
+/// Clones share the same tree.
+#[derive(Debug, Clone)]
+pub struct MemoryStorage {
+    tree: Arc<Mutex<Tree>>,
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        MemoryStorage::new()
+    }
+}
+
+impl MemoryStorage {
+    /// An empty store holding only the root directory
+    pub fn new() -> Self {
+        let tree = BTreeMap::from([(Vec::new(), Node::new(None))]);
+        MemoryStorage {
+            tree: Arc::new(Mutex::new(tree)),
+        }
+    }
+
+    /// The process-wide store called `name`, created empty on first use
+    pub fn named(name: &str) -> Self {
+        static STORES: OnceLock<Mutex<HashMap<String, MemoryStorage>>> = OnceLock::new();
+        STORES
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Number of files stored, not counting directories
+    pub fn file_count(&self) -> usize {
+        self.tree().values().filter(|node| !node.is_dir()).count()
+    }
+
+    fn tree(&self) -> MutexGuard<'_, Tree> {
+        self.tree.lock().unwrap()
+    }
+
+    fn key(path: &UniversalPath) -> Vec<String> {
+        path.path_segments().to_vec()
+    }
+
+    /// Fail unless the parent of `key` is a directory
+    fn check_parent(tree: &Tree, key: &[String]) -> Result<(), StorageError> {
+        let Some((_, parent)) = key.split_last() else {
+            return Err(StorageError::InvalidPath);
+        };
+        match tree.get(parent) {
+            Some(node) if node.is_dir() => Ok(()),
+            Some(_) => Err(StorageError::NotADirectory),
+            None => Err(StorageError::NotFound),
+        }
+    }
+
+    /// Keys strictly below `key`, in order
+    fn descendants<'t>(tree: &'t Tree, key: &[String]) -> impl Iterator<Item = &'t Vec<String>> {
+        tree.range::<[String], _>((Bound::Excluded(key), Bound::Unbounded))
+            .map(|(k, _)| k)
+            .take_while(move |k| k.starts_with(key))
+    }
+
+    fn write_data(&self, path: &UniversalPath, data: Vec<u8>) -> Result<(), StorageError> {
+        let key = Self::key(path);
+        let mut tree = self.tree();
+        Self::check_parent(&tree, &key)?;
+        match tree.get_mut(&key) {
+            Some(node) if node.is_dir() => Err(StorageError::NotAFile),
+            Some(node) => {
+                node.data = Some(data);
+                node.modified = SystemTime::now();
+                Ok(())
+            }
+            None => {
+                tree.insert(key, Node::new(Some(data)));
+                Ok(())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    fn backend(&self) -> StorageBackend {
+        StorageBackend::Memory
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        CAPABILITIES
+    }
+
+    async fn stat_opts(
+        &self,
+        path: &UniversalPath,
+        _opts: &CallOptions,
+    ) -> Result<EntryMetadata, StorageError> {
+        let tree = self.tree();
+        let node = tree
+            .get(path.path_segments())
+            .ok_or(StorageError::NotFound)?;
+        Ok(EntryMetadata {
+            kind: if node.is_dir() {
+                EntryKind::Directory
+            } else {
+                EntryKind::File
+            },
+            size_bytes: node.data.as_ref().map(|data| data.len() as u64),
+            modified_at: Some(node.modified),
+            created_at: Some(node.created),
+            permissions: EntryPermissions::default(),
+            is_hidden: super::is_hidden_name(path),
+            format: None,
+        })
+    }
+
+    async fn read_opts(
+        &self,
+        path: &UniversalPath,
+        _opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        let tree = self.tree();
+        let node = tree
+            .get(path.path_segments())
+            .ok_or(StorageError::NotFound)?;
+        node.data.clone().ok_or(StorageError::NotAFile)
+    }
+
+    async fn read_range_opts(
+        &self,
+        path: &UniversalPath,
+        range: Range<u64>,
+        _opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        let tree = self.tree();
+        let node = tree
+            .get(path.path_segments())
+            .ok_or(StorageError::NotFound)?;
+        let data = node.data.as_ref().ok_or(StorageError::NotAFile)?;
+        let range = clamp_range(range, data.len() as u64)?;
+        Ok(data[range.start as usize..range.end as usize].to_vec())
+    }
+
+    async fn list_opts(
+        &self,
+        path: &UniversalPath,
+        _opts: &CallOptions,
+    ) -> Result<Vec<UniversalPath>, StorageError> {
+        let tree = self.tree();
+        let key = path.path_segments();
+        match tree.get(key) {
+            Some(node) if node.is_dir() => {}
+            Some(_) => return Err(StorageError::NotADirectory),
+            None => return Err(StorageError::NotFound),
+        }
+        let dir = path.as_file();
+        Ok(Self::descendants(&tree, key)
+            .filter(|k| k.len() == key.len() + 1)
+            .map(|k| {
+                let child = dir.join(&k[key.len()]);
+                if tree[k].is_dir() {
+                    child.as_dir()
+                } else {
+                    child
+                }
+            })
+            .collect())
+    }
+
+    async fn write_opts(
+        &self,
+        path: &UniversalPath,
+        data: &[u8],
+        _opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.write_data(path, data.to_vec())
+    }
+
+    async fn write_stream_opts(
+        &self,
+        path: &UniversalPath,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        _opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await?;
+        let len = data.len() as u64;
+        self.write_data(path, data)?;
+        Ok(len)
+    }
+
+    async fn create_dir_opts(
+        &self,
+        path: &UniversalPath,
+        _opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        let key = Self::key(path);
+        let mut tree = self.tree();
+        for depth in 1..=key.len() {
+            match tree.get(&key[..depth]) {
+                Some(node) if node.is_dir() => {}
+                Some(_) => return Err(StorageError::NotADirectory),
+                None => {
+                    tree.insert(key[..depth].to_vec(), Node::new(None));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn delete_opts(
+        &self,
+        path: &UniversalPath,
+        _opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        let key = Self::key(path);
+        if key.is_empty() {
+            return Err(StorageError::InvalidPath);
+        }
+        let mut tree = self.tree();
+        if !tree.contains_key(&key) {
+            return Err(StorageError::NotFound);
+        }
+        if Self::descendants(&tree, &key).next().is_some() {
+            return Err(StorageError::Io(
+                std::io::ErrorKind::DirectoryNotEmpty.into(),
+            ));
+        }
+        tree.remove(&key);
+        Ok(())
+    }
+
+    async fn rename_opts(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        _opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        let (source, target) = (Self::key(from), Self::key(to));
+        let mut tree = self.tree();
+        if source.is_empty() || target.starts_with(&source) {
+            return Err(StorageError::InvalidPath);
+        }
+        if !tree.contains_key(&source) {
+            return Err(StorageError::NotFound);
+        }
+        if tree.contains_key(&target) {
+            return Err(StorageError::AlreadyExists);
+        }
+        Self::check_parent(&tree, &target)?;
+        let mut moved: Vec<_> = Self::descendants(&tree, &source).cloned().collect();
+        moved.push(source.clone());
+        for key in moved {
+            let node = tree.remove(&key).expect("key was just listed");
+            let mut renamed = target.clone();
+            renamed.extend_from_slice(&key[source.len()..]);
+            tree.insert(renamed, node);
+        }
+        Ok(())
+    }
+
+    async fn copy_opts(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        _opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        let data = match self.tree().get(from.path_segments()) {
+            Some(Node { data: Some(data), .. }) => data.clone(),
+            Some(_) => return Err(StorageError::NotAFile),
+            None => return Err(StorageError::NotFound),
+        };
+        let len = data.len() as u64;
+        self.write_data(to, data)?;
+        Ok(len)
+    }
+
+    async fn set_modified_opts(
+        &self,
+        path: &UniversalPath,
+        modified_at: SystemTime,
+        _opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        let mut tree = self.tree();
+        match tree.get_mut(path.path_segments()) {
+            Some(node) if node.is_dir() => Err(StorageError::NotAFile),
+            Some(node) => {
+                node.modified = modified_at;
+                Ok(())
+            }
+            None => Err(StorageError::NotFound),
+        }
+    }
+
+    async fn attribute_opts(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+        _opts: &CallOptions,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        let tree = self.tree();
+        let node = tree
+            .get(path.path_segments())
+            .ok_or(StorageError::NotFound)?;
+        Ok(node.attributes.get(name).cloned())
+    }
+
+    async fn set_attribute_opts(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+        value: &[u8],
+        _opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        let mut tree = self.tree();
+        let node = tree
+            .get_mut(path.path_segments())
+            .ok_or(StorageError::NotFound)?;
+        node.attributes.insert(name.to_string(), value.to_vec());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::open_storage_for;
+
+    #[tokio::test]
+    async fn test_tree_operations() {
+        let storage = MemoryStorage::new();
+        let root = UniversalPath::from_uri_str("mem://test/").unwrap();
+        let album = root.join("music").join("album");
+        assert!(matches!(
+            storage.write(&album.join("01.flac"), b"x").await,
+            Err(StorageError::NotFound)
+        ));
+        storage.create_dir(&album).await.unwrap();
+        storage
+            .write(&album.join("01.flac"), b"audio")
+            .await
+            .unwrap();
+        storage
+            .write(&album.join("02.flac"), b"more")
+            .await
+            .unwrap();
+        assert_eq!(
+            storage
+                .read_range(&album.join("01.flac"), 1..3)
+                .await
+                .unwrap(),
+            b"ud"
+        );
+        let listed = storage.list(&root.join("music")).await.unwrap();
+        assert_eq!(listed, [album.as_dir()]);
+        let listed = storage.list_with_metadata(&album).await.unwrap();
+        let sizes: Vec<_> = listed.iter().map(|(_, meta)| meta.size_bytes).collect();
+        assert_eq!(sizes, [Some(5), Some(4)]);
+
+        // Moving a directory carries everything below it along
+        let moved = root.join("archive");
+        storage.rename(&root.join("music"), &moved).await.unwrap();
+        assert_eq!(
+            storage
+                .read(&moved.join("album").join("02.flac"))
+                .await
+                .unwrap(),
+            b"more"
+        );
+        assert!(matches!(
+            storage.delete(&moved.join("album")).await,
+            Err(StorageError::Io(_))
+        ));
+        assert_eq!(storage.file_count(), 2);
+
+        // Named stores are shared with storage opened from a mem:// URI
+        MemoryStorage::named("shared")
+            .write(&root.join("a.txt"), b"hi")
+            .await
+            .unwrap();
+        let opened =
+            open_storage_for(&UniversalPath::from_uri_str("mem://shared/a.txt").unwrap()).unwrap();
+        assert_eq!(
+            opened
+                .read(&UniversalPath::from_uri_str("mem://shared/a.txt").unwrap())
+                .await
+                .unwrap(),
+            b"hi"
+        );
+    }
+}
@@ -0,0 +1,383 @@
+use super::{EntryMetadata, ScanProgress, Scanner, StorageBackend, StorageError};
+use crate::universal_path::UniversalPath;
+use futures::{
+    Stream, StreamExt,
+    stream::{BoxStream, SelectAll},
+};
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio::sync::{oneshot, watch};
+
+/// An entry found under one of a `MultiScanner`'s roots, by the root's label
+pub type LabelledEntry = (String, Result<(UniversalPath, EntryMetadata), StorageError>);
+
+/// Scans several roots at once, sharing out the calls they may make between them so
+/// a huge root cannot starve a small one.
+///
+/// Each root keeps its own scanner's concurrency. On top of that, a limit can be put
+/// on the calls in flight on each backend, and on all of them together. While roots
+/// are waiting on a limit, a freed call goes to the root with the fewest in flight for
+/// its weight, so a root of weight 2 gets twice the calls of a root of weight 1.
+#[derive(Clone, Default)]
+pub struct MultiScanner {
+    roots: Vec<ScanRoot>,
+    backend_limits: Vec<(StorageBackend, usize)>,
+    total_limit: Option<usize>,
+}
+
+#[derive(Clone)]
+struct ScanRoot {
+    label: String,
+    scanner: Scanner,
+    root: UniversalPath,
+    weight: u32,
+}
+
+impl MultiScanner {
+    pub fn new() -> Self {
+        MultiScanner::default()
+    }
+
+    /// Scan `root` with `scanner`, at weight 1, reporting it under `label`. A root of a
+    /// label already added replaces it.
+    pub fn with_root(mut self, label: &str, scanner: Scanner, root: &UniversalPath) -> Self {
+        self.roots.retain(|r| r.label != label);
+        self.roots.push(ScanRoot {
+            label: label.to_string(),
+            scanner,
+            root: root.clone(),
+            weight: 1,
+        });
+        self
+    }
+
+    /// The share of contended calls the root of `label` gets, relative to the others
+    pub fn with_weight(mut self, label: &str, weight: u32) -> Self {
+        if let Some(root) = self.roots.iter_mut().find(|r| r.label == label) {
+            root.weight = weight.max(1);
+        }
+        self
+    }
+
+    /// At most `limit` calls in flight on `backend`, across every root on it
+    pub fn with_backend_limit(mut self, backend: StorageBackend, limit: usize) -> Self {
+        self.backend_limits.retain(|(b, _)| *b != backend);
+        self.backend_limits.push((backend, limit.max(1)));
+        self
+    }
+
+    /// At most `limit` calls in flight across every root
+    pub fn with_total_limit(mut self, limit: usize) -> Self {
+        self.total_limit = Some(limit.max(1));
+        self
+    }
+
+    /// Everything under every root, interleaved as it is found. Must be called within a
+    /// Tokio runtime; the scans stop once the returned stream is dropped.
+    pub fn scan(&self) -> MultiScanStream {
+        let mut backends: Vec<(StorageBackend, Slots)> = Vec::new();
+        let mut roots = Vec::new();
+        for root in &self.roots {
+            let backend = root.scanner.backend();
+            let at = match backends.iter().position(|(b, _)| *b == backend) {
+                Some(at) => at,
+                None => {
+                    let limit = self
+                        .backend_limits
+                        .iter()
+                        .find(|(b, _)| *b == backend)
+                        .map(|(_, limit)| *limit);
+                    backends.push((backend, Slots { limit, in_use: 0 }));
+                    backends.len() - 1
+                }
+            };
+            roots.push((root.weight, at));
+        }
+        let shares = Arc::new(Mutex::new(Shares {
+            total: Slots {
+                limit: self.total_limit,
+                in_use: 0,
+            },
+            roots: roots
+                .iter()
+                .map(|&(weight, backend)| RootShare {
+                    weight,
+                    backend,
+                    in_use: 0,
+                })
+                .collect(),
+            backends: backends.into_iter().map(|(_, slots)| slots).collect(),
+            waiting: VecDeque::new(),
+        }));
+
+        let mut scans = SelectAll::new();
+        let mut progress = Vec::new();
+        for (at, root) in self.roots.iter().enumerate() {
+            let share = ScanShare {
+                shares: shares.clone(),
+                root: at,
+            };
+            let scan = root.scanner.clone().with_share(share).scan(&root.root);
+            progress.push((root.label.clone(), scan.progress()));
+            let label = root.label.clone();
+            let labelled: BoxStream<'static, LabelledEntry> =
+                Box::pin(scan.map(move |entry| (label.clone(), entry)));
+            scans.push(labelled);
+        }
+        MultiScanStream { scans, progress }
+    }
+}
+
+/// The entries a `MultiScanner` finds, with how far each root has got.
+pub struct MultiScanStream {
+    scans: SelectAll<BoxStream<'static, LabelledEntry>>,
+    progress: Vec<(String, watch::Receiver<ScanProgress>)>,
+}
+
+impl MultiScanStream {
+    /// How far the scan of the root of `label` has got, updated as it goes
+    pub fn progress(&self, label: &str) -> Option<watch::Receiver<ScanProgress>> {
+        self.progress
+            .iter()
+            .find(|(l, _)| l == label)
+            .map(|(_, progress)| progress.clone())
+    }
+
+    /// Each root's progress now, in the order the roots were added
+    pub fn all_progress(&self) -> Vec<(String, ScanProgress)> {
+        self.progress
+            .iter()
+            .map(|(label, progress)| (label.clone(), *progress.borrow()))
+            .collect()
+    }
+}
+
+impl Stream for MultiScanStream {
+    type Item = LabelledEntry;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.scans.poll_next_unpin(cx)
+    }
+}
+
+struct Shares {
+    total: Slots,
+    backends: Vec<Slots>,
+    roots: Vec<RootShare>,
+    waiting: VecDeque<Waiter>,
+}
+
+struct Slots {
+    limit: Option<usize>,
+    in_use: usize,
+}
+
+struct RootShare {
+    weight: u32,
+    /// Into `Shares::backends`
+    backend: usize,
+    in_use: usize,
+}
+
+struct Waiter {
+    root: usize,
+    tx: oneshot::Sender<Slot>,
+}
+
+impl Slots {
+    fn free(&self) -> bool {
+        self.limit.is_none_or(|limit| self.in_use < limit)
+    }
+}
+
+impl Shares {
+    fn fits(&self, root: usize) -> bool {
+        self.total.free() && self.backends[self.roots[root].backend].free()
+    }
+
+    fn take(&mut self, root: usize) {
+        self.total.in_use += 1;
+        let backend = self.roots[root].backend;
+        self.backends[backend].in_use += 1;
+        self.roots[root].in_use += 1;
+    }
+
+    fn give_back(&mut self, root: usize) {
+        self.total.in_use -= 1;
+        let backend = self.roots[root].backend;
+        self.backends[backend].in_use -= 1;
+        self.roots[root].in_use -= 1;
+    }
+
+    /// The waiter that fits whose root has the fewest calls in flight for its weight,
+    /// the longest waiting of those that tie
+    fn next_waiter(&self) -> Option<usize> {
+        let usage = |root: usize| {
+            let share = &self.roots[root];
+            (share.in_use as u64, share.weight as u64)
+        };
+        self.waiting
+            .iter()
+            .enumerate()
+            .filter(|(_, w)| self.fits(w.root))
+            .min_by(|(_, a), (_, b)| {
+                let ((a_used, a_weight), (b_used, b_weight)) = (usage(a.root), usage(b.root));
+                (a_used * b_weight).cmp(&(b_used * a_weight))
+            })
+            .map(|(at, _)| at)
+    }
+}
+
+/// A root's claim on the calls its `MultiScanner` shares out.
+#[derive(Clone)]
+pub(super) struct ScanShare {
+    shares: Arc<Mutex<Shares>>,
+    root: usize,
+}
+
+/// A call the holder may make, given back when dropped
+pub(super) struct Slot {
+    share: ScanShare,
+}
+
+impl ScanShare {
+    pub async fn acquire(&self) -> Slot {
+        let rx = {
+            let mut shares = self.lock();
+            if shares.fits(self.root) {
+                shares.take(self.root);
+                return Slot {
+                    share: self.clone(),
+                };
+            }
+            let (tx, rx) = oneshot::channel();
+            shares.waiting.push_back(Waiter {
+                root: self.root,
+                tx,
+            });
+            rx
+        };
+        rx.await.expect("the share outlives its waiters")
+    }
+
+    fn release(&self) {
+        // Slots whose waiter went away, given back once the lock is let go
+        let mut unclaimed = Vec::new();
+        {
+            let mut shares = self.lock();
+            shares.give_back(self.root);
+            while let Some(at) = shares.next_waiter() {
+                let waiter = shares.waiting.remove(at).expect("waiter found");
+                shares.take(waiter.root);
+                let slot = Slot {
+                    share: ScanShare {
+                        shares: self.shares.clone(),
+                        root: waiter.root,
+                    },
+                };
+                if let Err(slot) = waiter.tx.send(slot) {
+                    unclaimed.push(slot);
+                }
+            }
+        }
+        drop(unclaimed);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Shares> {
+        self.shares.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.share.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryStorage, Storage};
+    use futures::FutureExt;
+
+    #[tokio::test]
+    async fn test_fair_multi_scan() {
+        let storage = Arc::new(MemoryStorage::new());
+        let big = UniversalPath::from_uri_str("mem://nas/s3-mirror/").unwrap();
+        let small = UniversalPath::from_uri_str("mem://nas/laptop/").unwrap();
+        storage.create_dir(&big).await.unwrap();
+        storage.create_dir(&small).await.unwrap();
+        for track in 0..50 {
+            storage
+                .write(&big.join(format!("{track:02}.flac")), b"flac")
+                .await
+                .unwrap();
+        }
+        storage
+            .write(&small.join("01.flac"), b"flac")
+            .await
+            .unwrap();
+
+        let scanner = Scanner::new(storage.clone()).with_concurrency(4);
+        let multi = MultiScanner::new()
+            .with_root("big", scanner.clone(), &big)
+            .with_root("small", scanner, &small)
+            .with_weight("big", 3)
+            .with_backend_limit(StorageBackend::Memory, 2);
+        let mut scan = multi.scan();
+        let mut found = [0, 0];
+        while let Some((label, entry)) = scan.next().await {
+            entry.unwrap();
+            found[(label == "small") as usize] += 1;
+        }
+        assert_eq!(found, [50, 1]);
+        let progress = scan.all_progress();
+        assert_eq!(progress[0].0, "big");
+        assert!(progress.iter().all(|(_, p)| p.finished));
+        assert_eq!(scan.progress("small").unwrap().borrow().files, 1);
+
+        // With every slot taken, freed ones go by calls in flight for weight
+        let shares = Arc::new(Mutex::new(Shares {
+            total: Slots {
+                limit: Some(3),
+                in_use: 0,
+            },
+            backends: vec![Slots {
+                limit: None,
+                in_use: 0,
+            }],
+            roots: [3, 1]
+                .map(|weight| RootShare {
+                    weight,
+                    backend: 0,
+                    in_use: 0,
+                })
+                .into(),
+            waiting: VecDeque::new(),
+        }));
+        let share = |root| ScanShare {
+            shares: shares.clone(),
+            root,
+        };
+        let (heavy, light) = (share(0), share(1));
+        let mut held: Vec<_> = (0..3)
+            .map(|_| heavy.acquire().now_or_never().unwrap())
+            .collect();
+        let mut heavy_wait = Box::pin(heavy.acquire());
+        let mut light_wait = Box::pin(light.acquire());
+        assert!((&mut heavy_wait).now_or_never().is_none());
+        assert!((&mut light_wait).now_or_never().is_none());
+        // 3 in flight at weight 3 is more than none at weight 1
+        held.pop();
+        let light_slot = (&mut light_wait).now_or_never().unwrap();
+        assert!((&mut heavy_wait).now_or_never().is_none());
+        held.pop();
+        assert!((&mut heavy_wait).now_or_never().is_some());
+        drop(light_slot);
+        assert_eq!(shares.lock().unwrap().total.in_use, 1);
+    }
+}
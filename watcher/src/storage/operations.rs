@@ -0,0 +1,579 @@
+use super::{
+    ByteStream, CallOptions, Checksum, ChecksumAlgorithm, EntryMetadata, Storage, StorageBackend,
+    StorageCapabilities, StorageError, StorageLock,
+};
+use crate::jobs::{Job, JobId};
+use crate::universal_path::UniversalPath;
+use async_trait::async_trait;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    ops::Range,
+    pin::Pin,
+    sync::{
+        Arc, LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    sync::watch,
+};
+
+pub type OperationId = u64;
+
+/// An operation in flight, as an application's control API would report it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OperationInfo {
+    pub id: OperationId,
+    /// The storage call, e.g. `"read_range"`, or `"job:<kind>"` for a job
+    pub kind: String,
+    pub path: Option<UniversalPath>,
+    /// When the operation is part of a job
+    pub job: Option<JobId>,
+    /// The caller's `CallOptions::trace_label`
+    pub label: Option<String>,
+    pub started_at: SystemTime,
+    pub elapsed: Duration,
+    /// Read or written so far
+    pub bytes: u64,
+    /// Cancellation was asked for but the operation has not stopped yet
+    pub cancelling: bool,
+}
+
+struct Tracked {
+    kind: String,
+    path: Option<UniversalPath>,
+    job: Option<JobId>,
+    label: Option<String>,
+    started_at: SystemTime,
+    started: Instant,
+    bytes: AtomicU64,
+    cancel: watch::Sender<bool>,
+}
+
+impl Tracked {
+    fn info(&self, id: OperationId) -> OperationInfo {
+        OperationInfo {
+            id,
+            kind: self.kind.clone(),
+            path: self.path.clone(),
+            job: self.job,
+            label: self.label.clone(),
+            started_at: self.started_at,
+            elapsed: self.started.elapsed(),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            cancelling: *self.cancel.borrow(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Operations {
+    next_id: AtomicU64,
+    running: Mutex<BTreeMap<OperationId, Arc<Tracked>>>,
+}
+
+/// What is running right now, so a user can see why a scan is slow and stop a copy
+/// they started by mistake.
+///
+/// Operations are registered with `begin`, which returns a handle that unregisters
+/// the operation when dropped. `cancel` only signals: storage calls made through
+/// `TrackedStorage` fail with `StorageError::Cancelled` at once, and jobs stop when
+/// their worker next checks `OperationHandle::is_cancelled`. Clones share the same
+/// operations; `operations()` is the registry the process reports.
+#[derive(Clone, Default)]
+pub struct OperationRegistry {
+    inner: Arc<Operations>,
+}
+
+impl OperationRegistry {
+    pub fn new() -> Self {
+        OperationRegistry::default()
+    }
+
+    /// Register an operation of `kind` on `path`
+    pub fn begin<K: Into<String>>(&self, kind: K, path: Option<&UniversalPath>) -> OperationHandle {
+        self.register(kind.into(), path.cloned(), None, None)
+    }
+
+    /// Register a job a worker has just claimed
+    pub fn begin_job(&self, job: &Job) -> OperationHandle {
+        self.register(format!("job:{}", job.kind), None, Some(job.id), None)
+    }
+
+    fn register(
+        &self,
+        kind: String,
+        path: Option<UniversalPath>,
+        job: Option<JobId>,
+        label: Option<String>,
+    ) -> OperationHandle {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let tracked = Arc::new(Tracked {
+            kind,
+            path,
+            job,
+            label,
+            started_at: SystemTime::now(),
+            started: Instant::now(),
+            bytes: AtomicU64::new(0),
+            cancel: watch::Sender::new(false),
+        });
+        let mut running = self.inner.running.lock().unwrap();
+        running.insert(id, tracked.clone());
+        OperationHandle {
+            registry: self.clone(),
+            id,
+            tracked,
+        }
+    }
+
+    /// Everything in flight, oldest first
+    pub fn list(&self) -> Vec<OperationInfo> {
+        let running = self.inner.running.lock().unwrap();
+        running.iter().map(|(id, op)| op.info(*id)).collect()
+    }
+
+    pub fn get(&self, id: OperationId) -> Option<OperationInfo> {
+        let running = self.inner.running.lock().unwrap();
+        running.get(&id).map(|op| op.info(id))
+    }
+
+    /// Ask operation `id` to stop. False if it is not running, e.g. already finished.
+    pub fn cancel(&self, id: OperationId) -> bool {
+        let running = self.inner.running.lock().unwrap();
+        match running.get(&id) {
+            Some(op) => {
+                op.cancel.send_replace(true);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Ask every operation in `job` to stop, returning how many there were
+    pub fn cancel_job(&self, job: JobId) -> usize {
+        let running = self.inner.running.lock().unwrap();
+        let ops: Vec<_> = running.values().filter(|op| op.job == Some(job)).collect();
+        for op in &ops {
+            op.cancel.send_replace(true);
+        }
+        ops.len()
+    }
+}
+
+static OPERATIONS: LazyLock<OperationRegistry> = LazyLock::new(OperationRegistry::new);
+
+/// The registry `TrackedStorage` records into unless given another
+pub fn operations() -> &'static OperationRegistry {
+    &OPERATIONS
+}
+
+/// A registered operation; dropping it unregisters the operation.
+pub struct OperationHandle {
+    registry: OperationRegistry,
+    id: OperationId,
+    tracked: Arc<Tracked>,
+}
+
+impl OperationHandle {
+    pub fn id(&self) -> OperationId {
+        self.id
+    }
+
+    /// Count bytes read or written
+    pub fn add_bytes(&self, bytes: u64) {
+        self.tracked.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.tracked.cancel.borrow()
+    }
+
+    /// Resolves once the operation is cancelled
+    pub async fn cancelled(&self) {
+        let mut cancel = self.tracked.cancel.subscribe();
+        // The sender lives as long as the handle, so this only ends on cancellation
+        cancel.wait_for(|cancelled| *cancelled).await.ok();
+    }
+
+    /// Run `work`, giving up with `StorageError::Cancelled` if cancelled first
+    pub async fn run<T, F>(&self, work: F) -> Result<T, StorageError>
+    where
+        F: Future<Output = Result<T, StorageError>>,
+    {
+        tokio::select! {
+            result = work => result,
+            _ = self.cancelled() => Err(StorageError::Cancelled),
+        }
+    }
+}
+
+impl Drop for OperationHandle {
+    fn drop(&mut self) {
+        let mut running = self.registry.inner.running.lock().unwrap();
+        running.remove(&self.id);
+    }
+}
+
+/// Counts what passes through a stream into its operation, and stops it when the
+/// operation is cancelled
+struct TrackedReader<R> {
+    inner: R,
+    tracked: Arc<Tracked>,
+    /// For streams that outlive the call that opened them
+    _handle: Option<OperationHandle>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for TrackedReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if *self.tracked.cancel.borrow() {
+            return Poll::Ready(Err(std::io::Error::other(StorageError::Cancelled)));
+        }
+        let before = buf.filled().len();
+        let polled = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        self.tracked.bytes.fetch_add(read, Ordering::Relaxed);
+        polled
+    }
+}
+
+/// Registers every call made through the wrapped storage in an `OperationRegistry`,
+/// with the bytes it moves, so it can be listed and cancelled. Streams stay registered
+/// until dropped.
+pub struct TrackedStorage<S: Storage + ?Sized = dyn Storage> {
+    inner: Arc<S>,
+    registry: OperationRegistry,
+}
+
+impl<S: Storage + ?Sized> TrackedStorage<S> {
+    /// Track calls to `inner` in the process-wide `operations()`
+    pub fn new(inner: Arc<S>) -> Self {
+        TrackedStorage {
+            inner,
+            registry: operations().clone(),
+        }
+    }
+
+    pub fn with_registry(mut self, registry: OperationRegistry) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    pub fn inner(&self) -> &Arc<S> {
+        &self.inner
+    }
+
+    fn begin(&self, kind: &str, path: &UniversalPath, opts: &CallOptions) -> OperationHandle {
+        let label = opts.trace_label.clone();
+        self.registry
+            .register(kind.to_string(), Some(path.clone()), None, label)
+    }
+
+    /// Run one call as an operation, counting the bytes `len` says its result holds
+    async fn track<T, F>(
+        &self,
+        kind: &str,
+        path: &UniversalPath,
+        opts: &CallOptions,
+        len: impl Fn(&T) -> u64,
+        call: F,
+    ) -> Result<T, StorageError>
+    where
+        F: Future<Output = Result<T, StorageError>>,
+    {
+        let handle = self.begin(kind, path, opts);
+        let result = handle.run(call).await?;
+        handle.add_bytes(len(&result));
+        Ok(result)
+    }
+}
+
+fn nothing<T>(_: &T) -> u64 {
+    0
+}
+
+fn length(data: &[u8]) -> u64 {
+    data.len() as u64
+}
+
+#[async_trait]
+impl<S: Storage + ?Sized> Storage for TrackedStorage<S> {
+    fn backend(&self) -> StorageBackend {
+        self.inner.backend()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.inner.capabilities()
+    }
+
+    async fn stat_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<EntryMetadata, StorageError> {
+        let call = self.inner.stat_opts(path, opts);
+        self.track("stat", path, opts, nothing, call).await
+    }
+
+    async fn read_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        let call = self.inner.read_opts(path, opts);
+        self.track("read", path, opts, |d: &Vec<u8>| length(d), call)
+            .await
+    }
+
+    async fn read_range_opts(
+        &self,
+        path: &UniversalPath,
+        range: Range<u64>,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        let call = self.inner.read_range_opts(path, range, opts);
+        self.track("read_range", path, opts, |d: &Vec<u8>| length(d), call)
+            .await
+    }
+
+    async fn list_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<UniversalPath>, StorageError> {
+        let call = self.inner.list_opts(path, opts);
+        self.track("list", path, opts, nothing, call).await
+    }
+
+    async fn glob_opts(
+        &self,
+        pattern: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<UniversalPath>, StorageError> {
+        let call = self.inner.glob_opts(pattern, opts);
+        self.track("glob", pattern, opts, nothing, call).await
+    }
+
+    async fn symlink_target_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Option<UniversalPath>, StorageError> {
+        let call = self.inner.symlink_target_opts(path, opts);
+        self.track("symlink_target", path, opts, nothing, call)
+            .await
+    }
+
+    async fn read_suffix_opts(
+        &self,
+        path: &UniversalPath,
+        len: u64,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        let call = self.inner.read_suffix_opts(path, len, opts);
+        self.track("read_suffix", path, opts, |d: &Vec<u8>| length(d), call)
+            .await
+    }
+
+    async fn read_stream_opts<'a>(
+        &'a self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<ByteStream<'a>, StorageError> {
+        let handle = self.begin("read_stream", path, opts);
+        let inner = handle.run(self.inner.read_stream_opts(path, opts)).await?;
+        Ok(Box::pin(TrackedReader {
+            inner,
+            tracked: handle.tracked.clone(),
+            _handle: Some(handle),
+        }))
+    }
+
+    async fn checksum_opts(
+        &self,
+        path: &UniversalPath,
+        algorithm: ChecksumAlgorithm,
+        opts: &CallOptions,
+    ) -> Result<Checksum, StorageError> {
+        let call = self.inner.checksum_opts(path, algorithm, opts);
+        self.track("checksum", path, opts, nothing, call).await
+    }
+
+    async fn write_opts(
+        &self,
+        path: &UniversalPath,
+        data: &[u8],
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        let handle = self.begin("write", path, opts);
+        handle.run(self.inner.write_opts(path, data, opts)).await?;
+        handle.add_bytes(data.len() as u64);
+        Ok(())
+    }
+
+    async fn write_stream_opts(
+        &self,
+        path: &UniversalPath,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        let handle = self.begin("write_stream", path, opts);
+        let mut reader = TrackedReader {
+            inner: reader,
+            tracked: handle.tracked.clone(),
+            _handle: None,
+        };
+        handle
+            .run(self.inner.write_stream_opts(path, &mut reader, opts))
+            .await
+    }
+
+    async fn create_dir_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        let call = self.inner.create_dir_opts(path, opts);
+        self.track("create_dir", path, opts, nothing, call).await
+    }
+
+    async fn delete_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        let call = self.inner.delete_opts(path, opts);
+        self.track("delete", path, opts, nothing, call).await
+    }
+
+    async fn rename_opts(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        let call = self.inner.rename_opts(from, to, opts);
+        self.track("rename", from, opts, nothing, call).await
+    }
+
+    async fn copy_opts(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        let call = self.inner.copy_opts(from, to, opts);
+        self.track("copy", from, opts, |n| *n, call).await
+    }
+
+    async fn set_modified_opts(
+        &self,
+        path: &UniversalPath,
+        modified_at: SystemTime,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.inner.set_modified_opts(path, modified_at, opts).await
+    }
+
+    async fn attribute_opts(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+        opts: &CallOptions,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        self.inner.attribute_opts(path, name, opts).await
+    }
+
+    async fn set_attribute_opts(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+        value: &[u8],
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.inner.set_attribute_opts(path, name, value, opts).await
+    }
+
+    async fn acquire_lock_opts(
+        &self,
+        path: &UniversalPath,
+        ttl: Duration,
+        opts: &CallOptions,
+    ) -> Result<StorageLock, StorageError> {
+        self.inner.acquire_lock_opts(path, ttl, opts).await
+    }
+
+    async fn renew_lock_opts(
+        &self,
+        lock: &StorageLock,
+        ttl: Duration,
+        opts: &CallOptions,
+    ) -> Result<StorageLock, StorageError> {
+        self.inner.renew_lock_opts(lock, ttl, opts).await
+    }
+
+    async fn release_lock_opts(
+        &self,
+        lock: &StorageLock,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.inner.release_lock_opts(lock, opts).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_list_and_cancel_operations() {
+        let registry = OperationRegistry::new();
+        let memory = Arc::new(MemoryStorage::new());
+        let path = UniversalPath::from_uri_str("mem://ops/a.flac").unwrap();
+        memory.write(&path, &[7; 4096]).await.unwrap();
+        let storage = TrackedStorage::new(memory).with_registry(registry.clone());
+
+        let mut stream = storage.read_stream(&path).await.unwrap();
+        let mut buf = [0; 1000];
+        stream.read_exact(&mut buf).await.unwrap();
+        let ops = registry.list();
+        assert_eq!(ops.len(), 1);
+        assert_eq!((ops[0].kind.as_str(), ops[0].bytes), ("read_stream", 1000));
+        assert_eq!(ops[0].path.as_ref(), Some(&path));
+
+        assert!(registry.cancel(ops[0].id));
+        assert!(registry.get(ops[0].id).unwrap().cancelling);
+        let error = stream.read(&mut buf).await.unwrap_err();
+        assert!(error.to_string().contains("cancelled"), "{error}");
+        drop(stream);
+        assert!(registry.list().is_empty());
+        assert!(!registry.cancel(ops[0].id));
+
+        // A call waiting on something slow gives up as soon as it is cancelled
+        let job = registry.begin("job:sync", None);
+        let id = job.id();
+        let waiting = tokio::spawn(async move {
+            job.run(std::future::pending::<Result<(), StorageError>>())
+                .await
+        });
+        while registry.get(id).is_none() {
+            tokio::task::yield_now().await;
+        }
+        registry.cancel(id);
+        assert!(matches!(
+            waiting.await.unwrap(),
+            Err(StorageError::Cancelled)
+        ));
+    }
+}
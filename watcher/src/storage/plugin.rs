@@ -0,0 +1,586 @@
+//! Backends loaded at runtime from shared libraries, for code that cannot be linked
+//! into otolith itself, such as a vendor NAS SDK under an incompatible licence.
+//!
+//! A plugin is a C ABI library that exports
+//!
+//! ```c
+//! const OtolithPluginV1 *otolith_plugin_v1(void);
+//! ```
+//!
+//! returning a table that lives as long as the process, laid out as `PluginVTable`.
+//! The table names one URI scheme, which `UniversalPath` parses into
+//! `StorageBackend::Other` like any scheme not built in, and which `open_storage_for`
+//! routes to the plugin once it is loaded. Every function may be called from several
+//! threads at once and may block; calls run on tokio's blocking pool. URIs are passed
+//! as NUL-terminated UTF-8 in the form `UniversalPath` prints them.
+//!
+//! Functions return one of the `PLUGIN_*` status codes. Data comes back in a
+//! `PluginBuffer` the plugin allocates and otolith hands back to `free_buffer`: file
+//! bytes for `read_range`, newline-separated child URIs for `list`, and on
+//! `PLUGIN_ERROR` a UTF-8 message. Libraries are never unloaded.
+
+use super::{
+    CallOptions, EntryKind, EntryMetadata, EntryPermissions, Storage, StorageBackend, StorageCapabilities,
+    StorageError, clamp_range,
+};
+use crate::universal_path::UniversalPath;
+use async_trait::async_trait;
+use libloading::Library;
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString, c_char, c_void},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, RwLock},
+    time::{Duration, UNIX_EPOCH},
+};
+use thiserror::Error;
+
+/// The `abi_version` a plugin table must carry to be loaded
+pub const PLUGIN_ABI_VERSION: u32 = 1;
+/// The symbol every plugin library exports
+pub const PLUGIN_ENTRY_SYMBOL: &str = "otolith_plugin_v1";
+
+pub const PLUGIN_OK: i32 = 0;
+pub const PLUGIN_NOT_FOUND: i32 = 1;
+pub const PLUGIN_NOT_A_FILE: i32 = 2;
+pub const PLUGIN_NOT_A_DIRECTORY: i32 = 3;
+pub const PLUGIN_INVALID_PATH: i32 = 4;
+pub const PLUGIN_RANGE_NOT_SATISFIABLE: i32 = 5;
+pub const PLUGIN_UNSUPPORTED: i32 = 6;
+/// Any other failure, described by the message in the out buffer
+pub const PLUGIN_ERROR: i32 = 7;
+
+pub const PLUGIN_KIND_FILE: u32 = 0;
+pub const PLUGIN_KIND_DIRECTORY: u32 = 1;
+pub const PLUGIN_KIND_OTHER: u32 = 2;
+
+/// Bytes allocated by the plugin, returned to it through `free_buffer`. Zeroed
+/// before every call, so a plugin that writes nothing leaves it empty.
+#[repr(C)]
+#[derive(Debug)]
+pub struct PluginBuffer {
+    pub ptr: *mut u8,
+    pub len: usize,
+    /// For the plugin's own bookkeeping, e.g. the capacity of a `Vec` it leaked
+    pub cap: usize,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PluginStat {
+    /// One of the `PLUGIN_KIND_*` values
+    pub kind: u32,
+    /// `u64::MAX` when unknown
+    pub size: u64,
+    /// Milliseconds since the Unix epoch, `i64::MIN` when unknown
+    pub modified_unix_ms: i64,
+}
+
+pub type PluginStatFn = unsafe extern "C" fn(
+    ctx: *mut c_void,
+    uri: *const c_char,
+    stat: *mut PluginStat,
+    out: *mut PluginBuffer,
+) -> i32;
+/// Bytes `start..end` under the `Storage::read_range_opts` rules, with `end` already
+/// clamped to the size the plugin last reported
+pub type PluginReadRangeFn = unsafe extern "C" fn(
+    ctx: *mut c_void,
+    uri: *const c_char,
+    start: u64,
+    end: u64,
+    out: *mut PluginBuffer,
+) -> i32;
+pub type PluginListFn =
+    unsafe extern "C" fn(ctx: *mut c_void, uri: *const c_char, out: *mut PluginBuffer) -> i32;
+pub type PluginFreeFn = unsafe extern "C" fn(ctx: *mut c_void, buffer: *mut PluginBuffer);
+
+/// Everything otolith knows about a plugin, as returned by its entry point.
+#[repr(C)]
+pub struct PluginVTable {
+    pub abi_version: u32,
+    /// NUL-terminated lowercase URI scheme, e.g. `vendornas`
+    pub scheme: *const c_char,
+    /// Passed back as-is to every function
+    pub ctx: *mut c_void,
+    pub stat: PluginStatFn,
+    pub read_range: PluginReadRangeFn,
+    /// Null for backends that cannot list
+    pub list: Option<PluginListFn>,
+    pub free_buffer: PluginFreeFn,
+}
+
+pub type PluginEntryFn = unsafe extern "C" fn() -> *const PluginVTable;
+
+#[derive(Debug, Error)]
+pub enum PluginError {
+    #[error("could not read plugin directory {0}: {1}")]
+    Directory(PathBuf, #[source] std::io::Error),
+    #[error("could not load {0}: {1}")]
+    Load(PathBuf, #[source] libloading::Error),
+    #[error("{0} does not export {PLUGIN_ENTRY_SYMBOL}")]
+    MissingEntry(PathBuf),
+    #[error("{path} is built for plugin ABI {found}, expected {PLUGIN_ABI_VERSION}")]
+    AbiVersion { path: PathBuf, found: u32 },
+    #[error("invalid plugin scheme {0:?}")]
+    InvalidScheme(String),
+    #[error("scheme {0:?} is already taken")]
+    SchemeTaken(String),
+}
+
+/// The table and the library it came from, which must stay loaded while the table is
+/// in use
+struct Plugin {
+    vtable: &'static PluginVTable,
+    _library: Option<Library>,
+}
+
+// Plugins promise their functions are thread-safe, which is all `ctx` is used for
+unsafe impl Send for Plugin {}
+unsafe impl Sync for Plugin {}
+
+static PLUGINS: LazyLock<RwLock<HashMap<String, Arc<Plugin>>>> = LazyLock::new(Default::default);
+
+/// Whether a plugin has claimed `scheme`, which must already be lowercase
+pub(crate) fn is_registered(scheme: &str) -> bool {
+    PLUGINS.read().unwrap().contains_key(scheme)
+}
+
+/// The schemes of every plugin loaded so far, sorted
+pub fn plugin_schemes() -> Vec<String> {
+    let mut schemes: Vec<_> = PLUGINS.read().unwrap().keys().cloned().collect();
+    schemes.sort();
+    schemes
+}
+
+/// Load one plugin library and register its scheme, returning the scheme.
+///
+/// # Safety
+///
+/// The library runs arbitrary code when loaded and must implement the ABI described
+/// in this module's documentation.
+pub unsafe fn load_plugin(path: &Path) -> Result<String, PluginError> {
+    let library =
+        unsafe { Library::new(path) }.map_err(|e| PluginError::Load(path.to_path_buf(), e))?;
+    let entry = unsafe { library.get::<PluginEntryFn>(PLUGIN_ENTRY_SYMBOL.as_bytes()) }
+        .map_err(|_| PluginError::MissingEntry(path.to_path_buf()))?;
+    let vtable = unsafe { entry() };
+    let vtable = unsafe { vtable.as_ref() }.ok_or(PluginError::MissingEntry(path.to_path_buf()))?;
+    if vtable.abi_version != PLUGIN_ABI_VERSION {
+        return Err(PluginError::AbiVersion {
+            path: path.to_path_buf(),
+            found: vtable.abi_version,
+        });
+    }
+    unsafe { register(vtable, Some(library)) }
+}
+
+/// A library found by `load_plugins` and the scheme it registered
+pub type LoadedPlugin = (PathBuf, Result<String, PluginError>);
+
+/// Load every shared library directly inside `dir`, e.g. every `.so` on Linux. One
+/// result per library, so a broken plugin does not keep the others out.
+///
+/// # Safety
+///
+/// As for `load_plugin`, for every library in `dir`.
+pub unsafe fn load_plugins(dir: &Path) -> Result<Vec<LoadedPlugin>, PluginError> {
+    let entries =
+        std::fs::read_dir(dir).map_err(|e| PluginError::Directory(dir.to_path_buf(), e))?;
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.is_file()
+                && path.extension().and_then(|e| e.to_str())
+                    == Some(std::env::consts::DLL_EXTENSION)
+        })
+        .collect();
+    paths.sort();
+    Ok(paths
+        .into_iter()
+        .map(|path| {
+            let loaded = unsafe { load_plugin(&path) };
+            (path, loaded)
+        })
+        .collect())
+}
+
+/// Register a table linked into the program rather than loaded from a library.
+///
+/// # Safety
+///
+/// `vtable` must follow the ABI described in this module's documentation.
+pub unsafe fn register_plugin(vtable: &'static PluginVTable) -> Result<String, PluginError> {
+    unsafe { register(vtable, None) }
+}
+
+unsafe fn register(
+    vtable: &'static PluginVTable,
+    library: Option<Library>,
+) -> Result<String, PluginError> {
+    if vtable.scheme.is_null() {
+        return Err(PluginError::InvalidScheme(String::new()));
+    }
+    let scheme = unsafe { CStr::from_ptr(vtable.scheme) }
+        .to_string_lossy()
+        .into_owned();
+    let valid = scheme.starts_with(|c: char| c.is_ascii_lowercase())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "+-.".contains(c));
+    if !valid {
+        return Err(PluginError::InvalidScheme(scheme));
+    }
+    if StorageBackend::builtin_scheme(&scheme).is_some() {
+        return Err(PluginError::SchemeTaken(scheme));
+    }
+    let mut plugins = PLUGINS.write().unwrap();
+    if plugins.contains_key(&scheme) {
+        return Err(PluginError::SchemeTaken(scheme));
+    }
+    let plugin = Plugin {
+        vtable,
+        _library: library,
+    };
+    plugins.insert(scheme.clone(), Arc::new(plugin));
+    Ok(scheme)
+}
+
+/// A `Storage` backed by a loaded plugin.
+#[derive(Clone)]
+pub struct PluginStorage {
+    scheme: String,
+    plugin: Arc<Plugin>,
+}
+
+impl PluginStorage {
+    /// The storage for a registered scheme
+    pub fn for_scheme(scheme: &str) -> Result<Self, StorageError> {
+        let plugin = PLUGINS.read().unwrap().get(scheme).cloned();
+        plugin
+            .map(|plugin| PluginStorage {
+                scheme: scheme.to_string(),
+                plugin,
+            })
+            .ok_or_else(|| StorageError::UnsupportedBackend(StorageBackend::Other(scheme.into())))
+    }
+
+    fn uri(&self, path: &UniversalPath) -> Result<CString, StorageError> {
+        // Built-in schemes cannot be registered, so the scheme alone identifies the plugin
+        if path.backend().to_scheme() != self.scheme {
+            return Err(StorageError::InvalidPath);
+        }
+        CString::new(path.to_string()).map_err(|_| StorageError::InvalidPath)
+    }
+
+    /// Run `call` on the blocking pool with a fresh out buffer, returning its value
+    /// and whatever it left in the buffer, or the error its status maps to
+    async fn call<T, F>(&self, opts: &CallOptions, call: F) -> Result<(T, Vec<u8>), StorageError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&PluginVTable, *mut PluginBuffer) -> (i32, T) + Send + 'static,
+    {
+        let plugin = self.plugin.clone();
+        let task = tokio::task::spawn_blocking(move || {
+            let vtable = plugin.vtable;
+            let mut buffer = PluginBuffer {
+                ptr: std::ptr::null_mut(),
+                len: 0,
+                cap: 0,
+            };
+            let (status, value) = call(vtable, &mut buffer);
+            let bytes = if buffer.ptr.is_null() {
+                Vec::new()
+            } else {
+                let bytes = unsafe { std::slice::from_raw_parts(buffer.ptr, buffer.len) }.to_vec();
+                unsafe { (vtable.free_buffer)(vtable.ctx, &mut buffer) };
+                bytes
+            };
+            (status, value, bytes)
+        });
+        let (status, value, bytes) = opts
+            .enforce_timeout(async {
+                task.await
+                    .map_err(|e| StorageError::Io(std::io::Error::other(e)))
+            })
+            .await?;
+        match status {
+            PLUGIN_OK => Ok((value, bytes)),
+            PLUGIN_NOT_FOUND => Err(StorageError::NotFound),
+            PLUGIN_NOT_A_FILE => Err(StorageError::NotAFile),
+            PLUGIN_NOT_A_DIRECTORY => Err(StorageError::NotADirectory),
+            PLUGIN_INVALID_PATH => Err(StorageError::InvalidPath),
+            PLUGIN_RANGE_NOT_SATISFIABLE => Err(StorageError::RangeNotSatisfiable),
+            PLUGIN_UNSUPPORTED => Err(StorageError::UnsupportedFeature("plugin operation")),
+            _ => Err(StorageError::Io(std::io::Error::other(format!(
+                "{} plugin: {}",
+                self.scheme,
+                String::from_utf8_lossy(&bytes)
+            )))),
+        }
+    }
+
+    async fn stat_raw(
+        &self,
+        uri: CString,
+        opts: &CallOptions,
+    ) -> Result<EntryMetadata, StorageError> {
+        let (stat, _) = self
+            .call(opts, move |vtable, out| {
+                let mut stat = PluginStat {
+                    kind: PLUGIN_KIND_OTHER,
+                    size: u64::MAX,
+                    modified_unix_ms: i64::MIN,
+                };
+                let status = unsafe { (vtable.stat)(vtable.ctx, uri.as_ptr(), &mut stat, out) };
+                (status, stat)
+            })
+            .await?;
+        let modified_at = match stat.modified_unix_ms {
+            i64::MIN => None,
+            ms if ms >= 0 => UNIX_EPOCH.checked_add(Duration::from_millis(ms as u64)),
+            ms => UNIX_EPOCH.checked_sub(Duration::from_millis(ms.unsigned_abs())),
+        };
+        Ok(EntryMetadata {
+            kind: match stat.kind {
+                PLUGIN_KIND_FILE => EntryKind::File,
+                PLUGIN_KIND_DIRECTORY => EntryKind::Directory,
+                _ => EntryKind::Other,
+            },
+            size_bytes: (stat.size != u64::MAX).then_some(stat.size),
+            modified_at,
+            created_at: None,
+            permissions: EntryPermissions::default(),
+            is_hidden: false,
+            format: None,
+        })
+    }
+}
+
+pub(crate) const CAPABILITIES: StorageCapabilities = StorageCapabilities {
+    can_stat: true,
+    can_read: true,
+    can_read_range: true,
+    ..StorageCapabilities::none()
+};
+
+#[async_trait]
+impl Storage for PluginStorage {
+    fn backend(&self) -> StorageBackend {
+        StorageBackend::Other(self.scheme.clone())
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities {
+            can_list: self.plugin.vtable.list.is_some(),
+            can_glob: self.plugin.vtable.list.is_some(),
+            ..CAPABILITIES
+        }
+    }
+
+    async fn stat_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<EntryMetadata, StorageError> {
+        let mut meta = self.stat_raw(self.uri(path)?, opts).await?;
+        meta.is_hidden = super::is_hidden_name(path);
+        Ok(meta)
+    }
+
+    async fn read_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        match self.read_range_opts(path, 0..u64::MAX, opts).await {
+            // An empty file has no byte 0
+            Err(StorageError::RangeNotSatisfiable) => Ok(Vec::new()),
+            other => other,
+        }
+    }
+
+    async fn read_range_opts(
+        &self,
+        path: &UniversalPath,
+        range: Range<u64>,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        let uri = self.uri(path)?;
+        if range.start >= range.end {
+            return Ok(Vec::new());
+        }
+        // Plugins get a range that is already clamped, so they only ever copy bytes
+        let meta = self.stat_raw(uri.clone(), opts).await?;
+        if meta.kind != EntryKind::File {
+            return Err(StorageError::NotAFile);
+        }
+        let range = match meta.size_bytes {
+            Some(size) => clamp_range(range, size)?,
+            None => range,
+        };
+        let (_, bytes) = self
+            .call(opts, move |vtable, out| {
+                let status = unsafe {
+                    (vtable.read_range)(vtable.ctx, uri.as_ptr(), range.start, range.end, out)
+                };
+                (status, ())
+            })
+            .await?;
+        Ok(bytes)
+    }
+
+    async fn list_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<UniversalPath>, StorageError> {
+        let list = self
+            .plugin
+            .vtable
+            .list
+            .ok_or(StorageError::UnsupportedFeature("list"))?;
+        let uri = self.uri(path)?;
+        let (_, bytes) = self
+            .call(opts, move |vtable, out| {
+                (unsafe { list(vtable.ctx, uri.as_ptr(), out) }, ())
+            })
+            .await?;
+        String::from_utf8_lossy(&bytes)
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let child =
+                    UniversalPath::from_uri_str(line).map_err(|_| StorageError::InvalidPath)?;
+                self.uri(&child)?;
+                Ok(child)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::open_storage_for;
+
+    const TRACK: &[u8] = b"fLaC and the rest";
+
+    unsafe fn uri<'a>(uri: *const c_char) -> &'a str {
+        unsafe { CStr::from_ptr(uri) }
+            .to_str()
+            .unwrap()
+            .trim_end_matches('/')
+    }
+
+    unsafe fn fill(out: *mut PluginBuffer, bytes: &[u8]) {
+        let bytes = Box::leak(bytes.to_vec().into_boxed_slice());
+        unsafe {
+            (*out).ptr = bytes.as_mut_ptr();
+            (*out).len = bytes.len();
+        }
+    }
+
+    unsafe extern "C" fn stat(
+        _: *mut c_void,
+        path: *const c_char,
+        stat: *mut PluginStat,
+        out: *mut PluginBuffer,
+    ) -> i32 {
+        let kind = match unsafe { uri(path) } {
+            "memfs://box" => PLUGIN_KIND_DIRECTORY,
+            "memfs://box/a.flac" => PLUGIN_KIND_FILE,
+            "memfs://box/broken" => {
+                unsafe { fill(out, b"disk on fire") };
+                return PLUGIN_ERROR;
+            }
+            _ => return PLUGIN_NOT_FOUND,
+        };
+        unsafe {
+            (*stat).kind = kind;
+            (*stat).size = if kind == PLUGIN_KIND_FILE {
+                TRACK.len() as u64
+            } else {
+                u64::MAX
+            };
+            (*stat).modified_unix_ms = 1_700_000_000_000;
+        }
+        PLUGIN_OK
+    }
+
+    unsafe extern "C" fn read_range(
+        _: *mut c_void,
+        _: *const c_char,
+        start: u64,
+        end: u64,
+        out: *mut PluginBuffer,
+    ) -> i32 {
+        unsafe { fill(out, &TRACK[start as usize..end as usize]) };
+        PLUGIN_OK
+    }
+
+    unsafe extern "C" fn list(_: *mut c_void, _: *const c_char, out: *mut PluginBuffer) -> i32 {
+        unsafe { fill(out, b"memfs://box/a.flac\nmemfs://box/broken\n") };
+        PLUGIN_OK
+    }
+
+    unsafe extern "C" fn free_buffer(_: *mut c_void, buffer: *mut PluginBuffer) {
+        let buffer = unsafe { &*buffer };
+        let bytes = std::ptr::slice_from_raw_parts_mut(buffer.ptr, buffer.len);
+        drop(unsafe { Box::from_raw(bytes) });
+    }
+
+    #[tokio::test]
+    async fn test_registered_plugin_serves_its_scheme() {
+        let early = UniversalPath::from_uri_str("memfs://box/a.flac").unwrap();
+        assert_eq!(early.backend(), &StorageBackend::Other("memfs".into()));
+        assert!(open_storage_for(&early).is_err());
+        let vtable = Box::leak(Box::new(PluginVTable {
+            abi_version: PLUGIN_ABI_VERSION,
+            scheme: c"memfs".as_ptr(),
+            ctx: std::ptr::null_mut(),
+            stat,
+            read_range,
+            list: Some(list),
+            free_buffer,
+        }));
+        assert_eq!(unsafe { register_plugin(vtable) }.unwrap(), "memfs");
+        assert!(matches!(
+            unsafe { register_plugin(vtable) },
+            Err(PluginError::SchemeTaken(_))
+        ));
+        assert!(plugin_schemes().contains(&"memfs".to_string()));
+
+        // Parsing does not depend on what is loaded, so paths compare equal
+        let track = UniversalPath::from_uri_str("memfs://box/a.flac").unwrap();
+        assert_eq!(track, early);
+        let storage = open_storage_for(&track).unwrap();
+        assert!(storage.capabilities().can_list);
+        let meta = storage.stat(&track).await.unwrap();
+        assert_eq!(meta.size_bytes, Some(TRACK.len() as u64));
+        assert!(meta.modified_at.is_some());
+        assert_eq!(storage.read(&track).await.unwrap(), TRACK);
+        // A path parsed before the plugin loaded reaches it too
+        let storage_early = open_storage_for(&early).unwrap();
+        assert_eq!(storage_early.read(&early).await.unwrap(), TRACK);
+        assert_eq!(
+            storage.read_range(&track, 5..u64::MAX).await.unwrap(),
+            b"and the rest"
+        );
+        assert!(matches!(
+            storage.read_range(&track, 100..200).await,
+            Err(StorageError::RangeNotSatisfiable)
+        ));
+
+        let root = UniversalPath::from_uri_str("memfs://box/").unwrap();
+        let children = storage.list(&root).await.unwrap();
+        assert_eq!(children.len(), 2);
+        let err = storage.stat(&children[1]).await.unwrap_err();
+        assert!(err.to_string().contains("disk on fire"));
+        assert!(matches!(
+            storage.stat(&root.join("missing")).await,
+            Err(StorageError::NotFound)
+        ));
+    }
+}
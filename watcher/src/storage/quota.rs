@@ -0,0 +1,599 @@
+use super::{
+    ByteStream, CallOptions, Checksum, ChecksumAlgorithm, EntryKind, EntryMetadata, ListStream,
+    RangePart, Storage, StorageBackend,
+    StorageCapabilities, StorageError, StorageExt, StorageLock,
+};
+use crate::root::RootConfig;
+use crate::universal_path::UniversalPath;
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    ops::Range,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Limits on what may be stored under a root. `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    #[serde(default)]
+    pub max_files: Option<u64>,
+}
+
+impl Quota {
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    pub fn with_max_files(mut self, max_files: u64) -> Self {
+        self.max_files = Some(max_files);
+        self
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.max_bytes.is_none() && self.max_files.is_none()
+    }
+}
+
+/// What a root holds against its quota.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct QuotaUsage {
+    pub bytes: u64,
+    pub files: u64,
+    pub quota: Quota,
+}
+
+impl QuotaUsage {
+    /// How full the root is, from 0.0 up to 1.0 at the tighter of the two limits. An
+    /// unlimited quota reads 0.0. Suitable as a gauge.
+    pub fn utilization(&self) -> f64 {
+        let ratio = |used: u64, limit: Option<u64>| match limit {
+            Some(0) => 1.0,
+            Some(limit) => used as f64 / limit as f64,
+            None => 0.0,
+        };
+        ratio(self.bytes, self.quota.max_bytes).max(ratio(self.files, self.quota.max_files))
+    }
+
+    /// Usage after adding `bytes` and `files` and taking away `freed_bytes` and
+    /// `freed_files`, or `QuotaExceeded` if that grows past a limit
+    fn apply(
+        &self,
+        bytes: u64,
+        freed_bytes: u64,
+        files: u64,
+        freed_files: u64,
+    ) -> Result<QuotaUsage, StorageError> {
+        let next = QuotaUsage {
+            bytes: (self.bytes + bytes).saturating_sub(freed_bytes),
+            files: (self.files + files).saturating_sub(freed_files),
+            quota: self.quota,
+        };
+        // Shrinking is always allowed, even while over a limit lowered after the fact
+        for (resource, used, was, limit) in [
+            ("bytes", next.bytes, self.bytes, self.quota.max_bytes),
+            ("files", next.files, self.files, self.quota.max_files),
+        ] {
+            if let Some(limit) = limit
+                && used > limit
+                && used > was
+            {
+                return Err(StorageError::QuotaExceeded {
+                    resource,
+                    limit,
+                    used,
+                });
+            }
+        }
+        Ok(next)
+    }
+}
+
+/// Storage that enforces a `Quota` on everything written under `root`, failing writes
+/// and moves that would exceed it with `QuotaExceeded`. Paths outside the root pass
+/// through unchecked.
+///
+/// Usage is measured when the wrapper is created and tracked through its own writes;
+/// changes made to the root by anything else are not seen until the next `measure`.
+/// A streamed write is cut off once it runs past the space that was left when it
+/// started.
+pub struct QuotaStorage {
+    inner: Arc<dyn Storage>,
+    root: UniversalPath,
+    usage: Mutex<QuotaUsage>,
+}
+
+impl QuotaStorage {
+    /// Walk `root` to find its current usage, then enforce `quota` on top of it
+    pub async fn measure(
+        inner: Arc<dyn Storage>,
+        root: &UniversalPath,
+        quota: Quota,
+    ) -> Result<Self, StorageError> {
+        let (bytes, files) = footprint(&*inner, root).await?;
+        Ok(QuotaStorage {
+            inner,
+            root: root.clone(),
+            usage: Mutex::new(QuotaUsage {
+                bytes,
+                files,
+                quota,
+            }),
+        })
+    }
+
+    pub fn usage(&self) -> QuotaUsage {
+        *self.usage.lock().unwrap()
+    }
+
+    /// Change the limits, e.g. after the root's configuration is edited
+    pub fn set_quota(&self, quota: Quota) {
+        self.usage.lock().unwrap().quota = quota;
+    }
+
+    /// `inner` with the quota of each of `roots` that has one enforced under the root,
+    /// measured now, and the `QuotaStorage` keeping each by the root's label, for
+    /// reporting usage. `inner` itself if none of them has a quota.
+    pub async fn for_roots(
+        inner: Arc<dyn Storage>,
+        roots: &[RootConfig],
+    ) -> Result<(Arc<dyn Storage>, Vec<(String, Arc<QuotaStorage>)>), StorageError> {
+        let mut storage = inner;
+        let mut quotas = Vec::new();
+        for root in roots.iter().filter(|root| !root.quota.is_unlimited()) {
+            let quota = QuotaStorage::measure(storage, &root.path, root.quota).await?;
+            let quota = Arc::new(quota);
+            storage = quota.clone();
+            quotas.push((root.label.clone(), quota));
+        }
+        Ok((storage, quotas))
+    }
+
+    fn covers(&self, path: &UniversalPath) -> bool {
+        path.relative_to(&self.root).is_some()
+    }
+
+    /// Take the change out of the quota up front, so concurrent writers cannot
+    /// overshoot it together. Returns the inverse to put back if the write fails.
+    fn reserve(
+        &self,
+        bytes: u64,
+        freed_bytes: u64,
+        files: u64,
+        freed_files: u64,
+    ) -> Result<(u64, u64, u64, u64), StorageError> {
+        let mut usage = self.usage.lock().unwrap();
+        *usage = usage.apply(bytes, freed_bytes, files, freed_files)?;
+        Ok((freed_bytes, bytes, freed_files, files))
+    }
+
+    fn release(&self, (bytes, freed_bytes, files, freed_files): (u64, u64, u64, u64)) {
+        let mut usage = self.usage.lock().unwrap();
+        usage.bytes = (usage.bytes + bytes).saturating_sub(freed_bytes);
+        usage.files = (usage.files + files).saturating_sub(freed_files);
+    }
+
+    /// Size of the file at `path` and whether there is one
+    async fn existing(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(u64, bool), StorageError> {
+        match self.inner.stat_opts(path, opts).await {
+            Ok(meta) if meta.kind == EntryKind::File => Ok((meta.size_bytes.unwrap_or(0), true)),
+            Ok(_) => Err(StorageError::NotAFile),
+            Err(StorageError::NotFound) => Ok((0, false)),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Bytes and files under `path`, which may also be a single file
+async fn footprint<S: Storage + ?Sized>(
+    storage: &S,
+    path: &UniversalPath,
+) -> Result<(u64, u64), StorageError> {
+    let meta = match storage.stat(path).await {
+        Ok(meta) => meta,
+        Err(StorageError::NotFound) => return Ok((0, 0)),
+        Err(e) => return Err(e),
+    };
+    if meta.kind != EntryKind::Directory {
+        return Ok((meta.size_bytes.unwrap_or(0), 1));
+    }
+    let mut totals = (0, 0);
+    let mut entries = storage.walk(path);
+    while let Some(entry) = entries.next().await {
+        let (_, meta) = entry?;
+        if meta.kind == EntryKind::File {
+            totals.0 += meta.size_bytes.unwrap_or(0);
+            totals.1 += 1;
+        }
+    }
+    Ok(totals)
+}
+
+/// Passes reads through until more than `budget` bytes have gone by
+struct Budgeted<'a> {
+    reader: &'a mut (dyn AsyncRead + Send + Unpin),
+    budget: u64,
+    read: u64,
+}
+
+impl AsyncRead for Budgeted<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let polled = Pin::new(&mut *self.reader).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = polled {
+            self.read += (buf.filled().len() - before) as u64;
+            if self.read > self.budget {
+                // A read that fails must not have filled anything
+                buf.set_filled(before);
+                return Poll::Ready(Err(std::io::Error::other("quota exceeded")));
+            }
+        }
+        polled
+    }
+}
+
+#[async_trait]
+impl Storage for QuotaStorage {
+    fn backend(&self) -> StorageBackend {
+        self.inner.backend()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.inner.capabilities()
+    }
+
+    async fn stat_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<EntryMetadata, StorageError> {
+        self.inner.stat_opts(path, opts).await
+    }
+
+    async fn stat_many_opts(
+        &self,
+        paths: &[UniversalPath],
+        opts: &CallOptions,
+    ) -> Vec<Result<EntryMetadata, StorageError>> {
+        self.inner.stat_many_opts(paths, opts).await
+    }
+
+    async fn read_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        self.inner.read_opts(path, opts).await
+    }
+
+    async fn read_range_opts(
+        &self,
+        path: &UniversalPath,
+        range: Range<u64>,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        self.inner.read_range_opts(path, range, opts).await
+    }
+
+    async fn list_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<UniversalPath>, StorageError> {
+        self.inner.list_opts(path, opts).await
+    }
+
+    async fn list_with_metadata_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<(UniversalPath, EntryMetadata)>, StorageError> {
+        self.inner.list_with_metadata_opts(path, opts).await
+    }
+
+    async fn list_stream_opts<'a>(
+        &'a self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<ListStream<'a>, StorageError> {
+        self.inner.list_stream_opts(path, opts).await
+    }
+
+    async fn glob_opts(
+        &self,
+        pattern: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<UniversalPath>, StorageError> {
+        self.inner.glob_opts(pattern, opts).await
+    }
+
+    async fn symlink_target_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Option<UniversalPath>, StorageError> {
+        self.inner.symlink_target_opts(path, opts).await
+    }
+
+    async fn read_suffix_opts(
+        &self,
+        path: &UniversalPath,
+        len: u64,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        self.inner.read_suffix_opts(path, len, opts).await
+    }
+
+    async fn read_stream_opts<'a>(
+        &'a self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<ByteStream<'a>, StorageError> {
+        self.inner.read_stream_opts(path, opts).await
+    }
+
+    async fn read_ranges_opts(
+        &self,
+        path: &UniversalPath,
+        ranges: &[Range<u64>],
+        opts: &CallOptions,
+    ) -> Result<Vec<RangePart>, StorageError> {
+        self.inner.read_ranges_opts(path, ranges, opts).await
+    }
+
+    async fn checksum_opts(
+        &self,
+        path: &UniversalPath,
+        algorithm: ChecksumAlgorithm,
+        opts: &CallOptions,
+    ) -> Result<Checksum, StorageError> {
+        self.inner.checksum_opts(path, algorithm, opts).await
+    }
+
+    async fn write_opts(
+        &self,
+        path: &UniversalPath,
+        data: &[u8],
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        if !self.covers(path) {
+            return self.inner.write_opts(path, data, opts).await;
+        }
+        let (old, existed) = self.existing(path, opts).await?;
+        let undo = self.reserve(data.len() as u64, old, u64::from(!existed), 0)?;
+        let written = self.inner.write_opts(path, data, opts).await;
+        if written.is_err() {
+            self.release(undo);
+        }
+        written
+    }
+
+    async fn write_stream_opts(
+        &self,
+        path: &UniversalPath,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        if !self.covers(path) {
+            return self.inner.write_stream_opts(path, reader, opts).await;
+        }
+        let (old, existed) = self.existing(path, opts).await?;
+        // Count the file now and the bytes once their number is known
+        let undo = self.reserve(0, 0, u64::from(!existed), 0)?;
+        let usage = self.usage();
+        let budget = match usage.quota.max_bytes {
+            Some(limit) => (limit + old).saturating_sub(usage.bytes),
+            None => u64::MAX,
+        };
+        let mut budgeted = Budgeted {
+            reader,
+            budget,
+            read: 0,
+        };
+        match self
+            .inner
+            .write_stream_opts(path, &mut budgeted, opts)
+            .await
+        {
+            Ok(written) => {
+                self.release((written, old, 0, 0));
+                Ok(written)
+            }
+            Err(e) => {
+                self.release(undo);
+                if budgeted.read > budget {
+                    return Err(StorageError::QuotaExceeded {
+                        resource: "bytes",
+                        limit: usage.quota.max_bytes.unwrap_or(u64::MAX),
+                        used: usage.bytes.saturating_sub(old) + budgeted.read,
+                    });
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn create_dir_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.inner.create_dir_opts(path, opts).await
+    }
+
+    async fn delete_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        if !self.covers(path) {
+            return self.inner.delete_opts(path, opts).await;
+        }
+        let meta = self.inner.stat_opts(path, opts).await?;
+        self.inner.delete_opts(path, opts).await?;
+        if meta.kind == EntryKind::File {
+            self.release((0, meta.size_bytes.unwrap_or(0), 0, 1));
+        }
+        Ok(())
+    }
+
+    async fn rename_opts(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        let (inside_from, inside_to) = (self.covers(from), self.covers(to));
+        if inside_from == inside_to {
+            return self.inner.rename_opts(from, to, opts).await;
+        }
+        let (bytes, files) = footprint(&*self.inner, from).await?;
+        if inside_to {
+            let undo = self.reserve(bytes, 0, files, 0)?;
+            let moved = self.inner.rename_opts(from, to, opts).await;
+            if moved.is_err() {
+                self.release(undo);
+            }
+            moved
+        } else {
+            self.inner.rename_opts(from, to, opts).await?;
+            self.release((0, bytes, 0, files));
+            Ok(())
+        }
+    }
+
+    // `copy_opts` is left to the default, so what it writes is counted like any write
+
+    async fn set_modified_opts(
+        &self,
+        path: &UniversalPath,
+        modified_at: SystemTime,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.inner.set_modified_opts(path, modified_at, opts).await
+    }
+
+    async fn attribute_opts(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+        opts: &CallOptions,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        self.inner.attribute_opts(path, name, opts).await
+    }
+
+    async fn set_attribute_opts(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+        value: &[u8],
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.inner.set_attribute_opts(path, name, value, opts).await
+    }
+
+    // Lock files are bookkeeping, not library content, so they bypass the quota
+    async fn acquire_lock_opts(
+        &self,
+        path: &UniversalPath,
+        ttl: Duration,
+        opts: &CallOptions,
+    ) -> Result<StorageLock, StorageError> {
+        self.inner.acquire_lock_opts(path, ttl, opts).await
+    }
+
+    async fn renew_lock_opts(
+        &self,
+        lock: &StorageLock,
+        ttl: Duration,
+        opts: &CallOptions,
+    ) -> Result<StorageLock, StorageError> {
+        self.inner.renew_lock_opts(lock, ttl, opts).await
+    }
+
+    async fn release_lock_opts(
+        &self,
+        lock: &StorageLock,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.inner.release_lock_opts(lock, opts).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LocalStorage;
+    use std::fs;
+
+    #[tokio::test]
+    async fn test_quota_enforced_under_root() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("library")).unwrap();
+        fs::create_dir_all(dir.join("inbox")).unwrap();
+        fs::write(dir.join("library/01.flac"), vec![0u8; 40]).unwrap();
+        fs::write(dir.join("inbox/02.flac"), vec![0u8; 50]).unwrap();
+        let base = UniversalPath::local(dir.to_str().unwrap());
+        let (library, inbox) = (base.join("library/"), base.join("inbox/"));
+
+        let quota = Quota::default().with_max_bytes(100).with_max_files(3);
+        let storage = QuotaStorage::measure(Arc::new(LocalStorage::new()), &library, quota)
+            .await
+            .unwrap();
+        assert_eq!((storage.usage().bytes, storage.usage().files), (40, 1));
+        assert!((storage.usage().utilization() - 0.4).abs() < 1e-9);
+
+        // Moving the inbox file in fits; a 20 byte write after it does not
+        storage
+            .rename(&inbox.join("02.flac"), &library.join("02.flac"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            storage.write(&library.join("03.flac"), &[0u8; 20]).await,
+            Err(StorageError::QuotaExceeded {
+                resource: "bytes",
+                limit: 100,
+                used: 110
+            })
+        ));
+        assert!(!dir.join("library/03.flac").exists());
+        // Nor does a stream that only finds out part-way
+        let mut reader = &[0u8; 20][..];
+        assert!(matches!(
+            storage
+                .write_stream(&library.join("03.flac"), &mut reader)
+                .await,
+            Err(StorageError::QuotaExceeded { .. })
+        ));
+        assert!(!dir.join("library/03.flac").exists());
+        // Overwriting in place only counts the difference; outside the root is free
+        storage
+            .write(&library.join("01.flac"), &[0u8; 50])
+            .await
+            .unwrap();
+        storage
+            .write(&inbox.join("big"), &[0u8; 500])
+            .await
+            .unwrap();
+        assert_eq!((storage.usage().bytes, storage.usage().files), (100, 2));
+
+        storage.delete(&library.join("02.flac")).await.unwrap();
+        assert_eq!((storage.usage().bytes, storage.usage().files), (50, 1));
+    }
+}
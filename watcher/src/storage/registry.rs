@@ -0,0 +1,200 @@
+#[cfg(feature = "plugins")]
+use super::plugin;
+use super::{CredentialChain, CredentialProvider, Storage, StorageError};
+use crate::universal_path::UniversalPath;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, LazyLock, RwLock},
+};
+
+/// Opens storage for a path of the scheme it was registered under, logging in with
+/// credentials from the provider if it needs any.
+pub type StorageFactory = Arc<
+    dyn Fn(&UniversalPath, &dyn CredentialProvider) -> Result<Box<dyn Storage>, StorageError>
+        + Send
+        + Sync,
+>;
+
+/// Schemes of the backends compiled into this build, as `UniversalPath` prints them
+const BUILTIN_SCHEMES: &[&str] = &[
+    "file",
+    "smb",
+    "mem",
+    #[cfg(feature = "ftp")]
+    "ftp",
+    #[cfg(feature = "sftp")]
+    "sftp",
+    #[cfg(feature = "s3")]
+    "s3",
+    #[cfg(feature = "http")]
+    "http",
+    #[cfg(feature = "http")]
+    "https",
+    #[cfg(feature = "webdav")]
+    "dav",
+    #[cfg(feature = "webdav")]
+    "davs",
+];
+
+/// Which storage opens which URI scheme. Paths are looked up by the scheme they print
+/// with, so `cifs://` and `file://host/` paths both go to `smb`.
+#[derive(Clone)]
+pub struct StorageRegistry {
+    factories: BTreeMap<String, StorageFactory>,
+}
+
+impl Default for StorageRegistry {
+    fn default() -> Self {
+        StorageRegistry::with_builtins()
+    }
+}
+
+impl StorageRegistry {
+    /// A registry that opens nothing until schemes are registered
+    pub fn empty() -> Self {
+        StorageRegistry {
+            factories: BTreeMap::new(),
+        }
+    }
+
+    /// Every backend compiled into this build
+    pub fn with_builtins() -> Self {
+        let mut registry = StorageRegistry::empty();
+        for scheme in BUILTIN_SCHEMES {
+            registry.register(scheme, super::open_builtin);
+        }
+        registry
+    }
+
+    /// Open `scheme` with `factory` from now on, returning the factory it replaces
+    pub fn register<F>(&mut self, scheme: &str, factory: F) -> Option<StorageFactory>
+    where
+        F: Fn(&UniversalPath, &dyn CredentialProvider) -> Result<Box<dyn Storage>, StorageError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.factories
+            .insert(scheme.to_lowercase(), Arc::new(factory))
+    }
+
+    pub fn unregister(&mut self, scheme: &str) -> Option<StorageFactory> {
+        self.factories.remove(&scheme.to_lowercase())
+    }
+
+    /// The registered schemes, sorted
+    pub fn schemes(&self) -> Vec<&str> {
+        self.factories.keys().map(String::as_str).collect()
+    }
+
+    pub fn factory(&self, scheme: &str) -> Option<StorageFactory> {
+        self.factories.get(&scheme.to_lowercase()).cloned()
+    }
+
+    /// Storage for `path` with credentials from `provider`, skipped when the URI
+    /// carries a password. Schemes nobody registered go to a loaded plugin if one
+    /// claimed them, and otherwise fail with `UnsupportedBackend`.
+    pub fn open_with(
+        &self,
+        path: &UniversalPath,
+        provider: &dyn CredentialProvider,
+    ) -> Result<Box<dyn Storage>, StorageError> {
+        open(self.factory(path.backend().to_scheme()), path, provider)
+    }
+}
+
+fn open(
+    factory: Option<StorageFactory>,
+    path: &UniversalPath,
+    provider: &dyn CredentialProvider,
+) -> Result<Box<dyn Storage>, StorageError> {
+    // A password in the URI is a complete login and needs no lookup
+    let none = CredentialChain::new();
+    let provider: &dyn CredentialProvider = if path.password().is_some() {
+        &none
+    } else {
+        provider
+    };
+    if let Some(factory) = factory {
+        return factory(path, provider);
+    }
+    #[cfg(feature = "plugins")]
+    if plugin::is_registered(path.backend().to_scheme()) {
+        return Ok(Box::new(plugin::PluginStorage::for_scheme(
+            path.backend().to_scheme(),
+        )?));
+    }
+    Err(StorageError::UnsupportedBackend(path.backend().clone()))
+}
+
+static REGISTRY: LazyLock<RwLock<StorageRegistry>> = LazyLock::new(Default::default);
+
+/// The registry `open_storage_for` and `open_storage_with` dispatch through, which
+/// starts out as `StorageRegistry::with_builtins()`
+pub fn storage_registry() -> StorageRegistry {
+    REGISTRY.read().unwrap().clone()
+}
+
+/// Replace the process-wide registry
+pub fn set_storage_registry(registry: StorageRegistry) {
+    *REGISTRY.write().unwrap() = registry;
+}
+
+/// Add a backend for `scheme` to the process-wide registry, e.g. one for `gdrive://`
+/// paths, returning the factory it replaces
+pub fn register_backend<F>(scheme: &str, factory: F) -> Option<StorageFactory>
+where
+    F: Fn(&UniversalPath, &dyn CredentialProvider) -> Result<Box<dyn Storage>, StorageError>
+        + Send
+        + Sync
+        + 'static,
+{
+    REGISTRY.write().unwrap().register(scheme, factory)
+}
+
+/// `open_with` on the process-wide registry, without holding its lock while a backend
+/// connects
+pub(crate) fn open_global(
+    path: &UniversalPath,
+    provider: &dyn CredentialProvider,
+) -> Result<Box<dyn Storage>, StorageError> {
+    let factory = REGISTRY.read().unwrap().factory(path.backend().to_scheme());
+    open(factory, path, provider)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryStorage, StorageBackend, open_storage_for};
+
+    #[tokio::test]
+    async fn test_custom_scheme() {
+        let track = UniversalPath::from_uri_str("gdrive://me/music/a.flac").unwrap();
+        assert!(matches!(
+            open_storage_for(&track),
+            Err(StorageError::UnsupportedBackend(StorageBackend::Other(_)))
+        ));
+
+        // Every gdrive path lands in one in-memory store standing in for the drive
+        let drive = MemoryStorage::new();
+        drive.create_dir(&track.parent().unwrap()).await.unwrap();
+        drive.write(&track, b"audio").await.unwrap();
+        let shared = drive.clone();
+        register_backend("GDrive", move |_, _| Ok(Box::new(shared.clone())));
+        let storage = open_storage_for(&track).unwrap();
+        assert_eq!(storage.read(&track).await.unwrap(), b"audio");
+
+        // A registry of its own opens only what it was given
+        let mut registry = StorageRegistry::empty();
+        assert!(matches!(
+            registry.open_with(&UniversalPath::local("/"), &CredentialChain::new()),
+            Err(StorageError::UnsupportedBackend(StorageBackend::Local))
+        ));
+        registry.register("file", |_, _| Ok(Box::new(MemoryStorage::new())));
+        let local = registry
+            .open_with(&UniversalPath::local("/"), &CredentialChain::new())
+            .unwrap();
+        assert_eq!(local.backend(), StorageBackend::Memory);
+        assert!(StorageRegistry::with_builtins().schemes().contains(&"mem"));
+    }
+}
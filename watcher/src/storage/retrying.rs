@@ -0,0 +1,516 @@
+use super::{
+    ByteStream, CallOptions, Checksum, ChecksumAlgorithm, EntryMetadata, ListStream, RangePart,
+    Storage, StorageBackend, StorageCapabilities, StorageError, StorageLock,
+};
+use crate::retry::RetryPolicy;
+use crate::universal_path::UniversalPath;
+use async_trait::async_trait;
+use std::{
+    future::Future,
+    ops::Range,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::io::AsyncRead;
+
+/// Wraps any storage to retry calls that fail in ways that may pass, as told by
+/// `StorageError::is_retryable` or a classifier of your own, waiting out the policy's
+/// backoff between attempts. A timeout in `CallOptions` covers every attempt together.
+///
+/// Only calls that are safe to repeat are retried. A streamed write cannot be replayed
+/// and a rename may have happened before its answer was lost, so both are attempted
+/// once, as are locks; `read_stream` retries opening the stream, not reads from it. A
+/// delete that finds nothing after a failed attempt counts as done.
+pub struct RetryingStorage<S: Storage + ?Sized = dyn Storage> {
+    inner: Arc<S>,
+    policy: RetryPolicy,
+    /// Overrides of `policy` for paths on particular backends
+    backend_policies: Vec<(StorageBackend, RetryPolicy)>,
+    classify: fn(&StorageError) -> bool,
+    retries: AtomicU64,
+}
+
+impl<S: Storage + ?Sized> RetryingStorage<S> {
+    /// Retry `inner`'s calls under `RetryPolicy::network()`
+    pub fn new(inner: Arc<S>) -> Self {
+        RetryingStorage {
+            inner,
+            policy: RetryPolicy::network(),
+            backend_policies: Vec::new(),
+            classify: StorageError::is_retryable,
+            retries: AtomicU64::new(0),
+        }
+    }
+
+    pub fn with_policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Use `policy` for paths on `backend`, e.g. more patience for S3 throttling
+    pub fn with_backend_policy(mut self, backend: StorageBackend, policy: RetryPolicy) -> Self {
+        self.backend_policies.retain(|(b, _)| *b != backend);
+        self.backend_policies.push((backend, policy));
+        self
+    }
+
+    /// Decide which errors are worth another attempt instead of `is_retryable`
+    pub fn with_classifier(mut self, classify: fn(&StorageError) -> bool) -> Self {
+        self.classify = classify;
+        self
+    }
+
+    pub fn inner(&self) -> &Arc<S> {
+        &self.inner
+    }
+
+    /// Attempts made after a failure, over the wrapper's lifetime
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    fn policy_for(&self, path: &UniversalPath) -> &RetryPolicy {
+        self.backend_policies
+            .iter()
+            .find(|(backend, _)| backend == path.backend())
+            .map_or(&self.policy, |(_, policy)| policy)
+    }
+
+    /// `call` until it succeeds, fails for good or runs out of attempts
+    async fn retry<T, F, Fut>(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+        mut call: F,
+    ) -> Result<T, StorageError>
+    where
+        F: FnMut() -> Fut + Send,
+        Fut: Future<Output = Result<T, StorageError>> + Send,
+        T: Send,
+    {
+        let policy = self.policy_for(path);
+        opts.enforce_timeout(async {
+            let mut attempt = 1;
+            loop {
+                match call().await {
+                    Err(e) if (self.classify)(&e) && policy.should_retry(attempt) => {
+                        tokio::time::sleep(policy.delay(attempt)).await;
+                        attempt += 1;
+                        self.retries.fetch_add(1, Ordering::Relaxed);
+                    }
+                    other => return other,
+                }
+            }
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl<S: Storage + ?Sized> Storage for RetryingStorage<S> {
+    fn backend(&self) -> StorageBackend {
+        self.inner.backend()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.inner.capabilities()
+    }
+
+    async fn stat_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<EntryMetadata, StorageError> {
+        self.retry(path, opts, || self.inner.stat_opts(path, opts))
+            .await
+    }
+
+    /// The batch is sent on once; stats that fail in ways worth another attempt are
+    /// then retried one by one
+    async fn stat_many_opts(
+        &self,
+        paths: &[UniversalPath],
+        opts: &CallOptions,
+    ) -> Vec<Result<EntryMetadata, StorageError>> {
+        let mut stats = self.inner.stat_many_opts(paths, opts).await;
+        for (path, stat) in paths.iter().zip(&mut stats) {
+            if stat.as_ref().is_err_and(|e| (self.classify)(e)) {
+                self.retries.fetch_add(1, Ordering::Relaxed);
+                *stat = self.stat_opts(path, opts).await;
+            }
+        }
+        stats
+    }
+
+    async fn read_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        self.retry(path, opts, || self.inner.read_opts(path, opts))
+            .await
+    }
+
+    async fn read_range_opts(
+        &self,
+        path: &UniversalPath,
+        range: Range<u64>,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        self.retry(path, opts, || {
+            self.inner.read_range_opts(path, range.clone(), opts)
+        })
+        .await
+    }
+
+    async fn list_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<UniversalPath>, StorageError> {
+        self.retry(path, opts, || self.inner.list_opts(path, opts))
+            .await
+    }
+
+    async fn list_with_metadata_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<(UniversalPath, EntryMetadata)>, StorageError> {
+        self.retry(path, opts, || {
+            self.inner.list_with_metadata_opts(path, opts)
+        })
+        .await
+    }
+
+    /// Opening the stream is retried; a page that fails once it is open ends it
+    async fn list_stream_opts<'a>(
+        &'a self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<ListStream<'a>, StorageError> {
+        self.retry(path, opts, || self.inner.list_stream_opts(path, opts))
+            .await
+    }
+
+    async fn glob_opts(
+        &self,
+        pattern: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<UniversalPath>, StorageError> {
+        self.retry(pattern, opts, || self.inner.glob_opts(pattern, opts))
+            .await
+    }
+
+    async fn symlink_target_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Option<UniversalPath>, StorageError> {
+        self.retry(path, opts, || self.inner.symlink_target_opts(path, opts))
+            .await
+    }
+
+    async fn read_suffix_opts(
+        &self,
+        path: &UniversalPath,
+        len: u64,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        self.retry(path, opts, || self.inner.read_suffix_opts(path, len, opts))
+            .await
+    }
+
+    async fn read_stream_opts<'a>(
+        &'a self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<ByteStream<'a>, StorageError> {
+        self.retry(path, opts, || self.inner.read_stream_opts(path, opts))
+            .await
+    }
+
+    async fn read_ranges_opts(
+        &self,
+        path: &UniversalPath,
+        ranges: &[Range<u64>],
+        opts: &CallOptions,
+    ) -> Result<Vec<RangePart>, StorageError> {
+        self.retry(path, opts, || {
+            self.inner.read_ranges_opts(path, ranges, opts)
+        })
+        .await
+    }
+
+    async fn checksum_opts(
+        &self,
+        path: &UniversalPath,
+        algorithm: ChecksumAlgorithm,
+        opts: &CallOptions,
+    ) -> Result<Checksum, StorageError> {
+        self.retry(path, opts, || {
+            self.inner.checksum_opts(path, algorithm, opts)
+        })
+        .await
+    }
+
+    async fn write_opts(
+        &self,
+        path: &UniversalPath,
+        data: &[u8],
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.retry(path, opts, || self.inner.write_opts(path, data, opts))
+            .await
+    }
+
+    async fn write_stream_opts(
+        &self,
+        path: &UniversalPath,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        self.inner.write_stream_opts(path, reader, opts).await
+    }
+
+    async fn create_dir_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.retry(path, opts, || self.inner.create_dir_opts(path, opts))
+            .await
+    }
+
+    async fn delete_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        let retried = AtomicBool::new(false);
+        self.retry(path, opts, || async {
+            let result = self.inner.delete_opts(path, opts).await;
+            match (result, retried.swap(true, Ordering::Relaxed)) {
+                // An earlier attempt got through before failing
+                (Err(StorageError::NotFound), true) => Ok(()),
+                (result, _) => result,
+            }
+        })
+        .await
+    }
+
+    async fn rename_opts(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.inner.rename_opts(from, to, opts).await
+    }
+
+    async fn copy_opts(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        self.inner.copy_opts(from, to, opts).await
+    }
+
+    async fn set_modified_opts(
+        &self,
+        path: &UniversalPath,
+        modified_at: SystemTime,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.retry(path, opts, || {
+            self.inner.set_modified_opts(path, modified_at, opts)
+        })
+        .await
+    }
+
+    async fn attribute_opts(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+        opts: &CallOptions,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        self.retry(path, opts, || self.inner.attribute_opts(path, name, opts))
+            .await
+    }
+
+    async fn set_attribute_opts(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+        value: &[u8],
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.retry(path, opts, || {
+            self.inner.set_attribute_opts(path, name, value, opts)
+        })
+        .await
+    }
+
+    async fn acquire_lock_opts(
+        &self,
+        path: &UniversalPath,
+        ttl: Duration,
+        opts: &CallOptions,
+    ) -> Result<StorageLock, StorageError> {
+        self.inner.acquire_lock_opts(path, ttl, opts).await
+    }
+
+    async fn renew_lock_opts(
+        &self,
+        lock: &StorageLock,
+        ttl: Duration,
+        opts: &CallOptions,
+    ) -> Result<StorageLock, StorageError> {
+        self.inner.renew_lock_opts(lock, ttl, opts).await
+    }
+
+    async fn release_lock_opts(
+        &self,
+        lock: &StorageLock,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.inner.release_lock_opts(lock, opts).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use std::sync::atomic::AtomicU32;
+
+    /// Memory that fails the first `failures` calls with `error`
+    struct Flaky {
+        inner: MemoryStorage,
+        failures: AtomicU32,
+        error: fn() -> StorageError,
+    }
+
+    impl Flaky {
+        fn check(&self) -> Result<(), StorageError> {
+            let left = self.failures.load(Ordering::SeqCst);
+            if left == 0 {
+                return Ok(());
+            }
+            self.failures.store(left - 1, Ordering::SeqCst);
+            Err((self.error)())
+        }
+    }
+
+    #[async_trait]
+    impl Storage for Flaky {
+        fn backend(&self) -> StorageBackend {
+            StorageBackend::Memory
+        }
+
+        fn capabilities(&self) -> StorageCapabilities {
+            self.inner.capabilities()
+        }
+
+        async fn stat_opts(
+            &self,
+            path: &UniversalPath,
+            opts: &CallOptions,
+        ) -> Result<EntryMetadata, StorageError> {
+            self.check()?;
+            self.inner.stat_opts(path, opts).await
+        }
+
+        async fn read_opts(
+            &self,
+            path: &UniversalPath,
+            opts: &CallOptions,
+        ) -> Result<Vec<u8>, StorageError> {
+            self.check()?;
+            self.inner.read_opts(path, opts).await
+        }
+
+        async fn read_range_opts(
+            &self,
+            path: &UniversalPath,
+            range: Range<u64>,
+            opts: &CallOptions,
+        ) -> Result<Vec<u8>, StorageError> {
+            self.check()?;
+            self.inner.read_range_opts(path, range, opts).await
+        }
+
+        async fn list_opts(
+            &self,
+            path: &UniversalPath,
+            opts: &CallOptions,
+        ) -> Result<Vec<UniversalPath>, StorageError> {
+            self.check()?;
+            self.inner.list_opts(path, opts).await
+        }
+
+        async fn delete_opts(
+            &self,
+            path: &UniversalPath,
+            opts: &CallOptions,
+        ) -> Result<(), StorageError> {
+            // Carried out, but the answer is lost
+            self.inner.delete_opts(path, opts).await?;
+            self.check()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retries_transient_failures() {
+        let inner = MemoryStorage::new();
+        let path = UniversalPath::from_uri_str("mem://retry/a.flac").unwrap();
+        inner.write(&path, b"flac").await.unwrap();
+        let flaky = Arc::new(Flaky {
+            inner,
+            failures: AtomicU32::new(2),
+            error: || StorageError::Unavailable("503".into()),
+        });
+        let quick = RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            ..RetryPolicy::network()
+        };
+        let storage = RetryingStorage::new(flaky.clone()).with_policy(quick.clone());
+        assert_eq!(storage.read(&path).await.unwrap(), b"flac");
+        assert_eq!(storage.retries(), 2);
+
+        // Out of attempts
+        flaky.failures.store(10, Ordering::SeqCst);
+        let once = quick.clone().with_max_attempts(2);
+        let storage = storage.with_backend_policy(StorageBackend::Memory, once);
+        assert!(matches!(
+            storage.stat(&path).await,
+            Err(StorageError::Unavailable(_))
+        ));
+        assert_eq!(flaky.failures.load(Ordering::SeqCst), 8);
+
+        // Not worth retrying
+        flaky.failures.store(1, Ordering::SeqCst);
+        let dyn_storage: Arc<dyn Storage> = flaky.clone();
+        let strict = RetryingStorage::new(dyn_storage)
+            .with_policy(quick)
+            .with_classifier(|e| matches!(e, StorageError::Timeout(_)));
+        assert!(strict.list(&path.parent().unwrap()).await.is_err());
+        assert_eq!(strict.list(&path.parent().unwrap()).await.unwrap().len(), 1);
+
+        // The first delete went through before failing
+        flaky.failures.store(1, Ordering::SeqCst);
+        let storage = RetryingStorage::new(flaky.clone()).with_policy(RetryPolicy {
+            initial_backoff: Duration::ZERO,
+            ..RetryPolicy::network()
+        });
+        storage.delete(&path).await.unwrap();
+        assert!(matches!(
+            storage.delete(&path).await,
+            Err(StorageError::NotFound)
+        ));
+    }
+}
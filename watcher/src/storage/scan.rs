@@ -0,0 +1,474 @@
+use super::fd_budget::{FdBudget, fd_budget};
+use super::multi_scan::ScanShare;
+use super::{
+    CallOptions, EntryKind, EntryMetadata, FilterSet, SkipCounts, SkipReason, Skipped, Storage,
+    StorageBackend, StorageError, WalkItem,
+};
+use crate::media::detect_format;
+use crate::universal_path::UniversalPath;
+use futures::{Stream, StreamExt, stream::FuturesUnordered};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, ready},
+};
+use tokio::sync::{mpsc, watch};
+
+/// Entries found but not taken from the stream yet before the scan waits; bounds the
+/// memory a slow consumer can make a fast scan use.
+const SCAN_BUFFER: usize = 1024;
+
+/// `list` and `stat` calls a `Scanner` keeps in flight on `backend` unless told
+/// otherwise: many on local disks and object stores, few on servers that limit
+/// connections per client.
+pub fn default_scan_concurrency(backend: &StorageBackend) -> usize {
+    match backend {
+        StorageBackend::Local | StorageBackend::Memory => 32,
+        StorageBackend::S3 => 64,
+        StorageBackend::Http
+        | StorageBackend::Https
+        | StorageBackend::WebDav
+        | StorageBackend::WebDavs => 16,
+        StorageBackend::NetworkDrive => 8,
+        StorageBackend::Ftp | StorageBackend::Sftp => 4,
+        StorageBackend::Other(_) => 8,
+    }
+}
+
+/// How far a scan has got.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ScanProgress {
+    /// Files, directories and anything else found so far
+    pub entries: u64,
+    pub files: u64,
+    pub directories: u64,
+    /// Total size of the files found so far
+    pub bytes: u64,
+    pub errors: u64,
+    /// Entries left out, by reason
+    pub skipped: SkipCounts,
+    /// Every directory found has been listed
+    pub finished: bool,
+}
+
+/// Walks a tree with many `list` and `stat` calls in flight at once, for trees too
+/// big to walk one call at a time. Entries come out in whatever order the calls
+/// finish; use `StorageExt::walk_with` where the order matters. Directories reached
+/// through symlinks are reported but not descended into, so links cannot loop.
+///
+/// An error listing a directory or stat'ing an entry is yielded and counted, and the
+/// scan carries on with the rest; an entry that vanished since its directory was
+/// listed is left out.
+///
+/// `scan_items` also yields a `Skipped` record for each entry left out: those the
+/// filter does not take, broken symlinks, and directories that cannot be listed for
+/// want of permission, which are then not errors. Either way they are counted in
+/// `ScanProgress::skipped`.
+///
+/// Each call also takes a descriptor from an `FdBudget`, `fd_budget()` unless given
+/// another, which caps the files and connections open across every scan. A call that
+/// runs out of descriptors anyway is made again later, with fewer calls in flight from
+/// then on, and only fails once it is the only one left.
+#[derive(Clone)]
+pub struct Scanner {
+    storage: Arc<dyn Storage>,
+    concurrency: usize,
+    filter: Option<FilterSet>,
+    share: Option<ScanShare>,
+    budget: FdBudget,
+    detect_formats: bool,
+}
+
+impl Scanner {
+    /// A scanner for `storage`, with as many calls in flight as suits its backend
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        let concurrency = default_scan_concurrency(&storage.backend());
+        Scanner {
+            storage,
+            concurrency,
+            filter: None,
+            share: None,
+            budget: fd_budget().clone(),
+            detect_formats: false,
+        }
+    }
+
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Leave out what `filter` does, without stat'ing entries it excludes by name
+    pub fn with_filter(mut self, filter: FilterSet) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Read the start of every file to tell its format by, as `detect_format` does,
+    /// into `EntryMetadata::format`. The format is left `None` for a file that could
+    /// not be read, which is still reported.
+    pub fn with_format_detection(mut self, detect: bool) -> Self {
+        self.detect_formats = detect;
+        self
+    }
+
+    /// Take descriptors from `budget` instead of the shared one
+    pub fn with_fd_budget(mut self, budget: FdBudget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency
+    }
+
+    pub(super) fn backend(&self) -> StorageBackend {
+        self.storage.backend()
+    }
+
+    /// Make each call only once `share` allows it
+    pub(super) fn with_share(mut self, share: ScanShare) -> Self {
+        self.share = Some(share);
+        self
+    }
+
+    /// Everything under `root`. Must be called within a Tokio runtime; the scan runs on
+    /// a task of its own, which stops once the returned stream is dropped.
+    pub fn scan(&self, root: &UniversalPath) -> ScanStream {
+        ScanStream {
+            items: self.start(root, false),
+        }
+    }
+
+    /// What `scan` yields, with a `Skipped` record for each entry left out
+    pub fn scan_items(&self, root: &UniversalPath) -> ScanItemStream {
+        self.start(root, true)
+    }
+
+    fn start(&self, root: &UniversalPath, report_skips: bool) -> ScanItemStream {
+        let (tx, rx) = mpsc::channel(SCAN_BUFFER);
+        let (progress, watched) = watch::channel(ScanProgress::default());
+        tokio::spawn(self.clone().run(root.as_dir(), report_skips, tx, progress));
+        ScanItemStream {
+            rx,
+            progress: watched,
+        }
+    }
+
+    async fn run(
+        self,
+        root: UniversalPath,
+        report_skips: bool,
+        tx: mpsc::Sender<Result<WalkItem, StorageError>>,
+        progress: watch::Sender<ScanProgress>,
+    ) {
+        let storage = &*self.storage;
+        let budget = &self.budget;
+        let rel = |path: &UniversalPath| path.relative_to(&root).unwrap_or_default();
+        // Files the filter takes, once stat'ed, and only if formats are wanted
+        let sniff = |path: &UniversalPath, meta: &EntryMetadata| {
+            self.detect_formats
+                && meta.kind == EntryKind::File
+                && self
+                    .filter
+                    .as_ref()
+                    .is_none_or(|f| f.accepts_file(&rel(path), meta.size_bytes))
+        };
+        let mut queue = VecDeque::from([Call::List(root.clone())]);
+        let mut in_flight = FuturesUnordered::new();
+        let mut concurrency = self.concurrency;
+        // Counted, and sent only if asked for, returning false once nobody is listening
+        let skip = async |path: UniversalPath, reason: SkipReason| {
+            progress.send_modify(|p| p.skipped.record(reason));
+            !report_skips
+                || tx
+                    .send(Ok(WalkItem::Skipped(Skipped { path, reason })))
+                    .await
+                    .is_ok()
+        };
+        loop {
+            while in_flight.len() < concurrency {
+                let Some(call) = queue.pop_front() else {
+                    break;
+                };
+                let share = self.share.clone();
+                let sniff = &sniff;
+                in_flight.push(async move {
+                    let _slot = match &share {
+                        Some(share) => Some(share.acquire().await),
+                        None => None,
+                    };
+                    let _fd = budget.acquire().await;
+                    call.run(storage, sniff).await
+                });
+            }
+            let done = tokio::select! {
+                _ = tx.closed() => return,
+                done = in_flight.next() => done,
+            };
+            let Some(done) = done else {
+                progress.send_modify(|p| p.finished = true);
+                return;
+            };
+            let found = match done {
+                Done::Listed(children) => {
+                    // Stats before listings, so entries flow while a big tree opens up
+                    for child in children.into_iter().rev() {
+                        let excluded = self
+                            .filter
+                            .as_ref()
+                            .is_some_and(|f| f.excludes(&rel(&child)));
+                        if !excluded {
+                            queue.push_front(Call::Stat(child));
+                        } else if !skip(child, SkipReason::Filtered).await {
+                            return;
+                        }
+                    }
+                    continue;
+                }
+                Done::Gone => continue,
+                Done::Skipped(path, reason) => {
+                    if !skip(path, reason).await {
+                        return;
+                    }
+                    continue;
+                }
+                Done::Denied(dir, _) if report_skips => {
+                    if !skip(dir, SkipReason::PermissionDenied).await {
+                        return;
+                    }
+                    continue;
+                }
+                Done::Stat(path, meta, linked) => {
+                    let is_dir = meta.kind == EntryKind::Directory;
+                    let rejected = self
+                        .filter
+                        .as_ref()
+                        .filter(|_| !is_dir)
+                        .is_some_and(|f| !f.accepts_file(&rel(&path), meta.size_bytes));
+                    if rejected {
+                        if !skip(path, SkipReason::Filtered).await {
+                            return;
+                        }
+                        continue;
+                    }
+                    progress.send_modify(|p| {
+                        p.entries += 1;
+                        match meta.kind {
+                            EntryKind::File => {
+                                p.files += 1;
+                                p.bytes += meta.size_bytes.unwrap_or(0);
+                            }
+                            EntryKind::Directory => p.directories += 1,
+                            EntryKind::Symlink | EntryKind::Other => {}
+                        }
+                    });
+                    let path = match is_dir {
+                        true => path.as_dir(),
+                        false => path,
+                    };
+                    if is_dir && !linked {
+                        queue.push_back(Call::List(path.clone()));
+                    }
+                    Ok(WalkItem::Entry(path, meta))
+                }
+                Done::Exhausted(call, e) => {
+                    budget.record(&e);
+                    if in_flight.is_empty() {
+                        progress.send_modify(|p| p.errors += 1);
+                        Err(e)
+                    } else {
+                        concurrency = in_flight.len();
+                        queue.push_front(call);
+                        continue;
+                    }
+                }
+                Done::Failed(e) | Done::Denied(_, e) => {
+                    progress.send_modify(|p| p.errors += 1);
+                    Err(e)
+                }
+            };
+            if tx.send(found).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+enum Call {
+    List(UniversalPath),
+    Stat(UniversalPath),
+}
+
+enum Done {
+    Listed(Vec<UniversalPath>),
+    /// With whether a directory is a symlink
+    Stat(UniversalPath, EntryMetadata, bool),
+    Gone,
+    Skipped(UniversalPath, SkipReason),
+    /// A directory that could not be listed for want of permission
+    Denied(UniversalPath, StorageError),
+    /// Out of file descriptors, to be made again
+    Exhausted(Call, StorageError),
+    Failed(StorageError),
+}
+
+impl Call {
+    async fn run(
+        self,
+        storage: &dyn Storage,
+        sniff: impl Fn(&UniversalPath, &EntryMetadata) -> bool,
+    ) -> Done {
+        match self {
+            Call::List(dir) => match storage.list(&dir).await {
+                Ok(children) => Done::Listed(children),
+                Err(StorageError::NotFound) => Done::Gone,
+                Err(e) if e.is_fd_exhausted() => Done::Exhausted(Call::List(dir), e),
+                Err(e) if e.is_permission_denied() => Done::Denied(dir, e),
+                Err(e) => Done::Failed(e),
+            },
+            Call::Stat(path) => {
+                let mut meta = match storage.stat(&path).await {
+                    Ok(meta) => meta,
+                    Err(StorageError::NotFound) => {
+                        // Either gone since the listing or a link to nothing
+                        let link = CallOptions::default().with_no_follow();
+                        return match storage.stat_opts(&path, &link).await {
+                            Ok(meta) if meta.kind == EntryKind::Symlink => {
+                                Done::Skipped(path, SkipReason::BrokenSymlink)
+                            }
+                            _ => Done::Gone,
+                        };
+                    }
+                    Err(e) if e.is_fd_exhausted() => return Done::Exhausted(Call::Stat(path), e),
+                    Err(e) => return Done::Failed(e),
+                };
+                if meta.kind != EntryKind::Directory {
+                    if sniff(&path, &meta) {
+                        match detect_format(storage, &path).await {
+                            Ok(format) => meta.format = Some(format),
+                            Err(StorageError::NotFound) => return Done::Gone,
+                            Err(e) if e.is_fd_exhausted() => {
+                                return Done::Exhausted(Call::Stat(path), e);
+                            }
+                            Err(_) => {}
+                        }
+                    }
+                    return Done::Stat(path, meta, false);
+                }
+                match storage.symlink_target(&path).await {
+                    Ok(target) => Done::Stat(path, meta, target.is_some()),
+                    Err(e) if e.is_fd_exhausted() => Done::Exhausted(Call::Stat(path), e),
+                    Err(e) => Done::Failed(e),
+                }
+            }
+        }
+    }
+}
+
+/// The entries a `Scanner` finds, as it finds them.
+pub struct ScanStream {
+    items: ScanItemStream,
+}
+
+impl ScanStream {
+    /// The scan's progress, updated as entries are found, including those not taken
+    /// from the stream yet
+    pub fn progress(&self) -> watch::Receiver<ScanProgress> {
+        self.items.progress()
+    }
+}
+
+impl Stream for ScanStream {
+    type Item = Result<(UniversalPath, EntryMetadata), StorageError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match ready!(self.items.poll_next_unpin(cx)) {
+                Some(Ok(WalkItem::Entry(path, meta))) => {
+                    return Poll::Ready(Some(Ok((path, meta))));
+                }
+                Some(Ok(WalkItem::Skipped(_))) => {}
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+/// The entries a `Scanner` finds and those it leaves out, as it comes to them.
+pub struct ScanItemStream {
+    rx: mpsc::Receiver<Result<WalkItem, StorageError>>,
+    progress: watch::Receiver<ScanProgress>,
+}
+
+impl ScanItemStream {
+    /// The scan's progress, updated as entries are found, including those not taken
+    /// from the stream yet
+    pub fn progress(&self) -> watch::Receiver<ScanProgress> {
+        self.progress.clone()
+    }
+}
+
+impl Stream for ScanItemStream {
+    type Item = Result<WalkItem, StorageError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_scanner() {
+        let storage = Arc::new(MemoryStorage::new());
+        let root = UniversalPath::from_uri_str("mem://nas/music/").unwrap();
+        for artist in 0..5 {
+            let dir = root.join(format!("artist{artist}")).as_dir();
+            storage.create_dir(&dir).await.unwrap();
+            for track in 0..20 {
+                let path = root.join_all(&[format!("artist{artist}"), format!("{track:02}.flac")]);
+                storage.write(&path, b"flac").await.unwrap();
+            }
+        }
+        storage
+            .write(&root.join("cover.jpg"), b"jpeg data")
+            .await
+            .unwrap();
+
+        let mut scan = Scanner::new(storage.clone())
+            .with_concurrency(3)
+            .scan(&root);
+        let progress = scan.progress();
+        let mut found = Vec::new();
+        while let Some(entry) = scan.next().await {
+            let (path, _) = entry.unwrap();
+            found.push(path.relative_to(&root).unwrap().join("/"));
+        }
+        found.sort();
+        assert_eq!(found.len(), 106);
+        assert_eq!(found[0], "artist0");
+        assert_eq!(found[1], "artist0/00.flac");
+        assert_eq!(found.last().unwrap(), "cover.jpg");
+
+        let progress = *progress.borrow();
+        assert_eq!(
+            progress,
+            ScanProgress {
+                entries: 106,
+                files: 101,
+                directories: 5,
+                bytes: 100 * 4 + 9,
+                errors: 0,
+                skipped: SkipCounts::default(),
+                finished: true,
+            }
+        );
+    }
+}
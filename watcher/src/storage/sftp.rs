@@ -0,0 +1,667 @@
+use super::{
+    CallOptions, Credentials, EntryKind, EntryMetadata, EntryPermissions, Storage, StorageBackend,
+    StorageCapabilities, StorageError, clamp_range,
+};
+use crate::universal_path::UniversalPath;
+use async_trait::async_trait;
+use russh::{
+    client,
+    keys::{PrivateKeyWithHashAlg, PublicKeyOrCertificate, agent::client::AgentClient},
+};
+use russh_sftp::{
+    client::{
+        SftpSession,
+        error::Error as SftpError,
+        fs::{DirEntry, Metadata},
+    },
+    protocol::{FileType, StatusCode},
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::PathBuf,
+    sync::{Arc, LazyLock},
+    time::{Duration, UNIX_EPOCH},
+};
+use tokio::sync::Mutex;
+
+pub const DEFAULT_SFTP_PORT: u16 = 22;
+
+/// How to authenticate the SSH session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SftpAuth {
+    Password(String),
+    KeyFile {
+        path: PathBuf,
+        passphrase: Option<String>,
+    },
+    /// Try every identity offered by the agent at `SSH_AUTH_SOCK`
+    Agent,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SftpConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub auth: SftpAuth,
+    /// known_hosts file to verify the server key against; `~/.ssh/known_hosts` when None.
+    pub known_hosts: Option<PathBuf>,
+    /// Connect to servers missing from known_hosts. A key that contradicts a recorded one
+    /// is always rejected.
+    pub accept_unknown_hosts: bool,
+}
+
+impl SftpConfig {
+    pub fn new<H: Into<String>, U: Into<String>>(host: H, username: U, auth: SftpAuth) -> Self {
+        SftpConfig {
+            host: host.into(),
+            port: DEFAULT_SFTP_PORT,
+            username: username.into(),
+            auth,
+            known_hosts: None,
+            accept_unknown_hosts: false,
+        }
+    }
+
+    /// Settings for an `sftp://` path with no explicit configuration: the user in the
+    /// URI or else the local user name, and the URI's password, else the SSH agent if
+    /// one is running, else the first default key file.
+    pub fn for_path(path: &UniversalPath) -> Result<Self, StorageError> {
+        if path.backend() != &StorageBackend::Sftp {
+            return Err(StorageError::InvalidPath);
+        }
+        let host = path.host().ok_or(StorageError::InvalidPath)?;
+        let username = path
+            .user()
+            .map(str::to_string)
+            .ok_or(())
+            .or_else(|_| std::env::var("USER"))
+            .or_else(|_| std::env::var("USERNAME"))
+            .map_err(|_| StorageError::Connection("no user name for SFTP login".to_string()))?;
+
+        let home = std::env::var_os("HOME").map(PathBuf::from);
+        let auth = if let Some(password) = path.password() {
+            SftpAuth::Password(password.to_string())
+        } else if std::env::var_os("SSH_AUTH_SOCK").is_some() {
+            SftpAuth::Agent
+        } else {
+            home.iter()
+                .flat_map(|h| ["id_ed25519", "id_ecdsa", "id_rsa"].map(|k| h.join(".ssh").join(k)))
+                .find(|p| p.is_file())
+                .map(|path| SftpAuth::KeyFile {
+                    path,
+                    passphrase: None,
+                })
+                .unwrap_or(SftpAuth::Agent)
+        };
+
+        let mut config = SftpConfig::new(host, username, auth);
+        config.port = path.port().unwrap_or(DEFAULT_SFTP_PORT);
+        Ok(config)
+    }
+
+    /// Log in with `credentials` instead of the local user name and default keys
+    pub fn apply_credentials(&mut self, credentials: &Credentials) -> Result<(), StorageError> {
+        let (username, auth) = match credentials.clone() {
+            Credentials::Password { username, password } => {
+                (username, SftpAuth::Password(password))
+            }
+            Credentials::KeyFile {
+                username,
+                path,
+                passphrase,
+            } => (username, SftpAuth::KeyFile { path, passphrase }),
+            Credentials::Agent { username } => (username, SftpAuth::Agent),
+            _ => {
+                return Err(StorageError::Connection(format!(
+                    "SFTP needs a password, key file or agent login, not {credentials:?}"
+                )));
+            }
+        };
+        self.username = username;
+        self.auth = auth;
+        Ok(())
+    }
+}
+
+struct HostKeyCheck {
+    host: String,
+    port: u16,
+    known_hosts: Option<PathBuf>,
+    accept_unknown: bool,
+}
+
+impl client::Handler for HostKeyCheck {
+    type Error = russh::Error;
+
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &PublicKeyOrCertificate,
+    ) -> Result<bool, Self::Error> {
+        let PublicKeyOrCertificate::PublicKey { key, .. } = server_public_key else {
+            return Ok(self.accept_unknown);
+        };
+        let known = match &self.known_hosts {
+            Some(file) => russh::keys::check_known_hosts_path(&self.host, self.port, key, file),
+            None => russh::keys::check_known_hosts(&self.host, self.port, key),
+        };
+        Ok(match known {
+            Ok(true) => true,
+            Err(russh::keys::Error::KeyChanged { .. }) => false,
+            Ok(false) | Err(_) => self.accept_unknown,
+        })
+    }
+}
+
+struct Connection {
+    ssh: client::Handle<HostKeyCheck>,
+    sftp: SftpSession,
+}
+
+type PoolKey = (String, u16, String);
+
+/// Pool of storages by host, port and user, so repeated `open_storage_for` calls share
+/// one SSH connection.
+/// Stat requests `stat_many` keeps outstanding on the session at once
+const STAT_PIPELINE: usize = 32;
+
+static SHARED: LazyLock<std::sync::Mutex<HashMap<PoolKey, SftpStorage>>> =
+    LazyLock::new(Default::default);
+
+pub(crate) const CAPABILITIES: StorageCapabilities = StorageCapabilities {
+    can_stat: true,
+    can_read: true,
+    can_read_range: true,
+    can_list: true,
+    can_glob: true,
+    can_write: false,
+    can_delete: false,
+    can_rename: false,
+    can_checksum: false,
+};
+
+/// Storage over SFTP for a single server.
+///
+/// The SSH session is opened on first use and reused by every later call, including
+/// concurrent ones; clones share it too. A transport failure drops the session and the
+/// next call reconnects.
+#[derive(Clone)]
+pub struct SftpStorage {
+    config: Arc<SftpConfig>,
+    connection: Arc<Mutex<Option<Arc<Connection>>>>,
+}
+
+impl SftpStorage {
+    pub fn new(config: SftpConfig) -> Self {
+        SftpStorage {
+            config: Arc::new(config),
+            connection: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// A storage sharing its connection with every other `shared` call for the same
+    /// server and user.
+    pub fn shared(config: SftpConfig) -> Self {
+        let key = (config.host.clone(), config.port, config.username.clone());
+        let mut pool = SHARED.lock().unwrap_or_else(|e| e.into_inner());
+        pool.entry(key)
+            .or_insert_with(|| SftpStorage::new(config))
+            .clone()
+    }
+
+    pub fn config(&self) -> &SftpConfig {
+        &self.config
+    }
+
+    /// The server-side path for `upath`, which must be on this storage's host and port
+    fn remote_path(&self, upath: &UniversalPath) -> Result<String, StorageError> {
+        let same_host = upath
+            .host()
+            .is_some_and(|h| h.eq_ignore_ascii_case(&self.config.host));
+        let same_port = upath.port().unwrap_or(DEFAULT_SFTP_PORT) == self.config.port;
+        if upath.backend() != &StorageBackend::Sftp || !same_host || !same_port {
+            return Err(StorageError::InvalidPath);
+        }
+        Ok(upath.path())
+    }
+
+    async fn connection(&self) -> Result<Arc<Connection>, StorageError> {
+        let mut slot = self.connection.lock().await;
+        if let Some(conn) = slot.as_ref()
+            && !conn.ssh.is_closed()
+        {
+            return Ok(conn.clone());
+        }
+        let conn = Arc::new(self.connect().await?);
+        *slot = Some(conn.clone());
+        Ok(conn)
+    }
+
+    async fn connect(&self) -> Result<Connection, StorageError> {
+        let config = &self.config;
+        let handler = HostKeyCheck {
+            host: config.host.clone(),
+            port: config.port,
+            known_hosts: config.known_hosts.clone(),
+            accept_unknown: config.accept_unknown_hosts,
+        };
+        let ssh_config = Arc::new(client::Config {
+            inactivity_timeout: Some(Duration::from_secs(300)),
+            ..Default::default()
+        });
+        let mut ssh = client::connect(ssh_config, (config.host.as_str(), config.port), handler)
+            .await
+            .map_err(connection_error)?;
+
+        let user = config.username.as_str();
+        let authenticated = match &config.auth {
+            SftpAuth::Password(password) => ssh
+                .authenticate_password(user, password)
+                .await
+                .map_err(connection_error)?
+                .success(),
+            SftpAuth::KeyFile { path, passphrase } => {
+                let key = russh::keys::load_secret_key(path, passphrase.as_deref())
+                    .map_err(connection_error)?;
+                let hash = ssh
+                    .best_supported_rsa_hash()
+                    .await
+                    .map_err(connection_error)?
+                    .flatten();
+                ssh.authenticate_publickey(user, PrivateKeyWithHashAlg::new(Arc::new(key), hash))
+                    .await
+                    .map_err(connection_error)?
+                    .success()
+            }
+            SftpAuth::Agent => {
+                let mut agent = AgentClient::connect_env().await.map_err(connection_error)?;
+                let identities = agent.request_identities().await.map_err(connection_error)?;
+                let hash = ssh
+                    .best_supported_rsa_hash()
+                    .await
+                    .map_err(connection_error)?
+                    .flatten();
+                let mut authenticated = false;
+                for identity in identities {
+                    let russh::keys::agent::AgentIdentity::PublicKey { key, .. } = identity else {
+                        continue;
+                    };
+                    if ssh
+                        .authenticate_publickey_with(user, key, hash, &mut agent)
+                        .await
+                        .map_err(connection_error)?
+                        .success()
+                    {
+                        authenticated = true;
+                        break;
+                    }
+                }
+                authenticated
+            }
+        };
+        if !authenticated {
+            return Err(StorageError::Connection(format!(
+                "SSH authentication failed for {}@{}",
+                config.username, config.host
+            )));
+        }
+
+        let channel = ssh.channel_open_session().await.map_err(connection_error)?;
+        channel
+            .request_subsystem(true, "sftp")
+            .await
+            .map_err(connection_error)?;
+        let sftp = SftpSession::new(channel.into_stream())
+            .await
+            .map_err(connection_error)?;
+        Ok(Connection { ssh, sftp })
+    }
+
+    /// Run one operation on the shared session, dropping the session if the failure
+    /// was a transport problem rather than a server-side status.
+    async fn with_session<T, F, Fut>(&self, op: F) -> Result<T, StorageError>
+    where
+        F: FnOnce(Arc<Connection>) -> Fut,
+        Fut: Future<Output = Result<T, SftpError>>,
+    {
+        let conn = self.connection().await?;
+        match op(conn.clone()).await {
+            Ok(value) => Ok(value),
+            Err(e) => {
+                if !matches!(e, SftpError::Status(_)) {
+                    let mut slot = self.connection.lock().await;
+                    if slot.as_ref().is_some_and(|c| Arc::ptr_eq(c, &conn)) {
+                        *slot = None;
+                    }
+                }
+                Err(map_sftp_error(e))
+            }
+        }
+    }
+
+    async fn stat_inner(&self, path: &UniversalPath) -> Result<EntryMetadata, StorageError> {
+        let remote = self.remote_path(path)?;
+        let md = self
+            .with_session(|c| async move { c.sftp.metadata(remote).await })
+            .await?;
+        let mut meta = to_entry_metadata(&md);
+        meta.is_hidden = super::is_hidden_name(path);
+        Ok(meta)
+    }
+
+    async fn read_inner(&self, path: &UniversalPath) -> Result<Vec<u8>, StorageError> {
+        use tokio::io::AsyncReadExt;
+        let remote = self.remote_path(path)?;
+        self.with_session(|c| async move {
+            if c.sftp.metadata(remote.clone()).await?.is_dir() {
+                return Ok(None);
+            }
+            let mut file = c.sftp.open(remote).await?;
+            let mut buf = Vec::new();
+            file.read_to_end(&mut buf).await?;
+            Ok(Some(buf))
+        })
+        .await?
+        .ok_or(StorageError::NotAFile)
+    }
+
+    async fn read_range_inner(
+        &self,
+        path: &UniversalPath,
+        range: std::ops::Range<u64>,
+    ) -> Result<Vec<u8>, StorageError> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        if range.start >= range.end {
+            return Ok(Vec::new());
+        }
+        let remote = self.remote_path(path)?;
+        self.with_session(|c| async move {
+            let md = c.sftp.metadata(remote.clone()).await?;
+            if md.file_type() != FileType::File {
+                return Ok(Err(StorageError::NotAFile));
+            }
+            let range = match clamp_range(range, md.len()) {
+                Ok(range) => range,
+                Err(e) => return Ok(Err(e)),
+            };
+            let to_read = (range.end - range.start) as usize;
+            let mut file = c.sftp.open(remote).await?;
+            file.seek(std::io::SeekFrom::Start(range.start)).await?;
+            let mut buf = vec![0u8; to_read];
+            let mut read_so_far = 0usize;
+            while read_so_far < to_read {
+                let n = file.read(&mut buf[read_so_far..]).await?;
+                if n == 0 {
+                    break;
+                }
+                read_so_far += n;
+            }
+            buf.truncate(read_so_far);
+            Ok(Ok(buf))
+        })
+        .await?
+    }
+
+    async fn list_inner(&self, path: &UniversalPath) -> Result<Vec<UniversalPath>, StorageError> {
+        let dir = path.as_dir();
+        Ok(self
+            .listing(path)
+            .await?
+            .map(|entry| {
+                let child = dir.join(entry.file_name());
+                if entry.file_type().is_dir() {
+                    child.as_dir()
+                } else {
+                    child
+                }
+            })
+            .collect())
+    }
+
+    /// From the attributes readdir returns; symlinks, which they describe rather
+    /// than what they point to, are stat'ed on their own
+    async fn list_with_metadata_inner(
+        &self,
+        path: &UniversalPath,
+    ) -> Result<Vec<(UniversalPath, EntryMetadata)>, StorageError> {
+        let dir = path.as_dir();
+        let mut listed = Vec::new();
+        for entry in self.listing(path).await? {
+            let child = dir.join(entry.file_name());
+            let meta = match entry.file_type() {
+                FileType::Symlink => match self.stat_inner(&child).await {
+                    Ok(meta) => meta,
+                    Err(StorageError::NotFound) => continue,
+                    Err(e) => return Err(e),
+                },
+                _ => EntryMetadata {
+                    is_hidden: super::is_hidden_name(&child),
+                    format: None,
+                    ..to_entry_metadata(&entry.metadata())
+                },
+            };
+            let child = match meta.kind {
+                EntryKind::Directory => child.as_dir(),
+                _ => child,
+            };
+            listed.push((child, meta));
+        }
+        Ok(listed)
+    }
+
+    /// The entries of the directory at `path`, less `.` and `..`
+    async fn listing(
+        &self,
+        path: &UniversalPath,
+    ) -> Result<impl Iterator<Item = DirEntry> + use<>, StorageError> {
+        let remote = self.remote_path(path)?;
+        let listing = self
+            .with_session(|c| async move {
+                if !c.sftp.metadata(remote.clone()).await?.is_dir() {
+                    return Ok(None);
+                }
+                Ok(Some(c.sftp.read_dir(remote).await?))
+            })
+            .await?
+            .ok_or(StorageError::NotADirectory)?;
+        Ok(listing.filter(|entry| !matches!(entry.file_name().as_str(), "." | "..")))
+    }
+}
+
+fn connection_error<E: std::fmt::Display>(e: E) -> StorageError {
+    StorageError::Connection(e.to_string())
+}
+
+fn map_sftp_error(e: SftpError) -> StorageError {
+    match e {
+        SftpError::Status(status) => match status.status_code {
+            StatusCode::NoSuchFile => StorageError::NotFound,
+            StatusCode::PermissionDenied => StorageError::Io(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                status.error_message,
+            )),
+            code => StorageError::Io(std::io::Error::other(format!(
+                "{code}: {}",
+                status.error_message
+            ))),
+        },
+        other => connection_error(other),
+    }
+}
+
+fn to_entry_metadata(md: &Metadata) -> EntryMetadata {
+    let kind = match md.file_type() {
+        FileType::Dir => EntryKind::Directory,
+        FileType::File => EntryKind::File,
+        _ => EntryKind::Other,
+    };
+    EntryMetadata {
+        size_bytes: (kind == EntryKind::File).then(|| md.len()),
+        kind,
+        modified_at: md
+            .mtime
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs.into())),
+        created_at: None,
+        permissions: EntryPermissions {
+            uid: md.uid,
+            gid: md.gid,
+            ..md.permissions
+                .map(EntryPermissions::from_mode)
+                .unwrap_or_default()
+        },
+        is_hidden: false,
+        format: None,
+    }
+}
+
+#[async_trait]
+impl Storage for SftpStorage {
+    fn backend(&self) -> StorageBackend {
+        StorageBackend::Sftp
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        CAPABILITIES
+    }
+
+    async fn stat_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<EntryMetadata, StorageError> {
+        opts.enforce_timeout(self.stat_inner(path)).await
+    }
+
+    /// Pipelined: requests go out on the one session without waiting for the answers
+    /// to those before them
+    async fn stat_many_opts(
+        &self,
+        paths: &[UniversalPath],
+        opts: &CallOptions,
+    ) -> Vec<Result<EntryMetadata, StorageError>> {
+        use futures::StreamExt;
+        let mut stats = Vec::with_capacity(paths.len());
+        for path in paths {
+            stats.push(opts.enforce_timeout(self.stat_inner(path)));
+        }
+        futures::stream::iter(stats).buffered(STAT_PIPELINE).collect().await
+    }
+
+    async fn read_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        opts.enforce_timeout(self.read_inner(path)).await
+    }
+
+    async fn read_range_opts(
+        &self,
+        path: &UniversalPath,
+        range: std::ops::Range<u64>,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        opts.enforce_timeout(self.read_range_inner(path, range))
+            .await
+    }
+
+    async fn list_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<UniversalPath>, StorageError> {
+        opts.enforce_timeout(self.list_inner(path)).await
+    }
+
+    async fn list_with_metadata_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<(UniversalPath, EntryMetadata)>, StorageError> {
+        opts.enforce_timeout(self.list_with_metadata_inner(path))
+            .await
+    }
+
+    /// SFTP has no copy request short of the `copy-data` extension, and this backend
+    /// does not write, so a copy cannot be streamed through it either
+    async fn copy_opts(
+        &self,
+        _from: &UniversalPath,
+        _to: &UniversalPath,
+        _opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        Err(StorageError::UnsupportedFeature("copy"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remote_path_checks_host_and_port() {
+        let storage = SftpStorage::new(SftpConfig::new(
+            "nas.local",
+            "me",
+            SftpAuth::Password("pw".to_string()),
+        ));
+        let ok = UniversalPath::from_uri_str("sftp://NAS.local/srv/music/a.flac").unwrap();
+        assert_eq!(storage.remote_path(&ok).unwrap(), "/srv/music/a.flac");
+
+        for other in [
+            "sftp://other/srv/music",
+            "sftp://nas.local:2222/srv/music",
+            "ftp://nas.local/srv/music",
+        ] {
+            let path = UniversalPath::from_uri_str(other).unwrap();
+            assert!(matches!(
+                storage.remote_path(&path),
+                Err(StorageError::InvalidPath)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_config_for_path() {
+        let path = UniversalPath::from_uri_str("sftp://nas.local:2222/music").unwrap();
+        if let Ok(config) = SftpConfig::for_path(&path) {
+            assert_eq!(config.host, "nas.local");
+            assert_eq!(config.port, 2222);
+        }
+        let local = UniversalPath::local("/music");
+        assert!(SftpConfig::for_path(&local).is_err());
+
+        let a = SftpStorage::shared(SftpConfig::new("h", "u", SftpAuth::Agent));
+        let b = SftpStorage::shared(SftpConfig::new("h", "u", SftpAuth::Agent));
+        assert!(Arc::ptr_eq(&a.connection, &b.connection));
+    }
+
+    #[test]
+    fn test_metadata_conversion() {
+        let mut md = Metadata::empty();
+        md.set_regular(true);
+        md.size = Some(42);
+        md.mtime = Some(1_700_000_000);
+        md.permissions = Some(0o100640);
+        md.uid = Some(1000);
+        let entry = to_entry_metadata(&md);
+        assert_eq!(entry.kind, EntryKind::File);
+        assert_eq!(entry.size_bytes, Some(42));
+        assert_eq!(entry.permissions.mode, Some(0o640));
+        assert_eq!(entry.permissions.uid, Some(1000));
+        assert_eq!(entry.permissions.gid, None);
+        assert_eq!(entry.permissions.readonly, Some(false));
+        assert_eq!(
+            entry.modified_at,
+            Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+
+        let mut md = Metadata::empty();
+        md.set_dir(true);
+        let entry = to_entry_metadata(&md);
+        assert_eq!(entry.kind, EntryKind::Directory);
+        assert_eq!(entry.size_bytes, None);
+    }
+}
@@ -0,0 +1,305 @@
+use super::{Storage, StorageError};
+use crate::universal_path::UniversalPath;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+
+/// Samples kept per host; older ones are dropped as the clocks drift
+const MAX_SAMPLES: usize = 16;
+
+/// Skew worth telling the user about: S3 rejects signatures 15 minutes out, and well
+/// before that modification times stop lining up with when things happened
+pub const SKEW_WARNING: Duration = Duration::from_secs(60);
+
+/// How far a host's clock is from ours, as best we can tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SkewEstimate {
+    /// Positive when the host's clock is ahead of ours
+    pub offset_ms: i64,
+    /// The true offset is within this much either way
+    pub uncertainty_ms: u64,
+    pub samples: usize,
+}
+
+impl SkewEstimate {
+    /// Whether the clocks are certainly more than `threshold` apart
+    pub fn exceeds(&self, threshold: Duration) -> bool {
+        self.offset_ms
+            .unsigned_abs()
+            .saturating_sub(self.uncertainty_ms)
+            > threshold.as_millis() as u64
+    }
+
+    /// A time the host reported, e.g. a modification time, on our clock
+    pub fn to_local(&self, remote: SystemTime) -> SystemTime {
+        shift(remote, -self.offset_ms)
+    }
+
+    /// A local time on the host's clock
+    pub fn to_remote(&self, local: SystemTime) -> SystemTime {
+        shift(local, self.offset_ms)
+    }
+}
+
+/// A host whose clock is far enough off to make its modification times misleading.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SkewDiagnostic {
+    pub host: String,
+    pub estimate: SkewEstimate,
+}
+
+impl fmt::Display for SkewDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (secs, side) = match self.estimate.offset_ms {
+            ms if ms >= 0 => (ms as f64 / 1000.0, "ahead of"),
+            ms => (-ms as f64 / 1000.0, "behind"),
+        };
+        write!(
+            f,
+            "{}'s clock is {secs:.1}s {side} this machine's (±{:.1}s)",
+            self.host,
+            self.estimate.uncertainty_ms as f64 / 1000.0
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    offset_ms: i64,
+    uncertainty_ms: u64,
+}
+
+/// Per-host clock offsets, estimated by comparing times servers report, such as HTTP
+/// `Date` headers, with when we sent the request and got the answer.
+///
+/// Each sample brackets the offset by the round trip and the resolution of the
+/// server's clock; the estimate is the tightest recent sample, as NTP's clock filter
+/// does. Backends feed `clock_skew()`, the tracker shared by the process.
+#[derive(Debug, Default)]
+pub struct ClockSkew {
+    hosts: Mutex<BTreeMap<String, VecDeque<Sample>>>,
+}
+
+impl ClockSkew {
+    pub fn new() -> Self {
+        ClockSkew::default()
+    }
+
+    /// Record that `host` said it was `server_time` during a request sent at
+    /// `sent_at` and answered at `received_at`, with a clock that counts in steps of
+    /// `resolution`, e.g. a second for `Date` headers
+    pub fn observe(
+        &self,
+        host: &str,
+        server_time: SystemTime,
+        sent_at: SystemTime,
+        received_at: SystemTime,
+        resolution: Duration,
+    ) {
+        let (sent, received) = (millis(sent_at), millis(received_at).max(millis(sent_at)));
+        let resolution = resolution.as_millis() as i64;
+        // Servers truncate, so the true time is up to a step later than reported
+        let server = millis(server_time) + resolution / 2;
+        let sample = Sample {
+            offset_ms: server - (sent + received) / 2,
+            uncertainty_ms: ((received - sent + resolution) / 2) as u64,
+        };
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        let samples = hosts.entry(host.to_string()).or_default();
+        if samples.len() == MAX_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    pub fn estimate(&self, host: &str) -> Option<SkewEstimate> {
+        let hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        let samples = hosts.get(host)?;
+        let best = samples.iter().min_by_key(|s| s.uncertainty_ms)?;
+        Some(SkewEstimate {
+            offset_ms: best.offset_ms,
+            uncertainty_ms: best.uncertainty_ms,
+            samples: samples.len(),
+        })
+    }
+
+    /// Forget what was learned about `host`, e.g. after its clock was fixed
+    pub fn reset(&self, host: &str) {
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        hosts.remove(host);
+    }
+
+    /// Hosts whose clocks are more than `threshold` from ours, by host name
+    pub fn diagnostics(&self, threshold: Duration) -> Vec<SkewDiagnostic> {
+        let hosts: Vec<_> = {
+            let hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+            hosts.keys().cloned().collect()
+        };
+        hosts
+            .into_iter()
+            .filter_map(|host| {
+                let estimate = self.estimate(&host)?;
+                estimate
+                    .exceeds(threshold)
+                    .then_some(SkewDiagnostic { host, estimate })
+            })
+            .collect()
+    }
+
+    /// Take a sample from a backend without a clock to ask, such as SFTP or SMB, by
+    /// writing a small file in `dir` and reading back its modification time
+    pub async fn probe(
+        &self,
+        storage: &dyn Storage,
+        dir: &UniversalPath,
+    ) -> Result<SkewEstimate, StorageError> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let name = format!(
+            ".otolith-clock-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        );
+        let file = dir.join(&name);
+        let sent_at = SystemTime::now();
+        storage.write(&file, b"").await?;
+        let stat = storage.stat(&file).await;
+        let received_at = SystemTime::now();
+        storage.delete(&file).await?;
+        let modified = stat?
+            .modified_at
+            .ok_or(StorageError::UnsupportedFeature("modification times"))?;
+        // Some filesystems keep only whole or even seconds
+        let resolution = match millis(modified) % 1000 {
+            0 => Duration::from_secs(2),
+            _ => Duration::ZERO,
+        };
+        let host = host_key(dir);
+        self.observe(&host, modified, sent_at, received_at, resolution);
+        Ok(self.estimate(&host).expect("sample just recorded"))
+    }
+}
+
+static SKEW: LazyLock<ClockSkew> = LazyLock::new(ClockSkew::new);
+
+/// The tracker built-in backends record into as they talk to servers
+pub fn clock_skew() -> &'static ClockSkew {
+    &SKEW
+}
+
+/// How hosts are keyed: `host` or `host:port`, `localhost` for local paths
+pub fn host_key(path: &UniversalPath) -> String {
+    match (path.host(), path.port()) {
+        (Some(host), Some(port)) => format!("{host}:{port}"),
+        (Some(host), None) => host.to_string(),
+        (None, _) => "localhost".to_string(),
+    }
+}
+
+/// Record a response's `Date` header, if it has a valid one
+#[cfg(any(feature = "http", feature = "s3", feature = "webdav"))]
+pub(crate) fn observe_date_header(host: &str, sent_at: SystemTime, date: Option<&str>) {
+    let received_at = SystemTime::now();
+    let Some(date) = date.and_then(|d| chrono::DateTime::parse_from_rfc2822(d).ok()) else {
+        return;
+    };
+    let resolution = Duration::from_secs(1);
+    SKEW.observe(host, date.into(), sent_at, received_at, resolution);
+}
+
+/// Whether two modification times agree to within `tolerance`, for change detection
+/// that should not fire when a server rounds or re-derives a time
+pub(crate) fn mtimes_match(
+    a: Option<SystemTime>,
+    b: Option<SystemTime>,
+    tolerance: Duration,
+) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => {
+            let drift = a.duration_since(b).or_else(|_| b.duration_since(a));
+            drift.is_ok_and(|drift| drift <= tolerance)
+        }
+        (a, b) => a == b,
+    }
+}
+
+fn millis(t: SystemTime) -> i64 {
+    match t.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(d) => d.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    }
+}
+
+fn shift(t: SystemTime, ms: i64) -> SystemTime {
+    let by = Duration::from_millis(ms.unsigned_abs());
+    match ms >= 0 {
+        true => t + by,
+        false => t - by,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_skew_estimates() {
+        let skew = ClockSkew::new();
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let second = Duration::from_secs(1);
+        // A host 90s behind, seen over a slow round trip and then a quick one
+        let slow = (
+            t0 - Duration::from_secs(88),
+            t0,
+            t0 + Duration::from_secs(4),
+        );
+        skew.observe("nas.local", slow.0, slow.1, slow.2, second);
+        let fast = t0 + Duration::from_secs(10);
+        let reported = fast - Duration::from_secs(90);
+        skew.observe(
+            "nas.local",
+            reported,
+            fast,
+            fast + Duration::from_millis(200),
+            second,
+        );
+        let estimate = skew.estimate("nas.local").unwrap();
+        assert_eq!(estimate.samples, 2);
+        assert_eq!(estimate.uncertainty_ms, 600);
+        assert_eq!(estimate.offset_ms, -89_600);
+        assert_eq!(
+            estimate.to_local(reported),
+            fast - Duration::from_millis(400)
+        );
+        assert_eq!(estimate.to_remote(estimate.to_local(reported)), reported);
+
+        let diagnostics = skew.diagnostics(SKEW_WARNING);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "nas.local's clock is 89.6s behind this machine's (±0.6s)"
+        );
+        assert!(skew.diagnostics(Duration::from_secs(120)).is_empty());
+        skew.reset("nas.local");
+        assert!(skew.estimate("nas.local").is_none());
+
+        // Memory storage shares our clock
+        let storage = MemoryStorage::new();
+        let dir = UniversalPath::from_uri_str("mem://skew/").unwrap();
+        storage.create_dir(&dir).await.unwrap();
+        let probed = skew.probe(&storage, &dir).await.unwrap();
+        assert!(!probed.exceeds(second), "{probed:?}");
+        assert!(storage.list(&dir).await.unwrap().is_empty());
+
+        assert!(mtimes_match(Some(t0), Some(t0 + second), 2 * second));
+        assert!(!mtimes_match(Some(t0), Some(t0 + 3 * second), 2 * second));
+        assert!(!mtimes_match(Some(t0), None, 2 * second));
+    }
+}
@@ -0,0 +1,445 @@
+use super::{
+    ByteStream, CallOptions, EntryKind, EntryMetadata, EntryPermissions, LocalStorage, Storage, StorageAccount,
+    StorageBackend, StorageCapabilities, StorageError,
+};
+use crate::universal_path::UniversalPath;
+use async_trait::async_trait;
+use std::{path::PathBuf, sync::Arc, time::SystemTime};
+use tokio::io::AsyncRead;
+
+/// Mounted shares are read and written as local files
+static LOCAL: LocalStorage = LocalStorage::new();
+
+/// One share mounted into the local filesystem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmbMount {
+    pub host: String,
+    pub share: String,
+    /// Where the share's root appears locally
+    pub mount_point: PathBuf,
+}
+
+impl SmbMount {
+    pub fn new<H: Into<String>, S: Into<String>, P: Into<PathBuf>>(
+        host: H,
+        share: S,
+        mount_point: P,
+    ) -> Self {
+        SmbMount {
+            host: host.into(),
+            share: share.into(),
+            mount_point: mount_point.into(),
+        }
+    }
+
+    fn matches(&self, host: &str, share: &str) -> bool {
+        self.host.eq_ignore_ascii_case(host) && self.share.eq_ignore_ascii_case(share)
+    }
+}
+
+/// SMB/CIFS storage through shares the OS has already mounted.
+///
+/// `smb://host/share/rest` is served from `mount_point/rest` of the matching
+/// `SmbMount`, with host and share compared case-insensitively as SMB does. Listing
+/// `smb://host/` gives the mounted shares of that host. Talking SMB directly is left
+/// to the OS, so a share that is not mounted is `NotFound`.
+#[derive(Clone, Default)]
+pub struct SmbStorage {
+    mounts: Arc<Vec<SmbMount>>,
+}
+
+impl SmbStorage {
+    pub fn new(mounts: Vec<SmbMount>) -> Self {
+        SmbStorage {
+            mounts: Arc::new(mounts),
+        }
+    }
+
+    /// Storage over the CIFS/SMB mounts the system currently has
+    pub fn detected() -> Self {
+        SmbStorage::new(detect_mounts())
+    }
+
+    pub fn mounts(&self) -> &[SmbMount] {
+        &self.mounts
+    }
+
+    fn host_of(path: &UniversalPath) -> Result<&str, StorageError> {
+        if path.backend() != &StorageBackend::NetworkDrive {
+            return Err(StorageError::InvalidPath);
+        }
+        path.host().ok_or(StorageError::InvalidPath)
+    }
+
+    /// The mount and local path for a path inside a share
+    fn to_local(&self, path: &UniversalPath) -> Result<(&SmbMount, UniversalPath), StorageError> {
+        let host = Self::host_of(path)?;
+        let segments = path.path_segments();
+        let share = segments.first().ok_or(StorageError::InvalidPath)?;
+        let mount = self
+            .mounts
+            .iter()
+            .find(|m| m.matches(host, share))
+            .ok_or(StorageError::NotFound)?;
+        let root = mount
+            .mount_point
+            .to_str()
+            .ok_or(StorageError::InvalidPath)?;
+        let mut local = UniversalPath::local(root).join_all(&segments[1..]);
+        if path.is_dir_hint() {
+            local = local.as_dir();
+        }
+        Ok((mount, local))
+    }
+
+    fn from_local(mount: &SmbMount, local: &UniversalPath) -> Option<UniversalPath> {
+        let root = UniversalPath::local(mount.mount_point.to_str()?);
+        let rest = local.relative_to(&root)?;
+        let path = UniversalPath::from_segments(
+            StorageBackend::NetworkDrive,
+            Some(mount.host.as_str()),
+            std::iter::once(mount.share.clone()).chain(rest),
+        );
+        Some(if local.is_dir_hint() {
+            path.as_dir()
+        } else {
+            path
+        })
+    }
+
+    fn shares_of(&self, host: &str) -> Vec<UniversalPath> {
+        self.mounts
+            .iter()
+            .filter(|m| m.host.eq_ignore_ascii_case(host))
+            .map(|m| {
+                UniversalPath::from_segments(
+                    StorageBackend::NetworkDrive,
+                    Some(m.host.as_str()),
+                    [m.share.as_str()],
+                )
+                .as_dir()
+            })
+            .collect()
+    }
+}
+
+/// Parse `/proc/mounts` (or `mount` output in the same layout) for SMB shares.
+///
+/// Sources look like `//host/share[/subdir]` with an optional `user@` before the host;
+/// mounts of a subdirectory are skipped since they do not map a whole share.
+pub fn parse_mounts(table: &str) -> Vec<SmbMount> {
+    table
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (source, target, fstype) = (fields.next()?, fields.next()?, fields.next()?);
+            if !matches!(fstype, "cifs" | "smb3" | "smbfs") {
+                return None;
+            }
+            let source = unescape_octal(source.strip_prefix("//")?);
+            let (host, share) = source.split_once('/')?;
+            let host = host.rsplit('@').next()?;
+            if share.is_empty() || share.contains('/') {
+                return None;
+            }
+            Some(SmbMount::new(host, share, unescape_octal(target)))
+        })
+        .collect()
+}
+
+/// Undo the `\040`-style escaping the kernel applies to spaces and tabs
+fn unescape_octal(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(pos) = rest.find('\\') {
+        out.push_str(&rest[..pos]);
+        let code = rest.get(pos + 1..pos + 4);
+        match code.and_then(|c| u8::from_str_radix(c, 8).ok()) {
+            Some(byte) => {
+                out.push(byte as char);
+                rest = &rest[pos + 4..];
+            }
+            None => {
+                out.push('\\');
+                rest = &rest[pos + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn detect_mounts() -> Vec<SmbMount> {
+    std::fs::read_to_string("/proc/mounts")
+        .map(|table| parse_mounts(&table))
+        .unwrap_or_default()
+}
+
+#[async_trait]
+impl Storage for SmbStorage {
+    fn backend(&self) -> StorageBackend {
+        StorageBackend::NetworkDrive
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        LOCAL.capabilities()
+    }
+
+    async fn stat_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<EntryMetadata, StorageError> {
+        if path.path_segments().is_empty() {
+            let host = Self::host_of(path)?;
+            if self.shares_of(host).is_empty() {
+                return Err(StorageError::NotFound);
+            }
+            return Ok(EntryMetadata {
+                kind: EntryKind::Directory,
+                size_bytes: None,
+                modified_at: None,
+                created_at: None,
+                permissions: EntryPermissions::default(),
+                is_hidden: false,
+                format: None,
+            });
+        }
+        let (_, local) = self.to_local(path)?;
+        LOCAL.stat_opts(&local, opts).await
+    }
+
+    async fn read_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        let (_, local) = self.to_local(path)?;
+        LOCAL.read_opts(&local, opts).await
+    }
+
+    async fn read_range_opts(
+        &self,
+        path: &UniversalPath,
+        range: std::ops::Range<u64>,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        let (_, local) = self.to_local(path)?;
+        LOCAL.read_range_opts(&local, range, opts).await
+    }
+
+    async fn list_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<UniversalPath>, StorageError> {
+        if path.path_segments().is_empty() {
+            let shares = self.shares_of(Self::host_of(path)?);
+            return if shares.is_empty() {
+                Err(StorageError::NotFound)
+            } else {
+                Ok(shares)
+            };
+        }
+        let (mount, local) = self.to_local(path)?;
+        Ok(LOCAL
+            .list_opts(&local, opts)
+            .await?
+            .iter()
+            .filter_map(|entry| Self::from_local(mount, entry))
+            .collect())
+    }
+
+    /// A target outside the share has no `smb://` name and is `InvalidPath`
+    async fn symlink_target_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Option<UniversalPath>, StorageError> {
+        let (mount, local) = self.to_local(path)?;
+        match LOCAL.symlink_target_opts(&local, opts).await? {
+            Some(target) => Self::from_local(mount, &target)
+                .map(Some)
+                .ok_or(StorageError::InvalidPath),
+            None => Ok(None),
+        }
+    }
+
+    async fn read_suffix_opts(
+        &self,
+        path: &UniversalPath,
+        len: u64,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        let (_, local) = self.to_local(path)?;
+        LOCAL.read_suffix_opts(&local, len, opts).await
+    }
+
+    async fn read_stream_opts<'a>(
+        &'a self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<ByteStream<'a>, StorageError> {
+        let (_, local) = self.to_local(path)?;
+        LOCAL.read_stream_opts(&local, opts).await
+    }
+
+    async fn write_opts(
+        &self,
+        path: &UniversalPath,
+        data: &[u8],
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        let (_, local) = self.to_local(path)?;
+        LOCAL.write_opts(&local, data, opts).await
+    }
+
+    async fn write_stream_opts(
+        &self,
+        path: &UniversalPath,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        let (_, local) = self.to_local(path)?;
+        LOCAL.write_stream_opts(&local, reader, opts).await
+    }
+
+    async fn create_dir_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        let (_, local) = self.to_local(path)?;
+        LOCAL.create_dir_opts(&local, opts).await
+    }
+
+    async fn delete_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        let (_, local) = self.to_local(path)?;
+        LOCAL.delete_opts(&local, opts).await
+    }
+
+    async fn rename_opts(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        let ((_, from), (_, to)) = (self.to_local(from)?, self.to_local(to)?);
+        LOCAL.rename_opts(&from, &to, opts).await
+    }
+
+    async fn copy_opts(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        let ((_, from), (_, to)) = (self.to_local(from)?, self.to_local(to)?);
+        LOCAL.copy_opts(&from, &to, opts).await
+    }
+
+    async fn set_modified_opts(
+        &self,
+        path: &UniversalPath,
+        modified_at: SystemTime,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        let (_, local) = self.to_local(path)?;
+        LOCAL.set_modified_opts(&local, modified_at, opts).await
+    }
+
+    async fn attribute_opts(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+        opts: &CallOptions,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        let (_, local) = self.to_local(path)?;
+        LOCAL.attribute_opts(&local, name, opts).await
+    }
+
+    async fn set_attribute_opts(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+        value: &[u8],
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        let (_, local) = self.to_local(path)?;
+        LOCAL.set_attribute_opts(&local, name, value, opts).await
+    }
+}
+
+#[async_trait]
+impl StorageAccount for SmbStorage {
+    fn backend(&self) -> StorageBackend {
+        StorageBackend::NetworkDrive
+    }
+
+    async fn list_roots(&self) -> Result<Vec<UniversalPath>, StorageError> {
+        Ok(self
+            .mounts
+            .iter()
+            .map(|m| {
+                UniversalPath::from_segments(
+                    StorageBackend::NetworkDrive,
+                    Some(m.host.as_str()),
+                    [m.share.as_str()],
+                )
+                .as_dir()
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_parse_mounts() {
+        let table = "\
+/dev/sda1 / ext4 rw 0 0
+//nas/Music /mnt/music cifs rw,vers=3.0 0 0
+//alice@nas.local/Lossless\\040Rips /mnt/lossless\\040rips smb3 rw 0 0
+//nas/Music/Live /mnt/live cifs rw 0 0
+";
+        assert_eq!(
+            parse_mounts(table),
+            vec![
+                SmbMount::new("nas", "Music", "/mnt/music"),
+                SmbMount::new("nas.local", "Lossless Rips", "/mnt/lossless rips"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reads_through_mount() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("Björk")).unwrap();
+        fs::write(dir.join("Björk/Jóga.flac"), b"flac data").unwrap();
+        let storage = SmbStorage::new(vec![SmbMount::new("NAS", "music", dir)]);
+
+        let share = UniversalPath::from_uri_str("smb://nas/Music/").unwrap();
+        let entries = storage.list(&share).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].to_string(), "smb://NAS/music/Bj%C3%B6rk/");
+        let track = entries[0].join("Jóga.flac");
+        assert_eq!(storage.read_range(&track, 5..9).await.unwrap(), b"data");
+        assert_eq!(storage.stat(&track).await.unwrap().size_bytes, Some(9));
+
+        let host = UniversalPath::from_uri_str("smb://nas/").unwrap();
+        assert_eq!(storage.list(&host).await.unwrap().len(), 1);
+        let unmounted = UniversalPath::from_uri_str("smb://nas/Video/a.mkv").unwrap();
+        assert!(matches!(
+            storage.stat(&unmounted).await,
+            Err(StorageError::NotFound)
+        ));
+    }
+}
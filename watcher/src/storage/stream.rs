@@ -0,0 +1,210 @@
+use super::{CallOptions, EntryKind, Storage, StorageError};
+use crate::universal_path::UniversalPath;
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// A file's contents as an async reader, borrowing the storage it came from.
+pub type ByteStream<'a> = Pin<Box<dyn AsyncRead + Send + 'a>>;
+
+/// Bytes fetched per `read_range` call by `RangeReader`.
+pub const READ_STREAM_CHUNK: u64 = 1024 * 1024;
+
+type Fetch<'a> = Pin<Box<dyn Future<Output = Result<Vec<u8>, StorageError>> + Send + 'a>>;
+
+/// Reads a file front to back with one `read_range` per chunk, holding a single chunk
+/// in memory. This is what `Storage::read_stream` falls back to for backends without
+/// a native stream.
+pub struct RangeReader<'a, S: Storage + ?Sized> {
+    storage: &'a S,
+    path: UniversalPath,
+    opts: CallOptions,
+    length: u64,
+    position: u64,
+    chunk_size: u64,
+    chunk: Vec<u8>,
+    offset: usize,
+    pending: Option<Fetch<'a>>,
+}
+
+impl<'a, S: Storage + ?Sized> RangeReader<'a, S> {
+    /// Stat `path` so a missing file or a directory fails here rather than on the
+    /// first read, and so the reader knows where the file ends.
+    pub async fn open(
+        storage: &'a S,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Self, StorageError> {
+        let meta = storage.stat_opts(path, opts).await?;
+        if meta.kind != EntryKind::File {
+            return Err(StorageError::NotAFile);
+        }
+        let length = meta.size_bytes.ok_or(StorageError::UnsupportedFeature(
+            "streaming without a known length",
+        ))?;
+        Ok(RangeReader {
+            storage,
+            path: path.clone(),
+            opts: opts.clone(),
+            length,
+            position: 0,
+            chunk_size: READ_STREAM_CHUNK,
+            chunk: Vec::new(),
+            offset: 0,
+            pending: None,
+        })
+    }
+
+    pub fn with_chunk_size(mut self, chunk_size: u64) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+}
+
+fn into_io_error(e: StorageError) -> std::io::Error {
+    match e {
+        StorageError::Io(e) => e,
+        other => std::io::Error::other(other),
+    }
+}
+
+impl<S: Storage + ?Sized> AsyncRead for RangeReader<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.offset < this.chunk.len() {
+                let n = buf.remaining().min(this.chunk.len() - this.offset);
+                buf.put_slice(&this.chunk[this.offset..this.offset + n]);
+                this.offset += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.position >= this.length || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            let fetch = this.pending.get_or_insert_with(|| {
+                let (storage, path, opts) = (this.storage, this.path.clone(), this.opts.clone());
+                let range = this.position..this.position.saturating_add(this.chunk_size);
+                Box::pin(async move { storage.read_range_opts(&path, range, &opts).await })
+            });
+            let data = match fetch.as_mut().poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(result) => {
+                    this.pending = None;
+                    match result {
+                        Ok(data) => data,
+                        // The file shrank since it was stat'ed
+                        Err(StorageError::RangeNotSatisfiable) => Vec::new(),
+                        Err(e) => return Poll::Ready(Err(into_io_error(e))),
+                    }
+                }
+            };
+            if data.is_empty() {
+                this.length = this.position;
+            }
+            this.position += data.len() as u64;
+            this.chunk = data;
+            this.offset = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{EntryMetadata, EntryPermissions, StorageBackend, StorageCapabilities, clamp_range};
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::AsyncReadExt;
+
+    /// One in-memory file that only supports the required methods
+    struct Memory {
+        data: Vec<u8>,
+        reads: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Storage for Memory {
+        fn backend(&self) -> StorageBackend {
+            StorageBackend::Local
+        }
+
+        fn capabilities(&self) -> StorageCapabilities {
+            StorageCapabilities::none()
+        }
+
+        async fn stat_opts(
+            &self,
+            _path: &UniversalPath,
+            _opts: &CallOptions,
+        ) -> Result<EntryMetadata, StorageError> {
+            Ok(EntryMetadata {
+                kind: EntryKind::File,
+                size_bytes: Some(self.data.len() as u64),
+                modified_at: None,
+                created_at: None,
+                permissions: EntryPermissions::default(),
+                is_hidden: false,
+                format: None,
+            })
+        }
+
+        async fn read_opts(
+            &self,
+            _path: &UniversalPath,
+            _opts: &CallOptions,
+        ) -> Result<Vec<u8>, StorageError> {
+            Ok(self.data.clone())
+        }
+
+        async fn read_range_opts(
+            &self,
+            _path: &UniversalPath,
+            range: std::ops::Range<u64>,
+            _opts: &CallOptions,
+        ) -> Result<Vec<u8>, StorageError> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            let range = clamp_range(range, self.data.len() as u64)?;
+            Ok(self.data[range.start as usize..range.end as usize].to_vec())
+        }
+
+        async fn list_opts(
+            &self,
+            _path: &UniversalPath,
+            _opts: &CallOptions,
+        ) -> Result<Vec<UniversalPath>, StorageError> {
+            Err(StorageError::NotADirectory)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_range_reader_chunks() {
+        let storage = Memory {
+            data: (0..2500u32).map(|i| (i % 256) as u8).collect(),
+            reads: AtomicUsize::new(0),
+        };
+        let path = UniversalPath::local("/music/a.flac");
+        let mut reader = RangeReader::open(&storage, &path, &CallOptions::default())
+            .await
+            .unwrap()
+            .with_chunk_size(1000);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, storage.data);
+        assert_eq!(storage.reads.load(Ordering::SeqCst), 3);
+
+        let mut stream = storage.read_stream(&path).await.unwrap();
+        let mut head = [0u8; 4];
+        stream.read_exact(&mut head).await.unwrap();
+        assert_eq!(head, [0, 1, 2, 3]);
+
+        let tail = storage.read_suffix(&path, 3).await.unwrap();
+        assert_eq!(tail, &storage.data[2497..]);
+    }
+}
@@ -0,0 +1,506 @@
+use super::{EntryKind, EntryMetadata, Storage, StorageError, StorageLock};
+use crate::universal_path::UniversalPath;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+
+/// A check made against the storage before anything in a transaction is applied.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Precondition {
+    Exists(UniversalPath),
+    Missing(UniversalPath),
+    /// The file is still the version that was seen: same size and modification time,
+    /// where the backend reports them. The closest thing to an ETag every backend has.
+    Unchanged {
+        path: UniversalPath,
+        size: Option<u64>,
+        modified: Option<SystemTime>,
+    },
+}
+
+impl Precondition {
+    /// `Unchanged` for the version `meta` describes
+    pub fn unchanged(path: &UniversalPath, meta: &EntryMetadata) -> Self {
+        Precondition::Unchanged {
+            path: path.clone(),
+            size: meta.size_bytes,
+            modified: meta.modified_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Mutation {
+    Write(UniversalPath, Vec<u8>),
+    CreateDir(UniversalPath),
+    Rename(UniversalPath, UniversalPath),
+    Delete(UniversalPath),
+}
+
+/// How to put back what one applied mutation changed
+enum Undo {
+    /// It created the path
+    Remove(UniversalPath),
+    /// It moved `0` to `1`
+    MoveBack(UniversalPath, UniversalPath),
+    /// It replaced or removed a file whose old contents were moved to `backup`
+    Restore {
+        path: UniversalPath,
+        backup: UniversalPath,
+        /// Whether the mutation left something at `path` that has to go first
+        replaced: bool,
+    },
+    /// Like `Restore`, for a backend that cannot rename, so the old contents were read
+    RestoreBytes { path: UniversalPath, data: Vec<u8> },
+    /// It removed an empty directory
+    Recreate(UniversalPath),
+}
+
+#[derive(Debug, Error)]
+pub enum TransactionError {
+    #[error("precondition failed for {path}: {reason}")]
+    Precondition {
+        path: UniversalPath,
+        reason: &'static str,
+    },
+    /// Step `step` failed. The steps before it were undone unless `rollback_errors`
+    /// says otherwise, in which case the storage may be left part-way.
+    #[error("step {step} of the transaction failed: {source}")]
+    Failed {
+        step: usize,
+        #[source]
+        source: StorageError,
+        rollback_errors: Vec<StorageError>,
+    },
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+/// What a committed transaction left behind.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CommitReport {
+    pub applied: usize,
+    /// Backup copies that could not be removed after the commit
+    pub leftover_backups: Vec<UniversalPath>,
+}
+
+/// A batch of writes, renames and deletes applied together, e.g. one organizer run.
+///
+/// `commit` first checks every precondition, plus the implicit ones that rename
+/// sources and deleted paths exist and rename targets do not, so a batch that cannot
+/// apply changes nothing. It then applies the mutations in order. Files about to be
+/// replaced or deleted are first moved to a hidden sibling backup, or read into
+/// memory where the backend cannot rename. If a step fails, the steps before it are
+/// undone in reverse order. This is best effort, not atomic: another writer, or a
+/// failure while undoing, can still leave a partial result, which the error reports.
+/// Writers that all take the same `lock` first keep out of each other's way.
+pub struct StorageTransaction<'a> {
+    storage: &'a dyn Storage,
+    locks: Vec<(UniversalPath, Duration)>,
+    preconditions: Vec<Precondition>,
+    mutations: Vec<Mutation>,
+}
+
+impl<'a> StorageTransaction<'a> {
+    pub fn new(storage: &'a dyn Storage) -> Self {
+        StorageTransaction {
+            storage,
+            locks: Vec::new(),
+            preconditions: Vec::new(),
+            mutations: Vec::new(),
+        }
+    }
+
+    /// Hold the advisory lock on `path` for the commit, failing it with
+    /// `StorageError::Locked` if another writer has it. `ttl` should outlast the commit.
+    pub fn lock(&mut self, path: &UniversalPath, ttl: Duration) -> &mut Self {
+        self.locks.push((path.clone(), ttl));
+        self
+    }
+
+    pub fn require(&mut self, precondition: Precondition) -> &mut Self {
+        self.preconditions.push(precondition);
+        self
+    }
+
+    pub fn write<D: Into<Vec<u8>>>(&mut self, path: &UniversalPath, data: D) -> &mut Self {
+        self.mutations
+            .push(Mutation::Write(path.clone(), data.into()));
+        self
+    }
+
+    pub fn create_dir(&mut self, path: &UniversalPath) -> &mut Self {
+        self.mutations.push(Mutation::CreateDir(path.clone()));
+        self
+    }
+
+    pub fn rename(&mut self, from: &UniversalPath, to: &UniversalPath) -> &mut Self {
+        self.mutations
+            .push(Mutation::Rename(from.clone(), to.clone()));
+        self
+    }
+
+    pub fn delete(&mut self, path: &UniversalPath) -> &mut Self {
+        self.mutations.push(Mutation::Delete(path.clone()));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mutations.is_empty()
+    }
+
+    pub async fn commit(self) -> Result<CommitReport, TransactionError> {
+        self.check_capabilities()?;
+        let mut held: Vec<StorageLock> = Vec::with_capacity(self.locks.len());
+        for (path, ttl) in &self.locks {
+            match self.storage.acquire_lock(path, *ttl).await {
+                Ok(lock) => held.push(lock),
+                Err(e) => {
+                    self.release(held).await;
+                    return Err(e.into());
+                }
+            }
+        }
+        let result = self.commit_locked().await;
+        self.release(held).await;
+        result
+    }
+
+    async fn release(&self, locks: Vec<StorageLock>) {
+        // A lock left behind lapses at its expiry, so failing to release is not fatal
+        for lock in locks.iter().rev() {
+            let _ = self.storage.release_lock(lock).await;
+        }
+    }
+
+    async fn commit_locked(&self) -> Result<CommitReport, TransactionError> {
+        for precondition in self
+            .implicit_preconditions()
+            .iter()
+            .chain(&self.preconditions)
+        {
+            self.check(precondition).await?;
+        }
+
+        let mut undo = Vec::with_capacity(self.mutations.len());
+        for (step, mutation) in self.mutations.iter().enumerate() {
+            match self.apply(mutation).await {
+                Ok(u) => undo.extend(u),
+                Err(source) => {
+                    let rollback_errors = self.roll_back(undo).await;
+                    return Err(TransactionError::Failed {
+                        step,
+                        source,
+                        rollback_errors,
+                    });
+                }
+            }
+        }
+
+        let mut report = CommitReport {
+            applied: self.mutations.len(),
+            leftover_backups: Vec::new(),
+        };
+        for u in undo {
+            if let Undo::Restore { backup, .. } = u
+                && self.storage.delete(&backup).await.is_err()
+            {
+                report.leftover_backups.push(backup);
+            }
+        }
+        Ok(report)
+    }
+
+    fn check_capabilities(&self) -> Result<(), StorageError> {
+        let caps = self.storage.capabilities();
+        for mutation in &self.mutations {
+            let (supported, feature) = match mutation {
+                Mutation::Write(..) => (caps.can_write, "write"),
+                Mutation::CreateDir(_) => (caps.can_write, "create_dir"),
+                Mutation::Rename(..) => (caps.can_rename, "rename"),
+                Mutation::Delete(_) => (caps.can_delete, "delete"),
+            };
+            if !supported {
+                return Err(StorageError::UnsupportedFeature(feature));
+            }
+        }
+        Ok(())
+    }
+
+    /// What the mutations need to hold before the first one runs. Paths touched by an
+    /// earlier mutation in the batch are not checked, since their state by then
+    /// depends on the batch itself.
+    fn implicit_preconditions(&self) -> Vec<Precondition> {
+        let mut touched: Vec<&UniversalPath> = Vec::new();
+        let mut preconditions = Vec::new();
+        for mutation in &self.mutations {
+            let untouched = |p: &UniversalPath, touched: &[&UniversalPath]| !touched.contains(&p);
+            match mutation {
+                Mutation::Rename(from, to) => {
+                    if untouched(from, &touched) {
+                        preconditions.push(Precondition::Exists(from.clone()));
+                    }
+                    if untouched(to, &touched) {
+                        preconditions.push(Precondition::Missing(to.clone()));
+                    }
+                    touched.extend([from, to]);
+                }
+                Mutation::Delete(path) => {
+                    if untouched(path, &touched) {
+                        preconditions.push(Precondition::Exists(path.clone()));
+                    }
+                    touched.push(path);
+                }
+                Mutation::Write(path, _) | Mutation::CreateDir(path) => touched.push(path),
+            }
+        }
+        preconditions
+    }
+
+    async fn check(&self, precondition: &Precondition) -> Result<(), TransactionError> {
+        let (path, meta) = match precondition {
+            Precondition::Exists(path)
+            | Precondition::Missing(path)
+            | Precondition::Unchanged { path, .. } => (path, self.stat(path).await?),
+        };
+        let reason = match (precondition, meta) {
+            (Precondition::Exists(_), None) => "does not exist",
+            (Precondition::Missing(_), Some(_)) => "already exists",
+            (Precondition::Unchanged { .. }, None) => "no longer exists",
+            (Precondition::Unchanged { size, modified, .. }, Some(meta))
+                if (size.is_some() && *size != meta.size_bytes)
+                    || (modified.is_some() && *modified != meta.modified_at) =>
+            {
+                "has changed"
+            }
+            _ => return Ok(()),
+        };
+        Err(TransactionError::Precondition {
+            path: path.clone(),
+            reason,
+        })
+    }
+
+    async fn stat(&self, path: &UniversalPath) -> Result<Option<EntryMetadata>, StorageError> {
+        match self.storage.stat(path).await {
+            Ok(meta) => Ok(Some(meta)),
+            Err(StorageError::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Apply one mutation, returning how to undo it
+    async fn apply(&self, mutation: &Mutation) -> Result<Option<Undo>, StorageError> {
+        let storage = self.storage;
+        match mutation {
+            Mutation::Write(path, data) => {
+                let undo = match self.stat(path).await? {
+                    None => Undo::Remove(path.clone()),
+                    Some(meta) if meta.kind != EntryKind::File => {
+                        return Err(StorageError::NotAFile);
+                    }
+                    Some(_) => self.back_up(path, true).await?,
+                };
+                if let Err(e) = storage.write(path, data).await {
+                    // Nothing was written, so only a moved-away original needs putting back
+                    if let Undo::Restore { path, backup, .. } = undo {
+                        let replaced = false;
+                        let _ = self
+                            .undo(Undo::Restore {
+                                path,
+                                backup,
+                                replaced,
+                            })
+                            .await;
+                    }
+                    return Err(e);
+                }
+                Ok(Some(undo))
+            }
+            Mutation::CreateDir(path) => {
+                let existed = self.stat(path).await?.is_some();
+                storage.create_dir(path).await?;
+                Ok((!existed).then(|| Undo::Remove(path.as_dir())))
+            }
+            Mutation::Rename(from, to) => {
+                storage.rename(from, to).await?;
+                Ok(Some(Undo::MoveBack(from.clone(), to.clone())))
+            }
+            Mutation::Delete(path) => {
+                let meta = self.stat(path).await?.ok_or(StorageError::NotFound)?;
+                if meta.kind == EntryKind::Directory {
+                    storage.delete(path).await?;
+                    return Ok(Some(Undo::Recreate(path.as_dir())));
+                }
+                let undo = self.back_up(path, false).await?;
+                if matches!(undo, Undo::RestoreBytes { .. }) {
+                    storage.delete(path).await?;
+                }
+                Ok(Some(undo))
+            }
+        }
+    }
+
+    /// Keep the current contents of the file at `path` before it is replaced or
+    /// deleted. A rename to the backup also takes the file out of the way.
+    async fn back_up(&self, path: &UniversalPath, replacing: bool) -> Result<Undo, StorageError> {
+        if self.storage.capabilities().can_rename {
+            let backup = backup_path(path)?;
+            self.storage.rename(path, &backup).await?;
+            Ok(Undo::Restore {
+                path: path.clone(),
+                backup,
+                replaced: replacing,
+            })
+        } else {
+            Ok(Undo::RestoreBytes {
+                path: path.clone(),
+                data: self.storage.read(path).await?,
+            })
+        }
+    }
+
+    async fn undo(&self, undo: Undo) -> Result<(), StorageError> {
+        let storage = self.storage;
+        match undo {
+            Undo::Remove(path) => storage.delete(&path).await,
+            Undo::MoveBack(from, to) => storage.rename(&to, &from).await,
+            Undo::Restore {
+                path,
+                backup,
+                replaced,
+            } => {
+                if replaced {
+                    storage.delete(&path).await?;
+                }
+                storage.rename(&backup, &path).await
+            }
+            Undo::RestoreBytes { path, data } => storage.write(&path, &data).await,
+            Undo::Recreate(path) => storage.create_dir(&path).await,
+        }
+    }
+
+    /// Undo applied mutations newest first, carrying on past failures
+    async fn roll_back(&self, undo: Vec<Undo>) -> Vec<StorageError> {
+        let mut errors = Vec::new();
+        for u in undo.into_iter().rev() {
+            if let Err(e) = self.undo(u).await {
+                errors.push(e);
+            }
+        }
+        errors
+    }
+}
+
+/// A hidden sibling of `path` no other transaction will pick
+fn backup_path(path: &UniversalPath) -> Result<UniversalPath, StorageError> {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let name = path.last_segment().ok_or(StorageError::InvalidPath)?;
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    let parent = path.parent().ok_or(StorageError::InvalidPath)?;
+    Ok(parent.join(format!(
+        ".{name}.otolith-txn-{}-{}-{nanos}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LocalStorage;
+    use std::fs;
+
+    fn names(dir: &std::path::Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn test_commit_and_roll_back() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("01 a.flac"), b"a").unwrap();
+        fs::write(dir.join("02 b.flac"), b"b").unwrap();
+        fs::write(dir.join("cover.jpg"), b"old").unwrap();
+        let root = UniversalPath::local(dir.to_str().unwrap());
+        let storage = LocalStorage::new();
+
+        // The last step fails, so the rename, overwrite and delete are undone
+        let mut txn = StorageTransaction::new(&storage);
+        txn.rename(&root.join("01 a.flac"), &root.join("01 A.flac"))
+            .write(&root.join("cover.jpg"), b"new".to_vec())
+            .delete(&root.join("02 b.flac"))
+            .write(&root.join("missing").join("x"), b"x".to_vec());
+        match txn.commit().await {
+            Err(TransactionError::Failed {
+                step,
+                rollback_errors,
+                ..
+            }) => {
+                assert_eq!(step, 3);
+                assert!(rollback_errors.is_empty(), "{rollback_errors:?}");
+            }
+            other => panic!("expected a failed step, got {other:?}"),
+        }
+        assert_eq!(names(dir), ["01 a.flac", "02 b.flac", "cover.jpg"]);
+        assert_eq!(fs::read(dir.join("cover.jpg")).unwrap(), b"old");
+
+        // A precondition that does not hold stops the batch before it starts
+        let seen = storage.stat(&root.join("cover.jpg")).await.unwrap();
+        fs::write(dir.join("cover.jpg"), b"changed").unwrap();
+        let mut txn = StorageTransaction::new(&storage);
+        txn.require(Precondition::unchanged(&root.join("cover.jpg"), &seen))
+            .delete(&root.join("cover.jpg"));
+        assert!(matches!(
+            txn.commit().await,
+            Err(TransactionError::Precondition {
+                reason: "has changed",
+                ..
+            })
+        ));
+        let mut txn = StorageTransaction::new(&storage);
+        txn.rename(&root.join("01 a.flac"), &root.join("02 b.flac"));
+        assert!(matches!(
+            txn.commit().await,
+            Err(TransactionError::Precondition {
+                reason: "already exists",
+                ..
+            })
+        ));
+
+        // Another writer's lock on the root keeps the batch out
+        let ttl = Duration::from_secs(60);
+        let other = storage.acquire_lock(&root, ttl).await.unwrap();
+        let mut txn = StorageTransaction::new(&storage);
+        txn.lock(&root, ttl).delete(&root.join("02 b.flac"));
+        assert!(matches!(
+            txn.commit().await,
+            Err(TransactionError::Storage(StorageError::Locked(_)))
+        ));
+        storage.release_lock(&other).await.unwrap();
+
+        let mut txn = StorageTransaction::new(&storage);
+        txn.lock(&root, ttl)
+            .create_dir(&root.join("Album"))
+            .rename(
+                &root.join("01 a.flac"),
+                &root.join("Album").join("01 a.flac"),
+            )
+            .write(&root.join("cover.jpg"), b"new".to_vec())
+            .delete(&root.join("02 b.flac"));
+        let report = txn.commit().await.unwrap();
+        assert_eq!(report.applied, 4);
+        assert!(report.leftover_backups.is_empty());
+        assert_eq!(names(dir), ["Album", "cover.jpg"]);
+        assert_eq!(fs::read(dir.join("cover.jpg")).unwrap(), b"new");
+    }
+}
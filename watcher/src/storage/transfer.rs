@@ -0,0 +1,277 @@
+use super::{ByteStream, EntryKind, Storage, StorageError};
+use crate::universal_path::UniversalPath;
+use serde::Serialize;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::watch;
+
+/// How far a `transfer` has got.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct TransferProgress {
+    /// Bytes copied so far
+    pub bytes: u64,
+    /// The source's size, where it reports one
+    pub total_bytes: Option<u64>,
+    pub finished: bool,
+}
+
+/// What a `transfer` did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TransferReport {
+    pub bytes: u64,
+    /// Copied by the storage's own `copy`, which keeps the data on the server where
+    /// the backend can, rather than streamed through this process
+    pub server_side: bool,
+    /// The copy was given the source's modification time
+    pub modified_preserved: bool,
+}
+
+/// Copy the file `from` on `source` to `to` on `destination`, replacing a file there
+/// as `write` would; the parent directory must exist. A `to` marked as a directory,
+/// `backup/`, means the file of the same name in it, as with rsync and `cp`; see
+/// `copy_target`. When both are the same storage
+/// it copies with `Storage::copy`, otherwise the data is streamed from one to the other.
+///
+/// `progress`, if given, is updated as bytes are written. The source's modification
+/// time is carried over where the destination lets it be set.
+pub async fn transfer(
+    source: &dyn Storage,
+    from: &UniversalPath,
+    destination: &dyn Storage,
+    to: &UniversalPath,
+    progress: Option<&watch::Sender<TransferProgress>>,
+) -> Result<TransferReport, StorageError> {
+    let to = &copy_target(from, to);
+    let meta = source.stat(from).await?;
+    if meta.kind != EntryKind::File {
+        return Err(StorageError::NotAFile);
+    }
+    update(progress, |p| {
+        *p = TransferProgress {
+            total_bytes: meta.size_bytes,
+            ..TransferProgress::default()
+        }
+    });
+
+    let server_side = same_storage(source, destination);
+    let bytes = match server_side {
+        true => {
+            let bytes = source.copy(from, to).await?;
+            update(progress, |p| p.bytes = bytes);
+            bytes
+        }
+        false => {
+            let mut reader = Counting {
+                inner: source.read_stream(from).await?,
+                progress,
+            };
+            destination.write_stream(to, &mut reader).await?
+        }
+    };
+
+    let modified_preserved = match meta.modified_at {
+        Some(modified_at) => match destination.set_modified(to, modified_at).await {
+            Ok(()) => true,
+            Err(StorageError::UnsupportedFeature(_)) => false,
+            Err(e) => return Err(e),
+        },
+        None => false,
+    };
+    update(progress, |p| p.finished = true);
+    Ok(TransferReport {
+        bytes,
+        server_side,
+        modified_preserved,
+    })
+}
+
+/// Move the file `from` on `source` to `to` on `destination`, failing with
+/// `AlreadyExists` rather than replacing anything at `to`. On the same storage it is
+/// a `rename`; across storages, or where rename is unsupported, it is a `transfer`
+/// followed by deleting the source once the copy is in place. A `to` marked as a
+/// directory is moved into, as with `transfer`.
+pub async fn transfer_move(
+    source: &dyn Storage,
+    from: &UniversalPath,
+    destination: &dyn Storage,
+    to: &UniversalPath,
+    progress: Option<&watch::Sender<TransferProgress>>,
+) -> Result<TransferReport, StorageError> {
+    let to = &copy_target(from, to);
+    if same_storage(source, destination) {
+        let meta = source.stat(from).await?;
+        match source.rename(from, to).await {
+            Ok(()) => {
+                let bytes = meta.size_bytes.unwrap_or(0);
+                update(progress, |p| {
+                    *p = TransferProgress {
+                        bytes,
+                        total_bytes: meta.size_bytes,
+                        finished: true,
+                    }
+                });
+                return Ok(TransferReport {
+                    bytes,
+                    server_side: true,
+                    modified_preserved: meta.modified_at.is_some(),
+                });
+            }
+            Err(StorageError::UnsupportedFeature(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    match destination.stat(to).await {
+        Ok(_) => return Err(StorageError::AlreadyExists),
+        Err(StorageError::NotFound) => {}
+        Err(e) => return Err(e),
+    }
+    let report = transfer(source, from, destination, to, progress).await?;
+    source.delete(from).await?;
+    Ok(report)
+}
+
+/// Where a copy of the file `from` to `to` ends up: `to` itself, or for a `to` marked
+/// as a directory, the file of the same name in it
+pub fn copy_target(from: &UniversalPath, to: &UniversalPath) -> UniversalPath {
+    match (to.is_dir_hint() || to.is_root(), from.file_name()) {
+        (true, Some(name)) => to.join(name).as_file(),
+        _ => to.clone(),
+    }
+}
+
+fn update(
+    progress: Option<&watch::Sender<TransferProgress>>,
+    change: impl FnOnce(&mut TransferProgress),
+) {
+    if let Some(progress) = progress {
+        progress.send_modify(change);
+    }
+}
+
+fn same_storage(a: &dyn Storage, b: &dyn Storage) -> bool {
+    std::ptr::addr_eq(a, b)
+}
+
+/// Reports bytes as they are read
+struct Counting<'a, 'p> {
+    inner: ByteStream<'a>,
+    progress: Option<&'p watch::Sender<TransferProgress>>,
+}
+
+impl AsyncRead for Counting<'_, '_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let polled = self.inner.as_mut().poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        if read > 0 {
+            update(self.progress, |p| p.bytes += read);
+        }
+        polled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{LocalStorage, MemoryStorage};
+    use std::time::{Duration, SystemTime};
+
+    #[tokio::test]
+    async fn test_transfer_across_backends() {
+        let memory = MemoryStorage::new();
+        let album = UniversalPath::from_uri_str("mem://nas/music/Post/").unwrap();
+        memory.create_dir(&album).await.unwrap();
+        let track = album.join("01 Army of Me.flac");
+        memory.write(&track, &[7; 5000]).await.unwrap();
+        let recorded = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        memory.set_modified(&track, recorded).await.unwrap();
+
+        // On the same storage, copied and moved in place
+        let copy = album.join("copy.flac");
+        let report = transfer(&memory, &track, &memory, &copy, None)
+            .await
+            .unwrap();
+        assert!(report.server_side && report.modified_preserved);
+        assert_eq!(memory.read(&copy).await.unwrap(), [7; 5000]);
+        let moved = album.join("moved.flac");
+        transfer_move(&memory, &copy, &memory, &moved, None)
+            .await
+            .unwrap();
+        assert!(matches!(
+            memory.stat(&copy).await,
+            Err(StorageError::NotFound)
+        ));
+
+        // To local disk, streamed with progress and the mtime kept
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let local = LocalStorage::new();
+        let target = UniversalPath::local(dir.to_str().unwrap())
+            .as_dir()
+            .join("01.flac");
+        let (tx, rx) = watch::channel(TransferProgress::default());
+        let report = transfer(&memory, &track, &local, &target, Some(&tx))
+            .await
+            .unwrap();
+        assert_eq!((report.bytes, report.server_side), (5000, false));
+        assert_eq!(
+            *rx.borrow(),
+            TransferProgress {
+                bytes: 5000,
+                total_bytes: Some(5000),
+                finished: true,
+            }
+        );
+        let meta = local.stat(&target).await.unwrap();
+        assert_eq!(meta.modified_at, Some(recorded));
+
+        assert!(matches!(
+            transfer_move(&memory, &moved, &local, &target, None).await,
+            Err(StorageError::AlreadyExists)
+        ));
+        local.delete(&target).await.unwrap();
+        transfer_move(&memory, &moved, &local, &target, None)
+            .await
+            .unwrap();
+        assert!(matches!(
+            memory.stat(&moved).await,
+            Err(StorageError::NotFound)
+        ));
+        assert_eq!(local.read(&target).await.unwrap(), [7; 5000]);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_into_directory() {
+        let uri = |uri: &str| UniversalPath::from_uri_str(uri).unwrap();
+        let memory = MemoryStorage::new();
+        let backup = uri("mem://nas/backup/");
+        memory.create_dir(&backup).await.unwrap();
+        let track = uri("mem://nas/01.flac");
+        memory.write(&track, b"flac").await.unwrap();
+
+        // `backup/` is the directory to copy into, `backup` the file to copy to
+        transfer(&memory, &track, &memory, &backup, None)
+            .await
+            .unwrap();
+        assert_eq!(memory.read(&backup.join("01.flac")).await.unwrap(), b"flac");
+        assert_eq!(copy_target(&track, &backup.as_file()), backup.as_file());
+
+        let moved = backup.join("moved/");
+        memory.create_dir(&moved).await.unwrap();
+        transfer_move(&memory, &backup.join("01.flac"), &memory, &moved, None)
+            .await
+            .unwrap();
+        assert_eq!(memory.read(&moved.join("01.flac")).await.unwrap(), b"flac");
+        assert!(matches!(
+            transfer_move(&memory, &track, &memory, &moved, None).await,
+            Err(StorageError::AlreadyExists)
+        ));
+    }
+}
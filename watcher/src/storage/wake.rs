@@ -0,0 +1,546 @@
+use super::{
+    ByteStream, CallOptions, Checksum, ChecksumAlgorithm, EntryMetadata, Storage, StorageBackend,
+    StorageCapabilities, StorageError, StorageLock,
+};
+use crate::universal_path::UniversalPath;
+use async_trait::async_trait;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    fmt,
+    future::Future,
+    net::{Ipv4Addr, SocketAddr},
+    ops::Range,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{io::AsyncRead, net::UdpSocket, sync::Mutex};
+
+/// The hardware address a magic packet is addressed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl FromStr for MacAddress {
+    type Err = String;
+
+    /// `aa:bb:cc:dd:ee:ff`, `aa-bb-cc-dd-ee-ff` or `aabbccddeeff`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex: String = s.chars().filter(|c| !matches!(c, ':' | '-')).collect();
+        let bytes = hex::decode(&hex).map_err(|_| format!("not a MAC address: {s:?}"))?;
+        let bytes: [u8; 6] = bytes
+            .try_into()
+            .map_err(|_| format!("not a MAC address: {s:?}"))?;
+        Ok(MacAddress(bytes))
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+impl Serialize for MacAddress {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for MacAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// How to wake a host that sleeps between uses, such as a NAS that spins its disks
+/// down at night.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WakeConfig {
+    pub mac: MacAddress,
+    /// Where the magic packet is sent: the broadcast address of the host's network,
+    /// on the discard port by default
+    #[serde(default = "default_broadcast")]
+    pub broadcast: SocketAddr,
+    /// How long the host may take to answer after the first packet
+    #[serde(default = "default_spin_up")]
+    pub spin_up: Duration,
+    /// The wait before the first probe, doubled after each until 16 times as long
+    #[serde(default = "default_first_probe")]
+    pub first_probe: Duration,
+    /// How long a host that answered is taken to still be awake without checking
+    #[serde(default = "default_assume_awake")]
+    pub assume_awake: Duration,
+}
+
+fn default_broadcast() -> SocketAddr {
+    (Ipv4Addr::BROADCAST, 9).into()
+}
+
+fn default_spin_up() -> Duration {
+    Duration::from_secs(180)
+}
+
+fn default_first_probe() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_assume_awake() -> Duration {
+    Duration::from_secs(300)
+}
+
+impl WakeConfig {
+    /// Wake `mac` by broadcast, allowing 3 minutes to spin up
+    pub fn new(mac: MacAddress) -> Self {
+        WakeConfig {
+            mac,
+            broadcast: default_broadcast(),
+            spin_up: default_spin_up(),
+            first_probe: default_first_probe(),
+            assume_awake: default_assume_awake(),
+        }
+    }
+
+    pub fn with_broadcast(mut self, broadcast: SocketAddr) -> Self {
+        self.broadcast = broadcast;
+        self
+    }
+
+    pub fn with_spin_up(mut self, spin_up: Duration) -> Self {
+        self.spin_up = spin_up;
+        self
+    }
+
+    pub fn with_first_probe(mut self, first_probe: Duration) -> Self {
+        self.first_probe = first_probe;
+        self
+    }
+
+    pub fn with_assume_awake(mut self, assume_awake: Duration) -> Self {
+        self.assume_awake = assume_awake;
+        self
+    }
+}
+
+/// Six `0xff` bytes, then the address sixteen times
+pub fn magic_packet(mac: MacAddress) -> [u8; 102] {
+    let mut packet = [0xff; 102];
+    for chunk in packet[6..].chunks_mut(6) {
+        chunk.copy_from_slice(&mac.0);
+    }
+    packet
+}
+
+/// Broadcast a magic packet for `config.mac`
+pub async fn send_magic_packet(config: &WakeConfig) -> std::io::Result<()> {
+    let bind: SocketAddr = match config.broadcast {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.set_broadcast(true)?;
+    socket
+        .send_to(&magic_packet(config.mac), config.broadcast)
+        .await?;
+    Ok(())
+}
+
+/// Make sure the host behind `storage` is awake: `stat` `probe`, and if the host does
+/// not answer in a way that could mean it is asleep, send magic packets and probe
+/// again at growing intervals until it answers or `config.spin_up` runs out. Any
+/// answer from the server counts, including that `probe` is missing. Returns how
+/// long it took, or the last error if the host never answered.
+pub async fn wake(
+    storage: &(impl Storage + ?Sized),
+    probe: &UniversalPath,
+    config: &WakeConfig,
+) -> Result<Duration, StorageError> {
+    let started = Instant::now();
+    let opts = CallOptions::default().with_timeout(config.first_probe.max(Duration::from_secs(5)));
+    let mut delay = config.first_probe;
+    loop {
+        match storage.stat_opts(probe, &opts).await {
+            Err(e) if e.may_be_asleep() => {
+                if started.elapsed() >= config.spin_up {
+                    return Err(e);
+                }
+            }
+            _ => return Ok(started.elapsed()),
+        }
+        // Sent every round, as one packet can be lost, and a host that is booting
+        // ignores it
+        send_magic_packet(config).await?;
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(config.first_probe * 16);
+    }
+}
+
+/// Storage on a host that sleeps, woken with `wake` before the first call and before
+/// any call after it has been left alone for `assume_awake`. A call that fails in a
+/// way that could mean the host went back to sleep makes the next one wake it
+/// again. Callers waiting while it wakes share the one attempt.
+pub struct WakingStorage<S: Storage + ?Sized = dyn Storage> {
+    inner: Arc<S>,
+    config: WakeConfig,
+    probe: UniversalPath,
+    /// When the host last answered
+    awake_at: Mutex<Option<Instant>>,
+}
+
+impl<S: Storage + ?Sized> WakingStorage<S> {
+    /// Wake `inner`'s host as `config` says, checking it with a `stat` of `probe`
+    pub fn new(inner: Arc<S>, config: WakeConfig, probe: UniversalPath) -> Self {
+        WakingStorage {
+            inner,
+            config,
+            probe,
+            awake_at: Mutex::new(None),
+        }
+    }
+
+    pub fn config(&self) -> &WakeConfig {
+        &self.config
+    }
+
+    pub fn inner(&self) -> &Arc<S> {
+        &self.inner
+    }
+
+    async fn ensure_awake(&self) -> Result<(), StorageError> {
+        let mut awake_at = self.awake_at.lock().await;
+        if awake_at.is_some_and(|at| at.elapsed() < self.config.assume_awake) {
+            return Ok(());
+        }
+        wake(self.inner.as_ref(), &self.probe, &self.config).await?;
+        *awake_at = Some(Instant::now());
+        Ok(())
+    }
+
+    async fn call<T, F>(&self, call: F) -> Result<T, StorageError>
+    where
+        F: Future<Output = Result<T, StorageError>>,
+    {
+        self.ensure_awake().await?;
+        let result = call.await;
+        *self.awake_at.lock().await = match &result {
+            Err(e) if e.may_be_asleep() => None,
+            _ => Some(Instant::now()),
+        };
+        result
+    }
+}
+
+#[async_trait]
+impl<S: Storage + ?Sized> Storage for WakingStorage<S> {
+    fn backend(&self) -> StorageBackend {
+        self.inner.backend()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.inner.capabilities()
+    }
+
+    async fn stat_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<EntryMetadata, StorageError> {
+        self.call(self.inner.stat_opts(path, opts)).await
+    }
+
+    async fn read_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        self.call(self.inner.read_opts(path, opts)).await
+    }
+
+    async fn read_range_opts(
+        &self,
+        path: &UniversalPath,
+        range: Range<u64>,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        self.call(self.inner.read_range_opts(path, range, opts))
+            .await
+    }
+
+    async fn list_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<UniversalPath>, StorageError> {
+        self.call(self.inner.list_opts(path, opts)).await
+    }
+
+    async fn glob_opts(
+        &self,
+        pattern: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<UniversalPath>, StorageError> {
+        self.call(self.inner.glob_opts(pattern, opts)).await
+    }
+
+    async fn symlink_target_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Option<UniversalPath>, StorageError> {
+        self.call(self.inner.symlink_target_opts(path, opts)).await
+    }
+
+    async fn read_suffix_opts(
+        &self,
+        path: &UniversalPath,
+        len: u64,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        self.call(self.inner.read_suffix_opts(path, len, opts))
+            .await
+    }
+
+    async fn read_stream_opts<'a>(
+        &'a self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<ByteStream<'a>, StorageError> {
+        self.call(self.inner.read_stream_opts(path, opts)).await
+    }
+
+    async fn checksum_opts(
+        &self,
+        path: &UniversalPath,
+        algorithm: ChecksumAlgorithm,
+        opts: &CallOptions,
+    ) -> Result<Checksum, StorageError> {
+        self.call(self.inner.checksum_opts(path, algorithm, opts))
+            .await
+    }
+
+    async fn write_opts(
+        &self,
+        path: &UniversalPath,
+        data: &[u8],
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.call(self.inner.write_opts(path, data, opts)).await
+    }
+
+    async fn write_stream_opts(
+        &self,
+        path: &UniversalPath,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        self.call(self.inner.write_stream_opts(path, reader, opts))
+            .await
+    }
+
+    async fn create_dir_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.call(self.inner.create_dir_opts(path, opts)).await
+    }
+
+    async fn delete_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.call(self.inner.delete_opts(path, opts)).await
+    }
+
+    async fn rename_opts(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.call(self.inner.rename_opts(from, to, opts)).await
+    }
+
+    async fn copy_opts(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        self.call(self.inner.copy_opts(from, to, opts)).await
+    }
+
+    async fn set_modified_opts(
+        &self,
+        path: &UniversalPath,
+        modified_at: SystemTime,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.call(self.inner.set_modified_opts(path, modified_at, opts))
+            .await
+    }
+
+    async fn attribute_opts(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+        opts: &CallOptions,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        self.call(self.inner.attribute_opts(path, name, opts)).await
+    }
+
+    async fn set_attribute_opts(
+        &self,
+        path: &UniversalPath,
+        name: &str,
+        value: &[u8],
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.call(self.inner.set_attribute_opts(path, name, value, opts))
+            .await
+    }
+
+    async fn acquire_lock_opts(
+        &self,
+        path: &UniversalPath,
+        ttl: Duration,
+        opts: &CallOptions,
+    ) -> Result<StorageLock, StorageError> {
+        self.call(self.inner.acquire_lock_opts(path, ttl, opts))
+            .await
+    }
+
+    async fn renew_lock_opts(
+        &self,
+        lock: &StorageLock,
+        ttl: Duration,
+        opts: &CallOptions,
+    ) -> Result<StorageLock, StorageError> {
+        self.call(self.inner.renew_lock_opts(lock, ttl, opts)).await
+    }
+
+    async fn release_lock_opts(
+        &self,
+        lock: &StorageLock,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        self.call(self.inner.release_lock_opts(lock, opts)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// Memory on a host that does not answer until it gets a magic packet
+    struct Sleepy {
+        inner: MemoryStorage,
+        awake: Arc<AtomicBool>,
+    }
+
+    impl Sleepy {
+        fn check(&self) -> Result<(), StorageError> {
+            match self.awake.load(Ordering::SeqCst) {
+                true => Ok(()),
+                false => Err(std::io::Error::from(std::io::ErrorKind::HostUnreachable).into()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Storage for Sleepy {
+        fn backend(&self) -> StorageBackend {
+            StorageBackend::Memory
+        }
+
+        fn capabilities(&self) -> StorageCapabilities {
+            self.inner.capabilities()
+        }
+
+        async fn stat_opts(
+            &self,
+            path: &UniversalPath,
+            opts: &CallOptions,
+        ) -> Result<EntryMetadata, StorageError> {
+            self.check()?;
+            self.inner.stat_opts(path, opts).await
+        }
+
+        async fn read_opts(
+            &self,
+            path: &UniversalPath,
+            opts: &CallOptions,
+        ) -> Result<Vec<u8>, StorageError> {
+            self.check()?;
+            self.inner.read_opts(path, opts).await
+        }
+
+        async fn read_range_opts(
+            &self,
+            path: &UniversalPath,
+            range: Range<u64>,
+            opts: &CallOptions,
+        ) -> Result<Vec<u8>, StorageError> {
+            self.check()?;
+            self.inner.read_range_opts(path, range, opts).await
+        }
+
+        async fn list_opts(
+            &self,
+            path: &UniversalPath,
+            opts: &CallOptions,
+        ) -> Result<Vec<UniversalPath>, StorageError> {
+            self.check()?;
+            self.inner.list_opts(path, opts).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wake_on_lan() {
+        let mac: MacAddress = "00-11-32-AB-cd-EF".parse().unwrap();
+        assert_eq!(mac.to_string(), "00:11:32:ab:cd:ef");
+        assert!("00:11:32".parse::<MacAddress>().is_err());
+        let packet = magic_packet(mac);
+        assert_eq!(packet[..6], [0xff; 6]);
+        assert_eq!(packet[96..], mac.0);
+
+        // The NAS wakes when a packet for its address reaches it
+        let nas = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = WakeConfig::new(mac)
+            .with_broadcast(nas.local_addr().unwrap())
+            .with_first_probe(Duration::from_millis(10))
+            .with_spin_up(Duration::from_secs(5));
+        let awake = Arc::new(AtomicBool::new(false));
+        let woken = awake.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 200];
+            while let Ok(len) = nas.recv(&mut buf).await {
+                if buf[..len] == magic_packet(mac) {
+                    woken.store(true, Ordering::SeqCst);
+                }
+            }
+        });
+        let inner = MemoryStorage::new();
+        let track = UniversalPath::from_uri_str("mem://nas/a.flac").unwrap();
+        inner.write(&track, b"flac").await.unwrap();
+        let storage = WakingStorage::new(
+            Arc::new(Sleepy {
+                inner,
+                awake: awake.clone(),
+            }),
+            config,
+            track.parent().unwrap(),
+        );
+        assert_eq!(storage.read(&track).await.unwrap(), b"flac");
+        assert!(awake.load(Ordering::SeqCst));
+
+        // Asleep again, it fails once and is woken before the next call
+        awake.store(false, Ordering::SeqCst);
+        assert!(storage.read(&track).await.unwrap_err().may_be_asleep());
+        assert_eq!(storage.read(&track).await.unwrap(), b"flac");
+    }
+}
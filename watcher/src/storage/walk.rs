@@ -0,0 +1,584 @@
+use super::{EntryKind, EntryMetadata, FilterSet, Storage, StorageError, glob::literal_base};
+use crate::{collation::Collation, universal_path::UniversalPath};
+use futures::{Stream, StreamExt, future::BoxFuture, stream};
+use serde::Serialize;
+use std::{
+    collections::{HashSet, VecDeque},
+    pin::Pin,
+};
+
+/// Every entry under a walked root with its metadata, or an error for a directory
+/// that could not be listed or an entry that could not be stat'ed.
+pub type WalkStream<'a> =
+    Pin<Box<dyn Stream<Item = Result<(UniversalPath, EntryMetadata), StorageError>> + Send + 'a>>;
+
+/// Every entry under a walked root, and every one left out, with why.
+pub type WalkItemStream<'a> =
+    Pin<Box<dyn Stream<Item = Result<WalkItem, StorageError>> + Send + 'a>>;
+
+/// The children of one directory with their metadata, as `Storage::list_stream`
+/// fetches them.
+pub type ListStream<'a> =
+    Pin<Box<dyn Stream<Item = Result<(UniversalPath, EntryMetadata), StorageError>> + Send + 'a>>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WalkOrder {
+    /// Every entry at one depth before any at the next
+    #[default]
+    BreadthFirst,
+    /// Each directory's contents right after the directory itself
+    DepthFirst,
+}
+
+/// What the walker does with symlinks, on backends that have them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Yield links like any other entry but do not descend into linked directories
+    #[default]
+    Report,
+    /// Descend into linked directories, each link target at most once, so cycles end
+    Follow,
+    /// Leave links out entirely
+    Skip,
+}
+
+/// Why a walk, scan or index update left an entry out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// The filter's patterns, extensions or size limit, or the root's extensions,
+    /// leave it out
+    Filtered,
+    /// A directory that could not be listed for want of permission
+    PermissionDenied,
+    /// A directory at the depth limit, whose contents are not walked
+    TooDeep,
+    /// Neither a file nor a directory: a socket, device or FIFO
+    UnsupportedKind,
+    /// A symlink whose target does not exist
+    BrokenSymlink,
+    /// A symlink the symlink policy leaves out
+    Symlink,
+}
+
+/// An entry left out, and why.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Skipped {
+    pub path: UniversalPath,
+    pub reason: SkipReason,
+}
+
+/// What a walk or scan reports: an entry it found, or one it left out.
+#[derive(Debug, Clone)]
+pub enum WalkItem {
+    Entry(UniversalPath, EntryMetadata),
+    Skipped(Skipped),
+}
+
+impl WalkItem {
+    pub fn path(&self) -> &UniversalPath {
+        match self {
+            WalkItem::Entry(path, _) => path,
+            WalkItem::Skipped(skipped) => &skipped.path,
+        }
+    }
+}
+
+/// How many entries were left out, by reason.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct SkipCounts {
+    pub filtered: u64,
+    pub permission_denied: u64,
+    pub too_deep: u64,
+    pub unsupported_kind: u64,
+    pub broken_symlink: u64,
+    pub symlink: u64,
+}
+
+impl SkipCounts {
+    pub fn record(&mut self, reason: SkipReason) {
+        *self.count_mut(reason) += 1;
+    }
+
+    pub fn get(&self, reason: SkipReason) -> u64 {
+        let mut counts = *self;
+        *counts.count_mut(reason)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.filtered
+            + self.permission_denied
+            + self.too_deep
+            + self.unsupported_kind
+            + self.broken_symlink
+            + self.symlink
+    }
+
+    fn count_mut(&mut self, reason: SkipReason) -> &mut u64 {
+        match reason {
+            SkipReason::Filtered => &mut self.filtered,
+            SkipReason::PermissionDenied => &mut self.permission_denied,
+            SkipReason::TooDeep => &mut self.too_deep,
+            SkipReason::UnsupportedKind => &mut self.unsupported_kind,
+            SkipReason::BrokenSymlink => &mut self.broken_symlink,
+            SkipReason::Symlink => &mut self.symlink,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WalkOptions {
+    pub order: WalkOrder,
+    /// Deepest level yielded, 1 being the root's own children; `None` for no limit
+    pub max_depth: Option<usize>,
+    pub symlinks: SymlinkPolicy,
+    /// Order of each directory's children; `None` keeps the backend's listing order
+    pub sort: Option<Collation>,
+    /// Entries left out, along with everything below them
+    pub filter: Option<FilterSet>,
+}
+
+impl WalkOptions {
+    pub fn with_order(mut self, order: WalkOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    pub fn with_symlinks(mut self, symlinks: SymlinkPolicy) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    pub fn with_sort(mut self, collation: Collation) -> Self {
+        self.sort = Some(collation);
+        self
+    }
+
+    pub fn with_filter(mut self, filter: FilterSet) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+}
+
+/// Tree walking on top of `list` and `stat`, for any `Storage`, including
+/// `dyn Storage`.
+pub trait StorageExt: Storage {
+    /// Everything under `root`, breadth-first, not following symlinks
+    fn walk(&self, root: &UniversalPath) -> WalkStream<'_> {
+        self.walk_with(root, WalkOptions::default())
+    }
+
+    fn walk_with(&self, root: &UniversalPath, options: WalkOptions) -> WalkStream<'_>;
+
+    /// What `walk_with` yields, with a `Skipped` record for each entry it leaves out:
+    /// those the filter or the symlink policy do not take, broken symlinks, and
+    /// directories at the depth limit, after the directory itself. A directory that
+    /// cannot be listed for want of permission is skipped here rather than an error.
+    fn walk_items(&self, root: &UniversalPath, options: WalkOptions) -> WalkItemStream<'_>;
+
+    /// What `glob` finds for `pattern`, without what `filter` leaves out, taking paths
+    /// from the directory above the pattern's first wildcard
+    fn glob_filtered<'a>(
+        &'a self,
+        pattern: &'a UniversalPath,
+        filter: &'a FilterSet,
+    ) -> BoxFuture<'a, Result<Vec<UniversalPath>, StorageError>>;
+}
+
+impl<S: Storage + ?Sized> StorageExt for S {
+    fn walk_with(&self, root: &UniversalPath, options: WalkOptions) -> WalkStream<'_> {
+        let items = Walk::new(self, root, options, false).into_stream();
+        Box::pin(items.filter_map(|item| {
+            std::future::ready(match item {
+                Ok(WalkItem::Entry(path, meta)) => Some(Ok((path, meta))),
+                Ok(WalkItem::Skipped(_)) => None,
+                Err(e) => Some(Err(e)),
+            })
+        }))
+    }
+
+    fn walk_items(&self, root: &UniversalPath, options: WalkOptions) -> WalkItemStream<'_> {
+        Box::pin(Walk::new(self, root, options, true).into_stream())
+    }
+
+    fn glob_filtered<'a>(
+        &'a self,
+        pattern: &'a UniversalPath,
+        filter: &'a FilterSet,
+    ) -> BoxFuture<'a, Result<Vec<UniversalPath>, StorageError>> {
+        Box::pin(async move {
+            let base = literal_base(pattern);
+            let mut kept = Vec::new();
+            for path in self.glob(pattern).await? {
+                let Some(rel) = path.relative_to(&base) else {
+                    continue;
+                };
+                // Listings do not always say which entries are directories
+                let meta = match self.stat(&path).await {
+                    Ok(meta) => meta,
+                    Err(StorageError::NotFound) => continue,
+                    Err(e) => return Err(e),
+                };
+                if filter.allows(&rel, &meta.kind, meta.size_bytes) {
+                    kept.push(path);
+                }
+            }
+            Ok(kept)
+        })
+    }
+}
+
+struct Entry {
+    path: UniversalPath,
+    meta: EntryMetadata,
+    descend: bool,
+}
+
+struct Walk<'a, S: ?Sized> {
+    storage: &'a S,
+    root: UniversalPath,
+    options: WalkOptions,
+    /// Listings being read, with the depth of their entries: the innermost last when
+    /// depth-first, never more than one breadth-first
+    open: Vec<(ListStream<'a>, usize)>,
+    /// Directories still to list, breadth-first only
+    dirs: VecDeque<(UniversalPath, usize)>,
+    /// Symlink targets already descended into
+    followed: HashSet<String>,
+    /// Directories that cannot be listed for want of permission are skipped, not errors
+    report_skips: bool,
+    /// Left out since the last item, to be yielded next
+    skipped: VecDeque<Skipped>,
+}
+
+impl<'a, S: Storage + ?Sized> Walk<'a, S> {
+    fn new(storage: &'a S, root: &UniversalPath, options: WalkOptions, report_skips: bool) -> Self {
+        Walk {
+            storage,
+            root: root.as_dir(),
+            options,
+            open: Vec::new(),
+            dirs: VecDeque::from([(root.as_dir(), 0)]),
+            followed: HashSet::new(),
+            report_skips,
+            skipped: VecDeque::new(),
+        }
+    }
+
+    fn into_stream(self) -> impl Stream<Item = Result<WalkItem, StorageError>> + Send + 'a {
+        stream::unfold(self, |mut walk| async move {
+            let item = walk.next().await?;
+            Some((item, walk))
+        })
+    }
+
+    fn skip(&mut self, path: UniversalPath, reason: SkipReason) {
+        self.skipped.push_back(Skipped { path, reason });
+    }
+
+    async fn next(&mut self) -> Option<Result<WalkItem, StorageError>> {
+        loop {
+            if let Some(skipped) = self.skipped.pop_front() {
+                return Some(Ok(WalkItem::Skipped(skipped)));
+            }
+            let Some((listing, depth)) = self.open.last_mut() else {
+                let (dir, depth) = self.dirs.pop_front()?;
+                self.open(&dir, depth).await;
+                continue;
+            };
+            let depth = *depth;
+            let (path, meta) = match listing.next().await {
+                Some(Ok(child)) => child,
+                Some(Err(e)) => return Some(Err(e)),
+                None => {
+                    self.open.pop();
+                    continue;
+                }
+            };
+            let entry = match self.admit(path, meta, depth).await {
+                Some(Ok(entry)) => entry,
+                Some(Err(e)) => return Some(Err(e)),
+                None => continue,
+            };
+            if entry.descend {
+                match self.options.order {
+                    WalkOrder::BreadthFirst => self.dirs.push_back((entry.path.clone(), depth)),
+                    WalkOrder::DepthFirst => self.open(&entry.path, depth).await,
+                }
+            }
+            return Some(Ok(WalkItem::Entry(entry.path, entry.meta)));
+        }
+    }
+
+    /// Start reading the children of `dir`, which sits at `depth`. Their metadata comes
+    /// with the listing, page by page where the backend pages; only sorting holds a
+    /// whole directory at once.
+    async fn open(&mut self, dir: &UniversalPath, depth: usize) {
+        let depth = depth + 1;
+        if self.options.max_depth.is_some_and(|max| depth > max) {
+            return;
+        }
+        let listing = match self.storage.list_stream(dir).await {
+            Ok(listing) => listing,
+            Err(e) if self.report_skips && e.is_permission_denied() => {
+                self.skip(dir.clone(), SkipReason::PermissionDenied);
+                return;
+            }
+            Err(e) => {
+                self.open.push((Box::pin(stream::once(async { Err(e) })), depth));
+                return;
+            }
+        };
+        let listing = match &self.options.sort {
+            Some(collation) => {
+                let mut children: Vec<_> = listing.collect().await;
+                collation.sort_by_key(&mut children, |child| match child {
+                    Ok((path, _)) => path.last_segment().unwrap_or(""),
+                    Err(_) => "",
+                });
+                Box::pin(stream::iter(children))
+            }
+            None => listing,
+        };
+        self.open.push((listing, depth));
+    }
+
+    /// A listed child as the walk yields it, or `None` if it is left out
+    async fn admit(
+        &mut self,
+        path: UniversalPath,
+        meta: EntryMetadata,
+        depth: usize,
+    ) -> Option<Result<Entry, StorageError>> {
+        let rel = path.relative_to(&self.root).unwrap_or_default();
+        if self.options.filter.as_ref().is_some_and(|f| f.excludes(&rel)) {
+            self.skip(path, SkipReason::Filtered);
+            return None;
+        }
+        // Listings follow links, so one that comes back as a link could not be followed
+        if meta.kind == EntryKind::Symlink {
+            self.skip(path, SkipReason::BrokenSymlink);
+            return None;
+        }
+        let policy = self.options.symlinks;
+        let is_dir = meta.kind == EntryKind::Directory;
+        // Only directories can loop, so file links matter only when skipping
+        let target = if policy == SymlinkPolicy::Skip || is_dir {
+            match self.storage.symlink_target(&path).await {
+                Ok(target) => target,
+                Err(StorageError::NotFound) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        } else {
+            None
+        };
+        if target.is_some() && policy == SymlinkPolicy::Skip {
+            self.skip(path, SkipReason::Symlink);
+            return None;
+        }
+        if let Some(filter) = self.options.filter.as_ref().filter(|_| !is_dir)
+            && !filter.accepts_file(&rel, meta.size_bytes)
+        {
+            self.skip(path, SkipReason::Filtered);
+            return None;
+        }
+        let below_limit = self.options.max_depth.is_none_or(|max| depth < max);
+        let descend = is_dir
+            && below_limit
+            && match (&target, policy) {
+                (None, _) => true,
+                (Some(target), SymlinkPolicy::Follow) => self.followed.insert(target.to_string()),
+                (Some(_), _) => false,
+            };
+        let path = if is_dir { path.as_dir() } else { path };
+        if is_dir && !below_limit {
+            self.skip(path.clone(), SkipReason::TooDeep);
+        }
+        Some(Ok(Entry {
+            path,
+            meta,
+            descend,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{LocalStorage, Scanner};
+    use std::{fs, sync::Arc};
+
+    async fn names(
+        storage: &dyn Storage,
+        root: &UniversalPath,
+        options: WalkOptions,
+    ) -> Vec<String> {
+        let entries: Vec<_> = storage.walk_with(root, options).collect().await;
+        entries
+            .into_iter()
+            .map(|entry| {
+                let (path, _) = entry.unwrap();
+                path.relative_to(root).unwrap().join("/")
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_walk_orders_and_symlinks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("a/aa")).unwrap();
+        fs::create_dir_all(dir.join("b")).unwrap();
+        fs::write(dir.join("a/1.flac"), b"1").unwrap();
+        fs::write(dir.join("a/aa/2.flac"), b"2").unwrap();
+        fs::write(dir.join("b/3.flac"), b"3").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir, dir.join("b/loop")).unwrap();
+        let root = UniversalPath::local(dir.to_str().unwrap()).as_dir();
+        let storage: Box<dyn Storage> = Box::new(LocalStorage::new());
+        let sorted = |mut v: Vec<String>| {
+            v.sort();
+            v
+        };
+
+        let bfs = names(&*storage, &root, WalkOptions::default()).await;
+        let (first, rest) = bfs.split_at(2);
+        assert_eq!(sorted(first.to_vec()), ["a", "b"]);
+        assert_eq!(rest.last().unwrap(), "a/aa/2.flac");
+
+        let dfs = names(
+            &*storage,
+            &root,
+            WalkOptions::default().with_order(WalkOrder::DepthFirst),
+        )
+        .await;
+        // Everything under `a` comes straight after it, before `b` or its contents
+        let a = dfs.iter().position(|p| p == "a").unwrap();
+        assert!(dfs[a + 1..a + 4].iter().all(|p| p.starts_with("a/")));
+        let aa = dfs.iter().position(|p| p == "a/aa").unwrap();
+        assert_eq!(dfs[aa + 1], "a/aa/2.flac");
+
+        let shallow = names(&*storage, &root, WalkOptions::default().with_max_depth(1)).await;
+        assert_eq!(sorted(shallow), ["a", "b"]);
+
+        let ordered = names(
+            &*storage,
+            &root,
+            WalkOptions::default()
+                .with_order(WalkOrder::DepthFirst)
+                .with_sort(Collation::CodePoint),
+        )
+        .await;
+        assert_eq!(&ordered[..5], ["a", "a/1.flac", "a/aa", "a/aa/2.flac", "b"]);
+
+        #[cfg(unix)]
+        {
+            let reported = names(&*storage, &root, WalkOptions::default()).await;
+            assert!(reported.contains(&"b/loop".to_string()));
+            assert!(!reported.iter().any(|p| p.starts_with("b/loop/")));
+            let skipped = names(
+                &*storage,
+                &root,
+                WalkOptions::default().with_symlinks(SymlinkPolicy::Skip),
+            )
+            .await;
+            assert_eq!(skipped.len(), 6);
+            let followed = names(
+                &*storage,
+                &root,
+                WalkOptions::default().with_symlinks(SymlinkPolicy::Follow),
+            )
+            .await;
+            // Everything once more through the link, and the link inside that is not
+            // followed again
+            assert_eq!(followed.len(), 14);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_skip_reasons() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("a/aa")).unwrap();
+        fs::create_dir_all(dir.join(".cache")).unwrap();
+        fs::write(dir.join("a/1.flac"), b"1").unwrap();
+        fs::write(dir.join("a/aa/2.flac"), b"2").unwrap();
+        fs::write(dir.join("notes.txt"), b"notes").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.join("nowhere"), dir.join("dead.flac")).unwrap();
+        let root = UniversalPath::local(dir.to_str().unwrap()).as_dir();
+        let storage = Arc::new(LocalStorage::new());
+        let filter = FilterSet::new()
+            .with_extensions(["flac"])
+            .with_skip_hidden(true);
+        let skips = |items: Vec<Result<WalkItem, StorageError>>| {
+            let mut skips: Vec<_> = items
+                .into_iter()
+                .filter_map(|item| match item.unwrap() {
+                    WalkItem::Skipped(skipped) => Some((
+                        skipped.path.relative_to(&root).unwrap().join("/"),
+                        skipped.reason,
+                    )),
+                    WalkItem::Entry(..) => None,
+                })
+                .collect();
+            skips.sort();
+            skips
+        };
+        let mut expected = vec![
+            (".cache".to_string(), SkipReason::Filtered),
+            ("a/aa".to_string(), SkipReason::TooDeep),
+            ("notes.txt".to_string(), SkipReason::Filtered),
+        ];
+        #[cfg(unix)]
+        expected.insert(2, ("dead.flac".to_string(), SkipReason::BrokenSymlink));
+
+        let options = WalkOptions::default()
+            .with_max_depth(2)
+            .with_filter(filter.clone());
+        let walked = storage.walk_items(&root, options.clone()).collect().await;
+        assert_eq!(skips(walked), expected);
+        // The plain walk yields the same entries and no records
+        assert_eq!(names(&*storage, &root, options).await.len(), 3);
+
+        // A scan has no depth limit but says the same of the rest
+        let mut scan = Scanner::new(storage.clone())
+            .with_filter(filter)
+            .scan_items(&root);
+        let progress = scan.progress();
+        let mut scanned = Vec::new();
+        while let Some(item) = scan.next().await {
+            scanned.push(item);
+        }
+        expected.retain(|(_, reason)| *reason != SkipReason::TooDeep);
+        assert_eq!(skips(scanned), expected);
+        let counts = progress.borrow().skipped;
+        assert_eq!(counts.filtered, 2);
+        assert_eq!(counts.total(), expected.len() as u64);
+
+        // A directory that cannot be read is a record rather than an error, unless
+        // running as root, which reads it anyway
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let locked = dir.join("locked");
+            fs::create_dir(&locked).unwrap();
+            fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+            if fs::read_dir(&locked).is_err() {
+                let denied = ("locked".to_string(), SkipReason::PermissionDenied);
+                let walked = storage.walk_items(&root, WalkOptions::default()).collect().await;
+                assert!(skips(walked).contains(&denied));
+                let scanned = Scanner::new(storage.clone()).scan_items(&root).collect().await;
+                assert!(skips(scanned).contains(&denied));
+                let errors = storage.walk(&root).filter(|e| std::future::ready(e.is_err()));
+                assert_eq!(errors.count().await, 1);
+            }
+            fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+    }
+}
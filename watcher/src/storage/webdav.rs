@@ -0,0 +1,801 @@
+use super::{
+    CallOptions, Credentials, EntryKind, EntryMetadata, EntryPermissions, Storage, StorageBackend,
+    StorageCapabilities, StorageError, check_partial, clamp_range,
+};
+use crate::universal_path::UniversalPath;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use quick_xml::events::Event;
+use reqwest::{Method, StatusCode, header};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub(crate) const CAPABILITIES: StorageCapabilities = StorageCapabilities {
+    can_stat: true,
+    can_read: true,
+    can_read_range: true,
+    can_list: true,
+    can_glob: true,
+    can_write: true,
+    can_delete: true,
+    can_rename: true,
+    can_checksum: false,
+};
+
+/// Properties asked for in every PROPFIND
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/><d:getcontentlength/><d:getlastmodified/><d:creationdate/></d:prop></d:propfind>"#;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebDavConfig {
+    /// Sent with Basic authentication; None for servers that need no login
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Limit on each request, including transfers
+    pub timeout: Duration,
+}
+
+impl Default for WebDavConfig {
+    fn default() -> Self {
+        WebDavConfig {
+            username: None,
+            password: None,
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl WebDavConfig {
+    pub fn with_login<U: Into<String>, P: Into<String>>(
+        mut self,
+        username: U,
+        password: P,
+    ) -> Self {
+        self.username = Some(username.into());
+        self.password = Some(password.into());
+        self
+    }
+
+    /// The login in the path's URI, if any
+    pub fn for_path(path: &UniversalPath) -> Result<Self, StorageError> {
+        if !matches!(
+            path.backend(),
+            StorageBackend::WebDav | StorageBackend::WebDavs
+        ) {
+            return Err(StorageError::InvalidPath);
+        }
+        let mut config = WebDavConfig::default();
+        if let Some(user) = path.user() {
+            config = config.with_login(user, path.password().unwrap_or_default());
+        }
+        Ok(config)
+    }
+
+    pub fn apply_credentials(&mut self, credentials: &Credentials) -> Result<(), StorageError> {
+        match credentials {
+            Credentials::Anonymous => {
+                self.username = None;
+                self.password = None;
+            }
+            Credentials::Password { username, password } => {
+                self.username = Some(username.clone());
+                self.password = Some(password.clone());
+            }
+            _ => {
+                return Err(StorageError::Connection(format!(
+                    "WebDAV needs a password or anonymous login, not {credentials:?}"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Storage on a WebDAV server, as Nextcloud and most NAS devices offer: `dav://`
+/// paths over HTTP and `davs://` over HTTPS, logging in with Basic authentication.
+///
+/// Paths map to URLs one to one, so `davs://nas/remote.php/dav/files/me/Music/` is
+/// `https://nas/remote.php/dav/files/me/Music/`.
+#[derive(Clone)]
+pub struct WebDavStorage {
+    backend: StorageBackend,
+    config: WebDavConfig,
+    client: reqwest::Client,
+}
+
+impl WebDavStorage {
+    /// Storage for `backend`, which must be `WebDav` or `WebDavs`
+    pub fn new(backend: StorageBackend, config: WebDavConfig) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .build()
+            .expect("default TLS backend is available");
+        WebDavStorage::with_client(backend, config, client)
+    }
+
+    pub fn with_client(
+        backend: StorageBackend,
+        config: WebDavConfig,
+        client: reqwest::Client,
+    ) -> Self {
+        WebDavStorage {
+            backend,
+            config,
+            client,
+        }
+    }
+
+    fn url(&self, path: &UniversalPath) -> Result<String, StorageError> {
+        if path.backend() != &self.backend || path.host().is_none() {
+            return Err(StorageError::InvalidPath);
+        }
+        let uri = path.to_uri().map_err(|_| StorageError::InvalidPath)?;
+        let (_, rest) = uri.split_once("://").ok_or(StorageError::InvalidPath)?;
+        // Logins go in the Authorization header, not the URL
+        let rest = match path.user() {
+            Some(_) => rest.split_once('@').ok_or(StorageError::InvalidPath)?.1,
+            None => rest,
+        };
+        let scheme = match self.backend {
+            StorageBackend::WebDavs => "https",
+            _ => "http",
+        };
+        Ok(format!("{scheme}://{rest}"))
+    }
+
+    fn request(&self, method: Method, url: String) -> reqwest::RequestBuilder {
+        let request = self.client.request(method, url);
+        match &self.config.username {
+            Some(username) => request.basic_auth(username, self.config.password.as_ref()),
+            None => request,
+        }
+    }
+
+    async fn propfind(
+        &self,
+        path: &UniversalPath,
+        depth: &str,
+    ) -> Result<Vec<DavResource>, StorageError> {
+        let method = Method::from_bytes(b"PROPFIND").expect("valid method");
+        let sent_at = SystemTime::now();
+        let resp = self
+            .request(method, self.url(path)?)
+            .header("depth", depth)
+            .header(header::CONTENT_TYPE, "application/xml; charset=utf-8")
+            .body(PROPFIND_BODY)
+            .send()
+            .await
+            .map_err(connection_error)?;
+        let date = resp
+            .headers()
+            .get(header::DATE)
+            .and_then(|v| v.to_str().ok());
+        super::observe_date_header(&super::host_key(path), sent_at, date);
+        if resp.status() != StatusCode::MULTI_STATUS {
+            return Err(status_error(resp.status()));
+        }
+        let body = resp.text().await.map_err(connection_error)?;
+        parse_multistatus(&body)
+    }
+
+    async fn stat_inner(&self, path: &UniversalPath) -> Result<EntryMetadata, StorageError> {
+        let resources = self.propfind(path, "0").await?;
+        let resource = resources.into_iter().next().ok_or(StorageError::NotFound)?;
+        let mut meta = resource.metadata();
+        meta.is_hidden = super::is_hidden_name(path);
+        Ok(meta)
+    }
+
+    async fn list_inner(&self, path: &UniversalPath) -> Result<Vec<UniversalPath>, StorageError> {
+        let listed = self.list_with_metadata_inner(path).await?;
+        Ok(listed.into_iter().map(|(child, _)| child).collect())
+    }
+
+    /// From the properties the Depth 1 PROPFIND already asks for
+    async fn list_with_metadata_inner(
+        &self,
+        path: &UniversalPath,
+    ) -> Result<Vec<(UniversalPath, EntryMetadata)>, StorageError> {
+        let resources = self.propfind(&path.as_dir(), "1").await?;
+        let mut children = Vec::new();
+        let mut listed_self = false;
+        for resource in resources {
+            let segments = href_segments(&resource.href);
+            // The directory itself comes back too, usually first
+            if segments.len() <= path.path_segments().len() {
+                listed_self = true;
+                if !resource.is_collection {
+                    return Err(StorageError::NotADirectory);
+                }
+                continue;
+            }
+            let Some(name) = segments.last() else {
+                continue;
+            };
+            let child = path.as_dir().join(name);
+            let child = match resource.is_collection {
+                true => child.as_dir(),
+                false => child,
+            };
+            let mut meta = resource.metadata();
+            meta.is_hidden = super::is_hidden_name(&child);
+            children.push((child, meta));
+        }
+        if !listed_self && children.is_empty() {
+            return Err(StorageError::NotFound);
+        }
+        Ok(children)
+    }
+
+    async fn read_inner(&self, path: &UniversalPath) -> Result<Vec<u8>, StorageError> {
+        let resp = self
+            .request(Method::GET, self.url(path)?)
+            .send()
+            .await
+            .map_err(connection_error)?;
+        if !resp.status().is_success() {
+            return Err(status_error(resp.status()));
+        }
+        Ok(resp.bytes().await.map_err(connection_error)?.to_vec())
+    }
+
+    async fn read_range_inner(
+        &self,
+        path: &UniversalPath,
+        range: std::ops::Range<u64>,
+    ) -> Result<Vec<u8>, StorageError> {
+        if range.start >= range.end {
+            return Ok(Vec::new());
+        }
+        let resp = self
+            .request(Method::GET, self.url(path)?)
+            .header(
+                header::RANGE,
+                format!("bytes={}-{}", range.start, range.end - 1),
+            )
+            .send()
+            .await
+            .map_err(connection_error)?;
+        let status = resp.status();
+        if !status.is_success() {
+            return Err(status_error(status));
+        }
+        if status == StatusCode::PARTIAL_CONTENT {
+            let content_range = resp
+                .headers()
+                .get(header::CONTENT_RANGE)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string);
+            let body = resp.bytes().await.map_err(connection_error)?;
+            return check_partial(&range, content_range.as_deref(), body.to_vec());
+        }
+        let body = resp.bytes().await.map_err(connection_error)?;
+        let range = clamp_range(range, body.len() as u64)?;
+        Ok(body[range.start as usize..range.end as usize].to_vec())
+    }
+
+    async fn write_inner(&self, path: &UniversalPath, data: Vec<u8>) -> Result<(), StorageError> {
+        if path.is_dir_hint() {
+            return Err(StorageError::NotAFile);
+        }
+        let resp = self
+            .request(Method::PUT, self.url(path)?)
+            .body(data)
+            .send()
+            .await
+            .map_err(connection_error)?;
+        match resp.status() {
+            status if status.is_success() => Ok(()),
+            // RFC 4918 §9.7.1: the parent collection is missing
+            StatusCode::CONFLICT => Err(StorageError::NotFound),
+            StatusCode::METHOD_NOT_ALLOWED => Err(StorageError::NotAFile),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// MKCOL, creating missing parents first when the server answers 409
+    async fn create_dir_inner(&self, path: &UniversalPath) -> Result<(), StorageError> {
+        let mut missing = vec![path.as_dir()];
+        while let Some(dir) = missing.last() {
+            let method = Method::from_bytes(b"MKCOL").expect("valid method");
+            let resp = self
+                .request(method, self.url(dir)?)
+                .send()
+                .await
+                .map_err(connection_error)?;
+            match resp.status() {
+                status if status.is_success() => {
+                    missing.pop();
+                }
+                // Something is there already; only a directory will do
+                StatusCode::METHOD_NOT_ALLOWED => {
+                    if self.stat_inner(dir).await?.kind != EntryKind::Directory {
+                        return Err(StorageError::NotADirectory);
+                    }
+                    missing.pop();
+                }
+                StatusCode::CONFLICT => {
+                    let parent = dir.parent().ok_or(StorageError::NotFound)?;
+                    if parent.path_segments().is_empty() {
+                        return Err(StorageError::NotFound);
+                    }
+                    missing.push(parent);
+                }
+                status => return Err(status_error(status)),
+            }
+        }
+        Ok(())
+    }
+
+    /// DELETE, which removes a collection with everything in it, so directories are
+    /// checked for contents first
+    async fn delete_inner(&self, path: &UniversalPath) -> Result<(), StorageError> {
+        if path.path_segments().is_empty() {
+            return Err(StorageError::InvalidPath);
+        }
+        let meta = self.stat_inner(path).await?;
+        let path = match meta.kind {
+            EntryKind::Directory => {
+                if !self.list_inner(path).await?.is_empty() {
+                    return Err(StorageError::Io(
+                        std::io::ErrorKind::DirectoryNotEmpty.into(),
+                    ));
+                }
+                path.as_dir()
+            }
+            _ => path.clone(),
+        };
+        let resp = self
+            .request(Method::DELETE, self.url(&path)?)
+            .send()
+            .await
+            .map_err(connection_error)?;
+        match resp.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(status_error(status)),
+        }
+    }
+
+    async fn rename_inner(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+    ) -> Result<(), StorageError> {
+        if from.path_segments().is_empty() || to.relative_to(from).is_some() {
+            return Err(StorageError::InvalidPath);
+        }
+        let method = Method::from_bytes(b"MOVE").expect("valid method");
+        let resp = self
+            .request(method, self.url(from)?)
+            .header("destination", self.url(to)?)
+            .header("overwrite", "F")
+            .send()
+            .await
+            .map_err(connection_error)?;
+        match resp.status() {
+            status if status.is_success() => Ok(()),
+            StatusCode::PRECONDITION_FAILED => Err(StorageError::AlreadyExists),
+            StatusCode::CONFLICT => Err(StorageError::NotFound),
+            status => Err(status_error(status)),
+        }
+    }
+
+    async fn copy_inner(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+    ) -> Result<u64, StorageError> {
+        let meta = self.stat_inner(from).await?;
+        let (EntryKind::File, Some(size)) = (meta.kind, meta.size_bytes) else {
+            return Err(StorageError::NotAFile);
+        };
+        let method = Method::from_bytes(b"COPY").expect("valid method");
+        let resp = self
+            .request(method, self.url(from)?)
+            .header("destination", self.url(to)?)
+            .header("depth", "0")
+            .header("overwrite", "T")
+            .send()
+            .await
+            .map_err(connection_error)?;
+        match resp.status() {
+            status if status.is_success() => Ok(size),
+            StatusCode::CONFLICT => Err(StorageError::NotFound),
+            status => Err(status_error(status)),
+        }
+    }
+}
+
+/// One `<response>` of a multistatus reply, from its successful `<propstat>`s
+#[derive(Debug, Default, PartialEq)]
+struct DavResource {
+    href: String,
+    is_collection: bool,
+    size_bytes: Option<u64>,
+    modified_at: Option<SystemTime>,
+    created_at: Option<SystemTime>,
+}
+
+impl DavResource {
+    fn metadata(&self) -> EntryMetadata {
+        EntryMetadata {
+            kind: match self.is_collection {
+                true => EntryKind::Directory,
+                false => EntryKind::File,
+            },
+            size_bytes: self.size_bytes.filter(|_| !self.is_collection),
+            modified_at: self.modified_at,
+            created_at: self.created_at,
+            permissions: EntryPermissions::default(),
+            is_hidden: false,
+            format: None,
+        }
+    }
+}
+
+/// Read a 207 Multi-Status body, matching elements by local name since servers pick
+/// their own prefix for the `DAV:` namespace
+fn parse_multistatus(body: &str) -> Result<Vec<DavResource>, StorageError> {
+    let malformed = |e: &dyn std::fmt::Display| {
+        StorageError::Io(std::io::Error::other(format!("malformed multistatus: {e}")))
+    };
+    let mut reader = quick_xml::Reader::from_str(body);
+    let mut resources = Vec::new();
+    let mut current: Option<DavResource> = None;
+    // Properties of the propstat being read, kept only if its status is 200
+    let mut props = DavResource::default();
+    let mut status_ok = false;
+    // Text of the innermost element, with entity references resolved
+    let mut text = String::new();
+    loop {
+        match reader.read_event().map_err(|e| malformed(&e))? {
+            Event::Start(start) | Event::Empty(start) => {
+                text.clear();
+                match start.local_name().as_ref() {
+                    "response" => current = Some(DavResource::default()),
+                    "propstat" => {
+                        props = DavResource::default();
+                        status_ok = false;
+                    }
+                    "collection" => props.is_collection = true,
+                    _ => {}
+                }
+            }
+            Event::Text(content) => text.push_str(&content.xml10_content()),
+            Event::CData(content) => text.push_str(&content),
+            Event::GeneralRef(reference) => {
+                let resolved = match reference.resolve_char_ref().map_err(|e| malformed(&e))? {
+                    Some(c) => c,
+                    None => match reference.as_ref() {
+                        "amp" => '&',
+                        "lt" => '<',
+                        "gt" => '>',
+                        "quot" => '"',
+                        "apos" => '\'',
+                        other => return Err(malformed(&format!("unknown entity &{other};"))),
+                    },
+                };
+                text.push(resolved);
+            }
+            Event::End(end) => {
+                let value = text.trim();
+                match end.local_name().as_ref() {
+                    "href" => {
+                        if let Some(resource) = &mut current {
+                            resource.href = value.to_string();
+                        }
+                    }
+                    "status" => status_ok = value.split(' ').nth(1) == Some("200"),
+                    "getcontentlength" => props.size_bytes = value.parse().ok(),
+                    "getlastmodified" => {
+                        props.modified_at = DateTime::parse_from_rfc2822(value)
+                            .ok()
+                            .map(|d| d.with_timezone(&Utc).into())
+                    }
+                    "creationdate" => {
+                        props.created_at = DateTime::parse_from_rfc3339(value)
+                            .ok()
+                            .map(|d| d.with_timezone(&Utc).into())
+                    }
+                    "propstat" if status_ok => {
+                        if let Some(resource) = &mut current {
+                            let props = std::mem::take(&mut props);
+                            resource.is_collection |= props.is_collection;
+                            resource.size_bytes = resource.size_bytes.or(props.size_bytes);
+                            resource.modified_at = resource.modified_at.or(props.modified_at);
+                            resource.created_at = resource.created_at.or(props.created_at);
+                        }
+                    }
+                    "response" => resources.extend(current.take()),
+                    _ => {}
+                }
+                text.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(resources)
+}
+
+/// Decoded path segments of an href, which may be a full URL or just its path
+fn href_segments(href: &str) -> Vec<String> {
+    let path = match href.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("", |i| &rest[i..]),
+        None => href,
+    };
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(percent_decode)
+        .collect()
+}
+
+fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                out.push(byte);
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn connection_error(e: reqwest::Error) -> StorageError {
+    StorageError::Connection(e.to_string())
+}
+
+fn status_error(status: StatusCode) -> StorageError {
+    match status {
+        StatusCode::NOT_FOUND | StatusCode::GONE => StorageError::NotFound,
+        StatusCode::RANGE_NOT_SATISFIABLE => StorageError::RangeNotSatisfiable,
+        StatusCode::TOO_MANY_REQUESTS
+        | StatusCode::INTERNAL_SERVER_ERROR
+        | StatusCode::BAD_GATEWAY
+        | StatusCode::SERVICE_UNAVAILABLE
+        | StatusCode::GATEWAY_TIMEOUT => {
+            StorageError::Unavailable(format!("WebDAV server answered {status}"))
+        }
+        StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED => StorageError::Io(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("WebDAV server answered {status}"),
+        )),
+        _ => StorageError::Io(std::io::Error::other(format!(
+            "WebDAV server answered {status}"
+        ))),
+    }
+}
+
+#[async_trait]
+impl Storage for WebDavStorage {
+    fn backend(&self) -> StorageBackend {
+        self.backend.clone()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        CAPABILITIES
+    }
+
+    async fn stat_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<EntryMetadata, StorageError> {
+        opts.enforce_timeout(self.stat_inner(path)).await
+    }
+
+    async fn read_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        opts.enforce_timeout(self.read_inner(path)).await
+    }
+
+    async fn read_range_opts(
+        &self,
+        path: &UniversalPath,
+        range: std::ops::Range<u64>,
+        opts: &CallOptions,
+    ) -> Result<Vec<u8>, StorageError> {
+        opts.enforce_timeout(self.read_range_inner(path, range))
+            .await
+    }
+
+    async fn list_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<UniversalPath>, StorageError> {
+        opts.enforce_timeout(self.list_inner(path)).await
+    }
+
+    async fn list_with_metadata_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<Vec<(UniversalPath, EntryMetadata)>, StorageError> {
+        opts.enforce_timeout(self.list_with_metadata_inner(path))
+            .await
+    }
+
+    async fn write_opts(
+        &self,
+        path: &UniversalPath,
+        data: &[u8],
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        opts.enforce_timeout(self.write_inner(path, data.to_vec()))
+            .await
+    }
+
+    /// Buffers the whole file: a PUT body has to outlive the borrowed reader
+    async fn write_stream_opts(
+        &self,
+        path: &UniversalPath,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await?;
+        let len = data.len() as u64;
+        opts.enforce_timeout(self.write_inner(path, data)).await?;
+        Ok(len)
+    }
+
+    async fn create_dir_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        opts.enforce_timeout(self.create_dir_inner(path)).await
+    }
+
+    async fn delete_opts(
+        &self,
+        path: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        opts.enforce_timeout(self.delete_inner(path)).await
+    }
+
+    async fn rename_opts(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<(), StorageError> {
+        opts.enforce_timeout(self.rename_inner(from, to)).await
+    }
+
+    /// A `COPY` on the server
+    async fn copy_opts(
+        &self,
+        from: &UniversalPath,
+        to: &UniversalPath,
+        opts: &CallOptions,
+    ) -> Result<u64, StorageError> {
+        opts.enforce_timeout(self.copy_inner(from, to)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// As Nextcloud answers a Depth: 1 PROPFIND, with a 404 propstat for the length a
+    /// collection has no value for
+    const LISTING: &str = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:s="http://sabredav.org/ns">
+ <d:response>
+  <d:href>/dav/Music/</d:href>
+  <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype>
+   <d:getlastmodified>Tue, 13 Oct 2026 08:00:00 GMT</d:getlastmodified></d:prop>
+   <d:status>HTTP/1.1 200 OK</d:status></d:propstat>
+  <d:propstat><d:prop><d:getcontentlength/></d:prop>
+   <d:status>HTTP/1.1 404 Not Found</d:status></d:propstat>
+ </d:response>
+ <d:response>
+  <d:href>/dav/Music/Bj%c3%b6rk/</d:href>
+  <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop>
+   <d:status>HTTP/1.1 200 OK</d:status></d:propstat>
+ </d:response>
+ <d:response>
+  <D:href xmlns:D="DAV:">/dav/Music/01%20J%C3%B3ga.flac</D:href>
+  <d:propstat><d:prop><d:resourcetype/><d:getcontentlength>16</d:getcontentlength>
+   <d:creationdate>2026-10-01T12:00:00Z</d:creationdate></d:prop>
+   <d:status>HTTP/1.1 200 OK</d:status></d:propstat>
+ </d:response>
+</d:multistatus>"#;
+
+    /// Answers PROPFIND on /dav/Music/ and GET of the track, one request per connection
+    async fn serve() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = vec![0u8; 8192];
+                let n = socket.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).to_string();
+                let authorized = request.contains("authorization: Basic ZGo6cHc=");
+                let (status, body) = if !authorized {
+                    ("401 Unauthorized", String::new())
+                } else if request.starts_with("PROPFIND /dav/Music/ ")
+                    && request.contains("depth: 1")
+                {
+                    ("207 Multi-Status", LISTING.to_string())
+                } else if request.starts_with("GET /dav/Music/01%20J%C3%B3ga.flac ") {
+                    ("206 Partial Content", "4567".to_string())
+                } else {
+                    ("404 Not Found", String::new())
+                };
+                let range = match status.starts_with("206") {
+                    true => "Content-Range: bytes 4-7/16\r\n",
+                    false => "",
+                };
+                let response = format!(
+                    "HTTP/1.1 {status}\r\n{range}Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn test_list_and_read() {
+        let port = serve().await;
+        let music =
+            UniversalPath::from_uri_str(&format!("dav://dj:pw@127.0.0.1:{port}/dav/Music/"))
+                .unwrap();
+        let config = WebDavConfig::for_path(&music).unwrap();
+        let storage = WebDavStorage::new(StorageBackend::WebDav, config);
+        assert_eq!(
+            storage.url(&music).unwrap(),
+            format!("http://127.0.0.1:{port}/dav/Music/")
+        );
+
+        let children = storage.list(&music).await.unwrap();
+        assert_eq!(children.len(), 2);
+        assert_eq!(children[0].last_segment(), Some("Björk"));
+        assert!(children[0].is_dir_hint());
+        let track = &children[1];
+        assert_eq!(track.last_segment(), Some("01 Jóga.flac"));
+        assert_eq!(storage.read_range(track, 4..8).await.unwrap(), b"4567");
+        // The same PROPFIND answers with the metadata, which the server stubs no stat for
+        let listed = storage.list_with_metadata(&music).await.unwrap();
+        assert_eq!(listed[0].1.kind, EntryKind::Directory);
+        assert_eq!((&listed[1].0, listed[1].1.size_bytes), (track, Some(16)));
+
+        let resources = parse_multistatus(LISTING).unwrap();
+        let meta = resources[2].metadata();
+        assert_eq!((meta.kind, meta.size_bytes), (EntryKind::File, Some(16)));
+        assert!(meta.created_at.is_some());
+        let dir = resources[0].metadata();
+        assert_eq!((dir.kind, dir.size_bytes), (EntryKind::Directory, None));
+        assert!(dir.modified_at.is_some());
+
+        let stranger = WebDavStorage::new(StorageBackend::WebDav, WebDavConfig::default());
+        assert!(matches!(
+            stranger.list(&music).await,
+            Err(StorageError::Io(e)) if e.kind() == std::io::ErrorKind::PermissionDenied
+        ));
+    }
+}
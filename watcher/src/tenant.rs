@@ -0,0 +1,242 @@
+use crate::{
+    root::RootConfig,
+    storage::{CredentialStore, Storage, StorageError, open_storage_with},
+    universal_path::UniversalPath,
+    watch::{WatchEvent, WatchStream, Watcher},
+};
+use futures::StreamExt;
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum TenantError {
+    #[error("no tenant {0:?}")]
+    UnknownTenant(String),
+    #[error("tenant {0:?} already exists")]
+    DuplicateTenant(String),
+    #[error("{path} is not under any root of tenant {tenant:?}")]
+    OutsideRoots {
+        tenant: String,
+        path: Box<UniversalPath>,
+    },
+    #[error("root {path} overlaps a root of tenant {other:?}")]
+    RootOverlap {
+        path: Box<UniversalPath>,
+        other: String,
+    },
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+/// One user's or profile's share of a daemon: their roots and the credentials used to
+/// reach them. Everything done on a tenant's behalf stays inside its roots.
+#[derive(Debug, Clone)]
+pub struct Tenant {
+    pub id: String,
+    pub roots: Vec<RootConfig>,
+    pub credentials: CredentialStore,
+}
+
+impl Tenant {
+    pub fn new<S: Into<String>>(id: S) -> Self {
+        Tenant {
+            id: id.into(),
+            roots: Vec::new(),
+            credentials: CredentialStore::new(),
+        }
+    }
+
+    pub fn with_root(mut self, root: RootConfig) -> Self {
+        self.roots.push(root);
+        self
+    }
+
+    pub fn with_credentials(mut self, credentials: CredentialStore) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// The root `path` is under, if it belongs to this tenant. Both are normalized
+    /// first, so `..` cannot climb out of a root into another's.
+    pub fn root_for(&self, path: &UniversalPath) -> Option<&RootConfig> {
+        let path = path.normalize();
+        self.roots
+            .iter()
+            .find(|root| path.relative_to(&root.path.normalize()).is_some())
+    }
+
+    /// Storage for `path`, normalized, logging in with this tenant's credentials
+    /// only, never the process-wide provider's
+    pub fn open_storage(&self, path: &UniversalPath) -> Result<Box<dyn Storage>, TenantError> {
+        let path = self.check(path)?;
+        Ok(open_storage_with(&path, &self.credentials)?)
+    }
+
+    /// Whether the event is about this tenant's files. A rename counts if either end
+    /// is, so a file moved out of a root is seen leaving.
+    pub fn owns_event(&self, event: &WatchEvent) -> bool {
+        match event {
+            WatchEvent::Renamed { from, to } => {
+                self.root_for(from).is_some() || self.root_for(to).is_some()
+            }
+            event => self.root_for(event.path()).is_some(),
+        }
+    }
+
+    /// Watch one of this tenant's roots, dropping any event outside its roots
+    pub async fn watch(
+        &self,
+        watcher: &dyn Watcher,
+        root: &UniversalPath,
+    ) -> Result<WatchStream, TenantError> {
+        let root = self.check(root)?;
+        let tenant = self.clone();
+        let stream = watcher.watch(&root).await?;
+        Ok(Box::pin(stream.filter(move |event| {
+            let keep = match event {
+                Ok(event) => tenant.owns_event(event),
+                Err(_) => true,
+            };
+            async move { keep }
+        })))
+    }
+
+    /// `path` normalized, if it is under one of this tenant's roots
+    fn check(&self, path: &UniversalPath) -> Result<UniversalPath, TenantError> {
+        match self.root_for(path) {
+            Some(_) => Ok(path.normalize()),
+            None => Err(TenantError::OutsideRoots {
+                tenant: self.id.clone(),
+                path: Box::new(path.clone()),
+            }),
+        }
+    }
+}
+
+/// The tenants of a daemon, by id. No two tenants' roots may overlap, so every
+/// library path has at most one owner.
+#[derive(Debug, Clone, Default)]
+pub struct Tenants {
+    tenants: BTreeMap<String, Tenant>,
+}
+
+impl Tenants {
+    pub fn new() -> Self {
+        Tenants::default()
+    }
+
+    pub fn insert(&mut self, tenant: Tenant) -> Result<(), TenantError> {
+        if self.tenants.contains_key(&tenant.id) {
+            return Err(TenantError::DuplicateTenant(tenant.id));
+        }
+        for root in &tenant.roots {
+            let ours = root.path.normalize();
+            for other in self.tenants.values() {
+                let overlaps = other.roots.iter().any(|theirs| {
+                    let theirs = theirs.path.normalize();
+                    ours.relative_to(&theirs).is_some() || theirs.relative_to(&ours).is_some()
+                });
+                if overlaps {
+                    return Err(TenantError::RootOverlap {
+                        path: Box::new(root.path.clone()),
+                        other: other.id.clone(),
+                    });
+                }
+            }
+        }
+        self.tenants.insert(tenant.id.clone(), tenant);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<Tenant> {
+        self.tenants.remove(id)
+    }
+
+    /// The tenant a control request names
+    pub fn get(&self, id: &str) -> Result<&Tenant, TenantError> {
+        self.tenants
+            .get(id)
+            .ok_or_else(|| TenantError::UnknownTenant(id.to_string()))
+    }
+
+    /// The tenant owning `path`, if any
+    pub fn owner_of(&self, path: &UniversalPath) -> Option<&Tenant> {
+        self.tenants
+            .values()
+            .find(|tenant| tenant.root_for(path).is_some())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Tenant> {
+        self.tenants.values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Credentials;
+
+    #[test]
+    fn test_isolation() {
+        let alice_root = UniversalPath::from_uri_str("sftp://nas/home/alice/music/").unwrap();
+        let bob_root = UniversalPath::from_uri_str("sftp://nas/home/bob/music/").unwrap();
+        let alice = Tenant::new("alice")
+            .with_root(RootConfig::new(alice_root.clone()))
+            .with_credentials(CredentialStore::new().with(
+                "sftp",
+                "nas",
+                Credentials::password("alice", "pw"),
+            ));
+        let mut tenants = Tenants::new();
+        tenants.insert(alice).unwrap();
+        tenants
+            .insert(Tenant::new("bob").with_root(RootConfig::new(bob_root.clone())))
+            .unwrap();
+
+        // Nobody else may claim a root inside or around an existing one
+        let nested = Tenant::new("guest").with_root(RootConfig::new(alice_root.join("shared")));
+        assert!(matches!(
+            tenants.insert(nested),
+            Err(TenantError::RootOverlap { other, .. }) if other == "alice"
+        ));
+        let climbing = UniversalPath::from_uri_str("sftp://nas/home/bob/../alice/").unwrap();
+        assert!(matches!(
+            tenants.insert(Tenant::new("mallory").with_root(RootConfig::new(climbing))),
+            Err(TenantError::RootOverlap { other, .. }) if other == "alice"
+        ));
+        assert!(matches!(
+            tenants.insert(Tenant::new("bob")),
+            Err(TenantError::DuplicateTenant(_))
+        ));
+
+        let track = bob_root.join("01.flac");
+        assert_eq!(tenants.owner_of(&track).map(|t| t.id.as_str()), Some("bob"));
+        let alice = tenants.get("alice").unwrap();
+        assert!(matches!(
+            alice.open_storage(&track),
+            Err(TenantError::OutsideRoots { .. })
+        ));
+        assert!(!alice.owns_event(&WatchEvent::Created(track.clone())));
+        // `..` does not climb out of a root
+        let escaping =
+            UniversalPath::from_uri_str("sftp://nas/home/alice/music/../../bob/music/x.flac")
+                .unwrap();
+        assert!(alice.root_for(&escaping).is_none());
+        assert!(matches!(
+            alice.open_storage(&escaping),
+            Err(TenantError::OutsideRoots { .. })
+        ));
+        assert_eq!(
+            tenants.owner_of(&escaping).map(|t| t.id.as_str()),
+            Some("bob")
+        );
+        assert!(alice.owns_event(&WatchEvent::Renamed {
+            from: alice_root.join("a.flac"),
+            to: track,
+        }));
+        assert!(matches!(
+            tenants.get("carol"),
+            Err(TenantError::UnknownTenant(_))
+        ));
+    }
+}
@@ -0,0 +1,203 @@
+use crate::universal_path::UniversalPath;
+use serde::{Deserialize, Serialize};
+use unicode_normalization::{UnicodeNormalization, char::is_combining_mark};
+
+/// Written for a character with no ASCII approximation
+const UNMAPPED: char = '_';
+
+/// How names are written under a root.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NameMode {
+    /// As they are
+    #[default]
+    Unicode,
+    /// Approximated in ASCII by `to_ascii`, for car stereos and older players that
+    /// cannot show or open anything else
+    Ascii,
+}
+
+impl NameMode {
+    /// `name` as this mode writes it
+    pub fn apply(self, name: &str) -> String {
+        match self {
+            NameMode::Unicode => name.to_string(),
+            NameMode::Ascii => to_ascii(name),
+        }
+    }
+
+    /// `path` with every segment below `root` written in this mode; `root` itself and
+    /// paths outside it are left as they are
+    pub fn apply_path(self, root: &UniversalPath, path: &UniversalPath) -> UniversalPath {
+        let Some(segments) = path.relative_to(root).filter(|_| self != NameMode::Unicode) else {
+            return path.clone();
+        };
+        let segments: Vec<_> = segments.iter().map(|s| self.apply(s)).collect();
+        let mapped = root.as_dir().join_all(&segments);
+        match path.is_dir_hint() {
+            true => mapped.as_dir(),
+            false => mapped,
+        }
+    }
+}
+
+/// `name` in ASCII: accents dropped (`Björk` is `Bjork`), letters without a
+/// decomposition spelled out (`ß` is `ss`, `Æ` is `AE`), Cyrillic and Greek romanized,
+/// typographic quotes and dashes made plain, and anything else, such as CJK, written as
+/// `_`. Names already in ASCII come back unchanged. Different names can come out the
+/// same, so writers should resolve collisions as they would any other.
+pub fn to_ascii(name: &str) -> String {
+    if name.is_ascii() {
+        return name.to_string();
+    }
+    let mut out = String::with_capacity(name.len());
+    for c in name.nfkd().filter(|&c| !is_combining_mark(c)) {
+        if c.is_ascii() {
+            out.push(c);
+        } else if let Some(spelled) = spelling(c) {
+            out.push_str(spelled);
+        } else if let Some(romanized) = romanize(c) {
+            out.push_str(&romanized);
+        } else if c.is_whitespace() {
+            out.push(' ');
+        } else {
+            out.push(UNMAPPED);
+        }
+    }
+    out
+}
+
+/// Letters and punctuation NFKD leaves alone
+fn spelling(c: char) -> Option<&'static str> {
+    Some(match c {
+        'ß' => "ss",
+        'ẞ' => "SS",
+        'Æ' => "AE",
+        'æ' => "ae",
+        'Œ' => "OE",
+        'œ' => "oe",
+        'Ø' => "O",
+        'ø' => "o",
+        'Þ' => "Th",
+        'þ' => "th",
+        'Ð' | 'Đ' => "D",
+        'ð' | 'đ' => "d",
+        'Ł' => "L",
+        'ł' => "l",
+        'Ħ' => "H",
+        'ħ' => "h",
+        'ı' => "i",
+        'Ŋ' => "NG",
+        'ŋ' => "ng",
+        // Quotes become apostrophes, which FAT and Windows names allow
+        '‘' | '’' | '‚' | '‛' | '“' | '”' | '„' | '‟' | '«' | '»' | '‹' | '›' | '′' | '″' => {
+            "'"
+        }
+        '‐' | '‑' | '‒' | '–' | '—' | '―' | '−' | '⁄' | '•' => "-",
+        '×' => "x",
+        '¡' => "!",
+        '¿' => "?",
+        '©' => "(c)",
+        '®' => "(r)",
+        '°' => "deg",
+        _ => return None,
+    })
+}
+
+/// Cyrillic and Greek letters, by the plain romanizations common on record sleeves,
+/// capitalized as the letter was
+fn romanize(c: char) -> Option<String> {
+    let lower = c.to_lowercase().next()?;
+    let latin = match lower {
+        'а' => "a",
+        'б' => "b",
+        'в' => "v",
+        'г' => "g",
+        'д' => "d",
+        'е' | 'э' | 'є' => "e",
+        'ж' => "zh",
+        'з' => "z",
+        // Also й, ё and ї, which decompose to these and a mark
+        'и' | 'і' => "i",
+        'к' => "k",
+        'л' => "l",
+        'м' => "m",
+        'н' => "n",
+        'о' => "o",
+        'п' => "p",
+        'р' => "r",
+        'с' => "s",
+        'т' => "t",
+        'у' => "u",
+        'ф' => "f",
+        'х' => "kh",
+        'ц' => "ts",
+        'ч' => "ch",
+        'ш' => "sh",
+        'щ' => "shch",
+        'ъ' | 'ь' => "",
+        'ы' => "y",
+        'ю' => "yu",
+        'я' => "ya",
+        'α' => "a",
+        'β' => "v",
+        'γ' => "g",
+        'δ' => "d",
+        'ε' => "e",
+        'ζ' => "z",
+        'η' => "i",
+        'θ' => "th",
+        'ι' => "i",
+        'κ' => "k",
+        'λ' => "l",
+        'μ' => "m",
+        'ν' => "n",
+        'ξ' => "x",
+        'ο' => "o",
+        'π' => "p",
+        'ρ' => "r",
+        'σ' | 'ς' => "s",
+        'τ' => "t",
+        'υ' => "y",
+        'φ' => "f",
+        'χ' => "ch",
+        'ψ' => "ps",
+        'ω' => "o",
+        _ => return None,
+    };
+    if lower == c {
+        return Some(latin.to_string());
+    }
+    let mut chars = latin.chars();
+    Some(match chars.next() {
+        Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+        None => String::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_ascii() {
+        assert_eq!(to_ascii("01 Army of Me.flac"), "01 Army of Me.flac");
+        assert_eq!(to_ascii("Björk – Jóga.flac"), "Bjork - Joga.flac");
+        // Decomposed, as macOS stores it
+        assert_eq!(to_ascii("Bjo\u{308}rk"), "Bjork");
+        assert_eq!(to_ascii("Straße “Live” ½"), "Strasse 'Live' 1-2");
+        assert_eq!(to_ascii("Сергей Прокофьев"), "Sergei Prokofev");
+        assert_eq!(to_ascii("Μάνος Χατζιδάκις"), "Manos Chatzidakis");
+        assert_eq!(to_ascii("ﬁnal ＢＯＳＳ"), "final BOSS");
+        assert_eq!(to_ascii("坂本龍一.flac"), "____.flac");
+
+        let root = UniversalPath::local("/media/Čar").as_dir();
+        let track = root.join_all(&["Sigur Rós", "Ágætis byrjun", "01 Intro.flac"]);
+        let mapped = NameMode::Ascii.apply_path(&root, &track);
+        assert_eq!(
+            mapped,
+            root.join_all(&["Sigur Ros", "Agaetis byrjun", "01 Intro.flac"])
+        );
+        assert_eq!(NameMode::Unicode.apply_path(&root, &track), track);
+    }
+}
@@ -0,0 +1,352 @@
+use crate::root::RootConfig;
+use crate::storage::StorageBackend;
+use crate::universal_path::UniversalPath;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// The GUID path Windows names a volume by, `\\?\Volume{…}\`, which stays the same
+/// when the drive letter it is mounted at changes.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct VolumeId(String);
+
+impl VolumeId {
+    pub fn new<S: Into<String>>(id: S) -> Self {
+        VolumeId(id.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum VolumeError {
+    #[error("corrupt volume registry: {0}")]
+    CorruptState(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// What became of a root's volume since the registry last looked.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VolumeChange {
+    /// The volume is mounted at another drive letter, so the root is now at `path`
+    Moved { label: String, path: UniversalPath },
+    /// The volume is not mounted anywhere
+    Removed { label: String },
+    /// The volume is mounted again, at the drive letter it had
+    Arrived { label: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct RootVolume {
+    volume: VolumeId,
+    /// The drive letter of the root as it was configured
+    configured: char,
+    /// The drive letter the volume was last mounted at
+    drive: char,
+    mounted: bool,
+}
+
+/// Which volume each root on a Windows drive is on, so the root can be followed when
+/// its volume comes back at another drive letter.
+///
+/// With a file, every change is written through (write-then-rename) and the registry
+/// outlives the daemon, so a root configured as `E:\Music` is still found after the
+/// drive became `F:` while the daemon was stopped.
+#[derive(Debug, Default)]
+pub struct VolumeRegistry {
+    roots: BTreeMap<String, RootVolume>,
+    path: Option<PathBuf>,
+}
+
+impl VolumeRegistry {
+    /// A registry kept in memory only
+    pub fn new() -> Self {
+        VolumeRegistry::default()
+    }
+
+    /// Open (or create) a registry persisted at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, VolumeError> {
+        let path = path.as_ref().to_path_buf();
+        let roots = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(VolumeRegistry {
+            roots,
+            path: Some(path),
+        })
+    }
+
+    /// The volume the root labelled `label` was last seen on
+    pub fn volume(&self, label: &str) -> Option<&VolumeId> {
+        self.roots.get(label).map(|root| &root.volume)
+    }
+
+    /// Bring the registry up to date with `roots` and `mounts`, the volume mounted at
+    /// each drive letter, and say what became of each root's volume.
+    ///
+    /// Roots not on a drive are left out. A root seen for the first time is recorded
+    /// against the volume at its drive letter, once one is mounted there; so is a root
+    /// whose drive letter was changed in its configuration. Roots no longer given are
+    /// forgotten.
+    pub fn update(
+        &mut self,
+        roots: &[RootConfig],
+        mounts: &BTreeMap<char, VolumeId>,
+    ) -> Result<Vec<VolumeChange>, VolumeError> {
+        let before = self.roots.clone();
+        let mut changes = Vec::new();
+        self.roots
+            .retain(|label, _| roots.iter().any(|root| &root.label == label));
+        for root in roots {
+            let Some(drive) = root.path.drive_letter().map(|d| d.to_ascii_uppercase()) else {
+                continue;
+            };
+            let label = root.label.clone();
+            let known = self.roots.get(&label).filter(|known| {
+                // A root moved here is on the drive it was moved to, and one started
+                // again from its configuration is on the drive it was configured on
+                known.configured == drive || known.drive == drive
+            });
+            let Some(known) = known.cloned() else {
+                match mounts.get(&drive) {
+                    Some(volume) => {
+                        let volume = volume.clone();
+                        let recorded = RootVolume {
+                            volume,
+                            configured: drive,
+                            drive,
+                            mounted: true,
+                        };
+                        self.roots.insert(label, recorded);
+                    }
+                    None => {
+                        self.roots.remove(&label);
+                    }
+                }
+                continue;
+            };
+            let at = mounted_at(mounts, &known.volume);
+            let mounted = match at {
+                Some(at) if at == drive => {
+                    if !known.mounted {
+                        changes.push(VolumeChange::Arrived {
+                            label: label.clone(),
+                        });
+                    }
+                    true
+                }
+                Some(at) => {
+                    changes.push(VolumeChange::Moved {
+                        label: label.clone(),
+                        path: with_drive(&root.path, at),
+                    });
+                    true
+                }
+                None => {
+                    if known.mounted {
+                        changes.push(VolumeChange::Removed {
+                            label: label.clone(),
+                        });
+                    }
+                    false
+                }
+            };
+            let drive = at.unwrap_or(known.drive);
+            let known = RootVolume {
+                drive,
+                mounted,
+                ..known
+            };
+            self.roots.insert(label, known);
+        }
+        if self.roots != before {
+            self.save()?;
+        }
+        Ok(changes)
+    }
+
+    fn save(&self) -> Result<(), VolumeError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&self.roots)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+fn mounted_at(mounts: &BTreeMap<char, VolumeId>, volume: &VolumeId) -> Option<char> {
+    mounts
+        .iter()
+        .find(|(_, mounted)| *mounted == volume)
+        .map(|(drive, _)| *drive)
+}
+
+/// `path` on drive `drive` instead
+fn with_drive(path: &UniversalPath, drive: char) -> UniversalPath {
+    let segments = std::iter::once(format!("{drive}:")).chain(path.path_segments()[1..].to_vec());
+    let moved = UniversalPath::from_segments(StorageBackend::Local, None::<&str>, segments);
+    if path.is_dir_hint() {
+        moved.as_dir()
+    } else {
+        moved
+    }
+}
+
+/// The volume mounted at each drive letter now. Drives that are not volumes of
+/// their own, such as mapped network shares, are left out.
+///
+/// Windows announces volumes coming and going with `WM_DEVICECHANGE` to windows, and
+/// services that register for it, but a service has no window and the service
+/// control API does not pass those events on; calling this every few seconds sees
+/// the same arrivals, removals and reassigned letters.
+#[cfg(windows)]
+pub fn mounted_volumes() -> BTreeMap<char, VolumeId> {
+    use windows_sys::Win32::Storage::FileSystem::{
+        GetLogicalDrives, GetVolumeNameForVolumeMountPointW,
+    };
+    // SAFETY: GetLogicalDrives takes nothing and returns a bitmask
+    let drives = unsafe { GetLogicalDrives() };
+    let mut mounts = BTreeMap::new();
+    for (bit, drive) in ('A'..='Z').enumerate() {
+        if drives & (1 << bit) == 0 {
+            continue;
+        }
+        let mount_point: Vec<u16> = format!("{drive}:\\").encode_utf16().chain([0]).collect();
+        // A volume GUID path is 49 characters with its terminating null
+        let mut name = [0u16; 64];
+        // SAFETY: the mount point is null-terminated and the buffer is as long as
+        // we say it is
+        let found = unsafe {
+            GetVolumeNameForVolumeMountPointW(
+                mount_point.as_ptr(),
+                name.as_mut_ptr(),
+                name.len() as u32,
+            )
+        };
+        if found == 0 {
+            continue;
+        }
+        let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+        mounts.insert(drive, VolumeId(String::from_utf16_lossy(&name[..len])));
+    }
+    mounts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mounts(drives: &[(char, &str)]) -> BTreeMap<char, VolumeId> {
+        drives
+            .iter()
+            .map(|(drive, id)| (*drive, VolumeId::new(*id)))
+            .collect()
+    }
+
+    #[test]
+    fn test_follows_volume_across_letters() {
+        let music = RootConfig::new(UniversalPath::local("E:\\Music\\").as_dir());
+        let mut registry = VolumeRegistry::new();
+        let changes = registry
+            .update(
+                std::slice::from_ref(&music),
+                &mounts(&[('C', "sys"), ('E', "usb")]),
+            )
+            .unwrap();
+        assert!(changes.is_empty());
+        assert_eq!(registry.volume("Music"), Some(&VolumeId::new("usb")));
+
+        let changes = registry
+            .update(std::slice::from_ref(&music), &mounts(&[('C', "sys")]))
+            .unwrap();
+        assert_eq!(
+            changes,
+            [VolumeChange::Removed {
+                label: "Music".to_string()
+            }]
+        );
+        let changes = registry
+            .update(
+                std::slice::from_ref(&music),
+                &mounts(&[('C', "sys"), ('F', "usb")]),
+            )
+            .unwrap();
+        let moved = UniversalPath::local("F:\\Music\\").as_dir();
+        assert_eq!(
+            changes,
+            [VolumeChange::Moved {
+                label: "Music".to_string(),
+                path: moved.clone()
+            }]
+        );
+
+        // Once the daemon has moved the root, it stays put
+        let mut music = music;
+        music.path = moved;
+        let now = mounts(&[('C', "sys"), ('F', "usb")]);
+        assert!(
+            registry
+                .update(std::slice::from_ref(&music), &now)
+                .unwrap()
+                .is_empty()
+        );
+        let changes = registry
+            .update(std::slice::from_ref(&music), &mounts(&[]))
+            .unwrap();
+        assert_eq!(changes.len(), 1);
+        let changes = registry.update(&[music], &now).unwrap();
+        assert_eq!(
+            changes,
+            [VolumeChange::Arrived {
+                label: "Music".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_registry_persists_and_follows_configuration() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("volumes.json");
+        let music = RootConfig::new(UniversalPath::local("E:\\Music"));
+        let network = RootConfig::new(UniversalPath::from_uri_str("smb://nas/music/").unwrap());
+        let mut registry = VolumeRegistry::open(&path).unwrap();
+        registry
+            .update(&[music.clone(), network.clone()], &mounts(&[('E', "usb")]))
+            .unwrap();
+        assert_eq!(registry.volume(&network.label), None);
+
+        // Restarted from the configuration after the drive became F:
+        let mut registry = VolumeRegistry::open(&path).unwrap();
+        let changes = registry
+            .update(std::slice::from_ref(&music), &mounts(&[('F', "usb")]))
+            .unwrap();
+        assert!(
+            matches!(&changes[..], [VolumeChange::Moved { path, .. }] if path.drive_letter() == Some('F'))
+        );
+
+        // Pointed at another drive in the configuration, the root is that drive's
+        let mut elsewhere = music;
+        elsewhere.path = UniversalPath::local("G:\\Music");
+        let now = mounts(&[('F', "usb"), ('G', "ssd")]);
+        assert!(
+            registry
+                .update(&[elsewhere.clone()], &now)
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(registry.volume("Music"), Some(&VolumeId::new("ssd")));
+        assert!(registry.update(&[], &now).unwrap().is_empty());
+        assert_eq!(registry.volume("Music"), None);
+    }
+}
@@ -0,0 +1,84 @@
+mod debounce;
+#[cfg(feature = "watch")]
+mod native;
+mod polling;
+mod stability;
+
+use crate::storage::StorageError;
+use crate::universal_path::UniversalPath;
+use async_trait::async_trait;
+use futures::Stream;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::sync::mpsc;
+
+/// A change under a watched root.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatchEvent {
+    Created(UniversalPath),
+    Modified(UniversalPath),
+    Deleted(UniversalPath),
+    Renamed {
+        from: UniversalPath,
+        to: UniversalPath,
+    },
+    /// Events were lost, e.g. because the kernel queue overflowed; anything under the
+    /// path may have changed and should be listed again
+    Rescan(UniversalPath),
+    /// A file created or modified has stopped changing and can be processed, from a
+    /// `StabilityFilter`
+    FileReady(UniversalPath),
+}
+
+impl WatchEvent {
+    /// The path the event leaves behind: the new name for a rename
+    pub fn path(&self) -> &UniversalPath {
+        match self {
+            WatchEvent::Created(p)
+            | WatchEvent::Modified(p)
+            | WatchEvent::Deleted(p)
+            | WatchEvent::Rescan(p)
+            | WatchEvent::FileReady(p) => p,
+            WatchEvent::Renamed { to, .. } => to,
+        }
+    }
+}
+
+/// Events for one watched root, until the stream is dropped.
+pub type WatchStream = Pin<Box<dyn Stream<Item = Result<WatchEvent, StorageError>> + Send>>;
+
+#[async_trait]
+pub trait Watcher: Send + Sync {
+    /// Start watching everything under `root`. Fails up front if `root` cannot be
+    /// watched, e.g. because it does not exist.
+    async fn watch(&self, root: &UniversalPath) -> Result<WatchStream, StorageError>;
+}
+
+pub(crate) type EventSender = mpsc::UnboundedSender<Result<WatchEvent, StorageError>>;
+
+/// A stream fed through the returned sender, for watchers that produce events on a
+/// task of their own. The task should stop once the sender reports it is closed.
+pub(crate) fn channel() -> (EventSender, WatchStream) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    (tx, Box::pin(ChannelStream { rx }))
+}
+
+struct ChannelStream {
+    rx: mpsc::UnboundedReceiver<Result<WatchEvent, StorageError>>,
+}
+
+impl Stream for ChannelStream {
+    type Item = Result<WatchEvent, StorageError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+pub use debounce::EventDebouncer;
+#[cfg(feature = "watch")]
+pub use native::LocalWatcher;
+pub use polling::PollingWatcher;
+pub use stability::StabilityFilter;
@@ -0,0 +1,344 @@
+use super::{EventSender, WatchEvent, WatchStream, channel};
+use crate::universal_path::UniversalPath;
+use futures::StreamExt;
+use std::{collections::HashMap, time::Duration};
+use tokio::time::Instant;
+
+/// Coalesces bursts of events on the same path, the way editors and copy tools write
+/// a file in many small steps, into one event once the path has been quiet for the
+/// window. Intermediate states cancel out: a file created and deleted again is never
+/// reported, one created and then written to is one `Created`, and one deleted and
+/// created again, as some editors save, is one `Modified`. A path that keeps changing
+/// is still reported once `max_delay` has passed since its first event.
+///
+/// A rename carries what happened to the old name before it: a file written under a
+/// temporary name and renamed into place is `Created` at its final name. Errors pass
+/// straight through; a `Rescan` first flushes everything pending, since it supersedes
+/// it anyway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventDebouncer {
+    window: Duration,
+    max_delay: Duration,
+}
+
+impl Default for EventDebouncer {
+    /// Half a second of quiet, and no event held back for more than 10 seconds
+    fn default() -> Self {
+        EventDebouncer {
+            window: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl EventDebouncer {
+    pub fn new(window: Duration) -> Self {
+        EventDebouncer {
+            window,
+            ..EventDebouncer::default()
+        }
+    }
+
+    /// The longest an event is held back while its path keeps changing
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay.max(self.window);
+        self
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// `events`, debounced. Must be called within a Tokio runtime, as the events are
+    /// collected on a task of their own, which stops once the returned stream is
+    /// dropped or `events` ends.
+    pub fn debounce(&self, events: WatchStream) -> WatchStream {
+        let (tx, stream) = channel();
+        tokio::spawn(run(*self, events, tx));
+        stream
+    }
+}
+
+/// What a path is waiting to be reported as.
+struct Pending {
+    event: WatchEvent,
+    /// A renamed file was also written to, reported as `Modified` after the rename
+    modified: bool,
+    /// Order of the first event, so paths are reported in the order they changed
+    seq: u64,
+    first: Instant,
+    last: Instant,
+}
+
+/// Order and time of a path's first event, kept when later ones merge into it
+type Since = (u64, Instant);
+
+#[derive(Default)]
+struct Coalescer {
+    /// By the printed path the event leaves behind
+    pending: HashMap<String, Pending>,
+    next_seq: u64,
+}
+
+impl Coalescer {
+    fn add(&mut self, event: WatchEvent, now: Instant) {
+        let (event, modified, since) = match event {
+            WatchEvent::Renamed { from, to } => match self.rename(from, to) {
+                Some(renamed) => renamed,
+                None => return,
+            },
+            event => {
+                let previous = self.pending.remove(&event.path().to_string());
+                let since = previous.as_ref().map(|p| (p.seq, p.first));
+                let (event, modified) = match previous {
+                    Some(previous) => match merge(previous.event, previous.modified, event) {
+                        Some(merged) => merged,
+                        None => return,
+                    },
+                    None => (event, false),
+                };
+                (event, modified, since)
+            }
+        };
+        let (seq, first) = since.unwrap_or_else(|| {
+            self.next_seq += 1;
+            (self.next_seq, now)
+        });
+        // A moved file deleted at its new name, while its old name was taken again
+        let (event, modified) = match self.pending.remove(&event.path().to_string()) {
+            Some(later) => match merge(event, modified, later.event) {
+                Some(merged) => merged,
+                None => return,
+            },
+            None => (event, modified),
+        };
+        self.pending.insert(
+            event.path().to_string(),
+            Pending {
+                event,
+                modified,
+                seq,
+                first,
+                last: now,
+            },
+        );
+    }
+
+    /// A rename, carrying over what was pending for its old name, or nothing if the
+    /// file was only moved back where it was
+    fn rename(
+        &mut self,
+        from: UniversalPath,
+        to: UniversalPath,
+    ) -> Option<(WatchEvent, bool, Option<Since>)> {
+        // Whatever was pending where the file landed was replaced, unseen
+        if let Some(Pending {
+            event: WatchEvent::Renamed { from: other, .. },
+            seq,
+            first,
+            last,
+            ..
+        }) = self.pending.remove(&to.to_string())
+        {
+            self.pending.insert(
+                other.to_string(),
+                Pending {
+                    event: WatchEvent::Deleted(other),
+                    modified: false,
+                    seq,
+                    first,
+                    last,
+                },
+            );
+        }
+        let Some(previous) = self.pending.remove(&from.to_string()) else {
+            return Some((WatchEvent::Renamed { from, to }, false, None));
+        };
+        let since = Some((previous.seq, previous.first));
+        let (event, modified) = match previous.event {
+            WatchEvent::Created(_) => (WatchEvent::Created(to), false),
+            WatchEvent::Renamed { from: original, .. } if original == to => {
+                match previous.modified {
+                    true => (WatchEvent::Modified(to), false),
+                    false => return None,
+                }
+            }
+            WatchEvent::Renamed { from: original, .. } => (
+                WatchEvent::Renamed { from: original, to },
+                previous.modified,
+            ),
+            WatchEvent::Modified(_) => (WatchEvent::Renamed { from, to }, true),
+            _ => (WatchEvent::Renamed { from, to }, false),
+        };
+        Some((event, modified, since))
+    }
+
+    /// What is due by `now`, in the order the paths first changed
+    fn take_due(&mut self, debouncer: &EventDebouncer, now: Instant) -> Vec<WatchEvent> {
+        let due: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| deadline(p, debouncer) <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut due: Vec<_> = due
+            .into_iter()
+            .filter_map(|key| self.pending.remove(&key))
+            .collect();
+        due.sort_by_key(|p| p.seq);
+        due.into_iter().flat_map(report).collect()
+    }
+
+    fn take_all(&mut self) -> Vec<WatchEvent> {
+        let mut all: Vec<_> = self.pending.drain().map(|(_, p)| p).collect();
+        all.sort_by_key(|p| p.seq);
+        all.into_iter().flat_map(report).collect()
+    }
+
+    fn next_deadline(&self, debouncer: &EventDebouncer) -> Option<Instant> {
+        self.pending.values().map(|p| deadline(p, debouncer)).min()
+    }
+}
+
+fn deadline(pending: &Pending, debouncer: &EventDebouncer) -> Instant {
+    (pending.last + debouncer.window).min(pending.first + debouncer.max_delay)
+}
+
+/// The events a path is finally reported as
+fn report(pending: Pending) -> Vec<WatchEvent> {
+    match pending.event {
+        WatchEvent::Renamed { from, to } if pending.modified => vec![
+            WatchEvent::Renamed {
+                from,
+                to: to.clone(),
+            },
+            WatchEvent::Modified(to),
+        ],
+        event => vec![event],
+    }
+}
+
+/// `previous` followed by `next` on the same path, or nothing if they cancel out
+fn merge(previous: WatchEvent, modified: bool, next: WatchEvent) -> Option<(WatchEvent, bool)> {
+    Some(match (previous, next) {
+        (WatchEvent::Created(_), WatchEvent::Deleted(_)) => return None,
+        (created @ WatchEvent::Created(_), _) => (created, false),
+        // Replaced; a directory's contents may have been replaced with it
+        (WatchEvent::Deleted(_), WatchEvent::Created(p) | WatchEvent::Modified(p)) => {
+            match p.is_dir_hint() {
+                true => (WatchEvent::Rescan(p), false),
+                false => (WatchEvent::Modified(p), false),
+            }
+        }
+        (WatchEvent::Modified(_), deleted @ WatchEvent::Deleted(_)) => (deleted, false),
+        // The file was moved away and then deleted, so the original is gone
+        (WatchEvent::Renamed { from, .. }, WatchEvent::Deleted(_)) => {
+            (WatchEvent::Deleted(from), false)
+        }
+        (renamed @ WatchEvent::Renamed { .. }, _) => (renamed, true),
+        (WatchEvent::Modified(p), _) => (WatchEvent::Modified(p), modified),
+        (_, next) => (next, false),
+    })
+}
+
+async fn run(debouncer: EventDebouncer, mut events: WatchStream, tx: EventSender) {
+    let mut coalescer = Coalescer::default();
+    loop {
+        let deadline = coalescer.next_deadline(&debouncer);
+        let mut done = false;
+        let out: Vec<_> = tokio::select! {
+            _ = tx.closed() => return,
+            _ = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            } => coalescer.take_due(&debouncer, Instant::now()).into_iter().map(Ok).collect(),
+            next = events.next() => match next {
+                None => {
+                    done = true;
+                    coalescer.take_all().into_iter().map(Ok).collect()
+                }
+                Some(Err(e)) => vec![Err(e)],
+                Some(Ok(WatchEvent::Rescan(path))) => {
+                    let mut out: Vec<_> = coalescer.take_all().into_iter().map(Ok).collect();
+                    out.push(Ok(WatchEvent::Rescan(path)));
+                    out
+                }
+                Some(Ok(event)) => {
+                    coalescer.add(event, Instant::now());
+                    Vec::new()
+                }
+            },
+        };
+        for item in out {
+            if tx.send(item).is_err() {
+                return;
+            }
+        }
+        if done {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_debouncer() {
+        let root = UniversalPath::from_uri_str("mem://watch/").unwrap();
+        let file = |name: &str| root.join(name);
+        let (tx, raw) = channel();
+        let mut events = EventDebouncer::new(Duration::from_millis(50))
+            .with_max_delay(Duration::from_secs(5))
+            .debounce(raw);
+        let send = |event| tx.send(Ok(event)).unwrap();
+
+        // An editor's save: a temporary file written in bursts and moved into place
+        send(WatchEvent::Created(file(".01.flac.tmp")));
+        for _ in 0..5 {
+            send(WatchEvent::Modified(file(".01.flac.tmp")));
+        }
+        send(WatchEvent::Renamed {
+            from: file(".01.flac.tmp"),
+            to: file("01.flac"),
+        });
+        // Gone again before anyone saw it
+        send(WatchEvent::Created(file("02.flac")));
+        send(WatchEvent::Deleted(file("02.flac")));
+        // Saved by deleting and writing again
+        send(WatchEvent::Deleted(file("03.flac")));
+        send(WatchEvent::Created(file("03.flac")));
+        send(WatchEvent::Modified(file("03.flac")));
+        // Moved and then written to
+        send(WatchEvent::Renamed {
+            from: file("04.flac"),
+            to: file("05.flac"),
+        });
+        send(WatchEvent::Modified(file("05.flac")));
+
+        let mut seen = Vec::new();
+        for _ in 0..4 {
+            let next = tokio::time::timeout(Duration::from_secs(5), events.next());
+            seen.push(next.await.unwrap().unwrap().unwrap());
+        }
+        assert_eq!(
+            seen,
+            vec![
+                WatchEvent::Created(file("01.flac")),
+                WatchEvent::Modified(file("03.flac")),
+                WatchEvent::Renamed {
+                    from: file("04.flac"),
+                    to: file("05.flac"),
+                },
+                WatchEvent::Modified(file("05.flac")),
+            ]
+        );
+
+        // Nothing else was held back, and the stream ends with its input
+        drop(tx);
+        assert!(events.next().await.is_none());
+    }
+}
@@ -0,0 +1,239 @@
+use super::{EventSender, WatchEvent, WatchStream, Watcher, channel};
+use crate::storage::{LocalStorage, StorageBackend, StorageError};
+use crate::universal_path::UniversalPath;
+use async_trait::async_trait;
+use notify::{
+    Event, EventKind, RecursiveMode, Watcher as _,
+    event::{CreateKind, ModifyKind, RenameMode},
+};
+use std::{path::Path, time::Duration};
+use tokio::sync::mpsc;
+
+/// How long a rename's old name waits for its new one before it counts as a move out
+/// of the watched tree, i.e. a deletion.
+const RENAME_WINDOW: Duration = Duration::from_millis(100);
+
+/// Native change notifications for local paths: inotify, FSEvents or
+/// ReadDirectoryChangesW, whichever `notify` picks for the platform.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalWatcher;
+
+#[async_trait]
+impl Watcher for LocalWatcher {
+    async fn watch(&self, root: &UniversalPath) -> Result<WatchStream, StorageError> {
+        if root.backend() != &StorageBackend::Local {
+            return Err(StorageError::InvalidPath);
+        }
+        let pb = LocalStorage::new().to_pathbuf(root)?;
+        let (raw_tx, raw_rx) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |res| {
+            let _ = raw_tx.send(res);
+        })
+        .map_err(map_notify_error)?;
+        watcher
+            .watch(&pb, RecursiveMode::Recursive)
+            .map_err(map_notify_error)?;
+
+        let (tx, stream) = channel();
+        let root = root.clone();
+        tokio::spawn(async move {
+            // Dropping the watcher along with this task stops the notifications
+            let _watcher = watcher;
+            translate(raw_rx, tx, root).await;
+        });
+        Ok(stream)
+    }
+}
+
+/// Turn raw `notify` events into `WatchEvent`s until the consumer goes away, pairing
+/// the two halves of a rename by their tracker, or, where the platform gives none, by
+/// the new name coming straight after the old one.
+async fn translate(
+    mut raw: mpsc::UnboundedReceiver<notify::Result<Event>>,
+    tx: EventSender,
+    root: UniversalPath,
+) {
+    // Old name of a rename whose new name has not arrived yet
+    let mut pending: Option<(Option<usize>, UniversalPath)> = None;
+    // Tracker of the last rename already reported, so the `Both` after it is skipped
+    let mut paired = None;
+    loop {
+        let waiting = pending.is_some();
+        let received = tokio::select! {
+            _ = tx.closed() => return,
+            received = async {
+                if waiting {
+                    tokio::time::timeout(RENAME_WINDOW, raw.recv()).await.ok()
+                } else {
+                    Some(raw.recv().await)
+                }
+            } => received,
+        };
+        let Some(next) = received else {
+            // The old name never got a new one, so it left the watched tree
+            let (_, from) = pending.take().unwrap();
+            if tx.send(Ok(WatchEvent::Deleted(from))).is_err() {
+                return;
+            }
+            continue;
+        };
+        let Some(result) = next else { return };
+        let mut out = Vec::new();
+        match result {
+            Err(e) => out.push(Err(map_notify_error(e))),
+            Ok(event) => {
+                let tracker = event.tracker();
+                let paths: Vec<_> = event.paths.iter().filter_map(|p| to_upath(p)).collect();
+                match event.kind {
+                    EventKind::Create(kind) => {
+                        out.extend(paths.into_iter().map(|p| {
+                            Ok(WatchEvent::Created(if kind == CreateKind::Folder {
+                                p.as_dir()
+                            } else {
+                                p
+                            }))
+                        }));
+                    }
+                    EventKind::Remove(_) => {
+                        out.extend(paths.into_iter().map(|p| Ok(WatchEvent::Deleted(p))));
+                    }
+                    EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                        if let Some((_, from)) = pending.take() {
+                            out.push(Ok(WatchEvent::Deleted(from)));
+                        }
+                        pending = paths.into_iter().next().map(|p| (tracker, p));
+                    }
+                    EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                        let Some(to) = paths.into_iter().next() else {
+                            continue;
+                        };
+                        match pending.take() {
+                            Some((t, from)) if t == tracker => {
+                                paired = tracker;
+                                out.push(Ok(WatchEvent::Renamed { from, to }));
+                            }
+                            other => {
+                                pending = other;
+                                out.push(Ok(WatchEvent::Created(to)));
+                            }
+                        }
+                    }
+                    EventKind::Modify(ModifyKind::Name(RenameMode::Both))
+                        if tracker.is_none() || tracker != paired =>
+                    {
+                        let mut paths = paths.into_iter();
+                        if let (Some(from), Some(to)) = (paths.next(), paths.next()) {
+                            out.push(Ok(WatchEvent::Renamed { from, to }));
+                        }
+                    }
+                    // Already reported when the new name arrived
+                    EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {}
+                    // Platforms that cannot tell which side of a rename a path is on: a
+                    // name that is gone is the old one, and one that exists right after
+                    // it the new one
+                    EventKind::Modify(ModifyKind::Name(_)) => {
+                        for pb in &event.paths {
+                            let Some(p) = to_upath(pb) else { continue };
+                            if !pb.exists() {
+                                if let Some((_, from)) = pending.replace((None, p)) {
+                                    out.push(Ok(WatchEvent::Deleted(from)));
+                                }
+                                continue;
+                            }
+                            match pending.take() {
+                                Some((None, from)) => {
+                                    out.push(Ok(WatchEvent::Renamed { from, to: p }))
+                                }
+                                other => {
+                                    pending = other;
+                                    out.push(Ok(WatchEvent::Created(p)));
+                                }
+                            }
+                        }
+                    }
+                    EventKind::Modify(_) => {
+                        out.extend(paths.into_iter().map(|p| Ok(WatchEvent::Modified(p))));
+                    }
+                    _ if event.need_rescan() => {
+                        out.push(Ok(WatchEvent::Rescan(root.clone())));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        for item in out {
+            if tx.send(item).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+fn to_upath(path: &Path) -> Option<UniversalPath> {
+    path.to_str().map(UniversalPath::local)
+}
+
+fn map_notify_error(e: notify::Error) -> StorageError {
+    match e.kind {
+        notify::ErrorKind::PathNotFound => StorageError::NotFound,
+        notify::ErrorKind::Io(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            StorageError::NotFound
+        }
+        notify::ErrorKind::Io(e) => StorageError::Io(e),
+        other => StorageError::Io(std::io::Error::other(format!("{other:?}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use std::fs;
+
+    async fn next_event(stream: &mut WatchStream) -> WatchEvent {
+        tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .expect("no event within 5s")
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_local_watcher() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let root = UniversalPath::local(dir.to_str().unwrap()).as_dir();
+        let mut stream = LocalWatcher.watch(&root).await.unwrap();
+
+        fs::write(dir.join("a.flac"), b"flac").unwrap();
+        assert_eq!(
+            next_event(&mut stream).await,
+            WatchEvent::Created(root.join("a.flac"))
+        );
+        fs::rename(dir.join("a.flac"), dir.join("b.flac")).unwrap();
+        let renamed = loop {
+            match next_event(&mut stream).await {
+                WatchEvent::Modified(_) => continue,
+                other => break other,
+            }
+        };
+        assert_eq!(
+            renamed,
+            WatchEvent::Renamed {
+                from: root.join("a.flac"),
+                to: root.join("b.flac"),
+            }
+        );
+        fs::remove_file(dir.join("b.flac")).unwrap();
+        assert_eq!(
+            next_event(&mut stream).await,
+            WatchEvent::Deleted(root.join("b.flac"))
+        );
+
+        let missing = root.join("missing").as_dir();
+        assert!(matches!(
+            LocalWatcher.watch(&missing).await,
+            Err(StorageError::NotFound)
+        ));
+    }
+}
@@ -0,0 +1,181 @@
+use super::{WatchEvent, WatchStream, Watcher, channel};
+use crate::snapshot::{Snapshot, SnapshotOptions};
+use crate::storage::{ChecksumAlgorithm, EntryKind, Storage, StorageError};
+use crate::universal_path::UniversalPath;
+use async_trait::async_trait;
+use std::{sync::Arc, time::Duration};
+use tokio::time::MissedTickBehavior;
+
+/// Change detection by polling, for backends with no native notifications such as
+/// S3, SFTP and FTP. Every `interval` the tree is listed and stat'ed through the
+/// `Storage` trait and compared with the previous pass.
+///
+/// A file is modified when its size or modification time changes, or its SHA-256 with
+/// `with_hashing`, which reads every file on every pass. Times that move by no more
+/// than the tolerance, 2 seconds by default, do not count: servers that round times or
+/// derive them from listings in different formats report the same file differently. A file that disappears while
+/// one with the same fingerprint appears is a rename. A pass that fails part-way is
+/// reported as an error and not compared, so a flaky connection does not look like
+/// everything was deleted.
+#[derive(Clone)]
+pub struct PollingWatcher {
+    storage: Arc<dyn Storage>,
+    interval: Duration,
+    concurrency: usize,
+    hash_contents: bool,
+    mtime_tolerance: Duration,
+}
+
+impl PollingWatcher {
+    /// Poll through `storage` every 30 seconds with up to 8 calls in flight
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        PollingWatcher {
+            storage,
+            interval: Duration::from_secs(30),
+            concurrency: 8,
+            hash_contents: false,
+            mtime_tolerance: Duration::from_secs(2),
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// How many `list` or `stat` calls a pass keeps in flight at once
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Also compare file contents, for backends whose modification times cannot be
+    /// trusted
+    pub fn with_hashing(mut self, hash_contents: bool) -> Self {
+        self.hash_contents = hash_contents;
+        self
+    }
+
+    /// How far a modification time may move before the file counts as modified
+    pub fn with_mtime_tolerance(mut self, tolerance: Duration) -> Self {
+        self.mtime_tolerance = tolerance;
+        self
+    }
+
+    /// One pass over everything under `root`
+    async fn snapshot(&self, root: &UniversalPath) -> Result<Snapshot, StorageError> {
+        let mut options = SnapshotOptions::default().with_concurrency(self.concurrency);
+        if self.hash_contents {
+            options = options.with_checksums(ChecksumAlgorithm::Sha256);
+        }
+        Snapshot::capture(self.storage.as_ref(), root, &options).await
+    }
+}
+
+/// Events that turn `old` into `new`: deletions, then renames, creations and
+/// modifications, each in path order
+fn diff(old: &Snapshot, new: &Snapshot, tolerance: Duration) -> Vec<WatchEvent> {
+    let diff = Snapshot::diff_with_tolerance(old, new, tolerance);
+    let removed = diff
+        .removed
+        .into_iter()
+        .map(|e| WatchEvent::Deleted(e.path));
+    let moved = diff
+        .moved
+        .into_iter()
+        .map(|(from, to)| WatchEvent::Renamed { from, to });
+    let added = diff.added.into_iter().map(|e| WatchEvent::Created(e.path));
+    let modified = diff
+        .modified
+        .into_iter()
+        .map(|e| WatchEvent::Modified(e.path));
+    removed.chain(moved).chain(added).chain(modified).collect()
+}
+
+#[async_trait]
+impl Watcher for PollingWatcher {
+    async fn watch(&self, root: &UniversalPath) -> Result<WatchStream, StorageError> {
+        if self.storage.stat(root).await?.kind != EntryKind::Directory {
+            return Err(StorageError::NotADirectory);
+        }
+        let mut previous = self.snapshot(root).await?;
+        let (tx, stream) = channel();
+        let (watcher, root) = (self.clone(), root.clone());
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(watcher.interval);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            // The first tick is immediate, and the first pass is already done
+            ticks.tick().await;
+            loop {
+                tokio::select! {
+                    _ = tx.closed() => return,
+                    _ = ticks.tick() => {}
+                }
+                let events = match watcher.snapshot(&root).await {
+                    Ok(current) => {
+                        let events = diff(&previous, &current, watcher.mtime_tolerance);
+                        previous = current;
+                        events.into_iter().map(Ok).collect()
+                    }
+                    Err(e) => vec![Err(e)],
+                };
+                for event in events {
+                    if tx.send(event).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LocalStorage;
+    use futures::StreamExt;
+    use std::fs;
+
+    #[tokio::test]
+    async fn test_polling_watcher() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("Album")).unwrap();
+        fs::write(dir.join("Album/01.flac"), b"one").unwrap();
+        let root = UniversalPath::local(dir.to_str().unwrap()).as_dir();
+        let watcher =
+            PollingWatcher::new(Arc::new(LocalStorage::new())).with_interval(Duration::from_millis(20));
+        let mut stream = watcher.watch(&root).await.unwrap();
+        let album = root.join("Album");
+        let mut next = async || {
+            tokio::time::timeout(Duration::from_secs(5), stream.next())
+                .await
+                .expect("no event within 5s")
+                .unwrap()
+                .unwrap()
+        };
+
+        fs::write(dir.join("Album/02.flac"), b"two").unwrap();
+        assert_eq!(next().await, WatchEvent::Created(album.join("02.flac")));
+        fs::write(dir.join("Album/01.flac"), b"one, remastered").unwrap();
+        assert_eq!(next().await, WatchEvent::Modified(album.join("01.flac")));
+        fs::rename(dir.join("Album/02.flac"), dir.join("Album/03.flac")).unwrap();
+        // A pass may have caught the rewrite half done
+        let renamed = loop {
+            match next().await {
+                WatchEvent::Modified(_) => continue,
+                other => break other,
+            }
+        };
+        assert_eq!(
+            renamed,
+            WatchEvent::Renamed {
+                from: album.join("02.flac"),
+                to: album.join("03.flac"),
+            }
+        );
+        fs::remove_file(dir.join("Album/03.flac")).unwrap();
+        assert_eq!(next().await, WatchEvent::Deleted(album.join("03.flac")));
+    }
+}
@@ -0,0 +1,258 @@
+use super::{EventSender, WatchEvent, WatchStream, channel};
+use crate::storage::{EntryKind, Storage, StorageError};
+use futures::{StreamExt, stream};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::time::{Instant, MissedTickBehavior};
+
+/// Holds back `Created` and `Modified` events for a file until it has stopped
+/// changing, so a file still being copied in is not processed half-written. Held files
+/// are stat'ed every check interval; once one has had the same size and modification
+/// time for `stable_for`, its event is let through followed by `FileReady`.
+///
+/// A held file that is deleted is dropped, along with its `Deleted` if nothing about
+/// it had been let through yet; one that is renamed is held under its new name. Events
+/// for directories, deletions, renames of files not held, `Rescan` and errors pass
+/// straight through.
+#[derive(Clone)]
+pub struct StabilityFilter {
+    storage: Arc<dyn Storage>,
+    stable_for: Duration,
+    check_interval: Duration,
+    concurrency: usize,
+}
+
+impl StabilityFilter {
+    /// Let files through once they have not changed for `stable_for`, checking every
+    /// second, or more often for a shorter `stable_for`, with up to 8 `stat` calls in
+    /// flight
+    pub fn new(storage: Arc<dyn Storage>, stable_for: Duration) -> Self {
+        StabilityFilter {
+            storage,
+            stable_for,
+            check_interval: Duration::from_secs(1).min(stable_for.max(Duration::from_millis(10))),
+            concurrency: 8,
+        }
+    }
+
+    pub fn with_check_interval(mut self, interval: Duration) -> Self {
+        self.check_interval = interval.max(Duration::from_millis(1));
+        self
+    }
+
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// `events` with files held until they are stable. Must be called within a Tokio
+    /// runtime; the files are checked on a task of their own, which stops once the
+    /// returned stream is dropped or `events` ends and nothing is held.
+    pub fn filter(&self, events: WatchStream) -> WatchStream {
+        let (tx, stream) = channel();
+        tokio::spawn(self.clone().run(events, tx));
+        stream
+    }
+
+    async fn run(self, mut events: WatchStream, tx: EventSender) {
+        let mut held: HashMap<String, Held> = HashMap::new();
+        let mut ticks = tokio::time::interval(self.check_interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut ended = false;
+        loop {
+            if ended && held.is_empty() {
+                return;
+            }
+            let out = tokio::select! {
+                _ = tx.closed() => return,
+                _ = ticks.tick(), if !held.is_empty() => self.check(&mut held).await,
+                next = events.next(), if !ended => match next {
+                    None => {
+                        ended = true;
+                        Vec::new()
+                    }
+                    Some(Ok(event)) => hold(&mut held, event),
+                    Some(Err(e)) => vec![Err(e)],
+                },
+            };
+            for item in out {
+                if tx.send(item).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Stat every held file, letting through those that have been stable long enough
+    async fn check(
+        &self,
+        held: &mut HashMap<String, Held>,
+    ) -> Vec<Result<WatchEvent, StorageError>> {
+        let paths: Vec<_> = held.values().map(|h| h.event.path().clone()).collect();
+        let stats: Vec<_> = stream::iter(paths)
+            .map(|path| async move {
+                let stat = self.storage.stat(&path).await;
+                (path, stat)
+            })
+            .buffer_unordered(self.concurrency)
+            .collect()
+            .await;
+        let now = Instant::now();
+        let mut out = Vec::new();
+        for (path, stat) in stats {
+            let key = path.to_string();
+            let meta = match stat {
+                Ok(meta) => meta,
+                // Its `Deleted` is on the way
+                Err(StorageError::NotFound) => continue,
+                Err(e) => {
+                    out.push(Err(e));
+                    continue;
+                }
+            };
+            if meta.kind != EntryKind::File {
+                if let Some(h) = held.remove(&key) {
+                    out.push(Ok(h.event));
+                }
+                continue;
+            }
+            let Some(h) = held.get_mut(&key) else {
+                continue;
+            };
+            let seen = Some((meta.size_bytes, meta.modified_at));
+            if h.seen != seen {
+                h.seen = seen;
+                h.stable_since = now;
+            } else if now.duration_since(h.stable_since) >= self.stable_for {
+                let h = held.remove(&key).expect("held file is still held");
+                out.push(Ok(h.event));
+                out.push(Ok(WatchEvent::FileReady(path)));
+            }
+        }
+        out
+    }
+}
+
+/// A file waiting to settle.
+struct Held {
+    /// `Created` or `Modified`, whichever it first was
+    event: WatchEvent,
+    /// Size and modification time at the last check, none before the first
+    seen: Option<(Option<u64>, Option<SystemTime>)>,
+    stable_since: Instant,
+}
+
+impl Held {
+    fn new(event: WatchEvent) -> Self {
+        Held {
+            event,
+            seen: None,
+            stable_since: Instant::now(),
+        }
+    }
+}
+
+/// Take `event` into the held files, returning what passes through now
+fn hold(
+    held: &mut HashMap<String, Held>,
+    event: WatchEvent,
+) -> Vec<Result<WatchEvent, StorageError>> {
+    match event {
+        WatchEvent::Created(ref path) | WatchEvent::Modified(ref path) if path.is_dir_hint() => {
+            vec![Ok(event)]
+        }
+        WatchEvent::Created(ref path) | WatchEvent::Modified(ref path) => {
+            match held.get_mut(&path.to_string()) {
+                // Still changing
+                Some(h) => h.stable_since = Instant::now(),
+                None => {
+                    held.insert(path.to_string(), Held::new(event));
+                }
+            }
+            Vec::new()
+        }
+        WatchEvent::Deleted(path) => match held.remove(&path.to_string()) {
+            Some(Held {
+                event: WatchEvent::Created(_),
+                ..
+            }) => Vec::new(),
+            _ => vec![Ok(WatchEvent::Deleted(path))],
+        },
+        WatchEvent::Renamed { from, to } => {
+            let Some(h) = held.remove(&from.to_string()) else {
+                return vec![Ok(WatchEvent::Renamed { from, to })];
+            };
+            let (event, out) = match h.event {
+                WatchEvent::Created(_) => (WatchEvent::Created(to.clone()), Vec::new()),
+                _ => (
+                    WatchEvent::Modified(to.clone()),
+                    vec![Ok(WatchEvent::Renamed {
+                        from,
+                        to: to.clone(),
+                    })],
+                ),
+            };
+            held.insert(to.to_string(), Held::new(event));
+            out
+        }
+        event => vec![Ok(event)],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::universal_path::UniversalPath;
+
+    async fn next_event(stream: &mut WatchStream) -> WatchEvent {
+        tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .expect("no event within 5s")
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_stability_filter() {
+        let storage = Arc::new(MemoryStorage::new());
+        let root = UniversalPath::from_uri_str("mem://incoming/").unwrap();
+        let file = |name: &str| root.join(name);
+        let (tx, raw) = channel();
+        let mut events = StabilityFilter::new(storage.clone(), Duration::from_millis(150))
+            .with_check_interval(Duration::from_millis(20))
+            .filter(raw);
+        let send = |event| tx.send(Ok(event)).unwrap();
+
+        // Gone before it settled, so never seen at all
+        send(WatchEvent::Created(file("partial.flac")));
+        send(WatchEvent::Deleted(file("partial.flac")));
+        // Copied in a chunk at a time
+        let big = file("big.flac");
+        send(WatchEvent::Created(big.clone()));
+        let mut data = Vec::new();
+        for _ in 0..5 {
+            data.extend_from_slice(&[0; 1000]);
+            storage.write(&big, &data).await.unwrap();
+            send(WatchEvent::Modified(big.clone()));
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let last_write = Instant::now();
+
+        assert_eq!(
+            next_event(&mut events).await,
+            WatchEvent::Created(big.clone())
+        );
+        assert!(last_write.elapsed() >= Duration::from_millis(100));
+        assert_eq!(
+            next_event(&mut events).await,
+            WatchEvent::FileReady(big.clone())
+        );
+
+        drop(tx);
+        assert!(events.next().await.is_none());
+    }
+}
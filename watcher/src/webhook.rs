@@ -0,0 +1,281 @@
+use crate::retry::RetryPolicy;
+use chrono::{DateTime, SecondsFormat, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+/// Header carrying the HMAC-SHA256 of the request body, as `sha256=<hex>`.
+pub const SIGNATURE_HEADER: &str = "X-Otolith-Signature";
+/// Header carrying the event name.
+pub const EVENT_HEADER: &str = "X-Otolith-Event";
+
+#[derive(Debug, Error)]
+pub enum WebhookError {
+    #[error("invalid webhook body template: {0}")]
+    Template(String),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error("webhook endpoint answered {0}")]
+    Status(u16),
+}
+
+/// Something that happened in the library, e.g. `scan.completed` with the root in `data`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebhookEvent {
+    pub name: String,
+    pub timestamp: SystemTime,
+    pub data: serde_json::Value,
+}
+
+impl WebhookEvent {
+    pub fn new<S: Into<String>>(name: S, data: serde_json::Value) -> Self {
+        WebhookEvent {
+            name: name.into(),
+            timestamp: SystemTime::now(),
+            data,
+        }
+    }
+}
+
+/// Where and how to deliver events.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Event names to deliver; empty means all. A trailing `*` matches a prefix,
+    /// so `scan.*` covers `scan.started` and `scan.completed`.
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// JSON body with `{{event}}`, `{{timestamp}}`, `{{data}}` and `{{data.some.key}}`
+    /// placeholders, each replaced by a JSON value. Without one the body is
+    /// `{"event": .., "timestamp": .., "data": ..}`.
+    #[serde(default)]
+    pub body_template: Option<String>,
+    /// Shared secret for the signature header; unsigned when absent.
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default = "default_timeout")]
+    pub timeout: Duration,
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+impl WebhookConfig {
+    pub fn new<S: Into<String>>(url: S) -> Self {
+        WebhookConfig {
+            url: url.into(),
+            events: Vec::new(),
+            body_template: None,
+            secret: None,
+            headers: Vec::new(),
+            retry: RetryPolicy::default(),
+            timeout: default_timeout(),
+        }
+    }
+}
+
+/// Outbound HTTP POST sink for library events.
+pub struct WebhookSink {
+    config: WebhookConfig,
+    client: reqwest::Client,
+}
+
+impl WebhookSink {
+    pub fn new(config: WebhookConfig) -> Self {
+        WebhookSink {
+            config,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn config(&self) -> &WebhookConfig {
+        &self.config
+    }
+
+    /// Whether the event passes this sink's filter
+    pub fn accepts(&self, event: &WebhookEvent) -> bool {
+        self.config.events.is_empty()
+            || self
+                .config
+                .events
+                .iter()
+                .any(|pattern| match pattern.strip_suffix('*') {
+                    Some(prefix) => event.name.starts_with(prefix),
+                    None => *pattern == event.name,
+                })
+    }
+
+    /// Build the JSON body for `event`, failing if the template does not render to JSON
+    pub fn render_body(&self, event: &WebhookEvent) -> Result<String, WebhookError> {
+        let timestamp =
+            DateTime::<Utc>::from(event.timestamp).to_rfc3339_opts(SecondsFormat::Millis, true);
+        let Some(template) = &self.config.body_template else {
+            let body = serde_json::json!({
+                "event": event.name,
+                "timestamp": timestamp,
+                "data": event.data,
+            });
+            return Ok(body.to_string());
+        };
+
+        let mut out = String::with_capacity(template.len());
+        let mut rest = template.as_str();
+        while let Some(start) = rest.find("{{") {
+            out.push_str(&rest[..start]);
+            let after = &rest[start + 2..];
+            let end = after
+                .find("}}")
+                .ok_or_else(|| WebhookError::Template("unclosed placeholder".to_string()))?;
+            let key = after[..end].trim();
+            let value = match key {
+                "event" => serde_json::Value::from(event.name.as_str()),
+                "timestamp" => serde_json::Value::from(timestamp.as_str()),
+                "data" => event.data.clone(),
+                _ => match key.strip_prefix("data.") {
+                    Some(path) => {
+                        let pointer = format!("/{}", path.replace('.', "/"));
+                        event.data.pointer(&pointer).cloned().unwrap_or_default()
+                    }
+                    None => {
+                        return Err(WebhookError::Template(format!(
+                            "unknown placeholder {{{{{key}}}}}"
+                        )));
+                    }
+                },
+            };
+            out.push_str(&value.to_string());
+            rest = &after[end + 2..];
+        }
+        out.push_str(rest);
+
+        serde_json::from_str::<serde_json::Value>(&out)
+            .map_err(|e| WebhookError::Template(format!("rendered body is not JSON: {e}")))?;
+        Ok(out)
+    }
+
+    /// Send the event if it passes the filter, retrying network errors, 429 and 5xx
+    /// responses per the retry policy. Returns false when the event was filtered out.
+    pub async fn deliver(&self, event: &WebhookEvent) -> Result<bool, WebhookError> {
+        if !self.accepts(event) {
+            return Ok(false);
+        }
+        let body = self.render_body(event)?;
+        let signature = self
+            .config
+            .secret
+            .as_deref()
+            .map(|s| sign(s, body.as_bytes()));
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let mut request = self
+                .client
+                .post(&self.config.url)
+                .timeout(self.config.timeout)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(EVENT_HEADER, &event.name)
+                .body(body.clone());
+            if let Some(signature) = &signature {
+                request = request.header(SIGNATURE_HEADER, signature);
+            }
+            for (name, value) in &self.config.headers {
+                request = request.header(name, value);
+            }
+
+            let (err, retryable) = match request.send().await {
+                Ok(resp) if resp.status().is_success() => return Ok(true),
+                Ok(resp) => {
+                    let status = resp.status();
+                    let retryable = status.is_server_error() || status.as_u16() == 429;
+                    (WebhookError::Status(status.as_u16()), retryable)
+                }
+                Err(e) => (WebhookError::Http(e), true),
+            };
+            if !retryable || !self.config.retry.should_retry(attempt) {
+                return Err(err);
+            }
+            tokio::time::sleep(self.config.retry.delay(attempt)).await;
+        }
+    }
+}
+
+/// `sha256=<hex>` HMAC of `body` under `secret`, as sent in `SIGNATURE_HEADER`
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn event() -> WebhookEvent {
+        WebhookEvent {
+            name: "scan.completed".to_string(),
+            timestamp: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            data: serde_json::json!({ "root": { "uri": "file:/music" }, "files": 12 }),
+        }
+    }
+
+    #[test]
+    fn test_event_filter() {
+        let mut config = WebhookConfig::new("http://localhost/hook");
+        assert!(WebhookSink::new(config.clone()).accepts(&event()));
+        config.events = vec!["root.offline".to_string()];
+        assert!(!WebhookSink::new(config.clone()).accepts(&event()));
+        config.events.push("scan.*".to_string());
+        assert!(WebhookSink::new(config).accepts(&event()));
+    }
+
+    #[test]
+    fn test_render_body() {
+        let mut config = WebhookConfig::new("http://localhost/hook");
+        let default = WebhookSink::new(config.clone())
+            .render_body(&event())
+            .unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&default).unwrap();
+        assert_eq!(parsed["event"], "scan.completed");
+        assert_eq!(parsed["timestamp"], "2023-11-14T22:13:20.000Z");
+        assert_eq!(parsed["data"]["files"], 12);
+
+        config.body_template = Some(
+            r#"{"text": {{event}}, "root": {{data.root.uri}}, "missing": {{data.nope}}}"#
+                .to_string(),
+        );
+        let body = WebhookSink::new(config.clone())
+            .render_body(&event())
+            .unwrap();
+        assert_eq!(
+            body,
+            r#"{"text": "scan.completed", "root": "file:/music", "missing": null}"#
+        );
+
+        config.body_template = Some(r#"{"x": {{whatever}}}"#.to_string());
+        assert!(
+            WebhookSink::new(config.clone())
+                .render_body(&event())
+                .is_err()
+        );
+        config.body_template = Some(r#"{"x": {{event}}"#.to_string());
+        assert!(WebhookSink::new(config).render_body(&event()).is_err());
+    }
+
+    #[test]
+    fn test_signature() {
+        assert_eq!(
+            sign("key", b"The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+}
@@ -0,0 +1,97 @@
+//! Watch, scan, index and sync a local library into `MemoryStorage`, end to end,
+//! through the public API only.
+
+use futures::StreamExt;
+use std::{collections::BTreeMap, fs, sync::Arc, time::Duration};
+use watcher::{
+    AuditOptions, EntryKind, LocalStorage, MemoryStorage, PollingWatcher, Provenance, RootConfig,
+    Storage, StorageExt, StorageTransaction, UniversalPath, WatchEvent, Watcher, audit,
+    read_provenance, record_provenance,
+};
+
+#[tokio::test]
+async fn test_watch_scan_index_sync() {
+    let dir = std::env::temp_dir().join(format!("otolith-pipeline-{}", std::process::id()));
+    fs::create_dir_all(dir.join("album")).unwrap();
+    fs::write(dir.join("album/01.flac"), b"first track").unwrap();
+    fs::write(dir.join("album/cover.jpg"), b"jpeg").unwrap();
+    fs::write(dir.join("notes.txt"), b"ripped 2024").unwrap();
+    let root = RootConfig::new(UniversalPath::local(dir.to_str().unwrap()).as_dir());
+    let source: Arc<dyn Storage> = Arc::new(LocalStorage::new());
+
+    // Watch: a track added after the watch starts shows up as created
+    let watcher = PollingWatcher::new(source.clone()).with_interval(Duration::from_millis(50));
+    let mut events = watcher.watch(&root.path).await.unwrap();
+    fs::write(dir.join("album/02.flac"), b"second track").unwrap();
+    let created = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            match events.next().await.unwrap().unwrap() {
+                WatchEvent::Created(path) => break path,
+                _ => continue,
+            }
+        }
+    })
+    .await
+    .expect("no event for the new track");
+    assert_eq!(created.last_segment(), Some("02.flac"));
+    drop(events);
+
+    // Scan and index the audio files by their path under the root
+    let mut entries = Vec::new();
+    let mut walk = source.walk(&root.path);
+    while let Some(entry) = walk.next().await {
+        entries.push(entry.unwrap());
+    }
+    drop(walk);
+    let mut index = BTreeMap::new();
+    for (path, meta) in &entries {
+        if meta.kind == EntryKind::File && root.accepts_extension(path.extension()) {
+            let rel = path.relative_to(&root.path).unwrap().join("/");
+            index.insert(rel, (path.clone(), meta.clone()));
+        }
+    }
+    assert_eq!(
+        index.keys().collect::<Vec<_>>(),
+        ["album/01.flac", "album/02.flac"]
+    );
+
+    // Sync everything into the mirror in one transaction, then record provenance
+    let mirror = MemoryStorage::new();
+    let mirror_root = UniversalPath::from_uri_str("mem://library/").unwrap();
+    let mut tx = StorageTransaction::new(&mirror);
+    for (path, meta) in &entries {
+        let target = mirror_root.join_all(&path.relative_to(&root.path).unwrap());
+        match meta.kind {
+            EntryKind::Directory => tx.create_dir(&target),
+            _ => tx.write(&target, source.read(path).await.unwrap()),
+        };
+    }
+    tx.commit().await.unwrap();
+    for (path, meta) in index.values() {
+        let data = source.read(path).await.unwrap();
+        let target = mirror_root.join_all(&path.relative_to(&root.path).unwrap());
+        let provenance = Provenance::new(path, Some(meta), &data);
+        record_provenance(&mirror, &target, &provenance)
+            .await
+            .unwrap();
+    }
+
+    // The mirror holds the same tree, with provenance on every track
+    assert_eq!(mirror.file_count(), 4);
+    let options = AuditOptions {
+        mtime_tolerance: Duration::MAX,
+        ..AuditOptions::default()
+    };
+    let report = audit(source.as_ref(), &root.path, &mirror, &mirror_root, &options)
+        .await
+        .unwrap();
+    assert!(report.is_clean(), "{}", report.to_json());
+    assert_eq!(report.files_compared, 4);
+    let track = mirror_root.join("album").join("02.flac");
+    assert_eq!(mirror.read(&track).await.unwrap(), b"second track");
+    let provenance = read_provenance(&mirror, &track).await.unwrap().unwrap();
+    assert_eq!(provenance.source, index["album/02.flac"].0);
+    assert!(provenance.matches(b"second track"));
+
+    fs::remove_dir_all(&dir).ok();
+}